@@ -1,6 +1,8 @@
 #!/usr/bin/python
 
 import collections
+import hashlib
+import json
 import numpy
 import os
 import torch
@@ -16,6 +18,12 @@ parser.add_argument(
     default="./converted.st",
     help="Path to output safetensors model",
 )
+parser.add_argument(
+    "--manifest",
+    type=str,
+    default=None,
+    help="Path to output a manifest of tensor checksums (JSON), to be verified at load time",
+)
 args = parser.parse_args()
 
 
@@ -26,7 +34,22 @@ def rename_key(rename, name):
     return name
 
 
-def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=[]):
+def write_manifest(tensors, manifest_filename: str):
+    manifest = {
+        "tensors": {
+            k: {
+                "dtype": v["dtype"].replace("float", "F"),
+                "shape": list(v["shape"]),
+                "sha256": hashlib.sha256(v["data"]).hexdigest(),
+            }
+            for k, v in tensors.items()
+        }
+    }
+    with open(manifest_filename, "w") as f:
+        json.dump(manifest, f, indent=2, sort_keys=True)
+
+
+def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=[], manifest_filename=None):
     loaded: collections.OrderedDict = torch.load(pt_filename, map_location="cpu")
     if "state_dict" in loaded:
         loaded = loaded["state_dict"]
@@ -75,6 +98,8 @@ def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=
     dirname = os.path.dirname(sf_filename)
     os.makedirs(dirname, exist_ok=True)
     serialize_file(loaded, sf_filename, metadata={"format": "pt"})
+    if manifest_filename is not None:
+        write_manifest(loaded, manifest_filename)
     # reloaded = load_file(sf_filename)
     # for k in loaded:
     #     pt_tensor = torch.Tensor(
@@ -92,5 +117,8 @@ if __name__ == "__main__":
     convert_file(args.input, args.output,
                  rename={"time_faaaa": "time_first", "time_maa": "time_mix",
                          "lora_A": "lora.0", "lora_B": "lora.1"},
-                 transpose_names=["time_mix_w1", "time_mix_w2", "time_decay_w1", "time_decay_w2", "time_state", "lora.0"])
+                 transpose_names=["time_mix_w1", "time_mix_w2", "time_decay_w1", "time_decay_w2", "time_state", "lora.0"],
+                 manifest_filename=args.manifest)
     print(f"Saved to {args.output}")
+    if args.manifest is not None:
+        print(f"Manifest saved to {args.manifest}")
@@ -47,7 +47,7 @@ pub fn expand_derive_deserialize(input: &mut syn::DeriveInput) -> syn::Result<To
             impl #de_impl_generics #serde::de::DeserializeSeed<#delife> for crate::tensor::serialization::Seed<#delife, #context, #ident #ty_generics> #where_clause {
                 type Value = #ident #ty_generics;
 
-                fn deserialize<__D>(self, __deserializer: __D) -> #serde::__private::Result<Self::Value, __D::Error>
+                fn deserialize<__D>(self, __deserializer: __D) -> #serde::__private229::Result<Self::Value, __D::Error>
                 where
                     __D: #serde::Deserializer<#delife>,
                 {
@@ -174,7 +174,7 @@ fn build_generics(cont: &Container, borrowed: &BorrowedLifetimes) -> syn::Generi
                 attr::Default::Default => bound::with_self_bound(
                     cont,
                     &generics,
-                    &parse_quote!(_serde::__private::Default),
+                    &parse_quote!(_serde::__private229::Default),
                 ),
                 attr::Default::None | attr::Default::Path(_) => generics,
             };
@@ -191,7 +191,7 @@ fn build_generics(cont: &Container, borrowed: &BorrowedLifetimes) -> syn::Generi
                 cont,
                 &generics,
                 requires_default,
-                &parse_quote!(_serde::__private::Default),
+                &parse_quote!(_serde::__private229::Default),
             )
         }
     }
@@ -318,16 +318,16 @@ fn deserialize_transparent(cont: &Container, params: &Parameters) -> Fragment {
             quote!(#member: __transparent)
         } else {
             let value = match field.attrs.default() {
-                attr::Default::Default => quote!(_serde::__private::Default::default()),
+                attr::Default::Default => quote!(_serde::__private229::Default::default()),
                 attr::Default::Path(path) => quote!(#path()),
-                attr::Default::None => quote!(_serde::__private::PhantomData),
+                attr::Default::None => quote!(_serde::__private229::PhantomData),
             };
             quote!(#member: #value)
         }
     });
 
     quote_block! {
-        _serde::__private::Result::map(
+        _serde::__private229::Result::map(
             #path(__deserializer),
             |__transparent| #this_value { #(#assign),* })
     }
@@ -335,17 +335,17 @@ fn deserialize_transparent(cont: &Container, params: &Parameters) -> Fragment {
 
 fn deserialize_from(type_from: &syn::Type) -> Fragment {
     quote_block! {
-        _serde::__private::Result::map(
+        _serde::__private229::Result::map(
             <#type_from as _serde::Deserialize>::deserialize(__deserializer),
-            _serde::__private::From::from)
+            _serde::__private229::From::from)
     }
 }
 
 fn deserialize_try_from(type_try_from: &syn::Type) -> Fragment {
     quote_block! {
-        _serde::__private::Result::and_then(
+        _serde::__private229::Result::and_then(
             <#type_try_from as _serde::Deserialize>::deserialize(__deserializer),
-            |v| _serde::__private::TryFrom::try_from(v).map_err(_serde::de::Error::custom))
+            |v| _serde::__private229::TryFrom::try_from(v).map_err(_serde::de::Error::custom))
     }
 }
 
@@ -363,23 +363,23 @@ fn deserialize_unit_struct(params: &Parameters, cattrs: &attr::Container) -> Fra
     quote_block! {
         #[doc(hidden)]
         struct __Visitor #de_impl_generics #where_clause {
-            marker: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            marker: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::de::Visitor<#delife> for __Visitor #de_ty_generics #where_clause {
             type Value = #this_type #ty_generics;
 
-            fn expecting(&self, __formatter: &mut _serde::__private::Formatter) -> _serde::__private::fmt::Result {
-                _serde::__private::Formatter::write_str(__formatter, #expecting)
+            fn expecting(&self, __formatter: &mut _serde::__private229::Formatter) -> _serde::__private229::fmt::Result {
+                _serde::__private229::Formatter::write_str(__formatter, #expecting)
             }
 
             #[inline]
-            fn visit_unit<__E>(self) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_unit<__E>(self) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(#this_value)
+                _serde::__private229::Ok(#this_value)
             }
         }
 
@@ -387,8 +387,8 @@ fn deserialize_unit_struct(params: &Parameters, cattrs: &attr::Container) -> Fra
             __deserializer,
             #type_name,
             __Visitor {
-                marker: _serde::__private::PhantomData::<#this_type #ty_generics>,
-                lifetime: _serde::__private::PhantomData,
+                marker: _serde::__private229::PhantomData::<#this_type #ty_generics>,
+                lifetime: _serde::__private229::PhantomData,
             },
         )
     }
@@ -461,8 +461,8 @@ fn deserialize_tuple(
 
     let visitor_expr = quote! {
         __Visitor {
-            marker: _serde::__private::PhantomData::<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData,
+            marker: _serde::__private229::PhantomData::<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData,
         }
     };
     let dispatch = match form {
@@ -495,21 +495,21 @@ fn deserialize_tuple(
     quote_block! {
         #[doc(hidden)]
         struct __Visitor #de_impl_generics #where_clause {
-            marker: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            marker: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::de::Visitor<#delife> for __Visitor #de_ty_generics #where_clause {
             type Value = #this_type #ty_generics;
 
-            fn expecting(&self, __formatter: &mut _serde::__private::Formatter) -> _serde::__private::fmt::Result {
-                _serde::__private::Formatter::write_str(__formatter, #expecting)
+            fn expecting(&self, __formatter: &mut _serde::__private229::Formatter) -> _serde::__private229::fmt::Result {
+                _serde::__private229::Formatter::write_str(__formatter, #expecting)
             }
 
             #visit_newtype_struct
 
             #[inline]
-            fn visit_seq<__A>(self, #visitor_var: __A) -> _serde::__private::Result<Self::Value, __A::Error>
+            fn visit_seq<__A>(self, #visitor_var: __A) -> _serde::__private229::Result<Self::Value, __A::Error>
             where
                 __A: _serde::de::SeqAccess<#delife>,
             {
@@ -564,7 +564,7 @@ fn deserialize_seq(
                     let context = &params.context;
                     quote!({
                         #wrapper
-                        _serde::__private::Option::map(
+                        _serde::__private229::Option::map(
                             _serde::de::SeqAccess::next_element_seed::<crate::tensor::serialization::Seed<#context, #wrapper_ty>>(
                                 &mut __seq, crate::tensor::serialization::Seed::new(self.context))?,
                             |__wrap| __wrap.value)
@@ -574,8 +574,8 @@ fn deserialize_seq(
             let value_if_none = expr_is_missing_seq(None, index_in_seq, field, cattrs, expecting);
             let assign = quote! {
                 let #var = match #visit {
-                    _serde::__private::Some(__value) => __value,
-                    _serde::__private::None => #value_if_none,
+                    _serde::__private229::Some(__value) => __value,
+                    _serde::__private229::None => #value_if_none,
                 };
             };
             index_in_seq += 1;
@@ -598,13 +598,13 @@ fn deserialize_seq(
         let this_type = &params.this_type;
         let (_, ty_generics, _) = params.generics.split_for_impl();
         result = quote! {
-            _serde::__private::Into::<#this_type #ty_generics>::into(#result)
+            _serde::__private229::Into::<#this_type #ty_generics>::into(#result)
         };
     }
 
     let let_default = match cattrs.default() {
         attr::Default::Default => Some(quote!(
-            let __default: Self::Value = _serde::__private::Default::default();
+            let __default: Self::Value = _serde::__private229::Default::default();
         )),
         attr::Default::Path(path) => Some(quote!(
             let __default: Self::Value = #path();
@@ -619,7 +619,7 @@ fn deserialize_seq(
     quote_block! {
         #let_default
         #(#let_values)*
-        _serde::__private::Ok(#result)
+        _serde::__private229::Ok(#result)
     }
 }
 
@@ -651,18 +651,18 @@ fn deserialize_newtype_struct(
         let this_type = &params.this_type;
         let (_, ty_generics, _) = params.generics.split_for_impl();
         result = quote! {
-            _serde::__private::Into::<#this_type #ty_generics>::into(#result)
+            _serde::__private229::Into::<#this_type #ty_generics>::into(#result)
         };
     }
 
     quote! {
         #[inline]
-        fn visit_newtype_struct<__E>(self, __e: __E) -> _serde::__private::Result<Self::Value, __E::Error>
+        fn visit_newtype_struct<__E>(self, __e: __E) -> _serde::__private229::Result<Self::Value, __E::Error>
         where
             __E: _serde::Deserializer<#delife>,
         {
             let __field0: #field_ty = #value;
-            _serde::__private::Ok(#result)
+            _serde::__private229::Ok(#result)
         }
     }
 }
@@ -752,7 +752,7 @@ fn deserialize_struct(
 
             Some(quote! {
                 #[inline]
-                fn visit_seq<__A>(self, #mut_seq: __A) -> _serde::__private::Result<Self::Value, __A::Error>
+                fn visit_seq<__A>(self, #mut_seq: __A) -> _serde::__private229::Result<Self::Value, __A::Error>
                 where
                     __A: _serde::de::SeqAccess<#delife>,
                 {
@@ -768,7 +768,7 @@ fn deserialize_struct(
             impl #de_impl_generics _serde::de::DeserializeSeed<#delife> for __Visitor #de_ty_generics #where_clause {
                 type Value = #this_type #ty_generics;
 
-                fn deserialize<__D>(self, __deserializer: __D) -> _serde::__private::Result<Self::Value, __D::Error>
+                fn deserialize<__D>(self, __deserializer: __D) -> _serde::__private229::Result<Self::Value, __D::Error>
                 where
                     __D: _serde::Deserializer<#delife>,
                 {
@@ -795,8 +795,8 @@ fn deserialize_struct(
     let visitor_expr = quote! {
         __Visitor {
             context: self.context,
-            marker: _serde::__private::PhantomData::<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData,
+            marker: _serde::__private229::PhantomData::<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData,
         }
     };
     let dispatch = match form {
@@ -829,21 +829,21 @@ fn deserialize_struct(
         #[doc(hidden)]
         struct __Visitor #de_impl_generics #where_clause {
             context: &#delife #context,
-            marker: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            marker: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::de::Visitor<#delife> for __Visitor #de_ty_generics #where_clause {
             type Value = #this_type #ty_generics;
 
-            fn expecting(&self, __formatter: &mut _serde::__private::Formatter) -> _serde::__private::fmt::Result {
-                _serde::__private::Formatter::write_str(__formatter, #expecting)
+            fn expecting(&self, __formatter: &mut _serde::__private229::Formatter) -> _serde::__private229::fmt::Result {
+                _serde::__private229::Formatter::write_str(__formatter, #expecting)
             }
 
             #visit_seq
 
             #[inline]
-            fn visit_map<__A>(self, mut __map: __A) -> _serde::__private::Result<Self::Value, __A::Error>
+            fn visit_map<__A>(self, mut __map: __A) -> _serde::__private229::Result<Self::Value, __A::Error>
             where
                 __A: _serde::de::MapAccess<#delife>,
             {
@@ -916,7 +916,7 @@ fn prepare_enum_variant_enum(
         .position(|(_, variant)| variant.attrs.other())
         .map(|other_idx| {
             let ignore_variant = variant_names_idents[other_idx].1.clone();
-            quote!(_serde::__private::Ok(__Field::#ignore_variant))
+            quote!(_serde::__private229::Ok(__Field::#ignore_variant))
         });
 
     let variants_stmt = {
@@ -980,9 +980,9 @@ fn deserialize_externally_tagged_enum(
         // all variants have `#[serde(skip_deserializing)]`.
         quote! {
             // FIXME: Once feature(exhaustive_patterns) is stable:
-            // let _serde::__private::Err(__err) = _serde::de::EnumAccess::variant::<__Field>(__data);
-            // _serde::__private::Err(__err)
-            _serde::__private::Result::map(
+            // let _serde::__private229::Err(__err) = _serde::de::EnumAccess::variant::<__Field>(__data);
+            // _serde::__private229::Err(__err)
+            _serde::__private229::Result::map(
                 _serde::de::EnumAccess::variant::<__Field>(__data),
                 |(__impossible, _)| match __impossible {})
         }
@@ -1000,18 +1000,18 @@ fn deserialize_externally_tagged_enum(
         #[doc(hidden)]
         struct __Visitor #de_impl_generics #where_clause {
             context: &#delife #context,
-            marker: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            marker: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::de::Visitor<#delife> for __Visitor #de_ty_generics #where_clause {
             type Value = #this_type #ty_generics;
 
-            fn expecting(&self, __formatter: &mut _serde::__private::Formatter) -> _serde::__private::fmt::Result {
-                _serde::__private::Formatter::write_str(__formatter, #expecting)
+            fn expecting(&self, __formatter: &mut _serde::__private229::Formatter) -> _serde::__private229::fmt::Result {
+                _serde::__private229::Formatter::write_str(__formatter, #expecting)
             }
 
-            fn visit_enum<__A>(self, __data: __A) -> _serde::__private::Result<Self::Value, __A::Error>
+            fn visit_enum<__A>(self, __data: __A) -> _serde::__private229::Result<Self::Value, __A::Error>
             where
                 __A: _serde::de::EnumAccess<#delife>,
             {
@@ -1027,8 +1027,8 @@ fn deserialize_externally_tagged_enum(
             VARIANTS,
             __Visitor {
                 context: self.context,
-                marker: _serde::__private::PhantomData::<#this_type #ty_generics>,
-                lifetime: _serde::__private::PhantomData,
+                marker: _serde::__private229::PhantomData::<#this_type #ty_generics>,
+                lifetime: _serde::__private229::PhantomData,
             },
         )
     }
@@ -1072,8 +1072,8 @@ fn deserialize_internally_tagged_enum(
 
         let (__tag, __content) = _serde::Deserializer::deserialize_any(
             __deserializer,
-            _serde::__private::de::TaggedContentVisitor::<__Field>::new(#tag, #expecting))?;
-        let __deserializer = _serde::__private::de::ContentDeserializer::<__D::Error>::new(__content);
+            _serde::__private229::de::TaggedContentVisitor::<__Field>::new(#tag, #expecting))?;
+        let __deserializer = _serde::__private229::de::ContentDeserializer::<__D::Error>::new(__content);
 
         match __tag {
             #(#variant_arms)*
@@ -1126,9 +1126,9 @@ fn deserialize_adjacently_tagged_enum(
     // If unknown fields are allowed, we pick the visitor that can step over
     // those. Otherwise we pick the visitor that fails on unknown keys.
     let field_visitor_ty = if deny_unknown_fields {
-        quote! { _serde::__private::de::TagOrContentFieldVisitor }
+        quote! { _serde::__private229::de::TagOrContentFieldVisitor }
     } else {
-        quote! { _serde::__private::de::TagContentOtherFieldVisitor }
+        quote! { _serde::__private229::de::TagContentOtherFieldVisitor }
     };
 
     let tag_or_content = quote! {
@@ -1139,15 +1139,15 @@ fn deserialize_adjacently_tagged_enum(
     };
 
     let variant_seed = quote! {
-        _serde::__private::de::AdjacentlyTaggedEnumVariantSeed::<__Field> {
+        _serde::__private229::de::AdjacentlyTaggedEnumVariantSeed::<__Field> {
             enum_name: #rust_name,
             variants: VARIANTS,
-            fields_enum: _serde::__private::PhantomData
+            fields_enum: _serde::__private229::PhantomData
         }
     };
 
     let mut missing_content = quote! {
-        _serde::__private::Err(<__A::Error as _serde::de::Error>::missing_field(#content))
+        _serde::__private229::Err(<__A::Error as _serde::de::Error>::missing_field(#content))
     };
     let mut missing_content_fallthrough = quote!();
     let missing_content_arms = variants
@@ -1160,11 +1160,11 @@ fn deserialize_adjacently_tagged_enum(
 
             let arm = match variant.style {
                 Style::Unit => quote! {
-                    _serde::__private::Ok(#this_value::#variant_ident)
+                    _serde::__private229::Ok(#this_value::#variant_ident)
                 },
                 Style::Newtype if variant.attrs.deserialize_with().is_none() => {
                     let span = variant.original.span();
-                    let func = quote_spanned!(span=> _serde::__private::de::missing_field);
+                    let func = quote_spanned!(span=> _serde::__private229::de::missing_field);
                     quote! {
                         #func(#content).map(#this_value::#variant_ident)
                     }
@@ -1203,19 +1203,19 @@ fn deserialize_adjacently_tagged_enum(
         next_key
     } else {
         quote!({
-            let mut __rk : _serde::__private::Option<_serde::__private::de::TagOrContentField> = _serde::__private::None;
-            while let _serde::__private::Some(__k) = #next_key {
+            let mut __rk : _serde::__private229::Option<_serde::__private229::de::TagOrContentField> = _serde::__private229::None;
+            while let _serde::__private229::Some(__k) = #next_key {
                 match __k {
-                    _serde::__private::de::TagContentOtherField::Other => {
+                    _serde::__private229::de::TagContentOtherField::Other => {
                         let _ = _serde::de::MapAccess::next_value::<_serde::de::IgnoredAny>(&mut __map)?;
                         continue;
                     },
-                    _serde::__private::de::TagContentOtherField::Tag => {
-                        __rk = _serde::__private::Some(_serde::__private::de::TagOrContentField::Tag);
+                    _serde::__private229::de::TagContentOtherField::Tag => {
+                        __rk = _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Tag);
                         break;
                     }
-                    _serde::__private::de::TagContentOtherField::Content => {
-                        __rk = _serde::__private::Some(_serde::__private::de::TagOrContentField::Content);
+                    _serde::__private229::de::TagContentOtherField::Content => {
+                        __rk = _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Content);
                         break;
                     }
                 }
@@ -1230,13 +1230,13 @@ fn deserialize_adjacently_tagged_enum(
     // at this point immediately produce an error.
     let visit_remaining_keys = quote! {
         match #next_relevant_key {
-            _serde::__private::Some(_serde::__private::de::TagOrContentField::Tag) => {
-                _serde::__private::Err(<__A::Error as _serde::de::Error>::duplicate_field(#tag))
+            _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Tag) => {
+                _serde::__private229::Err(<__A::Error as _serde::de::Error>::duplicate_field(#tag))
             }
-            _serde::__private::Some(_serde::__private::de::TagOrContentField::Content) => {
-                _serde::__private::Err(<__A::Error as _serde::de::Error>::duplicate_field(#content))
+            _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Content) => {
+                _serde::__private229::Err(<__A::Error as _serde::de::Error>::duplicate_field(#content))
             }
-            _serde::__private::None => _serde::__private::Ok(__ret),
+            _serde::__private229::None => _serde::__private229::Ok(__ret),
         }
     };
 
@@ -1263,14 +1263,14 @@ fn deserialize_adjacently_tagged_enum(
         #[doc(hidden)]
         struct __Seed #de_impl_generics #where_clause {
             field: __Field,
-            marker: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            marker: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::de::DeserializeSeed<#delife> for __Seed #de_ty_generics #where_clause {
             type Value = #this_type #ty_generics;
 
-            fn deserialize<__D>(self, __deserializer: __D) -> _serde::__private::Result<Self::Value, __D::Error>
+            fn deserialize<__D>(self, __deserializer: __D) -> _serde::__private229::Result<Self::Value, __D::Error>
             where
                 __D: _serde::Deserializer<#delife>,
             {
@@ -1283,102 +1283,102 @@ fn deserialize_adjacently_tagged_enum(
         #[doc(hidden)]
         struct __Visitor #de_impl_generics #where_clause {
             context: &#delife #context,
-            marker: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            marker: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::de::Visitor<#delife> for __Visitor #de_ty_generics #where_clause {
             type Value = #this_type #ty_generics;
 
-            fn expecting(&self, __formatter: &mut _serde::__private::Formatter) -> _serde::__private::fmt::Result {
-                _serde::__private::Formatter::write_str(__formatter, #expecting)
+            fn expecting(&self, __formatter: &mut _serde::__private229::Formatter) -> _serde::__private229::fmt::Result {
+                _serde::__private229::Formatter::write_str(__formatter, #expecting)
             }
 
-            fn visit_map<__A>(self, mut __map: __A) -> _serde::__private::Result<Self::Value, __A::Error>
+            fn visit_map<__A>(self, mut __map: __A) -> _serde::__private229::Result<Self::Value, __A::Error>
             where
                 __A: _serde::de::MapAccess<#delife>,
             {
                 // Visit the first relevant key.
                 match #next_relevant_key {
                     // First key is the tag.
-                    _serde::__private::Some(_serde::__private::de::TagOrContentField::Tag) => {
+                    _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Tag) => {
                         // Parse the tag.
                         let __field = #variant_from_map;
                         // Visit the second key.
                         match #next_relevant_key {
                             // Second key is a duplicate of the tag.
-                            _serde::__private::Some(_serde::__private::de::TagOrContentField::Tag) => {
-                                _serde::__private::Err(<__A::Error as _serde::de::Error>::duplicate_field(#tag))
+                            _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Tag) => {
+                                _serde::__private229::Err(<__A::Error as _serde::de::Error>::duplicate_field(#tag))
                             }
                             // Second key is the content.
-                            _serde::__private::Some(_serde::__private::de::TagOrContentField::Content) => {
+                            _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Content) => {
                                 let __ret = _serde::de::MapAccess::next_value_seed(&mut __map,
                                     __Seed {
                                         field: __field,
-                                        marker: _serde::__private::PhantomData,
-                                        lifetime: _serde::__private::PhantomData,
+                                        marker: _serde::__private229::PhantomData,
+                                        lifetime: _serde::__private229::PhantomData,
                                     })?;
                                 // Visit remaining keys, looking for duplicates.
                                 #visit_remaining_keys
                             }
                             // There is no second key; might be okay if the we have a unit variant.
-                            _serde::__private::None => #missing_content
+                            _serde::__private229::None => #missing_content
                         }
                     }
                     // First key is the content.
-                    _serde::__private::Some(_serde::__private::de::TagOrContentField::Content) => {
+                    _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Content) => {
                         // Buffer up the content.
-                        let __content = _serde::de::MapAccess::next_value::<_serde::__private::de::Content>(&mut __map)?;
+                        let __content = _serde::de::MapAccess::next_value::<_serde::__private229::de::Content>(&mut __map)?;
                         // Visit the second key.
                         match #next_relevant_key {
                             // Second key is the tag.
-                            _serde::__private::Some(_serde::__private::de::TagOrContentField::Tag) => {
-                                let __deserializer = _serde::__private::de::ContentDeserializer::<__A::Error>::new(__content);
+                            _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Tag) => {
+                                let __deserializer = _serde::__private229::de::ContentDeserializer::<__A::Error>::new(__content);
                                 #finish_content_then_tag
                             }
                             // Second key is a duplicate of the content.
-                            _serde::__private::Some(_serde::__private::de::TagOrContentField::Content) => {
-                                _serde::__private::Err(<__A::Error as _serde::de::Error>::duplicate_field(#content))
+                            _serde::__private229::Some(_serde::__private229::de::TagOrContentField::Content) => {
+                                _serde::__private229::Err(<__A::Error as _serde::de::Error>::duplicate_field(#content))
                             }
                             // There is no second key.
-                            _serde::__private::None => {
-                                _serde::__private::Err(<__A::Error as _serde::de::Error>::missing_field(#tag))
+                            _serde::__private229::None => {
+                                _serde::__private229::Err(<__A::Error as _serde::de::Error>::missing_field(#tag))
                             }
                         }
                     }
                     // There is no first key.
-                    _serde::__private::None => {
-                        _serde::__private::Err(<__A::Error as _serde::de::Error>::missing_field(#tag))
+                    _serde::__private229::None => {
+                        _serde::__private229::Err(<__A::Error as _serde::de::Error>::missing_field(#tag))
                     }
                 }
             }
 
-            fn visit_seq<__A>(self, mut __seq: __A) -> _serde::__private::Result<Self::Value, __A::Error>
+            fn visit_seq<__A>(self, mut __seq: __A) -> _serde::__private229::Result<Self::Value, __A::Error>
             where
                 __A: _serde::de::SeqAccess<#delife>,
             {
                 // Visit the first element - the tag.
                 match _serde::de::SeqAccess::next_element(&mut __seq)? {
-                    _serde::__private::Some(__field) => {
+                    _serde::__private229::Some(__field) => {
                         // Visit the second element - the content.
                         match _serde::de::SeqAccess::next_element_seed(
                             &mut __seq,
                             __Seed {
                                 field: __field,
-                                marker: _serde::__private::PhantomData,
-                                lifetime: _serde::__private::PhantomData,
+                                marker: _serde::__private229::PhantomData,
+                                lifetime: _serde::__private229::PhantomData,
                             },
                         )? {
-                            _serde::__private::Some(__ret) => _serde::__private::Ok(__ret),
+                            _serde::__private229::Some(__ret) => _serde::__private229::Ok(__ret),
                             // There is no second element.
-                            _serde::__private::None => {
-                                _serde::__private::Err(_serde::de::Error::invalid_length(1, &self))
+                            _serde::__private229::None => {
+                                _serde::__private229::Err(_serde::de::Error::invalid_length(1, &self))
                             }
                         }
                     }
                     // There is no first element.
-                    _serde::__private::None => {
-                        _serde::__private::Err(_serde::de::Error::invalid_length(0, &self))
+                    _serde::__private229::None => {
+                        _serde::__private229::Err(_serde::de::Error::invalid_length(0, &self))
                     }
                 }
             }
@@ -1392,8 +1392,8 @@ fn deserialize_adjacently_tagged_enum(
             FIELDS,
             __Visitor {
                 context: self.context,
-                marker: _serde::__private::PhantomData::<#this_type #ty_generics>,
-                lifetime: _serde::__private::PhantomData,
+                marker: _serde::__private229::PhantomData::<#this_type #ty_generics>,
+                lifetime: _serde::__private229::PhantomData,
             },
         )
     }
@@ -1442,25 +1442,25 @@ fn deserialize_untagged_enum_after(
     // need to provide the error type.
     let first_attempt = first_attempt.map(|expr| {
         quote! {
-            if let _serde::__private::Result::<_, __D::Error>::Ok(__ok) = (|| #expr)() {
-                return _serde::__private::Ok(__ok);
+            if let _serde::__private229::Result::<_, __D::Error>::Ok(__ok) = (|| #expr)() {
+                return _serde::__private229::Ok(__ok);
             }
         }
     });
 
     quote_block! {
-        let __content = <_serde::__private::de::Content as _serde::Deserialize>::deserialize(__deserializer)?;
-        let __deserializer = _serde::__private::de::ContentRefDeserializer::<__D::Error>::new(&__content);
+        let __content = <_serde::__private229::de::Content as _serde::Deserialize>::deserialize(__deserializer)?;
+        let __deserializer = _serde::__private229::de::ContentRefDeserializer::<__D::Error>::new(&__content);
 
         #first_attempt
 
         #(
-            if let _serde::__private::Ok(__ok) = #attempts {
-                return _serde::__private::Ok(__ok);
+            if let _serde::__private229::Ok(__ok) = #attempts {
+                return _serde::__private229::Ok(__ok);
             }
         )*
 
-        _serde::__private::Err(_serde::de::Error::custom(#fallthrough_msg))
+        _serde::__private229::Err(_serde::de::Error::custom(#fallthrough_msg))
     }
 }
 
@@ -1473,7 +1473,7 @@ fn deserialize_externally_tagged_variant(
         let (wrapper, wrapper_ty, unwrap_fn) = wrap_deserialize_variant_with(params, variant, path);
         return quote_block! {
             #wrapper
-            _serde::__private::Result::map(
+            _serde::__private229::Result::map(
                 _serde::de::VariantAccess::newtype_variant::<#wrapper_ty>(__variant), #unwrap_fn)
         };
     }
@@ -1485,7 +1485,7 @@ fn deserialize_externally_tagged_variant(
             let this_value = &params.this_value;
             quote_block! {
                 _serde::de::VariantAccess::unit_variant(__variant)?;
-                _serde::__private::Ok(#this_value::#variant_ident)
+                _serde::__private229::Ok(#this_value::#variant_ident)
             }
         }
         Style::Newtype => deserialize_externally_tagged_newtype_variant(
@@ -1533,8 +1533,8 @@ fn deserialize_internally_tagged_variant(
                 quote!((#default))
             });
             quote_block! {
-                _serde::Deserializer::deserialize_any(#deserializer, _serde::__private::de::InternallyTaggedUnitVisitor::new(#type_name, #variant_name))?;
-                _serde::__private::Ok(#this_value::#variant_ident #default)
+                _serde::Deserializer::deserialize_any(#deserializer, _serde::__private229::de::InternallyTaggedUnitVisitor::new(#type_name, #variant_name))?;
+                _serde::__private229::Ok(#this_value::#variant_ident #default)
             }
         }
         Style::Newtype => deserialize_untagged_newtype_variant(
@@ -1562,7 +1562,7 @@ fn deserialize_untagged_variant(
     if let Some(path) = variant.attrs.deserialize_with() {
         let unwrap_fn = unwrap_to_variant_closure(params, variant, false);
         return quote_block! {
-            _serde::__private::Result::map(#path(#deserializer), #unwrap_fn)
+            _serde::__private229::Result::map(#path(#deserializer), #unwrap_fn)
         };
     }
 
@@ -1580,10 +1580,10 @@ fn deserialize_untagged_variant(
             quote_expr! {
                 match _serde::Deserializer::deserialize_any(
                     #deserializer,
-                    _serde::__private::de::UntaggedUnitVisitor::new(#type_name, #variant_name)
+                    _serde::__private229::de::UntaggedUnitVisitor::new(#type_name, #variant_name)
                 ) {
-                    _serde::__private::Ok(()) => _serde::__private::Ok(#this_value::#variant_ident #default),
-                    _serde::__private::Err(__err) => _serde::__private::Err(__err),
+                    _serde::__private229::Ok(()) => _serde::__private229::Ok(#this_value::#variant_ident #default),
+                    _serde::__private229::Err(__err) => _serde::__private229::Err(__err),
                 }
             }
         }
@@ -1620,7 +1620,7 @@ fn deserialize_externally_tagged_newtype_variant(
         let default = Expr(expr_is_missing(field, cattrs));
         return quote_block! {
             _serde::de::VariantAccess::unit_variant(__variant)?;
-            _serde::__private::Ok(#this_value::#variant_ident(#default))
+            _serde::__private229::Ok(#this_value::#variant_ident(#default))
         };
     }
 
@@ -1631,7 +1631,7 @@ fn deserialize_externally_tagged_newtype_variant(
             let context = &params.context;
             let func = quote_spanned!(span=> _serde::de::VariantAccess::newtype_variant_seed::<crate::tensor::serialization::Seed<#context, #field_ty>>);
             quote_expr! {
-                _serde::__private::Result::map(#func(__variant, crate::tensor::serialization::Seed::new(self.context)), #this_value::#variant_ident)
+                _serde::__private229::Result::map(#func(__variant, crate::tensor::serialization::Seed::new(self.context)), #this_value::#variant_ident)
             }
         }
         Some(path) => {
@@ -1639,7 +1639,7 @@ fn deserialize_externally_tagged_newtype_variant(
             let context = &params.context;
             quote_block! {
                 #wrapper
-                _serde::__private::Result::map(
+                _serde::__private229::Result::map(
                     _serde::de::VariantAccess::newtype_variant::<crate::tensor::serialization::Seed<#context, #wrapper_ty>>(
                         __variant, crate::tensor::serialization::Seed::new(self.context)),
                     |__wrapper| #this_value::#variant_ident(__wrapper.value))
@@ -1661,13 +1661,13 @@ fn deserialize_untagged_newtype_variant(
             let span = field.original.span();
             let func = quote_spanned!(span=> <#field_ty as _serde::Deserialize>::deserialize);
             quote_expr! {
-                _serde::__private::Result::map(#func(#deserializer), #this_value::#variant_ident)
+                _serde::__private229::Result::map(#func(#deserializer), #this_value::#variant_ident)
             }
         }
         Some(path) => {
             quote_block! {
-                let __value: _serde::__private::Result<#field_ty, _> = #path(#deserializer);
-                _serde::__private::Result::map(__value, #this_value::#variant_ident)
+                let __value: _serde::__private229::Result<#field_ty, _> = #path(#deserializer);
+                _serde::__private229::Result::map(__value, #this_value::#variant_ident)
             }
         }
     }
@@ -1718,7 +1718,7 @@ fn deserialize_generated_identifier(
 
         impl<'de> _serde::Deserialize<'de> for __Field #lifetime {
             #[inline]
-            fn deserialize<__D>(__deserializer: __D) -> _serde::__private::Result<Self, __D::Error>
+            fn deserialize<__D>(__deserializer: __D) -> _serde::__private229::Result<Self, __D::Error>
             where
                 __D: _serde::Deserializer<'de>,
             {
@@ -1735,14 +1735,14 @@ fn deserialize_field_identifier(
     cattrs: &attr::Container,
 ) -> Stmts {
     let (ignore_variant, fallthrough) = if cattrs.has_flatten() {
-        let ignore_variant = quote!(__other(_serde::__private::de::Content<'de>),);
-        let fallthrough = quote!(_serde::__private::Ok(__Field::__other(__value)));
+        let ignore_variant = quote!(__other(_serde::__private229::de::Content<'de>),);
+        let fallthrough = quote!(_serde::__private229::Ok(__Field::__other(__value)));
         (Some(ignore_variant), Some(fallthrough))
     } else if cattrs.deny_unknown_fields() {
         (None, None)
     } else {
         let ignore_variant = quote!(__ignore,);
-        let fallthrough = quote!(_serde::__private::Ok(__Field::__ignore));
+        let fallthrough = quote!(_serde::__private229::Ok(__Field::__ignore));
         (Some(ignore_variant), Some(fallthrough))
     };
 
@@ -1778,15 +1778,15 @@ fn deserialize_custom_identifier(
             // last variant (checked in `check_identifier`), so all preceding
             // are ordinary variants.
             let ordinary = &variants[..variants.len() - 1];
-            let fallthrough = quote!(_serde::__private::Ok(#this_value::#last_ident));
+            let fallthrough = quote!(_serde::__private229::Ok(#this_value::#last_ident));
             (ordinary, Some(fallthrough), None)
         } else if let Style::Newtype = last.style {
             let ordinary = &variants[..variants.len() - 1];
             let fallthrough = |value| {
                 quote! {
-                    _serde::__private::Result::map(
+                    _serde::__private229::Result::map(
                         _serde::Deserialize::deserialize(
-                            _serde::__private::de::IdentifierDeserializer::from(#value)
+                            _serde::__private229::de::IdentifierDeserializer::from(#value)
                         ),
                         #this_value::#last_ident)
                 }
@@ -1794,7 +1794,7 @@ fn deserialize_custom_identifier(
             (
                 ordinary,
                 Some(fallthrough(quote!(__value))),
-                Some(fallthrough(quote!(_serde::__private::de::Borrowed(
+                Some(fallthrough(quote!(_serde::__private229::de::Borrowed(
                     __value
                 )))),
             )
@@ -1852,8 +1852,8 @@ fn deserialize_custom_identifier(
 
         #[doc(hidden)]
         struct __FieldVisitor #de_impl_generics #where_clause {
-            marker: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            marker: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::de::Visitor<#delife> for __FieldVisitor #de_ty_generics #where_clause {
@@ -1863,8 +1863,8 @@ fn deserialize_custom_identifier(
         }
 
         let __visitor = __FieldVisitor {
-            marker: _serde::__private::PhantomData::<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData,
+            marker: _serde::__private229::PhantomData::<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData,
         };
         _serde::Deserializer::deserialize_identifier(__deserializer, __visitor)
     }
@@ -1881,14 +1881,14 @@ fn deserialize_identifier(
 ) -> Fragment {
     let str_mapping = fields.iter().map(|(_, ident, aliases)| {
         // `aliases` also contains a main name
-        quote!(#(#aliases)|* => _serde::__private::Ok(#this_value::#ident))
+        quote!(#(#aliases)|* => _serde::__private229::Ok(#this_value::#ident))
     });
     let bytes_mapping = fields.iter().map(|(_, ident, aliases)| {
         // `aliases` also contains a main name
         let aliases = aliases
             .iter()
             .map(|alias| Literal::byte_string(alias.as_bytes()));
-        quote!(#(#aliases)|* => _serde::__private::Ok(#this_value::#ident))
+        quote!(#(#aliases)|* => _serde::__private229::Ok(#this_value::#ident))
     });
 
     let expecting = expecting.unwrap_or(if is_variant {
@@ -1901,7 +1901,7 @@ fn deserialize_identifier(
         None
     } else {
         Some(quote! {
-            let __value = &_serde::__private::from_utf8_lossy(__value);
+            let __value = &_serde::__private229::from_utf8_lossy(__value);
         })
     };
 
@@ -1913,16 +1913,16 @@ fn deserialize_identifier(
     ) = if collect_other_fields {
         (
             Some(quote! {
-                let __value = _serde::__private::de::Content::String(_serde::__private::ToString::to_string(__value));
+                let __value = _serde::__private229::de::Content::String(_serde::__private229::ToString::to_string(__value));
             }),
             Some(quote! {
-                let __value = _serde::__private::de::Content::Str(__value);
+                let __value = _serde::__private229::de::Content::Str(__value);
             }),
             Some(quote! {
-                let __value = _serde::__private::de::Content::ByteBuf(__value.to_vec());
+                let __value = _serde::__private229::de::Content::ByteBuf(__value.to_vec());
             }),
             Some(quote! {
-                let __value = _serde::__private::de::Content::Bytes(__value);
+                let __value = _serde::__private229::de::Content::Bytes(__value);
             }),
         )
     } else {
@@ -1934,113 +1934,113 @@ fn deserialize_identifier(
         fallthrough
     } else if is_variant {
         fallthrough_arm_tokens = quote! {
-            _serde::__private::Err(_serde::de::Error::unknown_variant(__value, VARIANTS))
+            _serde::__private229::Err(_serde::de::Error::unknown_variant(__value, VARIANTS))
         };
         &fallthrough_arm_tokens
     } else {
         fallthrough_arm_tokens = quote! {
-            _serde::__private::Err(_serde::de::Error::unknown_field(__value, FIELDS))
+            _serde::__private229::Err(_serde::de::Error::unknown_field(__value, FIELDS))
         };
         &fallthrough_arm_tokens
     };
 
     let visit_other = if collect_other_fields {
         quote! {
-            fn visit_bool<__E>(self, __value: bool) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_bool<__E>(self, __value: bool) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::Bool(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::Bool(__value)))
             }
 
-            fn visit_i8<__E>(self, __value: i8) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_i8<__E>(self, __value: i8) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::I8(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::I8(__value)))
             }
 
-            fn visit_i16<__E>(self, __value: i16) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_i16<__E>(self, __value: i16) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::I16(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::I16(__value)))
             }
 
-            fn visit_i32<__E>(self, __value: i32) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_i32<__E>(self, __value: i32) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::I32(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::I32(__value)))
             }
 
-            fn visit_i64<__E>(self, __value: i64) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_i64<__E>(self, __value: i64) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::I64(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::I64(__value)))
             }
 
-            fn visit_u8<__E>(self, __value: u8) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_u8<__E>(self, __value: u8) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::U8(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::U8(__value)))
             }
 
-            fn visit_u16<__E>(self, __value: u16) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_u16<__E>(self, __value: u16) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::U16(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::U16(__value)))
             }
 
-            fn visit_u32<__E>(self, __value: u32) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_u32<__E>(self, __value: u32) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::U32(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::U32(__value)))
             }
 
-            fn visit_u64<__E>(self, __value: u64) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_u64<__E>(self, __value: u64) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::U64(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::U64(__value)))
             }
 
-            fn visit_f32<__E>(self, __value: f32) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_f32<__E>(self, __value: f32) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::F32(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::F32(__value)))
             }
 
-            fn visit_f64<__E>(self, __value: f64) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_f64<__E>(self, __value: f64) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::F64(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::F64(__value)))
             }
 
-            fn visit_char<__E>(self, __value: char) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_char<__E>(self, __value: char) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::Char(__value)))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::Char(__value)))
             }
 
-            fn visit_unit<__E>(self) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_unit<__E>(self) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
-                _serde::__private::Ok(__Field::__other(_serde::__private::de::Content::Unit))
+                _serde::__private229::Ok(__Field::__other(_serde::__private229::de::Content::Unit))
             }
         }
     } else {
         let u64_mapping = fields.iter().enumerate().map(|(i, (_, ident, _))| {
             let i = i as u64;
-            quote!(#i => _serde::__private::Ok(#this_value::#ident))
+            quote!(#i => _serde::__private229::Ok(#this_value::#ident))
         });
 
         let u64_fallthrough_arm_tokens;
@@ -2050,7 +2050,7 @@ fn deserialize_identifier(
             let index_expecting = if is_variant { "variant" } else { "field" };
             let fallthrough_msg = format!("{} index 0 <= i < {}", index_expecting, fields.len());
             u64_fallthrough_arm_tokens = quote! {
-                _serde::__private::Err(_serde::de::Error::invalid_value(
+                _serde::__private229::Err(_serde::de::Error::invalid_value(
                     _serde::de::Unexpected::Unsigned(__value),
                     &#fallthrough_msg,
                 ))
@@ -2059,7 +2059,7 @@ fn deserialize_identifier(
         };
 
         quote! {
-            fn visit_u64<__E>(self, __value: u64) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_u64<__E>(self, __value: u64) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
@@ -2076,7 +2076,7 @@ fn deserialize_identifier(
         let bytes_mapping = bytes_mapping.clone();
         let fallthrough_borrowed_arm = fallthrough_borrowed.as_ref().unwrap_or(fallthrough_arm);
         Some(quote! {
-            fn visit_borrowed_str<__E>(self, __value: &'de str) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_borrowed_str<__E>(self, __value: &'de str) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
@@ -2089,7 +2089,7 @@ fn deserialize_identifier(
                 }
             }
 
-            fn visit_borrowed_bytes<__E>(self, __value: &'de [u8]) -> _serde::__private::Result<Self::Value, __E>
+            fn visit_borrowed_bytes<__E>(self, __value: &'de [u8]) -> _serde::__private229::Result<Self::Value, __E>
             where
                 __E: _serde::de::Error,
             {
@@ -2108,13 +2108,13 @@ fn deserialize_identifier(
     };
 
     quote_block! {
-        fn expecting(&self, __formatter: &mut _serde::__private::Formatter) -> _serde::__private::fmt::Result {
-            _serde::__private::Formatter::write_str(__formatter, #expecting)
+        fn expecting(&self, __formatter: &mut _serde::__private229::Formatter) -> _serde::__private229::fmt::Result {
+            _serde::__private229::Formatter::write_str(__formatter, #expecting)
         }
 
         #visit_other
 
-        fn visit_str<__E>(self, __value: &str) -> _serde::__private::Result<Self::Value, __E>
+        fn visit_str<__E>(self, __value: &str) -> _serde::__private229::Result<Self::Value, __E>
         where
             __E: _serde::de::Error,
         {
@@ -2127,7 +2127,7 @@ fn deserialize_identifier(
             }
         }
 
-        fn visit_bytes<__E>(self, __value: &[u8]) -> _serde::__private::Result<Self::Value, __E>
+        fn visit_bytes<__E>(self, __value: &[u8]) -> _serde::__private229::Result<Self::Value, __E>
         where
             __E: _serde::de::Error,
         {
@@ -2165,16 +2165,16 @@ fn deserialize_map(
         .map(|(field, name)| {
             let field_ty = field.ty;
             quote! {
-                let mut #name: _serde::__private::Option<#field_ty> = _serde::__private::None;
+                let mut #name: _serde::__private229::Option<#field_ty> = _serde::__private229::None;
             }
         });
 
     // Collect contents for flatten fields into a buffer
     let let_collect = if cattrs.has_flatten() {
         Some(quote! {
-            let mut __collect = _serde::__private::Vec::<_serde::__private::Option<(
-                _serde::__private::de::Content,
-                _serde::__private::de::Content
+            let mut __collect = _serde::__private229::Vec::<_serde::__private229::Option<(
+                _serde::__private229::de::Content,
+                _serde::__private229::de::Content
             )>>::new();
         })
     } else {
@@ -2205,9 +2205,9 @@ fn deserialize_map(
                     quote!({
                         #wrapper
                         match _serde::de::MapAccess::next_value_seed::<crate::tensor::serialization::Seed<#context, #wrapper_ty>>(&mut __map, crate::tensor::serialization::Seed::new(self.context)) {
-                            _serde::__private::Ok(__wrapper) => __wrapper.value,
-                            _serde::__private::Err(__err) => {
-                                return _serde::__private::Err(__err);
+                            _serde::__private229::Ok(__wrapper) => __wrapper.value,
+                            _serde::__private229::Err(__err) => {
+                                return _serde::__private229::Err(__err);
                             }
                         }
                     })
@@ -2215,10 +2215,10 @@ fn deserialize_map(
             };
             quote! {
                 __Field::#name => {
-                    if _serde::__private::Option::is_some(&#name) {
-                        return _serde::__private::Err(<__A::Error as _serde::de::Error>::duplicate_field(#deser_name));
+                    if _serde::__private229::Option::is_some(&#name) {
+                        return _serde::__private229::Err(<__A::Error as _serde::de::Error>::duplicate_field(#deser_name));
                     }
-                    #name = _serde::__private::Some(#visit);
+                    #name = _serde::__private229::Some(#visit);
                 }
             }
         });
@@ -2227,7 +2227,7 @@ fn deserialize_map(
     let ignored_arm = if cattrs.has_flatten() {
         Some(quote! {
             __Field::__other(__name) => {
-                __collect.push(_serde::__private::Some((
+                __collect.push(_serde::__private229::Some((
                     __name,
                     _serde::de::MapAccess::next_value(&mut __map)?)));
             }
@@ -2244,14 +2244,14 @@ fn deserialize_map(
     let match_keys = if cattrs.deny_unknown_fields() && all_skipped {
         quote! {
             // FIXME: Once feature(exhaustive_patterns) is stable:
-            // let _serde::__private::None::<__Field> = _serde::de::MapAccess::next_key(&mut __map)?;
-            _serde::__private::Option::map(
+            // let _serde::__private229::None::<__Field> = _serde::de::MapAccess::next_key(&mut __map)?;
+            _serde::__private229::Option::map(
                 _serde::de::MapAccess::next_key::<__Field>(&mut __map)?,
                 |__impossible| match __impossible {});
         }
     } else {
         quote! {
-            while let _serde::__private::Some(__key) = _serde::de::MapAccess::next_key::<__Field>(&mut __map)? {
+            while let _serde::__private229::Some(__key) = _serde::de::MapAccess::next_key::<__Field>(&mut __map)? {
                 match __key {
                     #(#value_arms)*
                     #ignored_arm
@@ -2268,8 +2268,8 @@ fn deserialize_map(
 
             quote! {
                 let #name = match #name {
-                    _serde::__private::Some(#name) => #name,
-                    _serde::__private::None => #missing_expr
+                    _serde::__private229::Some(#name) => #name,
+                    _serde::__private229::None => #missing_expr
                 };
             }
         });
@@ -2288,22 +2288,22 @@ fn deserialize_map(
             };
             quote! {
                 let #name: #field_ty = #func(
-                    _serde::__private::de::FlatMapDeserializer(
+                    _serde::__private229::de::FlatMapDeserializer(
                         &mut __collect,
-                        _serde::__private::PhantomData))?;
+                        _serde::__private229::PhantomData))?;
             }
         });
 
     let collected_deny_unknown_fields = if cattrs.has_flatten() && cattrs.deny_unknown_fields() {
         Some(quote! {
-            if let _serde::__private::Some(_serde::__private::Some((__key, _))) =
-                __collect.into_iter().filter(_serde::__private::Option::is_some).next()
+            if let _serde::__private229::Some(_serde::__private229::Some((__key, _))) =
+                __collect.into_iter().filter(_serde::__private229::Option::is_some).next()
             {
-                if let _serde::__private::Some(__key) = __key.as_str() {
-                    return _serde::__private::Err(
+                if let _serde::__private229::Some(__key) = __key.as_str() {
+                    return _serde::__private229::Err(
                         _serde::de::Error::custom(format_args!("unknown field `{}`", &__key)));
                 } else {
-                    return _serde::__private::Err(
+                    return _serde::__private229::Err(
                         _serde::de::Error::custom(format_args!("unexpected map key")));
                 }
             }
@@ -2324,7 +2324,7 @@ fn deserialize_map(
 
     let let_default = match cattrs.default() {
         attr::Default::Default => Some(quote!(
-            let __default: Self::Value = _serde::__private::Default::default();
+            let __default: Self::Value = _serde::__private229::Default::default();
         )),
         attr::Default::Path(path) => Some(quote!(
             let __default: Self::Value = #path();
@@ -2341,7 +2341,7 @@ fn deserialize_map(
         let this_type = &params.this_type;
         let (_, ty_generics, _) = params.generics.split_for_impl();
         result = quote! {
-            _serde::__private::Into::<#this_type #ty_generics>::into(#result)
+            _serde::__private229::Into::<#this_type #ty_generics>::into(#result)
         };
     }
 
@@ -2360,7 +2360,7 @@ fn deserialize_map(
 
         #collected_deny_unknown_fields
 
-        _serde::__private::Ok(#result)
+        _serde::__private229::Ok(#result)
     }
 }
 
@@ -2384,19 +2384,19 @@ fn wrap_deserialize_with(
         #[doc(hidden)]
         struct __DeserializeWith #de_impl_generics #where_clause {
             value: #value_ty,
-            phantom: _serde::__private::PhantomData<#this_type #ty_generics>,
-            lifetime: _serde::__private::PhantomData<&#delife ()>,
+            phantom: _serde::__private229::PhantomData<#this_type #ty_generics>,
+            lifetime: _serde::__private229::PhantomData<&#delife ()>,
         }
 
         impl #de_impl_generics _serde::Deserialize<#delife> for __DeserializeWith #de_ty_generics #where_clause {
-            fn deserialize<__D>(__deserializer: __D) -> _serde::__private::Result<Self, __D::Error>
+            fn deserialize<__D>(__deserializer: __D) -> _serde::__private229::Result<Self, __D::Error>
             where
                 __D: _serde::Deserializer<#delife>,
             {
-                _serde::__private::Ok(__DeserializeWith {
+                _serde::__private229::Ok(__DeserializeWith {
                     value: #deserialize_with(__deserializer)?,
-                    phantom: _serde::__private::PhantomData,
-                    lifetime: _serde::__private::PhantomData,
+                    phantom: _serde::__private229::PhantomData,
+                    lifetime: _serde::__private229::PhantomData,
                 })
             }
         }
@@ -2481,7 +2481,7 @@ fn expr_is_missing(field: &Field, cattrs: &attr::Container) -> Fragment {
     match field.attrs.default() {
         attr::Default::Default => {
             let span = field.original.span();
-            let func = quote_spanned!(span=> _serde::__private::Default::default);
+            let func = quote_spanned!(span=> _serde::__private229::Default::default);
             return quote_expr!(#func());
         }
         attr::Default::Path(path) => {
@@ -2502,19 +2502,19 @@ fn expr_is_missing(field: &Field, cattrs: &attr::Container) -> Fragment {
     // match field.attrs.deserialize_with() {
     //     None => {
     //         let span = field.original.span();
-    //         let func = quote_spanned!(span=> _serde::__private::de::missing_field);
+    //         let func = quote_spanned!(span=> _serde::__private229::de::missing_field);
     //         quote_expr! {
     //             #func(#name)?
     //         }
     //     }
     //     Some(_) => {
     //         quote_expr! {
-    //             return _serde::__private::Err(<__A::Error as _serde::de::Error>::missing_field(#name))
+    //             return _serde::__private229::Err(<__A::Error as _serde::de::Error>::missing_field(#name))
     //         }
     //     }
     // }
     quote_expr! {
-        return _serde::__private::Err(<__A::Error as _serde::de::Error>::missing_field(#name))
+        return _serde::__private229::Err(<__A::Error as _serde::de::Error>::missing_field(#name))
     }
 }
 
@@ -2528,7 +2528,7 @@ fn expr_is_missing_seq(
     match field.attrs.default() {
         attr::Default::Default => {
             let span = field.original.span();
-            return quote_spanned!(span=> #assign_to _serde::__private::Default::default());
+            return quote_spanned!(span=> #assign_to _serde::__private229::Default::default());
         }
         attr::Default::Path(path) => {
             return quote_spanned!(path.span()=> #assign_to #path());
@@ -2542,7 +2542,7 @@ fn expr_is_missing_seq(
             quote!(#assign_to __default.#member)
         }
         attr::Default::None => quote!(
-            return _serde::__private::Err(_serde::de::Error::invalid_length(#index, &#expecting))
+            return _serde::__private229::Err(_serde::de::Error::invalid_length(#index, &#expecting))
         ),
     }
 }
@@ -0,0 +1,150 @@
+use std::{collections::HashMap, fs::File, path::PathBuf};
+
+use anyhow::Result;
+use clap::Parser;
+use itertools::Itertools;
+use memmap2::Mmap;
+use safetensors::{Dtype, SafeTensors};
+use web_rwkv::runtime::{
+    loader::Loader,
+    model::{EmbedDevice, ModelInfo, Quant},
+    prompt::PromptRecommendation,
+};
+
+/// Print what a model file contains and whether it can be loaded, e.g., to attach to a bug report.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(short, long, value_name = "FILE")]
+    model: PathBuf,
+    /// List the tensors of all layers instead of only the first.
+    #[arg(short, long, action)]
+    all: bool,
+}
+
+fn format_size(size: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+fn print_plans(info: &ModelInfo) {
+    let layers = info.num_layer;
+    let plans = [
+        ("fp16", Quant::None, 0),
+        ("int8, all layers", Quant::Int8, layers),
+        ("nf4, all layers", Quant::NF4, layers),
+        ("fp8, all layers", Quant::Fp8E4M3, layers),
+        ("q4_k, all layers", Quant::Q4K, layers),
+        ("int8, half of the layers", Quant::Int8, layers / 2),
+    ];
+    println!("estimated weights on device (embed on cpu / gpu):");
+    for (name, quant, num_layer) in plans {
+        let quant: HashMap<_, _> = (0..num_layer).map(|layer| (layer, quant)).collect();
+        let cpu = info.estimate_size(&quant, EmbedDevice::Cpu);
+        let gpu = info.estimate_size(&quant, EmbedDevice::Gpu);
+        println!(
+            "  {name:<28}{:>12} / {}",
+            format_size(cpu),
+            format_size(gpu)
+        );
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let file = File::open(&cli.model)?;
+    let data = unsafe { Mmap::map(&file)? };
+    println!(
+        "file: {} ({})",
+        cli.model.display(),
+        format_size(data.len())
+    );
+
+    let (_, metadata) = SafeTensors::read_metadata(&data)?;
+    if let Some(metadata) = metadata.metadata() {
+        println!("metadata:");
+        for (key, value) in metadata.iter().sorted() {
+            println!("  {key}: {value}");
+        }
+    }
+
+    let model = SafeTensors::deserialize(&data)?;
+    let tensors = model.tensors();
+    let tensors = tensors
+        .iter()
+        .sorted_by_key(|(name, _)| {
+            // sort layers by number rather than by text
+            let layer = name
+                .strip_prefix("blocks.")
+                .and_then(|x| x.split('.').next())
+                .and_then(|x| x.parse::<usize>().ok());
+            (layer, name.clone())
+        })
+        .collect_vec();
+
+    println!("tensors: {}", tensors.len());
+    let mut hidden = 0;
+    for (name, tensor) in &tensors {
+        let layer = name
+            .strip_prefix("blocks.")
+            .and_then(|x| x.split('.').next())
+            .and_then(|x| x.parse::<usize>().ok());
+        if !cli.all && layer.is_some_and(|layer| layer > 0) {
+            hidden += 1;
+            continue;
+        }
+        println!(
+            "  {name:<40}{:<8}{:?}",
+            format!("{:?}", tensor.dtype()),
+            tensor.shape()
+        );
+    }
+    if hidden > 0 {
+        println!("  ... {hidden} tensors of other layers, use `--all` to list them");
+    }
+
+    let unsupported = tensors
+        .iter()
+        .filter(|(_, tensor)| tensor.dtype() != Dtype::F16)
+        .map(|(name, tensor)| format!("{name} ({:?})", tensor.dtype()))
+        .collect_vec();
+    if !unsupported.is_empty() {
+        println!("warning: only f16 tensors can be loaded, but found:");
+        for name in unsupported {
+            println!("  {name}");
+        }
+    }
+
+    match Loader::info(&model) {
+        Ok(info) => {
+            println!("{info:#?}");
+            print_plans(&info);
+
+            let name = cli
+                .model
+                .file_stem()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            let recommendation =
+                PromptRecommendation::detect(&info, &name, metadata.metadata().as_ref());
+            let PromptRecommendation {
+                format,
+                template,
+                source,
+            } = recommendation;
+            println!(
+                "prompt format: {format:?} ({source:?}), e.g., {:?}",
+                template.query("Hi!")
+            );
+        }
+        Err(err) => println!("error: not a supported model: {err}"),
+    }
+    Ok(())
+}
@@ -22,7 +22,11 @@ use web_rwkv::{
         v5, Build, BuildFuture, ContextAutoLimits, Model, ModelBuilder, ModelInfo, ModelInput,
         ModelOutput, ModelState, ModelVersion, Quant, StateBuilder,
     },
-    tensor::{kind::ReadWrite, ops::TensorOp, TensorError, TensorGpu, TensorShape},
+    tensor::{
+        kind::ReadWrite,
+        ops::{Activation, TensorOp},
+        TensorError, TensorGpu, TensorShape,
+    },
     tokenizer::Tokenizer,
 };
 
@@ -80,7 +84,7 @@ async fn create_context(info: &ModelInfo, _auto: bool) -> Result<Context> {
         .auto_limits(info)
         .build()
         .await?;
-    println!("{:#?}", context.adapter.get_info());
+    println!("{:#?}", context.report());
     Ok(context)
 }
 
@@ -251,7 +255,7 @@ async fn run(cli: Cli) -> Result<()> {
         tensor.head.w.matmul_mat_op(
             buffer.ffn_x.view(.., .., .., ..)?,
             buffer.out.view(.., .., .., ..)?,
-            Default::default(),
+            Activation::None,
         )?,
     ];
     context.queue.submit(context.encode(&TensorOp::List(ops)));
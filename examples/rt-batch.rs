@@ -132,8 +132,8 @@ struct Cli {
     turbo: bool,
     #[arg(short, long)]
     embed_device: Option<EmbedDevice>,
-    #[arg(long, default_value_t = 128)]
-    token_chunk_size: usize,
+    #[arg(long)]
+    token_chunk_size: Option<usize>,
     #[arg(short, long, default_value_t = 4)]
     batch: usize,
     #[arg(short, long, action)]
@@ -160,7 +160,7 @@ async fn main() -> Result<()> {
     log::info!("{:#?}", info);
 
     let context = create_context(&info, cli.adapter).await?;
-    log::info!("{:#?}", context.adapter.get_info());
+    log::info!("{:#?}", context.report());
 
     let quant = (0..cli.quant)
         .map(|layer| (layer, Quant::Int8))
@@ -185,7 +185,12 @@ async fn main() -> Result<()> {
         Some(data) => {
             let data = SafeTensors::deserialize(data)?;
             let blend = Default::default();
-            let lora = Lora { data, blend };
+            let placement = Default::default();
+            let lora = Lora {
+                data,
+                blend,
+                placement,
+            };
             builder.lora(lora)
         }
         None => builder,
@@ -234,11 +239,12 @@ async fn main() -> Result<()> {
         tokens
             .into_iter()
             .map(|tokens| InferInputBatch {
-                tokens,
+                tokens: tokens.into(),
                 ..Default::default()
             })
             .collect(),
-        cli.token_chunk_size,
+        cli.token_chunk_size
+            .unwrap_or(context.tuning().token_chunk_size),
     );
 
     let mut num_token =
@@ -308,7 +314,7 @@ async fn main() -> Result<()> {
                 let token = sample(&batch, 0.5);
                 let decoded = tokenizer.decode(&[token])?;
                 let word = String::from_utf8_lossy(&decoded);
-                inference.batches[index].tokens = vec![token];
+                inference.batches[index].tokens = vec![token].into();
                 prompts[index].push_str(&word);
                 num_token[index] -= 1;
             }
@@ -22,11 +22,12 @@ use web_rwkv::{
             Build, ContextAutoLimits, ModelBuilder, ModelInfo, ModelRuntime, ModelVersion, Quant,
             State,
         },
+        prompt::{ChatTemplate, PromptFormat, PromptRecommendation},
         softmax::softmax_one,
         v4, v5, v6, JobRuntime,
     },
     tensor::{TensorCpu, TensorInit, TensorShape},
-    tokenizer::Tokenizer,
+    tokenizer::{TextAssembler, Tokenizer},
 };
 
 async fn create_context(info: &ModelInfo, _auto: bool) -> Result<Context> {
@@ -70,7 +71,7 @@ async fn load_tokenizer() -> Result<Tokenizer> {
     Ok(Tokenizer::new(&contents)?)
 }
 
-async fn load_prompt(path: Option<PathBuf>) -> Result<Prompt> {
+async fn load_prompt(path: Option<PathBuf>, template: &ChatTemplate) -> Result<Prompt> {
     match path {
         Some(path) => {
             let file = File::open(path).await?;
@@ -80,8 +81,8 @@ async fn load_prompt(path: Option<PathBuf>) -> Result<Prompt> {
             Ok(serde_json::from_str(&contents)?)
         }
         None => Ok(Prompt {
-            user: String::from("User"),
-            bot: String::from("Assistant"),
+            user: template.user.clone(),
+            bot: template.bot.clone(),
             intro: String::new(),
             text: vec![
                 [
@@ -130,6 +131,9 @@ struct Cli {
     adapter: bool,
     #[arg(short, long, value_name = "FILE")]
     prompt: Option<PathBuf>,
+    /// Prompt format (`world`, `raven` or `instruct`). Detected from the model if not set.
+    #[arg(short, long)]
+    format: Option<PromptFormat>,
     #[command(flatten)]
     sampler: Sampler,
 }
@@ -219,15 +223,26 @@ async fn main() -> Result<()> {
 
     let tokenizer = load_tokenizer().await?;
 
+    let name = cli
+        .model
+        .file_stem()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
     let file = File::open(cli.model).await?;
     let data = unsafe { Mmap::map(&file)? };
 
+    let (_, metadata) = SafeTensors::read_metadata(&data)?;
     let model = SafeTensors::deserialize(&data)?;
     let info = Loader::info(&model)?;
     log::info!("{:#?}", info);
 
+    let recommendation = PromptRecommendation::detect(&info, &name, metadata.metadata().as_ref())
+        .or_override(cli.format);
+    log::info!("{:?}", recommendation);
+    let template = recommendation.template;
+
     let context = create_context(&info, cli.adapter).await?;
-    log::info!("{:#?}", context.adapter.get_info());
+    log::info!("{:#?}", context.report());
 
     let quant = (0..cli.quant)
         .map(|layer| (layer, Quant::Int8))
@@ -252,7 +267,12 @@ async fn main() -> Result<()> {
         Some(data) => {
             let data = SafeTensors::deserialize(data)?;
             let blend = Default::default();
-            let lora = Lora { data, blend };
+            let placement = Default::default();
+            let lora = Lora {
+                data,
+                blend,
+                placement,
+            };
             builder.lora(lora)
         }
         None => builder,
@@ -280,11 +300,12 @@ async fn main() -> Result<()> {
     };
 
     // run initial prompt
-    let prompt = load_prompt(cli.prompt).await?;
+    let prompt = load_prompt(cli.prompt, &template).await?;
     let mut inference = InferInput::new(
         vec![InferInputBatch {
-            tokens: tokenizer.encode(prompt.build().as_bytes())?,
+            tokens: tokenizer.encode(prompt.build().as_bytes())?.into(),
             option: InferOption::Last,
+            ..Default::default()
         }],
         cli.token_chunk_size,
     );
@@ -311,7 +332,7 @@ async fn main() -> Result<()> {
     let mut last_tokens = vec![];
 
     loop {
-        let mut model_text = String::new();
+        let mut model_text = TextAssembler::default();
         let mut user_text = String::new();
 
         print!("{}: ", prompt.user);
@@ -327,14 +348,15 @@ async fn main() -> Result<()> {
             "+" => {
                 user_text.clone_from(&last_user_text);
                 inference.batches[0] = InferInputBatch {
-                    tokens: last_tokens.clone(),
+                    tokens: last_tokens.clone().into(),
                     option: InferOption::Last,
+                    ..Default::default()
                 };
                 state.load(backed.clone(), 0)?;
             }
             _ => {
                 last_user_text.clone_from(&user_text);
-                last_tokens.clone_from(&inference.batches[0].tokens);
+                last_tokens = inference.batches[0].tokens.to_vec();
                 backed = state.back(0).await?;
             }
         }
@@ -343,9 +365,12 @@ async fn main() -> Result<()> {
         std::io::stdout().flush()?;
 
         let prompt = format!("{}: {}\n\n{}:", prompt.user, user_text, prompt.bot);
-        inference.batches[0]
-            .tokens
-            .append(&mut tokenizer.encode(prompt.as_bytes())?);
+        let tokens = [
+            &inference.batches[0].tokens[..],
+            &tokenizer.encode(prompt.as_bytes())?,
+        ]
+        .concat();
+        inference.batches[0].tokens = tokens.into();
 
         loop {
             let input = inference.clone();
@@ -366,19 +391,18 @@ async fn main() -> Result<()> {
             let output = softmax_one(&context, output).await?;
 
             let token = cli.sampler.sample(&output);
-            let decoded = tokenizer.decode(&[token])?;
-            let word = String::from_utf8_lossy(&decoded);
-
-            model_text += &word;
-            print!("{}", word);
+            // characters split across tokens are printed once complete
+            let range = model_text.push(&tokenizer, token)?;
+            print!("{}", &model_text.text()[range]);
             std::io::stdout().flush()?;
 
             inference.batches[0] = InferInputBatch {
-                tokens: vec![token],
+                tokens: vec![token].into(),
                 option: InferOption::Last,
+                ..Default::default()
             };
 
-            if model_text.contains("\n\n") {
+            if template.cut(model_text.text()).is_some() || template.stop_tokens.contains(&token) {
                 break;
             }
         }
@@ -0,0 +1,161 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use memmap2::Mmap;
+use safetensors::SafeTensors;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, BufReader},
+};
+use web_rwkv::{
+    context::{Context, ContextBuilder, InstanceExt},
+    runtime::{
+        dump::ActivationDump,
+        infer::{InferInput, InferInputBatch, InferOption},
+        loader::Loader,
+        model::{Build, ContextAutoLimits, ModelBuilder, ModelInfo, ModelVersion},
+        v4, v5, v6, JobInput, JobRuntime,
+    },
+    tokenizer::Tokenizer,
+};
+
+async fn create_context(info: &ModelInfo) -> Result<Context> {
+    let instance = wgpu::Instance::default();
+    let adapter = instance
+        .adapter(wgpu::PowerPreference::HighPerformance)
+        .await?;
+    let context = ContextBuilder::new(adapter)
+        .auto_limits(info)
+        .build()
+        .await?;
+    Ok(context)
+}
+
+async fn load_tokenizer() -> Result<Tokenizer> {
+    let file = File::open("assets/rwkv_vocab_v20230424.json").await?;
+    let mut reader = BufReader::new(file);
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents).await?;
+    Ok(Tokenizer::new(&contents)?)
+}
+
+/// Capture the embed output and the output of every `att` and `ffn` block.
+macro_rules! hooks {
+    ($version:ident, $dump:expr, $num_layer:expr) => {{
+        let mut hooks = $version::HookMap::<f32>::default();
+        let dump = $dump.clone();
+        hooks.insert(
+            $version::Hook::PostEmbedLayerNorm,
+            Box::new(move |frame: $version::Frame<f32>| dump.capture("emb", &frame.buffer.x)),
+        );
+        for layer in 0..$num_layer {
+            for (hook, name) in [
+                ($version::Hook::PostAtt(layer), "att"),
+                ($version::Hook::PostFfn(layer), "ffn"),
+            ] {
+                let dump = $dump.clone();
+                hooks.insert(
+                    hook,
+                    Box::new(move |frame: $version::Frame<f32>| {
+                        dump.capture(format!("blocks.{layer}.{name}"), &frame.buffer.x)
+                    }),
+                );
+            }
+        }
+        hooks
+    }};
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(short, long, value_name = "FILE")]
+    model: PathBuf,
+    /// Text to run through the model.
+    #[arg(short, long, value_name = "FILE")]
+    prompt: Option<PathBuf>,
+    /// Directory to write one safetensors file per step into.
+    #[arg(short, long, value_name = "DIR")]
+    output: PathBuf,
+    #[arg(long, default_value_t = 32)]
+    token_chunk_size: usize,
+    /// Only dump every this many steps.
+    #[arg(long, default_value_t = 1)]
+    interval: usize,
+    /// Stop dumping after this many steps.
+    #[arg(long)]
+    limit: Option<usize>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Warn)
+        .with_module_level("web_rwkv", log::LevelFilter::Info)
+        .with_module_level("rt_dump", log::LevelFilter::Info)
+        .init()?;
+    let cli = Cli::parse();
+
+    let tokenizer = load_tokenizer().await?;
+    let prompt = match cli.prompt {
+        Some(path) => tokio::fs::read_to_string(path).await?,
+        None => include_str!("prompt.md").into(),
+    };
+    let tokens = tokenizer.encode(prompt.as_bytes())?;
+
+    let file = File::open(cli.model).await?;
+    let data = unsafe { Mmap::map(&file)? };
+
+    let model = SafeTensors::deserialize(&data)?;
+    let info = Loader::info(&model)?;
+    log::info!("{:#?}", info);
+
+    let context = create_context(&info).await?;
+    let builder = ModelBuilder::new(&context, model);
+
+    let dump = ActivationDump::new(&context, cli.token_chunk_size)
+        .interval(cli.interval)
+        .limit(cli.limit);
+    let runtime = match info.version {
+        ModelVersion::V4 => {
+            let model = Build::<v4::Model>::build(builder).await?;
+            let hooks = hooks!(v4, dump, info.num_layer);
+            JobRuntime::new(v4::ModelRuntime::<f32>::new_with_hooks(model, 1, hooks)).await
+        }
+        ModelVersion::V5 => {
+            let model = Build::<v5::Model>::build(builder).await?;
+            let hooks = hooks!(v5, dump, info.num_layer);
+            JobRuntime::new(v5::ModelRuntime::<f32>::new_with_hooks(model, 1, hooks)).await
+        }
+        ModelVersion::V6 => {
+            let model = Build::<v6::Model>::build(builder).await?;
+            let hooks = hooks!(v6, dump, info.num_layer);
+            JobRuntime::new(v6::ModelRuntime::<f32>::new_with_hooks(model, 1, hooks)).await
+        }
+    };
+
+    tokio::fs::create_dir_all(&cli.output).await?;
+
+    let batch = InferInputBatch {
+        tokens: tokens.into(),
+        option: InferOption::Last,
+        ..Default::default()
+    };
+    let mut input = InferInput::new(vec![batch], cli.token_chunk_size);
+    let mut step = 0;
+    while input.num_token() > 0 {
+        let num_token = input.chunk().num_token();
+        let (next, _) = runtime.infer(input).await;
+        input = next;
+
+        if let Some(data) = dump.dump(step, num_token).await? {
+            let path = cli.output.join(format!("step-{step:04}.st"));
+            tokio::fs::write(&path, data).await?;
+            log::info!("{}: {} tokens", path.display(), num_token);
+        }
+        step += 1;
+    }
+
+    Ok(())
+}
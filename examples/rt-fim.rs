@@ -0,0 +1,144 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use half::f16;
+use memmap2::Mmap;
+use safetensors::SafeTensors;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, BufReader},
+};
+use web_rwkv::{
+    context::{Context, ContextBuilder, InstanceExt},
+    runtime::{
+        fim::{FimLayout, FimSession, FimTokens},
+        loader::Loader,
+        model::{Build, ContextAutoLimits, ModelBuilder, ModelInfo, ModelRuntime, ModelVersion},
+        v4, v5, v6, JobRuntime,
+    },
+    tokenizer::Tokenizer,
+};
+
+fn sample(logits: &[f32]) -> u16 {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, x), (_, y)| x.total_cmp(y))
+        .unwrap()
+        .0 as u16
+}
+
+async fn create_context(info: &ModelInfo) -> Result<Context> {
+    let instance = wgpu::Instance::default();
+    let adapter = instance
+        .adapter(wgpu::PowerPreference::HighPerformance)
+        .await?;
+    let context = ContextBuilder::new(adapter)
+        .auto_limits(info)
+        .build()
+        .await?;
+    Ok(context)
+}
+
+async fn load_tokenizer() -> Result<Tokenizer> {
+    let file = File::open("assets/rwkv_vocab_v20230424.json").await?;
+    let mut reader = BufReader::new(file);
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents).await?;
+    Ok(Tokenizer::new(&contents)?)
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(short, long, value_name = "FILE")]
+    model: PathBuf,
+    /// Token id of the FIM prefix marker the model was trained with.
+    #[arg(long, value_name = "TOKEN")]
+    fim_prefix: u16,
+    /// Token id of the FIM suffix marker.
+    #[arg(long, value_name = "TOKEN")]
+    fim_suffix: u16,
+    /// Token id of the FIM middle marker.
+    #[arg(long, value_name = "TOKEN")]
+    fim_middle: u16,
+    /// Token ids that end the middle.
+    #[arg(long, value_name = "TOKEN", default_values_t = [0])]
+    stop: Vec<u16>,
+    /// Put the suffix first, so that its state is reused across completions.
+    #[arg(long, action)]
+    spm: bool,
+    #[arg(long, default_value_t = 128)]
+    max_token: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Warn)
+        .with_module_level("web_rwkv", log::LevelFilter::Info)
+        .with_module_level("rt_fim", log::LevelFilter::Info)
+        .init()?;
+    let cli = Cli::parse();
+
+    let tokenizer = load_tokenizer().await?;
+
+    let file = File::open(cli.model).await?;
+    let data = unsafe { Mmap::map(&file)? };
+
+    let model = SafeTensors::deserialize(&data)?;
+    let info = Loader::info(&model)?;
+    log::info!("{:#?}", info);
+
+    let context = create_context(&info).await?;
+    let builder = ModelBuilder::new(&context, model);
+
+    let tokens = FimTokens {
+        prefix: cli.fim_prefix,
+        suffix: cli.fim_suffix,
+        middle: cli.fim_middle,
+        stop: cli.stop,
+    };
+    let session = match info.version {
+        ModelVersion::V4 => {
+            let model = Build::<v4::Model>::build(builder).await?;
+            let builder = v4::ModelRuntime::<f16>::new(model, 1);
+            let state = builder.state();
+            FimSession::new(JobRuntime::new(builder).await, state, 0, tokens)
+        }
+        ModelVersion::V5 => {
+            let model = Build::<v5::Model>::build(builder).await?;
+            let builder = v5::ModelRuntime::<f16>::new(model, 1);
+            let state = builder.state();
+            FimSession::new(JobRuntime::new(builder).await, state, 0, tokens)
+        }
+        ModelVersion::V6 => {
+            let model = Build::<v6::Model>::build(builder).await?;
+            let builder = v6::ModelRuntime::<f16>::new(model, 1);
+            let state = builder.state();
+            FimSession::new(JobRuntime::new(builder).await, state, 0, tokens)
+        }
+    };
+    let layout = match cli.spm {
+        true => FimLayout::Spm,
+        false => FimLayout::Psm,
+    };
+    let mut session = session.layout(layout).suffix_overlap(4);
+
+    const PREFIX: &str = "fn fibonacci(n: u64) -> u64 {\n";
+    const SUFFIX: &str = "}\n\nfn main() {\n    println!(\"{}\", fibonacci(10));\n}\n";
+    let prefix = tokenizer.encode(PREFIX.as_bytes())?;
+    let suffix = tokenizer.encode(SUFFIX.as_bytes())?;
+
+    let output = session
+        .complete(&prefix, &suffix, cli.max_token, sample)
+        .await?;
+    let middle = tokenizer.decode(&output.tokens)?;
+    print!("{PREFIX}");
+    print!("\x1b[32m{}\x1b[0m", String::from_utf8_lossy(&middle));
+    print!("{SUFFIX}");
+    log::info!("stopped by {:?}", output.stop);
+
+    Ok(())
+}
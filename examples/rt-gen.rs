@@ -105,12 +105,14 @@ struct Cli {
     quant: usize,
     #[arg(long, value_name = "LAYERS", default_value_t = 0)]
     quant_nf4: usize,
+    #[arg(long, value_name = "LAYERS", default_value_t = 0)]
+    quant_fp8: usize,
     #[arg(short, long, action)]
     turbo: bool,
     #[arg(short, long)]
     embed_device: Option<EmbedDevice>,
-    #[arg(long, default_value_t = 128)]
-    token_chunk_size: usize,
+    #[arg(long)]
+    token_chunk_size: Option<usize>,
     #[arg(short, long, action)]
     adapter: bool,
 }
@@ -140,11 +142,12 @@ async fn main() -> Result<()> {
     log::info!("{:#?}", info);
 
     let context = create_context(&info, cli.adapter).await?;
-    log::info!("{:#?}", context.adapter.get_info());
+    log::info!("{:#?}", context.report());
 
     let quant = (0..cli.quant)
         .map(|layer| (layer, Quant::Int8))
         .chain((0..cli.quant_nf4).map(|layer| (layer, Quant::NF4)))
+        .chain((0..cli.quant_fp8).map(|layer| (layer, Quant::Fp8E4M3)))
         .collect();
     let embed_device = cli.embed_device.unwrap_or(EmbedDevice::Cpu).into();
     let lora = match cli.lora {
@@ -165,7 +168,12 @@ async fn main() -> Result<()> {
         Some(data) => {
             let data = SafeTensors::deserialize(data)?;
             let blend = Default::default();
-            let lora = Lora { data, blend };
+            let placement = Default::default();
+            let lora = Lora {
+                data,
+                blend,
+                placement,
+            };
             builder.lora(lora)
         }
         None => builder,
@@ -194,10 +202,14 @@ async fn main() -> Result<()> {
     let tokens = tokenizer.encode(PROMPT.as_bytes())?;
     let prompt_len = tokens.len();
     let prompt = InferInputBatch {
-        tokens,
+        tokens: tokens.into(),
         option: InferOption::Last,
+        ..Default::default()
     };
-    let mut prompt = InferInput::new(vec![prompt], cli.token_chunk_size);
+    let token_chunk_size = cli
+        .token_chunk_size
+        .unwrap_or(context.tuning().token_chunk_size);
+    let mut prompt = InferInput::new(vec![prompt], token_chunk_size);
 
     let mut read = false;
     let mut instant = Instant::now();
@@ -221,7 +233,7 @@ async fn main() -> Result<()> {
             let output = softmax_one(&context, output).await?;
             let output = output.to_vec();
             let token = sample(&output, 0.0);
-            prompt.batches[0].tokens.push(token);
+            prompt.batches[0].tokens = vec![token].into();
 
             let decoded = tokenizer.decode(&[token])?;
             let word = String::from_utf8_lossy(&decoded);
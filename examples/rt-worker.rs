@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use half::f16;
+use memmap2::Mmap;
+use safetensors::SafeTensors;
+use tokio::fs::File;
+use web_rwkv::{
+    context::{ContextBuilder, InstanceExt},
+    runtime::{
+        loader::Loader,
+        model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion, Quant},
+        v4, v5, v6,
+        worker::Worker,
+        JobRuntime,
+    },
+};
+
+/// Run a model as a subprocess worker: requests are read from stdin and responses are written to stdout,
+/// both as length-prefixed bincode frames (see `web_rwkv::runtime::worker`). Logs go to stderr.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(short, long, value_name = "FILE")]
+    model: PathBuf,
+    #[arg(short, long, value_name = "LAYERS", default_value_t = 0)]
+    quant: usize,
+    #[arg(long, value_name = "LAYERS", default_value_t = 0)]
+    quant_nf4: usize,
+    #[arg(long, default_value_t = 128)]
+    token_chunk_size: usize,
+    /// Number of jobs in each request.
+    #[arg(short, long, default_value_t = 1)]
+    batch: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Warn)
+        .with_module_level("web_rwkv", log::LevelFilter::Info)
+        .init()?;
+    let cli = Cli::parse();
+
+    let file = File::open(cli.model).await?;
+    let data = unsafe { Mmap::map(&file)? };
+    let model = SafeTensors::deserialize(&data)?;
+    let info = Loader::info(&model)?;
+    log::info!("{:#?}", info);
+
+    let instance = wgpu::Instance::default();
+    let adapter = instance
+        .adapter(wgpu::PowerPreference::HighPerformance)
+        .await?;
+    let context = ContextBuilder::new(adapter)
+        .auto_limits(&info)
+        .build()
+        .await?;
+    log::info!("{:#?}", context.report());
+
+    let quant = (0..cli.quant)
+        .map(|layer| (layer, Quant::Int8))
+        .chain((0..cli.quant_nf4).map(|layer| (layer, Quant::NF4)))
+        .collect();
+    let builder = ModelBuilder::new(&context, model).quant(quant);
+    let runtime = match info.version {
+        ModelVersion::V4 => {
+            let model = Build::<v4::Model>::build(builder).await?;
+            JobRuntime::new(v4::ModelRuntime::<f16>::new(model, cli.batch)).await
+        }
+        ModelVersion::V5 => {
+            let model = Build::<v5::Model>::build(builder).await?;
+            JobRuntime::new(v5::ModelRuntime::<f16>::new(model, cli.batch)).await
+        }
+        ModelVersion::V6 => {
+            let model = Build::<v6::Model>::build(builder).await?;
+            JobRuntime::new(v6::ModelRuntime::<f16>::new(model, cli.batch)).await
+        }
+    };
+
+    let worker = Worker::new(runtime, cli.token_chunk_size);
+    worker.run(tokio::io::stdin(), tokio::io::stdout()).await
+}
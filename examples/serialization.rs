@@ -65,7 +65,7 @@ async fn create_context(info: &ModelInfo, _auto: bool) -> Result<Context> {
         .auto_limits(info)
         .build()
         .await?;
-    println!("{:#?}", context.adapter.get_info());
+    println!("{:#?}", context.report());
     Ok(context)
 }
 
@@ -0,0 +1,139 @@
+#ifndef WEB_RWKV_H
+#define WEB_RWKV_H
+
+/* Generated by cbindgen from src/capi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum WebRwkvStatus {
+  WEB_RWKV_STATUS_OK = 0,
+  // A null pointer, a batch out of range, or a buffer of the wrong size.
+  WEB_RWKV_STATUS_INVALID_ARGUMENT = 1,
+  WEB_RWKV_STATUS_ERROR = 2,
+  // A bug in web-rwkv; the handles involved should not be used anymore.
+  WEB_RWKV_STATUS_PANIC = 3,
+} WebRwkvStatus;
+
+// A device and the async runtime that drives it.
+typedef struct WebRwkvContext WebRwkvContext;
+
+// A model with its state, ready for inference.
+typedef struct WebRwkvModel WebRwkvModel;
+
+// Options for [`web_rwkv_model_load`]. Start from [`web_rwkv_model_option_default`].
+typedef struct WebRwkvModelOption {
+  // Number of batches of the state, i.e., sequences run side by side.
+  size_t num_batch;
+  // Number of the first layers quantized into Int8.
+  size_t quant_int8;
+  // Number of the layers after those quantized into NF4.
+  size_t quant_nf4;
+  // Maximum number of tokens run in one step when feeding a prompt.
+  size_t token_chunk_size;
+} WebRwkvModelOption;
+
+typedef struct WebRwkvModelInfo {
+  // RWKV version: 4, 5 or 6.
+  uint32_t version;
+  size_t num_layer;
+  size_t num_emb;
+  // Number of logits of each inference.
+  size_t num_vocab;
+  size_t num_batch;
+  // Number of floats in the state of one batch.
+  size_t state_size;
+} WebRwkvModelInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// A description of the last error on the calling thread. The string is valid until the next call that fails on this thread.
+const char *web_rwkv_last_error(void);
+
+// Create a context on the high-performance adapter, requesting all the limits it supports.
+//
+// # Safety
+//
+// `out` must be a valid pointer. On success, it receives a context to be freed with [`web_rwkv_context_free`].
+WebRwkvStatus web_rwkv_context_create(WebRwkvContext **out);
+
+// Free a context. Models loaded on it stay usable.
+//
+// # Safety
+//
+// `context` must be null or returned by [`web_rwkv_context_create`], and not freed before.
+void web_rwkv_context_free(WebRwkvContext *context);
+
+WebRwkvModelOption web_rwkv_model_option_default(void);
+
+// Load a model from a SafeTensors file, with a state of `option.num_batch` batches starting from the initial state.
+//
+// # Safety
+//
+// `context` must be a live context, `path` a NUL-terminated UTF-8 string, `option` null (for the defaults) or valid,
+// and `out` a valid pointer. On success, `out` receives a model to be freed with [`web_rwkv_model_free`].
+WebRwkvStatus web_rwkv_model_load(const WebRwkvContext *context,
+                                  const char *path,
+                                  const WebRwkvModelOption *option,
+                                  WebRwkvModel **out);
+
+// Free a model and its state.
+//
+// # Safety
+//
+// `model` must be null or returned by [`web_rwkv_model_load`], and not freed before.
+void web_rwkv_model_free(WebRwkvModel *model);
+
+// # Safety
+//
+// `model` must be a live model, and `out` a valid pointer.
+WebRwkvStatus web_rwkv_model_info(const WebRwkvModel *model, WebRwkvModelInfo *out);
+
+// Reset a batch of the state to the initial state, e.g., to start a new sequence.
+//
+// # Safety
+//
+// `model` must be a live model.
+WebRwkvStatus web_rwkv_state_reset(const WebRwkvModel *model, size_t batch);
+
+// Read a batch of the state back into `data`, e.g., to cache a sequence and resume it later.
+//
+// # Safety
+//
+// `model` must be a live model, and `data` valid for writing `len` floats, which must be the state size.
+WebRwkvStatus web_rwkv_state_read(const WebRwkvModel *model, size_t batch, float *data, size_t len);
+
+// Write `data`, e.g., read by [`web_rwkv_state_read`] before, into a batch of the state.
+//
+// # Safety
+//
+// `model` must be a live model, and `data` valid for reading `len` floats, which must be the state size.
+WebRwkvStatus web_rwkv_state_write(const WebRwkvModel *model,
+                                   size_t batch,
+                                   const float *data,
+                                   size_t len);
+
+// Run `tokens` through a batch, continuing from its state, and write the logits of the last token into `logits`.
+// For token-by-token generation, run the prompt once and then each sampled token.
+//
+// # Safety
+//
+// `model` must be a live model, `tokens` valid for reading `num_token` tokens (at least one),
+// and `logits` null (to skip them) or valid for writing `len` floats, which must be at least `num_vocab`.
+WebRwkvStatus web_rwkv_infer(const WebRwkvModel *model,
+                             size_t batch,
+                             const uint16_t *tokens,
+                             size_t num_token,
+                             float *logits,
+                             size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* WEB_RWKV_H */
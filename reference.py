@@ -0,0 +1,47 @@
+#!/usr/bin/python
+
+# Generate reference outputs of the official Python implementation (`pip install rwkv`)
+# for the numerical validation test `runtime::model::tests::test_reference`.
+
+import argparse
+import os
+
+os.environ["RWKV_JIT_ON"] = "0"
+os.environ["RWKV_CUDA_ON"] = "0"
+
+import torch
+from rwkv.model import RWKV
+from rwkv.utils import PIPELINE
+from safetensors.torch import save_file
+
+parser = argparse.ArgumentParser()
+parser.add_argument("--input", type=str, help="Path to input pth model")
+parser.add_argument(
+    "--output",
+    type=str,
+    default="./reference.ref.st",
+    help="Path to output reference; name it after the converted model, e.g., model.st -> model.ref.st",
+)
+parser.add_argument(
+    "--prompt",
+    type=str,
+    default="The Eiffel Tower is located in the city of",
+    help="Prompt to run through the model",
+)
+args = parser.parse_args()
+
+model = RWKV(model=args.input.removesuffix(".pth"), strategy="cpu fp32")
+pipeline = PIPELINE(model, "rwkv_vocab_v20230424")
+
+tokens = pipeline.encode(args.prompt)
+with torch.no_grad():
+    logits, _ = model.forward(tokens, None, full_output=True)
+
+save_file(
+    {
+        "tokens": torch.tensor(tokens, dtype=torch.int32),
+        "logits": logits.float().contiguous(),
+    },
+    args.output,
+)
+print(f"{len(tokens)} tokens saved to {args.output}")
@@ -0,0 +1,565 @@
+//! A C API for embedding web-rwkv in servers written in other languages, e.g., through Python's `ctypes`, cgo or C++.
+//!
+//! The declarations are in `include/web_rwkv.h`, generated with `cbindgen --config cbindgen.toml --output include/web_rwkv.h`.
+//! Build the library with `cargo rustc --release --features capi --crate-type cdylib` (or `staticlib`).
+//!
+//! Every function returns a [`WebRwkvStatus`]; on failure, [`web_rwkv_last_error`] describes what went wrong.
+//! Handles may be shared across threads, but calls on the same batch of a model must not overlap.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_char, CStr, CString},
+    panic::AssertUnwindSafe,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use half::f16;
+use safetensors::SafeTensors;
+use wgpu::{Instance, PowerPreference};
+
+use crate::{
+    context::{Context, ContextBuilder, InstanceExt},
+    runtime::{
+        infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+        loader::Loader,
+        model::{Build, ModelBuilder, ModelInfo, ModelRuntime, ModelVersion, Quant, State},
+        v4, v5, v6, JobRuntime,
+    },
+    tensor::{TensorCpu, TensorInit, TensorShape},
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebRwkvStatus {
+    Ok = 0,
+    /// A null pointer, a batch out of range, or a buffer of the wrong size.
+    InvalidArgument = 1,
+    Error = 2,
+    /// A bug in web-rwkv; the handles involved should not be used anymore.
+    Panic = 3,
+}
+
+/// Options for [`web_rwkv_model_load`]. Start from [`web_rwkv_model_option_default`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WebRwkvModelOption {
+    /// Number of batches of the state, i.e., sequences run side by side.
+    pub num_batch: usize,
+    /// Number of the first layers quantized into Int8.
+    pub quant_int8: usize,
+    /// Number of the layers after those quantized into NF4.
+    pub quant_nf4: usize,
+    /// Maximum number of tokens run in one step when feeding a prompt.
+    pub token_chunk_size: usize,
+}
+
+impl Default for WebRwkvModelOption {
+    fn default() -> Self {
+        Self {
+            num_batch: 1,
+            quant_int8: 0,
+            quant_nf4: 0,
+            token_chunk_size: 128,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WebRwkvModelInfo {
+    /// RWKV version: 4, 5 or 6.
+    pub version: u32,
+    pub num_layer: usize,
+    pub num_emb: usize,
+    /// Number of logits of each inference.
+    pub num_vocab: usize,
+    pub num_batch: usize,
+    /// Number of floats in the state of one batch.
+    pub state_size: usize,
+}
+
+/// A device and the async runtime that drives it.
+pub struct WebRwkvContext {
+    runtime: Arc<tokio::runtime::Runtime>,
+    context: Context,
+}
+
+/// A model with its state, ready for inference.
+pub struct WebRwkvModel {
+    runtime: Arc<tokio::runtime::Runtime>,
+    jobs: JobRuntime<InferInput, InferOutput>,
+    state: Box<dyn State + Send + Sync>,
+    info: ModelInfo,
+    option: WebRwkvModelOption,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+}
+
+/// Run `f`, turning errors and panics into statuses.
+fn guard(f: impl FnOnce() -> Result<WebRwkvStatus>) -> WebRwkvStatus {
+    match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(err)) => {
+            set_last_error(format!("{err:#}"));
+            WebRwkvStatus::Error
+        }
+        Err(_) => {
+            set_last_error("panic in web-rwkv".into());
+            WebRwkvStatus::Panic
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Result<WebRwkvStatus> {
+    set_last_error(message.into());
+    Ok(WebRwkvStatus::InvalidArgument)
+}
+
+impl WebRwkvModel {
+    fn check_batch(&self, batch: usize) -> Result<()> {
+        match batch < self.option.num_batch {
+            true => Ok(()),
+            false => bail!("batch {batch} out of {}", self.option.num_batch),
+        }
+    }
+
+    fn state_size(&self) -> usize {
+        self.state.init().len()
+    }
+
+    /// Run `tokens` through `batch`, and return the logits of the last one.
+    async fn infer(&self, batch: usize, tokens: &[u16]) -> Vec<f32> {
+        let batches = (0..self.option.num_batch)
+            .map(|index| InferInputBatch {
+                tokens: match index == batch {
+                    true => tokens.to_vec().into(),
+                    false => vec![].into(),
+                },
+                option: InferOption::Last,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, self.option.token_chunk_size);
+        let mut logits = vec![];
+        while input.num_token() > 0 {
+            let (next, InferOutput(output)) = self.jobs.infer(input).await;
+            input = next;
+            match output.into_iter().nth(batch) {
+                Some(output) if output.size() > 0 => logits = output.to_vec(),
+                _ => {}
+            }
+        }
+        logits
+    }
+}
+
+async fn load_model(
+    context: &Context,
+    path: &Path,
+    option: WebRwkvModelOption,
+) -> Result<(
+    JobRuntime<InferInput, InferOutput>,
+    Box<dyn State + Send + Sync>,
+    ModelInfo,
+)> {
+    let data = std::fs::read(path)?;
+    let model = SafeTensors::deserialize(&data)?;
+    let info = Loader::info(&model)?;
+
+    let quant: HashMap<usize, Quant> = (0..option.quant_int8)
+        .map(|layer| (layer, Quant::Int8))
+        .chain(
+            (option.quant_int8..option.quant_int8 + option.quant_nf4)
+                .map(|layer| (layer, Quant::NF4)),
+        )
+        .collect();
+    let builder = ModelBuilder::new(context, model).quant(quant);
+    let num_batch = option.num_batch;
+    let (jobs, state): (_, Box<dyn State + Send + Sync>) = match info.version {
+        ModelVersion::V4 => {
+            let model = Build::<v4::Model>::build(builder).await?;
+            let runtime = v4::ModelRuntime::<f16>::new(model, num_batch);
+            let state = Box::new(runtime.state());
+            (JobRuntime::new(runtime).await, state)
+        }
+        ModelVersion::V5 => {
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime = v5::ModelRuntime::<f16>::new(model, num_batch);
+            let state = Box::new(runtime.state());
+            (JobRuntime::new(runtime).await, state)
+        }
+        ModelVersion::V6 => {
+            let model = Build::<v6::Model>::build(builder).await?;
+            let runtime = v6::ModelRuntime::<f16>::new(model, num_batch);
+            let state = Box::new(runtime.state());
+            (JobRuntime::new(runtime).await, state)
+        }
+    };
+    Ok((jobs, state, info))
+}
+
+/// A description of the last error on the calling thread. The string is valid until the next call that fails on this thread.
+#[no_mangle]
+pub extern "C" fn web_rwkv_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ptr())
+}
+
+/// Create a context on the high-performance adapter, requesting all the limits it supports.
+///
+/// # Safety
+///
+/// `out` must be a valid pointer. On success, it receives a context to be freed with [`web_rwkv_context_free`].
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_context_create(out: *mut *mut WebRwkvContext) -> WebRwkvStatus {
+    guard(|| {
+        if out.is_null() {
+            return invalid("null output");
+        }
+        let runtime = tokio::runtime::Runtime::new()?;
+        let context = runtime.block_on(async {
+            let instance = Instance::default();
+            let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+            let limits = adapter.limits();
+            let context = ContextBuilder::new(adapter).limits(limits).build().await?;
+            anyhow::Ok(context)
+        })?;
+        let context = WebRwkvContext {
+            runtime: Arc::new(runtime),
+            context,
+        };
+        *out = Box::into_raw(Box::new(context));
+        Ok(WebRwkvStatus::Ok)
+    })
+}
+
+/// Free a context. Models loaded on it stay usable.
+///
+/// # Safety
+///
+/// `context` must be null or returned by [`web_rwkv_context_create`], and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_context_free(context: *mut WebRwkvContext) {
+    if !context.is_null() {
+        drop(Box::from_raw(context));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn web_rwkv_model_option_default() -> WebRwkvModelOption {
+    Default::default()
+}
+
+/// Load a model from a SafeTensors file, with a state of `option.num_batch` batches starting from the initial state.
+///
+/// # Safety
+///
+/// `context` must be a live context, `path` a NUL-terminated UTF-8 string, `option` null (for the defaults) or valid,
+/// and `out` a valid pointer. On success, `out` receives a model to be freed with [`web_rwkv_model_free`].
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_model_load(
+    context: *const WebRwkvContext,
+    path: *const c_char,
+    option: *const WebRwkvModelOption,
+    out: *mut *mut WebRwkvModel,
+) -> WebRwkvStatus {
+    guard(|| {
+        let (Some(context), false, false) = (context.as_ref(), path.is_null(), out.is_null())
+        else {
+            return invalid("null argument");
+        };
+        let option = option.as_ref().copied().unwrap_or_default();
+        if option.num_batch == 0 {
+            return invalid("no batch");
+        }
+        let path = CStr::from_ptr(path).to_str()?;
+        let WebRwkvContext { runtime, context } = context;
+        let (jobs, state, info) = runtime.block_on(load_model(context, Path::new(path), option))?;
+        let model = WebRwkvModel {
+            runtime: runtime.clone(),
+            jobs,
+            state,
+            info,
+            option,
+        };
+        *out = Box::into_raw(Box::new(model));
+        Ok(WebRwkvStatus::Ok)
+    })
+}
+
+/// Free a model and its state.
+///
+/// # Safety
+///
+/// `model` must be null or returned by [`web_rwkv_model_load`], and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_model_free(model: *mut WebRwkvModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// # Safety
+///
+/// `model` must be a live model, and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_model_info(
+    model: *const WebRwkvModel,
+    out: *mut WebRwkvModelInfo,
+) -> WebRwkvStatus {
+    guard(|| {
+        let (Some(model), false) = (model.as_ref(), out.is_null()) else {
+            return invalid("null argument");
+        };
+        let info = &model.info;
+        let version = match info.version {
+            ModelVersion::V4 => 4,
+            ModelVersion::V5 => 5,
+            ModelVersion::V6 => 6,
+        };
+        *out = WebRwkvModelInfo {
+            version,
+            num_layer: info.num_layer,
+            num_emb: info.num_emb,
+            num_vocab: info.num_vocab,
+            num_batch: model.option.num_batch,
+            state_size: model.state_size(),
+        };
+        Ok(WebRwkvStatus::Ok)
+    })
+}
+
+/// Reset a batch of the state to the initial state, e.g., to start a new sequence.
+///
+/// # Safety
+///
+/// `model` must be a live model.
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_state_reset(
+    model: *const WebRwkvModel,
+    batch: usize,
+) -> WebRwkvStatus {
+    guard(|| {
+        let Some(model) = model.as_ref() else {
+            return invalid("null model");
+        };
+        if let Err(err) = model.check_batch(batch) {
+            return invalid(err.to_string());
+        }
+        model.state.load(model.state.init(), batch)?;
+        Ok(WebRwkvStatus::Ok)
+    })
+}
+
+/// Read a batch of the state back into `data`, e.g., to cache a sequence and resume it later.
+///
+/// # Safety
+///
+/// `model` must be a live model, and `data` valid for writing `len` floats, which must be the state size.
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_state_read(
+    model: *const WebRwkvModel,
+    batch: usize,
+    data: *mut f32,
+    len: usize,
+) -> WebRwkvStatus {
+    guard(|| {
+        let (Some(model), false) = (model.as_ref(), data.is_null()) else {
+            return invalid("null argument");
+        };
+        if let Err(err) = model.check_batch(batch) {
+            return invalid(err.to_string());
+        }
+        if len != model.state_size() {
+            return invalid(format!(
+                "{len} floats for a state of {}",
+                model.state_size()
+            ));
+        }
+        let tensor = model.runtime.block_on(model.state.back(batch))?;
+        std::slice::from_raw_parts_mut(data, len).copy_from_slice(&tensor);
+        Ok(WebRwkvStatus::Ok)
+    })
+}
+
+/// Write `data`, e.g., read by [`web_rwkv_state_read`] before, into a batch of the state.
+///
+/// # Safety
+///
+/// `model` must be a live model, and `data` valid for reading `len` floats, which must be the state size.
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_state_write(
+    model: *const WebRwkvModel,
+    batch: usize,
+    data: *const f32,
+    len: usize,
+) -> WebRwkvStatus {
+    guard(|| {
+        let (Some(model), false) = (model.as_ref(), data.is_null()) else {
+            return invalid("null argument");
+        };
+        if let Err(err) = model.check_batch(batch) {
+            return invalid(err.to_string());
+        }
+        if len != model.state_size() {
+            return invalid(format!(
+                "{len} floats for a state of {}",
+                model.state_size()
+            ));
+        }
+        let data = std::slice::from_raw_parts(data, len).to_vec();
+        let tensor = TensorCpu::from_data(model.state.init().shape(), data)?;
+        model.state.load(tensor, batch)?;
+        Ok(WebRwkvStatus::Ok)
+    })
+}
+
+/// Run `tokens` through a batch, continuing from its state, and write the logits of the last token into `logits`.
+/// For token-by-token generation, run the prompt once and then each sampled token.
+///
+/// # Safety
+///
+/// `model` must be a live model, `tokens` valid for reading `num_token` tokens (at least one),
+/// and `logits` null (to skip them) or valid for writing `len` floats, which must be at least `num_vocab`.
+#[no_mangle]
+pub unsafe extern "C" fn web_rwkv_infer(
+    model: *const WebRwkvModel,
+    batch: usize,
+    tokens: *const u16,
+    num_token: usize,
+    logits: *mut f32,
+    len: usize,
+) -> WebRwkvStatus {
+    guard(|| {
+        let (Some(model), false) = (model.as_ref(), tokens.is_null()) else {
+            return invalid("null argument");
+        };
+        if let Err(err) = model.check_batch(batch) {
+            return invalid(err.to_string());
+        }
+        if num_token == 0 {
+            return invalid("no token");
+        }
+        if !logits.is_null() && len < model.info.num_vocab {
+            return invalid(format!("{len} floats for {} logits", model.info.num_vocab));
+        }
+        let tokens = std::slice::from_raw_parts(tokens, num_token);
+        let output = model.runtime.block_on(model.infer(batch, tokens));
+        if output.is_empty() {
+            return Err(anyhow!("no logits"));
+        }
+        if !logits.is_null() {
+            std::slice::from_raw_parts_mut(logits, output.len()).copy_from_slice(&output);
+        }
+        Ok(WebRwkvStatus::Ok)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, ptr};
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::runtime::tiny::TinyModel;
+
+    #[test]
+    fn test_capi() -> Result<()> {
+        let mut context = ptr::null_mut();
+        if unsafe { web_rwkv_context_create(&mut context) } != WebRwkvStatus::Ok {
+            return Ok(());
+        }
+
+        let info = TinyModel::info(ModelVersion::V6);
+        let path = std::env::temp_dir().join(format!("web-rwkv-capi-{}.st", std::process::id()));
+        std::fs::write(&path, TinyModel::new(info.clone(), 42).serialize()?)?;
+        let path = CString::new(path.to_str().unwrap())?;
+
+        let option = WebRwkvModelOption {
+            num_batch: 2,
+            ..web_rwkv_model_option_default()
+        };
+        let mut model = ptr::null_mut();
+        let status = unsafe { web_rwkv_model_load(context, path.as_ptr(), &option, &mut model) };
+        let _ = std::fs::remove_file(path.to_str()?);
+        assert_eq!(status, WebRwkvStatus::Ok);
+
+        let mut model_info = unsafe { std::mem::zeroed::<WebRwkvModelInfo>() };
+        assert_eq!(
+            unsafe { web_rwkv_model_info(model, &mut model_info) },
+            WebRwkvStatus::Ok
+        );
+        assert_eq!(model_info.version, 6);
+        assert_eq!(model_info.num_vocab, info.num_vocab);
+
+        // the prompt at once, or token by token, in another batch
+        let prompt = [1u16, 2, 3, 4, 5];
+        let mut whole = vec![0.0f32; model_info.num_vocab];
+        let mut step = vec![0.0f32; model_info.num_vocab];
+        unsafe {
+            let status = web_rwkv_infer(
+                model,
+                0,
+                prompt.as_ptr(),
+                5,
+                whole.as_mut_ptr(),
+                whole.len(),
+            );
+            assert_eq!(status, WebRwkvStatus::Ok);
+            for token in prompt {
+                let status = web_rwkv_infer(model, 1, &token, 1, step.as_mut_ptr(), step.len());
+                assert_eq!(status, WebRwkvStatus::Ok);
+            }
+        }
+        for (x, y) in whole.iter().zip(step.iter()) {
+            assert!((x - y).abs() < 1.0e-2 * x.abs().max(1.0), "{x} vs {y}");
+        }
+
+        // a state read from one batch continues the same in another
+        let mut state = vec![0.0f32; model_info.state_size];
+        let mut next = [
+            vec![0.0f32; model_info.num_vocab],
+            vec![0.0f32; model_info.num_vocab],
+        ];
+        unsafe {
+            assert_eq!(
+                web_rwkv_state_read(model, 0, state.as_mut_ptr(), state.len()),
+                WebRwkvStatus::Ok
+            );
+            assert_eq!(web_rwkv_state_reset(model, 1), WebRwkvStatus::Ok);
+            assert_eq!(
+                web_rwkv_state_write(model, 1, state.as_ptr(), state.len()),
+                WebRwkvStatus::Ok
+            );
+            for (batch, logits) in next.iter_mut().enumerate() {
+                let status = web_rwkv_infer(model, batch, &6, 1, logits.as_mut_ptr(), logits.len());
+                assert_eq!(status, WebRwkvStatus::Ok);
+            }
+        }
+        assert_eq!(next[0], next[1]);
+
+        // invalid arguments are reported
+        unsafe {
+            let status = web_rwkv_infer(model, 2, &6, 1, ptr::null_mut(), 0);
+            assert_eq!(status, WebRwkvStatus::InvalidArgument);
+            let error = CStr::from_ptr(web_rwkv_last_error()).to_str()?;
+            assert!(error.contains("batch 2"));
+            let status = web_rwkv_state_read(model, 0, state.as_mut_ptr(), 1);
+            assert_eq!(status, WebRwkvStatus::InvalidArgument);
+
+            web_rwkv_model_free(model);
+            web_rwkv_context_free(context);
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,28 @@
-use std::{borrow::Cow, collections::BTreeMap, sync::Arc};
+#[cfg(not(target_arch = "wasm32"))]
+use std::cell::Cell;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
 
 use futures::Future;
+use rustc_hash::FxHasher;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use wasm_bindgen::prelude::wasm_bindgen;
 use web_rwkv_derive::{Deref, DerefMut};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    Adapter, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Buffer,
-    BufferDescriptor, BufferUsages, ComputePipeline, ComputePipelineDescriptor, Device,
-    DeviceDescriptor, Features, Instance, Limits, PipelineLayoutDescriptor, PowerPreference, Queue,
-    RequestAdapterOptions, ShaderModuleDescriptor,
+    Adapter, AdapterInfo, Backend, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, Buffer, BufferDescriptor, BufferUsages, CommandBuffer, ComputePipeline,
+    ComputePipelineDescriptor, Device, DeviceDescriptor, DeviceType, ErrorFilter, Features,
+    Instance, Limits, PipelineLayoutDescriptor, PowerPreference, Queue, RequestAdapterOptions,
+    ShaderModuleDescriptor, SubmissionIndex,
 };
 
 use crate::tensor::{
@@ -45,8 +58,28 @@ pub struct ContextId;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub struct ContextEvent {
-    pub buffer: Arc<Buffer>,
-    pub sender: tokio::sync::oneshot::Sender<Box<[u8]>>,
+    pub buffer: ArenaBuffer,
+    pub sender: flume::Sender<Box<[u8]>>,
+    /// The submission that writes the buffer, if only that one is waited for instead of all submitted work.
+    pub index: Option<SubmissionIndex>,
+}
+
+/// What the polling thread is asked to do.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) enum ContextRequest {
+    /// Read a buffer back.
+    Read(ContextEvent),
+    /// Poll until all submitted work is done.
+    Wait,
+    /// Poll until a submission is done, and tell the sender.
+    WaitFor(SubmissionIndex, futures::channel::oneshot::Sender<()>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<ContextEvent> for ContextRequest {
+    fn from(value: ContextEvent) -> Self {
+        Self::Read(value)
+    }
 }
 
 #[derive(Debug)]
@@ -57,20 +90,56 @@ pub struct ContextInternal {
     pub queue: Queue,
 
     pipeline_cache: ResourceCache<PipelineKey, CachedPipeline>,
+    /// Pipelines compiled so far, in the order they are compiled.
+    pipelines: Mutex<Vec<PipelineUsage>>,
     shape_cache: ResourceCache<View, Buffer>,
     buffer_cache: ResourceCache<BufferKey, Buffer>,
+    /// Bytes handed out to each arena, with the budgets and statistics of the arenas.
+    arenas: Arc<Arenas>,
 
+    poll: PollStrategy,
+    max_pending: Option<usize>,
+    tuning: TuningProfile,
+    /// Submissions through [`Context::submit`] that are not known to be done.
+    pending: Arc<AtomicUsize>,
+    /// The latest submissions through [`Context::submit`], if their number is bounded.
+    submissions: Mutex<VecDeque<SubmissionIndex>>,
+    /// Held while the error scope of an allocation in [`Context::catch_oom`] is open, since error scopes are device-wide.
+    #[cfg(not(target_arch = "wasm32"))]
+    scope: Mutex<()>,
     #[cfg(not(target_arch = "wasm32"))]
-    event: flume::Sender<ContextEvent>,
+    event: flume::Sender<ContextRequest>,
+}
+
+/// A handle of a device, which allocates buffers in one [`Arena`] (by default [`Arena::Workspace`]).
+/// Handles of other arenas on the same device are made by [`Context::in_arena`].
+#[derive(Debug, Clone)]
+pub struct Context {
+    internal: Arc<ContextInternal>,
+    arena: Arena,
+}
+
+impl std::ops::Deref for Context {
+    type Target = Arc<ContextInternal>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.internal
+    }
 }
 
-#[derive(Debug, Clone, Deref, DerefMut)]
-pub struct Context(Arc<ContextInternal>);
+impl From<Arc<ContextInternal>> for Context {
+    fn from(internal: Arc<ContextInternal>) -> Self {
+        Self {
+            internal,
+            arena: Default::default(),
+        }
+    }
+}
 
 #[cfg(not(target_arch = "wasm32"))]
 impl Drop for Context {
     fn drop(&mut self) {
-        if Arc::strong_count(&self.0) <= 1 {
+        if Arc::strong_count(&self.internal) <= 1 {
             self.clear_buffers();
             self.queue.submit(None);
             self.device.poll(wgpu::Maintain::Wait);
@@ -78,10 +147,31 @@ impl Drop for Context {
     }
 }
 
+/// Who polls the device, which is needed for buffers to be mapped when reading tensors back and for finished work to be freed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PollStrategy {
+    /// Reading back blocks on polling the device on a dedicated thread, and loading waits for the device to finish.
+    #[default]
+    Thread,
+    /// The context never polls the device, and the embedder does (e.g., with [`Context::poll`] or its own `device.poll`),
+    /// so that polling can be coordinated with its own use of the device. Reading back only completes once the device is polled.
+    External,
+}
+
 pub struct ContextBuilder {
     pub adapter: Adapter,
     pub features: Features,
     pub limits: Limits,
+    /// Ignored on web, where the browser drives the device.
+    pub poll: PollStrategy,
+    /// Bound on the submissions through [`Context::submit`] that may be in flight, see [`ContextBuilder::max_pending`].
+    pub max_pending: Option<usize>,
+    /// Pipelines compiled when the context is built, see [`ContextBuilder::warmup`].
+    pub warmup: Vec<PipelineUsage>,
+    /// Detected from the adapter by default, see [`TuningProfile`].
+    pub tuning: TuningProfile,
+    /// If a context may be handed out from the process-wide [`ContextCache`], see [`ContextReuse`].
+    pub reuse: ContextReuse,
 }
 
 #[wasm_bindgen]
@@ -93,15 +183,305 @@ pub enum CreateEnvironmentError {
     RequestDeviceFailed,
 }
 
+/// The device runs out of memory, e.g., while allocating the intermediates of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("out of device memory")]
+pub struct OutOfMemoryError;
+
+/// A category of device memory with a budget and statistics of its own, so that eviction and accounting can tell
+/// what the memory of a device is taken by. Each buffer belongs to the arena of the [`Context`] handle it is allocated with.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Arena {
+    /// Weights of loaded models, and adapters loaded for them.
+    Model,
+    /// States of sessions, including those kept on device by a state cache.
+    Session,
+    /// Buffers of jobs, and everything not allocated in the other arenas.
+    #[default]
+    Workspace,
+}
+
+impl Arena {
+    pub const ALL: [Arena; 3] = [Arena::Model, Arena::Session, Arena::Workspace];
+}
+
+/// Device memory of an [`Arena`], see [`Context::arena_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArenaStats {
+    pub arena: Arena,
+    /// Bytes of the buffers in use. Buffers only kept for reuse by the buffer cache don't count.
+    pub size: usize,
+    /// The most bytes in use after any allocation.
+    pub peak: usize,
+    pub budget: Option<usize>,
+    /// Buffers handed out, newly allocated or reused.
+    pub allocations: usize,
+    /// Allocations that exceed the budget.
+    pub rejections: usize,
+}
+
+/// The buffers of an arena take more than its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("{size} bytes in the {arena:?} arena exceed its budget of {budget} bytes")]
+pub struct OverBudgetError {
+    pub arena: Arena,
+    pub size: usize,
+    pub budget: usize,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    /// How deep [`Context::catch_oom`] calls nest on this thread, and if an allocation in the innermost one has failed.
+    static OOM_SCOPE: Cell<(usize, bool)> = const { Cell::new((0, false)) };
+}
+
+/// Restores the [`OOM_SCOPE`] of the enclosing call when a [`Context::catch_oom`] call returns, or its closure panics.
+#[cfg(not(target_arch = "wasm32"))]
+struct OomScope((usize, bool));
+
+#[cfg(not(target_arch = "wasm32"))]
+impl OomScope {
+    fn enter() -> Self {
+        let outer = OOM_SCOPE.with(|scope| scope.replace((scope.get().0 + 1, false)));
+        Self(outer)
+    }
+
+    fn failed(&self) -> bool {
+        OOM_SCOPE.with(|scope| scope.get().1)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for OomScope {
+    fn drop(&mut self) {
+        OOM_SCOPE.with(|scope| scope.set(self.0));
+    }
+}
+
+/// Counters of an arena, updated without locking as buffers are handed out and dropped.
+#[derive(Debug)]
+struct ArenaCounter {
+    size: AtomicUsize,
+    peak: AtomicUsize,
+    /// `usize::MAX` if there is no budget.
+    budget: AtomicUsize,
+    allocations: AtomicUsize,
+    rejections: AtomicUsize,
+}
+
+impl Default for ArenaCounter {
+    fn default() -> Self {
+        Self {
+            size: Default::default(),
+            peak: Default::default(),
+            budget: AtomicUsize::new(usize::MAX),
+            allocations: Default::default(),
+            rejections: Default::default(),
+        }
+    }
+}
+
+impl ArenaCounter {
+    fn budget(&self) -> Option<usize> {
+        match self.budget.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            budget => Some(budget),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Arenas([ArenaCounter; 3]);
+
+impl Arenas {
+    fn get(&self, arena: Arena) -> &ArenaCounter {
+        &self.0[arena as usize]
+    }
+
+    fn stats(&self, arena: Arena) -> ArenaStats {
+        let counter = self.get(arena);
+        ArenaStats {
+            arena,
+            size: counter.size.load(Ordering::Relaxed),
+            peak: counter.peak.load(Ordering::Relaxed),
+            budget: counter.budget(),
+            allocations: counter.allocations.load(Ordering::Relaxed),
+            rejections: counter.rejections.load(Ordering::Relaxed),
+        }
+    }
+
+    fn check(&self, arena: Arena) -> Result<(), OverBudgetError> {
+        let ArenaStats { size, budget, .. } = self.stats(arena);
+        match budget {
+            Some(budget) if size > budget => Err(OverBudgetError {
+                arena,
+                size,
+                budget,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Account `size` bytes handed out to `arena`, and check the budget of the arena with them.
+    fn track(&self, arena: Arena, size: usize) -> Result<(), OverBudgetError> {
+        let counter = self.get(arena);
+        let size = counter.size.fetch_add(size, Ordering::Relaxed) + size;
+        counter.peak.fetch_max(size, Ordering::Relaxed);
+        counter.allocations.fetch_add(1, Ordering::Relaxed);
+        match counter.budget() {
+            Some(budget) if size > budget => {
+                counter.rejections.fetch_add(1, Ordering::Relaxed);
+                Err(OverBudgetError {
+                    arena,
+                    size,
+                    budget,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A buffer accounted to an arena, until the lease is dropped.
+#[derive(Debug)]
+struct ArenaLease {
+    buffer: Arc<Buffer>,
+    arenas: Arc<Arenas>,
+    arena: Arena,
+}
+
+impl Drop for ArenaLease {
+    fn drop(&mut self) {
+        let counter = self.arenas.get(self.arena);
+        let size = self.buffer.size() as usize;
+        counter.size.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+/// A buffer handed out to an [`Arena`], which counts towards the arena until all its clones are dropped.
+/// A buffer only kept for reuse by the buffer cache doesn't count, and counts towards the arena that reuses it.
+#[derive(Debug, Clone)]
+pub struct ArenaBuffer(Arc<ArenaLease>);
+
+impl ArenaBuffer {
+    /// Number of clones of the buffer alive, like [`Arc::strong_count`].
+    pub fn strong_count(this: &Self) -> usize {
+        Arc::strong_count(&this.0)
+    }
+}
+
+impl std::ops::Deref for ArenaBuffer {
+    type Target = Arc<Buffer>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.buffer
+    }
+}
+
+/// A class of devices with similar performance characteristics, as detected from the adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GpuClass {
+    /// A discrete GPU with its own memory.
+    Discrete,
+    /// An integrated GPU sharing memory with the CPU, except on Apple silicon.
+    Integrated,
+    /// Apple silicon, through Metal.
+    Apple,
+    /// A software renderer running on the CPU, e.g., `llvmpipe` or WARP.
+    Software,
+    /// Anything else, e.g., a virtual GPU.
+    Unknown,
+}
+
+impl GpuClass {
+    pub fn detect(info: &AdapterInfo) -> Self {
+        match (info.backend, info.device_type) {
+            (_, DeviceType::Cpu) => Self::Software,
+            (Backend::Metal, _) => Self::Apple,
+            (_, DeviceType::DiscreteGpu) => Self::Discrete,
+            (_, DeviceType::IntegratedGpu) => Self::Integrated,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Default performance settings for a class of devices, chosen when a context is built and queried with [`Context::tuning`].
+///
+/// The defaults are rough starting points for each class, so that things run reasonably without experimenting;
+/// override them with [`ContextBuilder::tuning`] after benchmarking a specific device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TuningProfile {
+    pub class: GpuClass,
+    /// Suggested number of tokens of each step when feeding prompts, e.g., for `InferInput::new`, see [`JobRuntime::tuning`](crate::runtime::JobRuntime::tuning).
+    pub token_chunk_size: usize,
+    /// The number of command buffers the layers of a step are split into.
+    /// More buffers let the device start earlier, but add overhead on drivers where submissions are costly.
+    pub num_layer_chunk: usize,
+    /// Bound on submissions in flight if not set by [`ContextBuilder::max_pending`].
+    pub max_pending: Option<usize>,
+}
+
+impl TuningProfile {
+    pub fn new(class: GpuClass) -> Self {
+        let (token_chunk_size, num_layer_chunk, max_pending) = match class {
+            GpuClass::Discrete | GpuClass::Unknown => (128, 4, None),
+            GpuClass::Integrated => (64, 2, None),
+            GpuClass::Apple => (128, 2, None),
+            // the queue only runs as fast as the CPU, so running far ahead only takes memory
+            GpuClass::Software => (32, 1, Some(1)),
+        };
+        Self {
+            class,
+            token_chunk_size,
+            num_layer_chunk,
+            max_pending,
+        }
+    }
+
+    /// The profile of the class of the adapter.
+    pub fn detect(info: &AdapterInfo) -> Self {
+        Self::new(GpuClass::detect(info))
+    }
+}
+
+impl Default for TuningProfile {
+    fn default() -> Self {
+        Self::new(GpuClass::Unknown)
+    }
+}
+
+/// Features that are enabled when available, and left out otherwise.
+const OPTIONAL_FEATURES: Features = Features::SHADER_F16;
+
+/// What the device of a [`Context`] supports.
+#[derive(Debug, Clone)]
+pub struct ContextReport {
+    pub adapter: AdapterInfo,
+    pub features: Features,
+    /// If native `f16` arithmetic is available in shaders.
+    /// Kernels read and write half precision tensors as packed `u32`, so they run without it.
+    pub shader_f16: bool,
+    pub subgroup: bool,
+}
+
 impl<'a> ContextBuilder {
     pub fn new(adapter: Adapter) -> Self {
-        let features = Features::empty();
+        let features = adapter.features() & OPTIONAL_FEATURES;
         #[cfg(feature = "subgroup-ops")]
         let features = features | Features::SUBGROUP;
+        let tuning = TuningProfile::detect(&adapter.get_info());
         Self {
             adapter,
             features,
             limits: Default::default(),
+            poll: Default::default(),
+            max_pending: None,
+            warmup: vec![],
+            tuning,
+            reuse: Default::default(),
         }
     }
 
@@ -110,8 +490,37 @@ impl<'a> ContextBuilder {
             adapter,
             features,
             limits,
+            poll,
+            max_pending,
+            warmup,
+            tuning,
+            reuse,
         } = self;
 
+        #[cfg(not(target_arch = "wasm32"))]
+        let key = ContextKey {
+            adapter: adapter.get_info(),
+            features,
+            limits: limits.clone(),
+            poll,
+            max_pending,
+            tuning,
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(context) = ContextCache::get(&key, reuse) {
+            ContextCache::recycle(adapter);
+            context.warmup(&warmup);
+            return Ok(context);
+        }
+        let max_pending = max_pending.or(tuning.max_pending);
+
+        // e.g., `shader-f16` is not exposed on DX12, or on Vulkan devices without 16-bit storage
+        let missing = (features - adapter.features()) & OPTIONAL_FEATURES;
+        if !missing.is_empty() {
+            log::warn!("{:?} not supported by the adapter, falling back", missing);
+        }
+        let features = features - missing;
+
         let (device, queue) = adapter
             .request_device(
                 &DeviceDescriptor {
@@ -133,12 +542,21 @@ impl<'a> ContextBuilder {
             device,
             queue,
             pipeline_cache: Default::default(),
+            pipelines: Default::default(),
             shape_cache: Default::default(),
             buffer_cache: ResourceCache::new(2),
+            arenas: Default::default(),
+            poll,
+            max_pending,
+            tuning,
+            pending: Default::default(),
+            submissions: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            scope: Default::default(),
             #[cfg(not(target_arch = "wasm32"))]
             event,
         });
-        let context = Context(context);
+        let context = Context::from(context);
 
         // start a thread for reading back buffers
         #[cfg(not(target_arch = "wasm32"))]
@@ -146,21 +564,36 @@ impl<'a> ContextBuilder {
             let id = context.id;
             let context = Arc::downgrade(&context);
             std::thread::spawn(move || {
-                while let Ok(ContextEvent { buffer, sender }) = receiver.recv() {
-                    match context.upgrade() {
-                        Some(context) => {
+                while let Ok(request) = receiver.recv() {
+                    let Some(context) = context.upgrade() else {
+                        break;
+                    };
+                    match request {
+                        ContextRequest::Read(ContextEvent {
+                            buffer,
+                            sender,
+                            index,
+                        }) => {
                             #[cfg(feature = "trace")]
                             let _span = tracing::trace_span!("device").entered();
-                            let data = context.read_back_buffer(buffer);
+                            let data = context.read_back_buffer(buffer, index);
                             let _ = sender.send(data);
                         }
-                        None => break,
+                        ContextRequest::Wait => context.wait(),
+                        ContextRequest::WaitFor(index, sender) => {
+                            context.wait_for(index);
+                            let _ = sender.send(());
+                        }
                     }
                 }
                 log::info!("context {} destroyed", id);
             });
         }
 
+        #[cfg(not(target_arch = "wasm32"))]
+        let context = ContextCache::insert(key, context, reuse);
+
+        context.warmup(&warmup);
         Ok(context)
     }
 
@@ -183,10 +616,269 @@ impl<'a> ContextBuilder {
         f(&mut self.features);
         self
     }
+
+    pub fn poll(mut self, poll: PollStrategy) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    /// Bound the number of submissions through [`Context::submit`] that are in flight.
+    /// [`Context::throttle`] waits until the oldest ones are done before the next is submitted (e.g., the next step of a runtime),
+    /// so that the queue does not run far ahead of the device.
+    /// Ignored with [`PollStrategy::External`] and on web, where the context cannot wait by itself.
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = Some(max_pending.max(1));
+        self
+    }
+
+    /// Override the [`TuningProfile`] detected from the adapter.
+    pub fn tuning(mut self, profile: TuningProfile) -> Self {
+        self.tuning = profile;
+        self
+    }
+
+    /// Compile these pipelines when building, e.g., those recorded by [`Context::pipelines`] in an earlier run of the same app.
+    /// Otherwise pipelines are compiled the first time an operator needs them, so that only those in use are ever compiled,
+    /// at the cost of a slower first inference.
+    pub fn warmup(mut self, pipelines: Vec<PipelineUsage>) -> Self {
+        self.warmup = pipelines;
+        self
+    }
+
+    /// Hand out a context from the process-wide [`ContextCache`] if one was built before on the same adapter
+    /// with the same features, limits and settings, instead of requesting a new device. Ignored on web.
+    pub fn reuse(mut self, policy: ContextReuse) -> Self {
+        self.reuse = policy;
+        self
+    }
+}
+
+/// If [`ContextBuilder::build`] may hand out a context from the process-wide [`ContextCache`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContextReuse {
+    /// Always request a new device.
+    #[default]
+    Never,
+    /// Reuse a cached context. The cache keeps it alive until it is invalidated.
+    Keep,
+    /// Reuse a cached context only while it is still in use elsewhere, without keeping it alive.
+    WhileAlive,
+}
+
+/// What a cached context was built from.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, PartialEq)]
+struct ContextKey {
+    adapter: AdapterInfo,
+    features: Features,
+    limits: Limits,
+    poll: PollStrategy,
+    max_pending: Option<usize>,
+    tuning: TuningProfile,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+enum CachedContext {
+    Strong(Context),
+    Weak(Weak<ContextInternal>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl CachedContext {
+    fn upgrade(&self) -> Option<Context> {
+        match self {
+            CachedContext::Strong(context) => Some(context.clone()),
+            CachedContext::Weak(context) => context.upgrade().map(Context::from),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            CachedContext::Strong(_) => true,
+            CachedContext::Weak(context) => context.strong_count() > 0,
+        }
+    }
+
+    fn is(&self, context: &Context) -> bool {
+        match self {
+            CachedContext::Strong(other) => Arc::ptr_eq(&other.internal, &context.internal),
+            CachedContext::Weak(other) => {
+                std::ptr::eq(other.as_ptr(), Arc::as_ptr(&context.internal))
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+static CONTEXT_CACHE: Mutex<Vec<(ContextKey, CachedContext)>> = Mutex::new(Vec::new());
+
+/// Adapters left unused by cache hits, and the adapter each power preference resolves to.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct AdapterPool {
+    spare: Vec<Adapter>,
+    /// Number of adapters of the shared instance handed out and not recycled, of each info.
+    lent: Vec<(AdapterInfo, usize)>,
+    resolved: Vec<(PowerPreference, AdapterInfo)>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl AdapterPool {
+    fn lent(&mut self, info: &AdapterInfo) -> &mut usize {
+        let index = match self.lent.iter().position(|(other, _)| other == info) {
+            Some(index) => index,
+            None => {
+                self.lent.push((info.clone(), 0));
+                self.lent.len() - 1
+            }
+        };
+        &mut self.lent[index].1
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+static ADAPTER_POOL: Mutex<Option<AdapterPool>> = Mutex::new(None);
+
+/// Process-wide cache of an [`Instance`] and of the contexts built with [`ContextBuilder::reuse`],
+/// so that repeated model loads in one process (e.g., across tests or plugins) share devices
+/// instead of creating instances and devices over and over, which is slow and may hit driver limits.
+///
+/// Entries are keyed by the adapter's info together with the features, limits and settings of the builder.
+/// Invalidate a context, e.g., once its device is lost, so that the next build requests a new one.
+///
+/// The adapter passed to a build that hits the cache is kept and handed out again by [`ContextCache::adapter`],
+/// instead of being dropped: dropping an adapter of an instance while devices of its other adapters are alive
+/// crashes some drivers once those devices are dropped. Only as many adapters are kept as [`ContextCache::adapter`]
+/// has handed out, so adapters of other instances are dropped once those of the shared one are back.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ContextCache;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ContextCache {
+    /// The instance shared by the process, created on first use.
+    pub fn instance() -> &'static Instance {
+        static INSTANCE: std::sync::OnceLock<Instance> = std::sync::OnceLock::new();
+        INSTANCE.get_or_init(Instance::default)
+    }
+
+    /// An adapter of the shared instance, a spare one if there is any, or newly requested.
+    pub async fn adapter(
+        power_preference: PowerPreference,
+    ) -> Result<Adapter, CreateEnvironmentError> {
+        let spare = Self::lock_adapters(|pool| {
+            let (_, info) = pool
+                .resolved
+                .iter()
+                .find(|(other, _)| *other == power_preference)?;
+            let index = pool
+                .spare
+                .iter()
+                .position(|adapter| adapter.get_info() == *info)?;
+            let adapter = pool.spare.swap_remove(index);
+            *pool.lent(&adapter.get_info()) += 1;
+            Some(adapter)
+        });
+        if let Some(adapter) = spare {
+            return Ok(adapter);
+        }
+
+        let adapter = Self::instance().adapter(power_preference).await?;
+        let info = adapter.get_info();
+        Self::lock_adapters(|pool| {
+            pool.resolved
+                .retain(|(other, _)| *other != power_preference);
+            *pool.lent(&info) += 1;
+            pool.resolved.push((power_preference, info));
+        });
+        Ok(adapter)
+    }
+
+    fn lock_adapters<T>(f: impl FnOnce(&mut AdapterPool) -> T) -> T {
+        let mut pool = ADAPTER_POOL
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(pool.get_or_insert_with(Default::default))
+    }
+
+    /// Keep an adapter left unused by a cache hit, if one of its info is lent out; drop it otherwise.
+    fn recycle(adapter: Adapter) {
+        Self::lock_adapters(|pool| {
+            let lent = pool.lent(&adapter.get_info());
+            if *lent > 0 {
+                *lent -= 1;
+                pool.spare.push(adapter);
+            }
+        });
+    }
+
+    fn lock<T>(f: impl FnOnce(&mut Vec<(ContextKey, CachedContext)>) -> T) -> T {
+        let mut cache = CONTEXT_CACHE
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // contexts no longer alive are never handed out again
+        cache.retain(|(_, context)| context.is_alive());
+        f(&mut cache)
+    }
+
+    fn get(key: &ContextKey, reuse: ContextReuse) -> Option<Context> {
+        if reuse == ContextReuse::Never {
+            return None;
+        }
+        Self::lock(|cache| {
+            cache
+                .iter()
+                .find(|(other, _)| other == key)
+                .and_then(|(_, context)| context.upgrade())
+        })
+    }
+
+    /// Cache a newly built `context`, unless another one of the same key was cached meanwhile, which is returned instead.
+    fn insert(key: ContextKey, context: Context, reuse: ContextReuse) -> Context {
+        let cached = match reuse {
+            ContextReuse::Never => return context,
+            ContextReuse::Keep => CachedContext::Strong(context.clone()),
+            ContextReuse::WhileAlive => CachedContext::Weak(Arc::downgrade(&context.internal)),
+        };
+        Self::lock(|cache| {
+            if let Some(other) = cache
+                .iter()
+                .find(|(other, _)| other == &key)
+                .and_then(|(_, context)| context.upgrade())
+            {
+                return other;
+            }
+            cache.push((key, cached));
+            context
+        })
+    }
+
+    /// Number of contexts that can be handed out.
+    pub fn len() -> usize {
+        Self::lock(|cache| cache.len())
+    }
+
+    pub fn is_empty() -> bool {
+        Self::len() == 0
+    }
+
+    /// Stop handing out `context`. Returns `false` if it is not cached.
+    pub fn invalidate(context: &Context) -> bool {
+        Self::lock(|cache| {
+            let len = cache.len();
+            cache.retain(|(_, other)| !other.is(context));
+            cache.len() < len
+        })
+    }
+
+    /// Stop handing out any cached context.
+    pub fn clear() {
+        Self::lock(|cache| cache.clear());
+    }
 }
 
 /// A container of macro definitions in shader.
-#[derive(Debug, Default, Clone, Deref, DerefMut, PartialEq, Eq, Hash)]
+#[derive(Debug, Default, Clone, Deref, DerefMut, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Macros(BTreeMap<String, String>);
 
 impl Macros {
@@ -204,19 +896,34 @@ struct PipelineKey {
     name: String,
     entry_point: String,
     macros: Vec<(String, String)>,
+    /// Hash of the shader source, so that pipelines of outdated sources are never checked out.
+    source: u64,
 }
 
 impl PipelineKey {
-    fn new(name: String, entry_point: String, macros: Macros) -> Self {
+    fn new(name: String, source: &str, entry_point: String, macros: Macros) -> Self {
         let macros = macros.compile();
+        let mut hasher = FxHasher::default();
+        source.hash(&mut hasher);
+        let source = hasher.finish();
         Self {
             name,
             entry_point,
             macros,
+            source,
         }
     }
 }
 
+/// A pipeline that has been compiled by a context, which can be compiled ahead in another with [`ContextBuilder::warmup`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PipelineUsage {
+    pub name: String,
+    pub source: String,
+    pub entry_point: String,
+    pub macros: Macros,
+}
+
 #[derive(Debug)]
 pub struct CachedPipeline {
     pub pipeline: ComputePipeline,
@@ -237,6 +944,109 @@ struct BufferKey {
 
 impl Eq for Context {}
 
+impl Context {
+    /// A handle of the same device that allocates buffers in `arena`.
+    pub fn in_arena(&self, arena: Arena) -> Self {
+        Self {
+            internal: self.internal.clone(),
+            arena,
+        }
+    }
+
+    /// The arena that buffers allocated with this handle belong to.
+    #[inline]
+    pub fn arena(&self) -> Arena {
+        self.arena
+    }
+
+    pub(crate) fn checkout_buffer_init(&self, contents: &[u8], usage: BufferUsages) -> ArenaBuffer {
+        let size = std::mem::size_of_val(contents);
+        let _key = BufferKey { size, usage };
+        let desc = BufferInitDescriptor {
+            label: None,
+            contents,
+            usage,
+        };
+        // self.buffer_cache.checkout(
+        //     key,
+        //     || self.device.create_buffer_init(&desc),
+        //     |buffer| self.queue.write_buffer(buffer, 0, contents),
+        // )
+        let buffer: Arc<Buffer> = self
+            .allocate(|| self.device.create_buffer_init(&desc))
+            .into();
+        self.track(buffer)
+    }
+
+    pub(crate) fn checkout_buffer(&self, size: usize, usage: BufferUsages) -> ArenaBuffer {
+        let key = BufferKey { size, usage };
+        let desc = BufferDescriptor {
+            label: None,
+            size: size as u64,
+            usage,
+            mapped_at_creation: false,
+        };
+        let buffer = self.buffer_cache.checkout(
+            key,
+            || self.allocate(|| self.device.create_buffer(&desc)),
+            |_| {},
+        );
+        self.track(buffer)
+    }
+
+    /// Run `f`, and fail if the device runs out of memory allocating resources meanwhile,
+    /// or if an allocation takes the arena of this handle over its [budget](ContextInternal::set_budget).
+    /// The resource caches are cleared then, so that the failed allocations are not handed out again.
+    ///
+    /// Error scopes are device-wide, so each allocation of `f` opens one of its own, and calls from different threads
+    /// only wait for each other while allocating. Allocations outside of any call at the same time,
+    /// e.g., staging buffers of reading back, may still be blamed.
+    pub fn catch_oom<T>(&self, f: impl FnOnce() -> T) -> Result<T, OutOfMemoryError> {
+        let num_rejection = || {
+            self.arenas
+                .get(self.arena)
+                .rejections
+                .load(Ordering::Relaxed)
+        };
+        let rejected = num_rejection();
+
+        #[cfg(target_arch = "wasm32")]
+        let output = f();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let output = {
+            let scope = OomScope::enter();
+            let output = f();
+            if scope.failed() {
+                drop(scope);
+                self.clear_buffers();
+                return Err(OutOfMemoryError);
+            }
+            output
+        };
+
+        match num_rejection() > rejected {
+            true => {
+                self.clear_buffers();
+                Err(OutOfMemoryError)
+            }
+            false => Ok(output),
+        }
+    }
+
+    /// Account a buffer to the arena of the handle. Going over the budget fails the enclosing [`Context::catch_oom`].
+    fn track(&self, buffer: Arc<Buffer>) -> ArenaBuffer {
+        if let Err(err) = self.arenas.track(self.arena, buffer.size() as usize) {
+            log::warn!("{}", err);
+        }
+        ArenaBuffer(Arc::new(ArenaLease {
+            buffer,
+            arenas: self.arenas.clone(),
+            arena: self.arena,
+        }))
+    }
+}
+
 impl ContextInternal {
     pub fn checkout_pipeline(
         &self,
@@ -247,17 +1057,30 @@ impl ContextInternal {
         macros: Macros,
     ) -> Arc<CachedPipeline> {
         let name = name.as_ref();
+        let source = source.as_ref();
         let entry_point = entry_point.as_ref();
-        let key = PipelineKey::new(name.into(), entry_point.into(), macros.clone());
+        let key = PipelineKey::new(name.into(), source, entry_point.into(), macros.clone());
 
         self.pipeline_cache.checkout(
             key,
             || {
                 use gpp::{process_str, Context};
+
+                // pipelines with custom layouts cannot be warmed up
+                if layout.is_none() {
+                    let usage = PipelineUsage {
+                        name: name.into(),
+                        source: source.into(),
+                        entry_point: entry_point.into(),
+                        macros: macros.clone(),
+                    };
+                    self.pipelines.lock().unwrap().push(usage);
+                }
+
                 let mut context = Context::new();
                 context.macros = macros.0.into_iter().collect();
 
-                let shader = process_str(source.as_ref(), &mut context).unwrap();
+                let shader = process_str(source, &mut context).unwrap();
                 let module = &self.device.create_shader_module(ShaderModuleDescriptor {
                     label: Some(name),
                     source: wgpu::ShaderSource::Wgsl(Cow::from(shader)),
@@ -294,6 +1117,24 @@ impl ContextInternal {
         )
     }
 
+    /// Compile pipelines ahead, skipping those already compiled. See [`ContextBuilder::warmup`].
+    pub fn warmup(&self, pipelines: &[PipelineUsage]) {
+        for usage in pipelines {
+            self.checkout_pipeline(
+                &usage.name,
+                &usage.source,
+                &usage.entry_point,
+                None,
+                usage.macros.clone(),
+            );
+        }
+    }
+
+    /// Pipelines compiled so far, in the order they are compiled, to be saved and passed to [`ContextBuilder::warmup`] next time.
+    pub fn pipelines(&self) -> Vec<PipelineUsage> {
+        self.pipelines.lock().unwrap().clone()
+    }
+
     pub(crate) fn checkout_shape_uniform(&self, shape: Shape) -> Arc<Buffer> {
         let view = View {
             shape,
@@ -305,8 +1146,11 @@ impl ContextInternal {
             contents: &view.into_bytes(),
             usage: BufferUsages::UNIFORM,
         };
-        self.shape_cache
-            .checkout(view, || self.device.create_buffer_init(&desc), |_| {})
+        self.shape_cache.checkout(
+            view,
+            || self.allocate(|| self.device.create_buffer_init(&desc)),
+            |_| {},
+        )
     }
 
     pub(crate) fn checkout_view_uniform(&self, view: View) -> Arc<Buffer> {
@@ -315,36 +1159,11 @@ impl ContextInternal {
             contents: &view.into_bytes(),
             usage: BufferUsages::UNIFORM,
         };
-        self.shape_cache
-            .checkout(view, || self.device.create_buffer_init(&desc), |_| {})
-    }
-
-    pub(crate) fn checkout_buffer_init(&self, contents: &[u8], usage: BufferUsages) -> Arc<Buffer> {
-        let size = std::mem::size_of_val(contents);
-        let _key = BufferKey { size, usage };
-        let desc = BufferInitDescriptor {
-            label: None,
-            contents,
-            usage,
-        };
-        // self.buffer_cache.checkout(
-        //     key,
-        //     || self.device.create_buffer_init(&desc),
-        //     |buffer| self.queue.write_buffer(buffer, 0, contents),
-        // )
-        self.device.create_buffer_init(&desc).into()
-    }
-
-    pub(crate) fn checkout_buffer(&self, size: usize, usage: BufferUsages) -> Arc<Buffer> {
-        let key = BufferKey { size, usage };
-        let desc = BufferDescriptor {
-            label: None,
-            size: size as u64,
-            usage,
-            mapped_at_creation: false,
-        };
-        self.buffer_cache
-            .checkout(key, || self.device.create_buffer(&desc), |_| {})
+        self.shape_cache.checkout(
+            view,
+            || self.allocate(|| self.device.create_buffer_init(&desc)),
+            |_| {},
+        )
     }
 
     // pub(crate) fn checkout_buffer_uncached(&self, size: usize, usage: BufferUsages) -> Arc<Buffer> {
@@ -366,6 +1185,32 @@ impl ContextInternal {
         self.buffer_cache.maintain();
     }
 
+    /// Release cached buffers that are not in use, keeping compiled pipelines.
+    #[inline]
+    pub fn compact(&self) {
+        self.shape_cache.compact();
+        self.buffer_cache.compact();
+    }
+
+    /// Run the allocation `f`, in an error scope of its own if it is in a [`Context::catch_oom`] call on this thread,
+    /// which fails if the device runs out of memory.
+    fn allocate<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(not(target_arch = "wasm32"))]
+        if OOM_SCOPE.with(|scope| scope.get().0 > 0) {
+            use futures::FutureExt;
+
+            let _scope = self.scope.lock().expect("failed to lock error scope");
+            self.device.push_error_scope(ErrorFilter::OutOfMemory);
+            let output = f();
+            // native backends pop the scope at once, so the error is ready without blocking
+            if let Some(Some(_)) = self.device.pop_error_scope().now_or_never() {
+                OOM_SCOPE.with(|scope| scope.set((scope.get().0, true)));
+            }
+            return output;
+        }
+        f()
+    }
+
     /// Clear resource caches.
     #[inline]
     pub fn clear_buffers(&self) {
@@ -373,21 +1218,116 @@ impl ContextInternal {
         self.buffer_cache.clear();
     }
 
+    /// Set or clear the budget of an arena in bytes.
+    /// Allocations that take the arena over its budget fail the enclosing [`Context::catch_oom`].
+    pub fn set_budget(&self, arena: Arena, budget: Option<usize>) {
+        let budget = budget.unwrap_or(usize::MAX);
+        self.arenas
+            .get(arena)
+            .budget
+            .store(budget, Ordering::Relaxed);
+    }
+
+    /// Device memory of each arena, in the order of [`Arena::ALL`].
+    pub fn arena_stats(&self) -> Vec<ArenaStats> {
+        Arena::ALL.map(|arena| self.arenas.stats(arena)).to_vec()
+    }
+
+    /// Check that the buffers in use of an arena fit in its budget, e.g., after loading a model into it.
+    pub fn check_budget(&self, arena: Arena) -> Result<(), OverBudgetError> {
+        self.arenas.check(arena)
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
-    pub(crate) fn event(&self) -> flume::Sender<ContextEvent> {
+    pub(crate) fn event(&self) -> flume::Sender<ContextRequest> {
         self.event.clone()
     }
 
+    /// Submit command buffers to the queue, counting them as pending until they are done.
+    /// Returns the index of the submission, e.g., to [wait for](Self::wait_for) it alone.
+    pub fn submit(&self, commands: impl IntoIterator<Item = CommandBuffer>) -> SubmissionIndex {
+        let index = self.queue.submit(commands);
+
+        self.pending.fetch_add(1, Ordering::Release);
+        let pending = self.pending.clone();
+        self.queue.on_submitted_work_done(move || {
+            pending.fetch_sub(1, Ordering::Release);
+        });
+
+        if let (Some(_), PollStrategy::Thread) = (self.max_pending, self.poll) {
+            if let Ok(mut submissions) = self.submissions.lock() {
+                submissions.push_back(index.clone());
+            }
+        }
+        index
+    }
+
+    /// Wait until fewer than [`ContextBuilder::max_pending`] submissions are in flight, so that the next one is within the bound.
+    /// The polling thread of the context waits on the device, so that the caller is not blocked.
+    pub async fn throttle(&self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let (Some(max_pending), PollStrategy::Thread) = (self.max_pending, self.poll) else {
+                return;
+            };
+            // waiting for the latest of the oldest submissions waits for all of them
+            let index = {
+                let Ok(mut submissions) = self.submissions.lock() else {
+                    return;
+                };
+                let num_done = (submissions.len() + 1).saturating_sub(max_pending);
+                let index = submissions.drain(..num_done).next_back();
+                index
+            };
+            let Some(index) = index else {
+                return;
+            };
+            let (sender, receiver) = futures::channel::oneshot::channel();
+            if self
+                .event
+                .send(ContextRequest::WaitFor(index, sender))
+                .is_ok()
+            {
+                let _ = receiver.await;
+            }
+        }
+    }
+
+    /// Number of submissions through [`Context::submit`] that are not known to be done.
+    /// Submissions are known to be done once the device is polled after they finish.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Wait until all work submitted so far is done on the device, e.g., to measure the latency of a step,
+    /// or to coordinate with rendering. With [`PollStrategy::External`], this completes once the embedder polls the device.
+    pub async fn sync(&self) {
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        self.queue.on_submitted_work_done(move || {
+            let _ = sender.send(());
+        });
+        #[cfg(not(target_arch = "wasm32"))]
+        if let PollStrategy::Thread = self.poll {
+            let _ = self.event.send(ContextRequest::Wait);
+        }
+        let _ = receiver.await;
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
-    fn read_back_buffer(&self, buffer: Arc<Buffer>) -> Box<[u8]> {
+    fn read_back_buffer(&self, buffer: ArenaBuffer, index: Option<SubmissionIndex>) -> Box<[u8]> {
         assert!(buffer.usage().contains(BufferUsages::MAP_READ));
 
-        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let (sender, receiver) = flume::bounded(1);
         let slice = buffer.slice(..);
         slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
 
-        self.device.poll(wgpu::MaintainBase::Wait);
-        receiver.blocking_recv().unwrap().unwrap();
+        // with external polling, this waits until the embedder polls the device
+        match index {
+            Some(index) => self.wait_for(index),
+            None => self.wait(),
+        }
+        receiver.recv().unwrap().unwrap();
 
         let data = {
             let map = slice.get_mapped_range();
@@ -405,6 +1345,61 @@ impl ContextInternal {
         data
     }
 
+    #[inline]
+    pub fn poll_strategy(&self) -> PollStrategy {
+        self.poll
+    }
+
+    /// Poll the device without blocking, returning `true` if all submitted work is done.
+    /// Embedders that choose [`PollStrategy::External`] call this (or poll the device themselves) regularly.
+    #[inline]
+    pub fn poll(&self) -> bool {
+        self.device.poll(wgpu::Maintain::Poll).is_queue_empty()
+    }
+
+    /// Block until all submitted work is done, unless polling is left to the embedder.
+    pub fn wait(&self) {
+        match self.poll {
+            PollStrategy::Thread => {
+                self.device.poll(wgpu::Maintain::Wait);
+            }
+            PollStrategy::External => {}
+        }
+    }
+
+    /// Block until one submission is done, leaving later ones running, unless polling is left to the embedder.
+    pub fn wait_for(&self, index: SubmissionIndex) {
+        match self.poll {
+            PollStrategy::Thread => {
+                self.device
+                    .poll(wgpu::Maintain::WaitForSubmissionIndex(index));
+            }
+            PollStrategy::External => {}
+        }
+    }
+
+    /// Check if native `f16` arithmetic is enabled in shaders.
+    #[inline]
+    pub fn shader_f16(&self) -> bool {
+        self.device.features().contains(Features::SHADER_F16)
+    }
+
+    pub fn report(&self) -> ContextReport {
+        let features = self.device.features();
+        ContextReport {
+            adapter: self.adapter.get_info(),
+            features,
+            shader_f16: features.contains(Features::SHADER_F16),
+            subgroup: features.contains(Features::SUBGROUP),
+        }
+    }
+
+    /// The [`TuningProfile`] of the device, detected or as set by [`ContextBuilder::tuning`].
+    #[inline]
+    pub fn tuning(&self) -> TuningProfile {
+        self.tuning
+    }
+
     #[cfg(feature = "subgroup-ops")]
     pub fn min_subgroup_size(&self) -> u32 {
         self.adapter.limits().min_subgroup_size
@@ -415,3 +1410,281 @@ impl ContextInternal {
         self.adapter.limits().max_subgroup_size
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use wgpu::{AdapterInfo, Backend, DeviceType, Features, Instance, PowerPreference};
+
+    use super::{
+        Arena, Context, ContextBuilder, ContextCache, ContextReuse, GpuClass, InstanceExt,
+        OverBudgetError, PollStrategy, TuningProfile,
+    };
+    use crate::tensor::{kind::ReadWrite, ops::TensorOp, TensorGpu};
+
+    #[test]
+    fn test_shader_f16() -> Result<()> {
+        pollster::block_on(async {
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let supported = adapter.features().contains(Features::SHADER_F16);
+
+            // requesting the feature explicitly must not fail on adapters without it
+            let Ok(context) = ContextBuilder::new(adapter)
+                .update_features(|features| features.insert(Features::SHADER_F16))
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+            let report = context.report();
+            assert_eq!(context.shader_f16(), supported);
+            assert_eq!(report.shader_f16, supported);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_context_cache() -> Result<()> {
+        pollster::block_on(async {
+            let build = |reuse: ContextReuse, max_pending: usize| async move {
+                let adapter = ContextCache::adapter(PowerPreference::HighPerformance).await?;
+                let context = ContextBuilder::new(adapter)
+                    .max_pending(max_pending)
+                    .reuse(reuse)
+                    .build()
+                    .await?;
+                anyhow::Ok(context)
+            };
+            let Ok(first) = build(ContextReuse::Keep, 7).await else {
+                return Ok(());
+            };
+
+            // the same adapter and settings share the device, unless asked not to
+            let second = build(ContextReuse::Keep, 7).await?;
+            assert_eq!(first.id, second.id);
+            assert_ne!(build(ContextReuse::Never, 7).await?.id, first.id);
+            assert_ne!(build(ContextReuse::Keep, 6).await?.id, first.id);
+
+            // the cache keeps the context alive until invalidated
+            let id = first.id;
+            drop((first, second));
+            let third = build(ContextReuse::Keep, 7).await?;
+            assert_eq!(third.id, id);
+            assert!(ContextCache::invalidate(&third));
+            assert!(!ContextCache::invalidate(&third));
+            assert_ne!(build(ContextReuse::Keep, 7).await?.id, id);
+
+            // or only while in use elsewhere
+            let weak = build(ContextReuse::WhileAlive, 5).await?;
+            assert_eq!(build(ContextReuse::WhileAlive, 5).await?.id, weak.id);
+            let id = weak.id;
+            drop(weak);
+            assert_ne!(build(ContextReuse::WhileAlive, 5).await?.id, id);
+
+            // hits with adapters of other instances keep no more adapters than the shared instance handed out
+            let num_spare = || ContextCache::lock_adapters(|pool| pool.spare.len());
+            let num_lent =
+                ContextCache::lock_adapters(|pool| pool.lent.iter().map(|(_, n)| n).sum::<usize>());
+            let spare = num_spare();
+            let instance = Instance::default();
+            for _ in 0..num_lent + 3 {
+                let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+                ContextBuilder::new(adapter)
+                    .max_pending(7)
+                    .reuse(ContextReuse::Keep)
+                    .build()
+                    .await?;
+            }
+            assert_eq!(num_spare(), spare + num_lent);
+
+            ContextCache::clear();
+            assert!(ContextCache::is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_external_poll() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .poll(PollStrategy::External)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+            assert_eq!(context.poll_strategy(), PollStrategy::External);
+
+            let data = (0..64).map(|x| x as f32).collect::<Vec<_>>();
+            let tensor: TensorGpu<f32, ReadWrite> =
+                context.tensor_from_data([64, 1, 1, 1], data.clone())?;
+            let handle = tokio::spawn(async move { tensor.back().await });
+
+            // reading back only completes once the device is polled here
+            while !handle.is_finished() {
+                context.poll();
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            assert_eq!(handle.await?.to_vec(), data);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_sync() -> Result<()> {
+        pollster::block_on(async {
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter).max_pending(2).build().await else {
+                return Ok(());
+            };
+
+            let data = (0..64).map(|x| x as f32).collect::<Vec<_>>();
+            let source: TensorGpu<f32, ReadWrite> =
+                context.tensor_from_data([64, 1, 1, 1], data.clone())?;
+            let target: TensorGpu<f32, ReadWrite> = context.tensor_init([64, 1, 1, 1]);
+            for _ in 0..8 {
+                let op =
+                    TensorOp::blit(source.view(.., .., .., ..)?, target.view(.., .., .., ..)?)?;
+                context.throttle().await;
+                context.submit(context.encode(&op));
+                assert!(context.pending() <= 2);
+            }
+
+            context.sync().await;
+            assert_eq!(context.pending(), 0);
+            assert_eq!(target.back_in_place().to_vec(), data);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_arenas() -> Result<()> {
+        pollster::block_on(async {
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter).build().await else {
+                return Ok(());
+            };
+            let model = context.in_arena(Arena::Model);
+            assert_eq!(model.arena(), Arena::Model);
+            assert_eq!(context.arena(), Arena::Workspace);
+
+            let weights: TensorGpu<f32, ReadWrite> = model.tensor_init([64, 1, 1, 1]);
+            let stats = context.arena_stats();
+            assert_eq!(stats[0].arena, Arena::Model);
+            assert_eq!((stats[0].size, stats[0].allocations), (256, 1));
+            assert_eq!(stats[2].size, 0);
+
+            // a buffer dropped into the cache is no longer in use, and moves to the arena that reuses it
+            drop(weights);
+            let buffer: TensorGpu<f32, ReadWrite> = context.tensor_init([64, 1, 1, 1]);
+            let stats = context.arena_stats();
+            assert_eq!((stats[0].size, stats[0].peak), (0, 256));
+            assert_eq!(stats[2].size, 256);
+
+            // allocations over budget fail the enclosing scope, while other arenas are not affected
+            context.set_budget(Arena::Workspace, Some(512));
+            let alloc = |context: &Context, len: usize| {
+                context.catch_oom(|| {
+                    let _: TensorGpu<f32, ReadWrite> = context.tensor_init([len, 1, 1, 1]);
+                })
+            };
+            assert!(alloc(&context, 32).is_ok());
+            assert!(alloc(&context, 128).is_err());
+            assert!(alloc(&model, 128).is_ok());
+            assert_eq!(context.arena_stats()[2].rejections, 1);
+
+            // a rejection in the workspace meanwhile doesn't fail a scope of the model arena
+            let output = model.catch_oom(|| alloc(&context, 128));
+            assert!(matches!(output, Ok(Err(_))));
+            assert_eq!(context.arena_stats()[2].rejections, 2);
+
+            context.set_budget(Arena::Workspace, Some(128));
+            assert_eq!(
+                context.check_budget(Arena::Workspace),
+                Err(OverBudgetError {
+                    arena: Arena::Workspace,
+                    size: 256,
+                    budget: 128
+                })
+            );
+            drop(buffer);
+            assert_eq!(context.check_budget(Arena::Workspace), Ok(()));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_concurrent_catch_oom() -> Result<()> {
+        pollster::block_on(async {
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter).build().await else {
+                return Ok(());
+            };
+
+            // a call waiting in its closure does not hold up the allocations of calls on other threads
+            std::thread::scope(|scope| {
+                let context = &context;
+                let (sender, receiver) = std::sync::mpsc::channel();
+                let output = context.catch_oom(|| {
+                    scope.spawn(move || {
+                        let output = context.catch_oom(|| {
+                            let _: TensorGpu<f32, ReadWrite> = context.tensor_init([64, 1, 1, 1]);
+                        });
+                        let _ = sender.send(output);
+                    });
+                    receiver.recv_timeout(std::time::Duration::from_secs(10))
+                });
+                assert_eq!(output, Ok(Ok(Ok(()))));
+            });
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_tuning_profile() {
+        let info = |backend, device_type| AdapterInfo {
+            name: "test".into(),
+            vendor: 0,
+            device: 0,
+            device_type,
+            driver: "".into(),
+            driver_info: "".into(),
+            backend,
+        };
+        let cases = [
+            (Backend::Vulkan, DeviceType::DiscreteGpu, GpuClass::Discrete),
+            (
+                Backend::Dx12,
+                DeviceType::IntegratedGpu,
+                GpuClass::Integrated,
+            ),
+            (Backend::Metal, DeviceType::IntegratedGpu, GpuClass::Apple),
+            (Backend::Vulkan, DeviceType::Cpu, GpuClass::Software),
+            (Backend::Gl, DeviceType::VirtualGpu, GpuClass::Unknown),
+        ];
+        for (backend, device_type, class) in cases {
+            let profile = TuningProfile::detect(&info(backend, device_type));
+            assert_eq!(profile.class, class);
+            assert!(profile.token_chunk_size > 0 && profile.num_layer_chunk > 0);
+        }
+        assert_eq!(TuningProfile::default().num_layer_chunk, 4);
+        assert_eq!(TuningProfile::new(GpuClass::Software).max_pending, Some(1));
+    }
+}
@@ -8,7 +8,7 @@
 //! - Support Nvidia/AMD/Intel GPUs, including integrated GPUs.
 //! - Vulkan/Dx12/OpenGL backends.
 //! - Batched inference.
-//! - Int8 and NF4 quantization.
+//! - Int8, NF4, experimental Fp8 (E4M3/E5M2) and GGUF-style Q4_K/Q5_K quantization.
 //! - Very fast.
 //! - LoRA merging at loading time.
 //! - Support RWKV V4, V5 and V6.
@@ -34,6 +34,8 @@
 //!
 #![doc = document_features::document_features!()]
 
+#[cfg(feature = "capi")]
+pub mod capi;
 pub mod context;
 #[cfg(feature = "vanilla")]
 pub mod model;
@@ -41,6 +43,9 @@ pub mod num;
 #[cfg(feature = "runtime")]
 pub mod runtime;
 pub mod tensor;
+#[cfg(feature = "tokenizer")]
 pub mod tokenizer;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 pub use wgpu;
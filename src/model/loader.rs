@@ -356,7 +356,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -393,7 +393,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::opposite_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -431,7 +431,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::stable_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -477,7 +477,7 @@ impl<R: Reader> Loader<R> {
             )?;
             ops.push(op);
 
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             tensor_f16
         };
         Ok(tensor)
@@ -510,7 +510,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -544,7 +544,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -577,7 +577,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
@@ -615,7 +615,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
@@ -639,7 +639,7 @@ impl<R: Reader> Loader<R> {
                 ops.push(op);
             }
 
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             Ok(tensor.back().await)
         }
     }
@@ -1,4 +1,4 @@
-use std::{convert::Infallible, marker::PhantomData};
+use std::{convert::Infallible, marker::PhantomData, sync::Arc};
 
 use anyhow::Result;
 use half::f16;
@@ -19,9 +19,9 @@ use crate::{
     tensor::{
         kind::ReadWrite,
         matrix::Matrix,
-        ops::{Activation, TensorCommand, TensorOp},
+        ops::{Activation, BatchCopy, TensorCommand, TensorOp},
         shape::Shape,
-        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        Cursor, DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
         TensorShape,
     },
 };
@@ -130,7 +130,7 @@ impl<F: Float> Runtime<F> {
     pub fn new(context: &Context, info: &ModelInfo, num_token: usize) -> Self {
         let shape = Shape::new(info.num_emb, num_token, 1, 1);
         let tokens_shape = Shape::new(num_token, 1, 1, 1);
-        let cursors_shape = Shape::new(num_token, 1, 1, 1);
+        let cursors_shape = Shape::new(Cursor::PACKED_SIZE, num_token, 1, 1);
         let hidden_shape = Shape::new(info.num_hidden, num_token, 1, 1);
 
         Self {
@@ -289,7 +289,7 @@ impl super::ModelState for ModelState {
 
         let mut encoder = context.device.create_command_encoder(&Default::default());
         encoder.copy_tensor(self, &map).expect("back entire state");
-        context.queue.submit(Some(encoder.finish()));
+        context.submit(Some(encoder.finish()));
 
         let data = map.back().await.into();
         BackedState { shape, data }
@@ -303,7 +303,7 @@ impl super::ModelState for ModelState {
 
         let mut encoder = context.device.create_command_encoder(&Default::default());
         encoder.copy_tensor_batch(self, &map, batch, 0)?;
-        context.queue.submit(Some(encoder.finish()));
+        context.submit(Some(encoder.finish()));
 
         let data = map.back().await.into();
         Ok(BackedState { shape, data })
@@ -313,7 +313,7 @@ impl super::ModelState for ModelState {
         let context = self.context();
         let mut encoder = context.device.create_command_encoder(&Default::default());
         encoder.copy_tensor(self, other)?;
-        context.queue.submit(Some(encoder.finish()));
+        context.submit(Some(encoder.finish()));
         Ok(())
     }
 
@@ -324,11 +324,19 @@ impl super::ModelState for ModelState {
         to_batch: usize,
     ) -> Result<(), TensorError> {
         let context = self.context();
-        let op = TensorOp::blit(
-            self.view(.., .., from_batch, ..)?,
-            other.view(.., .., to_batch, ..)?,
-        )?;
-        context.queue.submit(context.encode(&op));
+        if Arc::ptr_eq(&self.buffer, &other.buffer) {
+            // a buffer cannot be copied into itself, so blit between batches of the same state
+            let op = TensorOp::blit(
+                self.view(.., .., from_batch, ..)?,
+                other.view(.., .., to_batch, ..)?,
+            )?;
+            context.submit(context.encode(&op));
+            return Ok(());
+        }
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        encoder.copy_tensor_batches([BatchCopy::new(&self.0, from_batch, &other.0, to_batch)])?;
+        context.submit(Some(encoder.finish()));
         Ok(())
     }
 }
@@ -430,8 +438,8 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
             w: Matrix::Fp16(loader.load_matrix_f16("head.weight").await?),
         };
 
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
+        context.submit(None);
+        context.wait();
 
         let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
         let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
@@ -494,8 +502,8 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
                 w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount).await?,
             };
 
-            context.queue.submit(None);
-            context.device.poll(wgpu::MaintainBase::Wait);
+            context.submit(None);
+            context.wait();
 
             layers.push(Layer {
                 att_layer_norm,
@@ -505,8 +513,8 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
             })
         }
 
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
+        context.submit(None);
+        context.wait();
 
         let tensor = ModelTensor {
             embed,
@@ -872,7 +880,7 @@ impl<F: Float + Hom<f16>> ModelRunInternal for Model<F> {
             ]);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok((header.head_o.clone(), redirect))
     }
 }
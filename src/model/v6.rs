@@ -1,4 +1,4 @@
-use std::{convert::Infallible, marker::PhantomData};
+use std::{convert::Infallible, marker::PhantomData, sync::Arc};
 
 use anyhow::Result;
 use half::f16;
@@ -19,9 +19,9 @@ use crate::{
     tensor::{
         kind::ReadWrite,
         matrix::Matrix,
-        ops::{Activation, TensorCommand, TensorOp},
+        ops::{Activation, BatchCopy, TensorCommand, TensorOp},
         shape::{Shape, TensorDimension},
-        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        Cursor, DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
         TensorReshape, TensorShape,
     },
 };
@@ -156,7 +156,7 @@ impl<F: Float> Runtime<F> {
     pub fn new(context: &Context, info: &ModelInfo, num_token: usize) -> Self {
         let shape = Shape::new(info.num_emb, num_token, 1, 1);
         let tokens_shape = Shape::new(num_token, 1, 1, 1);
-        let cursors_shape = Shape::new(num_token, 1, 1, 1);
+        let cursors_shape = Shape::new(Cursor::PACKED_SIZE, num_token, 1, 1);
         let hidden_shape = Shape::new(info.num_hidden, num_token, 1, 1);
         let time_mix_shape = Shape::new(info.num_emb, num_token, 5, 1);
         let time_mix_x_shape = Shape::new(info.time_mix_adapter_size, 5, num_token, 1);
@@ -367,7 +367,7 @@ impl super::ModelState for ModelState {
 
             let mut encoder = context.device.create_command_encoder(&Default::default());
             encoder.copy_tensor(state, &map).expect("back entire state");
-            context.queue.submit(Some(encoder.finish()));
+            context.submit(Some(encoder.finish()));
 
             let host = map.back().await;
             data.push((shape, host.to_vec()))
@@ -391,7 +391,7 @@ impl super::ModelState for ModelState {
 
             let mut encoder = context.device.create_command_encoder(&Default::default());
             encoder.copy_tensor_batch(state, &map, batch, 0)?;
-            context.queue.submit(Some(encoder.finish()));
+            context.submit(Some(encoder.finish()));
 
             let host = map.back().await;
             data.push((shape, host.to_vec()));
@@ -406,13 +406,12 @@ impl super::ModelState for ModelState {
     }
 
     fn blit(&self, other: &ModelState) -> Result<(), TensorError> {
+        let context = self.state[0].context();
+        let mut encoder = context.device.create_command_encoder(&Default::default());
         for (state, other) in self.state.iter().zip(other.state.iter()) {
-            let context = state.context();
-            state.check_shape(other.shape())?;
-            let mut encoder = context.device.create_command_encoder(&Default::default());
             encoder.copy_tensor(state, other)?;
-            context.queue.submit(Some(encoder.finish()));
         }
+        context.submit(Some(encoder.finish()));
         Ok(())
     }
 
@@ -422,14 +421,32 @@ impl super::ModelState for ModelState {
         from_batch: usize,
         to_batch: usize,
     ) -> Result<(), TensorError> {
-        for (state, other) in self.state.iter().zip(other.state.iter()) {
-            let context = state.context();
-            let op = TensorOp::blit(
-                state.view(.., .., from_batch, ..)?,
-                other.view(.., .., to_batch, ..)?,
-            )?;
-            context.queue.submit(context.encode(&op));
+        let context = self.state[0].context();
+        if Arc::ptr_eq(&self.state[0].buffer, &other.state[0].buffer) {
+            // a buffer cannot be copied into itself, so blit between batches of the same state
+            let ops = self
+                .state
+                .iter()
+                .zip(other.state.iter())
+                .map(|(state, other)| {
+                    TensorOp::blit(
+                        state.view(.., .., from_batch, ..)?,
+                        other.view(.., .., to_batch, ..)?,
+                    )
+                })
+                .try_collect()?;
+            context.submit(context.encode(&TensorOp::List(ops)));
+            return Ok(());
         }
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        let copies = self
+            .state
+            .iter()
+            .zip(other.state.iter())
+            .map(|(state, other)| BatchCopy::new(state, from_batch, other, to_batch));
+        encoder.copy_tensor_batches(copies)?;
+        context.submit(Some(encoder.finish()));
         Ok(())
     }
 }
@@ -536,8 +553,8 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
             w: Matrix::Fp16(loader.load_matrix_f16("head.weight").await?),
         };
 
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
+        context.submit(None);
+        context.wait();
 
         let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
         let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
@@ -641,8 +658,8 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
                 w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount).await?,
             };
 
-            context.queue.submit(None);
-            context.device.poll(wgpu::MaintainBase::Wait);
+            context.submit(None);
+            context.wait();
 
             layers.push(Layer {
                 att_layer_norm,
@@ -652,8 +669,8 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
             })
         }
 
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
+        context.submit(None);
+        context.wait();
 
         let tensor = ModelTensor {
             embed,
@@ -1166,7 +1183,7 @@ impl<F: Float> ModelRunInternal for Model<F> {
             ]);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok((header.head_o.clone(), redirect))
     }
 }
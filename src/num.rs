@@ -1,5 +1,6 @@
 use bytemuck::Pod;
 use half::f16;
+#[cfg(any(feature = "runtime", feature = "vanilla"))]
 use safetensors::Dtype;
 
 pub trait Zero: Sized + core::ops::Add<Self, Output = Self> {
@@ -76,22 +77,29 @@ pub trait Scalar: Sized + Clone + Copy + Pod + Zero + One + Send + Sync + sealed
         std::mem::size_of::<Self>()
     }
 
+    /// The type of the scalar in a safetensors file.
+    #[cfg(any(feature = "runtime", feature = "vanilla"))]
     const DATA_TYPE: Dtype;
 }
 
 impl Scalar for f32 {
+    #[cfg(any(feature = "runtime", feature = "vanilla"))]
     const DATA_TYPE: Dtype = Dtype::F32;
 }
 impl Scalar for f16 {
+    #[cfg(any(feature = "runtime", feature = "vanilla"))]
     const DATA_TYPE: Dtype = Dtype::F16;
 }
 impl Scalar for u8 {
+    #[cfg(any(feature = "runtime", feature = "vanilla"))]
     const DATA_TYPE: Dtype = Dtype::U8;
 }
 impl Scalar for u16 {
+    #[cfg(any(feature = "runtime", feature = "vanilla"))]
     const DATA_TYPE: Dtype = Dtype::U16;
 }
 impl Scalar for u32 {
+    #[cfg(any(feature = "runtime", feature = "vanilla"))]
     const DATA_TYPE: Dtype = Dtype::U32;
 }
 
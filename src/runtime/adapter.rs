@@ -0,0 +1,351 @@
+use std::{future::Future, sync::Arc};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    infer::{InferInput, InferInputBatch, InferOption, InferOutput, SampleOption, StopReason},
+    model::State,
+    prompt::{ChatTemplate, PromptFormat},
+    JobRuntime, SessionId,
+};
+use crate::tokenizer::Tokenizer;
+
+/// A message of a conversation, as agent frameworks pass them to their LLMs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    /// Instructions that come before the conversation.
+    System(String),
+    User(String),
+    Assistant(String),
+}
+
+/// The reply of [`TextGeneration::generate`], along with the numbers of tokens used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    pub text: String,
+    /// Why the generation ended. A token that completes a stop text counts as a stop token.
+    pub reason: StopReason,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+/// Text generation in the shape of the LLM traits of Rust agent frameworks, e.g., `llm-chain` or `langchain-rust`,
+/// so that implementing theirs is a matter of forwarding calls.
+pub trait TextGeneration {
+    /// Reply to a conversation.
+    fn generate(&self, messages: &[Message]) -> impl Future<Output = Result<Generation>> + Send;
+
+    /// Reply to a single user message.
+    fn invoke(&self, prompt: &str) -> impl Future<Output = Result<String>> + Send
+    where
+        Self: Sync,
+    {
+        let messages = [Message::User(prompt.into())];
+        async move { Ok(self.generate(&messages).await?.text) }
+    }
+}
+
+/// Text embeddings in the shape of the embedder traits of Rust agent frameworks.
+pub trait Embedding {
+    /// Embed each document into a vector.
+    fn embed_documents(
+        &self,
+        documents: &[String],
+    ) -> impl Future<Output = Result<Vec<Vec<f64>>>> + Send;
+
+    /// Embed a search query into a vector.
+    fn embed_query(&self, text: &str) -> impl Future<Output = Result<Vec<f64>>> + Send
+    where
+        Self: Sync,
+    {
+        let documents = [text.to_string()];
+        async move {
+            let mut embeds = self.embed_documents(&documents).await?;
+            Ok(embeds.remove(0))
+        }
+    }
+}
+
+/// How an [`Adapter`] writes prompts and generates replies.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdapterOption {
+    pub template: ChatTemplate,
+    pub sample: SampleOption,
+    /// Maximum number of tokens in a reply.
+    pub max_tokens: usize,
+    pub token_chunk_size: usize,
+}
+
+impl Default for AdapterOption {
+    fn default() -> Self {
+        Self {
+            template: PromptFormat::World.template(),
+            sample: Default::default(),
+            max_tokens: 512,
+            token_chunk_size: 128,
+        }
+    }
+}
+
+/// Implements [`TextGeneration`] and [`Embedding`] over a runtime and a tokenizer.
+///
+/// Every call starts from the initial state: a reply runs in batch 0 of `state`, which must be the runtime's state,
+/// and documents are embedded as many at a time as there are batches. Calls through clones of an adapter
+/// take turns, since they share the state; other users of the runtime must not run at the same time.
+#[derive(Clone)]
+pub struct Adapter {
+    runtime: JobRuntime<InferInput, InferOutput>,
+    state: Arc<dyn State + Send + Sync>,
+    tokenizer: Arc<Tokenizer>,
+    pub option: AdapterOption,
+    session: Option<SessionId>,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Adapter {
+    pub fn new(
+        runtime: JobRuntime<InferInput, InferOutput>,
+        state: impl State + Send + Sync + 'static,
+        tokenizer: Tokenizer,
+        option: AdapterOption,
+    ) -> Self {
+        Self {
+            runtime,
+            state: Arc::new(state),
+            tokenizer: Arc::new(tokenizer),
+            option,
+            session: None,
+            lock: Default::default(),
+        }
+    }
+
+    /// The session to account the work of all calls to.
+    pub fn session(mut self, value: SessionId) -> Self {
+        self.session = Some(value);
+        self
+    }
+
+    /// Render a conversation into a prompt that ends where the model writes its reply.
+    pub fn render(&self, messages: &[Message]) -> String {
+        let ChatTemplate { user, bot, .. } = &self.option.template;
+        let mut text: String = messages
+            .iter()
+            .map(|message| match message {
+                Message::System(text) => format!("{}\n\n", text.trim()),
+                Message::User(text) => format!("{user}: {}\n\n", text.trim()),
+                Message::Assistant(text) => format!("{bot}: {}\n\n", text.trim()),
+            })
+            .collect();
+        text.push_str(bot);
+        text.push(':');
+        text
+    }
+
+    /// Reset a batch to the initial state.
+    fn reset(&self, batch: usize) -> Result<()> {
+        Ok(self.state.load(self.state.init(), batch)?)
+    }
+
+    /// Run `tokens` through batch 0, and return the logits of the last one.
+    async fn run(&self, tokens: &[u16]) -> Result<Vec<f32>> {
+        let batches = (0..self.state.num_batch())
+            .map(|batch| InferInputBatch {
+                tokens: match batch {
+                    0 => tokens.into(),
+                    _ => vec![].into(),
+                },
+                option: InferOption::Last,
+                session: self.session,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, self.option.token_chunk_size);
+        let mut logits = vec![];
+        while input.num_token() > 0 {
+            let (next, InferOutput(output)) = self.runtime.infer(input).await;
+            input = next;
+            match output.into_iter().next() {
+                Some(output) if output.size() > 0 => logits = output.to_vec(),
+                _ => {}
+            }
+        }
+        Ok(logits)
+    }
+
+    async fn complete(&self, prompt: &[u16]) -> Result<Generation> {
+        let AdapterOption {
+            template,
+            sample,
+            max_tokens,
+            ..
+        } = &self.option;
+        if prompt.is_empty() {
+            bail!("empty prompt");
+        }
+        if *max_tokens == 0 {
+            bail!("no token to reply with");
+        }
+
+        let _guard = self.lock.lock().await;
+        self.reset(0)?;
+        let mut logits = self.run(prompt).await?;
+
+        let mut tokens = vec![];
+        let mut random = sample.seed;
+        let (text, reason) = loop {
+            let token = sample.sample(&logits, &mut random);
+            if template.stop_tokens.contains(&token) {
+                let text = String::from_utf8_lossy(&self.tokenizer.decode(&tokens)?).into();
+                break (text, StopReason::Token(token));
+            }
+            tokens.push(token);
+
+            let text = String::from_utf8_lossy(&self.tokenizer.decode(&tokens)?).to_string();
+            if let Some(text) = template.cut(&text) {
+                break (text.into(), StopReason::Token(token));
+            }
+            if tokens.len() >= *max_tokens {
+                break (text, StopReason::Length);
+            }
+            logits = self.run(&[token]).await?;
+        };
+
+        Ok(Generation {
+            text: text.trim().into(),
+            reason,
+            prompt_tokens: prompt.len(),
+            completion_tokens: tokens.len(),
+        })
+    }
+
+    async fn embed(&self, documents: &[String]) -> Result<Vec<Vec<f64>>> {
+        let prompts = documents
+            .iter()
+            .map(|document| Ok(self.tokenizer.encode(document.as_bytes())?))
+            .collect::<Result<Vec<_>>>()?;
+        if prompts.iter().any(Vec::is_empty) {
+            bail!("empty document");
+        }
+
+        let _guard = self.lock.lock().await;
+        let mut embeds = Vec::with_capacity(prompts.len());
+        for prompts in prompts.chunks(self.state.num_batch()) {
+            for batch in 0..prompts.len() {
+                self.reset(batch)?;
+            }
+            let chunk = self.option.token_chunk_size;
+            for embed in self.runtime.embed(prompts, chunk).await? {
+                embeds.push(embed.iter().map(|&x| x as f64).collect());
+            }
+        }
+        Ok(embeds)
+    }
+}
+
+impl TextGeneration for Adapter {
+    fn generate(&self, messages: &[Message]) -> impl Future<Output = Result<Generation>> + Send {
+        let prompt = self.render(messages);
+        async move {
+            let prompt = self.tokenizer.encode(prompt.as_bytes())?;
+            self.complete(&prompt).await
+        }
+    }
+}
+
+impl Embedding for Adapter {
+    fn embed_documents(
+        &self,
+        documents: &[String],
+    ) -> impl Future<Output = Result<Vec<Vec<f64>>>> + Send {
+        self.embed(documents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use anyhow::Result;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::{Adapter, AdapterOption, Embedding, Message, TextGeneration};
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            infer::{SampleOption, StopReason},
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelRuntime, ModelVersion},
+            tiny::TinyModel,
+            v5, JobRuntime,
+        },
+        tokenizer::Tokenizer,
+    };
+
+    #[test]
+    fn test_adapter() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime = v5::ModelRuntime::<f32>::new(model, 2);
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+
+            // one byte per token, and token 0 ends the text
+            let vocab: BTreeMap<u16, Vec<u8>> = (1..=255).map(|x| (x as u16, vec![x])).collect();
+            let tokenizer = Tokenizer::new(&serde_json::to_string(&vocab)?)?;
+
+            let option = AdapterOption {
+                sample: SampleOption {
+                    temperature: 0.0,
+                    ..Default::default()
+                },
+                max_tokens: 8,
+                token_chunk_size: 4,
+                ..Default::default()
+            };
+            let adapter = Adapter::new(runtime, state, tokenizer, option);
+            let messages = [
+                Message::System("Be brief.".into()),
+                Message::User("Hi".into()),
+            ];
+            assert_eq!(
+                adapter.render(&messages),
+                "Be brief.\n\nUser: Hi\n\nAssistant:"
+            );
+
+            // every call starts afresh, so replies are reproducible
+            let reply = adapter.generate(&messages).await?;
+            assert_eq!(adapter.generate(&messages).await?, reply);
+            assert_eq!(reply.prompt_tokens, 31);
+            assert!(reply.completion_tokens <= 8);
+            if reply.reason == StopReason::Length {
+                assert_eq!(reply.completion_tokens, 8);
+            }
+            let text = adapter.invoke("Hi").await?;
+            assert_eq!(text, adapter.generate(&messages[1..]).await?.text);
+
+            // more documents than batches are embedded in turns, each from the initial state
+            let documents = ["a", "bc", "def"].map(String::from);
+            let embeds = adapter.embed_documents(&documents).await?;
+            assert_eq!(embeds.len(), 3);
+            assert!(embeds.iter().all(|embed| embed.len() == info.num_emb));
+            assert_eq!(adapter.embed_query("def").await?, embeds[2]);
+            assert_ne!(embeds[0], embeds[1]);
+            assert!(adapter.embed_query("").await.is_err());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    infer::{InferInput, InferInputBatch, InferOption, InferOutput, StopOption, StopReason},
+    model::State,
+    JobRuntime,
+};
+
+/// How [`JobRuntime::beam_search`] expands and prunes its beams.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BeamOption {
+    /// Number of beams kept at each step, which is also the number of completions returned.
+    pub num_beam: usize,
+    pub stop: StopOption,
+    pub token_chunk_size: usize,
+}
+
+impl Default for BeamOption {
+    fn default() -> Self {
+        Self {
+            num_beam: 4,
+            stop: Default::default(),
+            token_chunk_size: 128,
+        }
+    }
+}
+
+/// One completion found by [`JobRuntime::beam_search`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Beam {
+    /// Generated tokens, without the stop token.
+    pub tokens: Vec<u16>,
+    pub reason: StopReason,
+    /// Sum of the log-probabilities of the generated tokens (the stop token included).
+    pub log_prob: f32,
+}
+
+/// A beam that is still being expanded, living in a batch of the state.
+#[derive(Debug, Clone)]
+struct LiveBeam {
+    batch: usize,
+    tokens: Vec<u16>,
+    log_prob: f32,
+}
+
+/// Log-softmax of `logits`, keeping only the `k` most probable tokens.
+fn top_log_probs(logits: &[f32], k: usize) -> Vec<(u16, f32)> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|x| (x - max).exp()).sum();
+    let norm = max + sum.ln();
+    let mut log_probs = logits
+        .iter()
+        .enumerate()
+        .map(|(token, &x)| (token as u16, x - norm))
+        .collect_vec();
+    let order = |(_, x): &(u16, f32), (_, y): &(u16, f32)| x.total_cmp(y).reverse();
+    if k < log_probs.len() {
+        log_probs.select_nth_unstable_by(k, order);
+        log_probs.truncate(k);
+    }
+    log_probs.sort_by(order);
+    log_probs
+}
+
+impl JobRuntime<InferInput, InferOutput> {
+    /// Continue `prompt` with beam search, and return the [`num_beam`](BeamOption::num_beam) best completions
+    /// ranked by log-probability.
+    ///
+    /// Each beam lives in a batch of `state`, which must be the runtime's state. The prompt (but its last token) is run in batch 0,
+    /// so batch 0 should start from the state to continue from. Each step, every beam is expanded with its most probable tokens,
+    /// and the best candidates overall are kept; their states are moved into place on GPU with [`State::read`] and [`State::write`],
+    /// so a beam picked more than once is forked, and one that is not picked is pruned.
+    /// A candidate that stops is set aside as a completion, and the beam width shrinks by one.
+    ///
+    /// The runtime must have at least as many batches as beams; the batches beyond are left untouched.
+    pub async fn beam_search(
+        &self,
+        state: &(impl State + ?Sized),
+        prompt: &[u16],
+        option: BeamOption,
+    ) -> Result<Vec<Beam>> {
+        let Some((&last, prefix)) = prompt.split_last() else {
+            bail!("empty prompt");
+        };
+        let BeamOption {
+            num_beam,
+            stop,
+            token_chunk_size: chunk,
+        } = option;
+        let num_batch = state.num_batch();
+        if num_beam == 0 || num_beam > num_batch {
+            bail!("{num_beam} beams for a state of {num_batch} batches");
+        }
+
+        let mut input = InferInput::new(
+            (0..num_batch)
+                .map(|batch| InferInputBatch {
+                    tokens: match batch {
+                        0 => prefix.into(),
+                        _ => vec![].into(),
+                    },
+                    option: InferOption::Last,
+                    ..Default::default()
+                })
+                .collect(),
+            chunk,
+        );
+        while input.num_token() > 0 {
+            (input, _) = self.infer(input).await;
+        }
+
+        // all beams start from batch 0, with the last token of the prompt pending
+        let mut beams = vec![LiveBeam {
+            batch: 0,
+            tokens: vec![],
+            log_prob: 0.0,
+        }];
+        input.batches[0].tokens = vec![last].into();
+
+        let mut finished = vec![];
+        while !beams.is_empty() {
+            let mut outputs = vec![None; num_batch];
+            while input.num_token() > 0 {
+                let (next, InferOutput(output)) = self.infer(input).await;
+                input = next;
+                for (batch, output) in output.into_iter().enumerate() {
+                    if output.size() > 0 {
+                        outputs[batch] = Some(output);
+                    }
+                }
+            }
+
+            let width = num_beam - finished.len();
+            let mut candidates = vec![];
+            for (index, beam) in beams.iter().enumerate() {
+                let Some(output) = &outputs[beam.batch] else {
+                    bail!("no output for batch {}", beam.batch);
+                };
+                let expanded = top_log_probs(output.data(), width)
+                    .into_iter()
+                    .map(|(token, log_prob)| (index, token, beam.log_prob + log_prob));
+                candidates.extend(expanded);
+            }
+            let candidates = candidates
+                .into_iter()
+                .sorted_by(|(_, _, x), (_, _, y)| x.total_cmp(y).reverse())
+                .take(width);
+
+            let mut next = vec![];
+            for (index, token, log_prob) in candidates {
+                let mut tokens = beams[index].tokens.clone();
+                let reason = match stop.tokens.contains(&token) {
+                    true => Some(StopReason::Token(token)),
+                    false => {
+                        tokens.push(token);
+                        (tokens.len() >= stop.max_tokens).then_some(StopReason::Length)
+                    }
+                };
+                match reason {
+                    Some(reason) => finished.push(Beam {
+                        tokens,
+                        reason,
+                        log_prob,
+                    }),
+                    None => next.push((beams[index].batch, token, tokens, log_prob)),
+                }
+            }
+
+            // copy out the states of the surviving parents first, since writing may overwrite them
+            let sources: HashMap<usize, _> = next
+                .iter()
+                .map(|&(source, ..)| source)
+                .unique()
+                .map(|source| Ok((source, state.read(source)?)))
+                .collect::<Result<_>>()?;
+            beams = next
+                .into_iter()
+                .enumerate()
+                .map(|(batch, (source, token, tokens, log_prob))| {
+                    if batch != source {
+                        state.write(sources[&source].clone(), batch)?;
+                    }
+                    input.batches[batch].tokens = vec![token].into();
+                    Ok(LiveBeam {
+                        batch,
+                        tokens,
+                        log_prob,
+                    })
+                })
+                .collect::<Result<_>>()?;
+        }
+
+        let beams = finished
+            .into_iter()
+            .sorted_by(|x, y| x.log_prob.total_cmp(&y.log_prob).reverse())
+            .collect();
+        Ok(beams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::BeamOption;
+    use crate::runtime::{
+        choice::ChoiceOption,
+        infer::{InferKind, InferRequest, InferResponse, SampleOption, StopOption},
+        model::{Build, ModelBuilder, ModelRuntime, ModelVersion},
+        tiny::{
+            tests::{create_context, prompts},
+            TinyModel,
+        },
+        v5, JobRuntime,
+    };
+
+    #[test]
+    fn test_beam_search() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let prompt = prompts(&info)[0][..10].to_vec();
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let create_runtime = |num_batch| {
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                async move {
+                    let model = Build::<v5::Model>::build(builder).await?;
+                    anyhow::Ok(v5::ModelRuntime::<f32>::new(model, num_batch))
+                }
+            };
+            let stop = StopOption {
+                max_tokens: 5,
+                tokens: vec![],
+            };
+
+            // a single beam is greedy decoding
+            let runtime = create_runtime(2).await?;
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+            let option = BeamOption {
+                num_beam: 1,
+                stop: stop.clone(),
+                token_chunk_size: 4,
+            };
+            let beams = runtime.beam_search(&state, &prompt, option).await?;
+            assert_eq!(beams.len(), 1);
+
+            let runtime = JobRuntime::new(create_runtime(1).await?).await;
+            let requests = vec![InferRequest {
+                tokens: prompt.clone().into(),
+                kind: InferKind::Token {
+                    sample: SampleOption {
+                        temperature: 0.0,
+                        top_p: 1.0,
+                        seed: 0,
+                    },
+                    stop: stop.clone(),
+                    phrases: vec![],
+                },
+                session: None,
+            }];
+            let InferResponse::Token { tokens, .. } = &runtime.serve(requests, 4).await?[0] else {
+                panic!("expect tokens");
+            };
+            assert_eq!(tokens, &beams[0].tokens);
+
+            let runtime = create_runtime(4).await?;
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+            let option = BeamOption {
+                num_beam: 3,
+                stop,
+                token_chunk_size: 4,
+            };
+            let beams = runtime.beam_search(&state, &prompt, option).await?;
+            assert_eq!(beams.len(), 3);
+            assert!(beams
+                .iter()
+                .tuple_windows()
+                .all(|(x, y)| x.log_prob >= y.log_prob));
+            assert!(beams.iter().all(|x| x.tokens.len() == 5));
+            assert!(beams.iter().map(|x| &x.tokens).all_unique());
+
+            // the scores agree with teacher forcing, so the states were moved along with their beams
+            let candidates = beams.iter().map(|x| x.tokens.clone()).collect_vec();
+            let runtime = JobRuntime::new(create_runtime(3).await?).await;
+            let option = ChoiceOption {
+                length_penalty: 0.0,
+                token_chunk_size: 32,
+            };
+            let choices = runtime.choose(&prompt, &candidates, option).await?;
+            for (choice, beam) in choices.iter().zip_eq(beams.iter()) {
+                assert!((choice.log_prob - beam.log_prob).abs() < 1.0e-3);
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::infer::InferRedirect;
+use crate::{
+    context::Context,
+    tensor::{
+        kind::ReadWrite, ops::TensorOp, TensorCpu, TensorError, TensorGpu, TensorInit, TensorShape,
+    },
+};
+
+/// Per-batch logit biases, added to the logits on GPU right after the head,
+/// so that masking tokens needs no readback of the full logits.
+///
+/// Attach to a runtime with `ModelRuntime::logit_bias`; the biases are added to every output,
+/// so they also apply before sampling in [`Sampled`](super::sampler::Sampled) or [`Greedy`](super::infer::Greedy) runtimes.
+/// Changing them takes effect from the next submitted job.
+#[derive(Debug, Clone)]
+pub struct LogitBias {
+    pub context: Context,
+    /// Biases of each batch, `[C, 1, B]`.
+    pub data: TensorGpu<f32, ReadWrite>,
+}
+
+impl LogitBias {
+    pub fn new(context: &Context, num_vocab: usize, num_batch: usize) -> Self {
+        Self {
+            context: context.clone(),
+            data: context.tensor_init([num_vocab, 1, num_batch, 1]),
+        }
+    }
+
+    #[inline]
+    pub fn num_vocab(&self) -> usize {
+        self.data.shape()[0]
+    }
+
+    #[inline]
+    pub fn num_batch(&self) -> usize {
+        self.data.shape()[2]
+    }
+
+    /// Set the biases of a batch. Tokens in `bans` are never picked, whatever their bias in `bias`.
+    pub fn set(&self, batch: usize, bias: &HashMap<u16, f32>, bans: &[u16]) -> Result<()> {
+        let num_vocab = self.num_vocab();
+        let mut data = vec![0.0; num_vocab];
+        // bans come last, so that they override biases of the same tokens
+        let tokens = bias
+            .iter()
+            .map(|(&token, &bias)| (token, bias))
+            .chain(bans.iter().map(|&token| (token, f32::NEG_INFINITY)));
+        for (token, bias) in tokens {
+            match data.get_mut(token as usize) {
+                Some(x) => *x = bias,
+                None => bail!("token {token} out of range of vocab size {num_vocab}"),
+            }
+        }
+        let tensor = TensorCpu::from_data([num_vocab, 1, 1, 1], data)?;
+        self.data.load_batch(&tensor, batch)?;
+        Ok(())
+    }
+
+    /// Remove all biases of a batch.
+    pub fn clear(&self, batch: usize) -> Result<()> {
+        let tensor = TensorCpu::init([self.num_vocab(), 1, 1, 1]);
+        self.data.load_batch(&tensor, batch)?;
+        Ok(())
+    }
+
+    /// Read back the biases of all batches.
+    pub async fn back(&self) -> TensorCpu<f32> {
+        self.data.back().await
+    }
+
+    /// Copy the biases onto another context.
+    pub async fn migrate(&self, context: &Context) -> Result<Self> {
+        let tensor = self.data.back().await;
+        let data = context.tensor_from_data(tensor.shape(), tensor.to_vec())?;
+        Ok(Self {
+            context: context.clone(),
+            data,
+        })
+    }
+
+    /// Add the biases of their batches to the output rows of `logits` of shape `[C, R]`.
+    pub(crate) fn op(
+        &self,
+        logits: &TensorGpu<f32, ReadWrite>,
+        redirect: &InferRedirect,
+    ) -> Result<TensorOp, TensorError> {
+        let num_header = logits.shape()[1];
+        if num_header == 0 {
+            return Ok(TensorOp::empty());
+        }
+        let slots = self.slots(num_header, redirect)?;
+        TensorOp::logit_bias(logits, &slots, &self.data)
+    }
+
+    /// The batch of each of `num_header` output rows, or `u32::MAX` for none, as the slots of [`TensorOp::logit_bias`].
+    pub(crate) fn slots(
+        &self,
+        num_header: usize,
+        redirect: &InferRedirect,
+    ) -> Result<TensorGpu<u32, ReadWrite>, TensorError> {
+        if redirect.outputs.len() > self.num_batch() {
+            return Err(TensorError::Batch(redirect.outputs.len(), self.num_batch()));
+        }
+
+        let mut slots = vec![u32::MAX; num_header];
+        for (batch, &(start, end)) in redirect.outputs.iter().enumerate() {
+            slots[start..end].fill(batch as u32);
+        }
+        self.context.tensor_from_data([1, num_header, 1, 1], slots)
+    }
+}
+
+/// A sequence of tokens biased as a whole during generation, e.g., to prefer or avoid a multi-token word.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Phrase {
+    pub tokens: Vec<u16>,
+    /// Added to the logit of each token that continues the phrase; negative to suppress it.
+    pub bias: f32,
+}
+
+/// Biases the logits of the tokens that continue [`Phrase`]s, tracking partial matches across steps.
+///
+/// Feed every token of the batch, prompt and generated ones, with [`PhraseBias::push`].
+/// A phrase of one token is biased at every step, like a single-token bias. A longer phrase is biased only once
+/// the tokens so far end with its beginning, and then on the token that comes next in it;
+/// so suppressing a phrase does not affect its first token elsewhere, but stops the phrase from being completed.
+#[derive(Debug, Default, Clone)]
+pub struct PhraseBias {
+    phrases: Vec<Phrase>,
+    /// Lengths of the proper prefixes of each phrase that the tokens so far end with, not counting the empty one.
+    matches: Vec<Vec<usize>>,
+}
+
+impl PhraseBias {
+    /// Track the given phrases. Empty phrases are ignored.
+    pub fn new(phrases: Vec<Phrase>) -> Self {
+        let phrases = phrases
+            .into_iter()
+            .filter(|phrase| !phrase.tokens.is_empty())
+            .collect_vec();
+        let matches = vec![vec![]; phrases.len()];
+        Self { phrases, matches }
+    }
+
+    /// If there is no phrase to bias.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.phrases.is_empty()
+    }
+
+    /// Forget all partial matches, e.g., before feeding another text.
+    pub fn reset(&mut self) {
+        self.matches.iter_mut().for_each(Vec::clear);
+    }
+
+    /// Advance the partial matches by one token.
+    pub fn push(&mut self, token: u16) {
+        for (phrase, matches) in self.phrases.iter().zip_eq(self.matches.iter_mut()) {
+            let tokens = &phrase.tokens;
+            // every position may also start a new match
+            *matches = [0]
+                .into_iter()
+                .chain(matches.iter().copied())
+                .filter(|&len| tokens[len] == token)
+                .map(|len| len + 1)
+                .filter(|&len| len < tokens.len())
+                .collect();
+        }
+    }
+
+    /// Add the biases of the tokens that continue a phrase to `logits`.
+    /// A token is biased once per phrase, however many partial matches of it it continues.
+    pub fn apply(&self, logits: &mut [f32]) {
+        for (phrase, matches) in self.phrases.iter().zip_eq(self.matches.iter()) {
+            let tokens = &phrase.tokens;
+            let next = match tokens.len() {
+                1 => vec![tokens[0]],
+                _ => matches.iter().map(|&len| tokens[len]).unique().collect(),
+            };
+            for token in next {
+                if let Some(x) = logits.get_mut(token as usize) {
+                    *x += phrase.bias;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{LogitBias, Phrase, PhraseBias};
+    use crate::runtime::{
+        infer::{
+            Greedy, InferInput, InferInputBatch, InferKind, InferOption, InferRequest,
+            InferResponse, SampleOption, StopOption,
+        },
+        model::{Build, ModelBuilder, ModelRuntime, ModelVersion, State},
+        tiny::{
+            tests::{create_context, generate, prompts},
+            TinyModel,
+        },
+        v5, v6, JobRuntime,
+    };
+
+    #[test]
+    fn test_phrase_bias() {
+        let phrases = vec![
+            Phrase {
+                tokens: vec![1, 2, 3],
+                bias: -10.0,
+            },
+            Phrase {
+                tokens: vec![1, 1, 4],
+                bias: 1.0,
+            },
+            Phrase {
+                tokens: vec![5],
+                bias: 2.0,
+            },
+            Phrase {
+                tokens: vec![],
+                bias: 100.0,
+            },
+        ];
+        let mut bias = PhraseBias::new(phrases);
+        let biased = |bias: &PhraseBias| {
+            let mut logits = vec![0.0; 6];
+            bias.apply(&mut logits);
+            logits
+        };
+
+        // only single-token phrases apply before any match
+        assert_eq!(biased(&bias), [0.0, 0.0, 0.0, 0.0, 0.0, 2.0]);
+
+        bias.push(1);
+        assert_eq!(biased(&bias), [0.0, 1.0, -10.0, 0.0, 0.0, 2.0]);
+
+        // `1, 1` matches two prefixes of the second phrase, and restarts the first
+        bias.push(1);
+        assert_eq!(biased(&bias), [0.0, 1.0, -10.0, 0.0, 1.0, 2.0]);
+
+        bias.push(2);
+        assert_eq!(biased(&bias), [0.0, 0.0, 0.0, -10.0, 0.0, 2.0]);
+
+        // a completed phrase is not tracked further
+        bias.push(3);
+        assert_eq!(biased(&bias), [0.0, 0.0, 0.0, 0.0, 0.0, 2.0]);
+
+        bias.push(1);
+        bias.reset();
+        assert_eq!(biased(&bias), [0.0, 0.0, 0.0, 0.0, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn test_logit_bias() -> Result<()> {
+        const LEN: usize = 8;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V6);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let prompts = prompts(&info);
+            let num_batch = prompts.len();
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v6::Model>::build(builder).await?;
+
+            // the first batch is biased and bans a few tokens, the second is left as is
+            let bias = LogitBias::new(&context, info.num_vocab, num_batch);
+            let bans = [0, 1, 2];
+            bias.set(0, &HashMap::from([(3, 1.5), (1, 2.0)]), &bans)?;
+            assert!(bias
+                .set(0, &HashMap::new(), &[info.num_vocab as u16])
+                .is_err());
+
+            let plain = v6::ModelRuntime::<f32>::new(model.clone(), num_batch);
+            let plain = JobRuntime::new(plain).await;
+            let biased = v6::ModelRuntime::<f32>::new(model.clone(), num_batch)
+                .logit_bias(Some(bias.clone()));
+            let biased = JobRuntime::new(biased).await;
+
+            let batches = prompts
+                .iter()
+                .map(|tokens| InferInputBatch {
+                    tokens: tokens.clone().into(),
+                    option: InferOption::Full,
+                    ..Default::default()
+                })
+                .collect_vec();
+            let mut input_plain = InferInput::new(batches, 32);
+            let mut input_biased = input_plain.clone();
+            while input_plain.num_token() > 0 {
+                let (next, expected) = plain.infer(input_plain).await;
+                input_plain = next;
+                let (next, output) = biased.infer(input_biased).await;
+                input_biased = next;
+
+                for (batch, (expected, output)) in expected.0.iter().zip_eq(&output.0).enumerate() {
+                    let rows = expected.data().chunks_exact(info.num_vocab);
+                    for (expected, output) in
+                        rows.zip_eq(output.data().chunks_exact(info.num_vocab))
+                    {
+                        for (token, (&x, &y)) in expected.iter().zip_eq(output).enumerate() {
+                            match (batch, token) {
+                                (0, 0..=2) => assert_eq!(y, f32::NEG_INFINITY),
+                                (0, 3) => assert!((x + 1.5 - y).abs() < 1.0e-5),
+                                _ => assert_eq!(x, y),
+                            }
+                        }
+                    }
+                }
+            }
+
+            // tokens are picked after the biases are added, so a large bias forces a token
+            bias.set(1, &HashMap::from([(5, 1.0e4)]), &[])?;
+            let runtime =
+                v6::ModelRuntime::<f32>::new(model, num_batch).logit_bias(Some(bias.clone()));
+            let greedy = JobRuntime::new(Greedy(runtime)).await;
+            let output = generate(&greedy, &prompts, LEN).await;
+            assert!(output[0].iter().all(|token| !bans.contains(token)));
+            assert_eq!(output[1], vec![5; LEN]);
+
+            bias.clear(1)?;
+            assert!(bias.back().await.data()[info.num_vocab..]
+                .iter()
+                .all(|&x| x == 0.0));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_phrase_bias_generate() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let prompt = prompts(&info)[0][..10].to_vec();
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime = v5::ModelRuntime::<f32>::new(model, 1);
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+
+            let generate = |phrases: Vec<Phrase>| {
+                let requests = vec![InferRequest {
+                    tokens: prompt.clone().into(),
+                    kind: InferKind::Token {
+                        sample: SampleOption {
+                            temperature: 0.0,
+                            top_p: 1.0,
+                            seed: 0,
+                        },
+                        stop: StopOption {
+                            max_tokens: 3,
+                            tokens: vec![],
+                        },
+                        phrases,
+                    },
+                    session: None,
+                }];
+                let runtime = &runtime;
+                let state = &state;
+                async move {
+                    state.load(state.init(), 0)?;
+                    match &runtime.serve(requests, 4).await?[0] {
+                        InferResponse::Token { tokens, .. } => anyhow::Ok(tokens.clone()),
+                        _ => unreachable!(),
+                    }
+                }
+            };
+            let greedy = generate(vec![]).await?;
+            let last = *prompt.last().unwrap();
+
+            // phrases are matched from the prompt on
+            let suppressed = generate(vec![Phrase {
+                tokens: vec![last, greedy[0]],
+                bias: f32::NEG_INFINITY,
+            }])
+            .await?;
+            assert_ne!(suppressed[0], greedy[0]);
+
+            let target = (greedy[0] + 1) % info.num_vocab as u16;
+            let boosted = generate(vec![Phrase {
+                tokens: vec![last, target],
+                bias: 1.0e4,
+            }])
+            .await?;
+            assert_eq!(boosted[0], target);
+
+            // a phrase that never matches leaves the generation alone
+            let unmatched = generate(vec![Phrase {
+                tokens: vec![target, target, greedy[0]],
+                bias: f32::NEG_INFINITY,
+            }])
+            .await?;
+            assert_eq!(unmatched, greedy);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use super::model::State;
+use crate::{
+    context::{Arena, Context},
+    tensor::{kind::ReadWrite, TensorCpu, TensorGpu, TensorInto},
+};
+
+/// Hashes of all prefixes of `tokens`, the empty one excluded, computed incrementally (FNV-1a over tokens).
+fn hash_prefixes(tokens: &[u16]) -> impl Iterator<Item = u64> + '_ {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    tokens.iter().scan(OFFSET, |hash, &token| {
+        *hash = (*hash ^ token as u64).wrapping_mul(PRIME);
+        Some(*hash)
+    })
+}
+
+#[derive(Debug, Clone)]
+enum CachedState {
+    Gpu(TensorGpu<f32, ReadWrite>),
+    Cpu(TensorCpu<f32>),
+}
+
+impl CachedState {
+    fn size(&self) -> usize {
+        match self {
+            CachedState::Gpu(tensor) => tensor.size(),
+            CachedState::Cpu(tensor) => tensor.size(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    /// The prefix itself, to tell apart prefixes of the same hash.
+    tokens: Vec<u16>,
+    state: CachedState,
+    last_used: u64,
+}
+
+/// Keeps the states of a batch after running token prefixes, e.g., system prompts, so that prompts sharing them skip their prefill.
+///
+/// States are [inserted](StateCache::insert) as read from a batch with [`State::read`], and kept on device until the
+/// device budget is exceeded; then the least recently used ones are moved to host, and dropped once the host budget
+/// is exceeded too. [`StateCache::checkout`] finds the longest cached prefix of a prompt.
+#[derive(Debug)]
+pub struct StateCache {
+    context: Context,
+    gpu_budget: usize,
+    cpu_budget: usize,
+    clock: u64,
+    entries: HashMap<u64, CacheEntry>,
+}
+
+impl StateCache {
+    /// Create a cache that keeps at most `gpu_budget` bytes of states on device and `cpu_budget` bytes on host.
+    /// States moved back to device are allocated in [`Arena::Session`].
+    pub fn new(context: &Context, gpu_budget: usize, cpu_budget: usize) -> Self {
+        Self {
+            context: context.in_arena(Arena::Session),
+            gpu_budget,
+            cpu_budget,
+            clock: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Number of cached prefixes.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size of the states on device, in bytes.
+    pub fn gpu_size(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| matches!(entry.state, CachedState::Gpu(_)))
+            .map(|entry| entry.state.size())
+            .sum()
+    }
+
+    /// Total size of the states on host, in bytes.
+    pub fn cpu_size(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| matches!(entry.state, CachedState::Cpu(_)))
+            .map(|entry| entry.state.size())
+            .sum()
+    }
+
+    /// Check if the state after `tokens` is cached on device.
+    pub fn is_resident(&self, tokens: &[u16]) -> bool {
+        self.find(tokens)
+            .is_some_and(|hash| matches!(self.entries[&hash].state, CachedState::Gpu(_)))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The hash of `tokens`, if the state after them is cached.
+    fn find(&self, tokens: &[u16]) -> Option<u64> {
+        let hash = hash_prefixes(tokens).last()?;
+        self.entries
+            .get(&hash)
+            .is_some_and(|entry| entry.tokens == tokens)
+            .then_some(hash)
+    }
+
+    /// Cache `state`, the state of a batch after running `tokens` from the initial state.
+    pub async fn insert(&mut self, tokens: &[u16], state: TensorGpu<f32, ReadWrite>) {
+        let Some(hash) = hash_prefixes(tokens).last() else {
+            return;
+        };
+        self.clock += 1;
+        let entry = CacheEntry {
+            tokens: tokens.to_vec(),
+            state: CachedState::Gpu(state),
+            last_used: self.clock,
+        };
+        self.entries.insert(hash, entry);
+        self.make_room(hash).await;
+    }
+
+    /// Remove the state after `tokens` from the cache.
+    pub fn remove(&mut self, tokens: &[u16]) -> bool {
+        match self.find(tokens) {
+            Some(hash) => self.entries.remove(&hash).is_some(),
+            None => false,
+        }
+    }
+
+    /// Find the longest cached prefix of `tokens`, and return its state on device along with the tokens left to run.
+    ///
+    /// At least one token is always left, so that running the rest yields the logits to continue from.
+    /// A state cached on host is moved back to device. If there is no hit, the whole prompt is left.
+    pub async fn checkout<'a>(
+        &mut self,
+        tokens: &'a [u16],
+    ) -> (Option<TensorGpu<f32, ReadWrite>>, &'a [u16]) {
+        let Some((_, prefix)) = tokens.split_last() else {
+            return (None, tokens);
+        };
+        let hit = hash_prefixes(prefix)
+            .enumerate()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .find(|(index, hash)| {
+                self.entries
+                    .get(hash)
+                    .is_some_and(|entry| entry.tokens == prefix[..=*index])
+            });
+        let Some((index, hash)) = hit else {
+            return (None, tokens);
+        };
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(&hash).expect("cache entry");
+        entry.last_used = self.clock;
+        let state = match &entry.state {
+            CachedState::Gpu(tensor) => tensor.clone(),
+            CachedState::Cpu(tensor) => {
+                let tensor: TensorGpu<f32, ReadWrite> = tensor.clone().transfer_into(&self.context);
+                entry.state = CachedState::Gpu(tensor.clone());
+                tensor
+            }
+        };
+        self.make_room(hash).await;
+        (Some(state), &tokens[index + 1..])
+    }
+
+    /// Check out the longest cached prefix of `tokens` into a batch of `state`, resetting it to the initial state if there is no hit,
+    /// and return the tokens left to run.
+    pub async fn checkout_into<'a>(
+        &mut self,
+        state: &(impl State + ?Sized),
+        batch: usize,
+        tokens: &'a [u16],
+    ) -> Result<&'a [u16]> {
+        let (cached, remaining) = self.checkout(tokens).await;
+        match cached {
+            Some(tensor) => state.write(tensor, batch)?,
+            None => state.load(state.init(), batch)?,
+        }
+        Ok(remaining)
+    }
+
+    /// Move states to host and drop them, least recently used first, until both budgets are met.
+    /// The state just used (of `hash`) goes last.
+    async fn make_room(&mut self, hash: u64) {
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(&other, entry)| (other == hash, entry.last_used, other))
+            .collect();
+        candidates.sort_unstable();
+
+        for &(_, _, other) in &candidates {
+            if self.gpu_size() <= self.gpu_budget {
+                break;
+            }
+            let entry = self.entries.get_mut(&other).expect("cache entry");
+            if let CachedState::Gpu(tensor) = &entry.state {
+                log::info!("move cached state of {} tokens to host", entry.tokens.len());
+                entry.state = CachedState::Cpu(tensor.back().await);
+            }
+        }
+        for &(_, _, other) in &candidates {
+            if self.cpu_size() <= self.cpu_budget {
+                break;
+            }
+            if matches!(self.entries[&other].state, CachedState::Cpu(_)) {
+                let entry = self.entries.remove(&other).expect("cache entry");
+                log::info!("drop cached state of {} tokens", entry.tokens.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::StateCache;
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelRuntime, ModelVersion, State},
+            tiny::TinyModel,
+            v5, JobRuntime,
+        },
+    };
+
+    /// Run `tokens` through batch 0, and return the logits of the last one.
+    async fn run(runtime: &JobRuntime<InferInput, InferOutput>, tokens: &[u16]) -> Vec<f32> {
+        let batches = (0..2)
+            .map(|batch| InferInputBatch {
+                tokens: match batch {
+                    0 => tokens.into(),
+                    _ => vec![].into(),
+                },
+                option: InferOption::Last,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, 4);
+        let mut logits = vec![];
+        while input.num_token() > 0 {
+            let (next, InferOutput(output)) = runtime.infer(input).await;
+            input = next;
+            match output.into_iter().next() {
+                Some(output) if output.size() > 0 => logits = output.to_vec(),
+                _ => {}
+            }
+        }
+        logits
+    }
+
+    #[test]
+    fn test_state_cache() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime = v5::ModelRuntime::<f32>::new(model, 2);
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+
+            let system = [1u16, 2, 3, 4, 5, 6];
+            let prompt = [1u16, 2, 3, 4, 5, 6, 7, 8, 9];
+            state.load(state.init(), 0)?;
+            let expected = run(&runtime, &prompt).await;
+
+            // a single state on device, and another on host
+            let size = state.init().size();
+            let mut cache = StateCache::new(&context, size, size);
+            let (cached, remaining) = cache.checkout(&prompt).await;
+            assert!(cached.is_none());
+            assert_eq!(remaining, prompt);
+
+            state.load(state.init(), 0)?;
+            run(&runtime, &system).await;
+            cache.insert(&system, state.read(0)?).await;
+            state.load(state.init(), 0)?;
+            run(&runtime, &system[..3]).await;
+            cache.insert(&system[..3], state.read(0)?).await;
+            assert_eq!(cache.len(), 2);
+            assert!(cache.is_resident(&system[..3]));
+            assert!(!cache.is_resident(&system));
+
+            // the longest prefix is moved back to device, and the prefill is skipped
+            let remaining = cache.checkout_into(&state, 0, &prompt).await?;
+            assert_eq!(remaining, &prompt[6..]);
+            assert!(cache.is_resident(&system));
+            assert!(!cache.is_resident(&system[..3]));
+            let output = run(&runtime, remaining).await;
+            for (x, y) in output.iter().zip(expected.iter()) {
+                assert!((x - y).abs() <= 1e-5 * y.abs().max(1.0), "{x} vs {y}");
+            }
+
+            // a fully cached prompt still leaves its last token
+            let (cached, remaining) = cache.checkout(&system).await;
+            assert!(cached.is_some());
+            assert_eq!(remaining, &system[3..]);
+
+            // a third state drops the least recently used one
+            state.load(state.init(), 0)?;
+            run(&runtime, &[7, 8]).await;
+            cache.insert(&[7, 8], state.read(0)?).await;
+            assert_eq!(cache.len(), 2);
+            assert!(cache.is_resident(&[7, 8]));
+            assert!(!cache.remove(&system));
+            assert!(cache.remove(&system[..3]));
+            assert_eq!(cache.gpu_size(), size);
+            assert_eq!(cache.cpu_size(), 0);
+            Ok(())
+        })
+    }
+}
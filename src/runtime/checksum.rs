@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, io};
+
+use safetensors::SafeTensorError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::loader::{Reader, ReaderKQuant, ReaderSend, ReaderTensor};
+
+#[derive(Debug, Error)]
+pub enum ChecksumError {
+    #[error("tensor {0} is not in the manifest")]
+    Unlisted(String),
+    #[error("tensor {0} is in the manifest but not in the model")]
+    Missing(String),
+    #[error("tensor {name} is {found} but the manifest expects {expected}")]
+    Layout {
+        name: String,
+        expected: String,
+        found: String,
+    },
+    #[error("tensor {name} is corrupted: SHA-256 {found}, but the manifest expects {expected}")]
+    Mismatch {
+        name: String,
+        expected: String,
+        found: String,
+    },
+    #[error("failed to read tensor {0}: {1}")]
+    Read(String, #[source] SafeTensorError),
+}
+
+/// The type, shape and SHA-256 digest of the bytes of a tensor.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TensorDigest {
+    pub dtype: String,
+    pub shape: Vec<usize>,
+    /// Lowercase hex of the SHA-256 digest.
+    pub sha256: String,
+}
+
+impl TensorDigest {
+    pub fn new((dtype, shape, data): &ReaderTensor) -> Self {
+        let sha256 = Sha256::digest(data)
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect();
+        Self {
+            dtype: format!("{dtype:?}"),
+            shape: shape.clone(),
+            sha256,
+        }
+    }
+
+    fn layout(&self) -> String {
+        format!("{} of shape {:?}", self.dtype, self.shape)
+    }
+}
+
+/// Digests of all tensors of a model, to detect corrupted or truncated files before (or while) loading them.
+///
+/// Generate a manifest from a model when exporting it and ship it alongside, e.g., as JSON.
+/// Then either check a whole file up front with [`Manifest::verify`], or wrap the reader into a [`VerifiedReader`],
+/// which checks each tensor as it is loaded.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub tensors: BTreeMap<String, TensorDigest>,
+}
+
+impl Manifest {
+    /// Compute the digests of all tensors of `model`.
+    pub async fn generate(model: &impl Reader) -> Result<Self, ChecksumError> {
+        let mut tensors = BTreeMap::new();
+        for name in model.names() {
+            let tensor = model
+                .tensor(name)
+                .await
+                .map_err(|err| ChecksumError::Read(name.into(), err))?;
+            tensors.insert(name.to_string(), TensorDigest::new(&tensor));
+        }
+        Ok(Self { tensors })
+    }
+
+    /// Check a tensor read from the model against its digest.
+    pub fn check(&self, name: &str, tensor: &ReaderTensor) -> Result<(), ChecksumError> {
+        let expected = self
+            .tensors
+            .get(name)
+            .ok_or_else(|| ChecksumError::Unlisted(name.into()))?;
+        let found = TensorDigest::new(tensor);
+        if (&found.dtype, &found.shape) != (&expected.dtype, &expected.shape) {
+            return Err(ChecksumError::Layout {
+                name: name.into(),
+                expected: expected.layout(),
+                found: found.layout(),
+            });
+        }
+        if found.sha256 != expected.sha256 {
+            return Err(ChecksumError::Mismatch {
+                name: name.into(),
+                expected: expected.sha256.clone(),
+                found: found.sha256,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that `model` has exactly the tensors of the manifest, and that each of them matches its digest.
+    /// This reads the whole model once.
+    pub async fn verify(&self, model: &impl Reader) -> Result<(), ChecksumError> {
+        if let Some(name) = self.tensors.keys().find(|name| !model.contains(name)) {
+            return Err(ChecksumError::Missing(name.clone()));
+        }
+        for name in model.names() {
+            let tensor = model
+                .tensor(name)
+                .await
+                .map_err(|err| ChecksumError::Read(name.into(), err))?;
+            self.check(name, &tensor)?;
+        }
+        Ok(())
+    }
+}
+
+/// A [`Reader`] that checks each tensor against a [`Manifest`] as it is read, so that a model built from it
+/// fails at the first corrupted tensor, with an error naming it.
+///
+/// The error is returned as a [`SafeTensorError::IoError`] of kind [`io::ErrorKind::InvalidData`],
+/// wrapping the [`ChecksumError`]. LoRAs loaded together with the model need manifests of their own.
+/// Pre-quantized matrices (see [`Reader::kquant`]) are checked by reading the tensor as well, which costs a second read.
+#[derive(Debug, Clone)]
+pub struct VerifiedReader<R> {
+    pub model: R,
+    pub manifest: Manifest,
+}
+
+impl<R> VerifiedReader<R> {
+    pub fn new(model: R, manifest: Manifest) -> Self {
+        Self { model, manifest }
+    }
+
+    fn check(&self, name: &str, tensor: &ReaderTensor) -> Result<(), SafeTensorError> {
+        self.manifest.check(name, tensor).map_err(|err| {
+            log::error!("{err}");
+            SafeTensorError::IoError(io::Error::new(io::ErrorKind::InvalidData, err))
+        })
+    }
+}
+
+impl<R: ReaderSend + Sync> ReaderSend for VerifiedReader<R> {
+    #[inline]
+    fn names(&self) -> Vec<&str> {
+        self.model.names()
+    }
+
+    #[inline]
+    fn contains(&self, name: &str) -> bool {
+        self.model.contains(name)
+    }
+
+    #[inline]
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        self.model.shape(name)
+    }
+
+    async fn tensor(&self, name: &str) -> Result<ReaderTensor<'_>, SafeTensorError> {
+        let tensor = self.model.tensor(name).await?;
+        self.check(name, &tensor)?;
+        Ok(tensor)
+    }
+
+    async fn kquant(&self, name: &str) -> Result<Option<ReaderKQuant>, SafeTensorError> {
+        let Some(kquant) = self.model.kquant(name).await? else {
+            return Ok(None);
+        };
+        let tensor = self.model.tensor(name).await?;
+        self.check(name, &tensor)?;
+        Ok(Some(kquant))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use safetensors::{SafeTensorError, SafeTensors};
+
+    use super::{ChecksumError, Manifest, VerifiedReader};
+    use wgpu::PowerPreference;
+
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            loader::Reader,
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion},
+            tiny::TinyModel,
+            v6,
+        },
+    };
+
+    #[test]
+    fn test_checksum() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let data = TinyModel::new(info.clone(), 42).serialize()?;
+
+        let manifest = pollster::block_on(async {
+            let model = SafeTensors::deserialize(&data)?;
+            let manifest = Manifest::generate(&model).await?;
+            manifest.verify(&model).await?;
+            anyhow::Ok(manifest)
+        })?;
+        let json = serde_json::to_string(&manifest)?;
+        assert_eq!(serde_json::from_str::<Manifest>(&json)?, manifest);
+
+        // flip a bit of the last tensor in the file
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let model = SafeTensors::deserialize(&corrupted)?;
+        let err = pollster::block_on(manifest.verify(&model)).unwrap_err();
+        let ChecksumError::Mismatch { name, .. } = err else {
+            panic!("expect a mismatch, found {err}");
+        };
+
+        let model = VerifiedReader::new(model, manifest.clone());
+        let err = pollster::block_on(model.tensor(&name)).unwrap_err();
+        let SafeTensorError::IoError(err) = err else {
+            panic!("expect an io error");
+        };
+        let err = err
+            .into_inner()
+            .unwrap()
+            .downcast::<ChecksumError>()
+            .unwrap();
+        assert!(matches!(*err, ChecksumError::Mismatch { name: ref x, .. } if *x == name));
+        assert!(err.to_string().contains(&name));
+
+        // other tensors are still fine
+        let other = model.names().into_iter().find(|x| *x != name).unwrap();
+        assert!(pollster::block_on(model.tensor(other)).is_ok());
+
+        // a tensor dropped from the manifest is reported as such
+        let mut partial = manifest.clone();
+        partial.tensors.remove(other);
+        let model = SafeTensors::deserialize(&data)?;
+        let err = pollster::block_on(partial.verify(&model)).unwrap_err();
+        assert!(matches!(err, ChecksumError::Unlisted(x) if x == other));
+        partial
+            .tensors
+            .insert("missing".into(), manifest.tensors[other].clone());
+        let err = pollster::block_on(partial.verify(&model)).unwrap_err();
+        assert!(matches!(err, ChecksumError::Missing(x) if x == "missing"));
+
+        // a model builds from a verified reader, and fails to at the corrupted tensor
+        let context = match pollster::block_on(async {
+            let instance = wgpu::Instance::default();
+            let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+            let context = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await?;
+            anyhow::Ok(context)
+        }) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        pollster::block_on(async {
+            let model = VerifiedReader::new(SafeTensors::deserialize(&data)?, manifest.clone());
+            Build::<v6::Model>::build(ModelBuilder::new(&context, model)).await?;
+
+            let model = VerifiedReader::new(SafeTensors::deserialize(&corrupted)?, manifest);
+            let err = Build::<v6::Model>::build(ModelBuilder::new(&context, model))
+                .await
+                .expect_err("corrupted model should fail to build");
+            assert!(format!("{err:#}").contains(&name));
+            anyhow::Ok(())
+        })
+    }
+}
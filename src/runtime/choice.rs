@@ -0,0 +1,219 @@
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+use super::{
+    infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+    JobRuntime,
+};
+#[cfg(feature = "tokenizer")]
+use crate::tokenizer::Tokenize;
+
+/// How the candidates of [`JobRuntime::choose`] are scored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChoiceOption {
+    /// The log-probability of a candidate is divided by its length to this power: 0 keeps the sum, 1 takes the mean per token.
+    pub length_penalty: f32,
+    pub token_chunk_size: usize,
+}
+
+impl Default for ChoiceOption {
+    fn default() -> Self {
+        Self {
+            length_penalty: 1.0,
+            token_chunk_size: 128,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Choice {
+    /// Sum of the log-probabilities of the candidate's tokens.
+    pub log_prob: f32,
+    /// The length normalized log-probability.
+    pub score: f32,
+    /// Softmax of the scores over all candidates.
+    pub prob: f32,
+}
+
+/// Log-softmax of `logits` at `token`.
+pub(super) fn log_prob(logits: &[f32], token: u16) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|x| (x - max).exp()).sum();
+    logits[token as usize] - max - sum.ln()
+}
+
+impl JobRuntime<InferInput, InferOutput> {
+    /// Score candidate completions of `prompt` by teacher forcing, one candidate for each batch of the runtime's state,
+    /// all of which should start from the same state (e.g., the initial one).
+    ///
+    /// The prompt (but its last token) is run first without reading back logits, and then all candidates together.
+    pub async fn choose(
+        &self,
+        prompt: &[u16],
+        candidates: &[Vec<u16>],
+        option: ChoiceOption,
+    ) -> Result<Vec<Choice>> {
+        let Some((&last, prompt)) = prompt.split_last() else {
+            bail!("empty prompt");
+        };
+        if candidates.is_empty() || candidates.iter().any(|tokens| tokens.is_empty()) {
+            bail!("empty candidate");
+        }
+
+        let batches = candidates
+            .iter()
+            .map(|_| InferInputBatch {
+                tokens: prompt.into(),
+                option: InferOption::Last,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, option.token_chunk_size);
+        while input.num_token() > 0 {
+            (input, _) = self.infer(input).await;
+        }
+
+        // the logits of the last prompt token predict the first candidate token, and so on
+        let batches = candidates
+            .iter()
+            .map(|tokens| InferInputBatch {
+                tokens: [last].iter().chain(tokens).copied().collect(),
+                option: InferOption::Full,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, option.token_chunk_size);
+        let mut logits = vec![vec![]; candidates.len()];
+        while input.num_token() > 0 {
+            let (next, InferOutput(output)) = self.infer(input).await;
+            input = next;
+            for (logits, output) in logits.iter_mut().zip_eq(output) {
+                logits.extend_from_slice(output.data());
+            }
+        }
+
+        let mut choices = candidates
+            .iter()
+            .zip_eq(logits)
+            .map(|(tokens, logits)| {
+                let num_vocab = logits.len() / (tokens.len() + 1);
+                let log_prob: f32 = tokens
+                    .iter()
+                    .zip(logits.chunks_exact(num_vocab))
+                    .map(|(&token, logits)| log_prob(logits, token))
+                    .sum();
+                let score = log_prob / (tokens.len() as f32).powf(option.length_penalty);
+                Choice {
+                    log_prob,
+                    score,
+                    prob: 0.0,
+                }
+            })
+            .collect_vec();
+
+        let max = choices
+            .iter()
+            .map(|x| x.score)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = choices.iter().map(|x| (x.score - max).exp()).sum();
+        for choice in choices.iter_mut() {
+            choice.prob = (choice.score - max).exp() / sum;
+        }
+        Ok(choices)
+    }
+
+    /// Tokenize and score text candidates, see [`JobRuntime::choose`].
+    /// Candidates are tokenized apart from the prompt, as the model would generate them after the prompt,
+    /// so a leading space, if any, should be part of the candidates.
+    #[cfg(feature = "tokenizer")]
+    pub async fn choose_text(
+        &self,
+        tokenizer: &impl Tokenize,
+        prompt: &str,
+        candidates: &[&str],
+        option: ChoiceOption,
+    ) -> Result<Vec<Choice>> {
+        let prompt = tokenizer.encode(prompt.as_bytes())?;
+        let candidates: Vec<_> = candidates
+            .iter()
+            .map(|candidate| tokenizer.encode(candidate.as_bytes()))
+            .try_collect()?;
+        self.choose(&prompt, &candidates, option).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::ChoiceOption;
+    use crate::runtime::{
+        model::{Build, ModelBuilder, ModelVersion},
+        tiny::{
+            tests::{create_context, infer_gpu, prompts},
+            TinyModel,
+        },
+        v5, JobRuntime,
+    };
+
+    #[test]
+    fn test_choose() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let prompt = prompts(&info)[0][..10].to_vec();
+            let candidates = prompts(&info)[1]
+                .chunks(4)
+                .take(3)
+                .map(|tokens| tokens[..tokens.len() - tokens[0] as usize % 3].to_vec())
+                .collect_vec();
+
+            let sequences = candidates
+                .iter()
+                .map(|tokens| [prompt.clone(), tokens.clone()].concat())
+                .collect_vec();
+            let Some(logits) =
+                infer_gpu(TinyModel::new(info.clone(), 42), &sequences, None).await?
+            else {
+                return Ok(());
+            };
+            let expected = candidates
+                .iter()
+                .zip_eq(logits.iter())
+                .map(|(tokens, logits)| {
+                    let logits = logits.chunks_exact(info.num_vocab).skip(prompt.len() - 1);
+                    let log_prob: f32 = tokens
+                        .iter()
+                        .zip(logits)
+                        .map(|(&token, logits)| {
+                            let sum: f32 = logits.iter().map(|x| x.exp()).sum();
+                            logits[token as usize] - sum.ln()
+                        })
+                        .sum();
+                    log_prob
+                })
+                .collect_vec();
+
+            let context = create_context(&info).await?;
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime =
+                JobRuntime::new(v5::ModelRuntime::<f32>::new(model, candidates.len())).await;
+
+            let option = ChoiceOption {
+                length_penalty: 0.0,
+                token_chunk_size: 32,
+            };
+            let choices = runtime.choose(&prompt, &candidates, option).await?;
+            for (choice, expected) in choices.iter().zip_eq(expected.iter()) {
+                assert!((choice.log_prob - expected).abs() < 1.0e-3);
+                assert_eq!(choice.score, choice.log_prob);
+            }
+            let sum: f32 = choices.iter().map(|x| x.prob).sum();
+            assert!((sum - 1.0).abs() < 1.0e-5);
+            assert!(runtime.choose(&[], &candidates, option).await.is_err());
+            Ok(())
+        })
+    }
+}
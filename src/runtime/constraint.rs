@@ -0,0 +1,1071 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use super::bias::LogitBias;
+use crate::tokenizer::Tokenize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConstraintError {
+    #[error("syntax error at byte {0}: {1}")]
+    Syntax(usize, &'static str),
+    #[error("rule {0} is defined twice")]
+    DuplicateRule(String),
+    #[error("rule {0} is used but not defined")]
+    UndefinedRule(String),
+    #[error("grammar has no root rule")]
+    MissingRoot,
+    #[error("rule {0} is left recursive")]
+    LeftRecursion(String),
+    #[error("unsupported schema: {0}")]
+    Schema(String),
+    #[error("token {0} is not allowed by the grammar")]
+    Rejected(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Element {
+    /// A char in (or, if negated, not in) any of the inclusive ranges.
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn char(c: char) -> Self {
+        Self::Char {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Char { ranges, negated } => {
+                ranges.iter().any(|&(low, high)| low <= c && c <= high) != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+
+    /// If any code point in `low..=high` may match; used for chars that are split across tokens.
+    fn intersects(&self, low: u32, high: u32) -> bool {
+        match self {
+            Element::Char {
+                ranges,
+                negated: false,
+            } => ranges
+                .iter()
+                .any(|&(x, y)| (x as u32) <= high && low <= (y as u32)),
+            Element::Char {
+                ranges,
+                negated: true,
+            } => !ranges
+                .iter()
+                .any(|&(x, y)| (x as u32) <= low && high <= (y as u32)),
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// Position in a grammar: the next element to match is `rules[rule][alt][index]`.
+type Position = (u32, u32, u32);
+/// Positions to return to, with the innermost one last. An empty stack has matched the root rule.
+type Stack = Vec<Position>;
+
+/// A context-free grammar over chars, parsed from [GBNF](https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md).
+///
+/// Supported are rules `name ::= ...`, literals `"..."`, char classes `[a-z]` and `[^...]`, any char `.`,
+/// groups `(...)`, alternatives `|`, repetitions `*`, `+`, `?`, `{m}`, `{m,}`, `{m,n}`, and comments `# ...`.
+/// Generation starts from the rule named `root`. Left recursive rules are rejected.
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    names: Vec<String>,
+    rules: Vec<Vec<Vec<Element>>>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(text: &str) -> Result<Self, ConstraintError> {
+        let mut parser = Parser {
+            text,
+            pos: 0,
+            names: vec![],
+            ids: HashMap::new(),
+            rules: vec![],
+        };
+        parser.parse()?;
+
+        let Parser { names, rules, .. } = parser;
+        let rules: Vec<_> = rules
+            .into_iter()
+            .zip(names.iter())
+            .map(|(rule, name)| rule.ok_or_else(|| ConstraintError::UndefinedRule(name.clone())))
+            .collect::<Result<_, _>>()?;
+        let root = names
+            .iter()
+            .position(|name| name == "root")
+            .ok_or(ConstraintError::MissingRoot)?;
+
+        let grammar = Self { names, rules, root };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    /// Convert a [JSON schema](https://json-schema.org/) into a grammar of the JSON texts it describes.
+    ///
+    /// Supported are `type` (or a list of types), `properties` with `required`, `items`, `enum`, `const`, `anyOf` and `oneOf`;
+    /// other keywords are ignored, and `$ref` is rejected. Properties are produced in the iteration order of the `properties` map,
+    /// and an object without `properties` takes any members.
+    pub fn from_json_schema(schema: &Value) -> Result<Self, ConstraintError> {
+        let mut converter = SchemaConverter::default();
+        let root = converter.visit(schema, "schema")?;
+        let mut text = format!("root ::= {root}\n");
+        for (name, body) in converter.rules {
+            text.push_str(&format!("{name} ::= {body}\n"));
+        }
+        text.push_str(JSON_GRAMMAR);
+        Self::parse(&text)
+    }
+
+    /// If the whole text matches the grammar.
+    pub fn matches(&self, text: &str) -> bool {
+        let mut stacks = self.start();
+        for c in text.chars() {
+            stacks = self.advance(&stacks, c);
+        }
+        stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    fn check_left_recursion(&self) -> Result<(), ConstraintError> {
+        let mut nullable = vec![false; self.rules.len()];
+        let is_nullable = |nullable: &[bool], element: &Element| match element {
+            Element::Rule(rule) => nullable[*rule],
+            Element::Char { .. } => false,
+        };
+        loop {
+            let mut changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if !nullable[rule]
+                    && alts
+                        .iter()
+                        .any(|seq| seq.iter().all(|x| is_nullable(&nullable, x)))
+                {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // rules that may be expanded without consuming any char
+        let edges = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut edges = vec![];
+                for seq in alts {
+                    for element in seq {
+                        if let Element::Rule(rule) = element {
+                            edges.push(*rule);
+                        }
+                        if !is_nullable(&nullable, element) {
+                            break;
+                        }
+                    }
+                }
+                edges
+            })
+            .collect::<Vec<_>>();
+
+        // 0: unvisited, 1: on the path, 2: done
+        fn visit(rule: usize, edges: &[Vec<usize>], marks: &mut [u8]) -> Option<usize> {
+            match marks[rule] {
+                1 => return Some(rule),
+                2 => return None,
+                _ => {}
+            }
+            marks[rule] = 1;
+            for &next in &edges[rule] {
+                if let Some(rule) = visit(next, edges, marks) {
+                    return Some(rule);
+                }
+            }
+            marks[rule] = 2;
+            None
+        }
+        let mut marks = vec![0; self.rules.len()];
+        for rule in 0..self.rules.len() {
+            if let Some(rule) = visit(rule, &edges, &mut marks) {
+                return Err(ConstraintError::LeftRecursion(self.names[rule].clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn element(&self, (rule, alt, index): Position) -> Option<&Element> {
+        self.rules[rule as usize][alt as usize].get(index as usize)
+    }
+
+    /// Expand rules on the top of the stack, until each stack waits for a char or is empty.
+    fn expand(&self, mut stack: Stack, output: &mut HashSet<Stack>) {
+        loop {
+            let Some(&top) = stack.last() else {
+                output.insert(stack);
+                return;
+            };
+            match self.element(top) {
+                None => {
+                    stack.pop();
+                }
+                Some(Element::Char { .. }) => {
+                    output.insert(stack);
+                    return;
+                }
+                Some(&Element::Rule(rule)) => {
+                    // drop finished positions, so that repetitions do not grow the stack
+                    let (parent, alt, index) = top;
+                    stack.pop();
+                    if self.element((parent, alt, index + 1)).is_some() {
+                        stack.push((parent, alt, index + 1));
+                    }
+                    for alt in 0..self.rules[rule].len() {
+                        let mut stack = stack.clone();
+                        stack.push((rule as u32, alt as u32, 0));
+                        self.expand(stack, output);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn start(&self) -> Vec<Stack> {
+        let mut output = HashSet::new();
+        for alt in 0..self.rules[self.root].len() {
+            self.expand(vec![(self.root as u32, alt as u32, 0)], &mut output);
+        }
+        output.into_iter().collect()
+    }
+
+    fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut output = HashSet::new();
+        for stack in stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if self.element(top).is_some_and(|x| x.matches(c)) {
+                let mut stack = stack.clone();
+                if let Some(top) = stack.last_mut() {
+                    top.2 += 1;
+                }
+                self.expand(stack, &mut output);
+            }
+        }
+        output.into_iter().collect()
+    }
+
+    /// Feed one byte of UTF-8 text. Returns `None` if no stack survives.
+    /// Bytes of an incomplete char are kept in `partial` as long as the char may still match.
+    fn feed(&self, stacks: &[Stack], partial: &[u8], byte: u8) -> Option<(Vec<Stack>, Vec<u8>)> {
+        let mut partial = partial.to_vec();
+        partial.push(byte);
+
+        let len = match partial[0] {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return None,
+        };
+        if partial.len() > 1 && byte & 0xc0 != 0x80 {
+            return None;
+        }
+
+        if partial.len() == len {
+            let c = std::str::from_utf8(&partial).ok()?.chars().next()?;
+            let stacks = self.advance(stacks, c);
+            return (!stacks.is_empty()).then_some((stacks, vec![]));
+        }
+
+        let rest = 6 * (len - partial.len()) as u32;
+        let value = partial[1..]
+            .iter()
+            .fold((partial[0] & (0x7f >> len)) as u32, |value, &byte| {
+                (value << 6) | (byte & 0x3f) as u32
+            });
+        let low = value << rest;
+        let high = low | ((1 << rest) - 1);
+        stacks
+            .iter()
+            .filter_map(|stack| stack.last())
+            .filter_map(|&top| self.element(top))
+            .any(|x| x.intersects(low, high))
+            .then(|| (stacks.to_vec(), partial))
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn error<T>(&self, message: &'static str) -> Result<T, ConstraintError> {
+        Err(ConstraintError::Syntax(self.pos, message))
+    }
+
+    fn expect(&mut self, pattern: &str, message: &'static str) -> Result<(), ConstraintError> {
+        match self.text[self.pos..].starts_with(pattern) {
+            true => {
+                self.pos += pattern.len();
+                Ok(())
+            }
+            false => self.error(message),
+        }
+    }
+
+    /// Skip spaces and comments, and also line breaks if `newline` is set.
+    fn space(&mut self, newline: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newline => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.next();
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.pos += 1;
+        }
+        (self.pos > start).then(|| &self.text[start..self.pos])
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.names.len();
+        self.names.push(name.to_owned());
+        self.ids.insert(name.to_owned(), id);
+        self.rules.push(None);
+        id
+    }
+
+    /// Add an anonymous rule, e.g., for a group or a repetition.
+    fn generate(&mut self, alts: Vec<Vec<Element>>) -> usize {
+        let id = self.names.len();
+        self.names.push(format!("_{id}"));
+        self.rules.push(Some(alts));
+        id
+    }
+
+    fn parse(&mut self) -> Result<(), ConstraintError> {
+        loop {
+            self.space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let Some(name) = self.name() else {
+                return self.error("expect a rule name");
+            };
+            let id = self.rule_id(name);
+            if self.rules[id].is_some() {
+                return Err(ConstraintError::DuplicateRule(name.to_owned()));
+            }
+
+            self.space(false);
+            self.expect("::=", "expect `::=`")?;
+            self.space(true);
+            let alts = self.alternates(false)?;
+            self.rules[id] = Some(alts);
+
+            match self.peek() {
+                None | Some('\r' | '\n') => {}
+                Some(_) => return self.error("expect the end of a rule"),
+            }
+        }
+    }
+
+    fn alternates(&mut self, nested: bool) -> Result<Vec<Vec<Element>>, ConstraintError> {
+        let mut alts = vec![self.sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.space(true);
+            alts.push(self.sequence(nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self, nested: bool) -> Result<Vec<Element>, ConstraintError> {
+        let mut seq = vec![];
+        loop {
+            let start = seq.len();
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    while self.peek() != Some('"') {
+                        let c = self.char()?;
+                        seq.push(Element::char(c));
+                    }
+                    self.pos += 1;
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let low = self.char()?;
+                        let high = match self.text[self.pos..].starts_with('-')
+                            && !self.text[self.pos..].starts_with("-]")
+                        {
+                            true => {
+                                self.pos += 1;
+                                self.char()?
+                            }
+                            false => low,
+                        };
+                        ranges.push((low, high));
+                    }
+                    self.pos += 1;
+                    seq.push(Element::Char { ranges, negated });
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    seq.push(Element::Char {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.space(true);
+                    let alts = self.alternates(true)?;
+                    self.expect(")", "expect `)`")?;
+                    let id = self.generate(alts);
+                    seq.push(Element::Rule(id));
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.name().unwrap_or_default();
+                    // the name of the next rule ends this one
+                    let pos = self.pos;
+                    self.space(nested);
+                    if self.text[self.pos..].starts_with("::=") {
+                        self.pos = pos - name.len();
+                        return self.error("expect the end of a rule");
+                    }
+                    self.pos = pos;
+                    let id = self.rule_id(name);
+                    seq.push(Element::Rule(id));
+                }
+                _ => return Ok(seq),
+            }
+            self.space(nested);
+
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    self.space(false);
+                    let min = self.number()?;
+                    self.space(false);
+                    let max = match self.peek() {
+                        Some(',') => {
+                            self.pos += 1;
+                            self.space(false);
+                            match self.peek() {
+                                Some('}') => None,
+                                _ => Some(self.number()?),
+                            }
+                        }
+                        _ => Some(min),
+                    };
+                    self.space(false);
+                    if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+                        return self.error("invalid repetition");
+                    }
+                    (min, max)
+                }
+                _ => continue,
+            };
+            self.pos += 1;
+            self.space(nested);
+
+            let group = seq.split_off(start);
+            let mut repeated = self.repeat(group, min, max);
+            seq.append(&mut repeated);
+        }
+    }
+
+    /// Repeat `group` at least `min` times and at most `max` times.
+    fn repeat(&mut self, group: Vec<Element>, min: usize, max: Option<usize>) -> Vec<Element> {
+        let mut seq = (0..min)
+            .flat_map(|_| group.iter().cloned())
+            .collect::<Vec<_>>();
+        match max {
+            None => {
+                // rule ::= group rule |
+                let id = self.generate(vec![]);
+                let mut alt = group;
+                alt.push(Element::Rule(id));
+                self.rules[id] = Some(vec![alt, vec![]]);
+                seq.push(Element::Rule(id));
+            }
+            Some(max) => {
+                // nested optionals, so that tokens after the group are not matched twice
+                let mut tail: Option<usize> = None;
+                for _ in min..max {
+                    let mut alt = group.clone();
+                    alt.extend(tail.map(Element::Rule));
+                    tail = Some(self.generate(vec![alt, vec![]]));
+                }
+                seq.extend(tail.map(Element::Rule));
+            }
+        }
+        seq
+    }
+
+    fn number(&mut self) -> Result<usize, ConstraintError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        match self.text[start..self.pos].parse() {
+            Ok(number) => Ok(number),
+            Err(_) => self.error("expect a number"),
+        }
+    }
+
+    /// Parse a possibly escaped char in a literal or a char class.
+    fn char(&mut self) -> Result<char, ConstraintError> {
+        let Some(c) = self.next() else {
+            return self.error("unexpected end of text");
+        };
+        if c != '\\' {
+            return Ok(c);
+        }
+        let hex = |parser: &mut Self, len: usize| {
+            let start = parser.pos;
+            let end = (start + len).min(parser.text.len());
+            let code = parser
+                .text
+                .get(start..end)
+                .and_then(|code| u32::from_str_radix(code, 16).ok())
+                .and_then(char::from_u32);
+            parser.pos = end;
+            match code {
+                Some(c) => Ok(c),
+                None => parser.error("invalid escape"),
+            }
+        };
+        match self.next() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('x') => hex(self, 2),
+            Some('u') => hex(self, 4),
+            Some('U') => hex(self, 8),
+            Some(c) => Ok(c),
+            None => self.error("unexpected end of text"),
+        }
+    }
+}
+
+const JSON_GRAMMAR: &str = r#"
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}"
+array ::= "[" ws ( value ws ( "," ws value ws )* )? "]"
+string ::= "\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\""
+number ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+integer ::= "-"? ( "0" | [1-9] [0-9]* )
+boolean ::= "true" | "false"
+null ::= "null"
+ws ::= [ \t\n]?
+"#;
+
+#[derive(Debug, Default)]
+struct SchemaConverter {
+    rules: Vec<(String, String)>,
+}
+
+impl SchemaConverter {
+    /// Define a rule and return its name.
+    fn rule(&mut self, name: &str, body: String) -> String {
+        self.rules.push((name.to_owned(), body));
+        name.to_owned()
+    }
+
+    /// A GBNF literal of a JSON value.
+    fn literal(value: &Value) -> String {
+        let text = value.to_string();
+        let text = text.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{text}\"")
+    }
+
+    /// Return a GBNF expression of the texts described by the schema.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, ConstraintError> {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(true) => return Ok("value".into()),
+            _ => return Err(ConstraintError::Schema(format!("{name} is not an object"))),
+        };
+        if schema.contains_key("$ref") {
+            return Err(ConstraintError::Schema("`$ref` is not supported".into()));
+        }
+
+        if let Some(value) = schema.get("const") {
+            return Ok(Self::literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Value::Array(values) = values else {
+                return Err(ConstraintError::Schema(format!(
+                    "enum of {name} is not an array"
+                )));
+            };
+            let body = values.iter().map(Self::literal).collect::<Vec<_>>();
+            return Ok(self.rule(name, body.join(" | ")));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(Value::Array(schemas)) = schema.get(key) {
+                let body = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(index, schema)| self.visit(schema, &format!("{name}-{index}")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(self.rule(name, body.join(" | ")));
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(kind)) => self.visit_type(schema, kind, name),
+            Some(Value::Array(kinds)) => {
+                let body = kinds
+                    .iter()
+                    .enumerate()
+                    .map(|(index, kind)| match kind {
+                        Value::String(kind) => {
+                            self.visit_type(schema, kind, &format!("{name}-{index}"))
+                        }
+                        _ => Err(ConstraintError::Schema(format!(
+                            "type of {name} is invalid"
+                        ))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(self.rule(name, body.join(" | ")))
+            }
+            Some(_) => Err(ConstraintError::Schema(format!(
+                "type of {name} is invalid"
+            ))),
+            None if schema.contains_key("properties") => self.visit_type(schema, "object", name),
+            None => Ok("value".into()),
+        }
+    }
+
+    fn visit_type(
+        &mut self,
+        schema: &Map<String, Value>,
+        kind: &str,
+        name: &str,
+    ) -> Result<String, ConstraintError> {
+        match kind {
+            "object" => self.visit_object(schema, name),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.visit(items, &format!("{name}-item"))?,
+                    None => "value".into(),
+                };
+                let body = format!(r#""[" ws ( {item} ws ( "," ws {item} ws )* )? "]""#);
+                Ok(self.rule(name, body))
+            }
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(kind.into()),
+            kind => Err(ConstraintError::Schema(format!("unknown type {kind}"))),
+        }
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<String, ConstraintError> {
+        let Some(Value::Object(properties)) = schema.get("properties") else {
+            return Ok("object".into());
+        };
+        let required: HashSet<&str> = match schema.get("required") {
+            Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect(),
+            _ => HashSet::new(),
+        };
+
+        let members = properties
+            .iter()
+            .enumerate()
+            .map(|(index, (key, value))| {
+                let value = self.visit(value, &format!("{name}-{index}"))?;
+                let key = Self::literal(&Value::String(key.clone()));
+                Ok(format!(r#"{key} ws ":" ws {value} ws"#))
+            })
+            .collect::<Result<Vec<_>, ConstraintError>>()?;
+
+        // `head` rules start the members, and `tail` rules follow a member that is already there
+        let (mut head, mut tail) = (String::new(), String::new());
+        for (index, (key, member)) in properties.keys().zip(members).enumerate().rev() {
+            let (head_body, tail_body) = match required.contains(key.as_str()) {
+                true => (
+                    format!("{member} {tail}"),
+                    format!(r#""," ws {member} {tail}"#),
+                ),
+                // an empty alternative must not end a line, or the rule continues on the next line
+                false if head.is_empty() => (
+                    format!("( {member} {tail} )?"),
+                    format!(r#"( "," ws {member} )? {tail}"#),
+                ),
+                false => (
+                    format!("{member} {tail} | {head}"),
+                    format!(r#"( "," ws {member} )? {tail}"#),
+                ),
+            };
+            head = self.rule(&format!("{name}-head-{index}"), head_body);
+            tail = self.rule(&format!("{name}-tail-{index}"), tail_body);
+        }
+        Ok(self.rule(name, format!(r#""{{" ws {head} "}}""#)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct TokenNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u16>,
+}
+
+/// Token bytes arranged in a trie, so that tokens sharing a prefix are matched together.
+#[derive(Debug, Default)]
+struct TokenTrie {
+    nodes: Vec<TokenNode>,
+    /// Bytes of each token, empty if the token is not in the vocabulary.
+    bytes: Vec<Vec<u8>>,
+}
+
+impl TokenTrie {
+    fn new(tokenizer: &impl Tokenize) -> Self {
+        let vocab_size = tokenizer.vocab_size();
+        let bytes: Vec<_> = (0..vocab_size.min(u16::MAX as usize + 1))
+            .map(|token| tokenizer.decode(&[token as u16]).unwrap_or_default())
+            .collect();
+        let mut nodes = vec![TokenNode::default()];
+        for (token, bytes) in bytes.iter().enumerate() {
+            let mut node = 0;
+            for &byte in bytes {
+                node = match nodes[node].children.iter().find(|(x, _)| *x == byte) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        let child = nodes.len();
+                        nodes.push(TokenNode::default());
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            if node > 0 {
+                nodes[node].tokens.push(token as u16);
+            }
+        }
+        Self { nodes, bytes }
+    }
+}
+
+/// A grammar compiled against the vocabulary of a tokenizer. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    grammar: Arc<Grammar>,
+    trie: Arc<TokenTrie>,
+    end: Vec<u16>,
+}
+
+impl Constraint {
+    pub fn new(grammar: Grammar, tokenizer: &impl Tokenize) -> Self {
+        Self {
+            grammar: Arc::new(grammar),
+            trie: Arc::new(TokenTrie::new(tokenizer)),
+            end: vec![0],
+        }
+    }
+
+    /// Set the tokens that end the text, allowed once the root rule is matched. By default this is token 0.
+    pub fn end(mut self, tokens: Vec<u16>) -> Self {
+        self.end = tokens;
+        self
+    }
+
+    #[inline]
+    pub fn vocab_size(&self) -> usize {
+        self.trie.bytes.len()
+    }
+
+    /// Start matching a new text.
+    pub fn matcher(&self) -> Matcher {
+        Matcher {
+            constraint: self.clone(),
+            stacks: self.grammar.start(),
+            partial: vec![],
+            finished: false,
+        }
+    }
+}
+
+/// The state of a text being generated under a [`Constraint`].
+///
+/// Before sampling each token, [`apply`](Self::apply) bans the tokens that the grammar does not allow
+/// in the [`LogitBias`] of the batch, so that [`Sampled`](super::sampler::Sampled) or [`Greedy`](super::infer::Greedy)
+/// runtimes only produce valid tokens; the sampled token is then [`accept`](Self::accept)ed.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    constraint: Constraint,
+    stacks: Vec<Stack>,
+    /// Bytes of a char that is split across tokens.
+    partial: Vec<u8>,
+    finished: bool,
+}
+
+impl Matcher {
+    /// If the text so far matches the root rule as a whole.
+    pub fn is_accepted(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// If an end token is accepted, after which no token is allowed.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Advance the matcher by a token, or fail if the grammar does not allow it.
+    pub fn accept(&mut self, token: u16) -> Result<(), ConstraintError> {
+        if self.finished {
+            return Err(ConstraintError::Rejected(token));
+        }
+        if self.constraint.end.contains(&token) && self.is_accepted() {
+            self.finished = true;
+            return Ok(());
+        }
+
+        let grammar = &self.constraint.grammar;
+        let bytes = match self.constraint.trie.bytes.get(token as usize) {
+            Some(bytes) if !bytes.is_empty() => bytes,
+            _ => return Err(ConstraintError::Rejected(token)),
+        };
+        let mut state = (self.stacks.clone(), self.partial.clone());
+        for &byte in bytes {
+            state = grammar
+                .feed(&state.0, &state.1, byte)
+                .ok_or(ConstraintError::Rejected(token))?;
+        }
+        (self.stacks, self.partial) = state;
+        Ok(())
+    }
+
+    /// Tokens that the grammar allows next, in ascending order.
+    pub fn allowed(&self) -> Vec<u16> {
+        if self.finished {
+            return vec![];
+        }
+        let mut tokens = vec![];
+        if self.is_accepted() {
+            tokens.extend_from_slice(&self.constraint.end);
+        }
+        self.visit(0, &self.stacks, &self.partial, &mut tokens);
+        tokens.sort_unstable();
+        tokens.dedup();
+        tokens
+    }
+
+    fn visit(&self, node: usize, stacks: &[Stack], partial: &[u8], tokens: &mut Vec<u16>) {
+        let grammar = &self.constraint.grammar;
+        let trie = &self.constraint.trie;
+        for &(byte, child) in &trie.nodes[node].children {
+            if let Some((stacks, partial)) = grammar.feed(stacks, partial, byte) {
+                tokens.extend_from_slice(&trie.nodes[child].tokens);
+                self.visit(child, &stacks, &partial, tokens);
+            }
+        }
+    }
+
+    /// If each token of the vocabulary is allowed next.
+    pub fn mask(&self) -> Vec<bool> {
+        let mut mask = vec![false; self.constraint.vocab_size()];
+        for token in self.allowed() {
+            if let Some(x) = mask.get_mut(token as usize) {
+                *x = true;
+            }
+        }
+        mask
+    }
+
+    /// The [mask](Self::mask) packed into a bitset, for [`softmax_masked`](super::softmax::softmax_masked).
+    pub fn packed_mask(&self) -> Vec<u32> {
+        super::softmax::pack_mask(&self.mask())
+    }
+
+    /// Ban all tokens that are not allowed next in a batch of the logit biases, replacing its other biases.
+    pub fn apply(&self, bias: &LogitBias, batch: usize) -> Result<()> {
+        let allowed: HashSet<u16> = self.allowed().into_iter().collect();
+        let bans: Vec<u16> = (0..bias.num_vocab())
+            .map(|token| token as u16)
+            .filter(|token| !allowed.contains(token))
+            .collect();
+        bias.set(batch, &HashMap::new(), &bans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Constraint, ConstraintError, Grammar};
+    use crate::tokenizer::Tokenizer;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+            # a comment
+            root ::= greeting ( " " name ){1,2} "!"?
+            greeting ::= "hi" | "hello"
+            name ::= [A-Z] [a-z]*
+        "#;
+        let grammar = Grammar::parse(text).unwrap();
+        assert!(grammar.matches("hi Bob"));
+        assert!(grammar.matches("hello Alice Bob!"));
+        assert!(!grammar.matches("hello"));
+        assert!(!grammar.matches("hello alice"));
+        assert!(!grammar.matches("hi A B C"));
+
+        let grammar = Grammar::parse(r#"root ::= [^a-c\n] . "\x41é""#).unwrap();
+        assert!(grammar.matches("dzAé"));
+        assert!(!grammar.matches("azAé"));
+
+        let error = |text| Grammar::parse(text).unwrap_err();
+        assert_eq!(error("rule ::= \"a\""), ConstraintError::MissingRoot);
+        assert_eq!(
+            error("root ::= item"),
+            ConstraintError::UndefinedRule("item".into())
+        );
+        assert_eq!(
+            error("root ::= x? root \"a\" | \"b\"\nx ::= \"x\""),
+            ConstraintError::LeftRecursion("root".into())
+        );
+        assert!(matches!(
+            error("root ::= ( \"a\""),
+            ConstraintError::Syntax(..)
+        ));
+        assert!(matches!(
+            error("root ::= \"a\"{3,2}"),
+            ConstraintError::Syntax(..)
+        ));
+    }
+
+    #[test]
+    fn test_matcher() {
+        // "你" is `[228, 189, 160]` and "好" is `[229, 165, 189]` in UTF-8
+        let vocab = r#"{
+            "1": "a", "2": "b", "3": "ab", "4": "ba", "5": [228], "6": [189, 160],
+            "7": [229], "8": [165, 189], "9": "c"
+        }"#;
+        let tokenizer = Tokenizer::new(vocab).unwrap();
+        let grammar = Grammar::parse(r#"root ::= ( "a" | "b" )+ "你"?"#).unwrap();
+        let constraint = Constraint::new(grammar, &tokenizer);
+
+        let mut matcher = constraint.matcher();
+        assert_eq!(matcher.allowed(), vec![1, 2, 3, 4]);
+        matcher.accept(3).unwrap();
+        assert!(matcher.is_accepted());
+        // the first byte of "你" is allowed, but not that of "好"
+        assert_eq!(matcher.allowed(), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(matcher.accept(9), Err(ConstraintError::Rejected(9)));
+
+        matcher.accept(5).unwrap();
+        assert!(!matcher.is_accepted());
+        assert_eq!(matcher.allowed(), vec![6]);
+        matcher.accept(6).unwrap();
+        assert_eq!(matcher.allowed(), vec![0]);
+        matcher.accept(0).unwrap();
+        assert!(matcher.is_finished());
+        assert!(matcher.allowed().is_empty());
+
+        let mask = constraint.matcher().mask();
+        assert_eq!(
+            mask,
+            vec![false, true, true, true, true, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "age": { "type": "integer" },
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "enum": ["x", "y"] } },
+            },
+            "required": ["name"],
+        });
+        let grammar = Grammar::from_json_schema(&schema).unwrap();
+        assert!(grammar.matches(r#"{"name": "Bob"}"#));
+        assert!(grammar.matches(r#"{"age": 42, "name": "Bob \"B\"", "tags": ["x", "y"]}"#));
+        assert!(grammar.matches(r#"{"name":"Bob","tags":[]}"#));
+        assert!(!grammar.matches(r#"{"age": 42}"#));
+        assert!(!grammar.matches(r#"{"name": "Bob", "age": 42}"#));
+        assert!(!grammar.matches(r#"{"name": "Bob", "tags": ["z"]}"#));
+        assert!(!grammar.matches(r#"{"age": 4.2, "name": "Bob"}"#));
+
+        let schema = json!({ "anyOf": [{ "type": "null" }, { "const": { "a": [1, true] } }] });
+        let grammar = Grammar::from_json_schema(&schema).unwrap();
+        assert!(grammar.matches("null"));
+        assert!(grammar.matches(r#"{"a":[1,true]}"#));
+        assert!(!grammar.matches("true"));
+
+        let grammar = Grammar::from_json_schema(&json!({})).unwrap();
+        assert!(grammar.matches(r#"[1.5e3, {"a": null}, "é"]"#));
+        assert!(!grammar.matches("[01]"));
+
+        assert!(matches!(
+            Grammar::from_json_schema(&json!({ "$ref": "#/$defs/a" })),
+            Err(ConstraintError::Schema(_))
+        ));
+    }
+}
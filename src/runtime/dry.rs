@@ -0,0 +1,487 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    infer::{InferChunk, InferInfo, InferOutput, InferOutputBatch, InferRedirect},
+    model::{EmbedDevice, ModelInfo, Quant},
+    Job, JobBuilder, RuntimeConfig,
+};
+use crate::tensor::{Cursor, IntoPackedCursors, TensorCpu, TensorInit};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+pub enum DryRunError {
+    #[error("input of {0} batches for a state of {1} batches")]
+    Batch(usize, usize),
+    #[error("chunk of batch {batch} has {len} tokens, but its job is built for {expected}")]
+    Chunk {
+        batch: usize,
+        len: usize,
+        expected: usize,
+    },
+    #[error("token {token} of batch {batch} out of range of vocabulary size {num_vocab}")]
+    TokenOutOfRange {
+        batch: usize,
+        token: u16,
+        num_vocab: usize,
+    },
+    #[error("{size} packed cursor words for {num_token} tokens")]
+    Cursor { size: usize, num_token: usize },
+    #[error("{size} bytes of device memory exceed the budget of {budget} bytes")]
+    Budget { size: usize, budget: usize },
+}
+
+/// What a [`DryRun`] has seen so far.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DryRunReport {
+    /// Jobs built, including those built ahead but never submitted.
+    pub num_build: usize,
+    /// Jobs submitted.
+    pub num_submit: usize,
+    /// Tokens of all submitted jobs.
+    pub num_token: usize,
+    /// Outputs (logits or embeddings) of all submitted jobs.
+    pub num_output: usize,
+    /// Most tokens in one job.
+    pub max_token: usize,
+    /// Estimated device memory of the weights, see [`ModelInfo::estimate_size`].
+    pub weight_size: usize,
+    /// Estimated device memory of the state, see [`ModelInfo::estimate_state_size`].
+    pub state_size: usize,
+    /// Estimated device memory of the buffers of the largest job, see [`ModelInfo::estimate_buffer_size`].
+    pub max_buffer_size: usize,
+    /// Errors met, in order. A runtime stops at its first error, as it would with a model.
+    pub errors: Vec<String>,
+}
+
+impl DryRunReport {
+    /// Estimated device memory at the largest job.
+    pub fn peak_size(&self) -> usize {
+        self.weight_size + self.state_size + self.max_buffer_size
+    }
+}
+
+/// A stand-in for a model runtime that runs no kernels and needs no device.
+///
+/// Jobs go through the same input handling as a model's: the chunks are checked against the jobs built for them,
+/// tokens are checked against the vocabulary, cursors are packed, and device memory is accounted (and checked
+/// against a budget if any). Outputs are zeros of the right shapes. This lets servers test their logic
+/// on machines without GPUs, and lets downstream projects validate their configurations in CI.
+#[derive(Debug, Clone)]
+pub struct DryRun {
+    info: ModelInfo,
+    num_batch: usize,
+    budget: Option<usize>,
+    report: Arc<Mutex<DryRunReport>>,
+}
+
+impl DryRun {
+    pub fn new(info: ModelInfo, num_batch: usize) -> Self {
+        let report = DryRunReport {
+            weight_size: info.estimate_size(&HashMap::new(), EmbedDevice::Cpu),
+            state_size: info.estimate_state_size(num_batch),
+            ..Default::default()
+        };
+        Self {
+            info,
+            num_batch,
+            budget: None,
+            report: Arc::new(Mutex::new(report)),
+        }
+    }
+
+    /// Account the weights as loaded with the given quantization and embed device.
+    pub fn quant(self, quant: &HashMap<usize, Quant>, embed_device: EmbedDevice) -> Self {
+        self.record(|report| report.weight_size = self.info.estimate_size(quant, embed_device));
+        self
+    }
+
+    /// Fail jobs that would take more device memory than `budget` bytes.
+    /// A [`RuntimeConfig::budget`] replaces it when the runtime is reconfigured.
+    pub fn budget(self, budget: usize) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+
+    pub fn report(&self) -> DryRunReport {
+        match self.report.lock() {
+            Ok(report) => report.clone(),
+            Err(_) => Default::default(),
+        }
+    }
+
+    fn record(&self, f: impl FnOnce(&mut DryRunReport)) {
+        if let Ok(mut report) = self.report.lock() {
+            f(&mut report);
+        }
+    }
+
+    fn fail<T>(&self, err: DryRunError) -> Result<T> {
+        self.record(|report| report.errors.push(err.to_string()));
+        Err(err.into())
+    }
+}
+
+impl JobBuilder<DryJob> for DryRun {
+    type Info = InferInfo;
+
+    fn reconfigure(&mut self, config: &RuntimeConfig) {
+        if let Some(budget) = config.budget {
+            self.budget = Some(budget);
+        }
+    }
+
+    fn build(&self, seed: Self::Info) -> Result<DryJob> {
+        if seed.num_batch() != self.num_batch {
+            return self.fail(DryRunError::Batch(seed.num_batch(), self.num_batch));
+        }
+
+        let redirect = seed.redirect();
+        let num_token = seed.num_token();
+        let buffer_size = self
+            .info
+            .estimate_buffer_size(num_token, redirect.headers.len());
+
+        let mut size = 0;
+        self.record(|report| {
+            report.num_build += 1;
+            report.max_token = report.max_token.max(num_token);
+            report.max_buffer_size = report.max_buffer_size.max(buffer_size);
+            size = report.weight_size + report.state_size + buffer_size;
+        });
+        if let Some(budget) = self.budget.filter(|&budget| size > budget) {
+            return self.fail(DryRunError::Budget { size, budget });
+        }
+
+        Ok(DryJob {
+            dry: self.clone(),
+            info: seed,
+            redirect,
+        })
+    }
+}
+
+pub struct DryJob {
+    dry: DryRun,
+    info: InferInfo,
+    redirect: InferRedirect,
+}
+
+impl Job for DryJob {
+    type Info = InferInfo;
+    type Input = InferChunk;
+    type Output = InferOutput;
+
+    fn load(self, input: &Self::Input) -> Result<Self> {
+        let dry = &self.dry;
+        if input.num_batch() != self.info.num_batch() {
+            return dry.fail(DryRunError::Batch(input.num_batch(), self.info.num_batch()));
+        }
+
+        let mut cursors = vec![];
+        let mut token = 0;
+        for (batch, (chunk, info)) in input.iter().zip(self.info.iter()).enumerate() {
+            let len = chunk.0.len();
+            if len != info.len {
+                let expected = info.len;
+                return dry.fail(DryRunError::Chunk {
+                    batch,
+                    len,
+                    expected,
+                });
+            }
+            let num_vocab = dry.info.num_vocab;
+            if let Some(&x) = chunk.0.iter().find(|&&x| x as usize >= num_vocab) {
+                return dry.fail(DryRunError::TokenOutOfRange {
+                    batch,
+                    token: x,
+                    num_vocab,
+                });
+            }
+            cursors.push(Cursor { batch, token, len });
+            token += len;
+        }
+
+        let size = cursors.into_cursors().len();
+        if size != Cursor::PACKED_SIZE * token {
+            let num_token = token;
+            return dry.fail(DryRunError::Cursor { size, num_token });
+        }
+        Ok(self)
+    }
+
+    fn submit(&mut self) {
+        let num_token = self.info.num_token();
+        let num_output = self.redirect.headers.len();
+        self.dry.record(|report| {
+            report.num_submit += 1;
+            report.num_token += num_token;
+            report.num_output += num_output;
+        });
+    }
+
+    async fn back(self) -> Result<Self::Output> {
+        let info = &self.dry.info;
+        let batches = self
+            .redirect
+            .outputs
+            .iter()
+            .zip(self.info.iter())
+            .map(|(&(start, end), batch)| {
+                let num_row = match batch.embed {
+                    true => info.num_emb,
+                    false => info.num_vocab,
+                };
+                let shape = [num_row, end - start, 1, 1];
+                TensorCpu::from_data(shape, vec![0.0; num_row * (end - start)])
+                    .map(InferOutputBatch)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(InferOutput(batches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+
+    use super::{DryRun, DryRunError};
+    use crate::{
+        runtime::{
+            event::Event,
+            infer::{
+                InferChunk, InferChunkBatch, InferInfo, InferInfoBatch, InferKind, InferOption,
+                InferRequest, InferResponse, SampleOption, StopOption,
+            },
+            model::{EmbedDevice, ModelVersion, Quant},
+            tiny::TinyModel,
+            Job, JobBuilder, JobRuntime, RuntimeConfig,
+        },
+        tensor::TensorShape,
+    };
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let quant = HashMap::from([(0, Quant::Int8)]);
+        let dry = DryRun::new(info.clone(), 2).quant(&quant, EmbedDevice::Gpu);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let responses = runtime.block_on(async {
+            let runtime = JobRuntime::new(dry.clone()).await;
+            let sample = SampleOption {
+                temperature: 0.0,
+                ..Default::default()
+            };
+            let stop = StopOption {
+                max_tokens: 3,
+                tokens: vec![],
+            };
+            let requests = vec![
+                InferRequest {
+                    tokens: vec![1; 40].into(),
+                    kind: InferKind::Logits(InferOption::Last),
+                    session: None,
+                },
+                InferRequest {
+                    tokens: vec![2, 3].into(),
+                    kind: InferKind::Token {
+                        sample,
+                        stop,
+                        phrases: vec![],
+                    },
+                    session: None,
+                },
+            ];
+            runtime.serve(requests, 32).await
+        })?;
+        let InferResponse::Logits(logits) = &responses[0] else {
+            panic!("expect logits");
+        };
+        assert_eq!(logits.shape()[0], info.num_vocab);
+        let InferResponse::Token { tokens, .. } = &responses[1] else {
+            panic!("expect tokens");
+        };
+        assert_eq!(tokens.len(), 3);
+
+        let report = dry.report();
+        assert!(report.errors.is_empty());
+        assert_eq!(report.num_token, 40 + 2 + 2);
+        assert_eq!(report.max_token, 32);
+        assert_eq!(report.state_size, info.estimate_state_size(2));
+        assert_eq!(
+            report.weight_size,
+            info.estimate_size(&quant, EmbedDevice::Gpu)
+        );
+
+        // the first batch has a token out of the vocabulary
+        let seed = InferInfo(vec![
+            InferInfoBatch {
+                len: 2,
+                option: Some(InferOption::Last),
+                embed: false,
+                ..Default::default()
+            },
+            Default::default(),
+        ]);
+        let chunk = InferChunk(vec![
+            InferChunkBatch(vec![1, info.num_vocab as u16]),
+            Default::default(),
+        ]);
+        let job = dry.build(seed.clone())?;
+        let err = job.load(&chunk).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(DryRunError::TokenOutOfRange { batch: 0, .. })
+        ));
+        assert_eq!(dry.report().errors.len(), 1);
+
+        let dry = DryRun::new(info, 2).budget(1024);
+        let err = dry.build(seed).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(DryRunError::Budget { budget: 1024, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limit() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let dry = DryRun::new(info, 1);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let runtime = JobRuntime::new(dry).await;
+            runtime.set_rate_limit(1, Some(50.0));
+            assert_eq!(runtime.rate_limit(1), Some(50.0));
+            assert_eq!(runtime.rate_limit(2), None);
+
+            let request = |session| InferRequest {
+                tokens: vec![2, 3].into(),
+                kind: InferKind::Token {
+                    sample: SampleOption {
+                        temperature: 0.0,
+                        ..Default::default()
+                    },
+                    stop: StopOption {
+                        max_tokens: 5,
+                        tokens: vec![],
+                    },
+                    phrases: vec![],
+                },
+                session: Some(session),
+            };
+
+            // the first token is generated right away, and each of the rest 20ms after the one before
+            let start = Instant::now();
+            let (limited, free) = tokio::join!(
+                runtime.serve(vec![request(1)], 32),
+                runtime.serve(vec![request(2)], 32)
+            );
+            limited?;
+            free?;
+            assert!(start.elapsed() >= Duration::from_millis(80));
+
+            let usage = runtime.usage(1).expect("usage of session 1");
+            assert_eq!(usage.generated_tokens, 5);
+            assert!(usage.throttled_time >= Duration::from_millis(60));
+            let usage = runtime.usage(2).expect("usage of session 2");
+            assert_eq!(usage.generated_tokens, 5);
+            assert_eq!(usage.throttled_time, Duration::ZERO);
+
+            runtime.set_rate_limit(1, None);
+            assert_eq!(runtime.rate_limit(1), None);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_reconfigure() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let dry = DryRun::new(info, 1);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let runtime = JobRuntime::new(dry.clone()).await;
+            let request = InferRequest {
+                tokens: vec![2, 3].into(),
+                kind: InferKind::Token {
+                    sample: runtime.config().sampler,
+                    stop: StopOption {
+                        max_tokens: 5,
+                        tokens: vec![],
+                    },
+                    phrases: vec![],
+                },
+                session: Some(1),
+            };
+            let count_metrics = |mut events: tokio::sync::broadcast::Receiver<Event>| {
+                let mut count = 0;
+                while let Ok(event) = events.try_recv() {
+                    if let Event::Metrics { session: 1, .. } = event {
+                        count += 1;
+                    }
+                }
+                count
+            };
+
+            // metrics after every step by default
+            let events = runtime.subscribe();
+            runtime.serve(vec![request.clone()], 32).await?;
+            assert_eq!(count_metrics(events), 5);
+
+            let config = RuntimeConfig {
+                sampler: SampleOption {
+                    temperature: 0.0,
+                    ..Default::default()
+                },
+                weights: HashMap::from([(1, 2.0)]),
+                metrics_interval: Some(Duration::from_secs(3600)),
+                ..Default::default()
+            };
+            runtime.reconfigure(config.clone());
+            assert_eq!(runtime.config(), config);
+
+            let events = runtime.subscribe();
+            runtime.serve(vec![request.clone()], 32).await?;
+            assert_eq!(count_metrics(events), 1);
+            assert_eq!(runtime.usage(1).map(|x| x.generated_tokens), Some(10));
+
+            // jobs fail once the budget is lowered
+            let mut events = runtime.subscribe();
+            runtime.reconfigure(RuntimeConfig {
+                budget: Some(1024),
+                ..config
+            });
+            let handle = tokio::spawn({
+                let runtime = runtime.clone();
+                async move { runtime.serve(vec![request], 32).await }
+            });
+            loop {
+                if let Event::Error { .. } = events.recv().await? {
+                    break;
+                }
+            }
+            assert!(handle.await.is_err());
+            assert!(dry
+                .report()
+                .errors
+                .iter()
+                .any(|err| err.contains("budget of 1024 bytes")));
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use safetensors::{tensor::TensorView, Dtype};
+
+use super::infer::MIN_TOKEN_CHUNK_SIZE;
+use crate::{
+    context::Context,
+    num::Float,
+    tensor::{kind::ReadWrite, ops::TensorOp, TensorCpu, TensorError, TensorGpu, TensorShape},
+};
+
+/// Captures intermediate activations of a runtime through hooks, so that they can be compared offline,
+/// e.g., against the Python reference implementation.
+///
+/// Each capture is a persistent GPU tensor that a hook copies a runtime buffer into when its job runs.
+/// After a step, [`ActivationDump::dump`] reads the step's tokens back and packs them into safetensors,
+/// one file per step (i.e., per token chunk).
+#[derive(Debug, Clone)]
+pub struct ActivationDump {
+    context: Context,
+    token_chunk_size: usize,
+    /// Only dump every `interval`-th step.
+    pub interval: usize,
+    /// Stop dumping after this many steps.
+    pub limit: Option<usize>,
+    captures: Arc<RwLock<BTreeMap<String, TensorGpu<f32, ReadWrite>>>>,
+}
+
+impl ActivationDump {
+    /// Create a dump for a runtime fed with chunks of at most `token_chunk_size` tokens.
+    /// The chunk size is rounded up the same way as in [`InferInput::new`](super::infer::InferInput::new).
+    pub fn new(context: &Context, token_chunk_size: usize) -> Self {
+        let token_chunk_size = token_chunk_size
+            .max(MIN_TOKEN_CHUNK_SIZE)
+            .next_multiple_of(MIN_TOKEN_CHUNK_SIZE);
+        Self {
+            context: context.clone(),
+            token_chunk_size,
+            interval: 1,
+            limit: None,
+            captures: Default::default(),
+        }
+    }
+
+    pub fn interval(mut self, value: usize) -> Self {
+        self.interval = value.max(1);
+        self
+    }
+
+    pub fn limit(mut self, value: Option<usize>) -> Self {
+        self.limit = value;
+        self
+    }
+
+    /// Create the op that copies `tensor` of shape `[C, T, 1, 1]` into the capture named `name`.
+    /// Call this inside a hook and return the op.
+    pub fn capture(
+        &self,
+        name: impl Into<String>,
+        tensor: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<TensorOp, TensorError> {
+        let shape = tensor.shape();
+        let num_token = shape[1];
+        if num_token > self.token_chunk_size {
+            return Err(TensorError::SliceOutOfRange {
+                dim: self.token_chunk_size,
+                start: 0,
+                end: num_token,
+            });
+        }
+
+        let capture = self
+            .captures
+            .write()
+            .unwrap()
+            .entry(name.into())
+            .or_insert_with(|| {
+                let shape = [shape[0], self.token_chunk_size, 1, 1];
+                self.context.tensor_init(shape)
+            })
+            .clone();
+        capture.check_shape([shape[0], self.token_chunk_size, 1, 1])?;
+
+        TensorOp::blit(
+            tensor.view(.., .., .., ..)?,
+            capture.view(.., 0..num_token, .., ..)?,
+        )
+    }
+
+    /// Names of all captures registered so far.
+    pub fn names(&self) -> Vec<String> {
+        self.captures.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Read back the first `num_token` tokens of every capture, as left by the last executed step.
+    pub async fn back(&self, num_token: usize) -> Result<Vec<(String, TensorCpu<f32>)>> {
+        let captures = self.captures.read().unwrap().clone();
+        let mut tensors = Vec::with_capacity(captures.len());
+        for (name, capture) in captures {
+            let tensor = capture.back().await.slice(.., 0..num_token, .., ..)?;
+            tensors.push((name, tensor));
+        }
+        Ok(tensors)
+    }
+
+    /// Read back and serialize the captures of step `step`, which processed `num_token` tokens.
+    /// Each capture is stored as an `f32` tensor of shape `[T, C]`.
+    ///
+    /// Returns `None` if the step is skipped because of [`interval`](Self::interval) or [`limit`](Self::limit).
+    pub async fn dump(&self, step: usize, num_token: usize) -> Result<Option<Vec<u8>>> {
+        if !step.is_multiple_of(self.interval) || self.limit.is_some_and(|limit| step >= limit) {
+            return Ok(None);
+        }
+        if num_token == 0 {
+            return Ok(None);
+        }
+
+        let tensors = self.back(num_token).await?;
+        let views = tensors
+            .iter()
+            .map(|(name, tensor)| {
+                let shape = tensor.shape();
+                let data = bytemuck::cast_slice(tensor.data());
+                let view = TensorView::new(Dtype::F32, vec![shape[1], shape[0]], data)?;
+                Ok((name, view))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(safetensors::serialize(views, &None)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use half::f16;
+    use itertools::Itertools;
+    use safetensors::SafeTensors;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::ActivationDump;
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption},
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion},
+            tiny::TinyModel,
+            v5, JobInput, JobRuntime,
+        },
+        tensor::ops::TensorOp,
+    };
+
+    #[test]
+    fn test_activation_dump() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let model = TinyModel::new(TinyModel::info(ModelVersion::V5), 42);
+            let info = model.model_info().clone();
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            let dump = ActivationDump::new(&context, 32).interval(2);
+            let mut hooks = v5::HookMap::default();
+            for layer in 0..info.num_layer {
+                let dump = dump.clone();
+                hooks.insert(
+                    v5::Hook::PostFfn(layer),
+                    Box::new(move |frame: v5::Frame<f16>| -> Result<TensorOp, _> {
+                        dump.capture(format!("blocks.{layer}.x"), &frame.buffer.x)
+                    }),
+                );
+            }
+            let model = Build::<v5::Model>::build(ModelBuilder::new(&context, model)).await?;
+            let bundle = v5::ModelRuntime::<f16>::new_with_hooks(model, 1, hooks);
+            let runtime = JobRuntime::new(bundle).await;
+
+            let batch = InferInputBatch {
+                tokens: (0..40).collect(),
+                option: InferOption::Last,
+                ..Default::default()
+            };
+            let mut input = InferInput::new(vec![batch], 32);
+            let mut files = vec![];
+            let mut step = 0;
+            while input.num_token() > 0 {
+                let num_token = input.chunk().num_token();
+                let (next, _) = runtime.infer(input).await;
+                input = next;
+                files.push(dump.dump(step, num_token).await?);
+                step += 1;
+            }
+
+            // the second step is skipped by the interval
+            assert_eq!(files.len(), 2);
+            assert!(files[1].is_none());
+
+            let data = files[0].as_ref().unwrap();
+            let tensors = SafeTensors::deserialize(data)?;
+            let names = tensors.names().into_iter().sorted().collect_vec();
+            assert_eq!(names.len(), info.num_layer);
+            let tensor = tensors.tensor("blocks.0.x")?;
+            assert_eq!(tensor.shape(), [32, info.num_emb]);
+            Ok(())
+        })
+    }
+}
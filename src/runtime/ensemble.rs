@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    infer::{InferInput, InferOutput, InferOutputBatch},
+    JobRuntime,
+};
+use crate::{
+    context::Context,
+    impl_deserialize_seed,
+    num::Float,
+    tensor::{ops::TensorOp, TensorCpu, TensorError, TensorGpu, TensorInto, TensorShape},
+};
+
+/// Weights of the two models' logits in one batch, i.e., `base * logits_base + tuned * logits_tuned`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EnsembleWeight {
+    pub base: f32,
+    pub tuned: f32,
+}
+
+impl Default for EnsembleWeight {
+    fn default() -> Self {
+        Self {
+            base: 0.5,
+            tuned: 0.5,
+        }
+    }
+}
+
+impl_deserialize_seed!(EnsembleWeight);
+
+/// Combine the logits of two models batch by batch on GPU.
+/// Batches that are empty in both are passed through.
+pub async fn ensemble<T: Float>(
+    context: &Context,
+    base: Vec<TensorCpu<T>>,
+    tuned: Vec<TensorCpu<T>>,
+    weights: &[EnsembleWeight],
+) -> Result<Vec<TensorCpu<T>>, TensorError> {
+    if base.len() != tuned.len() {
+        return Err(TensorError::Batch(base.len(), tuned.len()));
+    }
+    if base.len() != weights.len() {
+        return Err(TensorError::Batch(base.len(), weights.len()));
+    }
+
+    let mut tensors = Vec::with_capacity(base.len());
+    let mut ops = Vec::with_capacity(base.len());
+
+    for ((base, tuned), weight) in base.into_iter().zip(tuned).zip(weights) {
+        let output: TensorGpu<_, _> = base.transfer_into(context);
+        if output.size() > 0 {
+            let input: TensorGpu<_, _> = tuned.transfer_into(context);
+            let factor = vec![weight.tuned, weight.base, 0.0, 0.0];
+            let factor = context.tensor_from_data([4, 1, 1, 1], factor)?;
+            ops.push(TensorOp::blend(&factor, &input, &output)?);
+        } else {
+            tuned.check_shape(output.shape())?;
+        }
+        tensors.push(output);
+    }
+    context.submit(context.encode(&TensorOp::List(ops)));
+
+    let mut output = Vec::with_capacity(tensors.len());
+    for tensor in tensors.into_iter() {
+        output.push(tensor.back().await);
+    }
+    Ok(output)
+}
+
+/// Two runtimes of models sharing a tokenizer, e.g., a base model and its domain fine-tune,
+/// which read the same tokens and have their logits combined with per-batch weights.
+///
+/// Both runtimes must have the same number of batches and vocabulary size.
+pub struct Ensemble {
+    context: Context,
+    base: JobRuntime<InferInput, InferOutput>,
+    tuned: JobRuntime<InferInput, InferOutput>,
+}
+
+impl Ensemble {
+    pub fn new(
+        context: &Context,
+        base: JobRuntime<InferInput, InferOutput>,
+        tuned: JobRuntime<InferInput, InferOutput>,
+    ) -> Self {
+        Self {
+            context: context.clone(),
+            base,
+            tuned,
+        }
+    }
+
+    /// Run one step of both models on the same input, and combine their outputs with `weights`, one for each batch.
+    pub async fn infer(
+        &self,
+        input: InferInput,
+        weights: &[EnsembleWeight],
+    ) -> Result<(InferInput, InferOutput), TensorError> {
+        let ((input, base), (_, tuned)) =
+            futures::join!(self.base.infer(input.clone()), self.tuned.infer(input));
+        let base = base.0.into_iter().map(|x| x.0).collect();
+        let tuned = tuned.0.into_iter().map(|x| x.0).collect();
+        let output = ensemble(&self.context, base, tuned, weights).await?;
+        let output = InferOutput(output.into_iter().map(InferOutputBatch).collect());
+        Ok((input, output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::{Ensemble, EnsembleWeight};
+    use crate::{
+        context::{Context, ContextBuilder, InstanceExt},
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion},
+            tiny::TinyModel,
+            v5, JobRuntime,
+        },
+    };
+
+    async fn create_runtime(
+        context: &Context,
+        seed: u64,
+    ) -> Result<JobRuntime<InferInput, InferOutput>> {
+        let model = TinyModel::new(TinyModel::info(ModelVersion::V5), seed);
+        let model = Build::<v5::Model>::build(ModelBuilder::new(context, model)).await?;
+        Ok(JobRuntime::new(v5::ModelRuntime::<f32>::new(model, 2)).await)
+    }
+
+    fn create_input() -> InferInput {
+        let batches = [vec![1, 2, 3], vec![4, 5]]
+            .into_iter()
+            .map(|tokens| InferInputBatch {
+                tokens: tokens.into(),
+                option: InferOption::Full,
+                ..Default::default()
+            })
+            .collect();
+        InferInput::new(batches, 32)
+    }
+
+    #[test]
+    fn test_ensemble() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            let weights = [
+                EnsembleWeight::default(),
+                EnsembleWeight {
+                    base: 1.5,
+                    tuned: -0.5,
+                },
+            ];
+            let ensemble = Ensemble::new(
+                &context,
+                create_runtime(&context, 42).await?,
+                create_runtime(&context, 43).await?,
+            );
+            let (input, InferOutput(output)) = ensemble.infer(create_input(), &weights).await?;
+            assert_eq!(input.num_token(), 0);
+
+            let (_, InferOutput(base)) = create_runtime(&context, 42)
+                .await?
+                .infer(create_input())
+                .await;
+            let (_, InferOutput(tuned)) = create_runtime(&context, 43)
+                .await?
+                .infer(create_input())
+                .await;
+
+            for (((output, base), tuned), weight) in
+                output.iter().zip_eq(&base).zip_eq(&tuned).zip_eq(&weights)
+            {
+                for ((x, a), b) in output.0.iter().zip_eq(base.0.iter()).zip_eq(tuned.0.iter()) {
+                    let y = weight.base * a + weight.tuned * b;
+                    assert!((x - y).abs() <= 1.0e-5 * y.abs().max(1.0));
+                }
+            }
+            Ok(())
+        })
+    }
+}
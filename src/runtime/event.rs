@@ -0,0 +1,144 @@
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use super::{SessionId, Usage};
+use crate::context::ArenaStats;
+
+/// Structured events of a [`JobRuntime`](super::JobRuntime), for frontends that consume them over IPC or stdio.
+///
+/// Each event serializes into one JSON object tagged by `"event"`, e.g.,
+/// `{"event":"token_generated","session":1,"batch":0,"token":42}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A token is sampled for a batch.
+    TokenGenerated {
+        session: Option<SessionId>,
+        batch: usize,
+        token: u16,
+    },
+    /// A step of inference is read back.
+    ChunkDone {
+        num_token: usize,
+        sessions: Vec<SessionId>,
+    },
+    /// The state of a batch is read back, e.g., to be cached.
+    StateBacked {
+        session: Option<SessionId>,
+        batch: usize,
+        size: usize,
+    },
+    /// A summarization pass of a [`Memory`](super::memory::Memory) starts, over a prompt of `num_token` tokens.
+    Summarizing {
+        session: Option<SessionId>,
+        batch: usize,
+        num_token: usize,
+    },
+    /// A summary of `num_token` tokens is generated, and replaces the oldest turns of a conversation.
+    Summarized {
+        session: Option<SessionId>,
+        batch: usize,
+        num_token: usize,
+    },
+    Error {
+        message: String,
+    },
+    /// Something went wrong but the runtime recovered, e.g., a step that ran out of device memory is retried in smaller chunks.
+    Warning {
+        message: String,
+    },
+    /// The total usage of a session, after a step it takes part in.
+    /// At most one per [`RuntimeConfig::metrics_interval`](super::RuntimeConfig::metrics_interval) if set.
+    Metrics {
+        session: SessionId,
+        usage: Usage,
+    },
+    /// Device memory of each arena of the context, after a step, see [`JobRuntime::arenas`](super::JobRuntime::arenas).
+    /// At most one per [`RuntimeConfig::metrics_interval`](super::RuntimeConfig::metrics_interval) if set.
+    Arenas {
+        arenas: Vec<ArenaStats>,
+    },
+}
+
+impl Event {
+    /// Write the event as one line of JSON.
+    pub fn write_json_line(&self, mut writer: impl Write) -> std::io::Result<()> {
+        serde_json::to_writer(&mut writer, self)?;
+        writer.write_all(b"\n")
+    }
+}
+
+/// Write events as JSON lines until the runtime is dropped.
+/// If the writer falls behind, the dropped events are reported as an [`Event::Error`].
+pub async fn write_json_lines(
+    mut receiver: Receiver<Event>,
+    mut writer: impl Write,
+) -> std::io::Result<()> {
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => Event::Error {
+                message: format!("{count} events dropped"),
+            },
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        event.write_json_line(&mut writer)?;
+        writer.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use super::{write_json_lines, Event};
+    use crate::runtime::Usage;
+
+    #[test]
+    fn test_json_lines() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let (sender, receiver) = tokio::sync::broadcast::channel(4);
+            let events = vec![
+                Event::TokenGenerated {
+                    session: Some(1),
+                    batch: 0,
+                    token: 42,
+                },
+                Event::Metrics {
+                    session: 1,
+                    usage: Usage {
+                        prompt_tokens: 3,
+                        generated_tokens: 1,
+                        gpu_time: Duration::from_millis(5),
+                        throttled_time: Duration::ZERO,
+                    },
+                },
+            ];
+            for event in events.clone() {
+                sender.send(event)?;
+            }
+            drop(sender);
+
+            let mut output = vec![];
+            write_json_lines(receiver, &mut output).await?;
+            let output = String::from_utf8(output)?;
+            let lines: Vec<_> = output.lines().collect();
+            assert_eq!(
+                lines[0],
+                r#"{"event":"token_generated","session":1,"batch":0,"token":42}"#
+            );
+
+            let parsed = lines
+                .iter()
+                .map(|line| serde_json::from_str(line))
+                .collect::<Result<Vec<Event>, _>>()?;
+            assert_eq!(parsed, events);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    choice::log_prob,
+    event::Event,
+    infer::{
+        InferInput, InferInputBatch, InferOption, InferOutput, SampleOption, StopOption, StopReason,
+    },
+    model::State,
+    JobRuntime,
+};
+
+/// How the rollouts of [`JobRuntime::explore`] are generated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExploreOption {
+    /// Conditions that end each rollout.
+    pub stop: StopOption,
+    pub token_chunk_size: usize,
+}
+
+impl Default for ExploreOption {
+    fn default() -> Self {
+        Self {
+            stop: Default::default(),
+            token_chunk_size: 128,
+        }
+    }
+}
+
+/// One continuation of the prefix of [`JobRuntime::explore`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rollout {
+    /// The batch this rollout is generated in, which is also the index of its sampler.
+    pub batch: usize,
+    /// Generated tokens, without the stop token.
+    pub tokens: Vec<u16>,
+    pub reason: StopReason,
+    /// Sum of the log-probabilities of the sampled tokens (the stop token included) under the model,
+    /// i.e., before temperature and top-p are applied.
+    pub log_prob: f32,
+}
+
+impl JobRuntime<InferInput, InferOutput> {
+    /// Generate one continuation of `prefix` for each sampler, in parallel, and return them ranked by log-probability.
+    ///
+    /// The prefix (but its last token) is run once in batch 0 of `state`, which must be the runtime's state,
+    /// and the resulting state is [forked](State::fork) into the batches of the other samplers.
+    /// Batch 0 should thus start from the state to explore from (e.g., the initial one).
+    /// The runtime must have at least as many batches as samplers; the batches beyond are left untouched.
+    pub async fn explore(
+        &self,
+        state: &(impl State + ?Sized),
+        prefix: &[u16],
+        samplers: &[SampleOption],
+        option: ExploreOption,
+    ) -> Result<Vec<Rollout>> {
+        let Some((&last, prefix)) = prefix.split_last() else {
+            bail!("empty prefix");
+        };
+        let num_batch = state.num_batch();
+        let num_rollout = samplers.len();
+        if num_rollout == 0 || num_rollout > num_batch {
+            bail!("{num_rollout} samplers for a state of {num_batch} batches");
+        }
+
+        let batch = |batch: usize, tokens: &[u16]| InferInputBatch {
+            tokens: match batch < num_rollout {
+                true => tokens.into(),
+                false => vec![].into(),
+            },
+            option: InferOption::Last,
+            ..Default::default()
+        };
+
+        let batches = (0..num_batch)
+            .map(|index| batch(index, if index == 0 { prefix } else { &[] }))
+            .collect();
+        let mut input = InferInput::new(batches, option.token_chunk_size);
+        while input.num_token() > 0 {
+            (input, _) = self.infer(input).await;
+        }
+        state.fork(0, &(1..num_rollout).collect_vec())?;
+
+        let batches = (0..num_batch).map(|index| batch(index, &[last])).collect();
+        let mut input = InferInput::new(batches, option.token_chunk_size);
+
+        let stop = &option.stop;
+        let mut randoms = samplers.iter().map(|sample| sample.seed).collect_vec();
+        let mut generated = vec![vec![]; num_rollout];
+        let mut log_probs = vec![0.0; num_rollout];
+        let mut reasons = vec![None; num_rollout];
+
+        while reasons.iter().any(Option::is_none) {
+            let (next, InferOutput(output)) = self.infer(input).await;
+            input = next;
+
+            for (batch, (sample, output)) in samplers.iter().zip(output).enumerate() {
+                if reasons[batch].is_some() || output.size() == 0 {
+                    continue;
+                }
+                let token = sample.sample(output.data(), &mut randoms[batch]);
+                log_probs[batch] += log_prob(output.data(), token);
+                self.emit(Event::TokenGenerated {
+                    session: None,
+                    batch,
+                    token,
+                });
+
+                let tokens = &mut generated[batch];
+                reasons[batch] = match stop.tokens.contains(&token) {
+                    true => Some(StopReason::Token(token)),
+                    false => {
+                        tokens.push(token);
+                        (tokens.len() >= stop.max_tokens).then_some(StopReason::Length)
+                    }
+                };
+                if reasons[batch].is_none() {
+                    input.batches[batch].tokens = vec![token].into();
+                }
+            }
+        }
+
+        let rollouts = generated
+            .into_iter()
+            .zip_eq(reasons.into_iter().flatten())
+            .zip_eq(log_probs)
+            .enumerate()
+            .map(|(batch, ((tokens, reason), log_prob))| Rollout {
+                batch,
+                tokens,
+                reason,
+                log_prob,
+            })
+            .sorted_by(|x, y| x.log_prob.total_cmp(&y.log_prob).reverse())
+            .collect();
+        Ok(rollouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::ExploreOption;
+    use crate::runtime::{
+        choice::ChoiceOption,
+        infer::{InferKind, InferRequest, InferResponse, SampleOption, StopOption},
+        model::{Build, ModelBuilder, ModelRuntime, ModelVersion},
+        tiny::{
+            tests::{create_context, prompts},
+            TinyModel,
+        },
+        v5, JobRuntime,
+    };
+
+    #[test]
+    fn test_explore() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let prefix = prompts(&info)[0][..10].to_vec();
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let create_runtime = |num_batch| {
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                async move {
+                    let model = Build::<v5::Model>::build(builder).await?;
+                    anyhow::Ok(v5::ModelRuntime::<f32>::new(model, num_batch))
+                }
+            };
+
+            let samplers = [0.0, 1.0, 1.0]
+                .into_iter()
+                .enumerate()
+                .map(|(seed, temperature)| SampleOption {
+                    temperature,
+                    top_p: 1.0,
+                    seed: seed as u64,
+                })
+                .collect_vec();
+            let option = ExploreOption {
+                stop: StopOption {
+                    max_tokens: 5,
+                    tokens: vec![],
+                },
+                token_chunk_size: 4,
+            };
+            let runtime = create_runtime(4).await?;
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+            let rollouts = runtime.explore(&state, &prefix, &samplers, option).await?;
+            assert_eq!(rollouts.len(), 3);
+            assert!(rollouts
+                .iter()
+                .tuple_windows()
+                .all(|(x, y)| x.log_prob >= y.log_prob));
+            assert!(rollouts.iter().all(|x| x.tokens.len() == 5));
+
+            // the greedy rollout matches plain greedy decoding
+            let greedy = rollouts.iter().find(|x| x.batch == 0).unwrap();
+            let runtime = JobRuntime::new(create_runtime(1).await?).await;
+            let requests = vec![InferRequest {
+                tokens: prefix.clone().into(),
+                kind: InferKind::Token {
+                    sample: samplers[0],
+                    stop: StopOption {
+                        max_tokens: 5,
+                        tokens: vec![],
+                    },
+                    phrases: vec![],
+                },
+                session: None,
+            }];
+            let InferResponse::Token { tokens, .. } = &runtime.serve(requests, 4).await?[0] else {
+                panic!("expect tokens");
+            };
+            assert_eq!(tokens, &greedy.tokens);
+
+            // every forked batch continues from the prefix
+            let rollouts = rollouts
+                .into_iter()
+                .sorted_by_key(|x| x.batch)
+                .collect_vec();
+            let candidates = rollouts.iter().map(|x| x.tokens.clone()).collect_vec();
+            let runtime = JobRuntime::new(create_runtime(3).await?).await;
+            let option = ChoiceOption {
+                length_penalty: 0.0,
+                token_chunk_size: 32,
+            };
+            let choices = runtime.choose(&prefix, &candidates, option).await?;
+            for (choice, rollout) in choices.iter().zip_eq(rollouts.iter()) {
+                assert!((choice.log_prob - rollout.log_prob).abs() < 1.0e-3);
+            }
+            Ok(())
+        })
+    }
+}
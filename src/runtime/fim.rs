@@ -0,0 +1,288 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+    model::State,
+    JobRuntime,
+};
+use crate::{impl_deserialize_seed, tensor::TensorCpu};
+
+/// Special tokens of a model trained for fill-in-the-middle.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FimTokens {
+    pub prefix: u16,
+    pub suffix: u16,
+    pub middle: u16,
+    /// Tokens that end the middle, e.g., the end-of-middle or end-of-text token.
+    pub stop: Vec<u16>,
+}
+
+/// How the prefix and the suffix are laid out in the prompt.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FimLayout {
+    /// `<PRE> prefix <SUF> suffix <MID>`.
+    #[default]
+    Psm,
+    /// `<SUF> suffix <PRE> prefix <MID>`.
+    /// Since the suffix comes first, the state after it can be reused while the prefix changes.
+    Spm,
+}
+
+impl_deserialize_seed!(FimLayout);
+
+impl FimTokens {
+    /// Split the prompt into the conditioning part, whose state can be cached, and the remaining part.
+    pub fn split(&self, layout: FimLayout, prefix: &[u16], suffix: &[u16]) -> (Vec<u16>, Vec<u16>) {
+        let prefix = [&[self.prefix], prefix].concat();
+        let suffix = [&[self.suffix], suffix].concat();
+        match layout {
+            FimLayout::Psm => (prefix, [suffix, vec![self.middle]].concat()),
+            FimLayout::Spm => (suffix, [prefix, vec![self.middle]].concat()),
+        }
+    }
+
+    /// The whole prompt, after which the model generates the middle.
+    pub fn prompt(&self, layout: FimLayout, prefix: &[u16], suffix: &[u16]) -> Vec<u16> {
+        let (conditioning, remain) = self.split(layout, prefix, suffix);
+        [conditioning, remain].concat()
+    }
+}
+
+/// Why the generation of a middle stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FimStop {
+    /// A stop token was sampled.
+    Token(u16),
+    /// The middle started to reproduce the suffix.
+    Suffix,
+    /// The token limit was reached.
+    Length,
+}
+
+/// The generated middle of a fill-in-the-middle completion.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FimOutput {
+    /// Generated tokens, excluding the stop token and any reproduced suffix.
+    pub tokens: Vec<u16>,
+    pub stop: FimStop,
+}
+
+/// Fill-in-the-middle completion on one batch of a runtime.
+///
+/// The session keeps the state after the conditioning part of the last prompt (see [`FimTokens::split`]),
+/// so that consecutive completions sharing it, e.g., the same suffix with [`FimLayout::Spm`], skip re-reading it.
+pub struct FimSession {
+    runtime: JobRuntime<InferInput, InferOutput>,
+    state: Box<dyn State + Send + Sync>,
+    batch: usize,
+    token_chunk_size: usize,
+    pub tokens: FimTokens,
+    pub layout: FimLayout,
+    /// Stop once the middle ends with this many leading tokens of the suffix. `0` disables the check.
+    pub suffix_overlap: usize,
+    cache: Option<(Vec<u16>, TensorCpu<f32>)>,
+}
+
+impl FimSession {
+    pub fn new(
+        runtime: JobRuntime<InferInput, InferOutput>,
+        state: impl State + Send + Sync + 'static,
+        batch: usize,
+        tokens: FimTokens,
+    ) -> Self {
+        let token_chunk_size = runtime.tuning().token_chunk_size;
+        Self {
+            runtime,
+            state: Box::new(state),
+            batch,
+            token_chunk_size,
+            tokens,
+            layout: Default::default(),
+            suffix_overlap: 0,
+            cache: None,
+        }
+    }
+
+    pub fn layout(mut self, value: FimLayout) -> Self {
+        self.layout = value;
+        self
+    }
+
+    pub fn suffix_overlap(mut self, value: usize) -> Self {
+        self.suffix_overlap = value;
+        self
+    }
+
+    /// Override the number of tokens fed at each step, by default the one of the runtime's [tuning profile](JobRuntime::tuning).
+    pub fn token_chunk_size(mut self, value: usize) -> Self {
+        self.token_chunk_size = value;
+        self
+    }
+
+    /// Drop the cached conditioning state.
+    pub fn clear(&mut self) {
+        self.cache = None;
+    }
+
+    /// Feed `tokens` into the session's batch and return the logits of the last one.
+    async fn read(&self, tokens: Vec<u16>) -> TensorCpu<f32> {
+        let mut batches = vec![InferInputBatch::default(); self.state.num_batch()];
+        batches[self.batch] = InferInputBatch {
+            tokens: tokens.into(),
+            option: InferOption::Last,
+            ..Default::default()
+        };
+        let mut input = InferInput::new(batches, self.token_chunk_size);
+        loop {
+            let (next, InferOutput(mut output)) = self.runtime.infer(input).await;
+            input = next;
+            let output = output.swap_remove(self.batch).0;
+            if output.size() > 0 || input.num_token() == 0 {
+                return output;
+            }
+        }
+    }
+
+    /// Generate the middle between `prefix` and `suffix`.
+    /// The sampler receives the logits of each step and returns the next token.
+    pub async fn complete(
+        &mut self,
+        prefix: &[u16],
+        suffix: &[u16],
+        max_token: usize,
+        mut sample: impl FnMut(&[f32]) -> u16,
+    ) -> Result<FimOutput> {
+        let (conditioning, remain) = self.tokens.split(self.layout, prefix, suffix);
+        match &self.cache {
+            Some((tokens, backed)) if tokens == &conditioning => {
+                self.state.load(backed.clone(), self.batch)?;
+            }
+            _ => {
+                self.state.load(self.state.init(), self.batch)?;
+                self.read(conditioning.clone()).await;
+                let backed = self.state.back(self.batch).await?;
+                self.cache = Some((conditioning, backed));
+            }
+        }
+
+        let overlap = &suffix[..self.suffix_overlap.min(suffix.len())];
+        let mut tokens = vec![];
+        let mut logits = self.read(remain).await;
+        let stop = loop {
+            if tokens.len() >= max_token {
+                break FimStop::Length;
+            }
+            let token = sample(&logits);
+            if self.tokens.stop.contains(&token) {
+                break FimStop::Token(token);
+            }
+            tokens.push(token);
+            if !overlap.is_empty() && tokens.ends_with(overlap) {
+                tokens.truncate(tokens.len() - overlap.len());
+                break FimStop::Suffix;
+            }
+            logits = self.read(vec![token]).await;
+        };
+        Ok(FimOutput { tokens, stop })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::{FimLayout, FimSession, FimStop, FimTokens};
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelRuntime, ModelVersion},
+            tiny::TinyModel,
+            v5, JobRuntime,
+        },
+    };
+
+    fn tokens() -> FimTokens {
+        FimTokens {
+            prefix: 1,
+            suffix: 2,
+            middle: 3,
+            stop: vec![0, 4],
+        }
+    }
+
+    fn argmax(logits: &[f32]) -> u16 {
+        logits
+            .iter()
+            .enumerate()
+            .max_by(|(_, x), (_, y)| x.total_cmp(y))
+            .map(|(index, _)| index as u16)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_fim_layout() {
+        let tokens = tokens();
+        let (prefix, suffix) = ([10, 11], [20]);
+
+        let (conditioning, remain) = tokens.split(FimLayout::Psm, &prefix, &suffix);
+        assert_eq!(conditioning, vec![1, 10, 11]);
+        assert_eq!(remain, vec![2, 20, 3]);
+
+        let (conditioning, remain) = tokens.split(FimLayout::Spm, &prefix, &suffix);
+        assert_eq!(conditioning, vec![2, 20]);
+        assert_eq!(remain, vec![1, 10, 11, 3]);
+
+        let prompt = tokens.prompt(FimLayout::Spm, &prefix, &suffix);
+        assert_eq!(prompt, vec![2, 20, 1, 10, 11, 3]);
+    }
+
+    #[test]
+    fn test_fim_session() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let model = TinyModel::new(TinyModel::info(ModelVersion::V5), 42);
+            let info = model.model_info().clone();
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            let model = Build::<v5::Model>::build(ModelBuilder::new(&context, model)).await?;
+            let bundle = v5::ModelRuntime::<f32>::new(model, 2);
+            let state = bundle.state();
+            let runtime = JobRuntime::new(bundle).await;
+            assert_eq!(runtime.tuning(), context.tuning());
+
+            let suffix = (20..60).collect_vec();
+            let mut session = FimSession::new(runtime, state, 1, tokens()).layout(FimLayout::Spm);
+
+            // the second completion reuses the cached suffix state and must produce the same middle
+            let first = session.complete(&[10, 11], &suffix, 8, argmax).await?;
+            let second = session.complete(&[10, 11], &suffix, 8, argmax).await?;
+            assert_eq!(first, second);
+            session.clear();
+            let third = session.complete(&[10, 11], &suffix, 8, argmax).await?;
+            assert_eq!(first, third);
+
+            // a sampler that copies the suffix is stopped once it reproduces the overlap
+            let mut session = session.suffix_overlap(2);
+            let mut copy = suffix.clone().into_iter();
+            let output = session
+                .complete(&[10], &suffix, 8, |_| copy.next().unwrap_or(0))
+                .await?;
+            assert_eq!(output.stop, FimStop::Suffix);
+            assert!(output.tokens.is_empty());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,1106 @@
+//! Reading models in the GGUF format, as converted by llama.cpp.
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    sync::Mutex,
+};
+
+use half::{bf16, f16};
+use itertools::Itertools;
+use safetensors::{Dtype, SafeTensorError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::loader::{ReaderKQuant, ReaderSend, ReaderTensor};
+use crate::tensor::ops::KQuantFormat;
+
+#[derive(Debug, Error)]
+pub enum GgufError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a gguf file")]
+    Magic,
+    #[error("unsupported gguf version {0}")]
+    Version(u32),
+    #[error("invalid metadata value type {0}")]
+    ValueType(u32),
+    #[error("invalid or truncated string")]
+    String,
+    #[error("tensor {0} has unsupported type {1}")]
+    TensorType(String, u32),
+    #[error("tensor {0} has an invalid shape or lies out of the file")]
+    TensorInfo(String),
+}
+
+/// A metadata value of a GGUF file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    /// The value as an unsigned integer, if it is one.
+    pub fn to_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(x) => Some(x as u64),
+            GgufValue::U16(x) => Some(x as u64),
+            GgufValue::U32(x) => Some(x as u64),
+            GgufValue::U64(x) => Some(x),
+            GgufValue::I8(x) => x.try_into().ok(),
+            GgufValue::I16(x) => x.try_into().ok(),
+            GgufValue::I32(x) => x.try_into().ok(),
+            GgufValue::I64(x) => x.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Types of GGUF tensors that can be read.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GgmlType {
+    F32,
+    F16,
+    BF16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q4_K,
+    Q5_K,
+    Q6_K,
+}
+
+impl GgmlType {
+    fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Self::F32),
+            1 => Some(Self::F16),
+            2 => Some(Self::Q4_0),
+            3 => Some(Self::Q4_1),
+            6 => Some(Self::Q5_0),
+            7 => Some(Self::Q5_1),
+            8 => Some(Self::Q8_0),
+            12 => Some(Self::Q4_K),
+            13 => Some(Self::Q5_K),
+            14 => Some(Self::Q6_K),
+            30 => Some(Self::BF16),
+            _ => None,
+        }
+    }
+
+    /// Number of elements and bytes of a block.
+    pub fn block(&self) -> (usize, usize) {
+        match self {
+            GgmlType::F32 => (1, 4),
+            GgmlType::F16 | GgmlType::BF16 => (1, 2),
+            GgmlType::Q4_0 => (32, 18),
+            GgmlType::Q4_1 => (32, 20),
+            GgmlType::Q5_0 => (32, 22),
+            GgmlType::Q5_1 => (32, 24),
+            GgmlType::Q8_0 => (32, 34),
+            GgmlType::Q4_K => (256, 144),
+            GgmlType::Q5_K => (256, 176),
+            GgmlType::Q6_K => (256, 210),
+        }
+    }
+
+    /// Size in bytes of `len` elements, if they are whole blocks.
+    pub fn size(&self, len: usize) -> Option<usize> {
+        let (elements, bytes) = self.block();
+        (len.next_multiple_of(elements) == len).then_some(len / elements * bytes)
+    }
+
+    /// Decode whole blocks into `f32`.
+    pub fn dequantize(&self, data: &[u8]) -> Vec<f32> {
+        let (elements, bytes) = self.block();
+        let half = |x: &[u8]| f16::from_le_bytes([x[0], x[1]]).to_f32();
+        let mut output = Vec::with_capacity(data.len() / bytes * elements);
+        for b in data.chunks_exact(bytes) {
+            match self {
+                GgmlType::F32 => output.push(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                GgmlType::F16 => output.push(half(b)),
+                GgmlType::BF16 => output.push(bf16::from_le_bytes([b[0], b[1]]).to_f32()),
+                GgmlType::Q4_0 | GgmlType::Q4_1 | GgmlType::Q5_0 | GgmlType::Q5_1 => {
+                    let d = half(b);
+                    let (m, qh, qs) = match self {
+                        GgmlType::Q4_0 => (-8.0 * d, 0, &b[2..]),
+                        GgmlType::Q4_1 => (half(&b[2..]), 0, &b[4..]),
+                        GgmlType::Q5_0 => (-16.0 * d, read_u32(&b[2..]), &b[6..]),
+                        _ => (half(&b[2..]), read_u32(&b[4..]), &b[8..]),
+                    };
+                    for (half, shift) in [(0, 0), (16, 4)] {
+                        for (j, q) in qs[..16].iter().enumerate() {
+                            let low = (q >> shift) & 0xf;
+                            let high = ((qh >> (half + j)) & 1) as u8;
+                            output.push(d * (low | (high << 4)) as f32 + m);
+                        }
+                    }
+                }
+                GgmlType::Q8_0 => {
+                    let d = half(b);
+                    output.extend(b[2..].iter().map(|&q| d * q as i8 as f32));
+                }
+                GgmlType::Q4_K | GgmlType::Q5_K => {
+                    let block = KQuantBlock::new(b, *self == GgmlType::Q5_K);
+                    output.extend((0..256).map(|e| block.value(e)));
+                }
+                GgmlType::Q6_K => {
+                    let (ql, qh, sc) = (&b[..128], &b[128..192], &b[192..208]);
+                    let d = half(&b[208..]);
+                    for e in 0..256 {
+                        // each half of 128 elements is 4 rows of 32, packed in 64 low and 32 high bytes
+                        let (n, row, l) = (e / 128, (e % 128) / 32, e % 32);
+                        let low = (ql[64 * n + 32 * (row & 1) + l] >> (4 * (row >> 1))) & 0xf;
+                        let high = (qh[32 * n + l] >> (2 * row)) & 3;
+                        let q = (low | (high << 4)) as i32 - 32;
+                        let scale = sc[8 * n + 2 * row + l / 16] as i8 as f32;
+                        output.push(d * scale * q as f32);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+fn read_u32(x: &[u8]) -> u32 {
+    u32::from_le_bytes([x[0], x[1], x[2], x[3]])
+}
+
+/// A super-block of 256 elements of `Q4_K` or `Q5_K`.
+struct KQuantBlock<'a> {
+    d: f16,
+    dmin: f16,
+    scales: &'a [u8],
+    qh: Option<&'a [u8]>,
+    qs: &'a [u8],
+}
+
+impl<'a> KQuantBlock<'a> {
+    fn new(b: &'a [u8], q5: bool) -> Self {
+        let (qh, qs) = match q5 {
+            true => (Some(&b[16..48]), &b[48..176]),
+            false => (None, &b[16..144]),
+        };
+        Self {
+            d: f16::from_le_bytes([b[0], b[1]]),
+            dmin: f16::from_le_bytes([b[2], b[3]]),
+            scales: &b[4..16],
+            qh,
+            qs,
+        }
+    }
+
+    /// The 6-bit scale and minimum of the block `j` of 32 elements.
+    fn scale_min(&self, j: usize) -> (u8, u8) {
+        let q = self.scales;
+        match j {
+            0..4 => (q[j] & 63, q[j + 4] & 63),
+            _ => (
+                (q[j + 4] & 0xf) | ((q[j - 4] >> 6) << 4),
+                (q[j + 4] >> 4) | ((q[j] >> 6) << 4),
+            ),
+        }
+    }
+
+    /// The low 4 bits and the high bit of element `e`.
+    fn quant(&self, e: usize) -> (u8, u8) {
+        // each 64 elements take 32 bytes, the first 32 in the low nibbles
+        let (chunk, l) = (e / 64, e % 64);
+        let shift = l / 32;
+        let low = (self.qs[32 * chunk + l % 32] >> (4 * shift)) & 0xf;
+        let high = match self.qh {
+            Some(qh) => (qh[l % 32] >> (2 * chunk + shift)) & 1,
+            None => 0,
+        };
+        (low, high)
+    }
+
+    fn value(&self, e: usize) -> f32 {
+        let (sc, m) = self.scale_min(e / 32);
+        let (low, high) = self.quant(e);
+        let q = (low | (high << 4)) as f32;
+        self.d.to_f32() * sc as f32 * q - self.dmin.to_f32() * m as f32
+    }
+}
+
+/// Where the data of a tensor lies in the file.
+#[derive(Debug, Clone)]
+struct GgufTensor {
+    ty: GgmlType,
+    /// Shape exposed to the loader, outermost dimension first.
+    shape: Vec<usize>,
+    /// Absolute position of the data in the file.
+    offset: u64,
+    /// Factor to multiply the values by, undoing the rescaling of llama.cpp.
+    scale: f32,
+}
+
+impl GgufTensor {
+    fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+}
+
+/// A GGUF file read on demand from a [`Read`] + [`Seek`] stream.
+///
+/// Tensors of RWKV models converted by llama.cpp are renamed back to the names in the checkpoint,
+/// and the weights llama.cpp rescales are restored, so that the model loads as if it were a safetensors file.
+/// Tensors are given in `f16`, dequantized if they are stored quantized;
+/// tensors in `Q4_K` or `Q5_K` are also available as they are through [`Reader::kquant`](super::loader::Reader::kquant).
+#[derive(Debug)]
+pub struct GgufReader<R> {
+    stream: Mutex<R>,
+    metadata: HashMap<String, GgufValue>,
+    names: Vec<String>,
+    tensors: HashMap<String, GgufTensor>,
+}
+
+/// Parses the header of a GGUF stream, never allocating more than what remains in the stream.
+struct Header<'a, R> {
+    stream: &'a mut R,
+    remain: u64,
+}
+
+impl<R: Read> Header<'_, R> {
+    fn bytes(&mut self, len: u64) -> Result<Vec<u8>, GgufError> {
+        if len > self.remain {
+            return Err(GgufError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let mut data = vec![0; len as usize];
+        self.stream.read_exact(&mut data)?;
+        self.remain -= len;
+        Ok(data)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], GgufError> {
+        let data = self.bytes(N as u64)?;
+        Ok(data.try_into().expect("read exactly N bytes"))
+    }
+
+    fn u32(&mut self) -> Result<u32, GgufError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, GgufError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String, GgufError> {
+        let len = self.u64()?;
+        if len > self.remain {
+            return Err(GgufError::String);
+        }
+        String::from_utf8(self.bytes(len)?).map_err(|_| GgufError::String)
+    }
+
+    fn value(&mut self, ty: u32) -> Result<GgufValue, GgufError> {
+        let value = match ty {
+            0 => GgufValue::U8(u8::from_le_bytes(self.array()?)),
+            1 => GgufValue::I8(i8::from_le_bytes(self.array()?)),
+            2 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => GgufValue::U32(u32::from_le_bytes(self.array()?)),
+            5 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => GgufValue::Bool(u8::from_le_bytes(self.array()?) != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                let ty = self.u32()?;
+                let len = self.u64()?;
+                // every value takes at least a byte
+                if len > self.remain {
+                    return Err(GgufError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                let values = (0..len).map(|_| self.value(ty)).try_collect()?;
+                GgufValue::Array(values)
+            }
+            10 => GgufValue::U64(u64::from_le_bytes(self.array()?)),
+            11 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+            ty => return Err(GgufError::ValueType(ty)),
+        };
+        Ok(value)
+    }
+}
+
+impl<R: Read + Seek> GgufReader<R> {
+    const MAGIC: u32 = u32::from_le_bytes(*b"GGUF");
+    const DEFAULT_ALIGNMENT: u64 = 32;
+
+    /// Read and check the header of the stream.
+    pub fn new(mut stream: R) -> Result<Self, GgufError> {
+        let end = stream.seek(SeekFrom::End(0))?;
+        stream.seek(SeekFrom::Start(0))?;
+        let mut header = Header {
+            stream: &mut stream,
+            remain: end,
+        };
+
+        if header.u32().map_err(|_| GgufError::Magic)? != Self::MAGIC {
+            return Err(GgufError::Magic);
+        }
+        let version = header.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(GgufError::Version(version));
+        }
+        let num_tensor = header.u64()?;
+        let num_metadata = header.u64()?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..num_metadata {
+            let key = header.string()?;
+            let ty = header.u32()?;
+            let value = header.value(ty)?;
+            metadata.insert(key, value);
+        }
+
+        let mut infos = vec![];
+        for _ in 0..num_tensor {
+            let name = header.string()?;
+            let num_dim = header.u32()?;
+            let mut shape: Vec<usize> = (0..num_dim)
+                .map(|_| header.u64().map(|x| x as usize))
+                .try_collect()?;
+            // gguf lists the innermost dimension first
+            shape.reverse();
+            let id = header.u32()?;
+            let offset = header.u64()?;
+            let Some(ty) = GgmlType::from_id(id) else {
+                return Err(GgufError::TensorType(name, id));
+            };
+            infos.push((name, ty, shape, offset));
+        }
+
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(GgufValue::to_u64)
+            .filter(|&x| x > 0)
+            .unwrap_or(Self::DEFAULT_ALIGNMENT);
+        let start = end - header.remain;
+        let start = start.div_ceil(alignment) * alignment;
+
+        let rescale = metadata
+            .get("general.architecture")
+            .and_then(GgufValue::as_str)
+            .and_then(|arch| metadata.get(&format!("{arch}.rescale_every_n_layers")))
+            .and_then(GgufValue::to_u64)
+            .unwrap_or(0) as usize;
+
+        let mut names = vec![];
+        let mut tensors = HashMap::new();
+        for (name, ty, shape, offset) in infos.into_iter().sorted_by_key(|info| info.3) {
+            let len = shape.iter().product::<usize>();
+            let size = ty
+                .size(len)
+                .ok_or_else(|| GgufError::TensorInfo(name.clone()))?;
+            let offset = start + offset;
+            if offset + size as u64 > end {
+                return Err(GgufError::TensorInfo(name));
+            }
+
+            for (part, (target, scale)) in map_name(&name, rescale).into_iter().enumerate() {
+                let tensor = match shape.first() {
+                    // the lerp factors are fused along the outermost dimension
+                    Some(&count) if target.fused => {
+                        let shape = shape[1..].iter().copied().skip_while(|&x| x == 1);
+                        let shape = shape.collect_vec();
+                        let size = ty
+                            .size(len / count)
+                            .filter(|_| count == FUSED_LERP.len())
+                            .ok_or_else(|| GgufError::TensorInfo(name.clone()))?;
+                        GgufTensor {
+                            ty,
+                            shape,
+                            offset: offset + (part * size) as u64,
+                            scale,
+                        }
+                    }
+                    _ => GgufTensor {
+                        ty,
+                        shape: shape.clone(),
+                        offset,
+                        scale,
+                    },
+                };
+                names.push(target.name.clone());
+                tensors.insert(target.name, tensor);
+            }
+        }
+
+        Ok(Self {
+            stream: Mutex::new(stream),
+            metadata,
+            names,
+            tensors,
+        })
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, GgufValue> {
+        &self.metadata
+    }
+
+    /// The type a tensor is stored in.
+    pub fn ggml_type(&self, name: &str) -> Option<GgmlType> {
+        self.tensors.get(name).map(|tensor| tensor.ty)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.stream
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn info(&self, name: &str) -> Result<&GgufTensor, SafeTensorError> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| SafeTensorError::TensorNotFound(name.to_string()))
+    }
+
+    fn read(&self, tensor: &GgufTensor) -> Result<Vec<u8>, SafeTensorError> {
+        let size = tensor.ty.size(tensor.len()).unwrap_or_default();
+        let mut data = vec![0; size];
+        let mut stream = self.stream.lock().unwrap_or_else(|err| err.into_inner());
+        stream.seek(SeekFrom::Start(tensor.offset))?;
+        stream.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek + Send> ReaderSend for GgufReader<R> {
+    #[inline]
+    fn names(&self) -> Vec<&str> {
+        self.names.iter().map(AsRef::as_ref).collect()
+    }
+
+    #[inline]
+    fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    #[inline]
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        Ok(self.info(name)?.shape.clone())
+    }
+
+    async fn tensor(&self, name: &str) -> Result<ReaderTensor<'_>, SafeTensorError> {
+        let tensor = self.info(name)?;
+        let data = self.read(tensor)?;
+        let data = match (tensor.ty, tensor.scale) {
+            (GgmlType::F16, 1.0) => data,
+            (ty, scale) => ty
+                .dequantize(&data)
+                .into_iter()
+                .flat_map(|x| f16::from_f32(x * scale).to_le_bytes())
+                .collect(),
+        };
+        Ok((Dtype::F16, tensor.shape.clone(), Cow::Owned(data)))
+    }
+
+    async fn kquant(&self, name: &str) -> Result<Option<ReaderKQuant>, SafeTensorError> {
+        let tensor = self.info(name)?;
+        let format = match tensor.ty {
+            GgmlType::Q4_K => KQuantFormat::Q4K,
+            GgmlType::Q5_K => KQuantFormat::Q5K,
+            _ => return Ok(None),
+        };
+        let data = self.read(tensor)?;
+        Ok(Some(convert_kquant(
+            &data,
+            tensor.shape.clone(),
+            format,
+            tensor.scale,
+        )))
+    }
+}
+
+/// Re-pack `Q4_K` or `Q5_K` blocks into the layout of [`Matrix::Q4K`](crate::tensor::matrix::Matrix::Q4K)
+/// and [`Matrix::Q5K`](crate::tensor::matrix::Matrix::Q5K), in which the elements are in order.
+fn convert_kquant(
+    data: &[u8],
+    shape: Vec<usize>,
+    format: KQuantFormat,
+    scale: f32,
+) -> ReaderKQuant {
+    let q5 = format == KQuantFormat::Q5K;
+    let (_, bytes) = match q5 {
+        true => GgmlType::Q5_K.block(),
+        false => GgmlType::Q4_K.block(),
+    };
+    let len = data.len() / bytes * 256;
+
+    let mut w = vec![0u8; len / 2];
+    let mut h = vec![0u8; if q5 { len / 8 } else { 0 }];
+    let mut s = Vec::with_capacity(len / 16);
+    let mut m = Vec::with_capacity(len / 128);
+    for (index, b) in data.chunks_exact(bytes).enumerate() {
+        let block = KQuantBlock::new(b, q5);
+        m.push(f16::from_f32(block.d.to_f32() * scale));
+        m.push(f16::from_f32(block.dmin.to_f32() * scale));
+        for j in 0..8 {
+            let (sc, min) = block.scale_min(j);
+            s.extend([sc, min]);
+        }
+        for e in 0..256 {
+            let g = index * 256 + e;
+            let (low, high) = block.quant(e);
+            w[g / 2] |= low << (4 * (g % 2));
+            if q5 {
+                h[g / 8] |= high << (g % 8);
+            }
+        }
+    }
+    ReaderKQuant {
+        format,
+        shape,
+        w,
+        h,
+        s,
+        m,
+    }
+}
+
+/// Names of the lerp factors fused by llama.cpp, in order.
+const FUSED_LERP: [&str; 5] = ["w", "k", "v", "r", "g"];
+
+#[derive(Debug, Clone)]
+struct MappedName {
+    name: String,
+    /// If this is a part of a tensor fused by llama.cpp.
+    fused: bool,
+}
+
+/// Map a tensor name in llama.cpp's RWKV GGUF files to the name(s) in the checkpoint,
+/// together with the factor to undo rescaling with. Other names are kept.
+fn map_name(name: &str, rescale: usize) -> Vec<(MappedName, f32)> {
+    let mapped = |name: String| MappedName { name, fused: false };
+    let global = match name {
+        "token_embd.weight" => Some("emb.weight"),
+        "token_embd_norm.weight" => Some("blocks.0.ln0.weight"),
+        "token_embd_norm.bias" => Some("blocks.0.ln0.bias"),
+        "output_norm.weight" => Some("ln_out.weight"),
+        "output_norm.bias" => Some("ln_out.bias"),
+        "output.weight" => Some("head.weight"),
+        _ => None,
+    };
+    if let Some(name) = global {
+        return vec![(mapped(name.into()), 1.0)];
+    }
+
+    let Some((layer, suffix)) = name
+        .strip_prefix("blk.")
+        .and_then(|name| name.split_once('.'))
+        .and_then(|(layer, suffix)| Some((layer.parse::<usize>().ok()?, suffix)))
+    else {
+        return vec![(mapped(name.into()), 1.0)];
+    };
+
+    let block = format!("blocks.{layer}");
+    if suffix == "time_mix_lerp_fused.weight" {
+        return FUSED_LERP
+            .iter()
+            .map(|x| {
+                let name = format!("{block}.att.time_mix_{x}");
+                (MappedName { name, fused: true }, 1.0)
+            })
+            .collect();
+    }
+
+    let target = match suffix {
+        "attn_norm.weight" => "ln1.weight".into(),
+        "attn_norm.bias" => "ln1.bias".into(),
+        "attn_norm_2.weight" => "ln2.weight".into(),
+        "attn_norm_2.bias" => "ln2.bias".into(),
+        "time_mix_w1.weight" => "att.time_mix_w1".into(),
+        "time_mix_w2.weight" => "att.time_mix_w2".into(),
+        "time_mix_first.weight" => "att.time_first".into(),
+        "time_mix_decay.weight" => "att.time_decay".into(),
+        "time_mix_decay_w1.weight" => "att.time_decay_w1".into(),
+        "time_mix_decay_w2.weight" => "att.time_decay_w2".into(),
+        "time_mix_key.weight" => "att.key.weight".into(),
+        "time_mix_value.weight" => "att.value.weight".into(),
+        "time_mix_receptance.weight" => "att.receptance.weight".into(),
+        "time_mix_gate.weight" => "att.gate.weight".into(),
+        "time_mix_output.weight" => "att.output.weight".into(),
+        "time_mix_ln.weight" => "att.ln_x.weight".into(),
+        "time_mix_ln.bias" => "att.ln_x.bias".into(),
+        "channel_mix_lerp_k.weight" => "ffn.time_mix_k".into(),
+        "channel_mix_lerp_r.weight" => "ffn.time_mix_r".into(),
+        "channel_mix_key.weight" => "ffn.key.weight".into(),
+        "channel_mix_receptance.weight" => "ffn.receptance.weight".into(),
+        "channel_mix_value.weight" => "ffn.value.weight".into(),
+        suffix => match suffix
+            .strip_prefix("time_mix_lerp_")
+            .and_then(|x| x.strip_suffix(".weight"))
+        {
+            Some(x) => format!("att.time_mix_{x}"),
+            None => return vec![(mapped(name.into()), 1.0)],
+        },
+    };
+
+    let scale = match suffix {
+        "time_mix_output.weight" | "channel_mix_value.weight" if rescale > 0 => {
+            2.0_f32.powi((layer / rescale) as i32)
+        }
+        _ => 1.0,
+    };
+    vec![(mapped(format!("{block}.{target}")), scale)]
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+    use half::f16;
+    use itertools::Itertools;
+
+    use super::{GgmlType, GgufReader, GgufValue, FUSED_LERP};
+    use crate::{
+        runtime::{
+            loader::{Loader, Reader},
+            model::{ModelVersion, Quant},
+            tiny::{
+                tests::{create_context, infer_gpu, prompts},
+                TinyModel,
+            },
+        },
+        tensor::{matrix::Matrix, ops::Activation, TensorCpu, TensorGpu},
+    };
+
+    /// Serialize tensors of `(name, type, shape, data)` into a GGUF file.
+    pub fn write_gguf(
+        metadata: &[(&str, GgufValue)],
+        tensors: &[(String, GgmlType, Vec<usize>, Vec<u8>)],
+    ) -> Vec<u8> {
+        fn string(output: &mut Vec<u8>, x: &str) {
+            output.extend((x.len() as u64).to_le_bytes());
+            output.extend(x.as_bytes());
+        }
+        fn value(output: &mut Vec<u8>, x: &GgufValue) {
+            match x {
+                GgufValue::U32(x) => output.extend(x.to_le_bytes()),
+                GgufValue::String(x) => string(output, x),
+                _ => unimplemented!(),
+            }
+        }
+        let id = |ty: GgmlType| match ty {
+            GgmlType::F32 => 0u32,
+            GgmlType::F16 => 1,
+            GgmlType::Q8_0 => 8,
+            GgmlType::Q4_K => 12,
+            GgmlType::Q5_K => 13,
+            _ => unimplemented!(),
+        };
+
+        let mut output = vec![];
+        output.extend(b"GGUF");
+        output.extend(3u32.to_le_bytes());
+        output.extend((tensors.len() as u64).to_le_bytes());
+        output.extend((metadata.len() as u64).to_le_bytes());
+        for (key, x) in metadata {
+            string(&mut output, key);
+            let ty: u32 = match x {
+                GgufValue::U32(_) => 4,
+                GgufValue::String(_) => 8,
+                _ => unimplemented!(),
+            };
+            output.extend(ty.to_le_bytes());
+            value(&mut output, x);
+        }
+
+        let mut offset = 0;
+        for (name, ty, shape, data) in tensors {
+            string(&mut output, name);
+            output.extend((shape.len() as u32).to_le_bytes());
+            for &dim in shape.iter().rev() {
+                output.extend((dim as u64).to_le_bytes());
+            }
+            output.extend(id(*ty).to_le_bytes());
+            output.extend((offset as u64).to_le_bytes());
+            offset += data.len().div_ceil(32) * 32;
+        }
+        output.resize(output.len().div_ceil(32) * 32, 0);
+        for (_, _, _, data) in tensors {
+            output.extend(data);
+            output.resize(output.len().div_ceil(32) * 32, 0);
+        }
+        output
+    }
+
+    /// Quantize whole super-blocks into `Q4_K` or `Q5_K`, the way llama.cpp lays them out.
+    pub fn quantize_kquant(data: &[f32], q5: bool) -> Vec<u8> {
+        let max = if q5 { 31.0 } else { 15.0 };
+        let mut output = vec![];
+        for block in data.chunks_exact(256) {
+            let ranges = block
+                .chunks_exact(32)
+                .map(|x| {
+                    let lo = x.iter().fold(0.0f32, |acc, &x| acc.min(x));
+                    let hi = x.iter().fold(0.0f32, |acc, &x| acc.max(x));
+                    ((hi - lo) / max, -lo)
+                })
+                .collect_vec();
+            let d = f16::from_f32(ranges.iter().fold(0.0f32, |acc, x| acc.max(x.0)) / 63.0);
+            let dmin = f16::from_f32(ranges.iter().fold(0.0f32, |acc, x| acc.max(x.1)) / 63.0);
+            let q6 = |x: f32, d: f16| match d.to_f32() {
+                0.0 => 0,
+                d => (x / d).round().clamp(0.0, 63.0) as u8,
+            };
+            let (sc, mn): (Vec<_>, Vec<_>) = ranges
+                .iter()
+                .map(|&(scale, min)| (q6(scale, d), q6(min, dmin)))
+                .unzip();
+
+            output.extend(d.to_le_bytes());
+            output.extend(dmin.to_le_bytes());
+            let mut scales = [0u8; 12];
+            for j in 0..8 {
+                if j < 4 {
+                    scales[j] = sc[j];
+                    scales[j + 4] = mn[j];
+                } else {
+                    scales[j + 4] = (sc[j] & 0xf) | ((mn[j] & 0xf) << 4);
+                    scales[j - 4] |= (sc[j] >> 4) << 6;
+                    scales[j] |= (mn[j] >> 4) << 6;
+                }
+            }
+            output.extend(scales);
+
+            let q = block
+                .iter()
+                .enumerate()
+                .map(|(e, &x)| {
+                    let scale = d.to_f32() * sc[e / 32] as f32;
+                    let min = dmin.to_f32() * mn[e / 32] as f32;
+                    match scale {
+                        0.0 => 0,
+                        _ => ((x + min) / scale).round().clamp(0.0, max) as u8,
+                    }
+                })
+                .collect_vec();
+            let mut qh = [0u8; 32];
+            let mut qs = [0u8; 128];
+            for (e, &q) in q.iter().enumerate() {
+                let (chunk, l) = (e / 64, e % 64);
+                qs[32 * chunk + l % 32] |= (q & 0xf) << (4 * (l / 32));
+                qh[l % 32] |= (q >> 4) << (2 * chunk + l / 32);
+            }
+            if q5 {
+                output.extend(qh);
+            }
+            output.extend(qs);
+        }
+        output
+    }
+
+    /// Convert a tiny model into a GGUF file with llama.cpp's names and layout:
+    /// 1-dimensional tensors in `f32`, lerp factors fused, and weights rescaled every `rescale` layers.
+    /// Matrices that `quant` picks a type for are quantized.
+    pub fn convert_gguf(
+        model: &TinyModel,
+        rescale: usize,
+        quant: impl Fn(&str) -> Option<GgmlType>,
+    ) -> Vec<u8> {
+        let rename = |name: &str| -> String {
+            let global = [
+                ("emb.weight", "token_embd.weight"),
+                ("blocks.0.ln0.weight", "token_embd_norm.weight"),
+                ("blocks.0.ln0.bias", "token_embd_norm.bias"),
+                ("ln_out.weight", "output_norm.weight"),
+                ("ln_out.bias", "output_norm.bias"),
+                ("head.weight", "output.weight"),
+            ];
+            if let Some((_, x)) = global.iter().find(|(x, _)| *x == name) {
+                return x.to_string();
+            }
+            let (layer, suffix) = name
+                .strip_prefix("blocks.")
+                .and_then(|x| x.split_once('.'))
+                .unwrap();
+            let suffix = match suffix {
+                "ln1.weight" => "attn_norm.weight".into(),
+                "ln1.bias" => "attn_norm.bias".into(),
+                "ln2.weight" => "attn_norm_2.weight".into(),
+                "ln2.bias" => "attn_norm_2.bias".into(),
+                "att.time_first" => "time_mix_first.weight".into(),
+                "att.time_decay" => "time_mix_decay.weight".into(),
+                "att.ln_x.weight" => "time_mix_ln.weight".into(),
+                "att.ln_x.bias" => "time_mix_ln.bias".into(),
+                "ffn.time_mix_k" => "channel_mix_lerp_k.weight".into(),
+                "ffn.time_mix_r" => "channel_mix_lerp_r.weight".into(),
+                "att.time_mix_x" => "time_mix_lerp_x.weight".into(),
+                "att.time_mix_w1" => "time_mix_w1.weight".into(),
+                "att.time_mix_w2" => "time_mix_w2.weight".into(),
+                "att.time_decay_w1" => "time_mix_decay_w1.weight".into(),
+                "att.time_decay_w2" => "time_mix_decay_w2.weight".into(),
+                suffix => {
+                    let (module, rest) = suffix.split_once('.').unwrap();
+                    let module = match module {
+                        "att" => "time_mix",
+                        _ => "channel_mix",
+                    };
+                    format!("{module}_{rest}")
+                }
+            };
+            format!("blk.{layer}.{suffix}")
+        };
+
+        let data = |name: &str| {
+            let (shape, data) = model.data(name).unwrap();
+            (
+                shape.to_vec(),
+                data.iter().map(|x| x.to_f32()).collect_vec(),
+            )
+        };
+        let encode = |ty: GgmlType, data: &[f32]| -> Vec<u8> {
+            match ty {
+                GgmlType::F32 => data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+                GgmlType::F16 => data
+                    .iter()
+                    .flat_map(|&x| f16::from_f32(x).to_le_bytes())
+                    .collect(),
+                GgmlType::Q4_K => quantize_kquant(data, false),
+                GgmlType::Q5_K => quantize_kquant(data, true),
+                GgmlType::Q8_0 => data
+                    .chunks_exact(32)
+                    .flat_map(|x| {
+                        let amax = x.iter().fold(0.0f32, |acc, x| acc.max(x.abs()));
+                        let d = f16::from_f32(amax / 127.0);
+                        let q = x.iter().map(|&x| match d.to_f32() {
+                            0.0 => 0u8,
+                            d => (x / d).round() as i8 as u8,
+                        });
+                        d.to_le_bytes().into_iter().chain(q).collect_vec()
+                    })
+                    .collect(),
+                _ => unimplemented!(),
+            }
+        };
+
+        let mut tensors = vec![];
+        let names = Reader::names(model)
+            .into_iter()
+            .map(String::from)
+            .sorted()
+            .collect_vec();
+        for name in &names {
+            let (shape, mut data) = data(name);
+            let Some((prefix, x)) = name.rsplit_once(".att.time_mix_") else {
+                let target = rename(name);
+                if rescale > 0
+                    && (name.ends_with("att.output.weight") || name.ends_with("ffn.value.weight"))
+                {
+                    let layer: usize = name.split('.').nth(1).unwrap().parse().unwrap();
+                    let scale = 2.0_f32.powi(-((layer / rescale) as i32));
+                    data.iter_mut().for_each(|x| *x *= scale);
+                }
+                let ty = match shape.len() {
+                    1 => GgmlType::F32,
+                    _ => quant(name).unwrap_or(GgmlType::F16),
+                };
+                tensors.push((target, ty, shape, encode(ty, &data)));
+                continue;
+            };
+            match x {
+                "w" => {
+                    // fuse the lerp factors in the order of llama.cpp
+                    let data = FUSED_LERP
+                        .iter()
+                        .flat_map(|x| lerp(model, prefix, x))
+                        .collect_vec();
+                    let shape = vec![FUSED_LERP.len(), 1, 1, shape[0]];
+                    let target = format!("blk.{}.time_mix_lerp_fused.weight", &prefix[7..]);
+                    tensors.push((target, GgmlType::F32, shape, encode(GgmlType::F32, &data)));
+                }
+                "k" | "v" | "r" | "g" => {}
+                _ => {
+                    let ty = match shape.len() {
+                        1 => GgmlType::F32,
+                        _ => GgmlType::F16,
+                    };
+                    tensors.push((rename(name), ty, shape, encode(ty, &data)));
+                }
+            }
+        }
+
+        let metadata = [
+            ("general.architecture", GgufValue::String("rwkv6".into())),
+            (
+                "rwkv6.rescale_every_n_layers",
+                GgufValue::U32(rescale as u32),
+            ),
+        ];
+        write_gguf(&metadata, &tensors)
+    }
+
+    fn lerp(model: &TinyModel, prefix: &str, x: &str) -> Vec<f32> {
+        let (_, data) = model.data(&format!("{prefix}.att.time_mix_{x}")).unwrap();
+        data.iter().map(|x| x.to_f32()).collect()
+    }
+
+    #[test]
+    fn test_gguf_reader() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let model = TinyModel::new(info.clone(), 42);
+        let data = convert_gguf(&model, 1, |name| {
+            name.ends_with("ffn.value.weight").then_some(GgmlType::Q8_0)
+        });
+
+        let reader = GgufReader::new(Cursor::new(data.clone()))?;
+        assert_eq!(Loader::info(&reader)?, info);
+        assert_eq!(
+            reader.metadata().get("general.architecture"),
+            Some(&GgufValue::String("rwkv6".into()))
+        );
+        assert_eq!(
+            Reader::names(&reader).into_iter().sorted().collect_vec(),
+            Reader::names(&model).into_iter().sorted().collect_vec()
+        );
+        assert!(GgufReader::new(Cursor::new(&data[..data.len() - 2])).is_err());
+        assert!(GgufReader::new(Cursor::new(&data[..6])).is_err());
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        for name in Reader::names(&model) {
+            let (shape, expected) = model.data(name).unwrap();
+            let (_, actual_shape, actual) = runtime.block_on(reader.tensor(name))?;
+            assert_eq!(shape, &actual_shape[..], "{name}");
+            let actual: &[f16] = bytemuck::cast_slice(&actual);
+            for (index, (x, y)) in itertools::zip_eq(expected, actual).enumerate() {
+                let (x, y) = (x.to_f32(), y.to_f32());
+                // rescaling by powers of 2 only loses the bits of subnormal numbers
+                let tolerance = match reader.ggml_type(name) {
+                    Some(GgmlType::F16) | Some(GgmlType::F32) => 1.0e-6,
+                    _ => 1.0e-3,
+                };
+                assert!((x - y).abs() < tolerance, "{name} at {index}: {x} vs. {y}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_kquant() -> Result<()> {
+        let data = (0..1024)
+            .map(|x| ((x * 37 % 101) as f32 / 50.0 - 1.0) * (1.0 + (x / 256) as f32))
+            .collect_vec();
+        for (ty, q5, step) in [(GgmlType::Q4_K, false, 15.0), (GgmlType::Q5_K, true, 31.0)] {
+            let blocks = quantize_kquant(&data, q5);
+            let output = ty.dequantize(&blocks);
+            for (x, y) in itertools::zip_eq(&data, &output) {
+                assert!((x - y).abs() <= 4.0 * 2.0 / step, "{ty:?}: {x} vs. {y}");
+            }
+
+            let format = match q5 {
+                true => super::KQuantFormat::Q5K,
+                false => super::KQuantFormat::Q4K,
+            };
+            let converted = super::convert_kquant(&blocks, vec![4, 256], format, 2.0);
+            assert_eq!(converted.w.len(), 512);
+            assert_eq!(converted.s.len(), 64);
+            assert_eq!(converted.m.len(), 8);
+            assert_eq!(converted.h.len(), if q5 { 128 } else { 0 });
+
+            // dequantize the converted layout: `d * sc * q - dmin * m`
+            for (g, y) in output.iter().enumerate() {
+                let low = (converted.w[g / 2] >> (4 * (g % 2))) & 0xf;
+                let high = match q5 {
+                    true => (converted.h[g / 8] >> (g % 8)) & 1,
+                    false => 0,
+                };
+                let q = (low | (high << 4)) as f32;
+                let (sc, m) = (converted.s[2 * (g / 32)], converted.s[2 * (g / 32) + 1]);
+                let d = converted.m[2 * (g / 256)].to_f32();
+                let dmin = converted.m[2 * (g / 256) + 1].to_f32();
+                let x = d * sc as f32 * q - dmin * m as f32;
+                assert!((x - 2.0 * y).abs() < 1.0e-3, "{ty:?} at {g}: {x} vs. {y}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_inference() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let model = TinyModel::new(info.clone(), 42);
+
+        // llama.cpp rescales and fuses tensors, which the reader must undo
+        let data = convert_gguf(&model, 1, |_| None);
+        let reader = GgufReader::new(Cursor::new(data))?;
+        assert_eq!(Loader::info(&reader)?, info);
+
+        let prompts = prompts(&info);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let Some(expected) = runtime.block_on(infer_gpu(model.clone(), &prompts, None))? else {
+            return Ok(());
+        };
+        let Some(output) = runtime.block_on(infer_gpu(reader, &prompts, None))? else {
+            return Ok(());
+        };
+        for (expected, output) in itertools::zip_eq(expected.concat(), output.concat()) {
+            assert!(
+                (expected - output).abs() < 1.0e-3,
+                "{expected} vs. {output}"
+            );
+        }
+
+        // pre-quantized matrices are uploaded as they are, and multiply as their dequantized values do
+        let name = "blocks.1.ffn.value.weight";
+        let data = convert_gguf(&model, 1, |x| (x == name).then_some(GgmlType::Q4_K));
+        let reader = GgufReader::new(Cursor::new(data))?;
+        let context = runtime.block_on(create_context(&info))?;
+        let loader = Loader {
+            context: context.clone(),
+            model: reader,
+            lora: vec![],
+            runtime_lora: vec![],
+            registry: None,
+        };
+        let quant = runtime.block_on(loader.load_matrix(name.into(), Quant::Q4K))?;
+        assert!(matches!(quant, Matrix::Q4K { .. }));
+        let matrix = runtime.block_on(loader.load_matrix(name.into(), Quant::None))?;
+
+        let mut rng = fastrand::Rng::with_seed(3);
+        let input = (0..info.num_hidden).map(|_| rng.f32() - 0.5).collect_vec();
+        let input: TensorGpu<f32, _> =
+            context.tensor_from_data([info.num_hidden, 1, 1, 1], input)?;
+        let mut outputs = vec![];
+        for matrix in [quant, matrix] {
+            let output: TensorGpu<f32, _> = context.tensor_init([info.num_emb, 1, 1, 1]);
+            let op = matrix.matmul_vec_op(
+                input.view(.., .., .., ..)?,
+                output.view(.., .., .., ..)?,
+                Activation::None,
+            )?;
+            context.submit(context.encode(&op));
+            let output: TensorCpu<f32> = runtime.block_on(output.back());
+            outputs.push(output.to_vec());
+        }
+        for (x, y) in itertools::zip_eq(&outputs[0], &outputs[1]) {
+            assert!((x - y).abs() < 1.0e-2, "{x} vs. {y}");
+        }
+        Ok(())
+    }
+}
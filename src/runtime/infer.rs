@@ -1,11 +1,42 @@
+use std::{collections::BTreeMap, future::Future, ops::Deref, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use web_rwkv_derive::{Deref, DerefMut};
+use wgpu::CommandBuffer;
 
-use super::{JobInfo, JobInput};
-use crate::tensor::TensorCpu;
+use super::{
+    bias::{Phrase, PhraseBias},
+    event::Event,
+    model::EarlyExit,
+    probe::Probe,
+    sampler::{Sampled, SampledOutput, SamplerStep},
+    score::{Scored, ScoredOutput, ScorerStep},
+    stream::{HeadStream, Streamed},
+    tap::{Tap, Tapped, TappedOutput},
+    Job, JobBuilder, JobInfo, JobInput, JobRuntime, SessionId, Usage,
+};
+use crate::{
+    context::Context,
+    tensor::{
+        kind::ReadWrite, ops::LogitTransform, ops::TensorOp, TensorCpu, TensorError, TensorGpu,
+        TensorInit, TensorShape,
+    },
+};
 
 pub const MIN_TOKEN_CHUNK_SIZE: usize = 32;
-pub const NUM_LAYER_CHUNK: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum InferError {
+    #[error("token {token} of batch {batch} out of range of vocabulary size {num_vocab}")]
+    TokenOutOfRange {
+        batch: usize,
+        token: u16,
+        num_vocab: usize,
+    },
+}
 
 #[derive(Debug, Clone, Deref, DerefMut, PartialEq, Eq)]
 pub struct InferInfo(pub Vec<InferInfoBatch>);
@@ -14,6 +45,8 @@ pub struct InferInfo(pub Vec<InferInfoBatch>);
 pub struct InferInfoBatch {
     pub len: usize,
     pub option: Option<InferOption>,
+    pub embed: bool,
+    pub precision: Precision,
 }
 
 impl InferInfo {
@@ -27,6 +60,34 @@ impl InferInfo {
         self.0.len()
     }
 
+    /// Check if any batch reads back hidden states instead of logits.
+    #[inline]
+    pub fn embed(&self) -> bool {
+        self.0.iter().any(|x| x.embed && x.option.is_some())
+    }
+
+    /// Check if all batches that read back logits accept them in half precision.
+    #[inline]
+    pub fn half_logits(&self) -> bool {
+        let mut batches = self
+            .0
+            .iter()
+            .filter(|x| x.len > 0 && x.option.is_some() && !x.embed)
+            .peekable();
+        batches.peek().is_some() && batches.all(|x| x.precision == Precision::F16)
+    }
+
+    /// Check if all batches with outputs read back hidden states, so that the head can be skipped.
+    #[inline]
+    pub fn embed_only(&self) -> bool {
+        self.embed()
+            && self
+                .0
+                .iter()
+                .filter(|x| x.len > 0 && x.option.is_some())
+                .all(|x| x.embed)
+    }
+
     pub fn redirect(&self) -> InferRedirect {
         let mut headers = vec![];
         let mut inputs = vec![(0, 0); self.num_batch()];
@@ -73,7 +134,14 @@ impl InferInfo {
 impl JobInfo for InferInfo {
     #[inline]
     fn check(&self, info: &Self) -> bool {
-        self.num_token() == info.num_token() && self.redirect() == info.redirect()
+        self.num_token() == info.num_token()
+            && self.redirect() == info.redirect()
+            && self.half_logits() == info.half_logits()
+            && self
+                .0
+                .iter()
+                .map(|x| x.embed)
+                .eq(info.0.iter().map(|x| x.embed))
     }
 }
 
@@ -93,8 +161,17 @@ enum BatchState {
     Read(usize),
 }
 
+/// Precision of the logits read back from GPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Precision {
+    #[default]
+    F32,
+    /// Convert the logits to `f16` on GPU before reading them back, halving the transfer at a slight loss of precision.
+    F16,
+}
+
 /// Inference option for outputs.
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum InferOption {
     /// Only output the prediction for the last token.
     #[default]
@@ -116,18 +193,98 @@ impl InferChunk {
     pub fn num_batch(&self) -> usize {
         self.0.len()
     }
+
+    /// Check that all tokens are in a vocabulary of `num_vocab` tokens, e.g., of a model with a trimmed vocabulary.
+    pub fn check(&self, num_vocab: usize) -> Result<(), InferError> {
+        for (batch, chunk) in self.0.iter().enumerate() {
+            if let Some(&token) = chunk.iter().find(|&&token| token as usize >= num_vocab) {
+                return Err(InferError::TokenOutOfRange {
+                    batch,
+                    token,
+                    num_vocab,
+                });
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, Clone, Deref, DerefMut)]
 pub struct InferChunkBatch(pub Vec<u16>);
 
+/// Tokens of a batch that are not yet consumed.
+///
+/// The tokens are shared instead of copied, and consuming them only advances a cursor.
+/// This dereferences to the remaining tokens.
+#[derive(Debug, Default, Clone)]
+pub struct InferTokens {
+    data: Arc<[u16]>,
+    cursor: usize,
+}
+
+impl InferTokens {
+    /// Consume the first `len` remaining tokens.
+    #[inline]
+    pub fn advance(&mut self, len: usize) {
+        self.cursor = (self.cursor + len).min(self.data.len());
+    }
+}
+
+impl Deref for InferTokens {
+    type Target = [u16];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.data[self.cursor..]
+    }
+}
+
+impl PartialEq for InferTokens {
+    fn eq(&self, other: &Self) -> bool {
+        self.deref() == other.deref()
+    }
+}
+
+impl Eq for InferTokens {}
+
+impl From<Arc<[u16]>> for InferTokens {
+    fn from(data: Arc<[u16]>) -> Self {
+        Self { data, cursor: 0 }
+    }
+}
+
+impl From<Vec<u16>> for InferTokens {
+    fn from(value: Vec<u16>) -> Self {
+        Arc::<[u16]>::from(value).into()
+    }
+}
+
+impl FromIterator<u16> for InferTokens {
+    fn from_iter<I: IntoIterator<Item = u16>>(iter: I) -> Self {
+        Arc::<[u16]>::from_iter(iter).into()
+    }
+}
+
+impl From<&[u16]> for InferTokens {
+    fn from(value: &[u16]) -> Self {
+        Arc::<[u16]>::from(value).into()
+    }
+}
+
 /// One batch of the input task.
 #[derive(Debug, Default, Clone)]
 pub struct InferInputBatch {
     /// Tokens to infer. If this is empty, inference won't occur for the batch.
-    pub tokens: Vec<u16>,
+    pub tokens: InferTokens,
     /// Inference option for outputs.
     pub option: InferOption,
+    /// Output the normalized final hidden states instead of the logits.
+    pub embed: bool,
+    /// The session to account the work of this batch to.
+    pub session: Option<SessionId>,
+    /// Precision of the logits read back. They are read back in `f16` only if all batches with logits in a step ask for it;
+    /// either way, the output is in `f32`.
+    pub precision: Precision,
 }
 
 #[derive(Debug, Clone)]
@@ -171,7 +328,7 @@ impl JobInput for InferInput {
             return;
         };
         for (batch, info) in self.batches.iter_mut().zip_eq(info.0) {
-            batch.tokens = batch.tokens.split_off(info.len);
+            batch.tokens.advance(info.len);
         }
     }
 
@@ -187,6 +344,38 @@ impl JobInput for InferInput {
             .collect();
         InferChunk(chunk)
     }
+
+    fn usage(&self) -> (usize, Vec<(SessionId, Usage)>) {
+        let Some(info) = self.iter().next() else {
+            return (0, vec![]);
+        };
+        let usage = self
+            .batches
+            .iter()
+            .zip_eq(info.0.iter())
+            .filter(|(_, info)| info.len > 0)
+            .filter_map(|(batch, info)| {
+                let session = batch.session?;
+                let usage = Usage {
+                    prompt_tokens: info.len,
+                    generated_tokens: (info.len == batch.tokens.len()) as usize,
+                    ..Default::default()
+                };
+                Some((session, usage))
+            })
+            .collect();
+        (info.num_token(), usage)
+    }
+
+    fn shrink(&mut self) -> bool {
+        if self.token_chunk_size <= MIN_TOKEN_CHUNK_SIZE {
+            return false;
+        }
+        self.token_chunk_size = (self.token_chunk_size / 2)
+            .max(MIN_TOKEN_CHUNK_SIZE)
+            .next_multiple_of(MIN_TOKEN_CHUNK_SIZE);
+        true
+    }
 }
 
 impl IntoIterator for &InferInput {
@@ -197,7 +386,10 @@ impl IntoIterator for &InferInput {
         let batches = self
             .batches
             .iter()
-            .map(|batch| (BatchState::Read(batch.tokens.len()), batch.option))
+            .map(|batch| {
+                let state = BatchState::Read(batch.tokens.len());
+                (state, batch.option, batch.embed, batch.precision)
+            })
             .collect();
         let token_chunk_size = self.token_chunk_size;
         Self::IntoIter {
@@ -209,7 +401,7 @@ impl IntoIterator for &InferInput {
 
 #[derive(Debug, Clone)]
 pub struct InferIter {
-    batches: Vec<(BatchState, InferOption)>,
+    batches: Vec<(BatchState, InferOption, bool, Precision)>,
     token_chunk_size: usize,
 }
 
@@ -264,6 +456,8 @@ impl Iterator for InferIter {
                 (InferOption::Last, _) => None,
                 (InferOption::Full, _) => Some(InferOption::Full),
             };
+            info.embed = batch.2;
+            info.precision = batch.3;
         }
 
         Some(InferInfo(info))
@@ -276,19 +470,846 @@ pub struct InferOutputBatch(pub TensorCpu<f32>);
 #[derive(Debug, Clone, Deref, DerefMut)]
 pub struct InferOutput(pub Vec<InferOutputBatch>);
 
+/// A model runtime whose jobs pick the argmax token on GPU, for greedy decoding.
+/// Only the token ids are read back, instead of the logits.
+#[derive(Debug, Clone)]
+pub struct Greedy<R>(pub R);
+
+/// The argmax tokens of each batch, one for each output position.
+#[derive(Debug, Default, Clone, Deref, DerefMut, PartialEq, Eq)]
+pub struct GreedyOutput(pub Vec<Vec<u16>>);
+
+impl GreedyOutput {
+    /// Pick the argmax tokens from logits on CPU.
+    pub fn from_logits(output: InferOutput) -> Self {
+        let output = output
+            .0
+            .into_iter()
+            .map(|batch| {
+                let num_vocab = batch.shape()[0];
+                batch
+                    .data()
+                    .chunks_exact(num_vocab.max(1))
+                    .map(|x| {
+                        x.iter()
+                            .enumerate()
+                            .fold((0, f32::MIN), |acc, (index, &x)| match x > acc.1 {
+                                true => (index, x),
+                                false => acc,
+                            })
+                            .0 as u16
+                    })
+                    .collect()
+            })
+            .collect();
+        Self(output)
+    }
+}
+
+/// Sampling parameters of a batch that outputs tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SampleOption {
+    /// Pick the most probable token if this is zero.
+    pub temperature: f32,
+    pub top_p: f32,
+    /// Seed of the random numbers used for sampling.
+    pub seed: u64,
+}
+
+impl Default for SampleOption {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_p: 0.5,
+            seed: 0,
+        }
+    }
+}
+
+impl SampleOption {
+    /// Sample a token from `logits`, advancing the random `state`.
+    pub fn sample(&self, logits: &[f32], state: &mut u64) -> u16 {
+        let argmax = || {
+            logits
+                .iter()
+                .enumerate()
+                .fold((0, f32::MIN), |acc, (index, &x)| match x > acc.1 {
+                    true => (index, x),
+                    false => acc,
+                })
+                .0 as u16
+        };
+        if self.temperature <= 0.0 {
+            return argmax();
+        }
+
+        let max = logits.iter().copied().fold(f32::MIN, f32::max);
+        let probs = logits
+            .iter()
+            .map(|&x| ((x - max) / self.temperature).exp())
+            .collect_vec();
+        let sum: f32 = probs.iter().sum();
+
+        let mut cum = 0.0;
+        let sorted = probs
+            .into_iter()
+            .map(|x| x / sum)
+            .enumerate()
+            .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+            .take_while(|&(_, x)| {
+                let take = cum < self.top_p;
+                cum += x;
+                take
+            })
+            .collect_vec();
+
+        let total: f32 = sorted.iter().map(|(_, x)| x).sum();
+        let mut rand = next_random(state) * total;
+        for &(token, x) in &sorted {
+            if rand < x {
+                return token as u16;
+            }
+            rand -= x;
+        }
+        sorted
+            .first()
+            .map(|&(token, _)| token as u16)
+            .unwrap_or_else(argmax)
+    }
+}
+
+/// SplitMix64, returning a number uniformly in `[0, 1)`.
+pub(super) fn next_random(state: &mut u64) -> f32 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Conditions that end the generation of a batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StopOption {
+    /// Maximum number of tokens to generate.
+    pub max_tokens: usize,
+    /// Tokens that end the generation. They are not included in the output.
+    pub tokens: Vec<u16>,
+}
+
+impl Default for StopOption {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            tokens: vec![0],
+        }
+    }
+}
+
+impl StopOption {
+    /// Push a generated `token` unless it is a stop token, and return why the generation ends, if it does.
+    fn push(&self, tokens: &mut Vec<u16>, token: u16) -> Option<StopReason> {
+        if self.tokens.contains(&token) {
+            return Some(StopReason::Token(token));
+        }
+        tokens.push(token);
+        (tokens.len() >= self.max_tokens).then_some(StopReason::Length)
+    }
+}
+
+/// What a batch of a [`JobRuntime::serve`] call asks for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InferKind {
+    /// Logits of the prompt.
+    Logits(InferOption),
+    /// Normalized final hidden states of the prompt.
+    Embed(InferOption),
+    /// Tokens generated after the prompt.
+    Token {
+        sample: SampleOption,
+        stop: StopOption,
+        /// Phrases biased while generating, matched against the prompt and the tokens generated. See [`PhraseBias`].
+        #[serde(default)]
+        phrases: Vec<Phrase>,
+    },
+}
+
+impl Default for InferKind {
+    fn default() -> Self {
+        Self::Logits(InferOption::Last)
+    }
+}
+
+/// One batch of a [`JobRuntime::serve`] call.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InferRequest {
+    /// Prompt tokens. This must not be empty.
+    pub tokens: InferTokens,
+    pub kind: InferKind,
+    /// The session to account the work of this request to.
+    pub session: Option<SessionId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    /// A stop token is generated.
+    Token(u16),
+    /// The maximum number of tokens is reached.
+    Length,
+}
+
+/// Output of one batch of a [`JobRuntime::serve`] call, matching its [`InferKind`].
+#[derive(Debug, Clone)]
+pub enum InferResponse {
+    Logits(TensorCpu<f32>),
+    Embed(TensorCpu<f32>),
+    Token {
+        tokens: Vec<u16>,
+        reason: StopReason,
+    },
+}
+
+/// Concat the outputs of one batch along the token axis.
+fn concat_tokens(tensors: Vec<TensorCpu<f32>>) -> Result<TensorCpu<f32>, TensorError> {
+    let Some(first) = tensors.first() else {
+        return Err(TensorError::Empty);
+    };
+    let num_dim = first.shape()[0];
+    let num_token = tensors.iter().map(|x| x.shape()[1]).sum();
+    let data = tensors.iter().map(|x| x.data().to_vec()).concat();
+    TensorCpu::from_data([num_dim, num_token, 1, 1], data)
+}
+
+impl JobRuntime<InferInput, InferOutput> {
+    /// Run a batch of heterogeneous requests to completion, one for each batch of the runtime's state.
+    pub async fn serve(
+        &self,
+        requests: Vec<InferRequest>,
+        token_chunk_size: usize,
+    ) -> Result<Vec<InferResponse>> {
+        if requests.iter().any(|request| request.tokens.is_empty()) {
+            bail!("empty prompt");
+        }
+
+        let batches = requests
+            .iter()
+            .map(|request| {
+                let tokens = request.tokens.clone();
+                let (option, embed) = match request.kind {
+                    InferKind::Logits(option) => (option, false),
+                    InferKind::Embed(option) => (option, true),
+                    InferKind::Token { .. } => (InferOption::Last, false),
+                };
+                InferInputBatch {
+                    tokens,
+                    option,
+                    embed,
+                    session: request.session,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let mut input = InferInput::new(batches, token_chunk_size);
+
+        let mut outputs = vec![vec![]; requests.len()];
+        let mut generated = vec![vec![]; requests.len()];
+        let mut randoms = requests
+            .iter()
+            .map(|request| match request.kind {
+                InferKind::Token { sample, .. } => sample.seed,
+                _ => 0,
+            })
+            .collect_vec();
+        let mut phrases = requests
+            .iter()
+            .map(|request| match &request.kind {
+                InferKind::Token { phrases, .. } => {
+                    let mut bias = PhraseBias::new(phrases.clone());
+                    request.tokens.iter().for_each(|&token| bias.push(token));
+                    bias
+                }
+                _ => PhraseBias::default(),
+            })
+            .collect_vec();
+        let mut responses = vec![None; requests.len()];
+
+        while responses.iter().any(Option::is_none) {
+            let (next, output) = self.infer(input).await;
+            input = next;
+
+            for (batch, (request, output)) in requests.iter().zip_eq(output.0).enumerate() {
+                if responses[batch].is_some() {
+                    continue;
+                }
+                let done = input.batches[batch].tokens.is_empty();
+
+                match &request.kind {
+                    InferKind::Logits(_) | InferKind::Embed(_) => {
+                        if output.size() > 0 {
+                            outputs[batch].push(output.0);
+                        }
+                        if done {
+                            let output = concat_tokens(std::mem::take(&mut outputs[batch]))?;
+                            responses[batch] = Some(match request.kind {
+                                InferKind::Embed(_) => InferResponse::Embed(output),
+                                _ => InferResponse::Logits(output),
+                            });
+                        }
+                    }
+                    InferKind::Token { sample, stop, .. } => {
+                        if output.size() == 0 {
+                            continue;
+                        }
+                        let bias = &mut phrases[batch];
+                        let token = match bias.is_empty() {
+                            true => sample.sample(output.data(), &mut randoms[batch]),
+                            false => {
+                                let mut logits = output.0.to_vec();
+                                bias.apply(&mut logits);
+                                sample.sample(&logits, &mut randoms[batch])
+                            }
+                        };
+                        bias.push(token);
+                        self.emit(Event::TokenGenerated {
+                            session: request.session,
+                            batch,
+                            token,
+                        });
+                        let tokens = &mut generated[batch];
+                        match stop.push(tokens, token) {
+                            Some(reason) => {
+                                let tokens = std::mem::take(tokens);
+                                responses[batch] = Some(InferResponse::Token { tokens, reason });
+                            }
+                            None => input.batches[batch].tokens = vec![token].into(),
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(responses.into_iter().flatten().collect())
+    }
+
+    /// Embed each prompt into the normalized final hidden state of its last token, one prompt per batch of the runtime's state.
+    /// The head is skipped, saving its matmul, which is the largest of the model.
+    pub async fn embed(
+        &self,
+        prompts: &[Vec<u16>],
+        token_chunk_size: usize,
+    ) -> Result<Vec<TensorCpu<f32>>> {
+        let requests = prompts
+            .iter()
+            .map(|tokens| InferRequest {
+                tokens: tokens.clone().into(),
+                kind: InferKind::Embed(InferOption::Last),
+                session: None,
+            })
+            .collect();
+        self.serve(requests, token_chunk_size)
+            .await?
+            .into_iter()
+            .map(|response| match response {
+                InferResponse::Embed(tensor) => Ok(tensor),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+}
+
+impl JobRuntime<InferInput, GreedyOutput> {
+    /// Run greedy token requests to completion, one for each batch of the runtime's state.
+    /// Unlike [`JobRuntime::serve`] of logits, the argmax is picked on GPU and only the token ids are read back,
+    /// so requests with a non-zero temperature, phrase biases, or asking for logits or embeddings are rejected.
+    pub async fn serve(
+        &self,
+        requests: Vec<InferRequest>,
+        token_chunk_size: usize,
+    ) -> Result<Vec<InferResponse>> {
+        if requests.iter().any(|request| request.tokens.is_empty()) {
+            bail!("empty prompt");
+        }
+        let stops: Vec<_> = requests
+            .iter()
+            .map(|request| match &request.kind {
+                InferKind::Token {
+                    sample,
+                    stop,
+                    phrases,
+                } if sample.temperature <= 0.0 && phrases.is_empty() => Ok(stop),
+                _ => Err(anyhow!("greedy runtime only serves greedy token requests")),
+            })
+            .try_collect()?;
+
+        let batches = requests
+            .iter()
+            .map(|request| InferInputBatch {
+                tokens: request.tokens.clone(),
+                option: InferOption::Last,
+                session: request.session,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, token_chunk_size);
+
+        let mut generated = vec![vec![]; requests.len()];
+        let mut responses = vec![None; requests.len()];
+
+        while responses.iter().any(Option::is_none) {
+            let (next, GreedyOutput(output)) = self.infer(input).await;
+            input = next;
+
+            for (batch, (request, output)) in requests.iter().zip_eq(output).enumerate() {
+                let Some(&token) = output.last() else {
+                    continue;
+                };
+                if responses[batch].is_some() {
+                    continue;
+                }
+                self.emit(Event::TokenGenerated {
+                    session: request.session,
+                    batch,
+                    token,
+                });
+                let tokens = &mut generated[batch];
+                match stops[batch].push(tokens, token) {
+                    Some(reason) => {
+                        let tokens = std::mem::take(tokens);
+                        responses[batch] = Some(InferResponse::Token { tokens, reason });
+                    }
+                    None => input.batches[batch].tokens = vec![token].into(),
+                }
+            }
+        }
+
+        Ok(responses.into_iter().flatten().collect())
+    }
+}
+
+/// The job of a model runtime that the runtimes wrapping it (e.g., [`Greedy`]) build their jobs on.
+pub trait HeadJob: Job<Info = InferInfo, Input = InferChunk, Output = InferOutput> {
+    /// Where the outputs of each batch are among the headers of the job.
+    fn redirect(&self) -> &InferRedirect;
+    /// If the job reads exit heads, which decide on the logits, so that they are read back anyway.
+    fn has_exit(&self) -> bool;
+    /// The logits of the job on GPU, of shape `[V, H]` where `H` is the number of headers.
+    fn logits(&self) -> &TensorGpu<f32, ReadWrite>;
+    /// Append commands to run after the head, e.g., to pick tokens from the logits.
+    fn append(&mut self, commands: Vec<CommandBuffer>);
+    /// Wait for the job to finish, and hand the logits over in chunks of output rows as they are read back.
+    fn stream(self) -> impl Future<Output = Result<HeadStream>> + Send;
+}
+
+/// A model runtime whose [`HeadJob`]s the runtimes wrapping it customize.
+pub trait HeadJobBuilder<J: HeadJob>: JobBuilder<J, Info = InferInfo> {
+    /// Number of layers of the model.
+    fn num_layer(&self) -> usize;
+    /// If the head applies the transform of the logits in its matmul, see [`Matrix::matmul_logits_op`](crate::tensor::matrix::Matrix::matmul_logits_op).
+    /// This is only done if nothing is to see the logits in between, e.g., hooks after the head or the exit head.
+    fn fuses_logits(&self, info: &InferInfo) -> bool;
+    /// Build a job that also copies the hidden states at `taps` out, see [`Tapped`],
+    /// and runs only the first `depth` layers, skipping the head if that is not all of them, see [`Probe`].
+    /// Fails with [`OutOfMemoryError`](crate::context::OutOfMemoryError) if the device runs out of memory meanwhile.
+    #[allow(clippy::type_complexity)]
+    fn build_head_job(
+        &self,
+        info: InferInfo,
+        taps: &[Tap],
+        depth: usize,
+        transform: LogitTransform,
+    ) -> Result<(J, Vec<(Tap, TensorGpu<f32, ReadWrite>)>)>;
+}
+
+/// The part of a job after an exit layer, which runs only if the exit head is not confident.
+pub(crate) struct ExitJob {
+    pub option: EarlyExit,
+    pub commands: Vec<CommandBuffer>,
+    pub output: TensorGpu<f32, ReadWrite>,
+    /// The top-1 probability of each output of the exit head, see [`TensorOp::max_prob`].
+    pub confidence: TensorGpu<f32, ReadWrite>,
+}
+
+impl ExitJob {
+    /// Read the confidence of the exit heads back in order, submitting the part after each one that is not confident,
+    /// and return the logits of the first confident one, if any, still on GPU.
+    pub async fn settle(exits: Vec<Self>) -> Option<TensorGpu<f32, ReadWrite>> {
+        for exit in exits {
+            let confidence = exit.confidence.back().await;
+            let threshold = exit.option.threshold;
+            if confidence.data().iter().all(|&p| p >= threshold) {
+                return Some(exit.output);
+            }
+            exit.output.context.submit(exit.commands);
+        }
+        None
+    }
+}
+
+/// A [`HeadJob`] that reads back only the argmax token of each output.
+pub struct GreedyJob<J> {
+    job: J,
+    output: TensorGpu<u32, ReadWrite>,
+}
+
+impl<J: HeadJob> Job for GreedyJob<J> {
+    type Info = InferInfo;
+    type Input = InferChunk;
+    type Output = GreedyOutput;
+
+    fn load(self, input: &Self::Input) -> Result<Self> {
+        let job = self.job.load(input)?;
+        Ok(Self { job, ..self })
+    }
+
+    fn submit(&mut self) {
+        self.job.submit();
+    }
+
+    async fn settle(&mut self) {
+        self.job.settle().await;
+    }
+
+    async fn back(self) -> Result<Self::Output> {
+        // the exit head decides on the logits, so they are read back anyway
+        if self.job.has_exit() {
+            let output = self.job.back().await?;
+            return Ok(GreedyOutput::from_logits(output));
+        }
+
+        let output = self.output.back().await;
+        let batches = self
+            .job
+            .redirect()
+            .outputs
+            .iter()
+            .map(|&(start, end)| {
+                output.data()[start..end]
+                    .iter()
+                    .map(|&x| x as u16)
+                    .collect()
+            })
+            .collect();
+        Ok(GreedyOutput(batches))
+    }
+}
+
+/// A [`HeadJob`] that samples tokens on GPU, and reads back only them.
+pub struct SampledJob<J> {
+    job: J,
+    step: SamplerStep,
+}
+
+impl<J: HeadJob> Job for SampledJob<J> {
+    type Info = InferInfo;
+    type Input = InferChunk;
+    type Output = SampledOutput;
+
+    fn load(self, input: &Self::Input) -> Result<Self> {
+        let job = self.job.load(input)?;
+        self.step.load(job.redirect())?;
+        Ok(Self { job, ..self })
+    }
+
+    fn submit(&mut self) {
+        self.job.submit();
+    }
+
+    async fn settle(&mut self) {
+        self.job.settle().await;
+    }
+
+    async fn back(self) -> Result<Self::Output> {
+        let redirect = self.job.redirect().clone();
+        // the exit head decides on the logits, so they are sampled after being read back
+        if self.job.has_exit() {
+            let output = self.job.back().await?;
+            return self.step.back_logits(output, &redirect).await;
+        }
+        Ok(self.step.back(&redirect).await)
+    }
+}
+
+/// A [`HeadJob`] that computes the log-probabilities of the next tokens on GPU, and reads back only them.
+pub struct ScoredJob<J> {
+    job: J,
+    step: ScorerStep,
+}
+
+impl<J: HeadJob> Job for ScoredJob<J> {
+    type Info = InferInfo;
+    type Input = InferChunk;
+    type Output = ScoredOutput;
+
+    fn load(self, input: &Self::Input) -> Result<Self> {
+        let job = self.job.load(input)?;
+        let step = self.step.load(job.redirect())?;
+        Ok(Self { job, step })
+    }
+
+    fn submit(&mut self) {
+        self.job.submit();
+    }
+
+    async fn settle(&mut self) {
+        self.job.settle().await;
+    }
+
+    async fn back(self) -> Result<Self::Output> {
+        let redirect = self.job.redirect().clone();
+        // the exit head decides on the logits, so they are scored after being read back
+        if self.job.has_exit() {
+            let output = self.job.back().await?;
+            return Ok(self.step.back_logits(output, &redirect));
+        }
+        Ok(self.step.back(&redirect).await)
+    }
+}
+
+/// A [`HeadJob`] that hands the logits over in chunks of output rows as they are read back, see [`Streamed`].
+pub struct StreamedJob<J> {
+    job: J,
+}
+
+impl<J: HeadJob> Job for StreamedJob<J> {
+    type Info = InferInfo;
+    type Input = InferChunk;
+    type Output = HeadStream;
+
+    fn load(self, input: &Self::Input) -> Result<Self> {
+        let job = self.job.load(input)?;
+        Ok(Self { job })
+    }
+
+    fn submit(&mut self) {
+        self.job.submit();
+    }
+
+    async fn settle(&mut self) {
+        self.job.settle().await;
+    }
+
+    async fn back(self) -> Result<Self::Output> {
+        self.job.stream().await
+    }
+}
+
+/// A [`HeadJob`] that also reads back the hidden states at its [taps](Tap).
+pub struct TappedJob<J> {
+    job: J,
+    taps: Vec<(Tap, TensorGpu<f32, ReadWrite>)>,
+}
+
+impl<J: HeadJob> Job for TappedJob<J> {
+    type Info = InferInfo;
+    type Input = InferChunk;
+    type Output = TappedOutput;
+
+    fn load(self, input: &Self::Input) -> Result<Self> {
+        let job = self.job.load(input)?;
+        Ok(Self { job, ..self })
+    }
+
+    fn submit(&mut self) {
+        self.job.submit();
+    }
+
+    async fn settle(&mut self) {
+        self.job.settle().await;
+    }
+
+    async fn back(self) -> Result<Self::Output> {
+        let outputs = self.job.redirect().outputs.clone();
+        let logits = self.job.back().await?;
+        let mut taps = BTreeMap::new();
+        for (tap, tensor) in self.taps {
+            let tensor = tensor.back().await;
+            let batches = outputs
+                .iter()
+                .map(|&(start, end)| tensor.slice(.., start..end, .., ..))
+                .try_collect()?;
+            taps.insert(tap, batches);
+        }
+        Ok(TappedOutput { logits, taps })
+    }
+}
+
+impl<J: HeadJob, B: HeadJobBuilder<J>> JobBuilder<GreedyJob<J>> for Greedy<B> {
+    type Info = InferInfo;
+
+    fn maintain(&self) {
+        self.0.maintain();
+    }
+
+    fn context(&self) -> Option<Context> {
+        self.0.context()
+    }
+
+    fn build(&self, seed: Self::Info) -> Result<GreedyJob<J>> {
+        let mut job = self.0.build(seed)?;
+        let context = job.logits().context.clone();
+
+        let num_header = job.logits().shape()[1];
+        let output = context.tensor_init([1, num_header, 1, 1]);
+        if num_header > 0 && !job.has_exit() {
+            let op = TensorOp::argmax(job.logits(), &output)?;
+            job.append(context.encode(&op));
+        }
+
+        Ok(GreedyJob { job, output })
+    }
+}
+
+impl<J: HeadJob, B: HeadJobBuilder<J>> JobBuilder<SampledJob<J>> for Sampled<B> {
+    type Info = InferInfo;
+
+    fn maintain(&self) {
+        self.0.maintain();
+    }
+
+    fn context(&self) -> Option<Context> {
+        self.0.context()
+    }
+
+    fn build(&self, seed: Self::Info) -> Result<SampledJob<J>> {
+        let runtime = &self.0;
+
+        let num_header = seed.redirect().headers.len();
+        let fused = runtime.fuses_logits(&seed);
+        let step = SamplerStep::new(&self.1, num_header).fused(fused);
+        let transform = match fused {
+            true => step.transform(),
+            false => Default::default(),
+        };
+        let num_layer = runtime.num_layer();
+        let (mut job, _) = runtime.build_head_job(seed, &[], num_layer, transform)?;
+
+        if num_header > 0 && !job.has_exit() {
+            let context = job.logits().context.clone();
+            let op = step.op(job.logits())?;
+            job.append(context.encode(&op));
+        }
+
+        Ok(SampledJob { job, step })
+    }
+}
+
+impl<J: HeadJob, B: HeadJobBuilder<J>> JobBuilder<ScoredJob<J>> for Scored<B> {
+    type Info = InferInfo;
+
+    fn maintain(&self) {
+        self.0.maintain();
+    }
+
+    fn context(&self) -> Option<Context> {
+        self.0.context()
+    }
+
+    fn build(&self, seed: Self::Info) -> Result<ScoredJob<J>> {
+        let mut job = self.0.build(seed)?;
+
+        let num_header = job.logits().shape()[1];
+        let step = ScorerStep::new(&self.1, num_header);
+        if num_header > 0 && !job.has_exit() {
+            let context = job.logits().context.clone();
+            let op = step.op(job.logits())?;
+            job.append(context.encode(&op));
+        }
+
+        Ok(ScoredJob { job, step })
+    }
+}
+
+impl<J: HeadJob, B: HeadJobBuilder<J>> JobBuilder<StreamedJob<J>> for Streamed<B> {
+    type Info = InferInfo;
+
+    fn maintain(&self) {
+        self.0.maintain();
+    }
+
+    fn context(&self) -> Option<Context> {
+        self.0.context()
+    }
+
+    fn build(&self, seed: Self::Info) -> Result<StreamedJob<J>> {
+        let job = self.0.build(seed)?;
+        Ok(StreamedJob { job })
+    }
+}
+
+impl<J: HeadJob, B: HeadJobBuilder<J>> JobBuilder<TappedJob<J>> for Tapped<B> {
+    type Info = InferInfo;
+
+    fn maintain(&self) {
+        self.0.maintain();
+    }
+
+    fn context(&self) -> Option<Context> {
+        self.0.context()
+    }
+
+    fn build(&self, seed: Self::Info) -> Result<TappedJob<J>> {
+        let depth = self.0.num_layer();
+        let (job, taps) = self
+            .0
+            .build_head_job(seed, &self.1, depth, Default::default())?;
+        Ok(TappedJob { job, taps })
+    }
+}
+
+impl<J: HeadJob, B: HeadJobBuilder<J>> JobBuilder<TappedJob<J>> for Probe<B> {
+    type Info = InferInfo;
+
+    fn maintain(&self) {
+        self.0.maintain();
+    }
+
+    fn context(&self) -> Option<Context> {
+        self.0.context()
+    }
+
+    fn build(&self, seed: Self::Info) -> Result<TappedJob<J>> {
+        let Probe(runtime, depth) = self;
+        let taps = [Tap::PostFfn(depth.saturating_sub(1))];
+        let (job, taps) = runtime.build_head_job(seed, &taps, *depth, Default::default())?;
+        Ok(TappedJob { job, taps })
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use anyhow::Result;
+    use itertools::Itertools;
 
-    use super::{InferInfo, InferInput, InferOption};
-    use crate::runtime::{
-        infer::{InferInfoBatch, InferInputBatch},
-        JobInput,
+    use super::{
+        Greedy, GreedyOutput, InferInfo, InferInput, InferKind, InferOption, InferOutput,
+        InferRequest, InferResponse, InferTokens, Precision, SampleOption, StopOption, StopReason,
+    };
+    use crate::{
+        runtime::{
+            event::Event,
+            infer::{InferInfoBatch, InferInputBatch},
+            model::{Build, ModelBuilder, ModelRuntime, ModelVersion, State},
+            tiny::{
+                tests::{
+                    create_context, infer_gpu, layer_norm, prompts, with_runtime, Reference, LN_EPS,
+                },
+                TinyModel,
+            },
+            v5, JobInput, JobRuntime, Maintenance,
+        },
+        tensor::TensorShape,
     };
 
     impl From<(usize, Option<InferOption>)> for InferInfoBatch {
         fn from((len, option): (usize, Option<InferOption>)) -> Self {
-            Self { len, option }
+            Self {
+                len,
+                option,
+                embed: false,
+                precision: Default::default(),
+            }
         }
     }
 
@@ -301,7 +1322,11 @@ mod tests {
                 (vec![2; 0], InferOption::Full),
                 (vec![3; 65], InferOption::Full),
             ]
-            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .map(|(tokens, option)| InferInputBatch {
+                tokens: tokens.into(),
+                option,
+                ..Default::default()
+            })
             .to_vec(),
             token_chunk_size: 128,
         };
@@ -385,7 +1410,11 @@ mod tests {
                 (vec![2; 0], InferOption::Full),
                 (vec![3; 65], InferOption::Full),
             ]
-            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .map(|(tokens, option)| InferInputBatch {
+                tokens: tokens.into(),
+                option,
+                ..Default::default()
+            })
             .to_vec(),
             token_chunk_size: 128,
         };
@@ -413,7 +1442,11 @@ mod tests {
                 (vec![2; 0], InferOption::Full),
                 (vec![3; 3], InferOption::Full),
             ]
-            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .map(|(tokens, option)| InferInputBatch {
+                tokens: tokens.into(),
+                option,
+                ..Default::default()
+            })
             .to_vec(),
             token_chunk_size: 128,
         };
@@ -434,6 +1467,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tokens() {
+        let tokens: InferTokens = (0..100).collect();
+        let mut run = InferInput::new(
+            vec![InferInputBatch {
+                tokens: tokens.clone(),
+                ..Default::default()
+            }],
+            32,
+        );
+
+        run.step();
+        let batch = &run.batches[0].tokens;
+        assert_eq!(batch.len(), 68);
+        assert_eq!(batch[0], 32);
+        assert!(std::sync::Arc::ptr_eq(&batch.data, &tokens.data));
+
+        let mut tokens = tokens;
+        tokens.advance(1000);
+        assert!(tokens.is_empty());
+    }
+
     #[test]
     fn test_redirect() -> Result<()> {
         let run = InferInput {
@@ -443,7 +1498,11 @@ mod tests {
                 (vec![2; 0], InferOption::Full),
                 (vec![3; 3], InferOption::Full),
             ]
-            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .map(|(tokens, option)| InferInputBatch {
+                tokens: tokens.into(),
+                option,
+                ..Default::default()
+            })
             .to_vec(),
             token_chunk_size: 128,
         };
@@ -464,7 +1523,11 @@ mod tests {
                 (vec![2; 9], InferOption::Last),
                 (vec![3; 4], InferOption::Last),
             ]
-            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .map(|(tokens, option)| InferInputBatch {
+                tokens: tokens.into(),
+                option,
+                ..Default::default()
+            })
             .to_vec(),
             token_chunk_size: 32,
         };
@@ -500,4 +1563,392 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_sample() {
+        let logits = [0.5, 3.0, -1.0, 2.9];
+
+        let option = SampleOption {
+            temperature: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(option.sample(&logits, &mut 0), 1);
+
+        // with a small top-p only the most probable token survives
+        let option = SampleOption {
+            top_p: 0.1,
+            ..Default::default()
+        };
+        assert!((0..16).all(|seed| option.sample(&logits, &mut { seed }) == 1));
+
+        // the same seed gives the same tokens
+        let option = SampleOption {
+            top_p: 1.0,
+            ..Default::default()
+        };
+        let sample = |mut state: u64| {
+            (0..16)
+                .map(|_| option.sample(&logits, &mut state))
+                .collect_vec()
+        };
+        assert_eq!(sample(3), sample(3));
+        assert_ne!(sample(3), sample(4));
+    }
+
+    #[test]
+    fn test_greedy() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let prompts = prompts(&info);
+                let batches = prompts
+                    .iter()
+                    .map(|tokens| InferInputBatch {
+                        tokens: tokens.clone().into(),
+                        option: InferOption::Full,
+                        ..Default::default()
+                    })
+                    .collect_vec();
+                let input = InferInput::new(batches, 32);
+
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let (logits, greedy) = with_runtime!(builder, version, prompts.len(), |runtime| {
+                    let logits = JobRuntime::new(runtime()).await;
+                    let greedy = JobRuntime::new(Greedy(runtime())).await;
+                    (logits, greedy)
+                });
+
+                let mut input_logits = input.clone();
+                let mut input_greedy = input;
+                while input_logits.num_token() > 0 {
+                    let (next, output) = logits.infer(input_logits).await;
+                    input_logits = next;
+                    let expected = GreedyOutput::from_logits(output);
+
+                    let (next, output) = greedy.infer(input_greedy).await;
+                    input_greedy = next;
+                    assert_eq!(expected, output, "{version:?}");
+                }
+
+                // serving greedy token requests reads back the same tokens that are picked from the logits
+                let kind = InferKind::Token {
+                    sample: SampleOption {
+                        temperature: 0.0,
+                        ..Default::default()
+                    },
+                    stop: StopOption {
+                        max_tokens: 4,
+                        tokens: vec![],
+                    },
+                    phrases: vec![],
+                };
+                let mut requests = prompts
+                    .iter()
+                    .map(|tokens| InferRequest {
+                        tokens: tokens.clone().into(),
+                        kind: kind.clone(),
+                        session: None,
+                    })
+                    .collect_vec();
+                let expected = logits.serve(requests.clone(), 32).await?;
+                let output = greedy.serve(requests.clone(), 32).await?;
+                for (expected, output) in expected.iter().zip_eq(output.iter()) {
+                    let (
+                        InferResponse::Token {
+                            tokens: expected, ..
+                        },
+                        InferResponse::Token { tokens: output, .. },
+                    ) = (expected, output)
+                    else {
+                        panic!("expect tokens");
+                    };
+                    assert_eq!(expected.len(), 4);
+                    assert_eq!(expected, output, "{version:?}");
+                }
+
+                // the logits are not read back, so they cannot be served
+                requests[0].kind = InferKind::Logits(InferOption::Last);
+                assert!(greedy.serve(requests, 32).await.is_err());
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_serve() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let prompts = prompts(&info);
+                let Some(expected) =
+                    infer_gpu(TinyModel::new(info.clone(), 42), &prompts, None).await?
+                else {
+                    return Ok(());
+                };
+                let context = create_context(&info).await?;
+
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let runtime =
+                    with_runtime!(builder, version, 3, |runtime| JobRuntime::new(runtime())
+                        .await);
+
+                let sample = SampleOption {
+                    temperature: 0.0,
+                    ..Default::default()
+                };
+                let stop = StopOption {
+                    max_tokens: 3,
+                    tokens: vec![],
+                };
+                let requests = vec![
+                    InferRequest {
+                        tokens: prompts[0].clone().into(),
+                        kind: InferKind::Logits(InferOption::Full),
+                        session: Some(1),
+                    },
+                    InferRequest {
+                        tokens: prompts[1].clone().into(),
+                        kind: InferKind::Embed(InferOption::Last),
+                        session: None,
+                    },
+                    InferRequest {
+                        tokens: prompts[1].clone().into(),
+                        kind: InferKind::Token {
+                            sample,
+                            stop,
+                            phrases: vec![],
+                        },
+                        session: Some(2),
+                    },
+                ];
+                let mut events = runtime.subscribe();
+                let responses = runtime.serve(requests, 32).await?;
+
+                let InferResponse::Logits(logits) = &responses[0] else {
+                    panic!("{version:?}: expect logits");
+                };
+                assert_eq!(logits.shape()[1], prompts[0].len());
+                for (x, y) in logits.data().iter().zip_eq(expected[0].iter()) {
+                    assert!((x - y).abs() < 1.0e-4, "{version:?}: {x} vs {y}");
+                }
+
+                let InferResponse::Embed(embed) = &responses[1] else {
+                    panic!("{version:?}: expect embed");
+                };
+                assert_eq!(embed.shape()[0], info.num_emb);
+                assert_eq!(embed.shape()[1], 1);
+
+                let InferResponse::Token { tokens, reason } = &responses[2] else {
+                    panic!("{version:?}: expect tokens");
+                };
+                let last = &expected[1][(prompts[1].len() - 1) * info.num_vocab..];
+                let argmax = sample.sample(last, &mut 0);
+                assert_eq!(tokens.len(), 3);
+                assert_eq!(tokens[0], argmax);
+                assert_eq!(*reason, StopReason::Length);
+
+                // sampled tokens are fed back as prompts, except for the last one
+                let usage = runtime.usage(1).expect("usage of session 1");
+                assert_eq!(usage.prompt_tokens, prompts[0].len());
+                assert_eq!(usage.generated_tokens, 1);
+                assert!(usage.gpu_time > Duration::ZERO);
+                let usage = runtime.take_usage(2).expect("usage of session 2");
+                assert_eq!(usage.prompt_tokens, prompts[1].len() + 2);
+                assert_eq!(usage.generated_tokens, 3);
+                assert_eq!(runtime.usage(2), None);
+                assert_eq!(runtime.usages().len(), 1);
+
+                let mut generated = vec![];
+                while let Ok(event) = events.try_recv() {
+                    if let Event::TokenGenerated { session, token, .. } = event {
+                        assert_eq!(session, Some(2));
+                        generated.push(token);
+                    }
+                }
+                assert_eq!(&generated, tokens);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_embed() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let model = TinyModel::new(info.clone(), 42);
+                let prompts = prompts(&info);
+
+                let reference = Reference::new(&model);
+                let expected = prompts
+                    .iter()
+                    .map(|tokens| {
+                        let mut state = reference.init();
+                        let layers = tokens
+                            .iter()
+                            .map(|&token| reference.forward_layers(&mut state, token).0)
+                            .last()
+                            .unwrap();
+                        layer_norm(
+                            layers.last().unwrap(),
+                            &reference.vector("ln_out.weight"),
+                            &reference.vector("ln_out.bias"),
+                            LN_EPS,
+                        )
+                    })
+                    .collect_vec();
+
+                let num_batch = prompts.len();
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let runtime = with_runtime!(builder, version, num_batch, |runtime| {
+                    JobRuntime::new(runtime()).await
+                });
+
+                // all batches embed, so the head is skipped
+                let embeds = runtime.embed(&prompts, 32).await?;
+                for (expected, embed) in expected.iter().zip_eq(embeds.iter()) {
+                    assert_eq!(embed.shape()[0], info.num_emb);
+                    assert_eq!(embed.shape()[1], 1);
+                    for (a, b) in expected.iter().zip_eq(embed.data().iter()) {
+                        assert!(
+                            (a - b).abs() < 1.0e-2 * a.abs().max(1.0),
+                            "{version:?}: {a} vs {b}"
+                        );
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_half_logits() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let prompts = prompts(&info);
+                let Some(expected) =
+                    infer_gpu(TinyModel::new(info.clone(), 42), &prompts, None).await?
+                else {
+                    return Ok(());
+                };
+                let context = create_context(&info).await?;
+
+                let num_batch = prompts.len();
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let (state, runtime) = with_runtime!(builder, version, num_batch, |runtime| {
+                    let runtime = runtime();
+                    let state: Box<dyn State> = Box::new(runtime.state());
+                    (state, JobRuntime::new(runtime).await)
+                });
+
+                let run = |precisions: Vec<Precision>| {
+                    let batches = prompts
+                        .iter()
+                        .zip_eq(precisions)
+                        .map(|(tokens, precision)| InferInputBatch {
+                            tokens: tokens.clone().into(),
+                            option: InferOption::Full,
+                            precision,
+                            ..Default::default()
+                        })
+                        .collect();
+                    let runtime = &runtime;
+                    let state = &state;
+                    async move {
+                        for batch in 0..num_batch {
+                            state.load(state.init(), batch)?;
+                        }
+                        let mut input = InferInput::new(batches, 32);
+                        let mut logits = vec![vec![]; num_batch];
+                        while input.num_token() > 0 {
+                            let (next, InferOutput(output)) = runtime.infer(input).await;
+                            input = next;
+                            for (logits, output) in logits.iter_mut().zip_eq(output) {
+                                logits.extend_from_slice(&output.0);
+                            }
+                        }
+                        anyhow::Ok(logits)
+                    }
+                };
+
+                // one batch asking for full precision keeps the whole readback in `f32`
+                let mut precisions = vec![Precision::F16; num_batch];
+                precisions[0] = Precision::F32;
+                assert_eq!(run(precisions).await?, expected);
+
+                let logits = run(vec![Precision::F16; num_batch]).await?;
+                for (expected, logits) in expected.iter().zip_eq(logits.iter()) {
+                    assert_eq!(expected.len(), logits.len());
+                    for (a, b) in expected.iter().zip_eq(logits.iter()) {
+                        assert!(
+                            (a - b).abs() <= 1.0e-3 * a.abs().max(1.0),
+                            "{version:?}: {a} vs {b}"
+                        );
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_half_logits_ahead() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let prompt = prompts(&info).swap_remove(1);
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime = v5::ModelRuntime::<f32>::new(model, 1);
+            let state = runtime.state();
+            let maintenance = Maintenance {
+                idle: Duration::from_millis(50),
+                hook: None,
+            };
+            let runtime = JobRuntime::new_with_maintenance(runtime, Some(maintenance)).await;
+
+            // the whole prompt runs in one step, so that all requests look the same but for the precision
+            let run = |precision: Precision| {
+                let input = InferInput::new(
+                    vec![InferInputBatch {
+                        tokens: prompt.clone().into(),
+                        option: InferOption::Last,
+                        precision,
+                        ..Default::default()
+                    }],
+                    prompt.len(),
+                );
+                let runtime = &runtime;
+                let state = &state;
+                async move {
+                    state.load(state.init(), 0)?;
+                    let (_, InferOutput(output)) = runtime.infer(input).await;
+                    // wait for the runtime to build the job of this step ahead while idle
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    anyhow::Ok(output[0].0.to_vec())
+                }
+            };
+
+            // jobs built ahead for one precision are not taken by requests of the other
+            let expected = run(Precision::F32).await?;
+            let half = run(Precision::F16).await?;
+            assert_ne!(half, expected);
+            assert_eq!(run(Precision::F32).await?, expected);
+            Ok(())
+        })
+    }
 }
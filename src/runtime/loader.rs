@@ -1,20 +1,35 @@
-use std::{borrow::Cow, future::Future};
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
 use half::f16;
 use itertools::Itertools;
 use regex::Regex;
-use safetensors::{Dtype, SafeTensorError, SafeTensors};
+use safetensors::{
+    tensor::{Metadata, TensorInfo},
+    Dtype, SafeTensorError, SafeTensors,
+};
+use serde::{Deserialize, Serialize};
 use web_rwkv_derive::{Deref, DerefMut};
 
-use super::model::{ModelError, ModelInfo, ModelVersion, Quant};
+use super::{
+    lora::{LoraFactor, LoraMode, LoraPlacement, LoraTarget},
+    model::{ModelError, ModelInfo, ModelVersion, Quant},
+};
 use crate::{
-    context::Context,
+    context::{ArenaBuffer, Context},
     num::Scalar,
     tensor::{
         kind::ReadWrite,
         matrix::Matrix,
-        ops::TensorOp,
+        ops::{Fp8Format, KQuantFormat, TensorOp},
         shape::{Shape, TensorDimension},
         TensorCpu, TensorError, TensorGpu, TensorInit, TensorInto, TensorReshape, TensorShape,
     },
@@ -29,6 +44,30 @@ pub trait Reader {
     fn contains(&self, name: &str) -> bool;
     fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError>;
     fn tensor(&self, name: &str) -> impl Future<Output = Result<ReaderTensor, SafeTensorError>>;
+
+    /// Read a matrix stored pre-quantized in a k-quant format as is, if the reader has it so.
+    fn kquant(
+        &self,
+        _name: &str,
+    ) -> impl Future<Output = Result<Option<ReaderKQuant>, SafeTensorError>> {
+        async { Ok(None) }
+    }
+}
+
+/// A matrix quantized in the layout of [`Matrix::Q4K`] or [`Matrix::Q5K`], in row-major order.
+#[derive(Debug, Clone)]
+pub struct ReaderKQuant {
+    pub format: KQuantFormat,
+    /// Shape of the dequantized matrix, outermost dimension first.
+    pub shape: Vec<usize>,
+    /// The low 4 bits of elements, 2 per byte.
+    pub w: Vec<u8>,
+    /// The high bits of elements, 8 per byte; empty for [`KQuantFormat::Q4K`].
+    pub h: Vec<u8>,
+    /// Quantized scale and minimum of each block.
+    pub s: Vec<u8>,
+    /// Scale and minimum factors of each super-block.
+    pub m: Vec<f16>,
 }
 
 impl ReaderSend for SafeTensors<'_> {
@@ -56,6 +95,124 @@ impl ReaderSend for SafeTensors<'_> {
     }
 }
 
+/// A safetensors file read on demand from a [`Read`] + [`Seek`] stream (e.g., a [`File`](std::fs::File)).
+///
+/// Only the header is kept in memory; the data of each tensor is read when it is loaded, and dropped once uploaded,
+/// so models larger than the system memory can be loaded without mapping or reading the whole file.
+#[derive(Debug)]
+pub struct StreamReader<R> {
+    stream: Mutex<R>,
+    /// Position of the data section in the stream.
+    offset: u64,
+    names: Vec<String>,
+    tensors: HashMap<String, TensorInfo>,
+    metadata: Option<HashMap<String, String>>,
+}
+
+impl<R: Read + Seek> StreamReader<R> {
+    /// Same as that of the `safetensors` crate.
+    const MAX_HEADER_SIZE: u64 = 100_000_000;
+
+    /// Read and check the header of the stream.
+    pub fn new(mut stream: R) -> Result<Self, SafeTensorError> {
+        let end = stream.seek(SeekFrom::End(0))?;
+        stream.seek(SeekFrom::Start(0))?;
+
+        let mut len = [0; 8];
+        stream
+            .read_exact(&mut len)
+            .map_err(|_| SafeTensorError::HeaderTooSmall)?;
+        let len = u64::from_le_bytes(len);
+        if len > Self::MAX_HEADER_SIZE {
+            return Err(SafeTensorError::HeaderTooLarge);
+        }
+        let offset = 8 + len;
+        if offset > end {
+            return Err(SafeTensorError::InvalidHeaderLength);
+        }
+
+        let mut header = vec![0; len as usize];
+        stream.read_exact(&mut header)?;
+        let metadata: Metadata = serde_json::from_slice(&header)
+            .map_err(|_| SafeTensorError::InvalidHeaderDeserialization)?;
+
+        let tensors: HashMap<_, _> = metadata
+            .tensors()
+            .into_iter()
+            .map(|(name, info)| (name, info.clone()))
+            .collect();
+        for (name, info) in &tensors {
+            let (start, stop) = info.data_offsets;
+            let size = info.shape.iter().product::<usize>() * info.dtype.size();
+            if stop < start || offset + stop as u64 > end {
+                return Err(SafeTensorError::InvalidOffset(name.clone()));
+            }
+            if stop - start != size {
+                return Err(SafeTensorError::TensorInvalidInfo);
+            }
+        }
+        let names = tensors
+            .iter()
+            .sorted_by_key(|(_, info)| info.data_offsets)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        Ok(Self {
+            stream: Mutex::new(stream),
+            offset,
+            names,
+            tensors,
+            metadata: metadata.metadata().clone(),
+        })
+    }
+
+    /// The `__metadata__` of the header.
+    pub fn metadata(&self) -> Option<&HashMap<String, String>> {
+        self.metadata.as_ref()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.stream
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn info(&self, name: &str) -> Result<&TensorInfo, SafeTensorError> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| SafeTensorError::TensorNotFound(name.to_string()))
+    }
+}
+
+impl<R: Read + Seek + Send> ReaderSend for StreamReader<R> {
+    #[inline]
+    fn names(&self) -> Vec<&str> {
+        self.names.iter().map(AsRef::as_ref).collect()
+    }
+
+    #[inline]
+    fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    #[inline]
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        Ok(self.info(name)?.shape.clone())
+    }
+
+    async fn tensor(&self, name: &str) -> Result<ReaderTensor<'_>, SafeTensorError> {
+        let info = self.info(name)?;
+        let (start, stop) = info.data_offsets;
+        let mut data = vec![0; stop - start];
+        {
+            let mut stream = self.stream.lock().unwrap_or_else(|err| err.into_inner());
+            stream.seek(SeekFrom::Start(self.offset + start as u64))?;
+            stream.read_exact(&mut data)?;
+        }
+        Ok((info.dtype, info.shape.clone(), data.into()))
+    }
+}
+
 pub trait TensorFromReader<T: Scalar> {
     /// Create a tensor from safetensors reader.
     fn from_reader(reader: ReaderTensor) -> Result<TensorCpu<T>, TensorError>;
@@ -69,11 +226,8 @@ impl<T: Scalar> TensorFromReader<T> for TensorCpu<T> {
         let shape = Shape::from_slice_rev(&shape)?;
         match data {
             Cow::Borrowed(data) => Self::from_data(shape, bytemuck::cast_slice(data)),
-            Cow::Owned(data) => {
-                let data = bytemuck::cast_slice(&data);
-                let data = Cow::Owned(data.to_vec());
-                Self::from_data(shape, data)
-            }
+            // owned data (e.g., read from a stream) may not be aligned to `T`
+            Cow::Owned(data) => Self::from_data(shape, bytemuck::pod_collect_to_vec::<_, T>(&data)),
         }
     }
 }
@@ -87,6 +241,9 @@ pub struct Lora<R> {
     /// A blend pattern is a regex that matches the name of multiple tensors, and a blend factor.
     /// When applying the patterns, they are applied in order.
     pub blend: LoraBlend,
+    /// Whether the matrices of each layer are merged into the weights or applied at runtime.
+    /// Adapters applied at runtime in some layer are numbered after those added as runtime LoRAs, in order.
+    pub placement: LoraPlacement,
 }
 
 /// A list of LoRA blend patterns.
@@ -174,11 +331,174 @@ struct LoraMatrix {
     alpha: f32,
 }
 
+/// Counts of a [`TensorRegistry`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TensorRegistryStats {
+    /// Tensors uploaded and kept in the registry.
+    pub num_tensor: usize,
+    /// Loads served by an already uploaded tensor.
+    pub num_shared: usize,
+    /// Bytes of device memory that shared loads did not take.
+    pub shared_size: usize,
+}
+
+struct SharedTensor {
+    /// A `TensorGpu<T, ReadWrite>`.
+    tensor: Box<dyn Any + Send + Sync>,
+    buffer: ArenaBuffer,
+}
+
+#[derive(Default)]
+struct TensorRegistryInner {
+    tensors: HashMap<u64, SharedTensor>,
+    stats: TensorRegistryStats,
+}
+
+/// Tensors uploaded by loaders, keyed by their contents, so that identical tensors (e.g., tied embed and head,
+/// or layers that fine-tunes of the same base leave untouched) are uploaded once and share their buffers.
+/// A registry can be shared by the builders of several models.
+///
+/// Only tensors uploaded as they are read (i.e., not quantized, and with no LoRA blended in) are shared.
+/// Shared tensors must not be modified in place, so models built with a registry should not be [patched](super::patch::Patch).
+/// The contents are identified by a 64-bit hash, which is not meant to resist crafted collisions.
+#[derive(Default, Clone)]
+pub struct TensorRegistry(Arc<Mutex<TensorRegistryInner>>);
+
+impl std::fmt::Debug for TensorRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TensorRegistry")
+            .field(&self.stats())
+            .finish()
+    }
+}
+
+impl TensorRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn stats(&self) -> TensorRegistryStats {
+        self.lock(|inner| inner.stats).unwrap_or_default()
+    }
+
+    /// Drop the tensors that no model uses anymore.
+    pub fn compact(&self) {
+        self.lock(|inner| {
+            // one reference from the tensor kept, and one from the entry itself
+            inner
+                .tensors
+                .retain(|_, shared| ArenaBuffer::strong_count(&shared.buffer) > 2);
+            inner.stats.num_tensor = inner.tensors.len();
+        });
+    }
+
+    pub fn clear(&self) {
+        self.lock(|inner| {
+            inner.tensors.clear();
+            inner.stats.num_tensor = 0;
+        });
+    }
+
+    fn lock<T>(&self, f: impl FnOnce(&mut TensorRegistryInner) -> T) -> Option<T> {
+        self.0.lock().ok().map(|mut inner| f(&mut inner))
+    }
+
+    /// Hash the contents of a tensor, together with how it is uploaded.
+    fn key(kind: &str, (dt, shape, data): &ReaderTensor) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        kind.hash(&mut hasher);
+        format!("{dt:?}").hash(&mut hasher);
+        shape.hash(&mut hasher);
+        data.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn get<T: Scalar>(&self, key: u64) -> Option<TensorGpu<T, ReadWrite>> {
+        self.lock(|inner| {
+            let tensor = inner
+                .tensors
+                .get(&key)?
+                .tensor
+                .downcast_ref::<TensorGpu<T, ReadWrite>>()?
+                .clone();
+            inner.stats.num_shared += 1;
+            inner.stats.shared_size += tensor.size();
+            Some(tensor)
+        })
+        .flatten()
+    }
+
+    fn insert<T: Scalar>(&self, key: u64, tensor: &TensorGpu<T, ReadWrite>) {
+        self.lock(|inner| {
+            let shared = SharedTensor {
+                tensor: Box::new(tensor.clone()),
+                buffer: tensor.buffer.clone(),
+            };
+            inner.tensors.insert(key, shared);
+            inner.stats.num_tensor = inner.tensors.len();
+        });
+    }
+}
+
 #[derive(Clone)]
 pub struct Loader<R> {
     pub context: Context,
     pub model: R,
     pub lora: Vec<Lora<R>>,
+    pub runtime_lora: Vec<Lora<R>>,
+    /// Where identical tensors are looked up before uploading, if any.
+    pub registry: Option<TensorRegistry>,
+}
+
+/// Load the low-rank factors of one LoRA about the matrix with a given name, if it has them and a pattern matches it.
+/// Only the last matched pattern is loaded. The factor is of adapter 0.
+pub(crate) async fn load_lora_factor<R: Reader>(
+    context: &Context,
+    lora: &Lora<R>,
+    name: &str,
+    target: LoraTarget,
+    discount: f32,
+) -> Result<Option<LoraFactor>> {
+    let Some(blend) = lora
+        .blend
+        .iter()
+        .rfind(|blend| blend.pattern.is_match(name))
+    else {
+        return Ok(None);
+    };
+
+    let name = name.split('.').filter(|x| !x.contains("weight")).join(".");
+    let Ok(x) = lora.data.tensor(&format!("{name}.lora.0")).await else {
+        return Ok(None);
+    };
+    let Ok(y) = lora.data.tensor(&format!("{name}.lora.1")).await else {
+        return Ok(None);
+    };
+
+    let rank = x.1[1];
+    let alpha = blend.alpha;
+    let factor = discount * alpha / rank as f32;
+
+    // transpose the down projection so that it can be used as a matrix of `[C, R]`
+    let x = TensorCpu::<f16>::from_reader(x)?;
+    let [_, num_emb, _, _] = *x.shape();
+    let source = &x.data()[..];
+    let data = (0..rank)
+        .flat_map(|r| (0..num_emb).map(move |index| source[index * rank + r]))
+        .collect_vec();
+    let x = context.tensor_from_data([num_emb, rank, 1, 1], data)?;
+    let y = TensorCpu::<f16>::from_reader(y)?
+        .map(|y| f16::from_f32(factor * y.to_f32()))
+        .transfer_into(context);
+
+    log::info!("matrix (runtime LoRA) {name}, alpha: {alpha}, rank: {rank}");
+    Ok(Some(LoraFactor {
+        adapter: 0,
+        target,
+        rank,
+        x: Matrix::Fp16(x),
+        y: Matrix::Fp16(y),
+    }))
 }
 
 impl<R: Reader> Loader<R> {
@@ -259,6 +579,15 @@ impl<R: Reader> Loader<R> {
     /// Load all lora and blend factors about the vector with a given name.
     /// In each LoRA, only the last matched pattern is loaded.
     async fn lora_vectors(&self, name: impl AsRef<str>) -> Result<Vec<LoraVector>> {
+        self.lora_vectors_trimmed(name, usize::MAX).await
+    }
+
+    /// Load all LoRA vectors about the tensor with a given name, keeping only their first `num_row` rows.
+    async fn lora_vectors_trimmed(
+        &self,
+        name: impl AsRef<str>,
+        num_row: usize,
+    ) -> Result<Vec<LoraVector>> {
         let context = &self.context;
         let name = name.as_ref();
 
@@ -276,7 +605,12 @@ impl<R: Reader> Loader<R> {
             let Ok(tensor) = lora.data.tensor(name).await else {
                 continue;
             };
-            let tensor = TensorCpu::<f16>::from_reader(tensor)?.transfer_into(context);
+            let tensor = TensorCpu::<f16>::from_reader(tensor)?;
+            let tensor = match num_row < tensor.shape()[1] {
+                true => tensor.into_slice(.., ..num_row, .., ..)?,
+                false => tensor,
+            };
+            let tensor = tensor.transfer_into(context);
             let alpha = blend.alpha;
             vectors.push(LoraVector { tensor, alpha });
 
@@ -293,6 +627,9 @@ impl<R: Reader> Loader<R> {
 
         let mut matrices = vec![];
         for lora in self.lora.iter() {
+            if lora.placement.mode_of(name) == LoraMode::Runtime {
+                continue;
+            }
             let Some(blend) = lora
                 .blend
                 .iter()
@@ -321,6 +658,59 @@ impl<R: Reader> Loader<R> {
         Ok(matrices)
     }
 
+    /// Load the low-rank factors of all runtime LoRAs about the matrix with a given name,
+    /// followed by those of LoRAs placed at runtime in the matrix's layer.
+    /// In each LoRA, only the last matched pattern is loaded.
+    pub async fn load_lora_factors(
+        &self,
+        name: impl AsRef<str>,
+        discount: f32,
+    ) -> Result<Vec<LoraFactor>> {
+        let context = &self.context;
+        let name = name.as_ref();
+
+        let Some(target) = LoraTarget::from_name(name) else {
+            return Ok(vec![]);
+        };
+
+        let runtime_lora = self.runtime_lora.iter().map(Some).chain(
+            self.lora
+                .iter()
+                .filter(|lora| lora.placement.has_runtime())
+                .map(|lora| (lora.placement.mode_of(name) == LoraMode::Runtime).then_some(lora)),
+        );
+
+        let mut factors = vec![];
+        for (adapter, lora) in runtime_lora.enumerate() {
+            let Some(lora) = lora else {
+                continue;
+            };
+            if let Some(factor) = load_lora_factor(context, lora, name, target, discount).await? {
+                factors.push(LoraFactor { adapter, ..factor });
+            }
+        }
+        Ok(factors)
+    }
+
+    /// Upload a tensor read from the model, or take an identical one uploaded the same way from the registry.
+    fn upload<'a, T: Scalar>(
+        &self,
+        kind: &str,
+        tensor: ReaderTensor<'a>,
+        upload: impl FnOnce(ReaderTensor<'a>) -> Result<TensorGpu<T, ReadWrite>>,
+    ) -> Result<TensorGpu<T, ReadWrite>> {
+        let Some(registry) = &self.registry else {
+            return upload(tensor);
+        };
+        let key = TensorRegistry::key(kind, &tensor);
+        if let Some(tensor) = registry.get(key) {
+            return Ok(tensor);
+        }
+        let tensor = upload(tensor)?;
+        registry.insert(key, &tensor);
+        Ok(tensor)
+    }
+
     pub fn tensor_shape(&self, name: impl AsRef<str>) -> Result<Shape> {
         let shape = self.model.shape(name.as_ref())?;
         Ok(Shape::from_slice_rev(&shape)?)
@@ -332,14 +722,22 @@ impl<R: Reader> Loader<R> {
     ) -> Result<TensorGpu<f32, ReadWrite>> {
         use TensorDimension::{Auto, Dimension};
         let context = &self.context;
+        let lora = self.lora_vectors(name.as_ref()).await?;
         let tensor = self.model.tensor(name.as_ref()).await?;
-        let tensor: TensorGpu<_, _> = TensorCpu::<f16>::from_reader(tensor)?
-            .map(|x| x.to_f32())
-            .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
-            .transfer_into(context);
+        let upload = |tensor| {
+            let tensor: TensorGpu<_, _> = TensorCpu::<f16>::from_reader(tensor)?
+                .map(|x| x.to_f32())
+                .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
+                .transfer_into(context);
+            Ok(tensor)
+        };
+        if lora.is_empty() {
+            return self.upload("vector_f32", tensor, upload);
+        }
+        let tensor = upload(tensor)?;
 
         let mut ops = vec![];
-        for lora in self.lora_vectors(name).await? {
+        for lora in lora {
             let factor = vec![lora.alpha, 1.0 - lora.alpha, 0.0, 0.0];
             let factor = context.tensor_from_data([4, 1, 1, 1], factor)?;
 
@@ -355,7 +753,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -392,7 +790,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::opposite_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -430,7 +828,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::stable_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -443,9 +841,11 @@ impl<R: Reader> Loader<R> {
         let lora = self.lora_vectors(name.as_ref()).await?;
         let tensor = self.model.tensor(name.as_ref()).await?;
         let tensor = if lora.is_empty() {
-            TensorCpu::from_reader(tensor)?
-                .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
-                .transfer_into(context)
+            self.upload("vector_f16", tensor, |tensor| {
+                Ok(TensorCpu::from_reader(tensor)?
+                    .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
+                    .transfer_into(context))
+            })?
         } else {
             let tensor_f32: TensorGpu<f32, _> = TensorCpu::<f16>::from_reader(tensor)?
                 .map(|x| x.to_f32())
@@ -476,7 +876,7 @@ impl<R: Reader> Loader<R> {
             )?;
             ops.push(op);
 
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             tensor_f16
         };
         Ok(tensor)
@@ -487,30 +887,50 @@ impl<R: Reader> Loader<R> {
         name: impl AsRef<str>,
     ) -> Result<TensorGpu<f16, ReadWrite>> {
         let context = &self.context;
+        let matrices = self.lora_matrices(name.as_ref()).await?;
+        let vectors = self.lora_vectors(name.as_ref()).await?;
         let tensor = self.model.tensor(name.as_ref()).await?;
-        let tensor: TensorGpu<_, _> = TensorCpu::from_reader(tensor)?.transfer_into(context);
+        let upload = |tensor| Ok(TensorCpu::from_reader(tensor)?.transfer_into(context));
+        if matrices.is_empty() && vectors.is_empty() {
+            return self.upload("matrix_f16", tensor, upload);
+        }
+        let tensor: TensorGpu<_, _> = upload(tensor)?;
+        self.blend_matrix_f16(&tensor, matrices, vectors)?;
+        Ok(tensor)
+    }
+
+    /// Blend LoRA matrices and vectors into a matrix, which may keep only the first rows of the original.
+    /// The LoRA vectors must be trimmed to the same rows, see [`Loader::load_matrix_f16_trimmed`].
+    fn blend_matrix_f16(
+        &self,
+        tensor: &TensorGpu<f16, ReadWrite>,
+        matrices: Vec<LoraMatrix>,
+        vectors: Vec<LoraVector>,
+    ) -> Result<()> {
+        let context = &self.context;
+        let num_row = tensor.shape()[1];
 
         let mut ops = vec![];
-        for lora in self.lora_matrices(name.as_ref()).await? {
+        for lora in matrices {
             let factor = vec![lora.alpha / lora.rank as f32, 1.0, 0.0, 0.0];
             let factor = context.tensor_from_data([4, 1, 1, 1], factor)?;
             let op = TensorOp::blend_lora(
                 &factor,
                 lora.x.view(.., .., .., ..)?,
-                lora.y.view(.., .., .., ..)?,
+                lora.y.view(.., ..num_row, .., ..)?,
                 tensor.view(.., .., .., ..)?,
             )?;
             ops.push(op);
         }
-        for lora in self.lora_vectors(name.as_ref()).await? {
+        for lora in vectors {
             let factor = vec![lora.alpha, 1.0, 0.0, 0.0];
             let factor = context.tensor_from_data([4, 1, 1, 1], factor)?;
-            let op = TensorOp::blend(&factor, &lora.tensor, &tensor)?;
+            let op = TensorOp::blend(&factor, &lora.tensor, tensor)?;
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
-        Ok(tensor)
+        context.submit(context.encode(&TensorOp::List(ops)));
+        Ok(())
     }
 
     pub async fn load_matrix_f16_discount(
@@ -543,7 +963,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -576,7 +996,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
@@ -614,22 +1034,30 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
     pub async fn load_embed(&self) -> Result<TensorCpu<f16>> {
+        self.load_embed_trimmed(usize::MAX).await
+    }
+
+    /// Load the embed and keep only its first `num_vocab` rows.
+    pub async fn load_embed_trimmed(&self, num_vocab: usize) -> Result<TensorCpu<f16>> {
         let context = &self.context;
         let name = "emb.weight";
 
-        let (dt, shape, tensor) = self.model.tensor(name).await?;
-        let lora = self.lora_vectors(name).await?;
+        let tensor = TensorCpu::from_reader(self.model.tensor(name).await?)?;
+        let tensor = match num_vocab < tensor.shape()[1] {
+            true => tensor.into_slice(.., ..num_vocab, .., ..)?,
+            false => tensor,
+        };
+        let lora = self.lora_vectors_trimmed(name, num_vocab).await?;
 
         if lora.is_empty() {
-            let tensor = TensorCpu::from_reader((dt, shape, tensor))?;
             Ok(tensor)
         } else {
-            let tensor = TensorCpu::from_reader((dt, shape, tensor))?.transfer_into(context);
+            let tensor = tensor.transfer_into(context);
             let mut ops = vec![];
             for lora in lora {
                 let factor = vec![lora.alpha, 1.0, 0.0, 0.0];
@@ -637,17 +1065,44 @@ impl<R: Reader> Loader<R> {
                 let op = TensorOp::blend(&factor, &lora.tensor, &tensor)?;
                 ops.push(op);
             }
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             Ok(tensor.back().await)
         }
     }
 
+    /// Load a matrix and keep only its first `num_row` rows, e.g., to drop the padded tail of the vocabulary.
+    /// The rows are dropped before uploading, so that the whole matrix never takes device memory.
+    pub async fn load_matrix_f16_trimmed(
+        &self,
+        name: impl AsRef<str>,
+        num_row: usize,
+    ) -> Result<TensorGpu<f16, ReadWrite>> {
+        let context = &self.context;
+        let name = name.as_ref();
+        if num_row >= self.tensor_shape(name)?[1] {
+            return self.load_matrix_f16(name).await;
+        }
+
+        let tensor = self.model.tensor(name).await?;
+        let tensor: TensorGpu<_, _> = TensorCpu::<f16>::from_reader(tensor)?
+            .into_slice(.., ..num_row, .., ..)?
+            .transfer_into(context);
+
+        let matrices = self.lora_matrices(name).await?;
+        let vectors = self.lora_vectors_trimmed(name, num_row).await?;
+        self.blend_matrix_f16(&tensor, matrices, vectors)?;
+        Ok(tensor)
+    }
+
     pub async fn load_head(&self, chunk_size: usize) -> Result<Vec<TensorGpu<f16, ReadWrite>>> {
         let context = &self.context;
         let (_, shape, tensor) = self.model.tensor("head.weight").await?;
         let shape = Shape::new(shape[1], shape[0], 1, 1);
         let chunks = (shape[1] + chunk_size - 1) / chunk_size;
-        let data = bytemuck::cast_slice(&tensor);
+        let data: Cow<[f16]> = match &tensor {
+            Cow::Borrowed(data) => Cow::Borrowed(bytemuck::cast_slice(data)),
+            Cow::Owned(data) => Cow::Owned(bytemuck::pod_collect_to_vec(data)),
+        };
 
         let head = (0..chunks)
             .map(|chunk| {
@@ -660,6 +1115,66 @@ impl<R: Reader> Loader<R> {
         Ok(head)
     }
 
+    /// Upload a matrix the model stores pre-quantized in `format` without re-quantizing it.
+    /// Returns `None` if it is stored otherwise, or if any LoRA may blend into it.
+    async fn load_matrix_kquant(
+        &self,
+        name: &str,
+        format: KQuantFormat,
+        discount: f32,
+    ) -> Result<Option<Matrix>> {
+        let context = &self.context;
+        let lora = self.lora.iter().any(|lora| {
+            lora.placement.mode_of(name) != LoraMode::Runtime
+                && lora.blend.iter().any(|blend| blend.pattern.is_match(name))
+        });
+        if lora {
+            return Ok(None);
+        }
+        let Some(tensor) = self.model.kquant(name).await? else {
+            return Ok(None);
+        };
+        if tensor.format != format {
+            return Ok(None);
+        }
+
+        let shape = Shape::from_slice_rev(&tensor.shape)?;
+        let [k, m, b, _] = [shape[0], shape[1], shape[2], shape[3]];
+        let w = context.tensor_from_data([k / 2, m, b, 1], tensor.w)?;
+        let s = context.tensor_from_data(
+            [(k << 1) / TensorOp::KQUANT_BLOCK_SIZE as usize, m, b, 1],
+            tensor.s,
+        )?;
+        let factors = tensor
+            .m
+            .into_iter()
+            .map(|x| f16::from_f32(x.to_f32() * discount))
+            .collect_vec();
+        let factors = context.tensor_from_data(
+            [
+                (k << 1) / TensorOp::KQUANT_SUPER_BLOCK_SIZE as usize,
+                m,
+                b,
+                1,
+            ],
+            factors,
+        )?;
+        let matrix = match format {
+            KQuantFormat::Q4K => Matrix::Q4K { w, s, m: factors },
+            KQuantFormat::Q5K => {
+                let h = context.tensor_from_data([k / 8, m, b, 1], tensor.h)?;
+                Matrix::Q5K {
+                    w,
+                    h,
+                    s,
+                    m: factors,
+                }
+            }
+        };
+        log::info!("matrix (pre-quantized) {name}, format: {format}");
+        Ok(Some(matrix))
+    }
+
     pub async fn load_matrix(&self, name: String, quant: Quant) -> Result<Matrix> {
         let context = &self.context;
         match quant {
@@ -676,6 +1191,29 @@ impl<R: Reader> Loader<R> {
                 self.load_in_place_matrix_f16(&buffer, &name).await?;
                 Ok(Matrix::quant_nf4(&buffer)?)
             }
+            Quant::Fp8E4M3 | Quant::Fp8E5M2 => {
+                let shape = self.tensor_shape(&name)?;
+                let buffer = context.tensor_init(shape);
+                self.load_in_place_matrix_f16(&buffer, &name).await?;
+                let format = match quant {
+                    Quant::Fp8E5M2 => Fp8Format::E5M2,
+                    _ => Fp8Format::E4M3,
+                };
+                Ok(Matrix::quant_fp8(&buffer, format)?)
+            }
+            Quant::Q4K | Quant::Q5K => {
+                let format = match quant {
+                    Quant::Q5K => KQuantFormat::Q5K,
+                    _ => KQuantFormat::Q4K,
+                };
+                if let Some(matrix) = self.load_matrix_kquant(&name, format, 1.0).await? {
+                    return Ok(matrix);
+                }
+                let shape = self.tensor_shape(&name)?;
+                let buffer = context.tensor_init(shape);
+                self.load_in_place_matrix_f16(&buffer, &name).await?;
+                Ok(Matrix::quant_kquant(&buffer, format)?)
+            }
         }
     }
 
@@ -704,6 +1242,110 @@ impl<R: Reader> Loader<R> {
                     .await?;
                 Ok(Matrix::quant_nf4(&buffer)?)
             }
+            Quant::Fp8E4M3 | Quant::Fp8E5M2 => {
+                let shape = self.tensor_shape(&name)?;
+                let buffer = context.tensor_init(shape);
+                self.load_in_place_matrix_f16_discount(&buffer, &name, discount)
+                    .await?;
+                let format = match quant {
+                    Quant::Fp8E5M2 => Fp8Format::E5M2,
+                    _ => Fp8Format::E4M3,
+                };
+                Ok(Matrix::quant_fp8(&buffer, format)?)
+            }
+            Quant::Q4K | Quant::Q5K => {
+                let format = match quant {
+                    Quant::Q5K => KQuantFormat::Q5K,
+                    _ => KQuantFormat::Q4K,
+                };
+                if let Some(matrix) = self.load_matrix_kquant(&name, format, discount).await? {
+                    return Ok(matrix);
+                }
+                let shape = self.tensor_shape(&name)?;
+                let buffer = context.tensor_init(shape);
+                self.load_in_place_matrix_f16_discount(&buffer, &name, discount)
+                    .await?;
+                Ok(Matrix::quant_kquant(&buffer, format)?)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::TensorRegistry;
+    use crate::runtime::{
+        infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+        model::{Build, EmbedDevice, ModelBuilder, ModelVersion},
+        tiny::{
+            tests::{create_context, prompts},
+            TinyModel,
+        },
+        v6, JobRuntime,
+    };
+
+    #[test]
+    fn test_tensor_registry() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let mut model = TinyModel::new(info.clone(), 42);
+        let embed = model.tensors["emb.weight"].clone();
+        model.tensors.insert("head.weight".into(), embed);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let prompt = prompts(&info).swap_remove(1);
+            let infer = |model: v6::Model| async {
+                let runtime = JobRuntime::new(v6::ModelRuntime::<f32>::new(model, 1)).await;
+                let batch = InferInputBatch {
+                    tokens: prompt.clone().into(),
+                    option: InferOption::Last,
+                    ..Default::default()
+                };
+                let mut input = InferInput::new(vec![batch], 32);
+                let mut output = vec![];
+                while input.num_token() > 0 {
+                    let (next, InferOutput(batches)) = runtime.infer(input).await;
+                    input = next;
+                    output.extend(batches[0].0.to_vec());
+                }
+                output
+            };
+            let builder =
+                || ModelBuilder::new(&context, model.clone()).embed_device(EmbedDevice::Gpu);
+
+            let registry = TensorRegistry::new();
+            let first = Build::<v6::Model>::build(builder().registry(registry.clone())).await?;
+            // the head is tied to the embed
+            let stats = registry.stats();
+            assert_eq!(stats.num_shared, 1);
+            assert_eq!(stats.shared_size, info.head_buffer_size());
+
+            // the second model uploads nothing that the first one has
+            let second = Build::<v6::Model>::build(builder().registry(registry.clone())).await?;
+            assert_eq!(registry.stats().num_tensor, stats.num_tensor);
+            assert_eq!(registry.stats().num_shared, stats.num_tensor + 2);
+
+            // temporaries merged into other tensors while loading are released
+            registry.compact();
+            let num_tensor = registry.stats().num_tensor;
+            assert!(num_tensor > 0 && num_tensor < stats.num_tensor);
+            drop(first);
+            registry.compact();
+            assert_eq!(registry.stats().num_tensor, num_tensor);
+            drop(second);
+            registry.compact();
+            assert_eq!(registry.stats().num_tensor, 0);
+
+            let shared = builder().registry(TensorRegistry::new());
+            let output = infer(Build::<v6::Model>::build(shared).await?).await;
+            let expected = infer(Build::<v6::Model>::build(builder()).await?).await;
+            assert_eq!(output, expected);
+            Ok(())
+        })
+    }
+}
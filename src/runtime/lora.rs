@@ -0,0 +1,712 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use web_rwkv_derive::DeserializeSeed;
+
+use super::model::Quant;
+use crate::{
+    context::Context,
+    impl_deserialize_seed,
+    num::Float,
+    tensor::{
+        kind::ReadWrite,
+        matrix::Matrix,
+        ops::{Activation, Epilogue, LoraDelta, TensorOp},
+        TensorCpu, TensorError, TensorGpu, TensorGpuView, TensorInit, TensorShape,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum LoraError {
+    #[error("adapter {adapter} out of range of max {max}")]
+    AdapterOutOfRange { adapter: usize, max: usize },
+    #[error("adapter {0} not attached")]
+    NotAttached(usize),
+}
+
+/// The matrix in a layer that a runtime LoRA applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LoraTarget {
+    AttKey,
+    AttValue,
+    AttReceptance,
+    AttGate,
+    AttOutput,
+    FfnKey,
+    FfnValue,
+    FfnReceptance,
+}
+
+impl_deserialize_seed!(LoraTarget);
+
+impl LoraTarget {
+    /// Find the target from a matrix name, e.g., `blocks.0.att.key.weight`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let mut iter = name.split('.').skip(2);
+        match (iter.next(), iter.next()) {
+            (Some("att"), Some("key")) => Some(Self::AttKey),
+            (Some("att"), Some("value")) => Some(Self::AttValue),
+            (Some("att"), Some("receptance")) => Some(Self::AttReceptance),
+            (Some("att"), Some("gate")) => Some(Self::AttGate),
+            (Some("att"), Some("output")) => Some(Self::AttOutput),
+            (Some("ffn"), Some("key")) => Some(Self::FfnKey),
+            (Some("ffn"), Some("value")) => Some(Self::FfnValue),
+            (Some("ffn"), Some("receptance")) => Some(Self::FfnReceptance),
+            _ => None,
+        }
+    }
+}
+
+/// Where the matrices of a LoRA are applied in a layer, relative to quantization.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LoraMode {
+    /// Blend into the weights when loading, before they are quantized.
+    /// This has the highest fidelity, but each matrix is loaded in fp16 and blended first.
+    #[default]
+    Merge,
+    /// Keep the low-rank factors apart and apply them after the (quantized) matrix on every run.
+    /// Loading is faster, but each run costs extra low-rank matmuls. See [`LoraFactor`].
+    Runtime,
+}
+
+/// How a [`Lora`](super::loader::Lora) is applied in each layer. By default it is merged into all layers.
+///
+/// Only matrices can be applied at runtime; vectors of the LoRA are always merged.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoraPlacement {
+    /// Mode of the layers not listed in `layers`.
+    pub default: LoraMode,
+    pub layers: HashMap<usize, LoraMode>,
+}
+
+impl LoraPlacement {
+    pub fn new(default: LoraMode) -> Self {
+        Self {
+            default,
+            layers: Default::default(),
+        }
+    }
+
+    /// Set the mode of one layer.
+    pub fn layer(mut self, layer: usize, mode: LoraMode) -> Self {
+        self.layers.insert(layer, mode);
+        self
+    }
+
+    /// Apply at runtime in layers that are quantized, and merge in the others.
+    /// This keeps loading fast where quantization is chosen, at no runtime cost in full precision layers.
+    pub fn quantized_at_runtime(quant: &HashMap<usize, Quant>) -> Self {
+        quant
+            .iter()
+            .filter(|(_, &quant)| quant != Quant::None)
+            .fold(Self::default(), |placement, (&layer, _)| {
+                placement.layer(layer, LoraMode::Runtime)
+            })
+    }
+
+    pub fn mode(&self, layer: usize) -> LoraMode {
+        self.layers.get(&layer).copied().unwrap_or(self.default)
+    }
+
+    /// Mode of a tensor with the given name. Tensors outside of blocks (e.g., the head) are always merged.
+    pub fn mode_of(&self, name: &str) -> LoraMode {
+        let layer = name
+            .strip_prefix("blocks.")
+            .and_then(|name| name.split('.').next())
+            .and_then(|layer| layer.parse().ok());
+        match layer {
+            Some(layer) => self.mode(layer),
+            None => LoraMode::Merge,
+        }
+    }
+
+    /// If any layer applies the LoRA at runtime.
+    pub fn has_runtime(&self) -> bool {
+        self.default == LoraMode::Runtime
+            || self.layers.values().any(|&mode| mode == LoraMode::Runtime)
+    }
+}
+
+/// Low-rank factors of a runtime LoRA that stay resident on GPU.
+/// Instead of being blended into the weights, they are applied to the output of the target matrix on every run.
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct LoraFactor {
+    /// Index of the runtime LoRA this factor belongs to.
+    pub adapter: usize,
+    pub target: LoraTarget,
+    pub rank: usize,
+    /// Down projection, `[C, R]`.
+    pub x: Matrix,
+    /// Up projection with the blend factor folded in, `[R, C']`.
+    pub y: Matrix,
+}
+
+type HiddenMap = HashMap<[usize; 2], TensorGpu<f32, ReadWrite>>;
+
+/// Per-batch scaling factors of runtime LoRAs.
+/// Changing them takes effect from the next submitted job, without touching any weights.
+#[derive(Debug, Clone)]
+pub struct LoraAlpha {
+    pub context: Context,
+    /// One `[1, 1, B]` tensor for each adapter.
+    pub data: Vec<TensorGpu<f32, ReadWrite>>,
+    /// The down projections `[r, T]` of each rank and number of tokens, allocated once and reused by all jobs.
+    hidden: Arc<Mutex<HiddenMap>>,
+}
+
+impl LoraAlpha {
+    pub fn new(context: &Context, num_adapter: usize, num_batch: usize) -> Self {
+        let data = (0..num_adapter)
+            .map(|_| context.ones([1, 1, num_batch, 1]))
+            .collect();
+        Self {
+            context: context.clone(),
+            data,
+            hidden: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn num_adapter(&self) -> usize {
+        self.data.len()
+    }
+
+    #[inline]
+    pub fn num_batch(&self) -> usize {
+        self.data.first().map(|x| x.shape()[2]).unwrap_or_default()
+    }
+
+    fn check_adapter(&self, adapter: usize) -> Result<&TensorGpu<f32, ReadWrite>> {
+        self.data.get(adapter).ok_or_else(|| {
+            LoraError::AdapterOutOfRange {
+                adapter,
+                max: self.num_adapter(),
+            }
+            .into()
+        })
+    }
+
+    /// Set the alpha of an adapter for one batch.
+    pub fn set(&self, adapter: usize, batch: usize, alpha: f32) -> Result<()> {
+        let tensor = TensorCpu::from_data([1, 1, 1, 1], vec![alpha])?;
+        self.check_adapter(adapter)?.load_batch(&tensor, batch)?;
+        Ok(())
+    }
+
+    /// Set the alpha of an adapter for all batches.
+    pub fn load(&self, adapter: usize, alpha: &[f32]) -> Result<()> {
+        let tensor = TensorCpu::from_data([1, 1, alpha.len(), 1], alpha.to_vec())?;
+        self.check_adapter(adapter)?.load(&tensor)?;
+        Ok(())
+    }
+
+    /// Copy the alphas onto another context.
+    pub async fn migrate(&self, context: &Context) -> Result<Self> {
+        let mut data = vec![];
+        for tensor in &self.data {
+            let tensor = tensor.back().await;
+            data.push(context.tensor_from_data(tensor.shape(), tensor.to_vec())?);
+        }
+        Ok(Self {
+            context: context.clone(),
+            data,
+            hidden: Default::default(),
+        })
+    }
+
+    /// The down projection of `num_token` tokens by a factor of `rank`.
+    /// Ops run in the order they are submitted, so matmuls and jobs can share it one after another.
+    fn hidden(&self, rank: usize, num_token: usize) -> TensorGpu<f32, ReadWrite> {
+        let mut hidden = self
+            .hidden
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        hidden
+            .entry([rank, num_token])
+            .or_insert_with(|| self.context.tensor_init([rank, num_token, 1, 1]))
+            .clone()
+    }
+
+    /// Matrix multiplication with the contributions of runtime LoRAs on `target` added.
+    /// The bias is added with the base product, and the activation is applied after the contributions are added,
+    /// each of which is accumulated into the output in the same pass as its up projection.
+    /// Without `turbo`, the first contribution is added in the pass of the base product instead,
+    /// which lets LoRAs apply to quantized matrices, see [`Matrix::matmul_vec_lora_op`].
+    /// - `input` shape: `[C, A, 1]`.
+    /// - `output` shape: `[C', A, 1]`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn matmul_op<'a, F: Float, G: Float>(
+        &self,
+        matrix: &Matrix,
+        factors: &[LoraFactor],
+        target: LoraTarget,
+        cursors: &TensorGpu<u32, ReadWrite>,
+        input: TensorGpuView<F>,
+        output: &TensorGpu<G, ReadWrite>,
+        epilogue: impl Into<Epilogue<'a>>,
+        turbo: bool,
+    ) -> Result<TensorOp, TensorError> {
+        let num_token = output.shape()[1];
+        let epilogue = epilogue.into();
+
+        let factors = factors
+            .iter()
+            .filter(|factor| factor.target == target)
+            .filter_map(|factor| Some((factor, self.data.get(factor.adapter)?)))
+            .collect_vec();
+        if factors.is_empty() {
+            return matrix.matmul_op(input, output.view(.., .., .., ..)?, epilogue, turbo);
+        }
+
+        let fused = !turbo && matches!(factors[0].0.y, Matrix::Fp16(_));
+        let mut ops = vec![];
+        if !fused {
+            ops.push(matrix.matmul_op(
+                input.clone(),
+                output.view(.., .., .., ..)?,
+                Epilogue {
+                    activation: Activation::None,
+                    ..epilogue
+                },
+                turbo,
+            )?);
+        }
+
+        let num_factor = factors.len();
+        for (index, (factor, alpha)) in factors.into_iter().enumerate() {
+            let activation = match index + 1 == num_factor {
+                true => epilogue.activation,
+                false => Activation::None,
+            };
+            if let (true, 0, Matrix::Fp16(y)) = (fused, index, &factor.y) {
+                let hidden = self.hidden(factor.rank, num_token);
+                let delta = LoraDelta {
+                    factor: y,
+                    hidden: &hidden,
+                };
+                ops.append(&mut vec![
+                    factor.x.matmul_vec_op(
+                        input.clone(),
+                        hidden.view(.., .., .., ..)?,
+                        Activation::None,
+                    )?,
+                    TensorOp::scale_batch(cursors, alpha, &hidden)?,
+                    matrix.matmul_vec_lora_op(
+                        input.clone(),
+                        output.view(.., .., .., ..)?,
+                        Epilogue {
+                            activation,
+                            ..epilogue
+                        },
+                        delta,
+                    )?,
+                ]);
+                continue;
+            }
+
+            let hidden = self.hidden(factor.rank, num_token);
+            ops.append(&mut vec![
+                factor.x.matmul_op(
+                    input.clone(),
+                    hidden.view(.., .., .., ..)?,
+                    Activation::None,
+                    turbo,
+                )?,
+                TensorOp::scale_batch(cursors, alpha, &hidden)?,
+                factor.y.matmul_op(
+                    hidden.view(.., .., .., ..)?,
+                    output.view(.., .., .., ..)?,
+                    Epilogue::new(activation).accumulate(true),
+                    turbo,
+                )?,
+            ]);
+        }
+        Ok(TensorOp::List(ops))
+    }
+}
+
+/// The low-rank factors of a LoRA in each layer of a model, to be attached to its runtimes, see [`LoraAdapters`].
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct LoraAdapter {
+    /// Factors of each layer, in the order of layers.
+    pub layers: Vec<Vec<LoraFactor>>,
+}
+
+/// Runtime LoRAs attached to the runtimes of a loaded model, which come and go without reloading it.
+///
+/// Attach to a runtime with `ModelRuntime::lora_adapters`; clones share the adapters, so a server can keep one
+/// to attach, detach and select adapters while the runtime is running. The factors of an adapter are applied like
+/// those of a runtime LoRA of the model, scaled by a per-batch alpha that is zero until the adapter is selected.
+///
+/// Alphas take effect from the next submitted job. Attaching and detaching take effect from the next job built;
+/// to drop jobs the runtime has built ahead, [reconfigure](super::JobRuntime::reconfigure) it afterwards.
+#[derive(Debug, Clone)]
+pub struct LoraAdapters {
+    pub context: Context,
+    num_batch: usize,
+    #[allow(clippy::type_complexity)]
+    slots: Arc<RwLock<Vec<Option<(LoraAdapter, TensorGpu<f32, ReadWrite>)>>>>,
+}
+
+impl LoraAdapters {
+    pub fn new(context: &Context, num_batch: usize) -> Self {
+        Self {
+            context: context.clone(),
+            num_batch,
+            slots: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn num_batch(&self) -> usize {
+        self.num_batch
+    }
+
+    /// Indices of the attached adapters.
+    pub fn attached(&self) -> Vec<usize> {
+        let slots = self.slots.read().expect("failed to lock adapters");
+        slots.iter().positions(|slot| slot.is_some()).collect()
+    }
+
+    /// Attach an adapter, selected by no batch yet, and return its index.
+    /// Indices of detached adapters are reused.
+    pub fn attach(&self, adapter: LoraAdapter) -> usize {
+        let alpha = self.context.zeros([1, 1, self.num_batch, 1]);
+        let mut slots = self.slots.write().expect("failed to lock adapters");
+        match slots.iter().position(|slot| slot.is_none()) {
+            Some(index) => {
+                slots[index] = Some((adapter, alpha));
+                index
+            }
+            None => {
+                slots.push(Some((adapter, alpha)));
+                slots.len() - 1
+            }
+        }
+    }
+
+    /// Detach an adapter, returning it so that it can be attached again later.
+    pub fn detach(&self, adapter: usize) -> Result<LoraAdapter> {
+        let mut slots = self.slots.write().expect("failed to lock adapters");
+        let (adapter, _) = slots
+            .get_mut(adapter)
+            .and_then(Option::take)
+            .ok_or(LoraError::NotAttached(adapter))?;
+        Ok(adapter)
+    }
+
+    fn alpha(&self, adapter: usize) -> Result<TensorGpu<f32, ReadWrite>> {
+        let slots = self.slots.read().expect("failed to lock adapters");
+        match slots.get(adapter) {
+            Some(Some((_, alpha))) => Ok(alpha.clone()),
+            _ => Err(LoraError::NotAttached(adapter).into()),
+        }
+    }
+
+    /// Set the alpha of an adapter for one batch.
+    pub fn set(&self, adapter: usize, batch: usize, alpha: f32) -> Result<()> {
+        let tensor = TensorCpu::from_data([1, 1, 1, 1], vec![alpha])?;
+        self.alpha(adapter)?.load_batch(&tensor, batch)?;
+        Ok(())
+    }
+
+    /// Apply one adapter in full to a batch and none of the others, e.g., the adapter of the request it serves.
+    pub fn select(&self, batch: usize, adapter: Option<usize>) -> Result<()> {
+        if let Some(adapter) = adapter {
+            self.alpha(adapter)?;
+        }
+        for index in self.attached() {
+            let alpha = match Some(index) == adapter {
+                true => 1.0,
+                false => 0.0,
+            };
+            self.set(index, batch, alpha)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the attached adapters and their alphas onto another context, keeping their indices.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn migrate(&self, context: &Context) -> Result<Self> {
+        let slots = self.slots.read().expect("failed to lock adapters").clone();
+        let mut data = vec![];
+        for slot in slots {
+            let slot = match slot {
+                Some((adapter, alpha)) => {
+                    let adapter = super::model::migrate::<LoraAdapter>(&adapter, context).await?;
+                    let alpha = alpha.back().await;
+                    let alpha = context.tensor_from_data(alpha.shape(), alpha.to_vec())?;
+                    Some((adapter, alpha))
+                }
+                None => None,
+            };
+            data.push(slot);
+        }
+        Ok(Self {
+            context: context.clone(),
+            num_batch: self.num_batch,
+            slots: Arc::new(RwLock::new(data)),
+        })
+    }
+
+    /// The alphas of `lora` followed by those of the attached adapters,
+    /// and the factors of the attached adapters in each layer, numbered after the adapters of `lora`.
+    pub(crate) fn merge(&self, lora: &LoraAlpha) -> (LoraAlpha, Vec<Vec<LoraFactor>>) {
+        let slots = self.slots.read().expect("failed to lock adapters");
+        let mut data = lora.data.clone();
+        let mut layers: Vec<Vec<LoraFactor>> = vec![];
+        for (adapter, alpha) in slots.iter().flatten() {
+            let index = data.len();
+            data.push(alpha.clone());
+            for (layer, factors) in adapter.layers.iter().enumerate() {
+                if layers.len() <= layer {
+                    layers.resize(layer + 1, vec![]);
+                }
+                layers[layer].extend(factors.iter().cloned().map(|factor| LoraFactor {
+                    adapter: index,
+                    ..factor
+                }));
+            }
+        }
+        let lora = LoraAlpha {
+            data,
+            ..lora.clone()
+        };
+        (lora, layers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use half::f16;
+    use itertools::Itertools;
+    use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+
+    use super::{LoraAdapters, LoraError, LoraMode, LoraPlacement};
+    use crate::runtime::{
+        loader::{Lora, LoraBlend},
+        model::{Build, ModelBuilder, ModelRuntime, ModelVersion, Quant},
+        tiny::{
+            tests::{create_context, infer_steps, prompts},
+            TinyModel,
+        },
+        v5, JobRuntime,
+    };
+
+    #[test]
+    fn test_lora_adapters() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+
+            // low-rank deltas on the output matrices of all layers, which are discounted deeper in the model
+            let rank = 4;
+            let mut rng = fastrand::Rng::with_seed(7);
+            let tensors = (0..info.num_layer)
+                .flat_map(|layer| {
+                    [0, 1].map(|index| format!("blocks.{layer}.att.output.lora.{index}"))
+                })
+                .map(|name| {
+                    let data = (0..info.num_emb * rank)
+                        .map(|_| f16::from_f32(rng.f32() - 0.5))
+                        .collect_vec();
+                    (name, data)
+                })
+                .collect_vec();
+            let views = tensors
+                .iter()
+                .map(|(name, data)| {
+                    let data = bytemuck::cast_slice(data);
+                    let view = TensorView::new(Dtype::F16, vec![info.num_emb, rank], data)?;
+                    Ok((name, view))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let data = safetensors::serialize(views, &None)?;
+            let model = TinyModel::new(info.clone(), 42).serialize()?;
+            let lora = || -> Result<_> {
+                Ok(Lora {
+                    data: SafeTensors::deserialize(&data)?,
+                    blend: LoraBlend::full(1.0),
+                    placement: Default::default(),
+                })
+            };
+            let prompt = prompts(&info).swap_remove(1);
+            let steps = [3, 1, 4];
+
+            // the same LoRA loaded with the model as a runtime LoRA
+            let builder = ModelBuilder::new(&context, SafeTensors::deserialize(&model)?);
+            let expected = Build::<v5::Model>::build(builder.runtime_lora(lora()?)).await?;
+            let expected = v5::ModelRuntime::<f32>::new(expected, 2);
+            let expected = infer_steps(JobRuntime::new(expected).await, &prompt, &steps).await;
+
+            let builder = ModelBuilder::new(&context, SafeTensors::deserialize(&model)?);
+            let model = Build::<v5::Model>::build(builder).await?;
+            let base = v5::ModelRuntime::<f32>::new(model.clone(), 2);
+            let base = infer_steps(JobRuntime::new(base).await, &prompt, &steps).await;
+
+            // each run starts from a fresh state, with the adapters shared by all runtimes
+            let adapters = LoraAdapters::new(&context, 2);
+            let infer = || async {
+                let runtime = v5::ModelRuntime::<f32>::new(model.clone(), 2)
+                    .lora_adapters(Some(adapters.clone()));
+                infer_steps(JobRuntime::new(runtime).await, &prompt, &steps).await
+            };
+            let adapter = model.load_lora(&lora()?).await?;
+            assert_eq!(adapter.layers.len(), info.num_layer);
+
+            // attached adapters apply to no batch until selected, and only to the batches that select them
+            let index = adapters.attach(adapter);
+            assert_eq!(adapters.attached(), vec![index]);
+            let output = infer().await;
+            assert_eq!(output, base);
+
+            // compare the last step, where both batches output in the same run
+            adapters.select(0, Some(index))?;
+            let output = infer().await;
+            let [output, expected, base] = [&output, &expected, &base].map(|x| &x[steps.len()]);
+            let num_vocab = info.num_vocab;
+            for (x, y) in output[..num_vocab].iter().zip_eq(&expected[..num_vocab]) {
+                assert!((x - y).abs() < 1.0e-3, "{x} vs {y}");
+            }
+            assert_eq!(output[num_vocab..], base[num_vocab..]);
+            assert!(output
+                .iter()
+                .zip_eq(base)
+                .any(|(x, y)| (x - y).abs() > 1.0e-2));
+
+            // a detached adapter leaves the model as it was, and can be attached again
+            let adapter = adapters.detach(index)?;
+            assert!(adapters.attached().is_empty());
+            assert_eq!(&infer().await[steps.len()], base);
+            let err = adapters.select(0, Some(index)).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<LoraError>(),
+                Some(&LoraError::NotAttached(index))
+            );
+            assert!(adapters.detach(index).is_err());
+            assert_eq!(adapters.attach(adapter), index);
+
+            // a migrated runtime takes the attached adapters along, selected as they were
+            adapters.select(0, Some(index))?;
+            let other = create_context(&info).await?;
+            let runtime = v5::ModelRuntime::<f32>::new(model.clone(), 2)
+                .lora_adapters(Some(adapters.clone()))
+                .migrate(&other)
+                .await?;
+            assert_eq!(
+                runtime.adapters().map(LoraAdapters::attached),
+                Some(vec![index])
+            );
+            let output = infer_steps(JobRuntime::new(runtime).await, &prompt, &steps).await;
+            let expected = infer().await;
+            for (x, y) in output.iter().flatten().zip_eq(expected.iter().flatten()) {
+                assert!((x - y).abs() < 1.0e-4, "{x} vs {y}");
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_lora_placement() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+
+            // low-rank deltas on the key matrices of all layers
+            let rank = 4;
+            let mut rng = fastrand::Rng::with_seed(42);
+            let tensors = (0..info.num_layer)
+                .flat_map(|layer| {
+                    [0, 1].map(|index| format!("blocks.{layer}.att.key.lora.{index}"))
+                })
+                .map(|name| {
+                    let data = (0..info.num_emb * rank)
+                        .map(|_| f16::from_f32(rng.f32() - 0.5))
+                        .collect_vec();
+                    (name, data)
+                })
+                .collect_vec();
+            let views = tensors
+                .iter()
+                .map(|(name, data)| {
+                    let data = bytemuck::cast_slice(data);
+                    let view = TensorView::new(Dtype::F16, vec![info.num_emb, rank], data)?;
+                    Ok((name, view))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let data = safetensors::serialize(views, &None)?;
+            let model = TinyModel::new(info.clone(), 42).serialize()?;
+
+            let prompt = prompts(&info).swap_remove(1);
+            let infer = |placement: Option<LoraPlacement>, quant: Quant| {
+                let context = context.clone();
+                let (data, model) = (&data, &model);
+                let prompt = &prompt;
+                let quant = (0..info.num_layer).map(|layer| (layer, quant)).collect();
+                async move {
+                    let builder = ModelBuilder::new(&context, SafeTensors::deserialize(model)?);
+                    let builder = builder.quant(quant);
+                    let builder = match placement {
+                        Some(placement) => builder.lora(Lora {
+                            data: SafeTensors::deserialize(data)?,
+                            blend: LoraBlend::full(1.0),
+                            placement,
+                        }),
+                        None => builder,
+                    };
+                    let model = Build::<v5::Model>::build(builder).await?;
+                    let runtime = v5::ModelRuntime::<f32>::new(model, 2);
+                    let num_adapter = runtime.lora().map_or(0, |lora| lora.num_adapter());
+                    let output = infer_steps(JobRuntime::new(runtime).await, prompt, &[]).await;
+                    anyhow::Ok((output.concat(), num_adapter))
+                }
+            };
+
+            let (base, _) = infer(None, Quant::None).await?;
+            let (merged, num_adapter) = infer(Some(LoraPlacement::default()), Quant::None).await?;
+            assert_eq!(num_adapter, 0);
+            assert!(merged
+                .iter()
+                .zip_eq(&base)
+                .any(|(x, y)| (x - y).abs() > 1.0e-2));
+
+            let placements = [
+                LoraPlacement::new(LoraMode::Runtime),
+                LoraPlacement::default().layer(1, LoraMode::Runtime),
+            ];
+            for placement in placements {
+                let (output, num_adapter) = infer(Some(placement), Quant::None).await?;
+                assert_eq!(num_adapter, 1);
+                for (x, y) in output.iter().zip_eq(&merged) {
+                    assert!((x - y).abs() < 1.0e-2, "{x} vs {y}");
+                }
+            }
+
+            // runtime LoRAs also apply to quantized matrices, adding their deltas in the kernels of the formats
+            let (base, _) = infer(None, Quant::Int8).await?;
+            let placement = LoraPlacement::new(LoraMode::Runtime);
+            let (output, _) = infer(Some(placement), Quant::Int8).await?;
+            assert!(output
+                .iter()
+                .zip_eq(&base)
+                .any(|(x, y)| (x - y).abs() > 1.0e-2));
+            for (x, y) in output.iter().zip_eq(&merged) {
+                assert!((x - y).abs() < 1.0e-2, "{x} vs {y}");
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    event::Event,
+    infer::{InferInput, InferInputBatch, InferOption, InferOutput, SampleOption, StopOption},
+    model::State,
+    JobRuntime, SessionId,
+};
+
+/// How a [`Memory`] keeps a conversation within its token budget.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryOption {
+    /// Maximum number of tokens in the state (the summary and the turns since) before the oldest turns are summarized.
+    pub budget: usize,
+    /// Number of the most recent turns that are never summarized.
+    pub keep_turns: usize,
+    /// Tokens before the text to summarize, e.g., an instruction.
+    pub prefix: Vec<u16>,
+    /// Tokens after the text to summarize, e.g., the start of the answer.
+    pub suffix: Vec<u16>,
+    /// How the summary is sampled.
+    pub sample: SampleOption,
+    /// Conditions that end the summary.
+    pub stop: StopOption,
+    pub token_chunk_size: usize,
+}
+
+impl Default for MemoryOption {
+    fn default() -> Self {
+        Self {
+            budget: 4096,
+            keep_turns: 1,
+            prefix: vec![],
+            suffix: vec![],
+            sample: Default::default(),
+            stop: Default::default(),
+            token_chunk_size: 128,
+        }
+    }
+}
+
+/// A batch of a runtime's state, where a conversation lives or a summary is generated.
+#[derive(Clone, Copy)]
+pub struct Slot<'a> {
+    pub runtime: &'a JobRuntime<InferInput, InferOutput>,
+    /// The state of the runtime.
+    pub state: &'a dyn State,
+    pub batch: usize,
+}
+
+impl Slot<'_> {
+    /// Reset the batch to the initial state.
+    fn reset(&self) -> Result<()> {
+        Ok(self.state.load(self.state.init(), self.batch)?)
+    }
+
+    /// Run `tokens` through the batch, and return the logits of the last one.
+    async fn run(
+        &self,
+        tokens: &[u16],
+        chunk: usize,
+        session: Option<SessionId>,
+    ) -> Result<Vec<f32>> {
+        let batches = (0..self.state.num_batch())
+            .map(|batch| InferInputBatch {
+                tokens: match batch == self.batch {
+                    true => tokens.into(),
+                    false => vec![].into(),
+                },
+                option: InferOption::Last,
+                session,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, chunk);
+        let mut logits = vec![];
+        while input.num_token() > 0 {
+            let (next, InferOutput(output)) = self.runtime.infer(input).await;
+            input = next;
+            match output.into_iter().nth(self.batch) {
+                Some(output) if output.size() > 0 => logits = output.to_vec(),
+                _ => {}
+            }
+        }
+        Ok(logits)
+    }
+}
+
+/// The tokens of a conversation that a batch of a state has seen, kept within a token budget by summarization.
+///
+/// Turns are [pushed](Memory::push) after they have been run through the conversation's batch.
+/// When the state holds more tokens than the [`budget`](MemoryOption::budget), [`Memory::compact`] runs a summarization pass
+/// over the oldest turns (and the previous summary), and re-primes the batch from the initial state with the new summary
+/// followed by the turns kept. The summary can be generated by the same model, or by an auxiliary one.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    pub option: MemoryOption,
+    session: Option<SessionId>,
+    summary: Vec<u16>,
+    turns: VecDeque<Vec<u16>>,
+}
+
+impl Memory {
+    pub fn new(option: MemoryOption) -> Self {
+        Self {
+            option,
+            session: None,
+            summary: vec![],
+            turns: VecDeque::new(),
+        }
+    }
+
+    /// The session to account the work of summarization to, and to report it in events.
+    pub fn session(mut self, value: SessionId) -> Self {
+        self.session = Some(value);
+        self
+    }
+
+    /// The current summary of the turns that have been dropped.
+    pub fn summary(&self) -> &[u16] {
+        &self.summary
+    }
+
+    /// Turns since the last summary.
+    pub fn turns(&self) -> impl Iterator<Item = &[u16]> {
+        self.turns.iter().map(Vec::as_slice)
+    }
+
+    /// Tokens that the state has seen since the initial state, i.e., the summary followed by the turns since.
+    pub fn tokens(&self) -> Vec<u16> {
+        [self.summary.as_slice()]
+            .into_iter()
+            .chain(self.turns())
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Number of tokens that the state has seen since the initial state.
+    pub fn num_token(&self) -> usize {
+        self.summary.len() + self.turns.iter().map(Vec::len).sum::<usize>()
+    }
+
+    /// Record a turn that has been run through the conversation's batch.
+    pub fn push(&mut self, turn: Vec<u16>) {
+        if !turn.is_empty() {
+            self.turns.push_back(turn);
+        }
+    }
+
+    /// If the conversation is over budget and has turns that can be summarized.
+    pub fn is_over_budget(&self) -> bool {
+        self.num_token() > self.option.budget && self.turns.len() > self.option.keep_turns
+    }
+
+    /// Summarize the oldest turns if the conversation is over budget, and return if it is compacted.
+    ///
+    /// All turns but the last [`keep_turns`](MemoryOption::keep_turns) are summarized along with the previous summary,
+    /// in `summarizer` if given, or else in the conversation's own batch, which is re-primed afterwards anyway.
+    /// Progress is reported by [`Event::Summarizing`], [`Event::TokenGenerated`] and [`Event::Summarized`]
+    /// in the event stream of the runtime that generates the summary.
+    /// Other batches of the runtimes are left untouched, but must not be run at the same time.
+    pub async fn compact(
+        &mut self,
+        conversation: Slot<'_>,
+        summarizer: Option<Slot<'_>>,
+    ) -> Result<bool> {
+        if !self.is_over_budget() {
+            return Ok(false);
+        }
+        let MemoryOption {
+            keep_turns,
+            prefix,
+            suffix,
+            sample,
+            stop,
+            token_chunk_size: chunk,
+            ..
+        } = &self.option;
+        if stop.max_tokens == 0 {
+            bail!("no token to summarize into");
+        }
+
+        let num_summarized = self.turns.len() - keep_turns;
+        let dropped = self.turns.iter().take(num_summarized).map(Vec::as_slice);
+        let prompt = [prefix.as_slice(), &self.summary]
+            .into_iter()
+            .chain(dropped)
+            .chain([suffix.as_slice()])
+            .flatten()
+            .copied()
+            .collect_vec();
+
+        let slot = summarizer.unwrap_or(conversation);
+        let session = self.session;
+        slot.runtime.emit(Event::Summarizing {
+            session,
+            batch: slot.batch,
+            num_token: prompt.len(),
+        });
+        slot.reset()?;
+        let mut logits = slot.run(&prompt, *chunk, session).await?;
+
+        let mut summary = vec![];
+        let mut random = sample.seed;
+        loop {
+            let token = sample.sample(&logits, &mut random);
+            slot.runtime.emit(Event::TokenGenerated {
+                session,
+                batch: slot.batch,
+                token,
+            });
+            if stop.tokens.contains(&token) {
+                break;
+            }
+            summary.push(token);
+            if summary.len() >= stop.max_tokens {
+                break;
+            }
+            logits = slot.run(&[token], *chunk, session).await?;
+        }
+        slot.runtime.emit(Event::Summarized {
+            session,
+            batch: slot.batch,
+            num_token: summary.len(),
+        });
+
+        self.summary = summary;
+        self.turns.drain(..num_summarized);
+
+        conversation.reset()?;
+        let tokens = self.tokens();
+        if !tokens.is_empty() {
+            conversation.run(&tokens, *chunk, session).await?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{Memory, MemoryOption, Slot};
+    use crate::runtime::{
+        event::Event,
+        infer::{
+            InferInput, InferInputBatch, InferKind, InferRequest, InferResponse, SampleOption,
+            StopOption,
+        },
+        model::{Build, ModelBuilder, ModelRuntime, ModelVersion, State},
+        tiny::{
+            tests::{create_context, prompts},
+            TinyModel,
+        },
+        v5, JobRuntime,
+    };
+
+    #[test]
+    fn test_memory() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let turns = prompts(&info)[0]
+                .chunks(10)
+                .map(<[u16]>::to_vec)
+                .collect_vec();
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let create_runtime = |num_batch| {
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                async move {
+                    let model = Build::<v5::Model>::build(builder).await?;
+                    anyhow::Ok(v5::ModelRuntime::<f32>::new(model, num_batch))
+                }
+            };
+            let sample = SampleOption {
+                temperature: 0.0,
+                top_p: 1.0,
+                seed: 0,
+            };
+            let stop = StopOption {
+                max_tokens: 4,
+                tokens: vec![],
+            };
+            let option = MemoryOption {
+                budget: 25,
+                keep_turns: 1,
+                prefix: vec![1, 2],
+                suffix: vec![3],
+                sample,
+                stop: stop.clone(),
+                token_chunk_size: 8,
+            };
+
+            let runtime = create_runtime(2).await?;
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+            let run = |batch: usize, tokens: Vec<u16>| {
+                let runtime = &runtime;
+                async move {
+                    let batches = (0..2)
+                        .map(|index| InferInputBatch {
+                            tokens: match index == batch {
+                                true => tokens.clone().into(),
+                                false => vec![].into(),
+                            },
+                            ..Default::default()
+                        })
+                        .collect();
+                    let mut input = InferInput::new(batches, 8);
+                    while input.num_token() > 0 {
+                        (input, _) = runtime.infer(input).await;
+                    }
+                }
+            };
+            let conversation = Slot {
+                runtime: &runtime,
+                state: &state,
+                batch: 0,
+            };
+
+            let mut events = runtime.subscribe();
+            let mut memory = Memory::new(option.clone()).session(7);
+            let mut compacted = vec![];
+            for turn in &turns[..3] {
+                run(0, turn.clone()).await;
+                memory.push(turn.clone());
+                compacted.push(memory.compact(conversation, None).await?);
+            }
+            // the first two turns are summarized once the third one exceeds the budget
+            assert_eq!(compacted, [false, false, true]);
+            assert_eq!(memory.summary().len(), 4);
+            assert_eq!(memory.turns().collect_vec(), [turns[2].as_slice()]);
+            assert_eq!(memory.num_token(), 14);
+
+            // the summary is what the model generates from the prompt
+            let prompt = [vec![1, 2], turns[0].clone(), turns[1].clone(), vec![3]].concat();
+            let greedy = JobRuntime::new(create_runtime(1).await?).await;
+            let requests = vec![InferRequest {
+                tokens: prompt.clone().into(),
+                kind: InferKind::Token {
+                    sample,
+                    stop,
+                    phrases: vec![],
+                },
+                session: None,
+            }];
+            let InferResponse::Token { tokens, .. } = &greedy.serve(requests, 8).await?[0] else {
+                panic!("expect tokens");
+            };
+            assert_eq!(tokens, memory.summary());
+
+            // the batch is re-primed with the summary and the turn kept
+            state.load(state.init(), 1)?;
+            run(1, memory.tokens()).await;
+            let primed = state.back(0).await?.to_vec();
+            let expected = state.back(1).await?.to_vec();
+            for (x, y) in primed.iter().zip_eq(expected.iter()) {
+                assert!((x - y).abs() < 1.0e-4, "{x} vs {y}");
+            }
+
+            let mut progress = vec![];
+            while let Ok(event) = events.try_recv() {
+                match event {
+                    Event::Summarizing { .. } | Event::Summarized { .. } => progress.push(event),
+                    Event::TokenGenerated { session, .. } => assert_eq!(session, Some(7)),
+                    _ => {}
+                }
+            }
+            assert_eq!(
+                progress,
+                [
+                    Event::Summarizing {
+                        session: Some(7),
+                        batch: 0,
+                        num_token: prompt.len(),
+                    },
+                    Event::Summarized {
+                        session: Some(7),
+                        batch: 0,
+                        num_token: 4,
+                    },
+                ]
+            );
+
+            // an auxiliary summarizer (here the same model) gives the same summary
+            let auxiliary = create_runtime(1).await?;
+            let auxiliary_state = auxiliary.state();
+            let auxiliary = JobRuntime::new(auxiliary).await;
+            let summarizer = Slot {
+                runtime: &auxiliary,
+                state: &auxiliary_state,
+                batch: 0,
+            };
+            state.load(state.init(), 0)?;
+            let mut other = Memory::new(option);
+            for turn in &turns[..3] {
+                run(0, turn.clone()).await;
+                other.push(turn.clone());
+                other.compact(conversation, Some(summarizer)).await?;
+            }
+            assert_eq!(other.summary(), memory.summary());
+            Ok(())
+        })
+    }
+}
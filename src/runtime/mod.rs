@@ -1,16 +1,67 @@
-use std::future::Future;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
 
+use self::{event::Event, infer::SampleOption};
+use crate::context::{ArenaStats, Context, OutOfMemoryError, TuningProfile};
+
+#[cfg(feature = "adapter")]
+pub mod adapter;
+pub mod beam;
+pub mod bias;
+pub mod cache;
+#[cfg(feature = "checksum")]
+pub mod checksum;
+pub mod choice;
+#[cfg(feature = "tokenizer")]
+pub mod constraint;
+pub mod dry;
+pub mod dump;
+pub mod ensemble;
+pub mod event;
+pub mod explore;
+pub mod fim;
+pub mod gguf;
 pub mod infer;
 pub mod loader;
+pub mod lora;
+pub mod memory;
 pub mod model;
+pub mod noise;
+pub mod patch;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pool;
+pub mod probe;
+pub mod prompt;
+pub mod sampler;
+pub mod score;
+pub mod snapshot;
 pub mod softmax;
+pub mod speculative;
+pub mod stream;
+pub mod tap;
+pub mod tiny;
+pub mod transfer;
 pub mod v4;
 pub mod v5;
 pub mod v6;
+pub mod validate;
+pub mod vision;
+#[cfg(feature = "worker")]
+pub mod worker;
 
 // const MAX_QUEUE_SIZE: usize = 2;
+/// Events not yet received by a slow subscriber before the oldest ones are dropped.
+const MAX_EVENT_QUEUE_SIZE: usize = 1024;
+/// Submissions waiting behind the current one whose first jobs are built ahead.
+const MAX_BUILD_AHEAD: usize = 4;
 
 pub trait JobInfo: Send + Clone + 'static {
     /// Check if the info are compatible.
@@ -27,6 +78,11 @@ pub trait Job: Sized + Send + 'static {
     fn load(self, input: &Self::Input) -> Result<Self>;
     /// Submit the job to GPU and execute it immediately.
     fn submit(&mut self);
+    /// Wait for decisions the job takes on GPU results before the next job is submitted, e.g., an [early exit](model::EarlyExit),
+    /// so that the next job runs on the state this one leaves. [`Job::back`] settles the job as well if this is not called.
+    fn settle(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
     /// Wait for the job to finish and read the data back.
     fn back(self) -> impl Future<Output = Result<Self::Output>> + Send;
 }
@@ -37,12 +93,151 @@ pub trait JobBuilder<J: Job>: Send + Clone + 'static {
     /// Build a [`Job`] from the given info.
     /// This usually involves creating a list of GPU commands (but not actually execution).
     fn build(&self, info: Self::Info) -> Result<J>;
+
+    /// Release resources that are no longer needed. Called by the scheduler when idle, see [`Maintenance`].
+    fn maintain(&self) {}
+
+    /// Apply a new configuration of the runtime, e.g., its [`budget`](RuntimeConfig::budget).
+    /// Called by the scheduler between steps after [`JobRuntime::reconfigure`], before any job is built with it.
+    fn reconfigure(&mut self, _config: &RuntimeConfig) {}
+
+    /// The context jobs are built on, whose [arenas](crate::context::Arena) the runtime reports, see [`Event::Arenas`].
+    fn context(&self) -> Option<Context> {
+        None
+    }
+}
+
+pub type MaintenanceFn = Box<dyn FnMut() -> futures::future::BoxFuture<'static, ()> + Send>;
+
+/// Work for the scheduler to do once no submission has arrived for `idle`, to smooth out latency during the next burst.
+///
+/// The scheduler releases cached buffers that are not in use (see [`JobBuilder::maintain`]),
+/// builds the job of the last step ahead since the next request likely looks the same, and then runs `hook`,
+/// e.g., to back states of least recently used sessions to host.
+pub struct Maintenance {
+    pub idle: Duration,
+    pub hook: Option<MaintenanceFn>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_millis(500),
+            hook: None,
+        }
+    }
+}
+
+/// Parameters of a live [`JobRuntime`], changed with [`JobRuntime::reconfigure`] without restarting it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    /// Sampling that servers fall back on for requests that don't choose their own. The runtime only keeps it.
+    pub sampler: SampleOption,
+    /// Weights of sessions when several submissions wait: the next to run is the one whose sessions have been served
+    /// the fewest prompt tokens per weight. Sessions not listed weigh 1. If empty, submissions run in order.
+    pub weights: HashMap<SessionId, f64>,
+    /// The least time between two [`Event::Metrics`] of a session, or `None` to emit them after every step.
+    pub metrics_interval: Option<Duration>,
+    /// Device memory in bytes that jobs may take, for builders that account it (e.g., [`DryRun`](dry::DryRun)).
+    /// `None` keeps the builder's own.
+    pub budget: Option<usize>,
+}
+
+/// Picks the next waiting submission by weighted fair queuing over sessions, see [`RuntimeConfig::weights`].
+#[derive(Debug, Default)]
+struct Fairness {
+    weights: HashMap<SessionId, f64>,
+    /// Prompt tokens served to each session, divided by its weight.
+    served: HashMap<SessionId, f64>,
+}
+
+impl Fairness {
+    fn reconfigure(&mut self, weights: HashMap<SessionId, f64>) {
+        if weights.is_empty() {
+            self.served.clear();
+        }
+        self.weights = weights;
+    }
+
+    fn weight(&self, session: SessionId) -> f64 {
+        let weight = self.weights.get(&session).copied().unwrap_or(1.0);
+        weight.max(f64::EPSILON)
+    }
+
+    /// Account the prompt tokens of a step to its sessions.
+    fn serve(&mut self, usage: &[(SessionId, Usage)]) {
+        if self.weights.is_empty() {
+            return;
+        }
+        // sessions seen for the first time start level with the least served, instead of taking over
+        let start = self
+            .served
+            .values()
+            .copied()
+            .reduce(f64::min)
+            .unwrap_or(0.0);
+        for (session, usage) in usage {
+            let weight = self.weight(*session);
+            let served = self.served.entry(*session).or_insert(start);
+            *served += usage.prompt_tokens as f64 / weight;
+        }
+    }
+
+    /// Take the waiting submission whose sessions have been served the least, or the first one if no weights are set.
+    fn next<I: JobInput, O>(
+        &self,
+        pending: &mut VecDeque<(u64, Submission<I, O>)>,
+    ) -> Option<(u64, Submission<I, O>)> {
+        if self.weights.is_empty() {
+            return pending.pop_front();
+        }
+        let served = |(_, submission): &(u64, Submission<I, O>)| {
+            let (_, usage) = submission.input.usage();
+            usage
+                .iter()
+                .filter_map(|(session, _)| self.served.get(session))
+                .copied()
+                .reduce(f64::min)
+                .unwrap_or(0.0)
+        };
+        let index = pending
+            .iter()
+            .enumerate()
+            .min_by(|(_, x), (_, y)| served(x).total_cmp(&served(y)))
+            .map(|(index, _)| index)?;
+        pending.remove(index)
+    }
 }
 
 #[derive(Debug)]
 struct Submission<I, O> {
     input: I,
-    sender: tokio::sync::oneshot::Sender<(I, O)>,
+    sender: tokio::sync::oneshot::Sender<Result<(I, O)>>,
+}
+
+/// Identifies the session (e.g., a user or an API key) that some input belongs to, for accounting.
+pub type SessionId = u64;
+
+/// Resources consumed by a session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Input tokens run through the model. Sampled tokens that are fed back are counted again here.
+    pub prompt_tokens: usize,
+    /// Predictions made at the end of inputs, i.e., the tokens that can be sampled.
+    pub generated_tokens: usize,
+    /// Time of the steps that the session takes part in, split by its share of tokens in each step.
+    pub gpu_time: Duration,
+    /// Time that steps of the session are held back by its rate limit, see [`JobRuntime::set_rate_limit`].
+    pub throttled_time: Duration,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.generated_tokens += rhs.generated_tokens;
+        self.gpu_time += rhs.gpu_time;
+        self.throttled_time += rhs.throttled_time;
+    }
 }
 
 pub trait JobInput: Send + 'static {
@@ -53,10 +248,151 @@ pub trait JobInput: Send + 'static {
     fn step(&mut self);
     /// The current step's chunk to feed into the job.
     fn chunk(&self) -> Self::Chunk;
+    /// Number of tokens in the current step's chunk, and the usage of each session among them with `gpu_time` left empty.
+    /// Inputs not tagged with sessions need not implement this.
+    fn usage(&self) -> (usize, Vec<(SessionId, Usage)>) {
+        (0, vec![])
+    }
+    /// Halve the chunk size for the rest of the input, so that a step that runs out of device memory can be retried.
+    /// Returns `false` if the chunk size is already at its floor.
+    fn shrink(&mut self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Default)]
+struct Accounting {
+    sessions: HashMap<SessionId, Usage>,
+    /// When the last step finished, so that the time of overlapping steps is not counted twice.
+    last_done: Option<Instant>,
+    /// When the metrics of each session are last emitted.
+    last_metrics: HashMap<SessionId, Instant>,
+    /// When the statistics of the arenas are last emitted.
+    last_arenas: Option<Instant>,
+}
+
+impl Accounting {
+    /// Account a step, and return the total usage of the sessions in it.
+    fn record(
+        &mut self,
+        (num_token, usage): (usize, Vec<(SessionId, Usage)>),
+        submitted: Instant,
+    ) -> Vec<(SessionId, Usage)> {
+        let done = Instant::now();
+        let start = self.last_done.map_or(submitted, |last| last.max(submitted));
+        let elapsed = done.saturating_duration_since(start);
+        self.last_done = Some(done);
+
+        usage
+            .into_iter()
+            .map(|(session, mut usage)| {
+                if num_token > 0 {
+                    usage.gpu_time = elapsed.mul_f64(usage.prompt_tokens as f64 / num_token as f64);
+                }
+                let total = self.sessions.entry(session).or_default();
+                *total += usage;
+                (session, *total)
+            })
+            .collect()
+    }
+
+    /// Whether the metrics of a session are due at `now`, if emitted at most once every `interval`.
+    fn due(&mut self, session: SessionId, now: Instant, interval: Option<Duration>) -> bool {
+        let Some(interval) = interval else {
+            return true;
+        };
+        match self.last_metrics.get(&session) {
+            Some(&last) if now.saturating_duration_since(last) < interval => false,
+            _ => {
+                self.last_metrics.insert(session, now);
+                true
+            }
+        }
+    }
+
+    /// Whether the statistics of the arenas are due at `now`, like the metrics of a session.
+    fn due_arenas(&mut self, now: Instant, interval: Option<Duration>) -> bool {
+        match (self.last_arenas, interval) {
+            (Some(last), Some(interval)) if now.saturating_duration_since(last) < interval => false,
+            _ => {
+                self.last_arenas = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Account the time that a step is held back for the rate limited sessions in it.
+    fn throttle(&mut self, sessions: &[SessionId], elapsed: Duration) {
+        for &session in sessions {
+            self.sessions.entry(session).or_default().throttled_time += elapsed;
+        }
+    }
+}
+
+/// Paces the generated tokens of sessions, see [`JobRuntime::set_rate_limit`].
+#[derive(Debug, Default)]
+struct RateLimiter {
+    /// Maximum generated tokens per second of each limited session.
+    limits: HashMap<SessionId, f64>,
+    /// When each limited session may generate its next token.
+    next: HashMap<SessionId, Instant>,
+}
+
+impl RateLimiter {
+    /// The limited sessions that generate tokens in a step.
+    fn limited(&self, usage: &[(SessionId, Usage)]) -> Vec<SessionId> {
+        usage
+            .iter()
+            .filter(|(session, usage)| {
+                usage.generated_tokens > 0 && self.limits.contains_key(session)
+            })
+            .map(|(session, _)| *session)
+            .collect()
+    }
+
+    /// When a step may be submitted, which is when all limited sessions generating tokens in it may.
+    fn ready(&self, usage: &[(SessionId, Usage)]) -> Option<Instant> {
+        self.limited(usage)
+            .iter()
+            .filter_map(|session| self.next.get(session))
+            .max()
+            .copied()
+    }
+
+    /// Advance the pace of the sessions generating tokens in a step submitted at `now`.
+    fn consume(&mut self, usage: &[(SessionId, Usage)], now: Instant) {
+        for (session, usage) in usage {
+            let Some(&limit) = self.limits.get(session) else {
+                continue;
+            };
+            if usage.generated_tokens == 0 {
+                continue;
+            }
+            // idle time does not build up into a burst
+            let next = self.next.entry(*session).or_insert(now);
+            *next =
+                (*next).max(now) + Duration::from_secs_f64(usage.generated_tokens as f64 / limit);
+        }
+    }
+}
+
+/// A submission held back by the rate limiter.
+struct Throttled<I, O> {
+    ready: Instant,
+    since: Instant,
+    id: u64,
+    submission: Submission<I, O>,
 }
 
 #[derive(Debug, Clone)]
-pub struct JobRuntime<I, O>(tokio::sync::mpsc::Sender<Submission<I, O>>);
+pub struct JobRuntime<I, O> {
+    sender: tokio::sync::mpsc::Sender<Submission<I, O>>,
+    accounting: Arc<Mutex<Accounting>>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    config: Arc<tokio::sync::watch::Sender<RuntimeConfig>>,
+    events: tokio::sync::broadcast::Sender<Event>,
+    context: Option<Context>,
+}
 
 #[allow(clippy::type_complexity)]
 impl<I, O, T, F> JobRuntime<I, O>
@@ -68,46 +404,219 @@ where
     for<'a> &'a I: IntoIterator<Item = T, IntoIter = F>,
 {
     pub async fn new<J>(builder: impl JobBuilder<J, Info = T>) -> Self
+    where
+        J: Job<Info = T, Input = I::Chunk, Output = O>,
+    {
+        Self::new_with_maintenance(builder, None).await
+    }
+
+    /// Create a runtime that does `maintenance` when idle.
+    pub async fn new_with_maintenance<J>(
+        builder: impl JobBuilder<J, Info = T>,
+        maintenance: Option<Maintenance>,
+    ) -> Self
     where
         J: Job<Info = T, Input = I::Chunk, Output = O>,
     {
         let (sender, receiver) = tokio::sync::mpsc::channel(1);
-        let handle = tokio::spawn(Self::run(builder, receiver));
-        tokio::spawn(async move {
-            match handle.await {
-                Ok(_) => {}
-                Err(err) => log::error!("{}", err),
-            }
-        });
-        Self(sender)
+        let accounting: Arc<Mutex<Accounting>> = Default::default();
+        let limiter: Arc<Mutex<RateLimiter>> = Default::default();
+        let (config, config_receiver) = tokio::sync::watch::channel(RuntimeConfig::default());
+        let (events, _) = tokio::sync::broadcast::channel(MAX_EVENT_QUEUE_SIZE);
+        let context = builder.context();
+        let handle = tokio::spawn(Self::run(
+            builder,
+            receiver,
+            maintenance,
+            accounting.clone(),
+            limiter.clone(),
+            config_receiver,
+            events.clone(),
+        ));
+        {
+            let events = events.clone();
+            tokio::spawn(async move {
+                let message = match handle.await {
+                    Ok(Ok(_)) => return,
+                    Ok(Err(err)) => err.to_string(),
+                    Err(err) => err.to_string(),
+                };
+                log::error!("{}", message);
+                let _ = events.send(Event::Error { message });
+            });
+        }
+        Self {
+            sender,
+            accounting,
+            limiter,
+            config: Arc::new(config),
+            events,
+            context,
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run<J>(
-        builder: impl JobBuilder<J, Info = T>,
+        mut builder: impl JobBuilder<J, Info = T>,
         mut receiver: tokio::sync::mpsc::Receiver<Submission<I, O>>,
+        mut maintenance: Option<Maintenance>,
+        accounting: Arc<Mutex<Accounting>>,
+        limiter: Arc<Mutex<RateLimiter>>,
+        mut config: tokio::sync::watch::Receiver<RuntimeConfig>,
+        events: tokio::sync::broadcast::Sender<Event>,
     ) -> Result<()>
     where
         J: Job<Info = T, Input = I::Chunk, Output = O>,
     {
-        let mut queue: Vec<(T, tokio::task::JoinHandle<Result<J>>)> = vec![];
+        let context = builder.context();
+
+        // one task reads back all submitted jobs, instead of a task for each
+        let (completions, receiver_completions) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(complete(
+            receiver_completions,
+            accounting.clone(),
+            config.clone(),
+            events.clone(),
+            context.clone(),
+        ));
+
+        // jobs being built, with the id of the waiting submission they are built ahead for
+        let mut queue: Vec<(T, tokio::task::JoinHandle<Result<J>>, Option<u64>)> = vec![];
         let mut iter: Option<F> = None;
         let mut predict: usize = 0;
+        let mut last_info: Option<T> = None;
+        let mut idle = false;
+
+        // submissions taken from the channel ahead of time, with their ids
+        let mut pending: VecDeque<(u64, Submission<I, O>)> = VecDeque::new();
+        let mut count: u64 = 0;
+
+        // submissions held back by the rate limiter, which let others go ahead in the meantime
+        let mut throttled: Vec<Throttled<I, O>> = vec![];
+
+        let mut fairness = Fairness::default();
+
+        loop {
+            // between steps is a safe point to apply a new configuration
+            if config.has_changed().unwrap_or(false) {
+                let value = config.borrow_and_update().clone();
+                builder.reconfigure(&value);
+                fairness.reconfigure(value.weights);
+
+                // jobs built ahead are built with the old configuration
+                for (_, handle, _) in queue.drain(..) {
+                    handle.abort();
+                }
+                iter = None;
+            }
+
+            let now = Instant::now();
+            let wake = throttled.iter().map(|x| x.ready).min();
+            let ready = throttled
+                .iter()
+                .position(|x| x.ready <= now)
+                .map(|index| throttled.remove(index));
+            if let Some(Throttled {
+                since,
+                id,
+                submission,
+                ..
+            }) = ready
+            {
+                let (_, usage) = submission.input.usage();
+                let sessions = match limiter.lock() {
+                    Ok(limiter) => limiter.limited(&usage),
+                    Err(_) => vec![],
+                };
+                if let Ok(mut accounting) = accounting.lock() {
+                    accounting.throttle(&sessions, now.saturating_duration_since(since));
+                }
+                pending.push_front((id, submission));
+            }
+
+            let submission = match fairness.next(&mut pending) {
+                Some(submission) => Some(submission),
+                // wait for a new submission, or until the first held back is ready
+                None if wake.is_some() => tokio::select! {
+                    submission = receiver.recv() => submission.map(|x| (count, x)),
+                    _ = tokio::time::sleep_until(wake.unwrap_or(now).into()) => continue,
+                },
+                None => match maintenance.as_mut() {
+                    Some(maintenance) if !idle => {
+                        match tokio::time::timeout(maintenance.idle, receiver.recv()).await {
+                            Ok(submission) => submission.map(|x| (count, x)),
+                            Err(_) => {
+                                idle = true;
+                                builder.maintain();
+
+                                if let Some(info) = last_info.clone() {
+                                    if !queue.iter().any(|(key, _, _)| info.check(key)) {
+                                        let key = info.clone();
+                                        let builder = builder.clone();
+                                        let handle =
+                                            tokio::task::spawn_blocking(move || builder.build(key));
+                                        queue.push((info, handle, None));
+                                    }
+                                }
+
+                                if let Some(hook) = maintenance.hook.as_mut() {
+                                    hook().await;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    _ => receiver.recv().await.map(|x| (count, x)),
+                },
+            };
+            let Some((id, submission)) = submission else {
+                match wake {
+                    Some(wake) => {
+                        tokio::time::sleep_until(wake.into()).await;
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+            if id == count {
+                count += 1;
+            }
+            idle = false;
+
+            let (_, usage) = submission.input.usage();
+            let ready = match limiter.lock() {
+                Ok(limiter) => limiter.ready(&usage),
+                Err(_) => None,
+            };
+            if let Some(ready) = ready.filter(|&ready| ready > Instant::now()) {
+                throttled.push(Throttled {
+                    ready,
+                    since: Instant::now(),
+                    id,
+                    submission,
+                });
+                continue;
+            }
+            let Submission { input, sender } = submission;
+
+            // jobs built ahead for this submission are now like any other
+            for (_, _, ahead) in queue.iter_mut().filter(|(_, _, ahead)| *ahead == Some(id)) {
+                *ahead = None;
+            }
 
-        while let Some(Submission { input, sender }) = receiver.recv().await {
             let Some(info) = (&input).into_iter().next() else {
                 continue;
             };
+            last_info = Some(info.clone());
 
-            let chunk = input.chunk();
-
-            let mut job = loop {
+            let job = loop {
                 let mut candidates = vec![];
                 let mut remain = vec![];
-                for (key, handle) in queue.drain(..) {
-                    match (candidates.is_empty(), info.check(&key)) {
-                        (true, false) => handle.abort(),
-                        (false, false) => remain.push((key, handle)),
-                        (_, true) => candidates.push(handle),
+                for (key, handle, ahead) in queue.drain(..) {
+                    match (candidates.is_empty(), info.check(&key), ahead) {
+                        (_, true, _) => candidates.push(handle),
+                        (true, false, None) => handle.abort(),
+                        (_, false, _) => remain.push((key, handle, ahead)),
                     }
                 }
                 queue = remain;
@@ -139,47 +648,642 @@ where
                     let key = info.clone();
                     let builder = builder.clone();
                     let handle = tokio::task::spawn_blocking(move || builder.build(key));
-                    queue.push((info.clone(), handle));
+                    queue.push((info.clone(), handle, None));
                 }
 
                 if !candidates.is_empty() {
                     let (job, _, remain) = futures::future::select_all(candidates).await;
                     let mut remain = remain
                         .into_iter()
-                        .map(|handle| (info.clone(), handle))
+                        .map(|handle| (info.clone(), handle, None))
                         .collect();
                     std::mem::swap(&mut queue, &mut remain);
                     queue.append(&mut remain);
-                    break job??;
+                    break job?;
                 }
-            }
-            .load(&chunk)?;
+            };
+
+            // retry the step in smaller chunks if the device runs out of memory
+            let mut input = input;
+            let job = match job {
+                Ok(job) => job,
+                Err(err) if err.is::<OutOfMemoryError>() && input.shrink() => {
+                    let message = format!("{err}; retrying the step in smaller chunks");
+                    log::warn!("{}", message);
+                    let _ = events.send(Event::Warning { message });
+                    iter = None;
+                    pending.push_front((id, Submission { input, sender }));
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let chunk = input.chunk();
+            let mut job = match job.load(&chunk) {
+                Ok(job) => job,
+                // invalid input only fails its own submission, e.g., a token out of a trimmed vocabulary
+                Err(err) => {
+                    let message = err.to_string();
+                    log::error!("{}", message);
+                    let _ = events.send(Event::Error { message });
+                    let _ = sender.send(Err(err));
+                    iter = None;
+                    continue;
+                }
+            };
 
-            async fn back<J: Job, I: JobInput>(
-                job: J,
-                mut input: I,
-                sender: tokio::sync::oneshot::Sender<(I, J::Output)>,
-            ) -> Result<()> {
-                let output = job.back().await?;
-                input.step();
-                let _ = sender.send((input, output));
-                Ok(())
+            // keep the queue within its bound of submissions in flight without blocking the executor
+            if let Some(context) = &context {
+                context.throttle().await;
             }
+            let submitted = {
+                // the span is not `Send`, so it must not be held across an await point
+                #[cfg(feature = "trace")]
+                let _span = tracing::trace_span!("submit").entered();
+                let submitted = Instant::now();
+                let (_, usage) = input.usage();
+                if let Ok(mut limiter) = limiter.lock() {
+                    limiter.consume(&usage, submitted);
+                }
+                fairness.serve(&usage);
+                job.submit();
+                submitted
+            };
+            job.settle().await;
+            let _ = completions.send(Completion {
+                job,
+                input,
+                sender,
+                submitted,
+            });
 
-            #[cfg(feature = "trace")]
-            let _span = tracing::trace_span!("submit").entered();
-            job.submit();
-            tokio::spawn(back(job, input, sender));
+            // while the GPU is busy, build the first jobs of submissions already waiting
+            while pending.len() < MAX_BUILD_AHEAD {
+                let Ok(submission) = receiver.try_recv() else {
+                    break;
+                };
+                let id = count;
+                count += 1;
+                if let Some(info) = (&submission.input).into_iter().next() {
+                    if !queue.iter().any(|(key, _, _)| info.check(key)) {
+                        let key = info.clone();
+                        let builder = builder.clone();
+                        let handle = tokio::task::spawn_blocking(move || builder.build(key));
+                        queue.push((info, handle, Some(id)));
+                    }
+                }
+                pending.push_back((id, submission));
+            }
         }
         Ok(())
     }
 
     /// Perform (partial) inference and return the remaining input and (perhaps partial) output.
     /// The amount of input processed during one call is bound by the input chunk size.
+    ///
+    /// Panics if the input is rejected, see [`JobRuntime::try_infer`].
     pub async fn infer(&self, input: I) -> (I, O) {
+        self.try_infer(input)
+            .await
+            .expect("receive infer output error")
+    }
+
+    /// Like [`JobRuntime::infer`], but return an error if the input is rejected (e.g., with a token out of the vocabulary)
+    /// or its output cannot be read back. Other submissions are not affected.
+    pub async fn try_infer(&self, input: I) -> Result<(I, O)> {
         let (sender, receiver) = tokio::sync::oneshot::channel();
         let submission = Submission { input, sender };
-        let _ = self.0.send(submission).await;
-        receiver.await.expect("receive infer output error")
+        let _ = self.sender.send(submission).await;
+        receiver.await?
+    }
+
+    /// Subscribe to the events of the runtime. See [`event::write_json_lines`] to forward them to a frontend.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Emit an event into the runtime's stream, e.g., [`Event::StateBacked`] after reading back a state.
+    pub fn emit(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    /// Resources consumed by a session so far.
+    pub fn usage(&self, session: SessionId) -> Option<Usage> {
+        let accounting = self.accounting.lock().ok()?;
+        accounting.sessions.get(&session).copied()
+    }
+
+    /// Resources consumed by all sessions so far.
+    pub fn usages(&self) -> HashMap<SessionId, Usage> {
+        match self.accounting.lock() {
+            Ok(accounting) => accounting.sessions.clone(),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    /// Device memory of each arena of the context the runtime builds jobs on, or none if its builder doesn't tell.
+    pub fn arenas(&self) -> Vec<ArenaStats> {
+        self.context
+            .as_ref()
+            .map(|context| context.arena_stats())
+            .unwrap_or_default()
+    }
+
+    /// The [tuning profile](TuningProfile) of the context the runtime builds jobs on, or the default one if its builder doesn't tell,
+    /// e.g., to pick the size of chunks of an input.
+    pub fn tuning(&self) -> TuningProfile {
+        self.context
+            .as_ref()
+            .map(|context| context.tuning())
+            .unwrap_or_default()
+    }
+
+    /// Return the resources consumed by a session and restart its counters, e.g., at the end of a billing period.
+    pub fn take_usage(&self, session: SessionId) -> Option<Usage> {
+        let mut accounting = self.accounting.lock().ok()?;
+        accounting.last_metrics.remove(&session);
+        accounting.sessions.remove(&session)
+    }
+
+    /// Limit the tokens a session generates to `max_tokens_per_second`, or lift the limit with `None`.
+    ///
+    /// The scheduler paces steps in which the session generates tokens (i.e., that end one of its inputs),
+    /// holding them back while submissions of other inputs go ahead. Prompt tokens are not limited.
+    /// A step is held back until every limited session generating in it is ready, so sessions with different limits
+    /// should be run in inputs of their own. The time held back is accounted as [`Usage::throttled_time`], not as GPU time.
+    pub fn set_rate_limit(&self, session: SessionId, max_tokens_per_second: Option<f64>) {
+        let Ok(mut limiter) = self.limiter.lock() else {
+            return;
+        };
+        match max_tokens_per_second {
+            Some(limit) if limit > 0.0 => {
+                limiter.limits.insert(session, limit);
+            }
+            _ => {
+                limiter.limits.remove(&session);
+                limiter.next.remove(&session);
+            }
+        }
+    }
+
+    /// The rate limit of a session, in generated tokens per second.
+    pub fn rate_limit(&self, session: SessionId) -> Option<f64> {
+        let limiter = self.limiter.lock().ok()?;
+        limiter.limits.get(&session).copied()
+    }
+
+    /// The current configuration of the runtime.
+    pub fn config(&self) -> RuntimeConfig {
+        self.config.borrow().clone()
+    }
+
+    /// Replace the configuration of the live runtime, e.g., to tune a server without restarting it.
+    ///
+    /// The scheduler applies it between steps: the step running is not affected, and jobs built ahead are rebuilt.
+    /// Metrics are throttled from the next step that completes on.
+    pub fn reconfigure(&self, config: RuntimeConfig) {
+        self.config.send_replace(config);
+    }
+}
+
+/// A submitted job, waiting for its output to be read back.
+struct Completion<J: Job, I> {
+    job: J,
+    input: I,
+    sender: tokio::sync::oneshot::Sender<Result<(I, J::Output)>>,
+    submitted: Instant,
+}
+
+/// Read back submitted jobs and send their outputs, until the runtime stops submitting.
+/// Readbacks run concurrently within this one task, so that a job's output is not held up by an earlier one.
+async fn complete<J: Job, I: JobInput>(
+    mut receiver: tokio::sync::mpsc::UnboundedReceiver<Completion<J, I>>,
+    accounting: Arc<Mutex<Accounting>>,
+    config: tokio::sync::watch::Receiver<RuntimeConfig>,
+    events: tokio::sync::broadcast::Sender<Event>,
+    context: Option<Context>,
+) {
+    let mut pending = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            completion = receiver.recv() => match completion {
+                Some(completion) => {
+                    pending.push(back(completion, &accounting, &config, &events, context.as_ref()))
+                }
+                None => break,
+            },
+            Some(_) = pending.next(), if !pending.is_empty() => {}
+        }
+    }
+    while pending.next().await.is_some() {}
+}
+
+async fn back<J: Job, I: JobInput>(
+    completion: Completion<J, I>,
+    accounting: &Mutex<Accounting>,
+    config: &tokio::sync::watch::Receiver<RuntimeConfig>,
+    events: &tokio::sync::broadcast::Sender<Event>,
+    context: Option<&Context>,
+) {
+    let Completion {
+        job,
+        mut input,
+        sender,
+        submitted,
+    } = completion;
+
+    let output = match job.back().await {
+        Ok(output) => output,
+        Err(err) => {
+            let message = err.to_string();
+            log::error!("{}", message);
+            let _ = events.send(Event::Error { message });
+            let _ = sender.send(Err(err));
+            return;
+        }
+    };
+
+    let (num_token, usage) = input.usage();
+    let sessions = usage.iter().map(|(session, _)| *session).collect();
+    let interval = config.borrow().metrics_interval;
+    let (totals, arenas) = match accounting.lock() {
+        Ok(mut accounting) => {
+            let now = Instant::now();
+            let totals = accounting.record((num_token, usage), submitted);
+            let totals = totals
+                .into_iter()
+                .filter(|&(session, _)| accounting.due(session, now, interval))
+                .collect();
+            let arenas = context
+                .filter(|_| accounting.due_arenas(now, interval))
+                .map(|context| context.arena_stats());
+            (totals, arenas)
+        }
+        Err(_) => (vec![], None),
+    };
+    let _ = events.send(Event::ChunkDone {
+        num_token,
+        sessions,
+    });
+    for (session, usage) in totals {
+        let _ = events.send(Event::Metrics { session, usage });
+    }
+    if let Some(arenas) = arenas {
+        let _ = events.send(Event::Arenas { arenas });
+    }
+
+    input.step();
+    let _ = sender.send(Ok((input, output)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{JobBuilder, JobRuntime, Maintenance, MaintenanceFn};
+    use crate::{
+        context::{Arena, OutOfMemoryError, OverBudgetError},
+        runtime::{
+            event::Event,
+            infer::{
+                InferError, InferInfo, InferInput, InferInputBatch, InferOption, InferOutput,
+                MIN_TOKEN_CHUNK_SIZE,
+            },
+            model::{Build, ModelBuilder, ModelVersion},
+            tiny::{
+                tests::{check_prompts, create_context, infer_steps, prompts},
+                TinyModel,
+            },
+            v5, v6,
+        },
+    };
+
+    #[test]
+    fn test_maintenance() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let prompts = prompts(&info);
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+
+            let count = Arc::new(AtomicUsize::new(0));
+            let hook: MaintenanceFn = {
+                let count = count.clone();
+                Box::new(move || {
+                    let count = count.clone();
+                    Box::pin(async move {
+                        count.fetch_add(1, Ordering::SeqCst);
+                    })
+                })
+            };
+            let maintenance = Maintenance {
+                idle: Duration::from_millis(100),
+                hook: Some(hook),
+            };
+            let plain =
+                JobRuntime::new(v5::ModelRuntime::<f32>::new(model.clone(), prompts.len())).await;
+            let maintained = JobRuntime::new_with_maintenance(
+                v5::ModelRuntime::<f32>::new(model, prompts.len()),
+                Some(maintenance),
+            )
+            .await;
+
+            let batches = |tokens: &[Vec<u16>]| {
+                let batches = tokens
+                    .iter()
+                    .map(|tokens| InferInputBatch {
+                        tokens: tokens.clone().into(),
+                        option: InferOption::Last,
+                        ..Default::default()
+                    })
+                    .collect();
+                InferInput::new(batches, 32)
+            };
+            let run = |runtime: JobRuntime<InferInput, InferOutput>, input: InferInput| async move {
+                let mut input = input;
+                let mut outputs = vec![];
+                while input.num_token() > 0 {
+                    let (next, InferOutput(output)) = runtime.infer(input).await;
+                    input = next;
+                    outputs.extend(output.into_iter().map(|x| x.0.to_vec()));
+                }
+                outputs
+            };
+
+            let expected = run(plain.clone(), batches(&prompts)).await;
+            let output = run(maintained.clone(), batches(&prompts)).await;
+            assert_eq!(output, expected);
+
+            // maintenance runs once per idle period
+            let before = count.load(Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert_eq!(count.load(Ordering::SeqCst), before + 1);
+
+            // the runtime keeps working after compaction and building ahead
+            let next = vec![vec![1]; prompts.len()];
+            let expected = run(plain, batches(&next)).await;
+            let output = run(maintained, batches(&next)).await;
+            assert_eq!(output, expected);
+            Ok(())
+        })
+    }
+
+    /// Runs out of device memory on steps of more than the minimum chunk size.
+    #[derive(Clone)]
+    struct Spiky(v5::ModelRuntime<f32>);
+
+    impl JobBuilder<v5::InferJob> for Spiky {
+        type Info = InferInfo;
+
+        fn build(&self, seed: Self::Info) -> Result<v5::InferJob> {
+            if seed.num_token() > MIN_TOKEN_CHUNK_SIZE {
+                return Err(OutOfMemoryError.into());
+            }
+            self.0.build(seed)
+        }
+    }
+
+    #[test]
+    fn test_oom_retry() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let prompts = prompts(&info);
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let plain =
+                JobRuntime::new(v5::ModelRuntime::<f32>::new(model.clone(), prompts.len())).await;
+            let spiky =
+                JobRuntime::new(Spiky(v5::ModelRuntime::<f32>::new(model, prompts.len()))).await;
+            let mut events = spiky.subscribe();
+
+            let input = |token_chunk_size: usize| {
+                let batches = prompts
+                    .iter()
+                    .map(|tokens| InferInputBatch {
+                        tokens: tokens.clone().into(),
+                        option: InferOption::Full,
+                        ..Default::default()
+                    })
+                    .collect();
+                InferInput::new(batches, token_chunk_size)
+            };
+            let num_batch = prompts.len();
+            let run = |runtime: JobRuntime<InferInput, InferOutput>, input: InferInput| async move {
+                let mut input = input;
+                let mut outputs = vec![vec![]; num_batch];
+                while input.num_token() > 0 {
+                    let (next, InferOutput(output)) = runtime.infer(input).await;
+                    input = next;
+                    for (batch, output) in output.into_iter().enumerate() {
+                        outputs[batch].extend(output.0.to_vec());
+                    }
+                }
+                (input, outputs)
+            };
+
+            // the first step fails at 128 and 64 tokens, and the rest of the input goes on in the minimum chunks
+            let (_, expected) = run(plain, input(MIN_TOKEN_CHUNK_SIZE)).await;
+            let (input, output) = run(spiky, input(128)).await;
+            assert_eq!(output, expected);
+            assert_eq!(input.token_chunk_size(), MIN_TOKEN_CHUNK_SIZE);
+
+            let mut warnings = 0;
+            while let Ok(event) = events.try_recv() {
+                match event {
+                    Event::Warning { .. } => warnings += 1,
+                    Event::Error { message } => panic!("{message}"),
+                    _ => {}
+                }
+            }
+            assert_eq!(warnings, 2);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_rejected_input() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder.trim_vocab(100)).await?;
+            let runtime = JobRuntime::new(v5::ModelRuntime::<f32>::new(model, 2)).await;
+            let mut events = runtime.subscribe();
+
+            let input = |tokens: [Vec<u16>; 2]| {
+                let batches = tokens
+                    .into_iter()
+                    .map(|tokens| InferInputBatch {
+                        tokens: tokens.into(),
+                        option: InferOption::Last,
+                        ..Default::default()
+                    })
+                    .collect();
+                InferInput::new(batches, 32)
+            };
+
+            // a token beyond the trimmed vocabulary fails only its own submission
+            let err = runtime
+                .try_infer(input([vec![1, 2], vec![3, 150]]))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<InferError>(),
+                Some(&InferError::TokenOutOfRange {
+                    batch: 1,
+                    token: 150,
+                    num_vocab: 100,
+                })
+            );
+            assert!(matches!(events.try_recv(), Ok(Event::Error { .. })));
+
+            // and the runtime goes on serving the others
+            let (_, InferOutput(output)) = runtime.infer(input([vec![1, 2], vec![3, 4]])).await;
+            assert!(output.iter().all(|output| output.0.len() == 100));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_arenas() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+
+            // weights and states are accounted in arenas of their own, apart from the buffers of jobs
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let size = context.arena_stats()[0].size;
+            assert!(size > 0);
+            assert_eq!(context.arena_stats()[1].size, 0);
+
+            let num_batch = 2;
+            let runtime = v5::ModelRuntime::<f32>::new(model.clone(), num_batch);
+            let runtime = JobRuntime::new(runtime).await;
+            let head_size = info.num_emb / info.num_head;
+            let state_size = info.num_emb * (head_size + 2) * num_batch * info.num_layer * 4;
+            let stats = runtime.arenas();
+            assert_eq!((stats[0].size, stats[1].size), (size, state_size));
+
+            let mut events = runtime.subscribe();
+            let prompt = prompts(&info).swap_remove(1);
+            infer_steps(runtime, &prompt, &[]).await;
+            let mut arenas = None;
+            while let Ok(event) = events.try_recv() {
+                if let Event::Arenas { arenas: stats } = event {
+                    arenas = Some(stats);
+                }
+            }
+            let arenas = arenas.expect("no arena statistics emitted");
+            assert_eq!(arenas[0].size, size);
+            assert!(arenas[2].peak > 0);
+
+            // another model that doesn't fit in the budget of the arena with the first fails to load
+            context.set_budget(Arena::Model, Some(size + size / 2));
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let err = Build::<v5::Model>::build(builder).await.unwrap_err();
+            let err = err.downcast_ref::<OverBudgetError>();
+            assert_eq!(err.map(|err| err.arena), Some(Arena::Model));
+            assert!(context.arena_stats()[0].rejections > 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_build_ahead() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V6);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let prompts = [prompts(&info), prompts(&info)].concat();
+            let num_batch = prompts.len();
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v6::Model>::build(builder).await?;
+            let serial =
+                JobRuntime::new(v6::ModelRuntime::<f32>::new(model.clone(), num_batch)).await;
+            let runtime = JobRuntime::new(v6::ModelRuntime::<f32>::new(model, num_batch)).await;
+
+            // each request runs a single batch, so that requests in flight do not share states
+            let input = |batch: usize| {
+                let batches = (0..num_batch)
+                    .map(|index| InferInputBatch {
+                        tokens: match index == batch {
+                            true => prompts[batch].clone().into(),
+                            false => vec![].into(),
+                        },
+                        option: InferOption::Last,
+                        ..Default::default()
+                    })
+                    .collect();
+                InferInput::new(batches, 16)
+            };
+            let run = |runtime: JobRuntime<InferInput, InferOutput>, batch: usize| {
+                let mut input = input(batch);
+                async move {
+                    let mut output = vec![];
+                    while input.num_token() > 0 {
+                        let (next, InferOutput(mut outputs)) = runtime.infer(input).await;
+                        input = next;
+                        output.extend_from_slice(&outputs.swap_remove(batch).0);
+                    }
+                    output
+                }
+            };
+
+            let mut expected = vec![];
+            for batch in 0..num_batch {
+                expected.push(run(serial.clone(), batch).await);
+            }
+
+            // requests queue up behind each other, so the first jobs of waiting ones are built ahead
+            let handles = (0..num_batch)
+                .map(|batch| tokio::spawn(run(runtime.clone(), batch)))
+                .collect_vec();
+            for (batch, handle) in handles.into_iter().enumerate() {
+                assert_eq!(handle.await?, expected[batch]);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_many_batches() -> Result<()> {
+        // more batches than fit in 8 bits, of various lengths
+        let num_vocab = TinyModel::info(ModelVersion::V4).num_vocab;
+        let prompts = (0..300)
+            .map(|batch: usize| {
+                (0..1 + batch % 3)
+                    .map(|index| ((batch * 7 + index) % num_vocab) as u16)
+                    .collect()
+            })
+            .collect_vec();
+        for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+            check_prompts(version, &prompts)?;
+        }
+        Ok(())
     }
 }
@@ -6,13 +6,24 @@ use half::f16;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use wasm_bindgen::prelude::wasm_bindgen;
+use web_rwkv_derive::DeserializeSeed;
 
-use super::loader::{Lora, Reader};
+use super::{
+    infer::next_random,
+    loader::{Lora, Reader, TensorRegistry},
+    lora::LoraAlpha,
+};
 use crate::{
     context::{Context, ContextBuilder},
     impl_deserialize_seed,
     num::Scalar,
-    tensor::{kind::ReadWrite, TensorCpu, TensorError, TensorGpu, TensorGpuView},
+    tensor::{
+        kind::ReadWrite,
+        ops::{BatchCopy, TensorCommand, TensorOp},
+        shape::Shape,
+        Cursor, DeepClone, TensorCpu, TensorError, TensorGpu, TensorGpuView, TensorInit,
+        TensorShape,
+    },
 };
 
 #[wasm_bindgen]
@@ -46,6 +57,76 @@ pub struct ModelInfo {
 impl ModelInfo {
     pub const BUFFER_SIZE: usize = 256 << 20;
     pub const STORAGE_BUFFER_BINDING_SIZE: usize = 128 << 20;
+
+    /// Keep only the first `num_vocab` tokens of the vocabulary.
+    /// The cutoff is rounded up to a multiple of 4 as required by the head, and never exceeds the original size.
+    pub fn trim_vocab(self, num_vocab: usize) -> Self {
+        let num_vocab = num_vocab.next_multiple_of(4).min(self.num_vocab);
+        Self { num_vocab, ..self }
+    }
+
+    /// Estimate the device memory taken by the weights when loaded with the given quantization of layers.
+    /// Vectors are counted roughly, and temporary buffers used while loading are not counted.
+    pub fn estimate_size(&self, quant: &HashMap<usize, Quant>, embed_device: EmbedDevice) -> usize {
+        /// Upper bound of the number of vectors in a layer, over all versions.
+        const NUM_LAYER_VECTOR: usize = 16;
+
+        let num_emb = self.num_emb;
+        let num_hidden = self.num_hidden;
+        let num_att_matrix = match self.version {
+            ModelVersion::V4 => 4,
+            ModelVersion::V5 | ModelVersion::V6 => 5,
+        };
+        let adapter_size = match self.version {
+            ModelVersion::V6 => {
+                let mix = 10 * self.time_mix_adapter_size * num_emb;
+                let decay = 2 * self.time_decay_adapter_size * num_emb;
+                (mix + decay) * f16::size()
+            }
+            _ => 0,
+        };
+
+        let layers: usize = (0..self.num_layer)
+            .map(|layer| {
+                let quant = quant.get(&layer).copied().unwrap_or_default();
+                let att = num_att_matrix * quant.matrix_size(num_emb, num_emb);
+                let ffn = quant.matrix_size(num_emb, num_hidden)
+                    + quant.matrix_size(num_hidden, num_emb)
+                    + quant.matrix_size(num_emb, num_emb);
+                let vectors = NUM_LAYER_VECTOR * num_emb * f32::size();
+                att + ffn + adapter_size + vectors
+            })
+            .sum();
+        let embed = match embed_device {
+            EmbedDevice::Cpu => 0,
+            EmbedDevice::Gpu => self.head_buffer_size(),
+        };
+        layers + self.head_buffer_size() + embed
+    }
+}
+
+impl Quant {
+    /// Size in bytes of a matrix of `[k, n]` (i.e., with `k` inputs and `n` outputs) on device.
+    pub fn matrix_size(&self, k: usize, n: usize) -> usize {
+        let len = k * n;
+        match self {
+            Quant::None => len * f16::size(),
+            Quant::Int8 => len + 2 * len / TensorOp::INT8_BLOCK_SIZE as usize * f16::size(),
+            Quant::NF4 => len / 2 + len / TensorOp::NF4_BLOCK_SIZE as usize * f16::size(),
+            Quant::Fp8E4M3 | Quant::Fp8E5M2 => {
+                len + len / TensorOp::FP8_BLOCK_SIZE as usize * f16::size()
+            }
+            Quant::Q4K | Quant::Q5K => {
+                let scales = 2 * len / TensorOp::KQUANT_BLOCK_SIZE as usize;
+                let minmax = 2 * len / TensorOp::KQUANT_SUPER_BLOCK_SIZE as usize * f16::size();
+                let high = match self {
+                    Quant::Q5K => len / 8,
+                    _ => 0,
+                };
+                len / 2 + high + scales + minmax
+            }
+        }
+    }
 }
 
 impl_deserialize_seed!(ModelInfo);
@@ -61,6 +142,27 @@ impl ModelInfo {
     pub fn head_buffer_size(&self) -> usize {
         self.num_emb * self.num_vocab * f16::size()
     }
+
+    /// Estimate the device memory taken by a state of `num_batch` batches.
+    pub fn estimate_state_size(&self, num_batch: usize) -> usize {
+        let num_row = match self.version {
+            ModelVersion::V4 => 5,
+            ModelVersion::V5 | ModelVersion::V6 => self.num_emb / self.num_head + 2,
+        };
+        self.num_layer * self.num_emb * num_row * num_batch * f32::size()
+    }
+
+    /// Estimate the device memory taken by the buffers of a job of `num_token` tokens with `num_header` outputs.
+    /// Activations are counted as `f32`, so this is an upper bound for `f16` runtimes.
+    pub fn estimate_buffer_size(&self, num_token: usize, num_header: usize) -> usize {
+        /// Upper bound of the number of activations of `num_emb` for each token, over all versions.
+        const NUM_BUFFER: usize = 24;
+
+        let buffer = (NUM_BUFFER * self.num_emb + self.num_hidden) * num_token * f32::size();
+        let cursors = (Cursor::PACKED_SIZE + 1) * num_token * u32::size();
+        let header = (self.num_emb + self.num_vocab) * num_header * f32::size();
+        buffer + cursors + header
+    }
 }
 
 pub trait AsAny {
@@ -70,7 +172,7 @@ pub trait AsAny {
 pub trait State {
     /// Batch number of this state.
     fn num_batch(&self) -> usize;
-    /// Initialize a one-batch state on CPU.
+    /// Initialize a one-batch state on CPU, as set by [`StateInit`].
     fn init(&self) -> TensorCpu<f32>;
     /// The part of the state that is used in an `att` layer.
     fn att(&self, layer: usize) -> Result<TensorGpuView<f32>, TensorError>;
@@ -79,19 +181,136 @@ pub trait State {
     /// Load a batch of the state from CPU to GPU.
     fn load(&self, tensor: TensorCpu<f32>, batch: usize) -> Result<(), TensorError>;
     /// Read back a batch of the state from GPU to CPU.
+    /// The batch is copied when this is called, so inference submitted before awaiting does not affect the result.
     fn back(&self, batch: usize) -> BoxFuture<Result<TensorCpu<f32>, TensorError>>;
     /// Write into the state from a GPU tensor.
     fn write(&self, tensor: TensorGpu<f32, ReadWrite>, batch: usize) -> Result<(), TensorError>;
     /// Read the state out into a GPU tensor.
     fn read(&self, batch: usize) -> Result<TensorGpu<f32, ReadWrite>, TensorError>;
+    /// Copy a batch of the state on GPU right away, to be read back later. See [`StateSnapshot`].
+    fn snapshot(&self, batch: usize) -> Result<StateSnapshot, TensorError> {
+        self.read(batch).map(StateSnapshot)
+    }
+    /// Write a snapshot back into a batch, rolling it back to where the snapshot was taken.
+    fn restore(&self, snapshot: &StateSnapshot, batch: usize) -> Result<(), TensorError> {
+        self.write(snapshot.0.clone(), batch)
+    }
+    /// Copy a batch of the state into other batches on GPU, e.g., to continue a shared prefix in several ways.
+    fn fork(&self, batch: usize, targets: &[usize]) -> Result<(), TensorError> {
+        let tensor = self.read(batch)?;
+        for &target in targets.iter().filter(|&&target| target != batch) {
+            self.write(tensor.clone(), target)?;
+        }
+        Ok(())
+    }
+    /// Read only the given layers of a batch out into a GPU tensor, stacked in the order given,
+    /// e.g., the last few layers as a lightweight checkpoint for small rollbacks.
+    fn read_layers(
+        &self,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<TensorGpu<f32, ReadWrite>, TensorError>;
+    /// Write the layers read by [`State::read_layers`] back into a batch, leaving other layers untouched.
+    fn write_layers(
+        &self,
+        tensor: TensorGpu<f32, ReadWrite>,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<(), TensorError>;
+    /// Read back only the given layers of a batch from GPU to CPU. See [`State::read_layers`].
+    fn back_layers(
+        &self,
+        batch: usize,
+        layers: &[usize],
+    ) -> BoxFuture<'_, Result<TensorCpu<f32>, TensorError>> {
+        let tensor = self.read_layers(batch, layers);
+        Box::pin(async move { Ok(tensor?.back().await) })
+    }
+    /// Load the layers read back by [`State::back_layers`] from CPU into a batch, leaving other layers untouched.
+    fn load_layers(
+        &self,
+        tensor: TensorCpu<f32>,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<(), TensorError>;
     /// Get an embed vector from a backed state.
     fn embed(&self, layer: usize, backed: TensorCpu<f32>) -> Result<TensorCpu<f32>, TensorError>;
+    /// Get a fixed-dimension vector from a backed state for similarity search, e.g., in retrieval or long-term memory.
+    ///
+    /// The vector is the token-shift state of the `att` block (the normalized input of the last token) of each of `layers`,
+    /// scaled to unit length and concatenated, so it has shape `[num_emb * layers.len(), 1, 1]` whatever the model version.
+    /// The cosine similarity of two such vectors is their dot product divided by the number of layers.
+    fn embedding(
+        &self,
+        backed: TensorCpu<f32>,
+        layers: &[usize],
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        if layers.is_empty() {
+            return Err(TensorError::Empty);
+        }
+        let mut data = vec![];
+        for &layer in layers {
+            let x = self.embed(layer, backed.clone())?.to_vec();
+            let norm = x.iter().map(|x| x * x).sum::<f32>().sqrt();
+            match norm > 0.0 {
+                true => data.extend(x.into_iter().map(|x| x / norm)),
+                false => data.extend(x),
+            }
+        }
+        TensorCpu::from_data([data.len(), 1, 1, 1], data)
+    }
+    /// Create a new state of `num_batch` batches on GPU, keeping the contents of the existing batches that fit.
+    /// Batches beyond the old size are initialized.
+    fn resize(&self, num_batch: usize) -> Result<Self, TensorError>
+    where
+        Self: Sized;
+}
+
+/// A copy of one batch of a state, taken on GPU at the point in the queue where it is created.
+///
+/// Inference of the batch can go on right after the snapshot is taken, while the copy is read back (e.g., for periodic persistence)
+/// or [restored](State::restore) later to undo the tokens run since (e.g., rejected drafts in speculative decoding).
+#[derive(Debug, Clone)]
+pub struct StateSnapshot(pub TensorGpu<f32, ReadWrite>);
+
+impl StateSnapshot {
+    pub async fn back(self) -> TensorCpu<f32> {
+        self.0.back().await
+    }
+}
+
+/// Rebuild a model on another context, e.g., on a different adapter.
+///
+/// The weights are copied through host memory in their on-device format, so quantized matrices are not quantized again.
+/// The device of the model must still be responsive.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn migrate<M>(model: &M, context: &Context) -> Result<M>
+where
+    M: Clone + Serialize + Send + 'static,
+    for<'de> crate::tensor::serialization::Seed<'de, Context, M>:
+        serde::de::DeserializeSeed<'de, Value = M>,
+{
+    use serde::de::DeserializeSeed;
+
+    // serializing reads back the weights in place, which blocks
+    let model = model.clone();
+    let data =
+        tokio::task::spawn_blocking(move || cbor4ii::serde::to_vec(vec![], &model)).await??;
+
+    let reader = cbor4ii::core::utils::SliceReader::new(&data);
+    let mut deserializer = cbor4ii::serde::Deserializer::new(reader);
+    let seed = crate::tensor::serialization::Seed::<Context, M>::new(context);
+    Ok(seed.deserialize(&mut deserializer)?)
 }
 
 pub trait ModelRuntime {
     fn info(&self) -> ModelInfo;
     fn state(&self) -> impl State + AsAny + Send + Sync + 'static;
     fn model(&self) -> impl Serialize + Send + Sync + 'static;
+    /// Per-batch scaling factors of the runtime LoRAs, if the runtime supports any.
+    fn lora(&self) -> Option<LoraAlpha> {
+        None
+    }
 }
 
 /// Quantization of a layer.
@@ -105,6 +324,17 @@ pub enum Quant {
     Int8,
     /// Use `NF4` quantization.
     NF4,
+    /// Use experimental `Fp8` quantization in `E4M3` format.
+    /// Keeps more of the weights' dynamic range than `Int8`, at the same memory cost.
+    Fp8E4M3,
+    /// Use experimental `Fp8` quantization in `E5M2` format.
+    /// Wider range but coarser steps than `E4M3`.
+    Fp8E5M2,
+    /// Use GGUF-style `Q4_K` quantization: 4-bit elements with a scale and a minimum
+    /// for every 32 of them, which are themselves quantized in super-blocks of 256.
+    Q4K,
+    /// Use GGUF-style `Q5_K` quantization, like `Q4K` but with 5-bit elements.
+    Q5K,
 }
 
 /// Device to put the model's embed tensor.
@@ -116,6 +346,34 @@ pub enum EmbedDevice {
     Gpu,
 }
 
+/// Skip the layers after `layer` in a step if the prediction read from there is already confident.
+///
+/// The exit head is the model's own head applied to the hidden state after `layer`.
+/// Only steps in which every token produces an output (e.g., generation) may exit.
+/// The states of the skipped layers are then left unchanged by that step, i.e., they miss its tokens;
+/// the next step is only submitted after the exit is decided, so it always runs on them as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EarlyExit {
+    /// Index of the layer after which the exit head is read.
+    pub layer: usize,
+    /// Minimum top-1 probability for every output token to exit.
+    pub threshold: f32,
+}
+
+impl_deserialize_seed!(EarlyExit);
+
+impl EarlyExit {
+    /// Check if the top-1 probabilities of all tokens in `logits` reach the threshold.
+    pub fn check(&self, logits: &TensorCpu<f32>) -> bool {
+        let num_vocab = logits.shape()[0];
+        logits.data().chunks_exact(num_vocab).all(|x| {
+            let max = x.iter().fold(f32::MIN, |acc, &x| acc.max(x));
+            let sum: f32 = x.iter().map(|x| (x - max).exp()).sum();
+            sum.recip() >= self.threshold
+        })
+    }
+}
+
 pub trait Build<T> {
     fn build(self) -> impl Future<Output = Result<T>>;
 }
@@ -124,8 +382,13 @@ pub struct ModelBuilder<R: Reader> {
     pub context: Context,
     pub model: R,
     pub lora: Vec<Lora<R>>,
+    pub runtime_lora: Vec<Lora<R>>,
     pub quant: HashMap<usize, Quant>,
+    pub quant_head: bool,
     pub embed_device: EmbedDevice,
+    pub num_vocab: Option<usize>,
+    pub registry: Option<TensorRegistry>,
+    pub token_chunk_size: Option<usize>,
 }
 
 impl<R: Reader> ModelBuilder<R> {
@@ -134,8 +397,13 @@ impl<R: Reader> ModelBuilder<R> {
             context: context.clone(),
             model,
             lora: vec![],
+            runtime_lora: vec![],
             quant: Default::default(),
+            quant_head: false,
             embed_device: Default::default(),
+            num_vocab: None,
+            registry: None,
+            token_chunk_size: None,
         }
     }
 
@@ -144,15 +412,321 @@ impl<R: Reader> ModelBuilder<R> {
         self
     }
 
+    /// Quantize the head to `Int8` with a scale for each row, see [`Matrix::Int8Row`](crate::tensor::matrix::Matrix::Int8Row).
+    /// Logit biases, and the length adjustment and penalties of a sampler, are then applied in the head matmul itself.
+    pub fn quant_head(mut self, value: bool) -> Self {
+        self.quant_head = value;
+        self
+    }
+
     pub fn embed_device(mut self, value: EmbedDevice) -> Self {
         self.embed_device = value;
         self
     }
 
+    /// Add a LoRA that is merged into the weights before quantization, or applied at runtime, per its
+    /// [`placement`](Lora::placement) in each layer.
     pub fn lora(mut self, value: Lora<R>) -> Self {
         self.lora.push(value);
         self
     }
+
+    /// Add a LoRA whose matrices are applied at runtime instead of being blended into the weights.
+    /// Its contribution can then be scaled per batch via [`LoraAlpha`](super::lora::LoraAlpha).
+    /// Vectors in a runtime LoRA are ignored, and so is its placement.
+    pub fn runtime_lora(mut self, value: Lora<R>) -> Self {
+        self.runtime_lora.push(value);
+        self
+    }
+
+    /// Drop the tail of the vocabulary from both embed and head at load time,
+    /// e.g., the padding beyond the tokenizer's actual size, to save VRAM and head compute.
+    /// See [`ModelInfo::trim_vocab`] for how the cutoff is rounded. Jobs reject tokens beyond the cutoff when loading them.
+    pub fn trim_vocab(mut self, value: usize) -> Self {
+        self.num_vocab = Some(value);
+        self
+    }
+
+    /// Upload tensors identical to ones in the registry only once, sharing their buffers. See [`TensorRegistry`].
+    pub fn registry(mut self, value: TensorRegistry) -> Self {
+        self.registry = Some(value);
+        self
+    }
+
+    /// The most tokens the model is to be run with in one go, e.g., the token chunk size of inference.
+    /// Only checked against the limits of the device in [`validate`](Self::validate).
+    pub fn token_chunk_size(mut self, value: usize) -> Self {
+        self.token_chunk_size = Some(value);
+        self
+    }
+}
+
+/// How [`State::init`] fills a fresh batch of a state.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StateInit {
+    /// The neutral state of the model, i.e., zeros.
+    #[default]
+    Zero,
+    /// Add noise uniformly distributed in `[-scale, scale)` onto the neutral state.
+    Uniform { scale: f32, seed: u64 },
+    /// Add Gaussian noise with the standard deviation `std` onto the neutral state.
+    Gaussian { std: f32, seed: u64 },
+}
+
+impl_deserialize_seed!(StateInit);
+
+impl StateInit {
+    /// Add the noise onto `tensor`, the neutral state of one batch.
+    /// Values are drawn from the seed in the order of elements, so the same seed always gives the same state.
+    pub fn apply(&self, tensor: TensorCpu<f32>) -> TensorCpu<f32> {
+        let mut state = match *self {
+            StateInit::Zero => return tensor,
+            StateInit::Uniform { seed, .. } | StateInit::Gaussian { seed, .. } => seed,
+        };
+        let shape = tensor.shape();
+        let data: Vec<f32> = tensor
+            .iter()
+            .map(|&x| match *self {
+                StateInit::Zero => x,
+                StateInit::Uniform { scale, .. } => {
+                    x + scale * (2.0 * next_random(&mut state) - 1.0)
+                }
+                StateInit::Gaussian { std, .. } => {
+                    // Box-Muller, with `u` in `(0, 1]` to keep the log finite
+                    let u = 1.0 - next_random(&mut state);
+                    let v = next_random(&mut state);
+                    let z = (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos();
+                    x + std * z
+                }
+            })
+            .collect();
+        TensorCpu::from_data(shape, data).expect("same shape")
+    }
+}
+
+/// How a state is stored on GPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StateQuant {
+    /// Every layer in `f32`.
+    #[default]
+    None,
+    /// Every layer in Int8, with one scale per row of each batch. See [`QuantState`].
+    Int8,
+}
+
+impl_deserialize_seed!(StateQuant);
+
+/// The storage of a state quantized into Int8, for very large batch counts where the state dominates VRAM.
+///
+/// Each layer is kept as codes of shape `[C, S, B]`, each row of each batch scaled by its absolute maximum.
+/// The runtime dequantizes a layer into a `f32` buffer shared by all layers right before running it, and quantizes it back after,
+/// so the state takes about a quarter of the VRAM, plus one layer in full precision.
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct QuantState {
+    pub codes: Vec<TensorGpu<u8, ReadWrite>>,
+    /// Scales of each layer, of shape `[1, S, B]`.
+    pub scales: Vec<TensorGpu<f32, ReadWrite>>,
+    /// The layer being run, of shape `[C, S, B]`.
+    pub buffer: TensorGpu<f32, ReadWrite>,
+}
+
+impl QuantState {
+    /// Create a zeroed state of `num_layer` layers, each of shape `[C, S, B]`.
+    pub fn new(context: &Context, shape: impl Into<Shape>, num_layer: usize) -> Self {
+        let shape: Shape = shape.into();
+        let codes = (0..num_layer).map(|_| context.zeros(shape)).collect();
+        let scales = (0..num_layer)
+            .map(|_| context.zeros([1, shape[1], shape[2], 1]))
+            .collect();
+        let buffer = context.tensor_init(shape);
+        Self {
+            codes,
+            scales,
+            buffer,
+        }
+    }
+
+    #[inline]
+    pub fn num_batch(&self) -> usize {
+        self.buffer.shape()[2]
+    }
+
+    #[inline]
+    pub fn num_layer(&self) -> usize {
+        self.codes.len()
+    }
+
+    /// Dequantize a layer into the buffer before it runs.
+    pub fn dequantize(&self, layer: usize) -> Result<TensorOp, TensorError> {
+        self.check_layers(&[layer])?;
+        TensorOp::dequantize_state(
+            self.codes[layer].view(.., .., .., ..)?,
+            &self.scales[layer],
+            self.buffer.view(.., .., .., ..)?,
+        )
+    }
+
+    /// Quantize the buffer back into a layer after it runs.
+    pub fn quantize(&self, layer: usize) -> Result<TensorOp, TensorError> {
+        self.check_layers(&[layer])?;
+        TensorOp::quantize_state(
+            self.buffer.view(.., .., .., ..)?,
+            &self.scales[layer],
+            self.codes[layer].view(.., .., .., ..)?,
+        )
+    }
+
+    fn check_layers(&self, layers: &[usize]) -> Result<(), TensorError> {
+        if layers.is_empty() {
+            return Err(TensorError::Empty);
+        }
+        match layers.iter().find(|&&layer| layer >= self.num_layer()) {
+            Some(&layer) => Err(TensorError::SliceOutOfRange {
+                dim: self.num_layer(),
+                start: layer,
+                end: layer + 1,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Dequantize the given layers of a batch into a GPU tensor of shape `[C, S, L]`, stacked in the order given.
+    pub fn read_layers(
+        &self,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<TensorGpu<f32, ReadWrite>, TensorError> {
+        self.check_layers(layers)?;
+        let context = self.buffer.context();
+        let [num_emb, num_row, _, _] = *self.buffer.shape();
+        let tensor: TensorGpu<_, _> = context.tensor_init([num_emb, num_row, layers.len(), 1]);
+
+        let ops = layers
+            .iter()
+            .enumerate()
+            .map(|(index, &layer)| {
+                TensorOp::dequantize_state(
+                    self.codes[layer].view(.., .., batch, ..)?,
+                    &self.scales[layer],
+                    tensor.view(.., .., index, ..)?,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        context.submit(context.encode(&TensorOp::List(ops)));
+        Ok(tensor)
+    }
+
+    /// Quantize a GPU tensor of shape `[C, S, L]` into the given layers of a batch.
+    pub fn write_layers(
+        &self,
+        tensor: &TensorGpu<f32, ReadWrite>,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<(), TensorError> {
+        self.check_layers(layers)?;
+        let context = self.buffer.context();
+        let [num_emb, num_row, _, _] = *self.buffer.shape();
+        tensor.check_shape([num_emb, num_row, layers.len(), 1])?;
+
+        let ops = layers
+            .iter()
+            .enumerate()
+            .map(|(index, &layer)| {
+                TensorOp::quantize_state(
+                    tensor.view(.., .., index, ..)?,
+                    &self.scales[layer],
+                    self.codes[layer].view(.., .., batch, ..)?,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        context.submit(context.encode(&TensorOp::List(ops)));
+        Ok(())
+    }
+
+    /// Create a new storage of `num_batch` batches, keeping the codes of the existing batches that fit.
+    /// Batches beyond the old size are zeros.
+    pub fn resize(&self, num_batch: usize) -> Result<Self, TensorError> {
+        if num_batch == 0 {
+            return Err(TensorError::Empty);
+        }
+        let context = self.buffer.context();
+        let [num_emb, num_row, _, _] = *self.buffer.shape();
+        let num_copy = self.num_batch().min(num_batch);
+        let state = Self::new(context, [num_emb, num_row, num_batch, 1], self.num_layer());
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        let copies = self
+            .codes
+            .iter()
+            .zip(state.codes.iter())
+            .flat_map(|(x, y)| (0..num_copy).map(move |batch| BatchCopy::new(x, batch, y, batch)));
+        encoder.copy_tensor_batches(copies)?;
+        let copies = self
+            .scales
+            .iter()
+            .zip(state.scales.iter())
+            .flat_map(|(x, y)| (0..num_copy).map(move |batch| BatchCopy::new(x, batch, y, batch)));
+        encoder.copy_tensor_batches(copies)?;
+        context.submit(Some(encoder.finish()));
+        Ok(state)
+    }
+}
+
+impl DeepClone for QuantState {
+    fn deep_clone(&self) -> Self {
+        Self {
+            codes: self.codes.iter().map(DeepClone::deep_clone).collect(),
+            scales: self.scales.iter().map(DeepClone::deep_clone).collect(),
+            buffer: self.buffer.deep_clone(),
+        }
+    }
+}
+
+/// Create the state of a model apart from its runtime, e.g., to start the runtime
+/// [with](super::v6::ModelRuntime::new_with_state) a non-zero initialization.
+///
+/// ```ignore
+/// let init = StateInit::Gaussian { std: 0.01, seed: 42 };
+/// let builder = StateBuilder::new(&context, &info).num_batch(4).init(init);
+/// let state = Build::<v6::State>::build(builder).await?;
+/// let runtime = v6::ModelRuntime::<f16>::new_with_state(model, state);
+/// ```
+#[derive(Debug, Clone)]
+pub struct StateBuilder {
+    pub context: Context,
+    pub info: ModelInfo,
+    pub num_batch: usize,
+    pub init: StateInit,
+    pub quant: StateQuant,
+}
+
+impl StateBuilder {
+    pub fn new(context: &Context, info: &ModelInfo) -> Self {
+        Self {
+            context: context.clone(),
+            info: info.clone(),
+            num_batch: 1,
+            init: Default::default(),
+            quant: Default::default(),
+        }
+    }
+
+    pub fn num_batch(mut self, value: usize) -> Self {
+        self.num_batch = value;
+        self
+    }
+
+    /// Set how every batch is initialized, both now and whenever it is [reset](State::init) or [added](State::resize).
+    pub fn init(mut self, value: StateInit) -> Self {
+        self.init = value;
+        self
+    }
+
+    /// Set how the state is stored on GPU. See [`QuantState`].
+    pub fn quant(mut self, value: StateQuant) -> Self {
+        self.quant = value;
+        self
+    }
 }
 
 pub trait ContextAutoLimits {
@@ -172,3 +746,708 @@ impl ContextAutoLimits for ContextBuilder {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::future::BoxFuture;
+    use itertools::Itertools;
+    use safetensors::{Dtype, SafeTensors};
+
+    use super::{
+        AsAny, Build, EarlyExit, EmbedDevice, ModelBuilder, ModelInfo, ModelRuntime, ModelVersion,
+        Quant, State, StateBuilder, StateInit, StateQuant,
+    };
+    use crate::{
+        runtime::{
+            infer::{
+                InferChunk, InferChunkBatch, InferError, InferInput, InferInputBatch, InferOption,
+                InferOutput,
+            },
+            loader::{Loader, Reader},
+            tiny::{
+                tests::{create_context, infer_gpu, infer_steps, prompts, with_runtime},
+                TinyModel,
+            },
+            v4, v5, v6, JobRuntime,
+        },
+        tensor::{TensorCpu, TensorInit, TensorShape},
+    };
+
+    #[test]
+    fn test_early_exit() -> Result<()> {
+        let info = ModelInfo {
+            num_layer: 3,
+            ..TinyModel::info(ModelVersion::V5)
+        };
+        let confident = TensorCpu::from_data([4, 1, 1, 1], vec![0.0, 0.0, 10.0, 0.0])?;
+        let uniform = TensorCpu::from_data([4, 1, 1, 1], vec![0.0; 4])?;
+        let option = EarlyExit {
+            layer: 0,
+            threshold: 0.5,
+        };
+        assert!(option.check(&confident));
+        assert!(!option.check(&uniform));
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let (prompt, steps) = ([1, 2, 3, 4], [5, 6, 7]);
+            let exits = |exits: &[(usize, f32)]| {
+                exits
+                    .iter()
+                    .map(|&(layer, threshold)| EarlyExit { layer, threshold })
+                    .collect_vec()
+            };
+            let mut outputs = vec![];
+            for early_exit in [
+                exits(&[]),
+                exits(&[(0, 2.0), (1, 2.0)]),
+                exits(&[(0, 0.0)]),
+                exits(&[(1, 0.0), (0, 2.0)]),
+                exits(&[(0, 0.0), (1, 0.0)]),
+            ] {
+                let model = TinyModel::new(info.clone(), 42);
+                let model = Build::<v5::Model>::build(ModelBuilder::new(&context, model)).await?;
+                let runtime = v5::ModelRuntime::<f32>::new(model, 2).early_exit(early_exit);
+                let runtime = JobRuntime::new(runtime).await;
+                outputs.push(infer_steps(runtime, &prompt, &steps).await);
+            }
+
+            // never confident: every step runs through all layers
+            assert_eq!(outputs[0], outputs[1]);
+            // the prompt still runs through all layers, but single tokens exit at the first confident head
+            for output in &outputs[2..] {
+                assert_eq!(outputs[0][0], output[0]);
+            }
+            for ((full, first), second) in outputs[0][1..]
+                .iter()
+                .zip_eq(&outputs[2][1..])
+                .zip_eq(&outputs[3][1..])
+            {
+                assert_eq!(full.len(), first.len());
+                assert_ne!(full, first);
+                assert_ne!(full, second);
+                assert_ne!(first, second);
+            }
+            assert_eq!(outputs[2], outputs[4]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_state_quant() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V4);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let builder = StateBuilder::new(&context, &info).quant(StateQuant::Int8);
+            assert!(Build::<v4::State>::build(builder).await.is_err());
+
+            for version in [ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let prompts = prompts(&info);
+                let Some(expected) =
+                    infer_gpu(TinyModel::new(info.clone(), 42), &prompts, None).await?
+                else {
+                    return Ok(());
+                };
+                let context = create_context(&info).await?;
+
+                let num_batch = prompts.len();
+                let state = StateBuilder::new(&context, &info)
+                    .num_batch(num_batch)
+                    .quant(StateQuant::Int8);
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let (state, runtime) = match version {
+                    ModelVersion::V5 => {
+                        let model = Build::<v5::Model>::build(builder).await?;
+                        let state = Build::<v5::State>::build(state).await?;
+                        let runtime = v5::ModelRuntime::<f32>::new_with_state(model, state);
+                        let state: Box<dyn State> = Box::new(runtime.state());
+                        (state, JobRuntime::new(runtime).await)
+                    }
+                    ModelVersion::V6 => {
+                        let model = Build::<v6::Model>::build(builder).await?;
+                        let state = Build::<v6::State>::build(state).await?;
+                        let runtime = v6::ModelRuntime::<f32>::new_with_state(model, state);
+                        let state: Box<dyn State> = Box::new(runtime.state());
+                        (state, JobRuntime::new(runtime).await)
+                    }
+                    ModelVersion::V4 => unreachable!(),
+                };
+
+                let batches = prompts
+                    .iter()
+                    .map(|tokens| InferInputBatch {
+                        tokens: tokens.clone().into(),
+                        option: InferOption::Full,
+                        ..Default::default()
+                    })
+                    .collect();
+                let mut input = InferInput::new(batches, 32);
+                let mut logits = vec![vec![]; num_batch];
+                while input.num_token() > 0 {
+                    let (next, InferOutput(output)) = runtime.infer(input).await;
+                    input = next;
+                    for (logits, output) in logits.iter_mut().zip_eq(output) {
+                        logits.extend_from_slice(&output.0);
+                    }
+                }
+                for (expected, logits) in expected.iter().zip_eq(logits.iter()) {
+                    for (a, b) in expected.iter().zip_eq(logits.iter()) {
+                        assert!(
+                            (a - b).abs() <= 5.0e-2 * a.abs().max(1.0),
+                            "{version:?}: {a} vs {b}"
+                        );
+                    }
+                }
+
+                // the state goes through quantization when written, and not again when read
+                let backed = state.back(1).await?;
+                state.load(backed.clone(), 0)?;
+                assert_eq!(state.back(0).await?.to_vec(), backed.to_vec());
+                state.load(state.init(), 0)?;
+                state.fork(1, &[0])?;
+                assert_eq!(state.back(0).await?.to_vec(), backed.to_vec());
+                let layers = state.read_layers(1, &[1])?;
+                state.write_layers(layers, 0, &[0])?;
+                assert_ne!(state.back(0).await?.to_vec(), backed.to_vec());
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_state_init() -> Result<()> {
+        async fn check_init(
+            build: impl Fn(StateInit) -> BoxFuture<'static, Result<Box<dyn State>>>,
+        ) -> Result<()> {
+            let init = StateInit::Gaussian { std: 0.5, seed: 42 };
+            let state = build(init).await?;
+            let zero = build(StateInit::Zero).await?.init().to_vec();
+            let noise = state.init().to_vec();
+            assert_eq!(noise, state.init().to_vec());
+            for batch in 0..state.num_batch() {
+                assert_eq!(state.back(batch).await?.to_vec(), noise);
+            }
+
+            // noise is added onto the neutral state, with the given deviation
+            let delta = itertools::zip_eq(&noise, &zero)
+                .filter(|(x, y)| x.is_finite() && **y > f32::MIN)
+                .map(|(x, y)| x - y)
+                .collect_vec();
+            let mean = delta.iter().sum::<f32>() / delta.len() as f32;
+            let var = delta.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / delta.len() as f32;
+            assert!(mean.abs() < 0.05, "mean: {mean}");
+            assert!((var.sqrt() - 0.5).abs() < 0.05, "std: {}", var.sqrt());
+
+            let other = build(StateInit::Uniform {
+                scale: 0.5,
+                seed: 42,
+            })
+            .await?;
+            assert_ne!(other.init().to_vec(), noise);
+            Ok(())
+        }
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let build = |init: StateInit| -> BoxFuture<'static, Result<Box<dyn State>>> {
+                    let builder = StateBuilder::new(&context, &info).num_batch(2).init(init);
+                    Box::pin(async move {
+                        let state: Box<dyn State> = match version {
+                            ModelVersion::V4 => Box::new(Build::<v4::State>::build(builder).await?),
+                            ModelVersion::V5 => Box::new(Build::<v5::State>::build(builder).await?),
+                            ModelVersion::V6 => Box::new(Build::<v6::State>::build(builder).await?),
+                        };
+                        Ok(state)
+                    })
+                };
+                check_init(build).await?;
+
+                let init = StateInit::Gaussian { std: 0.5, seed: 7 };
+                let builder = StateBuilder::new(&context, &info).num_batch(2).init(init);
+                match version {
+                    ModelVersion::V4 => {
+                        check_resize(Build::<v4::State>::build(builder).await?).await?
+                    }
+                    ModelVersion::V5 => {
+                        check_resize(Build::<v5::State>::build(builder).await?).await?
+                    }
+                    ModelVersion::V6 => {
+                        check_resize(Build::<v6::State>::build(builder).await?).await?
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    async fn check_resize(state: impl State) -> Result<()> {
+        let init = state.init();
+        let filled: Vec<TensorCpu<f32>> = (0..state.num_batch())
+            .map(|batch| {
+                let data = (0..init.len()).map(|index| (batch * init.len() + index) as f32);
+                TensorCpu::from_data(init.shape(), data.collect_vec())
+            })
+            .try_collect()?;
+        for (batch, tensor) in filled.iter().enumerate() {
+            state.load(tensor.clone(), batch)?;
+        }
+
+        let grown = state.resize(state.num_batch() + 1)?;
+        assert_eq!(grown.num_batch(), filled.len() + 1);
+        for (batch, tensor) in filled.iter().enumerate() {
+            assert_eq!(grown.back(batch).await?.to_vec(), tensor.to_vec());
+        }
+        assert_eq!(grown.back(filled.len()).await?.to_vec(), init.to_vec());
+
+        let shrunk = grown.resize(1)?;
+        assert_eq!(shrunk.num_batch(), 1);
+        assert_eq!(shrunk.back(0).await?.to_vec(), filled[0].to_vec());
+        assert!(shrunk.resize(0).is_err());
+
+        // the original state is left untouched
+        assert_eq!(state.back(1).await?.to_vec(), filled[1].to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_resize() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let model = TinyModel::new(TinyModel::info(version), 42);
+                let Ok(context) = create_context(model.model_info()).await else {
+                    return Ok(());
+                };
+                let builder = ModelBuilder::new(&context, model);
+                with_runtime!(builder, version, 2, |runtime| {
+                    check_resize(runtime().state()).await?
+                });
+            }
+            Ok(())
+        })
+    }
+
+    async fn check_layers(state: impl State, num_layer: usize) -> Result<()> {
+        let init = state.init();
+        let full = (0..init.len())
+            .map(|index| index as f32 + 1.0)
+            .collect_vec();
+        let full = TensorCpu::from_data(init.shape(), full)?;
+        let zeros = TensorCpu::from_data(init.shape(), vec![0.0; init.len()])?;
+
+        // restore each layer alone into zeros: the layers add up to the full state
+        let mut sum = vec![0.0; init.len()];
+        for layer in 0..num_layer {
+            state.load(full.clone(), 1)?;
+            let partial = state.back_layers(1, &[layer]).await?;
+            assert_eq!(partial.len() * num_layer, full.len());
+
+            state.load(zeros.clone(), 1)?;
+            state.load_layers(partial, 1, &[layer])?;
+            let restored = state.back(1).await?.to_vec();
+            assert_ne!(restored, zeros.to_vec());
+            sum.iter_mut().zip(restored).for_each(|(x, y)| *x += y);
+        }
+        assert_eq!(sum, full.to_vec());
+
+        // layers are stacked in the order given
+        state.load(full.clone(), 0)?;
+        let layers = (0..num_layer).rev().collect_vec();
+        let reversed = state.read_layers(0, &layers)?;
+        state.write_layers(reversed, 1, &layers)?;
+        assert_eq!(state.back(1).await?.to_vec(), full.to_vec());
+
+        assert!(state.read_layers(0, &[]).is_err());
+        assert!(state.read_layers(0, &[num_layer]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_layers() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let builder = StateBuilder::new(&context, &info).num_batch(2);
+                let num_layer = info.num_layer;
+                match version {
+                    ModelVersion::V4 => {
+                        check_layers(Build::<v4::State>::build(builder).await?, num_layer).await?
+                    }
+                    ModelVersion::V5 => {
+                        check_layers(Build::<v5::State>::build(builder).await?, num_layer).await?
+                    }
+                    ModelVersion::V6 => {
+                        check_layers(Build::<v6::State>::build(builder).await?, num_layer).await?
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_state_embedding() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let builder = StateBuilder::new(&context, &info).num_batch(1);
+                let state: Box<dyn State> = match version {
+                    ModelVersion::V4 => Box::new(Build::<v4::State>::build(builder).await?),
+                    ModelVersion::V5 => Box::new(Build::<v5::State>::build(builder).await?),
+                    ModelVersion::V6 => Box::new(Build::<v6::State>::build(builder).await?),
+                };
+                // the row of the token-shift state of `att` of each layer in the backed state
+                let row = |layer: usize| match version {
+                    ModelVersion::V4 => 5 * layer,
+                    _ => layer * (info.num_emb / info.num_head + 2),
+                };
+
+                let init = state.init();
+                let data = (0..init.len()).map(|x| (x % 7) as f32 - 3.0).collect_vec();
+                let backed = TensorCpu::from_data(init.shape(), data.clone())?;
+
+                let layers = [info.num_layer - 1, 0];
+                let embedding = state.embedding(backed, &layers)?;
+                assert_eq!(
+                    embedding.shape(),
+                    [info.num_emb * layers.len(), 1, 1, 1].into()
+                );
+                for (x, &layer) in embedding.chunks(info.num_emb).zip_eq(&layers) {
+                    let start = row(layer) * info.num_emb;
+                    let expected = &data[start..start + info.num_emb];
+                    let norm = expected.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    for (x, y) in x.iter().zip_eq(expected) {
+                        assert!((x - y / norm).abs() < 1.0e-6, "{version:?}: {x} vs {y}");
+                    }
+                }
+
+                // the initial state has no token shift, so its vector stays zero
+                let embedding = state.embedding(init, &layers)?;
+                assert!(embedding.iter().all(|&x| x == 0.0));
+                assert!(state.embedding(state.init(), &[]).is_err());
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_state_snapshot() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime = v5::ModelRuntime::<f32>::new(model, 1);
+            let state = runtime.state();
+            let job = JobRuntime::new(runtime).await;
+
+            let prompt = prompts(&info).swap_remove(1);
+            let mut input = InferInput::new(
+                vec![InferInputBatch {
+                    tokens: prompt.into(),
+                    option: InferOption::Last,
+                    ..Default::default()
+                }],
+                32,
+            );
+            while input.num_token() > 0 {
+                (input, _) = job.infer(input).await;
+            }
+
+            // both are taken before the next step, and read back after it
+            let snapshot = state.snapshot(0)?;
+            let backed = state.back(0);
+            let input = InferInput::new(
+                vec![InferInputBatch {
+                    tokens: vec![3].into(),
+                    option: InferOption::Last,
+                    ..Default::default()
+                }],
+                32,
+            );
+            let _ = job.infer(input).await;
+
+            let snapshot = snapshot.back().await.to_vec();
+            let backed = backed.await?.to_vec();
+            let after = state.back(0).await?.to_vec();
+            assert_eq!(snapshot, backed);
+            assert_ne!(snapshot, after);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_migrate() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            macro_rules! check {
+                ($version:ident, $info:expr, $context:expr, $other:expr) => {{
+                    let builder = ModelBuilder::new(&$context, TinyModel::new($info.clone(), 42));
+                    let model = Build::<$version::Model>::build(builder).await?;
+                    let prompt = prompts(&$info).swap_remove(1);
+                    let steps = [3, 1, 4];
+
+                    let runtime = $version::ModelRuntime::<f32>::new(model.clone(), 2);
+                    let expected =
+                        infer_steps(JobRuntime::new(runtime).await, &prompt, &steps).await;
+
+                    let runtime = $version::ModelRuntime::<f32>::new(model, 2);
+                    let mut output =
+                        infer_steps(JobRuntime::new(runtime.clone()).await, &prompt, &steps[..1])
+                            .await;
+                    let runtime = runtime.migrate(&$other).await?;
+                    let job = JobRuntime::new(runtime).await;
+                    output.append(&mut infer_steps(job, &steps[1..2], &steps[2..]).await);
+
+                    for (x, y) in output.iter().flatten().zip_eq(expected.iter().flatten()) {
+                        assert!((x - y).abs() < 1.0e-4, "{x} vs {y}");
+                    }
+                }};
+            }
+
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let (Ok(context), Ok(other)) =
+                    (create_context(&info).await, create_context(&info).await)
+                else {
+                    return Ok(());
+                };
+                match version {
+                    ModelVersion::V4 => check!(v4, info, context, other),
+                    ModelVersion::V5 => check!(v5, info, context, other),
+                    ModelVersion::V6 => check!(v6, info, context, other),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_migrate_state() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            macro_rules! check {
+                ($version:ident, $info:expr, $context:expr, $other:expr) => {{
+                    let builder = ModelBuilder::new(&$context, TinyModel::new($info.clone(), 42));
+                    let model = Build::<$version::Model>::build(builder).await?;
+                    let init = StateInit::Gaussian { std: 0.5, seed: 7 };
+                    let builder = StateBuilder::new(&$context, &$info)
+                        .num_batch(2)
+                        .init(init)
+                        .quant(StateQuant::Int8);
+                    let state = Build::<$version::State>::build(builder).await?;
+                    let runtime = $version::ModelRuntime::<f32>::new_with_state(model, state);
+                    let prompt = prompts(&$info).swap_remove(1);
+                    infer_steps(JobRuntime::new(runtime.clone()).await, &prompt, &[]).await;
+
+                    // the state of the migrated runtime is quantized and initialized as before
+                    let migrated = runtime.migrate(&$other).await?;
+                    let (state, other) = (runtime.state(), migrated.state());
+                    let other_state = other.as_any().downcast_ref::<$version::State>();
+                    assert!(other_state.is_some_and(|state| state.quant.is_some()));
+                    assert_eq!(other.init().to_vec(), state.init().to_vec());
+                    for batch in 0..2 {
+                        let expected = state.back(batch).await?.to_vec();
+                        let output = other.back(batch).await?.to_vec();
+                        for (x, y) in output.iter().zip_eq(expected.iter()) {
+                            assert!((x - y).abs() < 1.0e-3, "{x} vs {y}");
+                        }
+                    }
+                }};
+            }
+
+            for version in [ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let (Ok(context), Ok(other)) =
+                    (create_context(&info).await, create_context(&info).await)
+                else {
+                    return Ok(());
+                };
+                match version {
+                    ModelVersion::V5 => check!(v5, info, context, other),
+                    ModelVersion::V6 => check!(v6, info, context, other),
+                    ModelVersion::V4 => unreachable!(),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_trim_vocab() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V5);
+        assert_eq!(info.clone().trim_vocab(100).num_vocab, 100);
+        assert_eq!(info.clone().trim_vocab(101).num_vocab, 104);
+        assert_eq!(info.clone().trim_vocab(1000).num_vocab, info.num_vocab);
+
+        let prompts = prompts(&info.clone().trim_vocab(100));
+        let runtime = tokio::runtime::Runtime::new()?;
+        let Some(full) =
+            runtime.block_on(infer_gpu(TinyModel::new(info.clone(), 42), &prompts, None))?
+        else {
+            return Ok(());
+        };
+        let Some(trimmed) = runtime.block_on(infer_gpu(
+            TinyModel::new(info.clone(), 42),
+            &prompts,
+            Some(100),
+        ))?
+        else {
+            return Ok(());
+        };
+
+        for (full, trimmed) in full.iter().zip_eq(trimmed.iter()) {
+            let full = full.chunks_exact(info.num_vocab);
+            let trimmed = trimmed.chunks_exact(100);
+            for (full, trimmed) in full.zip_eq(trimmed) {
+                for (a, b) in full[..100].iter().zip_eq(trimmed) {
+                    assert!((a - b).abs() <= 1.0e-5 * a.abs().max(1.0));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tokens() {
+        let chunk = InferChunk(vec![
+            InferChunkBatch(vec![1, 2, 3]),
+            InferChunkBatch(vec![]),
+            InferChunkBatch(vec![99, 100, 101]),
+        ]);
+        assert!(chunk.check(102).is_ok());
+        assert_eq!(
+            chunk.check(100),
+            Err(InferError::TokenOutOfRange {
+                batch: 2,
+                token: 100,
+                num_vocab: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+            let info = TinyModel::info(version);
+            let model = TinyModel::new(info.clone(), 42);
+            let size: usize = model
+                .names()
+                .iter()
+                .map(|name| model.data(name).unwrap().1.len() * 2)
+                .sum();
+
+            let plan = |quant: Quant| (0..info.num_layer).map(|layer| (layer, quant)).collect();
+            let estimate = info.estimate_size(&plan(Quant::None), EmbedDevice::Gpu);
+            assert!(
+                estimate >= size && estimate < size + size / 8,
+                "{estimate} vs {size}"
+            );
+
+            let int8 = info.estimate_size(&plan(Quant::Int8), EmbedDevice::Gpu);
+            let nf4 = info.estimate_size(&plan(Quant::NF4), EmbedDevice::Gpu);
+            assert!(nf4 < int8 && int8 < estimate);
+            let q4k = info.estimate_size(&plan(Quant::Q4K), EmbedDevice::Gpu);
+            let q5k = info.estimate_size(&plan(Quant::Q5K), EmbedDevice::Gpu);
+            assert!(nf4 < q4k && q4k < q5k && q5k < int8);
+            let cpu = info.estimate_size(&plan(Quant::None), EmbedDevice::Cpu);
+            assert_eq!(estimate - cpu, info.head_buffer_size());
+        }
+    }
+
+    /// Read an `f32` or `i32` tensor from safetensors as `f32`.
+    fn read_reference(tensors: &SafeTensors, name: &str) -> Result<Vec<f32>> {
+        let tensor = tensors.tensor(name)?;
+        let data = tensor
+            .data()
+            .chunks_exact(4)
+            .map(|x| [x[0], x[1], x[2], x[3]]);
+        match tensor.dtype() {
+            Dtype::F32 => Ok(data.map(f32::from_le_bytes).collect()),
+            Dtype::I32 => Ok(data.map(|x| i32::from_le_bytes(x) as f32).collect()),
+            dtype => anyhow::bail!("unsupported reference dtype {dtype:?} of {name}"),
+        }
+    }
+
+    /// Compare against outputs of the Python reference implementation.
+    ///
+    /// Set `WEB_RWKV_REFERENCE` to a directory of `<name>.st` models converted by `convert_safetensors.py`,
+    /// each with a `<name>.ref.st` generated by `reference.py`.
+    /// The mean absolute logit error of every token must be within `WEB_RWKV_REFERENCE_TOLERANCE` (default `0.05`).
+    #[test]
+    fn test_reference() -> Result<()> {
+        let Ok(dir) = std::env::var("WEB_RWKV_REFERENCE") else {
+            return Ok(());
+        };
+        let tolerance: f32 = match std::env::var("WEB_RWKV_REFERENCE_TOLERANCE") {
+            Ok(value) => value.parse()?,
+            Err(_) => 0.05,
+        };
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".ref.st"))
+            else {
+                continue;
+            };
+
+            let reference = std::fs::read(&path)?;
+            let reference = SafeTensors::deserialize(&reference)?;
+            let tokens = read_reference(&reference, "tokens")?;
+            let tokens = tokens.into_iter().map(|x| x as u16).collect_vec();
+            let expected = read_reference(&reference, "logits")?;
+
+            let data = std::fs::read(path.with_file_name(format!("{name}.st")))?;
+            let model = SafeTensors::deserialize(&data)?;
+            let num_vocab = Loader::info(&model)?.num_vocab;
+            let Some(output) = runtime.block_on(infer_gpu(model, &[tokens], None))? else {
+                return Ok(());
+            };
+
+            assert_eq!(expected.len(), output[0].len(), "{name}: output size");
+            let (token, error) = expected
+                .chunks_exact(num_vocab)
+                .zip_eq(output[0].chunks_exact(num_vocab))
+                .map(|(a, b)| {
+                    let error = a.iter().zip_eq(b).map(|(a, b)| (a - b).abs()).sum::<f32>();
+                    error / num_vocab as f32
+                })
+                .enumerate()
+                .max_by(|(_, x), (_, y)| x.total_cmp(y))
+                .unwrap_or_default();
+            assert!(
+                error <= tolerance,
+                "{name}: token {token}, mean absolute logit error {error}"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::{
+    context::Context,
+    num::Float,
+    tensor::{
+        kind::ReadWrite,
+        ops::{Noise, TensorOp},
+        TensorError, TensorGpu,
+    },
+};
+
+/// Injects seeded noise into activations through hooks, for robustness and sampling diversity experiments.
+///
+/// Every op created draws a fresh seed from a counter, so that the noise differs across layers and steps,
+/// while the whole sequence is reproducible given the same seed and the same inference schedule.
+///
+/// ```ignore
+/// let noise = NoiseInjection::new(&context, Noise::Gaussian(0.1), 42);
+/// hooks.insert(
+///     v6::Hook::PostAtt(layer),
+///     Box::new(move |frame: v6::Frame<f16>| noise.op(&frame.buffer.x)),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct NoiseInjection {
+    context: Context,
+    noise: Noise,
+    seed: u64,
+    counter: Arc<AtomicU64>,
+}
+
+impl NoiseInjection {
+    pub fn new(context: &Context, noise: Noise, seed: u64) -> Self {
+        Self {
+            context: context.clone(),
+            noise,
+            seed,
+            counter: Default::default(),
+        }
+    }
+
+    /// Create the op that injects noise into `x` in place. Call this inside a hook and return the op.
+    pub fn op(&self, x: &TensorGpu<impl Float, ReadWrite>) -> Result<TensorOp, TensorError> {
+        let count = self.counter.fetch_add(1, Ordering::Relaxed);
+        let seed = self.seed ^ count.wrapping_mul(0x9e3779b97f4a7c15);
+        let seed = vec![seed as u32, (seed >> 32) as u32, 0, 0];
+        let seed = self.context.tensor_from_data([4, 1, 1, 1], seed)?;
+        TensorOp::noise(x, self.noise, &seed)
+    }
+
+    /// Restart the sequence of seeds.
+    pub fn reset(&self) {
+        self.counter.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,469 @@
+use anyhow::{bail, Result};
+use half::f16;
+
+use super::loader::{Reader, TensorFromReader};
+use crate::{
+    context::Context,
+    tensor::{
+        kind::ReadWrite, matrix::Matrix, ops::TensorOp, shape::TensorDimension, TensorCpu,
+        TensorGpu, TensorInto, TensorReshape, TensorShape,
+    },
+};
+
+/// A tensor of a loaded model that a delta can be added to.
+#[derive(Debug, Clone)]
+pub enum PatchTarget {
+    /// A matrix, and the factor that the model scales it with when loading.
+    Matrix(Matrix, f32),
+    Vector(TensorGpu<f16, ReadWrite>),
+}
+
+/// A model whose weights can be patched in place.
+pub trait Patchable {
+    fn context(&self) -> &Context;
+    /// Named tensors that accept deltas, grouped so that each group (e.g., a layer) is patched at once.
+    fn patch_targets(&self) -> Vec<Vec<(String, PatchTarget)>>;
+    /// All matrices of the model by name, e.g., to inspect their quantization or to run custom kernels against them.
+    /// The matrices share their buffers with the model, and should be treated as read-only.
+    fn matrices(&self) -> Vec<(String, Matrix)> {
+        self.patch_targets()
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, target)| match target {
+                PatchTarget::Matrix(matrix, _) => Some((name, matrix)),
+                PatchTarget::Vector(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Weight deltas to add to a loaded model, e.g., the difference between a fine-tuned checkpoint and its base.
+///
+/// The deltas are read and uploaded one layer at a time, so that the memory overhead is bounded by the size of one layer.
+/// Deltas of tensors that are transformed when loading (e.g., time decays) or that are not kept on device are ignored.
+#[derive(Clone)]
+pub struct Patch<R> {
+    /// Binary safetensors delta content, named as in the model.
+    pub data: R,
+    /// The factor to scale the deltas with.
+    pub alpha: f32,
+}
+
+impl<R: Reader> Patch<R> {
+    /// Add the deltas onto `model`. Returns the number of patched tensors.
+    ///
+    /// All targets are checked before any is modified, so the model is left intact on errors.
+    pub async fn apply(&self, model: &impl Patchable) -> Result<usize> {
+        let context = model.context();
+        let groups = model.patch_targets();
+
+        let mut count = 0;
+        for (name, target) in groups.iter().flatten() {
+            if !self.data.contains(name) {
+                continue;
+            }
+            let shape = self.data.shape(name)?.into_iter().rev().collect::<Vec<_>>();
+            let expected = match target {
+                PatchTarget::Matrix(Matrix::Fp16(matrix), _) => matrix.shape(),
+                PatchTarget::Matrix(_, _) => bail!("cannot patch quantized matrix {name}"),
+                PatchTarget::Vector(vector) => vector.shape(),
+            };
+            if shape.iter().product::<usize>() != expected.len() {
+                bail!("delta {name} of shape {shape:?} does not match {expected}");
+            }
+            count += 1;
+        }
+        let skipped = self.data.names().len() - count;
+        if skipped > 0 {
+            log::warn!("{skipped} deltas are not applied to the model");
+        }
+
+        for group in groups {
+            let mut ops = vec![];
+            for (name, target) in group {
+                if !self.data.contains(&name) {
+                    continue;
+                }
+                let delta = self.data.tensor(&name).await?;
+                let delta = TensorCpu::<f16>::from_reader(delta)?;
+                let (output, discount) = match target {
+                    PatchTarget::Matrix(Matrix::Fp16(matrix), discount) => (matrix, discount),
+                    PatchTarget::Vector(vector) => (vector, 1.0),
+                    PatchTarget::Matrix(_, _) => unreachable!(),
+                };
+
+                use TensorDimension::Dimension;
+                let shape = output.shape();
+                let delta: TensorGpu<f16, ReadWrite> = delta
+                    .reshape(
+                        Dimension(shape[0]),
+                        Dimension(shape[1]),
+                        Dimension(shape[2]),
+                        Dimension(shape[3]),
+                    )?
+                    .transfer_into(context);
+
+                let factor = vec![self.alpha * discount, 1.0, 0.0, 0.0];
+                let factor = context.tensor_from_data([4, 1, 1, 1], factor)?;
+                ops.push(TensorOp::blend(&factor, &delta, &output)?);
+            }
+
+            // wait for each group so that its deltas are freed before loading the next
+            context.submit(context.encode(&TensorOp::List(ops)));
+            context.wait();
+        }
+
+        Ok(count)
+    }
+}
+
+/// A new checkpoint of a loaded model, e.g., after another round of fine-tuning, of which only the changed tensors are uploaded.
+///
+/// The checkpoints are compared tensor by tensor; those that differ are read from `data` and written into the model in place,
+/// one group (e.g., layer) at a time, while the rest of the model stays on device as is. Quantized matrices are quantized again
+/// in their formats. LoRAs merged into the model when loading are not applied again.
+#[derive(Clone)]
+pub struct Reload<R> {
+    /// Binary safetensors content of the checkpoint that the model is loaded from.
+    pub base: R,
+    /// Binary safetensors content of the new checkpoint.
+    pub data: R,
+}
+
+/// What a [`Reload`] has done.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// Tensors uploaded, since they differ between the checkpoints.
+    pub reloaded: Vec<String>,
+    /// Number of groups (e.g., layers) that have any tensor uploaded.
+    pub groups: usize,
+    /// Number of tensors that are the same in both checkpoints.
+    pub unchanged: usize,
+    /// Tensors that differ but cannot be written in place, e.g., those transformed when loading (like time decays)
+    /// or not kept on device. The model must be built again for them to take effect.
+    pub stale: Vec<String>,
+}
+
+impl<R: Reader> Reload<R> {
+    /// Whether tensor `name` of the new checkpoint differs from that of the base.
+    pub async fn changed(&self, name: &str) -> Result<bool> {
+        if !self.base.contains(name) {
+            return Ok(true);
+        }
+        let (dt, shape, data) = self.data.tensor(name).await?;
+        let (base_dt, base_shape, base_data) = self.base.tensor(name).await?;
+        Ok(dt != base_dt || shape != base_shape || data != base_data)
+    }
+
+    /// The names of tensors of the new checkpoint that differ from those of the base, in order.
+    pub async fn diff(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for name in self.data.names() {
+            if self.changed(name).await? {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    /// Upload the changed tensors of the new checkpoint into `model`.
+    ///
+    /// All targets are checked before any is modified, so the model is left intact on errors.
+    pub async fn apply(&self, model: &impl Patchable) -> Result<ReloadReport> {
+        let context = model.context();
+        let groups = model.patch_targets();
+
+        for (name, target) in groups.iter().flatten() {
+            if !self.data.contains(name) {
+                continue;
+            }
+            let shape = self.data.shape(name)?.into_iter().rev().collect::<Vec<_>>();
+            let expected = match target {
+                PatchTarget::Matrix(matrix, _) => matrix.shape(),
+                PatchTarget::Vector(vector) => vector.shape(),
+            };
+            if shape.iter().product::<usize>() != expected.len() {
+                bail!("tensor {name} of shape {shape:?} does not match {expected}");
+            }
+        }
+
+        let mut report = ReloadReport::default();
+        for name in self.data.names() {
+            let target = groups.iter().flatten().any(|(x, _)| x == name);
+            if !target && self.changed(name).await? {
+                report.stale.push(name.to_string());
+            }
+        }
+        if !report.stale.is_empty() {
+            log::warn!(
+                "{} changed tensors cannot be reloaded in place",
+                report.stale.len()
+            );
+        }
+
+        for group in groups {
+            let count = report.reloaded.len();
+            let mut ops = vec![];
+            for (name, target) in group {
+                if !self.data.contains(&name) {
+                    continue;
+                }
+                if !self.changed(&name).await? {
+                    report.unchanged += 1;
+                    continue;
+                }
+
+                let tensor = self.data.tensor(&name).await?;
+                let tensor = TensorCpu::<f16>::from_reader(tensor)?;
+                let (shape, discount) = match &target {
+                    PatchTarget::Matrix(matrix, discount) => (matrix.shape(), *discount),
+                    PatchTarget::Vector(vector) => (vector.shape(), 1.0),
+                };
+
+                use TensorDimension::Dimension;
+                let tensor = tensor.reshape(
+                    Dimension(shape[0]),
+                    Dimension(shape[1]),
+                    Dimension(shape[2]),
+                    Dimension(shape[3]),
+                )?;
+                match target {
+                    PatchTarget::Matrix(matrix, _) => {
+                        let tensor: TensorGpu<f16, ReadWrite> = tensor
+                            .map(|x| f16::from_f32(discount * x.to_f32()))
+                            .transfer_into(context);
+                        ops.push(matrix.requant_op(&tensor)?);
+                    }
+                    PatchTarget::Vector(vector) => vector.load(&tensor)?,
+                }
+                report.reloaded.push(name);
+            }
+            if report.reloaded.len() == count {
+                continue;
+            }
+            report.groups += 1;
+
+            // wait for each group so that its tensors are freed before loading the next
+            context.submit(context.encode(&TensorOp::List(ops)));
+            context.wait();
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use half::f16;
+    use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+    use wgpu::{Instance, PowerPreference};
+
+    use super::{Patch, Reload};
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            loader::Reader,
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion, Quant},
+            tiny::TinyModel,
+            v5,
+        },
+        tensor::matrix::Matrix,
+    };
+
+    #[test]
+    fn test_patch() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            let base = TinyModel::new(info.clone(), 42);
+            let tuned = TinyModel::new(info.clone(), 43);
+
+            // the delta between the checkpoints
+            let names = tuned.names();
+            let deltas: Vec<(Vec<usize>, Vec<f16>)> = names
+                .iter()
+                .map(|name| {
+                    let (shape, x) = base.data(name).unwrap();
+                    let (_, y) = tuned.data(name).unwrap();
+                    let data = x
+                        .iter()
+                        .zip(y.iter())
+                        .map(|(x, y)| f16::from_f32(y.to_f32() - x.to_f32()))
+                        .collect();
+                    (shape.to_vec(), data)
+                })
+                .collect();
+            let views = names
+                .iter()
+                .zip(deltas.iter())
+                .map(|(name, (shape, data))| {
+                    let data = bytemuck::cast_slice(data);
+                    Ok((name, TensorView::new(Dtype::F16, shape.clone(), data)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let data = safetensors::serialize(views, &None)?;
+            let data = SafeTensors::deserialize(&data)?;
+            let patch = Patch { data, alpha: 1.0 };
+
+            let model: v5::Model =
+                Build::<v5::Model>::build(ModelBuilder::new(&context, base.clone())).await?;
+            let expected: v5::Model =
+                Build::<v5::Model>::build(ModelBuilder::new(&context, tuned)).await?;
+            let count = patch.apply(&model).await?;
+            assert!(count > 0);
+
+            let layer = &model.tensor.layers[1];
+            let layer_expected = &expected.tensor.layers[1];
+            let check = |x: Vec<f16>, y: Vec<f16>| {
+                for (x, y) in x.into_iter().zip(y) {
+                    assert!((x.to_f32() - y.to_f32()).abs() < 2.0e-3, "{x} vs {y}");
+                }
+            };
+            check(
+                layer.att_layer_norm.w.back().await.to_vec(),
+                layer_expected.att_layer_norm.w.back().await.to_vec(),
+            );
+            let (Matrix::Fp16(x), Matrix::Fp16(y)) = (&layer.ffn.w_v, &layer_expected.ffn.w_v)
+            else {
+                unreachable!()
+            };
+            check(x.back().await.to_vec(), y.back().await.to_vec());
+
+            // quantized matrices are rejected before anything is modified
+            let quant = HashMap::from([(0, Quant::Int8)]);
+            let model: v5::Model =
+                Build::<v5::Model>::build(ModelBuilder::new(&context, base).quant(quant)).await?;
+            let before = model.tensor.head.layer_norm.w.back().await.to_vec();
+            assert!(patch.apply(&model).await.is_err());
+            assert_eq!(model.tensor.head.layer_norm.w.back().await.to_vec(), before);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_reload() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            // the new checkpoint only changes layer 1
+            let base = TinyModel::new(info.clone(), 42);
+            let tuned = TinyModel::new(info.clone(), 43);
+            let names = base.names();
+            let views = names
+                .iter()
+                .map(|&name| {
+                    let model = match name.starts_with("blocks.1.") {
+                        true => &tuned,
+                        false => &base,
+                    };
+                    let (shape, data) = model.data(name).unwrap();
+                    let data = bytemuck::cast_slice(data);
+                    Ok((name, TensorView::new(Dtype::F16, shape.to_vec(), data)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let data = safetensors::serialize(views, &None)?;
+            let base_data = base.serialize()?;
+            let reload = Reload {
+                base: SafeTensors::deserialize(&base_data)?,
+                data: SafeTensors::deserialize(&data)?,
+            };
+
+            let changed = reload.diff().await?;
+            assert!(!changed.is_empty());
+            assert!(changed.iter().all(|name| name.starts_with("blocks.1.")));
+
+            // layer 1 is quantized, so that it is quantized again in place
+            let quant = HashMap::from([(1, Quant::Int8)]);
+            let model: v5::Model = Build::<v5::Model>::build(
+                ModelBuilder::new(&context, base.clone()).quant(quant.clone()),
+            )
+            .await?;
+            let model_data = SafeTensors::deserialize(&data)?;
+            let expected: v5::Model =
+                Build::<v5::Model>::build(ModelBuilder::new(&context, model_data).quant(quant))
+                    .await?;
+            let before = model.tensor.layers[0]
+                .ffn_layer_norm
+                .w
+                .back()
+                .await
+                .to_vec();
+
+            let report = reload.apply(&model).await?;
+            assert_eq!(report.groups, 1);
+            assert_eq!(report.reloaded.len() + report.stale.len(), changed.len());
+            assert!(report
+                .reloaded
+                .contains(&"blocks.1.ffn.value.weight".to_string()));
+            // time decays are transformed when loading
+            assert!(report
+                .stale
+                .contains(&"blocks.1.att.time_decay".to_string()));
+
+            let layer = &model.tensor.layers[1];
+            let layer_expected = &expected.tensor.layers[1];
+            assert_eq!(
+                layer.att_layer_norm.w.back().await.to_vec(),
+                layer_expected.att_layer_norm.w.back().await.to_vec()
+            );
+            let (
+                Matrix::Int8 { w, m },
+                Matrix::Int8 {
+                    w: w_expected,
+                    m: m_expected,
+                },
+            ) = (&layer.ffn.w_v, &layer_expected.ffn.w_v)
+            else {
+                unreachable!()
+            };
+            assert_eq!(w.back().await.to_vec(), w_expected.back().await.to_vec());
+            assert_eq!(m.back().await.to_vec(), m_expected.back().await.to_vec());
+            assert_eq!(
+                model.tensor.layers[0]
+                    .ffn_layer_norm
+                    .w
+                    .back()
+                    .await
+                    .to_vec(),
+                before
+            );
+
+            // reloading the same checkpoint again changes nothing
+            let reload = Reload {
+                base: SafeTensors::deserialize(&data)?,
+                data: SafeTensors::deserialize(&data)?,
+            };
+            let report = reload.apply(&model).await?;
+            assert_eq!(report.groups, 0);
+            assert!(report.reloaded.is_empty() && report.stale.is_empty());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,227 @@
+use std::{collections::HashMap, hash::Hash};
+
+use anyhow::Result;
+use serde::{de::DeserializeSeed, Serialize};
+
+use crate::{context::Context, tensor::serialization::Seed};
+
+#[derive(Debug)]
+struct PoolEntry<M> {
+    /// Serialized weights kept on host, in the same (possibly quantized) format as on device.
+    data: Vec<u8>,
+    /// The model, if its weights are resident on device.
+    model: Option<M>,
+    priority: usize,
+    pinned: bool,
+    last_used: u64,
+}
+
+/// Hosts several models on one device within a memory budget.
+///
+/// Each model keeps a serialized copy of its weights on host. When a model is requested but does not fit,
+/// the device weights of other models are dropped, lowest priority first and then least recently used,
+/// and restored from the host copy on demand. Pinned models are never evicted.
+///
+/// Note that the device memory of an evicted model is only freed once all its clones are dropped,
+/// e.g., the runtimes built from it.
+#[derive(Debug)]
+pub struct ModelPool<K, M> {
+    context: Context,
+    budget: usize,
+    clock: u64,
+    entries: HashMap<K, PoolEntry<M>>,
+}
+
+impl<K, M> ModelPool<K, M>
+where
+    K: Eq + Hash + Clone + std::fmt::Debug,
+    M: Clone + Serialize + Send + 'static,
+    for<'de> Seed<'de, Context, M>: DeserializeSeed<'de, Value = M>,
+{
+    /// Create a pool that keeps at most `budget` bytes of model weights on device.
+    pub fn new(context: &Context, budget: usize) -> Self {
+        Self {
+            context: context.clone(),
+            budget,
+            clock: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Add a model whose weights are on device. This reads the weights back once to make the host copy.
+    /// Models with higher `priority` are evicted later.
+    pub async fn insert(&mut self, key: K, model: M, priority: usize) -> Result<()> {
+        // serializing reads back the weights in place, which blocks
+        let data = {
+            let model = model.clone();
+            tokio::task::spawn_blocking(move || cbor4ii::serde::to_vec(vec![], &model)).await??
+        };
+        self.clock += 1;
+        let entry = PoolEntry {
+            data,
+            model: Some(model),
+            priority,
+            pinned: false,
+            last_used: self.clock,
+        };
+        self.entries.insert(key.clone(), entry);
+        self.make_room(&key);
+        Ok(())
+    }
+
+    /// Remove a model from the pool.
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Keep a model on device regardless of the budget, or allow it to be evicted again.
+    pub fn pin(&mut self, key: &K, pinned: bool) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.pinned = pinned;
+        }
+    }
+
+    pub fn set_priority(&mut self, key: &K, priority: usize) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.priority = priority;
+        }
+    }
+
+    /// Check if the weights of a model are on device.
+    pub fn is_resident(&self, key: &K) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|entry| entry.model.is_some())
+    }
+
+    /// Total size of the weights on device, in bytes.
+    pub fn resident_size(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.model.is_some())
+            .map(|entry| entry.data.len())
+            .sum()
+    }
+
+    /// Drop the device weights of a model, unless it is pinned.
+    pub fn evict(&mut self, key: &K) -> bool {
+        match self.entries.get_mut(key) {
+            Some(entry) if !entry.pinned && entry.model.is_some() => {
+                log::info!("evict model {:?}", key);
+                entry.model = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Get a model, restoring its weights onto device if they were evicted.
+    pub fn get(&mut self, key: &K) -> Result<Option<M>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Ok(None);
+        };
+        entry.last_used = clock;
+
+        if entry.model.is_none() {
+            log::info!("restore model {:?}", key);
+            let reader = cbor4ii::core::utils::SliceReader::new(&entry.data);
+            let mut deserializer = cbor4ii::serde::Deserializer::new(reader);
+            let seed = Seed::<Context, M>::new(&self.context);
+            entry.model = Some(seed.deserialize(&mut deserializer)?);
+        }
+        let model = entry.model.clone();
+
+        self.make_room(key);
+        Ok(model)
+    }
+
+    /// Evict other models until the budget is met or nothing more can be evicted.
+    fn make_room(&mut self, key: &K) {
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(other, entry)| *other != key && !entry.pinned && entry.model.is_some())
+            .map(|(other, entry)| (entry.priority, entry.last_used, other.clone()))
+            .collect();
+        candidates.sort_by_key(|(priority, last_used, _)| (*priority, *last_used));
+
+        let mut candidates = candidates.into_iter();
+        while self.resident_size() > self.budget {
+            let Some((_, _, other)) = candidates.next() else {
+                log::warn!("model pool exceeds its budget of {} bytes", self.budget);
+                break;
+            };
+            self.evict(&other);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::ModelPool;
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion},
+            tiny::TinyModel,
+            v5,
+        },
+    };
+
+    #[test]
+    fn test_model_pool() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            let mut models = vec![];
+            for seed in 0..3 {
+                let model = TinyModel::new(info.clone(), seed);
+                let model = Build::<v5::Model>::build(ModelBuilder::new(&context, model)).await?;
+                models.push(model);
+            }
+
+            // measure one model, and leave room for two
+            let mut pool = ModelPool::<_, v5::Model>::new(&context, usize::MAX);
+            pool.insert(0, models[0].clone(), 0).await?;
+            let size = pool.resident_size();
+            let mut pool = ModelPool::<_, v5::Model>::new(&context, 2 * size);
+
+            pool.insert("a", models[0].clone(), 1).await?;
+            pool.insert("b", models[1].clone(), 0).await?;
+            pool.pin(&"a", true);
+            pool.insert("c", models[2].clone(), 0).await?;
+            // "b" has the lowest priority and was used least recently
+            assert!(pool.is_resident(&"a"));
+            assert!(!pool.is_resident(&"b"));
+            assert!(pool.is_resident(&"c"));
+
+            // restoring "b" evicts "c" since "a" is pinned
+            let restored = pool.get(&"b")?.unwrap();
+            assert!(pool.is_resident(&"b"));
+            assert!(!pool.is_resident(&"c"));
+            assert_eq!(pool.resident_size(), 2 * size);
+
+            let expected = models[1].tensor.head.layer_norm.w.back().await;
+            let actual = restored.tensor.head.layer_norm.w.back().await;
+            assert_eq!(expected.to_vec(), actual.to_vec());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    infer::{InferInput, InferInputBatch, InferOption},
+    model::State,
+    tap::TappedOutput,
+    JobRuntime,
+};
+
+/// A model runtime whose jobs run only the first `k` layers, and read back the output of layer `k - 1`
+/// (i.e., [`Tap::PostFfn(k - 1)`](super::tap::Tap::PostFfn)) for the tokens that have outputs.
+///
+/// The later layers and the head are skipped, so probing a prompt costs a fraction of a full prefill.
+/// This is meant for routing features, e.g., for a multi-model router to detect the language or domain of a request
+/// with a small [`ProbeHead`] before picking the model to serve it. The logits of a probe job are left empty (zeros).
+///
+/// Give a probe its own state (e.g., `v6::ModelRuntime::new(model.clone(), 1)` shares the weights but not the state),
+/// so that probing never touches the states of requests being served.
+#[derive(Debug, Clone)]
+pub struct Probe<R>(pub R, pub usize);
+
+impl JobRuntime<InferInput, TappedOutput> {
+    /// Run each prompt from the initial state in a batch of `state`, which must be the runtime's state,
+    /// and return the hidden state of its last token at the (first) tap, i.e., the features of a [`Probe`].
+    ///
+    /// Prompts are run as many at a time as there are batches.
+    pub async fn probe(
+        &self,
+        state: &(impl State + ?Sized),
+        prompts: &[Vec<u16>],
+        token_chunk_size: usize,
+    ) -> Result<Vec<Vec<f32>>> {
+        if prompts.iter().any(Vec::is_empty) {
+            bail!("empty prompt");
+        }
+
+        let num_batch = state.num_batch();
+        let mut features = Vec::with_capacity(prompts.len());
+        for prompts in prompts.chunks(num_batch) {
+            let batches = (0..num_batch)
+                .map(|batch| InferInputBatch {
+                    tokens: prompts.get(batch).cloned().unwrap_or_default().into(),
+                    option: InferOption::Last,
+                    ..Default::default()
+                })
+                .collect();
+            for batch in 0..prompts.len() {
+                state.load(state.init(), batch)?;
+            }
+
+            let mut input = InferInput::new(batches, token_chunk_size);
+            let mut outputs = vec![None; prompts.len()];
+            while input.num_token() > 0 {
+                let (next, output) = self.infer(input).await;
+                input = next;
+                let Some((_, taps)) = output.taps.into_iter().next() else {
+                    continue;
+                };
+                for (output, tap) in outputs.iter_mut().zip(taps) {
+                    if tap.size() > 0 {
+                        *output = Some(tap);
+                    }
+                }
+            }
+            for output in outputs {
+                let Some(output) = output else {
+                    bail!("no tap to probe");
+                };
+                features.push(output.to_vec());
+            }
+        }
+        Ok(features)
+    }
+}
+
+/// A linear classifier over the features of a [`Probe`], e.g., trained offline with logistic regression.
+///
+/// Features are standardized (to zero mean and unit variance) before the linear map,
+/// since the scale of the residual stream grows with depth.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProbeHead {
+    /// One row of `num_emb` weights for each class.
+    pub weight: Vec<Vec<f32>>,
+    /// One bias for each class.
+    pub bias: Vec<f32>,
+}
+
+impl ProbeHead {
+    /// Probabilities of the classes given the `features` of a prompt.
+    pub fn classify(&self, features: &[f32]) -> Result<Vec<f32>> {
+        if self.weight.len() != self.bias.len() {
+            bail!(
+                "{} rows of weights for {} biases",
+                self.weight.len(),
+                self.bias.len()
+            );
+        }
+        if let Some(row) = self.weight.iter().find(|row| row.len() != features.len()) {
+            bail!("{} weights for {} features", row.len(), features.len());
+        }
+
+        let len = features.len().max(1) as f32;
+        let mean = features.iter().sum::<f32>() / len;
+        let var = features.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / len;
+        let std = (var + 1.0e-5).sqrt();
+
+        let logits: Vec<f32> = self
+            .weight
+            .iter()
+            .zip(self.bias.iter())
+            .map(|(row, bias)| {
+                let dot: f32 = row
+                    .iter()
+                    .zip(features.iter())
+                    .map(|(w, x)| w * (x - mean) / std)
+                    .sum();
+                dot + bias
+            })
+            .collect();
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exp: Vec<f32> = logits.iter().map(|x| (x - max).exp()).collect();
+        let sum: f32 = exp.iter().sum();
+        Ok(exp.into_iter().map(|x| x / sum).collect())
+    }
+
+    /// The most probable class given the `features` of a prompt.
+    pub fn predict(&self, features: &[f32]) -> Result<usize> {
+        let probs = self.classify(features)?;
+        let class = probs
+            .iter()
+            .enumerate()
+            .max_by(|(_, x), (_, y)| x.total_cmp(y))
+            .map_or(0, |(class, _)| class);
+        Ok(class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{Probe, ProbeHead};
+    use crate::runtime::{
+        model::{ModelBuilder, ModelInfo, ModelRuntime, ModelVersion, State},
+        tiny::{
+            tests::{create_context, prompts, with_runtime, Reference},
+            TinyModel,
+        },
+        JobRuntime,
+    };
+
+    #[test]
+    fn test_probe() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = ModelInfo {
+                    num_layer: 8,
+                    ..TinyModel::info(version)
+                };
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let model = TinyModel::new(info.clone(), 42);
+                let prompts = prompts(&info);
+                let prompts = [prompts.clone(), vec![prompts[0][..7].to_vec()]].concat();
+
+                let reference = Reference::new(&model);
+                let expected = prompts
+                    .iter()
+                    .map(|tokens| {
+                        let mut state = reference.init();
+                        let mut layers = vec![];
+                        for &token in tokens {
+                            layers = reference.forward_layers(&mut state, token).0;
+                        }
+                        layers[3].clone()
+                    })
+                    .collect_vec();
+
+                // run the first 4 layers, with fewer batches than prompts
+                let builder = ModelBuilder::new(&context, model);
+                let (state, runtime) = with_runtime!(builder, version, 2, |runtime| {
+                    let runtime = runtime();
+                    let state: Box<dyn State> = Box::new(runtime.state());
+                    (state, JobRuntime::new(Probe(runtime, 4)).await)
+                });
+                let skipped = state.back_layers(0, &[4, 7]).await?.to_vec();
+
+                let features = runtime.probe(&*state, &prompts, 32).await?;
+                assert_eq!(features.len(), prompts.len());
+                for (features, expected) in features.iter().zip_eq(expected.iter()) {
+                    for (a, b) in features.iter().zip_eq(expected.iter()) {
+                        assert!(
+                            (a - b).abs() < 1.0e-2 * b.abs().max(1.0),
+                            "{version:?}: {a} vs {b}"
+                        );
+                    }
+                }
+
+                // each prompt starts afresh, and the skipped layers of the state are left untouched
+                assert_eq!(
+                    runtime.probe(&*state, &prompts[2..], 32).await?[0],
+                    features[2]
+                );
+                assert_eq!(state.back_layers(0, &[4, 7]).await?.to_vec(), skipped);
+                assert!(runtime.probe(&*state, &[vec![]], 32).await.is_err());
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_probe_head() -> Result<()> {
+        let head = ProbeHead {
+            weight: vec![vec![1.0, 0.0, -1.0], vec![-1.0, 0.0, 1.0]],
+            bias: vec![0.0, 0.5],
+        };
+        let probs = head.classify(&[3.0, 2.0, 1.0])?;
+        assert!((probs.iter().sum::<f32>() - 1.0).abs() < 1.0e-6);
+        assert!(probs[0] > probs[1]);
+        assert_eq!(head.predict(&[3.0, 2.0, 1.0])?, 0);
+        assert_eq!(head.predict(&[1.0, 2.0, 3.0])?, 1);
+        // features are standardized, so scaling them changes nothing
+        let scaled = head.classify(&[30.0, 20.0, 10.0])?;
+        for (a, b) in probs.iter().zip(scaled.iter()) {
+            assert!((a - b).abs() < 1.0e-4);
+        }
+        assert!(head.classify(&[1.0, 2.0]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use std::{collections::HashMap, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::model::{ModelInfo, ModelVersion};
+
+/// Size of the vocabulary of the World tokenizer. Models with smaller vocabularies use the Pile tokenizer.
+const WORLD_VOCAB_SIZE: usize = 65536;
+/// Metadata keys that name the prompt format or the model, in order of precedence.
+const FORMAT_KEYS: [&str; 2] = ["prompt_format", "chat_format"];
+const NAME_KEYS: [&str; 2] = ["name", "model_name"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+#[error("unknown prompt format {0}")]
+pub struct UnknownPromptFormat(pub String);
+
+/// The chat prompt format of a family of community models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PromptFormat {
+    /// `User: ...\n\nAssistant: ...`, for World models of all versions.
+    World,
+    /// `Bob: ...\n\nAlice: ...`, for Raven models (V4 chat fine-tunes with the Pile tokenizer).
+    Raven,
+    /// `Instruction: ...\n\nResponse: ...`, for instruct fine-tunes of Eagle (V5) and Finch (V6).
+    Instruct,
+}
+
+impl FromStr for PromptFormat {
+    type Err = UnknownPromptFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "world" | "chat" => Ok(Self::World),
+            "raven" => Ok(Self::Raven),
+            "instruct" | "instruction" => Ok(Self::Instruct),
+            _ => Err(UnknownPromptFormat(s.into())),
+        }
+    }
+}
+
+impl PromptFormat {
+    pub fn template(&self) -> ChatTemplate {
+        let (user, bot) = match self {
+            PromptFormat::World => ("User", "Assistant"),
+            PromptFormat::Raven => ("Bob", "Alice"),
+            PromptFormat::Instruct => ("Instruction", "Response"),
+        };
+        ChatTemplate {
+            user: user.into(),
+            bot: bot.into(),
+            stop: vec!["\n\n".into()],
+            stop_tokens: vec![0],
+        }
+    }
+}
+
+/// How turns of a conversation are written into a prompt, and what ends a reply.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatTemplate {
+    /// Name of the user's role, e.g., `User`.
+    pub user: String,
+    /// Name of the model's role, e.g., `Assistant`.
+    pub bot: String,
+    /// Texts that end a reply once generated. They are not part of the reply.
+    pub stop: Vec<String>,
+    /// Tokens that end a reply, e.g., the end-of-text token.
+    pub stop_tokens: Vec<u16>,
+}
+
+impl ChatTemplate {
+    /// One finished turn, e.g., `User: hi\n\nAssistant: hello\n\n`.
+    pub fn turn(&self, user: &str, bot: &str) -> String {
+        format!(
+            "{}: {}\n\n{}: {}\n\n",
+            self.user,
+            user.trim(),
+            self.bot,
+            bot.trim()
+        )
+    }
+
+    /// The prompt of a new user message, after which the model writes its reply.
+    /// Note that there is no space after the bot's name, since the model's tokenizer puts it at the start of the reply.
+    pub fn query(&self, user: &str) -> String {
+        format!("{}: {}\n\n{}:", self.user, user.trim(), self.bot)
+    }
+
+    /// Render a conversation of finished turns followed by a new user message.
+    pub fn render(&self, turns: &[(String, String)], user: &str) -> String {
+        let mut text: String = turns.iter().map(|(x, y)| self.turn(x, y)).collect();
+        text.push_str(&self.query(user));
+        text
+    }
+
+    /// Cut a reply at the first stop text. Returns `None` if no stop text is found yet.
+    pub fn cut<'a>(&self, reply: &'a str) -> Option<&'a str> {
+        self.stop
+            .iter()
+            .filter_map(|stop| reply.find(stop.as_str()))
+            .min()
+            .map(|index| &reply[..index])
+    }
+}
+
+/// Where a [`PromptRecommendation`] comes from, from the most to the least reliable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PromptSource {
+    /// Set by the user.
+    Override,
+    /// Named in the model's metadata.
+    Metadata,
+    /// Guessed from the model's name.
+    Name,
+    /// Guessed from the model's version and tokenizer.
+    Info,
+}
+
+/// A suggested prompt format for a model. See [`PromptRecommendation::detect`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PromptRecommendation {
+    pub format: PromptFormat,
+    pub template: ChatTemplate,
+    pub source: PromptSource,
+}
+
+impl PromptRecommendation {
+    fn new(format: PromptFormat, source: PromptSource) -> Self {
+        Self {
+            format,
+            template: format.template(),
+            source,
+        }
+    }
+
+    /// Suggest the prompt format of a model, from (in order of precedence):
+    /// 1. the `prompt_format` entry of the metadata, e.g., `world`, `raven` or `instruct`;
+    /// 2. the model's name, from the metadata or else `name` (e.g., the file name);
+    /// 3. the model's version and vocabulary size.
+    pub fn detect(
+        info: &ModelInfo,
+        name: &str,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> Self {
+        let lookup = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|&key| metadata.and_then(|metadata| metadata.get(key)))
+        };
+
+        if let Some(format) = lookup(&FORMAT_KEYS) {
+            match format.parse() {
+                Ok(format) => return Self::new(format, PromptSource::Metadata),
+                Err(err) => log::warn!("{err}"),
+            }
+        }
+
+        let name = lookup(&NAME_KEYS).map(String::as_str).unwrap_or(name);
+        let name = name.to_lowercase();
+        if name.contains("instruct") {
+            return Self::new(PromptFormat::Instruct, PromptSource::Name);
+        }
+        if name.contains("raven") {
+            return Self::new(PromptFormat::Raven, PromptSource::Name);
+        }
+        if ["world", "eagle", "finch"].iter().any(|x| name.contains(x)) {
+            return Self::new(PromptFormat::World, PromptSource::Name);
+        }
+
+        let format = match (info.version, info.num_vocab) {
+            (ModelVersion::V4, num_vocab) if num_vocab < WORLD_VOCAB_SIZE => PromptFormat::Raven,
+            _ => PromptFormat::World,
+        };
+        Self::new(format, PromptSource::Info)
+    }
+
+    /// Replace the recommendation if the user chooses a format.
+    pub fn or_override(self, format: Option<PromptFormat>) -> Self {
+        match format {
+            Some(format) => Self::new(format, PromptSource::Override),
+            None => self,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{PromptFormat, PromptRecommendation, PromptSource};
+    use crate::runtime::model::{ModelInfo, ModelVersion};
+
+    fn info(version: ModelVersion, num_vocab: usize) -> ModelInfo {
+        ModelInfo {
+            version,
+            num_layer: 24,
+            num_emb: 2048,
+            num_hidden: 7168,
+            num_vocab,
+            num_head: 32,
+            time_mix_adapter_size: 0,
+            time_decay_adapter_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_detect_prompt() {
+        let v4 = info(ModelVersion::V4, 50277);
+        let v6 = info(ModelVersion::V6, 65536);
+        let detect = |info, name| PromptRecommendation::detect(info, name, None);
+
+        let rec = detect(&v4, "RWKV-4-Raven-7B-v12-Eng98%-Other2%-20230521-ctx8192");
+        assert_eq!(
+            (rec.format, rec.source),
+            (PromptFormat::Raven, PromptSource::Name)
+        );
+        assert_eq!(rec.template.user, "Bob");
+        let rec = detect(&v6, "RWKV-x060-World-1B6-v2.1-20240328-ctx4096.st");
+        assert_eq!(
+            (rec.format, rec.source),
+            (PromptFormat::World, PromptSource::Name)
+        );
+        let rec = detect(&v6, "rwkv-6-finch-1b6-instruct");
+        assert_eq!(rec.format, PromptFormat::Instruct);
+        assert_eq!(detect(&v4, "model.st").format, PromptFormat::Raven);
+        assert_eq!(detect(&v6, "model.st").source, PromptSource::Info);
+
+        let metadata = HashMap::from([
+            ("prompt_format".into(), "Instruct".into()),
+            ("name".into(), "RWKV-4-Raven".into()),
+        ]);
+        let rec = PromptRecommendation::detect(&v4, "model.st", Some(&metadata));
+        assert_eq!(
+            (rec.format, rec.source),
+            (PromptFormat::Instruct, PromptSource::Metadata)
+        );
+        let rec = rec.or_override(Some(PromptFormat::World));
+        assert_eq!(
+            (rec.format, rec.source),
+            (PromptFormat::World, PromptSource::Override)
+        );
+
+        let template = rec.template;
+        let turns = [("Hi!".into(), "Hello.".into())];
+        assert_eq!(
+            template.render(&turns, " How are you? "),
+            "User: Hi!\n\nAssistant: Hello.\n\nUser: How are you?\n\nAssistant:"
+        );
+        assert_eq!(template.cut(" Fine.\n\nUser:"), Some(" Fine."));
+        assert_eq!(template.cut(" Fine."), None);
+    }
+}
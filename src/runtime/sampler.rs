@@ -0,0 +1,1151 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use web_rwkv_derive::{Deref, DerefMut};
+
+use super::infer::{InferOutput, InferRedirect, SampleOption};
+use crate::{
+    context::Context,
+    tensor::{
+        kind::ReadWrite,
+        ops::{LogitTransform, TensorOp},
+        TensorCpu, TensorError, TensorGpu, TensorInit, TensorShape,
+    },
+};
+
+/// Sampling parameters of a batch, applied on GPU by [`Sampled`] runtimes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SamplerOption {
+    /// Pick the most probable token if this is zero.
+    pub temperature: f32,
+    pub top_p: f32,
+    /// Only sample among this many most probable tokens; no limit if zero.
+    pub top_k: usize,
+    /// Subtracted from the logits of the tokens the batch has sampled.
+    pub presence_penalty: f32,
+    /// Subtracted from the logits of the tokens the batch has sampled, once for each time sampled.
+    pub frequency_penalty: f32,
+    /// Controls on the end-of-sequence token by the number of tokens generated, applied before the penalties.
+    #[serde(default)]
+    pub length: LengthOption,
+    /// Penalty on extending repetitions of the recent tokens, applied after the presence and frequency penalties.
+    #[serde(default)]
+    pub dry: DryOption,
+}
+
+impl Default for SamplerOption {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_p: 0.5,
+            top_k: 0,
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+            length: Default::default(),
+            dry: Default::default(),
+        }
+    }
+}
+
+impl From<SampleOption> for SamplerOption {
+    fn from(value: SampleOption) -> Self {
+        Self {
+            temperature: value.temperature,
+            top_p: value.top_p,
+            ..Default::default()
+        }
+    }
+}
+
+/// How the logit of the end-of-sequence token changes as a batch generates, e.g., to curb rambling outputs.
+///
+/// With `n` tokens generated beyond `start`, `boost * n + |logit| * (decay^n - 1)` is added to the logit of `eos`.
+/// Before `min_length` tokens are generated, `eos` is never sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LengthOption {
+    /// The end-of-sequence token. Nothing is applied if `None`.
+    pub eos: Option<u16>,
+    pub min_length: usize,
+    /// The number of generated tokens from which `eos` is boosted.
+    pub start: usize,
+    /// Added to the logit of `eos` for each token generated beyond `start`.
+    pub boost: f32,
+    /// Length normalization: scales the magnitude of the logit of `eos` up exponentially with each token generated
+    /// beyond `start`. No effect if 1.
+    pub decay: f32,
+}
+
+impl Default for LengthOption {
+    fn default() -> Self {
+        Self {
+            eos: None,
+            min_length: 0,
+            start: 0,
+            boost: 0.0,
+            decay: 1.0,
+        }
+    }
+}
+
+impl LengthOption {
+    /// The token to adjust with `generated` tokens so far, and the amount added to its logit, both constant
+    /// and in proportion to the logit's magnitude; `None` if nothing is to be changed.
+    pub fn adjust(&self, generated: usize) -> Option<(u16, f32, f32)> {
+        let eos = self.eos?;
+        if generated < self.min_length {
+            return Some((eos, f32::NEG_INFINITY, 0.0));
+        }
+        let n = generated.saturating_sub(self.start);
+        let add = self.boost * n as f32;
+        let scale = match self.decay > 0.0 {
+            true => self.decay.powf(n as f32) - 1.0,
+            false => 0.0,
+        };
+        (add != 0.0 || scale != 0.0).then_some((eos, add, scale))
+    }
+
+    /// Apply the adjustment to `logits` with `generated` tokens so far.
+    pub fn apply(&self, logits: &mut [f32], generated: usize) {
+        let Some((token, add, scale)) = self.adjust(generated) else {
+            return;
+        };
+        if let Some(logit) = logits.get_mut(token as usize) {
+            *logit += add + logit.abs() * scale;
+        }
+    }
+}
+
+/// The DRY ("don't repeat yourself") penalty: a token that would extend a repetition of the recent tokens
+/// of at least `allowed_length` tokens is penalized by `multiplier * base^(length - allowed_length)`,
+/// where `length` is that of the longest repetition it extends. Only the last [`Sampler::HISTORY_LEN`] tokens are looked at.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DryOption {
+    /// Nothing is applied if zero.
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+}
+
+impl Default for DryOption {
+    fn default() -> Self {
+        Self {
+            multiplier: 0.0,
+            base: 1.75,
+            allowed_length: 2,
+        }
+    }
+}
+
+impl DryOption {
+    /// Apply the penalty to `logits` after `history`, oldest first.
+    pub fn apply(&self, logits: &mut [f32], history: &[u16]) {
+        if self.multiplier == 0.0 || history.len() < 2 {
+            return;
+        }
+        let last = history.len() - 1;
+        let mut lengths = BTreeMap::<u16, usize>::new();
+        for index in 0..last {
+            let len = (0..=index)
+                .take_while(|&k| history[index - k] == history[last - k])
+                .count();
+            if len >= self.allowed_length {
+                let next = lengths.entry(history[index + 1]).or_default();
+                *next = len.max(*next);
+            }
+        }
+        for (token, len) in lengths {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= self.multiplier * self.base.powf((len - self.allowed_length) as f32);
+            }
+        }
+    }
+}
+
+/// Random state of a batch of a [`Sampler`]: a counter-based Philox4x32-10 generator keyed by the seed.
+///
+/// The random numbers are drawn on CPU with integer arithmetic only, so the same state gives the same sequence
+/// on every adapter and backend. The state can be saved and [restored](Sampler::set_random) to resume a generation elsewhere;
+/// the token drawn with a number may still differ if the logits themselves differ between devices.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RandomState {
+    pub seed: u64,
+    /// Number of random numbers drawn so far.
+    pub counter: u64,
+}
+
+impl RandomState {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, counter: 0 }
+    }
+
+    /// The Philox4x32-10 block of a counter and a key.
+    pub fn philox(counter: [u32; 4], key: [u32; 2]) -> [u32; 4] {
+        const M0: u64 = 0xd2511f53;
+        const M1: u64 = 0xcd9e8d57;
+        const W0: u32 = 0x9e3779b9;
+        const W1: u32 = 0xbb67ae85;
+
+        let (mut x, mut key) = (counter, key);
+        for round in 0..10 {
+            if round > 0 {
+                key = [key[0].wrapping_add(W0), key[1].wrapping_add(W1)];
+            }
+            let p0 = M0 * x[0] as u64;
+            let p1 = M1 * x[2] as u64;
+            x = [
+                (p1 >> 32) as u32 ^ x[1] ^ key[0],
+                p1 as u32,
+                (p0 >> 32) as u32 ^ x[3] ^ key[1],
+                p0 as u32,
+            ];
+        }
+        x
+    }
+
+    /// Draw the next number uniformly in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        let counter = [self.counter as u32, (self.counter >> 32) as u32, 0, 0];
+        let key = [self.seed as u32, (self.seed >> 32) as u32];
+        self.counter = self.counter.wrapping_add(1);
+        let [x, ..] = Self::philox(counter, key);
+        (x >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SamplerBatch {
+    option: SamplerOption,
+    random: RandomState,
+    /// Number of tokens generated since set.
+    length: usize,
+    /// Number of tokens pushed into the ring of the batch.
+    pushed: usize,
+}
+
+/// Sampling options, random states, counts and recent history of sampled tokens of each batch,
+/// shared by the jobs of a [`Sampled`] runtime.
+///
+/// The random states advance and the counts and histories update as jobs are loaded and run,
+/// so a batch that is given a new request should be [`set`](Self::set) again.
+/// Sampled tokens are counted and pushed into a ring of recent tokens on GPU, so penalties need no upload of past tokens.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    context: Context,
+    counts: TensorGpu<f32, ReadWrite>,
+    history: TensorGpu<u32, ReadWrite>,
+    batches: Arc<Mutex<Vec<SamplerBatch>>>,
+    error: f32,
+}
+
+impl Sampler {
+    /// Tolerance of the top-p filtering by default, see [`TensorOp::sample`].
+    pub const DEFAULT_ERROR: f32 = 0.01;
+    /// Number of recent tokens of each batch kept on GPU for the DRY penalty.
+    pub const HISTORY_LEN: usize = 256;
+
+    pub fn new(context: &Context, num_vocab: usize, num_batch: usize) -> Self {
+        let batch = SamplerBatch {
+            option: Default::default(),
+            random: Default::default(),
+            length: 0,
+            pushed: 0,
+        };
+        Self {
+            context: context.clone(),
+            counts: context.tensor_init([num_vocab, 1, num_batch, 1]),
+            history: context.tensor_init([Self::HISTORY_LEN, 1, num_batch, 1]),
+            batches: Arc::new(Mutex::new(vec![batch; num_batch])),
+            error: Self::DEFAULT_ERROR,
+        }
+    }
+
+    /// Set how much probability mass the top-p filtering may keep beyond `top_p`.
+    pub fn error(self, error: f32) -> Self {
+        Self { error, ..self }
+    }
+
+    #[inline]
+    pub fn num_vocab(&self) -> usize {
+        self.counts.shape()[0]
+    }
+
+    #[inline]
+    pub fn num_batch(&self) -> usize {
+        self.counts.shape()[2]
+    }
+
+    fn lock<T>(&self, f: impl FnOnce(&mut Vec<SamplerBatch>) -> T) -> T {
+        let mut batches = self.batches.lock().unwrap_or_else(|err| err.into_inner());
+        f(&mut batches)
+    }
+
+    /// Set the option of a batch, seed its random numbers, and forget the tokens it has sampled.
+    pub fn set(&self, batch: usize, option: SamplerOption, seed: u64) -> Result<()> {
+        self.reset(batch)?;
+        self.lock(|batches| {
+            batches[batch] = SamplerBatch {
+                option,
+                random: RandomState::new(seed),
+                length: 0,
+                pushed: 0,
+            }
+        });
+        Ok(())
+    }
+
+    pub fn option(&self, batch: usize) -> Option<SamplerOption> {
+        self.lock(|batches| batches.get(batch).map(|batch| batch.option))
+    }
+
+    /// The random state of a batch, which advances by one for each output of the batch when a job is loaded.
+    pub fn random(&self, batch: usize) -> Option<RandomState> {
+        self.lock(|batches| batches.get(batch).map(|batch| batch.random))
+    }
+
+    /// Restore the random state of a batch, e.g., one saved on another device, keeping its option and counts.
+    pub fn set_random(&self, batch: usize, random: RandomState) -> Result<()> {
+        self.lock(|batches| match batches.get_mut(batch) {
+            Some(batch) => {
+                batch.random = random;
+                Ok(())
+            }
+            None => bail!("batch {batch} out of range of {}", batches.len()),
+        })
+    }
+
+    /// The number of tokens a batch has generated, which advances by one for each output of the batch when a job is loaded.
+    pub fn length(&self, batch: usize) -> Option<usize> {
+        self.lock(|batches| batches.get(batch).map(|batch| batch.length))
+    }
+
+    /// Forget the tokens a batch has sampled, so that they are no longer penalized, and restart its length.
+    pub fn reset(&self, batch: usize) -> Result<()> {
+        let zeros = TensorCpu::init([self.num_vocab(), 1, 1, 1]);
+        self.counts.load_batch(&zeros, batch)?;
+        self.lock(|batches| {
+            if let Some(batch) = batches.get_mut(batch) {
+                batch.length = 0;
+                batch.pushed = 0;
+            }
+        });
+        Ok(())
+    }
+
+    /// Replace the recent history of a batch with the tail of `tokens`, e.g., of the prompt,
+    /// so that the DRY penalty also catches repetitions of it. This does not count the tokens for the other penalties.
+    pub fn set_history(&self, batch: usize, tokens: &[u16]) -> Result<()> {
+        if batch >= self.num_batch() {
+            bail!("batch {batch} out of range of {}", self.num_batch());
+        }
+        // lay the tokens out as if pushed one by one from the start of the ring
+        let mut history = vec![0; Self::HISTORY_LEN];
+        for (index, &token) in tokens.iter().enumerate() {
+            history[index % Self::HISTORY_LEN] = token as u32;
+        }
+        let history = TensorCpu::from_data([Self::HISTORY_LEN, 1, 1, 1], history)?;
+        self.history.load_batch(&history, batch)?;
+        self.lock(|batches| batches[batch].pushed = tokens.len());
+        Ok(())
+    }
+
+    /// Read back the recent history of a batch, oldest first.
+    pub async fn history(&self, batch: usize) -> Result<Vec<u16>> {
+        let Some(pushed) = self.lock(|batches| batches.get(batch).map(|batch| batch.pushed)) else {
+            bail!("batch {batch} out of range of {}", self.num_batch());
+        };
+        let history = self.history.back().await;
+        let ring = &history.data()[batch * Self::HISTORY_LEN..(batch + 1) * Self::HISTORY_LEN];
+        let len = pushed.min(Self::HISTORY_LEN);
+        Ok((pushed - len..pushed)
+            .map(|index| ring[index % Self::HISTORY_LEN] as u16)
+            .collect())
+    }
+
+    /// Read back how many times each batch has sampled each token.
+    pub async fn counts(&self) -> TensorCpu<f32> {
+        self.counts.back().await
+    }
+
+    /// Capture the option, random state and counts of a batch as a [`SeededSampler`],
+    /// e.g., to continue the generation on CPU or on another device.
+    pub async fn export(&self, batch: usize) -> Result<SeededSampler> {
+        let (Some(option), Some(random)) = (self.option(batch), self.random(batch)) else {
+            bail!("batch {batch} out of range of {}", self.num_batch());
+        };
+        let num_vocab = self.num_vocab();
+        let counts = self.counts().await;
+        let counts = counts.data()[batch * num_vocab..(batch + 1) * num_vocab]
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0.0)
+            .map(|(token, &count)| (token as u16, count as usize))
+            .collect();
+        let history = self.history(batch).await?;
+        Ok(SeededSampler {
+            option,
+            random,
+            counts,
+            history,
+        })
+    }
+
+    /// Restore a batch from a [`SeededSampler`], with its recent history. Its length restarts from the number of tokens sampled.
+    pub fn import(&self, batch: usize, sampler: &SeededSampler) -> Result<()> {
+        if batch >= self.num_batch() {
+            bail!("batch {batch} out of range of {}", self.num_batch());
+        }
+        let mut counts = vec![0.0; self.num_vocab()];
+        for (&token, &count) in &sampler.counts {
+            if let Some(x) = counts.get_mut(token as usize) {
+                *x = count as f32;
+            }
+        }
+        let counts = TensorCpu::from_data([self.num_vocab(), 1, 1, 1], counts)?;
+        self.counts.load_batch(&counts, batch)?;
+        self.set_history(batch, &sampler.history)?;
+        self.lock(|batches| {
+            batches[batch] = SamplerBatch {
+                option: sampler.option,
+                random: sampler.random,
+                length: sampler.counts.values().sum(),
+                pushed: sampler.history.len(),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// A model runtime whose jobs sample tokens on GPU with the options of a [`Sampler`].
+/// Length controls, penalties, top-k and top-p filtering and sampling all run after the head,
+/// so that only the sampled token ids are read back, instead of the logits.
+#[derive(Debug, Clone)]
+pub struct Sampled<R>(pub R, pub Sampler);
+
+/// The sampled tokens of each batch, one for each output position.
+#[derive(Debug, Default, Clone, Deref, DerefMut, PartialEq, Eq)]
+pub struct SampledOutput(pub Vec<Vec<u16>>);
+
+/// Device buffers for sampling the outputs of one job.
+#[derive(Debug)]
+pub(crate) struct SamplerStep {
+    sampler: Sampler,
+    params: TensorGpu<f32, ReadWrite>,
+    penalties: TensorGpu<f32, ReadWrite>,
+    lengths: TensorGpu<f32, ReadWrite>,
+    dry: TensorGpu<f32, ReadWrite>,
+    matches: TensorGpu<u32, ReadWrite>,
+    output: TensorGpu<u32, ReadWrite>,
+    /// If the length adjustment and penalties are applied by the head, see [`SamplerStep::transform`].
+    fused: bool,
+}
+
+impl SamplerStep {
+    pub fn new(sampler: &Sampler, num_header: usize) -> Self {
+        let context = &sampler.context;
+        Self {
+            sampler: sampler.clone(),
+            params: context.tensor_init([4, num_header, 1, 1]),
+            penalties: context.tensor_init([4, num_header, 1, 1]),
+            lengths: context.tensor_init([4, num_header, 1, 1]),
+            dry: context.tensor_init([8, num_header, 1, 1]),
+            matches: context.tensor_init([Sampler::HISTORY_LEN, num_header, 1, 1]),
+            output: context.tensor_init([1, num_header, 1, 1]),
+            fused: false,
+        }
+    }
+
+    /// Leave the length adjustment and penalties to the head matmul, which applies [`SamplerStep::transform`].
+    pub fn fused(self, value: bool) -> Self {
+        Self {
+            fused: value,
+            ..self
+        }
+    }
+
+    /// The length adjustment and penalties of the step, for the head matmul to apply in place of [`SamplerStep::op`].
+    pub fn transform(&self) -> LogitTransform<'_> {
+        LogitTransform::default()
+            .length(&self.lengths)
+            .penalty(&self.penalties, &self.sampler.counts)
+    }
+
+    /// Adjust by length, penalize, sample, and count and push the sampled tokens of `logits` of shape `[C, R]`.
+    pub fn op(&self, logits: &TensorGpu<f32, ReadWrite>) -> Result<TensorOp, TensorError> {
+        let Self {
+            sampler,
+            params,
+            penalties,
+            lengths,
+            dry,
+            matches,
+            output,
+            fused,
+        } = self;
+        let mut ops = vec![];
+        if !fused {
+            ops.push(TensorOp::length_penalty(logits, lengths)?);
+            ops.push(TensorOp::penalty(logits, penalties, &sampler.counts)?);
+        }
+        ops.append(&mut vec![
+            TensorOp::dry_penalty(logits, dry, &sampler.history, matches)?,
+            TensorOp::sample(logits, params, output, sampler.error)?,
+            TensorOp::count_tokens(output, penalties, &sampler.counts)?,
+            TensorOp::push_history(output, dry, &sampler.history)?,
+        ]);
+        Ok(TensorOp::List(ops))
+    }
+
+    /// Write the options of the output rows, advancing the random state of their batches.
+    pub fn load(&self, redirect: &InferRedirect) -> Result<()> {
+        let num_header = self.output.shape()[1];
+        if num_header == 0 {
+            return Ok(());
+        }
+        if redirect.outputs.len() > self.sampler.num_batch() {
+            bail!(
+                "{} batches for a sampler of {} batches",
+                redirect.outputs.len(),
+                self.sampler.num_batch()
+            );
+        }
+
+        let mut params = vec![0.0; 4 * num_header];
+        let mut penalties = vec![0.0; 4 * num_header];
+        let mut lengths = [-1.0, 0.0, 0.0, 0.0].repeat(num_header);
+        let mut dry = vec![0.0; 8 * num_header];
+        self.sampler.lock(|batches| {
+            for (index, &(start, end)) in redirect.outputs.iter().enumerate() {
+                let batch = &mut batches[index];
+                let option = batch.option;
+                // rows of the same step see the history before it, as tokens are pushed after all rows are sampled
+                let total = batch.pushed;
+                for row in start..end {
+                    let random = batch.random.next_f32();
+                    params[4 * row..4 * row + 4].copy_from_slice(&[
+                        option.temperature,
+                        option.top_p,
+                        random,
+                        option.top_k as f32,
+                    ]);
+                    penalties[4 * row..4 * row + 4].copy_from_slice(&[
+                        option.presence_penalty,
+                        option.frequency_penalty,
+                        index as f32,
+                        1.0,
+                    ]);
+                    if let Some((token, add, scale)) = option.length.adjust(batch.length) {
+                        lengths[4 * row..4 * row + 3].copy_from_slice(&[token as f32, add, scale]);
+                    }
+                    dry[8 * row..8 * row + 8].copy_from_slice(&[
+                        option.dry.multiplier,
+                        option.dry.base,
+                        option.dry.allowed_length as f32,
+                        0.0,
+                        index as f32,
+                        total as f32,
+                        batch.pushed as f32,
+                        1.0,
+                    ]);
+                    batch.length += 1;
+                    batch.pushed += 1;
+                }
+            }
+        });
+
+        let shape = [4, num_header, 1, 1];
+        self.params.load(&TensorCpu::from_data(shape, params)?)?;
+        self.penalties
+            .load(&TensorCpu::from_data(shape, penalties)?)?;
+        self.lengths.load(&TensorCpu::from_data(shape, lengths)?)?;
+        self.dry
+            .load(&TensorCpu::from_data([8, num_header, 1, 1], dry)?)?;
+        Ok(())
+    }
+
+    pub async fn back(self, redirect: &InferRedirect) -> SampledOutput {
+        let output = self.output.back().await;
+        let batches = redirect
+            .outputs
+            .iter()
+            .map(|&(start, end)| {
+                output.data()[start..end]
+                    .iter()
+                    .map(|&x| x as u16)
+                    .collect()
+            })
+            .collect();
+        SampledOutput(batches)
+    }
+
+    /// Sample from logits that are already read back, e.g., by an exit head.
+    pub async fn back_logits(
+        self,
+        logits: InferOutput,
+        redirect: &InferRedirect,
+    ) -> Result<SampledOutput> {
+        let num_header = self.output.shape()[1];
+        if num_header == 0 {
+            return Ok(self.back(redirect).await);
+        }
+
+        let context = &self.sampler.context;
+        let num_vocab = self.sampler.num_vocab();
+        let data = logits
+            .0
+            .iter()
+            .flat_map(|batch| batch.data().iter().copied())
+            .collect_vec();
+        let logits: TensorGpu<f32, _> =
+            context.tensor_from_data([num_vocab, num_header, 1, 1], data)?;
+        let op = self.op(&logits)?;
+        context.submit(context.encode(&op));
+        Ok(self.back(redirect).await)
+    }
+}
+
+/// Samples tokens on CPU from logits read back, with the same options as a [`Sampler`], reproducibly:
+/// the same seed and the same logits always give the same tokens, e.g., for tests and regression comparisons across releases.
+///
+/// Random numbers come from a [`RandomState`], one for each sampled token, and ties between equally probable tokens
+/// are broken by token id, so nothing depends on the order of hashing or sorting.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeededSampler {
+    pub option: SamplerOption,
+    pub random: RandomState,
+    /// How many times each token has been sampled, for the penalties.
+    counts: BTreeMap<u16, usize>,
+    /// The last [`Sampler::HISTORY_LEN`] sampled tokens, oldest first, for the DRY penalty.
+    #[serde(default)]
+    history: Vec<u16>,
+}
+
+impl SeededSampler {
+    pub fn new(option: SamplerOption) -> Self {
+        Self {
+            option,
+            ..Default::default()
+        }
+    }
+
+    /// Seed the random numbers, restarting their sequence.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.random = RandomState::new(seed);
+        self
+    }
+
+    /// Restart the random numbers from the seed, and forget the sampled tokens, e.g., to replay a generation.
+    pub fn reset(&mut self) {
+        self.random = RandomState::new(self.random.seed);
+        self.counts.clear();
+        self.history.clear();
+    }
+
+    /// The recent sampled tokens, oldest first.
+    pub fn history(&self) -> &[u16] {
+        &self.history
+    }
+
+    /// How many times `token` has been sampled.
+    pub fn count(&self, token: u16) -> usize {
+        self.counts.get(&token).copied().unwrap_or_default()
+    }
+
+    /// The tokens that may be sampled from `logits` after length controls, penalties, temperature and top-k and top-p filtering,
+    /// with their probabilities renormalized, most probable first.
+    pub fn candidates(&self, logits: &[f32]) -> Vec<(u16, f32)> {
+        let SamplerOption {
+            temperature,
+            top_p,
+            top_k,
+            presence_penalty,
+            frequency_penalty,
+            length,
+            dry,
+        } = self.option;
+
+        let mut logits = logits.to_vec();
+        length.apply(&mut logits, self.counts.values().sum());
+        for (&token, &count) in &self.counts {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= presence_penalty + frequency_penalty * count as f32;
+            }
+        }
+        dry.apply(&mut logits, &self.history);
+        // sort by logit (descending), then by token (ascending)
+        let sorted = logits
+            .iter()
+            .enumerate()
+            .map(|(token, &logit)| (token as u16, logit))
+            .sorted_by(|(a, x), (b, y)| y.total_cmp(x).then(a.cmp(b)))
+            .collect_vec();
+        let Some(&(top, max)) = sorted.first() else {
+            return vec![];
+        };
+        if temperature <= 0.0 {
+            return vec![(top, 1.0)];
+        }
+
+        let probs = sorted
+            .iter()
+            .map(|&(token, logit)| (token, ((logit - max) / temperature).exp()))
+            .collect_vec();
+        let sum: f32 = probs.iter().map(|(_, x)| x).sum();
+        let top_k = match top_k {
+            0 => probs.len(),
+            k => k,
+        };
+        let mut cum = 0.0;
+        let kept = probs
+            .into_iter()
+            .map(|(token, x)| (token, x / sum))
+            .take(top_k)
+            .take_while(|&(_, x)| {
+                let take = cum < top_p;
+                cum += x;
+                take
+            })
+            .collect_vec();
+        let total: f32 = kept.iter().map(|(_, x)| x).sum();
+        kept.into_iter()
+            .map(|(token, x)| (token, x / total))
+            .collect()
+    }
+
+    /// Sample a token from `logits`, advancing the random state by one and counting the token.
+    pub fn sample(&mut self, logits: &[f32]) -> u16 {
+        let candidates = self.candidates(logits);
+        let rand = self.random.next_f32();
+        let mut cum = 0.0;
+        let token = candidates
+            .iter()
+            .find(|(_, x)| {
+                cum += x;
+                rand < cum
+            })
+            .or(candidates.last())
+            .map_or(0, |&(token, _)| token);
+        *self.counts.entry(token).or_default() += 1;
+        if self.history.len() == Sampler::HISTORY_LEN {
+            self.history.remove(0);
+        }
+        self.history.push(token);
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{
+        DryOption, LengthOption, RandomState, Sampled, Sampler, SamplerOption, SeededSampler,
+    };
+    use crate::{
+        runtime::{
+            infer::Greedy,
+            model::{Build, ModelBuilder, ModelVersion},
+            tiny::{
+                tests::{create_context, generate, prompts},
+                TinyModel,
+            },
+            v6, JobRuntime,
+        },
+        tensor::{ops::TensorOp, TensorGpu},
+    };
+
+    #[test]
+    fn test_philox() {
+        // known answers of Philox4x32-10 from Random123
+        assert_eq!(
+            RandomState::philox([0; 4], [0; 2]),
+            [0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8]
+        );
+        assert_eq!(
+            RandomState::philox([u32::MAX; 4], [u32::MAX; 2]),
+            [0x408f276d, 0x41c83b0e, 0xa20bc7c6, 0x6d5451fd]
+        );
+        assert_eq!(
+            RandomState::philox(
+                [0x243f6a88, 0x85a308d3, 0x13198a2e, 0x03707344],
+                [0xa4093822, 0x299f31d0]
+            ),
+            [0xd16cfe09, 0x94fdcceb, 0x5001e420, 0x24126ea1]
+        );
+
+        // a restored state resumes the sequence
+        let mut random = RandomState::new(42);
+        let head: Vec<_> = (0..4).map(|_| random.next_f32()).collect();
+        let saved = serde_json::to_string(&random).unwrap();
+        let tail: Vec<_> = (0..4).map(|_| random.next_f32()).collect();
+
+        let mut restored: RandomState = serde_json::from_str(&saved).unwrap();
+        assert_eq!(restored.counter, 4);
+        assert_eq!(
+            (0..4).map(|_| restored.next_f32()).collect::<Vec<_>>(),
+            tail
+        );
+        assert_ne!(head, tail);
+        assert!(head.iter().chain(&tail).all(|x| (0.0..1.0).contains(x)));
+    }
+
+    #[test]
+    fn test_seeded_sampler() {
+        let mut random = RandomState::new(7);
+        let logits: Vec<f32> = (0..64).map(|_| 4.0 * random.next_f32()).collect();
+        let option = SamplerOption {
+            temperature: 1.0,
+            top_p: 0.9,
+            top_k: 8,
+            ..Default::default()
+        };
+        let run = |seed: u64| {
+            let mut sampler = SeededSampler::new(option).seed(seed);
+            (0..32).map(|_| sampler.sample(&logits)).collect::<Vec<_>>()
+        };
+
+        // the same seed gives the same tokens, and only the top-k are ever sampled
+        let tokens = run(42);
+        assert_eq!(tokens, run(42));
+        assert_ne!(tokens, run(43));
+        let sampler = SeededSampler::new(option);
+        let candidates = sampler.candidates(&logits);
+        assert_eq!(candidates.len(), 8);
+        assert!(tokens
+            .iter()
+            .all(|token| candidates.iter().any(|(x, _)| x == token)));
+        let sum: f32 = candidates.iter().map(|(_, x)| x).sum();
+        assert!((sum - 1.0).abs() < 1.0e-5);
+
+        // a reset replays the generation
+        let mut sampler = SeededSampler::new(option).seed(42);
+        let first: Vec<_> = (0..32).map(|_| sampler.sample(&logits)).collect();
+        assert_eq!(sampler.random.counter, 32);
+        assert_eq!(
+            sampler.count(tokens[0]),
+            tokens.iter().filter(|&&x| x == tokens[0]).count()
+        );
+        sampler.reset();
+        let second: Vec<_> = (0..32).map(|_| sampler.sample(&logits)).collect();
+        assert_eq!(first, second);
+
+        // ties are broken by token id, and greedy sampling picks the first of the most probable
+        let mut logits = vec![0.0f32; 16];
+        logits[3] = 1.0;
+        logits[9] = 1.0;
+        let option = SamplerOption {
+            temperature: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(SeededSampler::new(option).sample(&logits), 3);
+        let option = SamplerOption {
+            top_k: 1,
+            ..Default::default()
+        };
+        assert_eq!(SeededSampler::new(option).candidates(&logits), [(3, 1.0)]);
+
+        // penalties move away from tokens sampled before
+        let option = SamplerOption {
+            temperature: 0.0,
+            presence_penalty: 0.5,
+            ..Default::default()
+        };
+        let mut sampler = SeededSampler::new(option);
+        assert_eq!(sampler.sample(&logits), 3);
+        assert_eq!(sampler.sample(&logits), 9);
+    }
+
+    #[test]
+    fn test_dry_option() {
+        let dry = DryOption {
+            multiplier: 1.0,
+            base: 2.0,
+            allowed_length: 2,
+        };
+        // `1 2` repeats at the end, so `3` would extend it to `1 2 3`
+        let history = [1, 2, 3, 1, 2];
+        let mut logits = vec![0.0f32; 5];
+        dry.apply(&mut logits, &history);
+        assert_eq!(logits, [0.0, 0.0, 0.0, -1.0, 0.0]);
+
+        // longer repetitions are penalized exponentially, by the longest one a token extends
+        let history = [4, 1, 2, 0, 1, 2, 3, 4, 1, 2];
+        let mut logits = vec![0.0f32; 5];
+        dry.apply(&mut logits, &history);
+        assert_eq!(logits, [-2.0, 0.0, 0.0, -1.0, 0.0]);
+
+        let mut logits = vec![0.0f32; 5];
+        DryOption::default().apply(&mut logits, &history);
+        assert_eq!(logits, [0.0; 5]);
+
+        // greedy sampling leaves a loop once it is repeated long enough, and only the recent tokens are kept
+        let option = SamplerOption {
+            temperature: 0.0,
+            dry: DryOption {
+                multiplier: 4.0,
+                ..dry
+            },
+            ..Default::default()
+        };
+        let mut sampler = SeededSampler::new(option);
+        let mut logits = vec![0.0f32; 4];
+        let mut tokens = vec![];
+        for _ in 0..Sampler::HISTORY_LEN + 8 {
+            let token = sampler.sample(&logits);
+            // without the penalty, this would loop over `0 1 2 0 1 2 ...`
+            logits = vec![0.0; 4];
+            logits[(token as usize + 1) % 3] = 3.0;
+            tokens.push(token);
+        }
+        assert!(tokens.contains(&3));
+        assert_eq!(sampler.history().len(), Sampler::HISTORY_LEN);
+        assert_eq!(sampler.history(), &tokens[8..]);
+        sampler.reset();
+        assert!(sampler.history().is_empty());
+    }
+
+    #[test]
+    fn test_dry_penalty() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let Ok(context) = pollster::block_on(create_context(&info)) else {
+            return Ok(());
+        };
+        fastrand::seed(42);
+
+        const C: usize = 16;
+        const H: usize = 16;
+        const S: usize = 2;
+
+        // a few tokens, so that repetitions are common
+        let history = (0..H * S).map(|_| fastrand::u32(0..4)).collect_vec();
+        let history_dev: TensorGpu<u32, _> =
+            context.tensor_from_data([H, 1, S, 1], history.clone())?;
+        let x = (0..C * 3).map(|_| fastrand::f32()).collect_vec();
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, 3, 1, 1], x.clone())?;
+        let lengths: TensorGpu<u32, _> = context.tensor_init([H, 3, 1, 1]);
+
+        // (option, slot, total): the ring of slot 1 has wrapped around, and the last row is disabled
+        let option = DryOption {
+            multiplier: 0.8,
+            base: 1.75,
+            allowed_length: 1,
+        };
+        let rows = [
+            (option, 0, 10),
+            (option, 1, 40),
+            (DryOption::default(), 0, 10),
+        ];
+        let params = rows
+            .iter()
+            .enumerate()
+            .flat_map(|(row, (option, slot, total))| {
+                let (slot, total) = (*slot as f32, *total as f32);
+                let allowed = option.allowed_length as f32;
+                let position = total + row as f32;
+                [option.multiplier, option.base, allowed, 0.0]
+                    .into_iter()
+                    .chain([slot, total, position, 1.0])
+            })
+            .collect_vec();
+        let params: TensorGpu<f32, _> = context.tensor_from_data([8, 3, 1, 1], params)?;
+        let tokens: TensorGpu<u32, _> = context.tensor_from_data([1, 3, 1, 1], vec![7, 8, 9])?;
+
+        let ops = TensorOp::List(vec![
+            TensorOp::dry_penalty(&x_dev, &params, &history_dev, &lengths)?,
+            TensorOp::push_history(&tokens, &params, &history_dev)?,
+        ]);
+        context.submit(context.encode(&ops));
+
+        let output = x_dev.back_in_place().to_vec();
+        for (row, (option, slot, total)) in rows.into_iter().enumerate() {
+            let len = total.min(H);
+            let ring = &history[slot * H..(slot + 1) * H];
+            let tokens = (total - len..total)
+                .map(|index| ring[index % H] as u16)
+                .collect_vec();
+            let mut expected = x[row * C..(row + 1) * C].to_vec();
+            option.apply(&mut expected, &tokens);
+            for (&x, &y) in output[row * C..(row + 1) * C].iter().zip_eq(&expected) {
+                assert!(
+                    (x - y).abs() <= 1.0e-4 * y.abs().max(1.0),
+                    "row {row}: {x} vs {y}"
+                );
+            }
+        }
+        assert_ne!(output, x);
+
+        let mut expected = history;
+        expected[10] = 7;
+        expected[H + 41 % H] = 8;
+        expected[12] = 9;
+        assert_eq!(history_dev.back_in_place().to_vec(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_option() {
+        let length = LengthOption {
+            eos: Some(5),
+            min_length: 2,
+            start: 3,
+            boost: 0.5,
+            decay: 2.0,
+        };
+        assert_eq!(LengthOption::default().adjust(100), None);
+        assert_eq!(length.adjust(0), Some((5, f32::NEG_INFINITY, 0.0)));
+        assert_eq!(length.adjust(3), None);
+        assert_eq!(length.adjust(5), Some((5, 1.0, 3.0)));
+
+        let mut logits = vec![0.0f32; 8];
+        logits[5] = -2.0;
+        length.apply(&mut logits, 5);
+        assert_eq!(logits[5], -2.0 + 1.0 + 2.0 * 3.0);
+
+        // the end-of-sequence token is banned at first, then boosted until it is picked
+        let mut logits = vec![0.0f32; 8];
+        logits[2] = 4.0;
+        logits[5] = 1.0;
+        let option = SamplerOption {
+            temperature: 0.0,
+            length: LengthOption {
+                eos: Some(5),
+                min_length: 1,
+                start: 1,
+                boost: 1.0,
+                decay: 1.0,
+            },
+            ..Default::default()
+        };
+        let mut sampler = SeededSampler::new(option);
+        let tokens: Vec<_> = (0..7).map(|_| sampler.sample(&logits)).collect();
+        assert_eq!(tokens, [2, 2, 2, 2, 2, 5, 5]);
+
+        let mut banned = logits.clone();
+        banned[5] = 10.0;
+        let mut sampler = SeededSampler::new(option);
+        assert_eq!(sampler.sample(&banned), 2);
+        assert_eq!(sampler.sample(&banned), 5);
+    }
+
+    #[test]
+    fn test_sampled() -> Result<()> {
+        const LEN: usize = 16;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V6);
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let prompts = prompts(&info);
+            let num_batch = prompts.len();
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v6::Model>::build(builder).await?;
+
+            let runtime = v6::ModelRuntime::<f32>::new(model.clone(), num_batch);
+            let greedy = JobRuntime::new(Greedy(runtime)).await;
+            let expected = generate(&greedy, &prompts, LEN).await;
+
+            let sample = |sampler: Sampler| async {
+                let runtime = v6::ModelRuntime::<f32>::new(model.clone(), num_batch);
+                let runtime = JobRuntime::new(Sampled(runtime, sampler)).await;
+                generate(&runtime, &prompts, LEN).await
+            };
+            let sampler = |option: SamplerOption, seed: u64| -> Result<Sampler> {
+                let sampler = Sampler::new(&context, info.num_vocab, num_batch);
+                for batch in 0..num_batch {
+                    sampler.set(batch, option, seed + batch as u64)?;
+                }
+                Ok(sampler)
+            };
+
+            // zero temperature and top-k of 1 both pick the argmax
+            let option = SamplerOption {
+                temperature: 0.0,
+                ..Default::default()
+            };
+            assert_eq!(sample(sampler(option, 0)?).await, expected);
+            let option = SamplerOption {
+                top_k: 1,
+                ..Default::default()
+            };
+            assert_eq!(sample(sampler(option, 0)?).await, expected);
+
+            // the same seeds give the same tokens
+            let option = SamplerOption {
+                temperature: 1.5,
+                top_p: 0.9,
+                top_k: 64,
+                ..Default::default()
+            };
+            let output = sample(sampler(option, 7)?).await;
+            assert_eq!(sample(sampler(option, 7)?).await, output);
+            assert_ne!(output, expected);
+
+            // the end-of-sequence token is banned before the minimal length, and boosted after the start
+            let eos = expected[0][0];
+            let option = SamplerOption {
+                temperature: 0.0,
+                length: LengthOption {
+                    eos: Some(eos),
+                    min_length: 1,
+                    start: 4,
+                    boost: 1.0e4,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let lengths = sampler(option, 0)?;
+            let output = sample(lengths.clone()).await;
+            assert_ne!(output[0][0], eos);
+            for (tokens, expected) in output.iter().zip_eq(&expected) {
+                if expected[0] != eos {
+                    assert_eq!(tokens[..4], expected[..4]);
+                }
+                assert!(tokens[5..].iter().all(|&token| token == eos));
+            }
+            assert_eq!(lengths.length(0), Some(LEN));
+
+            // a large presence penalty forbids repetition, and the sampled tokens are counted on GPU
+            let option = SamplerOption {
+                temperature: 0.0,
+                presence_penalty: 1.0e4,
+                ..Default::default()
+            };
+            let sampler = sampler(option, 0)?;
+            let output = sample(sampler.clone()).await;
+            assert!(expected
+                .iter()
+                .any(|tokens| tokens.iter().unique().count() < LEN));
+            let counts = sampler.counts().await;
+            for (batch, tokens) in output.iter().enumerate() {
+                assert_eq!(tokens.len(), LEN);
+                assert_eq!(tokens.iter().unique().count(), LEN);
+                let counts = &counts.data()[batch * info.num_vocab..(batch + 1) * info.num_vocab];
+                for (token, &count) in counts.iter().enumerate() {
+                    let expected = tokens.contains(&(token as u16)) as u8 as f32;
+                    assert_eq!(count, expected);
+                }
+            }
+
+            // a batch exported as a seeded sampler and imported into another batch carries its counts over
+            let exported = sampler.export(0).await?;
+            assert_eq!(exported.option, option);
+            assert_eq!(exported.random, sampler.random(0).unwrap());
+            for &token in &output[0] {
+                assert_eq!(exported.count(token), 1);
+            }
+            assert_eq!(exported.history(), &output[0][..]);
+            sampler.import(1, &exported)?;
+            assert_eq!(sampler.export(1).await?, exported);
+            assert_eq!(sampler.length(1), Some(LEN));
+            assert!(sampler.import(num_batch, &exported).is_err());
+            Ok(())
+        })
+    }
+}
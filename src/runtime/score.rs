@@ -0,0 +1,632 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use web_rwkv_derive::{Deref, DerefMut};
+
+use super::{
+    choice::log_prob,
+    infer::{InferInput, InferInputBatch, InferOption, InferOutput, InferRedirect},
+    model::State,
+    JobRuntime,
+};
+#[cfg(feature = "tokenizer")]
+use crate::tokenizer::Tokenize;
+use crate::{
+    context::Context,
+    impl_deserialize_seed,
+    tensor::{
+        kind::ReadWrite, ops::TensorOp, TensorCpu, TensorError, TensorGpu, TensorInit, TensorShape,
+    },
+};
+
+/// The order in which a model reads the tokens of a text.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TokenOrder {
+    /// Left to right, as most models are trained.
+    #[default]
+    Forward,
+    /// Right to left over the same tokens, for a model trained on token sequences reversed.
+    Reverse,
+}
+
+impl_deserialize_seed!(TokenOrder);
+
+impl TokenOrder {
+    /// Lay out a request in reading order: the context the model reads before the target, and the target.
+    ///
+    /// A forward model reads the left context, then the target; a reverse model reads the right context, then the target,
+    /// both reversed. The context on the other side comes after the target, so it has no effect on its score.
+    pub fn arrange(self, request: &ScoreRequest) -> (Vec<u16>, Vec<u16>) {
+        match self {
+            TokenOrder::Forward => (request.left.clone(), request.target.clone()),
+            TokenOrder::Reverse => (
+                request.right.iter().rev().copied().collect(),
+                request.target.iter().rev().copied().collect(),
+            ),
+        }
+    }
+
+    /// Bring values of the target tokens, e.g., their log-probabilities, from reading order back to text order.
+    pub fn restore<T>(self, mut values: Vec<T>) -> Vec<T> {
+        if self == TokenOrder::Reverse {
+            values.reverse();
+        }
+        values
+    }
+}
+
+/// A target span of tokens to score in the context of the tokens around it, all in text (left to right) order.
+///
+/// The same request can be scored by a forward and a reverse model, each conditioning on its own side.
+/// For contrastive (e.g., mutual information) scoring of a reply `y` to a prompt `x`, score `y` after `x` with a forward model,
+/// and `x` before `y` with a reverse one, i.e., `ScoreRequest::new(y).left(x)` and `ScoreRequest::new(x).right(y)`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ScoreRequest {
+    pub left: Vec<u16>,
+    pub target: Vec<u16>,
+    pub right: Vec<u16>,
+}
+
+impl ScoreRequest {
+    pub fn new(target: impl Into<Vec<u16>>) -> Self {
+        Self {
+            target: target.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Tokens to the left of the target.
+    pub fn left(mut self, value: impl Into<Vec<u16>>) -> Self {
+        self.left = value.into();
+        self
+    }
+
+    /// Tokens to the right of the target.
+    pub fn right(mut self, value: impl Into<Vec<u16>>) -> Self {
+        self.right = value.into();
+        self
+    }
+
+    /// Tokenize the parts of a text apart, as in [`JobRuntime::choose_text`].
+    #[cfg(feature = "tokenizer")]
+    pub fn encode(
+        tokenizer: &impl Tokenize,
+        left: &str,
+        target: &str,
+        right: &str,
+    ) -> Result<Self> {
+        Ok(Self {
+            left: tokenizer.encode(left.as_bytes())?,
+            target: tokenizer.encode(target.as_bytes())?,
+            right: tokenizer.encode(right.as_bytes())?,
+        })
+    }
+
+    /// Reverse the characters of a text and tokenize the parts of the reversed text, for a model trained on reversed text
+    /// with its own tokenization (rather than on reversed token sequences).
+    ///
+    /// The request is then scored with [`TokenOrder::Forward`]: its `left` holds the reversed right context, and so on,
+    /// and the log-probabilities are those of the tokens of the reversed target.
+    #[cfg(feature = "tokenizer")]
+    pub fn encode_reversed(
+        tokenizer: &impl Tokenize,
+        left: &str,
+        target: &str,
+        right: &str,
+    ) -> Result<Self> {
+        let reverse = |text: &str| text.chars().rev().collect::<String>();
+        Self::encode(tokenizer, &reverse(right), &reverse(target), &reverse(left))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ScoreOption {
+    pub order: TokenOrder,
+    pub token_chunk_size: usize,
+}
+
+impl Default for ScoreOption {
+    fn default() -> Self {
+        Self {
+            order: Default::default(),
+            token_chunk_size: 128,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    /// Log-probabilities of the target tokens, in text order whichever order the model reads.
+    pub log_probs: Vec<f32>,
+    /// Sum of the log-probabilities.
+    pub log_prob: f32,
+}
+
+impl Score {
+    /// Combine the scores of a forward and a reverse model, e.g., `log P(y | x) + weight * log P(x | y)`.
+    pub fn contrast(&self, other: &Score, weight: f32) -> f32 {
+        self.log_prob + weight * other.log_prob
+    }
+}
+
+impl JobRuntime<InferInput, InferOutput> {
+    /// Score the targets of `requests` by teacher forcing, reading tokens in `option.order`.
+    ///
+    /// Each request runs in a batch of `state`, which must be the runtime's state, from the initial state;
+    /// requests are run as many at a time as there are batches. Since the first target token in reading order
+    /// is predicted by the last context token, the context on the side the model reads first must not be empty
+    /// (prepend or append a start token, e.g., `0`, if there is none).
+    pub async fn score(
+        &self,
+        state: &(impl State + ?Sized),
+        requests: &[ScoreRequest],
+        option: ScoreOption,
+    ) -> Result<Vec<Score>> {
+        let arranged = requests
+            .iter()
+            .map(|request| option.order.arrange(request))
+            .collect_vec();
+        if arranged.iter().any(|(context, _)| context.is_empty()) {
+            bail!("empty context");
+        }
+        if arranged.iter().any(|(_, target)| target.is_empty()) {
+            bail!("empty target");
+        }
+
+        let num_batch = state.num_batch();
+        let mut scores = Vec::with_capacity(requests.len());
+        for arranged in arranged.chunks(num_batch) {
+            for batch in 0..arranged.len() {
+                state.load(state.init(), batch)?;
+            }
+
+            // the context, but its last token, is run without reading back logits
+            let batches = (0..num_batch)
+                .map(|batch| InferInputBatch {
+                    tokens: match arranged.get(batch) {
+                        Some((context, _)) => context[..context.len() - 1].to_vec().into(),
+                        None => vec![].into(),
+                    },
+                    option: InferOption::Last,
+                    ..Default::default()
+                })
+                .collect();
+            let mut input = InferInput::new(batches, option.token_chunk_size);
+            while input.num_token() > 0 {
+                (input, _) = self.infer(input).await;
+            }
+
+            let batches = (0..num_batch)
+                .map(|batch| InferInputBatch {
+                    tokens: match arranged.get(batch) {
+                        Some((context, target)) => {
+                            [&context[context.len() - 1..], target].concat().into()
+                        }
+                        None => vec![].into(),
+                    },
+                    option: InferOption::Full,
+                    ..Default::default()
+                })
+                .collect();
+            let mut input = InferInput::new(batches, option.token_chunk_size);
+            let mut logits = vec![vec![]; arranged.len()];
+            while input.num_token() > 0 {
+                let (next, InferOutput(output)) = self.infer(input).await;
+                input = next;
+                for (logits, output) in logits.iter_mut().zip(output) {
+                    logits.extend_from_slice(output.data());
+                }
+            }
+
+            for ((_, target), logits) in arranged.iter().zip_eq(logits) {
+                let num_vocab = logits.len() / (target.len() + 1);
+                let log_probs = target
+                    .iter()
+                    .zip(logits.chunks_exact(num_vocab))
+                    .map(|(&token, logits)| log_prob(logits, token))
+                    .collect_vec();
+                let log_prob = log_probs.iter().sum();
+                let log_probs = option.order.restore(log_probs);
+                scores.push(Score {
+                    log_probs,
+                    log_prob,
+                });
+            }
+        }
+        Ok(scores)
+    }
+}
+
+/// The perplexity of a text from the log-probabilities of its tokens, i.e., `exp` of their mean negative.
+pub fn perplexity(log_probs: &[f32]) -> f32 {
+    let sum: f32 = log_probs.iter().sum();
+    (-sum / log_probs.len().max(1) as f32).exp()
+}
+
+#[derive(Debug, Default, Clone)]
+struct ScorerBatch {
+    tokens: Vec<u16>,
+    /// Number of tokens already run.
+    cursor: usize,
+}
+
+/// The token sequences that the batches of a [`Scored`] runtime are teacher-forced with, shared with its jobs.
+///
+/// The target of each output row is the token after its input token, so batches must run their sequences
+/// from the start with [`InferOption::Full`]. The cursors advance as jobs are loaded.
+#[derive(Debug, Clone)]
+pub struct Scorer {
+    context: Context,
+    batches: Arc<Mutex<Vec<ScorerBatch>>>,
+}
+
+impl Scorer {
+    pub fn new(context: &Context, num_batch: usize) -> Self {
+        Self {
+            context: context.clone(),
+            batches: Arc::new(Mutex::new(vec![Default::default(); num_batch])),
+        }
+    }
+
+    #[inline]
+    pub fn num_batch(&self) -> usize {
+        self.lock(|batches| batches.len())
+    }
+
+    fn lock<T>(&self, f: impl FnOnce(&mut Vec<ScorerBatch>) -> T) -> T {
+        let mut batches = self.batches.lock().unwrap_or_else(|err| err.into_inner());
+        f(&mut batches)
+    }
+
+    /// Set the sequence a batch runs next, restarting its cursor.
+    pub fn set(&self, batch: usize, tokens: &[u16]) -> Result<()> {
+        self.lock(|batches| match batches.get_mut(batch) {
+            Some(batch) => {
+                *batch = ScorerBatch {
+                    tokens: tokens.to_vec(),
+                    cursor: 0,
+                };
+                Ok(())
+            }
+            None => bail!("batch {batch} out of range of {}", batches.len()),
+        })
+    }
+}
+
+/// A model runtime whose jobs compute the log-probability of the next token of each output row on GPU,
+/// by log-softmax and gathering the logits, and read back only them instead of the logits.
+#[derive(Debug, Clone)]
+pub struct Scored<R>(pub R, pub Scorer);
+
+/// Log-probabilities of the next tokens of each batch, one for each output row that has a next token.
+#[derive(Debug, Default, Clone, Deref, DerefMut, PartialEq)]
+pub struct ScoredOutput(pub Vec<Vec<f32>>);
+
+/// Device buffers for scoring the outputs of one job.
+#[derive(Debug)]
+pub(crate) struct ScorerStep {
+    scorer: Scorer,
+    tokens: TensorGpu<u32, ReadWrite>,
+    output: TensorGpu<f32, ReadWrite>,
+    /// Target of each output row, `u32::MAX` if it has none.
+    targets: Vec<u32>,
+    /// Number of output rows of each batch that have a target.
+    counts: Vec<usize>,
+}
+
+impl ScorerStep {
+    pub fn new(scorer: &Scorer, num_header: usize) -> Self {
+        let context = &scorer.context;
+        Self {
+            scorer: scorer.clone(),
+            tokens: context.tensor_init([1, num_header, 1, 1]),
+            output: context.tensor_init([1, num_header, 1, 1]),
+            targets: vec![],
+            counts: vec![],
+        }
+    }
+
+    /// Compute the log-probabilities of the targets of `logits` of shape `[C, R]`.
+    pub fn op(&self, logits: &TensorGpu<f32, ReadWrite>) -> Result<TensorOp, TensorError> {
+        TensorOp::log_prob(logits, &self.tokens, &self.output)
+    }
+
+    /// Write the targets of the output rows, advancing the cursors of their batches.
+    pub fn load(self, redirect: &InferRedirect) -> Result<Self> {
+        let num_header = self.output.shape()[1];
+        if redirect.outputs.len() > self.scorer.num_batch() {
+            bail!(
+                "{} batches for a scorer of {} batches",
+                redirect.outputs.len(),
+                self.scorer.num_batch()
+            );
+        }
+
+        let mut tokens = vec![u32::MAX; num_header];
+        let counts = self.scorer.lock(|batches| {
+            redirect
+                .outputs
+                .iter()
+                .zip(batches.iter_mut())
+                .map(|(&(start, end), batch)| {
+                    let len = end - start;
+                    let targets = batch
+                        .tokens
+                        .iter()
+                        .skip(batch.cursor + 1)
+                        .take(len)
+                        .map(|&token| token as u32);
+                    let count = tokens[start..end]
+                        .iter_mut()
+                        .zip(targets)
+                        .map(|(x, token)| *x = token)
+                        .count();
+                    batch.cursor += len;
+                    count
+                })
+                .collect_vec()
+        });
+        if num_header > 0 {
+            self.tokens.load(&TensorCpu::from_data(
+                [1, num_header, 1, 1],
+                tokens.clone(),
+            )?)?;
+        }
+        Ok(Self {
+            targets: tokens,
+            counts,
+            ..self
+        })
+    }
+
+    pub async fn back(self, redirect: &InferRedirect) -> ScoredOutput {
+        let output = self.output.back().await;
+        let batches = redirect
+            .outputs
+            .iter()
+            .zip(&self.counts)
+            .map(|(&(start, _), &len)| output.data()[start..start + len].to_vec())
+            .collect();
+        ScoredOutput(batches)
+    }
+
+    /// Score logits that are already read back, e.g., by an exit head.
+    pub fn back_logits(self, logits: InferOutput, redirect: &InferRedirect) -> ScoredOutput {
+        let batches = logits
+            .0
+            .iter()
+            .zip(&redirect.outputs)
+            .zip(&self.counts)
+            .map(|((logits, &(start, _)), &len)| {
+                let num_vocab = logits.shape()[0].max(1);
+                logits
+                    .data()
+                    .chunks_exact(num_vocab)
+                    .zip(&self.targets[start..start + len])
+                    .map(|(logits, &token)| log_prob(logits, token as u16))
+                    .collect()
+            })
+            .collect();
+        ScoredOutput(batches)
+    }
+}
+
+impl JobRuntime<InferInput, ScoredOutput> {
+    /// Teacher-force each sequence from the initial state, and return the log-probability of each of its tokens but the first,
+    /// computed on GPU so that only them are read back. See [`perplexity`] to summarize them.
+    ///
+    /// `scorer` must be the one of the runtime, and `state` the runtime's state.
+    /// Sequences are run as many at a time as there are batches.
+    pub async fn log_probs(
+        &self,
+        state: &(impl State + ?Sized),
+        scorer: &Scorer,
+        sequences: &[Vec<u16>],
+        token_chunk_size: usize,
+    ) -> Result<Vec<Vec<f32>>> {
+        if sequences.iter().any(|tokens| tokens.is_empty()) {
+            bail!("empty sequence");
+        }
+
+        let num_batch = state.num_batch();
+        let mut log_probs = Vec::with_capacity(sequences.len());
+        for sequences in sequences.chunks(num_batch) {
+            let mut batches = Vec::with_capacity(num_batch);
+            for batch in 0..num_batch {
+                let tokens = sequences.get(batch).cloned().unwrap_or_default();
+                if batch < sequences.len() {
+                    state.load(state.init(), batch)?;
+                }
+                scorer.set(batch, &tokens)?;
+                batches.push(InferInputBatch {
+                    tokens: tokens.into(),
+                    option: InferOption::Full,
+                    ..Default::default()
+                });
+            }
+
+            let mut input = InferInput::new(batches, token_chunk_size);
+            let mut outputs = vec![vec![]; sequences.len()];
+            while input.num_token() > 0 {
+                let (next, ScoredOutput(output)) = self.infer(input).await;
+                input = next;
+                for (outputs, output) in outputs.iter_mut().zip(output) {
+                    outputs.extend(output);
+                }
+            }
+            log_probs.append(&mut outputs);
+        }
+        Ok(log_probs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{perplexity, ScoreOption, ScoreRequest, Scored, Scorer, TokenOrder};
+    use crate::runtime::{
+        choice::log_prob,
+        model::{Build, ModelBuilder, ModelRuntime, ModelVersion, State},
+        tiny::{
+            tests::{create_context, infer_gpu, prompts, with_runtime},
+            TinyModel,
+        },
+        v6, JobRuntime,
+    };
+
+    #[test]
+    fn test_log_probs() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let prompts = prompts(&info);
+                let Some(logits) =
+                    infer_gpu(TinyModel::new(info.clone(), 42), &prompts, None).await?
+                else {
+                    return Ok(());
+                };
+                let expected = prompts
+                    .iter()
+                    .zip_eq(logits.iter())
+                    .map(|(tokens, logits)| {
+                        tokens[1..]
+                            .iter()
+                            .zip(logits.chunks_exact(info.num_vocab))
+                            .map(|(&token, logits)| log_prob(logits, token))
+                            .collect_vec()
+                    })
+                    .collect_vec();
+
+                // fewer batches than sequences
+                let context = create_context(&info).await?;
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let scorer = Scorer::new(&context, 1);
+                let (state, runtime) = with_runtime!(builder, version, 1, |runtime| {
+                    let runtime = runtime();
+                    let state: Box<dyn State> = Box::new(runtime.state());
+                    let runtime = Scored(runtime, scorer.clone());
+                    (state, JobRuntime::new(runtime).await)
+                });
+
+                let log_probs = runtime.log_probs(&*state, &scorer, &prompts, 7).await?;
+                for (x, y) in log_probs.iter().zip_eq(expected.iter()) {
+                    assert_eq!(x.len(), y.len(), "{version:?}");
+                    for (x, y) in x.iter().zip(y.iter()) {
+                        assert!((x - y).abs() < 1.0e-4, "{version:?}: {x} vs {y}");
+                    }
+                    let (x, y) = (perplexity(x), perplexity(y));
+                    assert!((x - y).abs() < 1.0e-3 * y, "{version:?}: {x} vs {y}");
+                }
+                assert!(runtime
+                    .log_probs(&*state, &scorer, &[vec![]], 7)
+                    .await
+                    .is_err());
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_score() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V6);
+            let [a, b] = &prompts(&info)[..] else {
+                unreachable!()
+            };
+            let requests = [
+                ScoreRequest::new(&a[10..20])
+                    .left(&a[..10])
+                    .right(&a[20..30]),
+                ScoreRequest::new(&b[5..12]).left(&b[..5]).right(&b[12..]),
+                ScoreRequest::new(&a[30..31])
+                    .left(&a[29..30])
+                    .right(&a[31..]),
+            ];
+
+            // reference: the context and the target run in reading order in one go
+            let expected = |sequences: Vec<Vec<u16>>, logits: Vec<Vec<f32>>| {
+                sequences
+                    .iter()
+                    .zip_eq(logits.iter())
+                    .zip_eq(requests.iter())
+                    .map(|((tokens, logits), request)| {
+                        let skip = tokens.len() - request.target.len();
+                        tokens[skip..]
+                            .iter()
+                            .zip(logits.chunks_exact(info.num_vocab).skip(skip - 1))
+                            .map(|(&token, logits)| {
+                                let sum: f32 = logits.iter().map(|x| x.exp()).sum();
+                                logits[token as usize] - sum.ln()
+                            })
+                            .collect_vec()
+                    })
+                    .collect_vec()
+            };
+            let forward = requests
+                .iter()
+                .map(|request| [request.left.clone(), request.target.clone()].concat())
+                .collect_vec();
+            let reverse = requests
+                .iter()
+                .map(|request| {
+                    let (context, target) = TokenOrder::Reverse.arrange(request);
+                    [context, target].concat()
+                })
+                .collect_vec();
+            let Some(logits) = infer_gpu(TinyModel::new(info.clone(), 42), &forward, None).await?
+            else {
+                return Ok(());
+            };
+            let forward = expected(forward, logits);
+            let logits = infer_gpu(TinyModel::new(info.clone(), 42), &reverse, None).await?;
+            let mut reverse = expected(reverse, logits.expect("context"));
+            reverse.iter_mut().for_each(|x| x.reverse());
+
+            // fewer batches than requests
+            let context = create_context(&info).await?;
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v6::Model>::build(builder).await?;
+            let runtime = v6::ModelRuntime::<f32>::new(model, 2);
+            let state = runtime.state();
+            let runtime = JobRuntime::new(runtime).await;
+
+            for (order, expected) in [
+                (TokenOrder::Forward, forward),
+                (TokenOrder::Reverse, reverse),
+            ] {
+                let option = ScoreOption {
+                    order,
+                    token_chunk_size: 4,
+                };
+                let scores = runtime.score(&state, &requests, option).await?;
+                for (score, expected) in scores.iter().zip_eq(expected.iter()) {
+                    assert_eq!(score.log_probs.len(), expected.len());
+                    for (x, y) in score.log_probs.iter().zip(expected.iter()) {
+                        assert!((x - y).abs() < 1.0e-3, "{order:?}: {x} vs {y}");
+                    }
+                    let sum: f32 = expected.iter().sum();
+                    assert!((score.log_prob - sum).abs() < 1.0e-3);
+                }
+            }
+
+            let request = ScoreRequest::new(&a[..5]).right(&a[5..10]);
+            let option = ScoreOption::default();
+            assert!(runtime
+                .score(&state, std::slice::from_ref(&request), option)
+                .await
+                .is_err());
+            let option = ScoreOption {
+                order: TokenOrder::Reverse,
+                ..option
+            };
+            assert!(runtime.score(&state, &[request], option).await.is_ok());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{model::State, sampler::SeededSampler, transfer::StateTransfer};
+use crate::tensor::TensorCpu;
+
+/// Leading bytes of an encoded session snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"RWKVSESS";
+/// Version of the layout of session snapshots, bumped whenever it changes.
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SnapshotError {
+    #[error("not an encoded session snapshot")]
+    Magic,
+    #[error("session snapshot format {0} is not supported")]
+    Format(u32),
+    #[error("session snapshot is truncated")]
+    Size,
+}
+
+/// Everything of a snapshot but the state, which follows it encoded by a [`StateTransfer`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SnapshotHeader {
+    format: u32,
+    sampler: Option<SeededSampler>,
+    history: Vec<u16>,
+}
+
+/// The state of one sequence, the state of its sampler, and the tail of its tokens, enough to continue a session on another device.
+///
+/// Snapshots are encoded the same way on native and wasm builds: the magic, the length of the header as a little-endian `u32`,
+/// the header in CBOR, then the state as encoded by a [`StateTransfer`], which checks it against the model on decoding.
+/// The bytes can be kept in a file on desktop and in `IndexedDB` in browsers, and be moved between the two.
+#[derive(Debug, Clone)]
+pub struct InferenceSessionSnapshot {
+    pub state: TensorCpu<f32>,
+    /// The sampler of the session, which is portable as it samples on CPU with integer random numbers.
+    pub sampler: Option<SeededSampler>,
+    /// The last tokens of the session, e.g., for repetition checks or to show the conversation again.
+    pub history: Vec<u16>,
+}
+
+impl InferenceSessionSnapshot {
+    pub fn new(state: TensorCpu<f32>) -> Self {
+        Self {
+            state,
+            sampler: None,
+            history: vec![],
+        }
+    }
+
+    /// Read back one batch of `state` into a snapshot.
+    pub async fn capture(state: &(impl State + ?Sized), batch: usize) -> Result<Self> {
+        Ok(Self::new(state.back(batch).await?))
+    }
+
+    pub fn sampler(mut self, sampler: SeededSampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Keep the last `tail` of `tokens` as the history.
+    pub fn history(mut self, tokens: &[u16], tail: usize) -> Self {
+        let start = tokens.len().saturating_sub(tail);
+        self.history = tokens[start..].to_vec();
+        self
+    }
+
+    /// Load the state of the snapshot into one batch of `state`.
+    pub fn restore(&self, state: &(impl State + ?Sized), batch: usize) -> Result<()> {
+        state.load(self.state.clone(), batch)?;
+        Ok(())
+    }
+
+    pub fn encode(&self, transfer: &StateTransfer) -> Result<Vec<u8>> {
+        let header = SnapshotHeader {
+            format: SNAPSHOT_FORMAT,
+            sampler: self.sampler.clone(),
+            history: self.history.clone(),
+        };
+        let header = cbor4ii::serde::to_vec(vec![], &header)?;
+        let state = transfer.encode(&self.state)?;
+
+        let mut output = Vec::with_capacity(SNAPSHOT_MAGIC.len() + 4 + header.len() + state.len());
+        output.extend_from_slice(&SNAPSHOT_MAGIC);
+        output.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        output.extend_from_slice(&header);
+        output.extend_from_slice(&state);
+        Ok(output)
+    }
+
+    /// Decode a snapshot, checking its state against the model of `transfer`.
+    pub fn decode(transfer: &StateTransfer, data: &[u8]) -> Result<Self> {
+        let Some(data) = data.strip_prefix(&SNAPSHOT_MAGIC) else {
+            bail!(SnapshotError::Magic);
+        };
+        if data.len() < 4 {
+            bail!(SnapshotError::Size);
+        }
+        let (len, data) = data.split_at(4);
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if data.len() < len {
+            bail!(SnapshotError::Size);
+        }
+        let (header, state) = data.split_at(len);
+
+        let reader = cbor4ii::core::utils::SliceReader::new(header);
+        let mut deserializer = cbor4ii::serde::Deserializer::new(reader);
+        let header = SnapshotHeader::deserialize(&mut deserializer)?;
+        if header.format != SNAPSHOT_FORMAT {
+            bail!(SnapshotError::Format(header.format));
+        }
+
+        let state = transfer.decode(state)?;
+        Ok(Self {
+            state,
+            sampler: header.sampler,
+            history: header.history,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{InferenceSessionSnapshot, SnapshotError, SnapshotHeader, SNAPSHOT_MAGIC};
+    use crate::{
+        runtime::{
+            model::{ModelInfo, ModelVersion},
+            sampler::{SamplerOption, SeededSampler},
+            transfer::{StateTransfer, TransferError},
+        },
+        tensor::{TensorCpu, TensorInit, TensorShape},
+    };
+
+    fn info(num_layer: usize) -> ModelInfo {
+        ModelInfo {
+            version: ModelVersion::V6,
+            num_layer,
+            num_emb: 8,
+            num_hidden: 16,
+            num_vocab: 32,
+            num_head: 2,
+            time_mix_adapter_size: 4,
+            time_decay_adapter_size: 4,
+        }
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let data: Vec<_> = (0..8 * 6 * 2).map(|x| x as f32 * 0.25 - 3.0).collect();
+        let tensor: TensorCpu<f32> = TensorCpu::from_data([8, 6, 2, 1], data)?;
+
+        let option = SamplerOption {
+            temperature: 0.8,
+            presence_penalty: 0.5,
+            ..Default::default()
+        };
+        let mut sampler = SeededSampler::new(option).seed(7);
+        let logits: Vec<_> = (0..32).map(|x| x as f32 * 0.1).collect();
+        for _ in 0..3 {
+            sampler.sample(&logits);
+        }
+
+        let tokens: Vec<u16> = (0..10).collect();
+        let snapshot = InferenceSessionSnapshot::new(tensor.clone())
+            .sampler(sampler.clone())
+            .history(&tokens, 4);
+        assert_eq!(snapshot.history, vec![6, 7, 8, 9]);
+
+        let transfer = StateTransfer::new(info(2));
+        let encoded = snapshot.encode(&transfer)?;
+        let decoded = InferenceSessionSnapshot::decode(&transfer, &encoded)?;
+        assert_eq!(decoded.state.shape(), tensor.shape());
+        assert_eq!(decoded.state.to_vec(), tensor.to_vec());
+        assert_eq!(decoded.history, snapshot.history);
+
+        // the restored sampler continues the same sequence
+        let mut restored = decoded.sampler.expect("sampler");
+        assert_eq!(restored, sampler);
+        assert_eq!(restored.sample(&logits), sampler.sample(&logits));
+
+        let error = |result: Result<InferenceSessionSnapshot>| result.unwrap_err();
+        let err = error(InferenceSessionSnapshot::decode(&transfer, &encoded[1..]));
+        assert_eq!(err.downcast::<SnapshotError>()?, SnapshotError::Magic);
+        let err = error(InferenceSessionSnapshot::decode(&transfer, &encoded[..10]));
+        assert_eq!(err.downcast::<SnapshotError>()?, SnapshotError::Size);
+
+        // the state is still checked against the model
+        let other = StateTransfer::new(info(3));
+        let err = error(InferenceSessionSnapshot::decode(&other, &encoded));
+        assert_eq!(err.downcast::<TransferError>()?, TransferError::ModelInfo);
+
+        let header = SnapshotHeader {
+            format: 2,
+            sampler: None,
+            history: vec![],
+        };
+        let header = cbor4ii::serde::to_vec(vec![], &header)?;
+        let mut encoded = SNAPSHOT_MAGIC.to_vec();
+        encoded.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(&header);
+        let err = error(InferenceSessionSnapshot::decode(&transfer, &encoded));
+        assert_eq!(err.downcast::<SnapshotError>()?, SnapshotError::Format(2));
+        Ok(())
+    }
+}
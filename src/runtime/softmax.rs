@@ -1,7 +1,12 @@
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
 use crate::{
     context::Context,
     num::Float,
-    tensor::{ops::TensorOp, TensorCpu, TensorError, TensorGpu, TensorInto},
+    tensor::{
+        kind::ReadWrite, ops::TensorOp, TensorCpu, TensorError, TensorGpu, TensorInto, TensorShape,
+    },
 };
 
 pub async fn softmax_one<T: Float>(
@@ -14,7 +19,7 @@ pub async fn softmax_one<T: Float>(
 
     let tensor: TensorGpu<_, _> = input.transfer_into(context);
     let op = TensorOp::softmax(&tensor)?;
-    context.queue.submit(context.encode(&op));
+    context.submit(context.encode(&op));
 
     let output = tensor.back().await;
     Ok(output)
@@ -34,7 +39,7 @@ pub async fn softmax<T: Float>(
         }
         tensors.push(tensor);
     }
-    context.queue.submit(context.encode(&TensorOp::List(ops)));
+    context.submit(context.encode(&TensorOp::List(ops)));
 
     let mut output = Vec::with_capacity(tensors.len());
     for tensor in tensors.into_iter() {
@@ -42,3 +47,229 @@ pub async fn softmax<T: Float>(
     }
     Ok(output)
 }
+
+/// Pack which tokens of a vocabulary are kept into the bitset taken by [`softmax_masked`],
+/// where bit `t % 32` of word `t / 32` is set if token `t` is kept.
+pub fn pack_mask(keep: &[bool]) -> Vec<u32> {
+    let mut mask = vec![0u32; keep.len().div_ceil(32)];
+    for (token, _) in keep.iter().enumerate().filter(|(_, &keep)| keep) {
+        mask[token / 32] |= 1 << (token % 32);
+    }
+    mask
+}
+
+/// Like [`softmax`], but each input is normalized over the tokens kept by its mask only, in the same pass on GPU.
+/// Masks are bitsets over the vocabulary as made by [`pack_mask`]; masked tokens get zero probability, and inputs without a mask are not masked.
+///
+/// This is the primitive for grammar constraints, vocabulary subsets or banned tokens when probabilities are read back.
+pub async fn softmax_masked<T: Float>(
+    context: &Context,
+    input: Vec<TensorCpu<T>>,
+    masks: Vec<Option<Vec<u32>>>,
+) -> Result<Vec<TensorCpu<T>>, TensorError> {
+    if input.len() != masks.len() {
+        return Err(TensorError::Batch(masks.len(), input.len()));
+    }
+
+    let mut tensors = Vec::with_capacity(input.len());
+    let mut ops = Vec::with_capacity(input.len());
+
+    for (input, mask) in input.into_iter().zip(masks) {
+        let tensor: TensorGpu<_, _> = input.transfer_into(context);
+        if tensor.size() > 0 {
+            let op = match mask {
+                Some(mask) => {
+                    let shape = tensor.shape();
+                    let mask: TensorGpu<u32, ReadWrite> =
+                        context.tensor_from_data([shape[0].div_ceil(32), 1, shape[2], 1], mask)?;
+                    TensorOp::softmax_masked(&tensor, &mask)?
+                }
+                None => TensorOp::softmax(&tensor)?,
+            };
+            ops.push(op);
+        }
+        tensors.push(tensor);
+    }
+    context.submit(context.encode(&TensorOp::List(ops)));
+
+    let mut output = Vec::with_capacity(tensors.len());
+    for tensor in tensors.into_iter() {
+        output.push(tensor.back().await);
+    }
+    Ok(output)
+}
+
+/// Temperature and top-p truncation of a batch in [`softmax_top_p`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SoftmaxOption {
+    /// Logits are divided by the temperature. A non-positive temperature keeps the most probable tokens only.
+    pub temperature: f32,
+    /// Tokens are kept from the most probable on, while those before weigh less than `top_p`.
+    pub top_p: f32,
+}
+
+impl Default for SoftmaxOption {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_p: 1.0,
+        }
+    }
+}
+
+/// Like [`softmax`], but with the temperature and top-p truncation of each batch applied on GPU,
+/// and only the kept tokens read back with their (renormalized) probabilities, most probable first.
+///
+/// Each input holds the logits of one token, of shape `[C, 1, 1]`, and all are run in one pass.
+/// At most `max_tokens` tokens are kept for each input, which bounds the readback; empty inputs give empty lists.
+pub async fn softmax_top_p<T: Float>(
+    context: &Context,
+    input: Vec<TensorCpu<T>>,
+    options: &[SoftmaxOption],
+    max_tokens: usize,
+) -> Result<Vec<Vec<(u16, f32)>>, TensorError> {
+    if input.len() != options.len() {
+        return Err(TensorError::Batch(options.len(), input.len()));
+    }
+
+    let mut outputs = vec![vec![]; input.len()];
+    let (batches, input): (Vec<_>, Vec<_>) = input
+        .into_iter()
+        .enumerate()
+        .filter(|(_, input)| input.size() > 0)
+        .unzip();
+    if input.is_empty() {
+        return Ok(outputs);
+    }
+
+    let input = TensorCpu::stack(input)?;
+    let [num_vocab, _, num_batch, _] = *input.shape();
+    input.check_shape([num_vocab, 1, num_batch, 1])?;
+    let num_token = max_tokens.clamp(1, num_vocab);
+
+    let (temperature, top_p): (Vec<_>, Vec<_>) = batches
+        .iter()
+        .map(|&batch| (options[batch].temperature, options[batch].top_p))
+        .unzip();
+    let temperature: TensorGpu<f32, ReadWrite> =
+        context.tensor_from_data([1, 1, num_batch, 1], temperature)?;
+    let top_p: TensorGpu<f32, ReadWrite> = context.tensor_from_data([1, 1, num_batch, 1], top_p)?;
+
+    let x: TensorGpu<T, ReadWrite> = input.transfer_into(context);
+    let probs: TensorGpu<f32, ReadWrite> = context.tensor_init([num_token, 1, num_batch, 1]);
+    let tokens: TensorGpu<u32, ReadWrite> = context.tensor_init([num_token, 1, num_batch, 1]);
+    let count: TensorGpu<u32, ReadWrite> = context.tensor_init([1, 1, num_batch, 1]);
+
+    let ops = vec![
+        TensorOp::softmax_temperature(&x, &temperature)?,
+        TensorOp::topk(&x, &probs, &tokens)?,
+        TensorOp::top_p(&probs, &top_p, &count)?,
+    ];
+    context.submit(context.encode(&TensorOp::List(ops)));
+
+    let probs = probs.back().await;
+    let tokens = tokens.back().await;
+    let count = count.back().await;
+    for (((batch, probs), tokens), &count) in batches
+        .into_iter()
+        .zip_eq(probs.chunks_exact(num_token))
+        .zip_eq(tokens.chunks_exact(num_token))
+        .zip_eq(count.iter())
+    {
+        outputs[batch] = tokens
+            .iter()
+            .zip_eq(probs)
+            .take(count as usize)
+            .map(|(&token, &prob)| (token as u16, prob))
+            .collect();
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{softmax_top_p, SoftmaxOption};
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        tensor::{TensorCpu, TensorInit},
+    };
+
+    #[test]
+    fn test_softmax_top_p() -> Result<()> {
+        let context = match pollster::block_on(async {
+            let instance = wgpu::Instance::default();
+            let adapter = instance
+                .adapter(wgpu::PowerPreference::HighPerformance)
+                .await?;
+            anyhow::Ok(ContextBuilder::new(adapter).build().await?)
+        }) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const K: usize = 64;
+
+        let logits = (0..3)
+            .map(|_| (0..C).map(|_| 8.0 * fastrand::f32() - 4.0).collect_vec())
+            .collect_vec();
+        let options = [
+            SoftmaxOption::default(),
+            SoftmaxOption {
+                temperature: 0.5,
+                top_p: 0.3,
+            },
+            SoftmaxOption {
+                temperature: 0.0,
+                top_p: 0.9,
+            },
+            SoftmaxOption::default(),
+        ];
+        let input = logits
+            .iter()
+            .map(|x| TensorCpu::from_data([C, 1, 1, 1], x.clone()))
+            .chain([Ok(TensorCpu::from_data([0, 1, 1, 1], vec![])?)])
+            .try_collect()?;
+        let outputs = pollster::block_on(softmax_top_p(&context, input, &options, K))?;
+        assert_eq!(outputs.len(), 4);
+        assert!(outputs[3].is_empty());
+
+        for ((x, option), output) in logits.iter().zip(&options).zip(&outputs) {
+            // reference: sort by probability, truncate by mass, then by count
+            let max = x.iter().copied().fold(f32::MIN, f32::max);
+            let temperature = option.temperature.max(1.0e-30);
+            let probs = x
+                .iter()
+                .map(|x| ((x - max) / temperature).exp())
+                .collect_vec();
+            let sum: f32 = probs.iter().sum();
+            let mut before = 0.0;
+            let expected = probs
+                .iter()
+                .enumerate()
+                .map(|(token, x)| (token as u16, x / sum))
+                .sorted_by(|(i, x), (j, y)| y.total_cmp(x).then(i.cmp(j)))
+                .take(K)
+                .take_while(|&(_, x)| {
+                    let keep = x > 0.0 && (before == 0.0 || before < option.top_p);
+                    before += x;
+                    keep
+                })
+                .collect_vec();
+            let total: f32 = expected.iter().map(|(_, x)| x).sum();
+
+            assert_eq!(output.len(), expected.len(), "{option:?}");
+            for (&(token, prob), &(expected_token, expected_prob)) in output.iter().zip(&expected) {
+                assert_eq!(token, expected_token);
+                assert!((prob - expected_prob / total).abs() < 1.0e-4);
+            }
+        }
+        assert_eq!(outputs[0].len(), K);
+        assert_eq!(outputs[2].len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    event::Event,
+    infer::{GreedyOutput, InferInput, InferInputBatch, InferOption, StopOption, StopReason},
+    model::State,
+    JobRuntime,
+};
+
+/// How [`JobRuntime::speculate`] drafts and verifies tokens.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpeculativeOption {
+    /// Tokens drafted before each verification.
+    pub num_draft: usize,
+    pub stop: StopOption,
+    pub token_chunk_size: usize,
+}
+
+impl Default for SpeculativeOption {
+    fn default() -> Self {
+        Self {
+            num_draft: 4,
+            stop: Default::default(),
+            token_chunk_size: 128,
+        }
+    }
+}
+
+/// Tokens generated by [`JobRuntime::speculate`], with how many drafts were accepted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Speculation {
+    /// Generated tokens, without the stop token.
+    pub tokens: Vec<u16>,
+    pub reason: StopReason,
+    pub num_drafted: usize,
+    pub num_accepted: usize,
+}
+
+impl JobRuntime<InferInput, GreedyOutput> {
+    /// Run `tokens` through batch 0 and return the picked token of each output.
+    async fn run_batch(
+        &self,
+        num_batch: usize,
+        tokens: &[u16],
+        option: InferOption,
+        chunk: usize,
+    ) -> Vec<u16> {
+        let batches = (0..num_batch)
+            .map(|batch| InferInputBatch {
+                tokens: match batch {
+                    0 => tokens.into(),
+                    _ => vec![].into(),
+                },
+                option,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, chunk);
+        let mut output = vec![];
+        while input.num_token() > 0 {
+            let (next, GreedyOutput(batches)) = self.infer(input).await;
+            input = next;
+            if let Some(tokens) = batches.into_iter().next() {
+                output.extend(tokens);
+            }
+        }
+        output
+    }
+
+    /// Continue `prompt` greedily with this runtime as the verifier, using `draft` (e.g., a much smaller model) to propose tokens.
+    ///
+    /// Each step, the draft proposes [`num_draft`](SpeculativeOption::num_draft) tokens one by one,
+    /// and this runtime checks all of them in a single chunked pass. The longest prefix that agrees with its own picks is accepted,
+    /// followed by its pick at the first disagreement, so the output is the same as greedy decoding of this runtime alone.
+    /// Both states are [snapshotted](State::snapshot) before each step and [restored](State::restore) if a draft is rejected.
+    ///
+    /// Only batch 0 of both runtimes is used, and `state` and `draft_state` must be their states;
+    /// both should start from the state to continue from. Afterwards the states are left at no particular position.
+    pub async fn speculate(
+        &self,
+        state: &(impl State + ?Sized),
+        draft: &Self,
+        draft_state: &(impl State + ?Sized),
+        prompt: &[u16],
+        option: SpeculativeOption,
+    ) -> Result<Speculation> {
+        let Some((&last, prefix)) = prompt.split_last() else {
+            bail!("empty prompt");
+        };
+        if option.num_draft == 0 {
+            bail!("no token to draft");
+        }
+        let SpeculativeOption {
+            num_draft,
+            stop,
+            token_chunk_size: chunk,
+        } = option;
+        let (num_batch, num_draft_batch) = (state.num_batch(), draft_state.num_batch());
+
+        self.run_batch(num_batch, prefix, InferOption::Last, chunk)
+            .await;
+        draft
+            .run_batch(num_draft_batch, prefix, InferOption::Last, chunk)
+            .await;
+
+        // tokens that each runtime has yet to run, before the drafted ones
+        let mut pending = vec![last];
+        let mut draft_pending = vec![last];
+
+        let mut tokens = vec![];
+        let (mut num_drafted, mut num_accepted) = (0, 0);
+        loop {
+            let draft_snapshot = draft_state.snapshot(0)?;
+            let mut drafted = Vec::with_capacity(num_draft);
+            let mut input = draft_pending.clone();
+            while drafted.len() < num_draft {
+                let output = draft.run_batch(num_draft_batch, &input, InferOption::Last, chunk);
+                let Some(&token) = output.await.last() else {
+                    bail!("draft produced no output");
+                };
+                drafted.push(token);
+                input = vec![token];
+            }
+
+            let snapshot = state.snapshot(0)?;
+            let input = [pending.as_slice(), &drafted].concat();
+            let output = self
+                .run_batch(num_batch, &input, InferOption::Full, chunk)
+                .await;
+            if output.len() != input.len() {
+                bail!("{} outputs for {} tokens", output.len(), input.len());
+            }
+            // the picks after the last pending token and after each drafted token
+            let picks = &output[pending.len() - 1..];
+            let accepted = drafted
+                .iter()
+                .zip(picks)
+                .take_while(|(x, y)| x == y)
+                .count();
+            let next = picks[accepted];
+            num_drafted += num_draft;
+            num_accepted += accepted;
+
+            for &token in drafted[..accepted].iter().chain([&next]) {
+                self.emit(Event::TokenGenerated {
+                    session: None,
+                    batch: 0,
+                    token,
+                });
+                let reason = match stop.tokens.contains(&token) {
+                    true => Some(StopReason::Token(token)),
+                    false => {
+                        tokens.push(token);
+                        (tokens.len() >= stop.max_tokens).then_some(StopReason::Length)
+                    }
+                };
+                if let Some(reason) = reason {
+                    return Ok(Speculation {
+                        tokens,
+                        reason,
+                        num_drafted,
+                        num_accepted,
+                    });
+                }
+            }
+
+            if accepted == num_draft {
+                // the draft has yet to run its last token; the verifier has run all of them
+                draft_pending = vec![drafted[num_draft - 1], next];
+                pending = vec![next];
+            } else {
+                // roll back both, and run the accepted tokens again along with the next step
+                draft_state.restore(&draft_snapshot, 0)?;
+                state.restore(&snapshot, 0)?;
+                draft_pending = [draft_pending.as_slice(), &drafted[..accepted], &[next]].concat();
+                pending = [pending.as_slice(), &drafted[..accepted], &[next]].concat();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::SpeculativeOption;
+    use crate::runtime::{
+        infer::{
+            Greedy, InferKind, InferRequest, InferResponse, SampleOption, StopOption, StopReason,
+        },
+        model::{Build, ModelBuilder, ModelRuntime, ModelVersion},
+        tiny::{
+            tests::{create_context, prompts},
+            TinyModel,
+        },
+        v6, JobRuntime,
+    };
+
+    #[test]
+    fn test_speculate() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V6);
+            let prefix = prompts(&info)[1][..10].to_vec();
+            let Ok(context) = create_context(&info).await else {
+                return Ok(());
+            };
+            let create_model = |seed| {
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), seed));
+                Build::<v6::Model>::build(builder)
+            };
+            let stop = StopOption {
+                max_tokens: 12,
+                tokens: vec![],
+            };
+
+            // plain greedy decoding of the verifier
+            let model = create_model(42).await?;
+            let runtime = JobRuntime::new(v6::ModelRuntime::<f32>::new(model.clone(), 1)).await;
+            let requests = vec![InferRequest {
+                tokens: prefix.clone().into(),
+                kind: InferKind::Token {
+                    sample: SampleOption {
+                        temperature: 0.0,
+                        top_p: 1.0,
+                        seed: 0,
+                    },
+                    stop: stop.clone(),
+                    phrases: vec![],
+                },
+                session: None,
+            }];
+            let InferResponse::Token { tokens, .. } = &runtime.serve(requests, 4).await?[0] else {
+                panic!("expect tokens");
+            };
+
+            for seed in [42, 7] {
+                let verifier = v6::ModelRuntime::<f32>::new(model.clone(), 2);
+                let state = verifier.state();
+                let verifier = JobRuntime::new(Greedy(verifier)).await;
+                let draft = v6::ModelRuntime::<f32>::new(create_model(seed).await?, 1);
+                let draft_state = draft.state();
+                let draft = JobRuntime::new(Greedy(draft)).await;
+
+                let option = SpeculativeOption {
+                    num_draft: 3,
+                    stop: stop.clone(),
+                    token_chunk_size: 4,
+                };
+                let speculation = verifier
+                    .speculate(&state, &draft, &draft_state, &prefix, option)
+                    .await?;
+                assert_eq!(&speculation.tokens, tokens);
+                assert_eq!(speculation.reason, StopReason::Length);
+                assert!(speculation.num_accepted <= speculation.num_drafted);
+                if seed == 42 {
+                    // the same model as draft never misses
+                    assert_eq!(speculation.num_accepted, speculation.num_drafted);
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,432 @@
+use std::{collections::VecDeque, ops::Range};
+
+use anyhow::Result;
+use itertools::Itertools;
+use wgpu::{BufferUsages, CommandBuffer, SubmissionIndex};
+
+use super::infer::{InferOutput, InferOutputBatch, InferRedirect, MIN_TOKEN_CHUNK_SIZE};
+use crate::{
+    context::{ArenaBuffer, Context},
+    num::Float,
+    tensor::{
+        kind::ReadWrite, matrix::Matrix, ops::Activation, TensorCpu, TensorGpu, TensorInit,
+        TensorShape,
+    },
+};
+
+/// A model runtime whose jobs hand the logits over as a [`HeadStream`] instead of waiting for all of them.
+///
+/// The head runs in slices of output rows set by `ModelRuntime::head_chunk`,
+/// each read back as soon as it is done while the device goes on with the next one.
+/// Without it, or if the job exits early, the stream has one chunk for each batch.
+#[derive(Debug, Clone)]
+pub struct Streamed<R>(pub R);
+
+/// The head matmul of some output rows, followed by a copy of them into a buffer to map.
+#[derive(Debug)]
+pub(crate) struct HeadSlice {
+    rows: Range<usize>,
+    /// Where the command buffers of the slice are in the commands of the job.
+    commands: Range<usize>,
+    staging: ArenaBuffer,
+    index: Option<SubmissionIndex>,
+}
+
+/// Encode the head matmul from `input` into `output` in slices of `chunk` rows, appending them to `commands`.
+pub(crate) fn encode_head<F: Float>(
+    matrix: &Matrix,
+    input: &TensorGpu<F, ReadWrite>,
+    output: &TensorGpu<f32, ReadWrite>,
+    chunk: usize,
+    commands: &mut Vec<CommandBuffer>,
+) -> Result<Vec<HeadSlice>> {
+    let context = output.context();
+    let num_header = output.shape()[1];
+    let size = output.shape()[0] * std::mem::size_of::<f32>();
+
+    let mut slices = vec![];
+    for start in (0..num_header).step_by(chunk.max(1)) {
+        let rows = start..num_header.min(start + chunk.max(1));
+        let turbo = rows.len() % MIN_TOKEN_CHUNK_SIZE == 0;
+        let op = matrix.matmul_op(
+            input.view(.., rows.clone(), .., ..)?,
+            output.view(.., rows.clone(), .., ..)?,
+            Activation::None,
+            turbo,
+        )?;
+        let first = commands.len();
+        commands.append(&mut context.encode(&op));
+
+        let staging = context.checkout_buffer(
+            rows.len() * size,
+            BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        );
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(
+            &output.buffer,
+            (rows.start * size) as u64,
+            &staging,
+            0,
+            (rows.len() * size) as u64,
+        );
+        commands.push(encoder.finish());
+
+        slices.push(HeadSlice {
+            rows,
+            commands: first..commands.len(),
+            staging,
+            index: None,
+        });
+    }
+    Ok(slices)
+}
+
+/// Submit the commands of a job in order, each slice of the head on its own so that it can be waited for alone.
+pub(crate) fn submit(context: &Context, commands: Vec<CommandBuffer>, slices: &mut [HeadSlice]) {
+    let mut commands = commands.into_iter();
+    let mut cursor = 0;
+    for slice in slices.iter_mut() {
+        if slice.commands.start > cursor {
+            context.submit(commands.by_ref().take(slice.commands.start - cursor));
+        }
+        slice.index = Some(context.submit(commands.by_ref().take(slice.commands.len())));
+        cursor = slice.commands.end;
+    }
+    // the rest of the job, or what wrappers of the job append after the head
+    context.submit(commands);
+}
+
+/// Logits of some consecutive output rows of a job.
+#[derive(Debug, Clone)]
+pub struct HeadChunk {
+    /// The output rows of the job in the chunk, see [`HeadStream::outputs`].
+    pub rows: Range<usize>,
+    /// The logits of shape `[V, rows.len()]`.
+    pub logits: TensorCpu<f32>,
+}
+
+impl HeadChunk {
+    /// Logits of one output row of the job, if it is in the chunk.
+    pub fn row(&self, row: usize) -> Option<&[f32]> {
+        let num_vocab = self.logits.shape()[0];
+        let index = row.checked_sub(self.rows.start)?;
+        let data = self.logits.data();
+        (row < self.rows.end).then(|| &data[index * num_vocab..(index + 1) * num_vocab])
+    }
+}
+
+enum PendingChunk {
+    Ready(HeadChunk),
+    #[cfg(not(target_arch = "wasm32"))]
+    Reading(Range<usize>, flume::Receiver<Box<[u8]>>),
+    #[cfg(target_arch = "wasm32")]
+    Reading(Range<usize>, ArenaBuffer, Context),
+}
+
+/// The logits of a job, arriving in chunks of output rows in order while the rest of the head may still be running.
+///
+/// A sampler can start on a batch once the chunk with its last output row arrives, see [`HeadStream::completed`].
+pub struct HeadStream {
+    num_vocab: usize,
+    outputs: Vec<(usize, usize)>,
+    pending: VecDeque<PendingChunk>,
+}
+
+impl std::fmt::Debug for HeadStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeadStream")
+            .field("num_vocab", &self.num_vocab)
+            .field("outputs", &self.outputs)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl HeadStream {
+    /// Start reading back the slices of a submitted job.
+    pub(crate) fn new(
+        output: &TensorGpu<f32, ReadWrite>,
+        redirect: &InferRedirect,
+        slices: Vec<HeadSlice>,
+    ) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        let pending = slices
+            .into_iter()
+            .map(|slice| {
+                use crate::context::ContextEvent;
+
+                let (sender, receiver) = flume::bounded(1);
+                let event = ContextEvent {
+                    buffer: slice.staging,
+                    sender,
+                    index: slice.index,
+                };
+                let _ = output.context().event().send(event.into());
+                PendingChunk::Reading(slice.rows, receiver)
+            })
+            .collect();
+        #[cfg(target_arch = "wasm32")]
+        let pending = slices
+            .into_iter()
+            .map(|slice| PendingChunk::Reading(slice.rows, slice.staging, output.context().clone()))
+            .collect();
+
+        Self {
+            num_vocab: output.shape()[0],
+            outputs: redirect.outputs.clone(),
+            pending,
+        }
+    }
+
+    /// A stream of the output that is already read back, one chunk for each batch.
+    pub(crate) fn from_output(
+        output: InferOutput,
+        redirect: &InferRedirect,
+        num_vocab: usize,
+    ) -> Self {
+        let pending = output
+            .0
+            .into_iter()
+            .zip(&redirect.outputs)
+            .filter(|(_, &(start, end))| end > start)
+            .map(|(batch, &(start, end))| {
+                PendingChunk::Ready(HeadChunk {
+                    rows: start..end,
+                    logits: batch.0,
+                })
+            })
+            .collect();
+        Self {
+            num_vocab,
+            outputs: redirect.outputs.clone(),
+            pending,
+        }
+    }
+
+    #[inline]
+    pub fn num_vocab(&self) -> usize {
+        self.num_vocab
+    }
+
+    /// The output rows `(start, end)` of each batch.
+    #[inline]
+    pub fn outputs(&self) -> &[(usize, usize)] {
+        &self.outputs
+    }
+
+    /// Batches whose last output row is in `chunk`, i.e., whose logits are all read back once it arrives.
+    pub fn completed(&self, chunk: &HeadChunk) -> Vec<usize> {
+        self.outputs
+            .iter()
+            .positions(|&(start, end)| end > start && chunk.rows.contains(&(end - 1)))
+            .collect()
+    }
+
+    /// Wait for the next chunk, or return `None` if all are read back.
+    pub async fn next(&mut self) -> Option<Result<HeadChunk>> {
+        let chunk = match self.pending.pop_front()? {
+            PendingChunk::Ready(chunk) => return Some(Ok(chunk)),
+            #[cfg(not(target_arch = "wasm32"))]
+            PendingChunk::Reading(rows, receiver) => match receiver.recv_async().await {
+                Ok(data) => self.chunk(rows, bytemuck::cast_slice(&data).to_vec()),
+                Err(err) => Err(err.into()),
+            },
+            #[cfg(target_arch = "wasm32")]
+            PendingChunk::Reading(rows, buffer, context) => {
+                match Self::read(&context, &buffer).await {
+                    Ok(data) => self.chunk(rows, data),
+                    Err(err) => Err(err),
+                }
+            }
+        };
+        Some(chunk)
+    }
+
+    fn chunk(&self, rows: Range<usize>, data: Vec<f32>) -> Result<HeadChunk> {
+        let logits = TensorCpu::from_data([self.num_vocab, rows.len(), 1, 1], data)?;
+        Ok(HeadChunk { rows, logits })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn read(context: &Context, buffer: &wgpu::Buffer) -> Result<Vec<f32>> {
+        let (sender, receiver) = flume::unbounded();
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+
+        context.device.poll(wgpu::MaintainBase::Wait);
+        receiver.recv_async().await??;
+
+        let data = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range()).to_vec();
+        buffer.unmap();
+        Ok(data)
+    }
+
+    /// Wait for all chunks, and put the logits of each batch together.
+    pub async fn collect(mut self) -> Result<InferOutput> {
+        let mut chunks = vec![];
+        while let Some(chunk) = self.next().await {
+            chunks.push(chunk?);
+        }
+
+        let batches = self
+            .outputs
+            .iter()
+            .map(|&(start, end)| {
+                let rows = (start..end)
+                    .map(|row| chunks.iter().find_map(|chunk| chunk.row(row)))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow::anyhow!("rows {start}..{end} are not read back"))?;
+                let num_emb = rows.first().map_or(self.num_vocab, |row| row.len());
+                let data = rows.concat();
+                let logits = TensorCpu::from_data([num_emb, end - start, 1, 1], data)?;
+                Ok(InferOutputBatch(logits))
+            })
+            .collect::<Result<_>>()?;
+        Ok(InferOutput(batches))
+    }
+
+    /// Wait for all chunks of a job with slices covering all output rows, and put them together in order.
+    pub(crate) async fn concat(mut self) -> Result<TensorCpu<f32>> {
+        let mut data = Vec::with_capacity(self.num_vocab * self.outputs.last().map_or(0, |x| x.1));
+        while let Some(chunk) = self.next().await {
+            data.extend_from_slice(&chunk?.logits.data()[..]);
+        }
+        let num_row = data.len() / self.num_vocab.max(1);
+        Ok(TensorCpu::from_data([self.num_vocab, num_row, 1, 1], data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::Streamed;
+    use crate::{
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+            loader::{Loader, Reader, StreamReader},
+            model::{ModelBuilder, ModelVersion},
+            tiny::{
+                tests::{create_context, infer_gpu, prompts, with_runtime},
+                TinyModel,
+            },
+            JobRuntime,
+        },
+        tensor::TensorShape,
+    };
+
+    #[test]
+    fn test_head_stream() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                let info = TinyModel::info(version);
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let prompts = prompts(&info);
+                let batches = prompts
+                    .iter()
+                    .map(|tokens| InferInputBatch {
+                        tokens: tokens.clone().into(),
+                        option: InferOption::Full,
+                        ..Default::default()
+                    })
+                    .collect_vec();
+                let input = InferInput::new(batches, 32);
+
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let num_batch = prompts.len();
+                let (logits, sliced, streamed) =
+                    with_runtime!(builder, version, num_batch, |runtime| {
+                        (
+                            JobRuntime::new(runtime()).await,
+                            JobRuntime::new(runtime().head_chunk(Some(5))).await,
+                            JobRuntime::new(Streamed(runtime().head_chunk(Some(5)))).await,
+                        )
+                    });
+
+                let check = |x: &InferOutput, y: &InferOutput| {
+                    for (x, y) in x.iter().zip_eq(y.iter()) {
+                        assert_eq!(x.0.shape(), y.0.shape(), "{version:?}");
+                        for (x, y) in x.0.iter().zip(y.0.iter()) {
+                            assert!((x - y).abs() < 1.0e-4, "{version:?}: {x} vs {y}");
+                        }
+                    }
+                };
+
+                let mut inputs = [input.clone(), input.clone(), input];
+                while inputs[0].num_token() > 0 {
+                    let [input, input_sliced, input_streamed] = inputs;
+                    let (input, expected) = logits.infer(input).await;
+                    let (input_sliced, output) = sliced.infer(input_sliced).await;
+                    check(&expected, &output);
+
+                    let (input_streamed, mut stream) = streamed.infer(input_streamed).await;
+                    let num_header: usize = stream.outputs().iter().map(|(x, y)| y - x).sum();
+                    let mut cursor = 0;
+                    let mut completed = vec![];
+                    let mut chunks = vec![];
+                    while let Some(chunk) = stream.next().await {
+                        let chunk = chunk?;
+                        // chunks come in order, each of no more rows than the head chunk
+                        assert_eq!(chunk.rows.start, cursor, "{version:?}");
+                        assert!(chunk.rows.len() <= 5, "{version:?}");
+                        cursor = chunk.rows.end;
+                        completed.append(&mut stream.completed(&chunk));
+                        chunks.push(chunk);
+                    }
+                    assert_eq!(cursor, num_header, "{version:?}");
+
+                    let expected_completed = stream
+                        .outputs()
+                        .iter()
+                        .positions(|(x, y)| y > x)
+                        .collect_vec();
+                    assert_eq!(completed, expected_completed, "{version:?}");
+                    for (batch, &(start, end)) in stream.outputs().iter().enumerate() {
+                        let data = (start..end)
+                            .flat_map(|row| chunks.iter().find_map(|chunk| chunk.row(row)))
+                            .flatten()
+                            .copied()
+                            .collect_vec();
+                        let expected = expected[batch].0.to_vec();
+                        assert_eq!(data.len(), expected.len(), "{version:?}");
+                        for (x, y) in data.iter().zip(expected.iter()) {
+                            assert!((x - y).abs() < 1.0e-4, "{version:?}: {x} vs {y}");
+                        }
+                    }
+
+                    inputs = [input, input_sliced, input_streamed];
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_stream_reader() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let model = TinyModel::new(info.clone(), 42);
+        let data = model.serialize()?;
+
+        let reader = StreamReader::new(std::io::Cursor::new(data.clone()))?;
+        assert_eq!(Loader::info(&reader)?, info);
+        assert_eq!(reader.names().len(), Reader::names(&model).len());
+        assert!(reader.metadata().is_none());
+        assert!(StreamReader::new(std::io::Cursor::new(&data[..data.len() - 2])).is_err());
+        assert!(StreamReader::new(std::io::Cursor::new(&data[..4])).is_err());
+
+        let prompts = prompts(&info);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let Some(expected) = runtime.block_on(infer_gpu(model, &prompts, None))? else {
+            return Ok(());
+        };
+        let Some(output) = runtime.block_on(infer_gpu(reader, &prompts, None))? else {
+            return Ok(());
+        };
+        assert_eq!(expected, output);
+        Ok(())
+    }
+}
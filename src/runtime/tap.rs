@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::infer::InferOutput;
+use crate::tensor::TensorCpu;
+
+/// A point in the model where the hidden states of the output tokens are copied out by a [`Tapped`] runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Tap {
+    /// The residual stream of a layer, after its attention block.
+    PostAtt(usize),
+    /// The residual stream of a layer, after its FFN block, i.e., the output of the layer.
+    PostFfn(usize),
+    /// The final hidden state, after the layer norm of the head.
+    HeadNorm,
+}
+
+/// A model runtime whose jobs also copy the hidden states at some [`Tap`]s out, and read them back with the logits.
+///
+/// Only the tokens that have outputs are tapped, i.e., the last token of each batch or all of them,
+/// following [`InferOption`](super::infer::InferOption). The residual stream is rescaled back when tapped,
+/// so the values do not depend on the periodic halving of the runtime. Early exit is disabled for tapped jobs.
+#[derive(Debug, Clone)]
+pub struct Tapped<R>(pub R, pub Vec<Tap>);
+
+/// The logits of a [`Tapped`] job, along with the tapped hidden states.
+#[derive(Debug, Clone)]
+pub struct TappedOutput {
+    pub logits: InferOutput,
+    /// Hidden states of each batch at each tap, of shape `[C, T]` where `T` is the number of outputs of the batch.
+    /// Empty if the job has no output.
+    pub taps: BTreeMap<Tap, Vec<TensorCpu<f32>>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{Tap, Tapped};
+    use crate::{
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption},
+            model::{ModelBuilder, ModelInfo, ModelVersion},
+            tiny::{
+                tests::{create_context, layer_norm, prompts, with_runtime, Reference, LN_EPS},
+                TinyModel,
+            },
+            JobRuntime,
+        },
+        tensor::TensorShape,
+    };
+
+    #[test]
+    fn test_taps() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+                // deep enough for the residual stream to be halved on the way
+                let info = ModelInfo {
+                    num_layer: 8,
+                    ..TinyModel::info(version)
+                };
+                let Ok(context) = create_context(&info).await else {
+                    return Ok(());
+                };
+                let model = TinyModel::new(info.clone(), 42);
+                let prompts = prompts(&info);
+
+                let reference = Reference::new(&model);
+                let expected = prompts
+                    .iter()
+                    .map(|tokens| {
+                        let mut state = reference.init();
+                        tokens
+                            .iter()
+                            .map(|&token| reference.forward_layers(&mut state, token).0)
+                            .collect_vec()
+                    })
+                    .collect_vec();
+
+                let taps = vec![
+                    Tap::PostAtt(0),
+                    Tap::PostFfn(3),
+                    Tap::PostFfn(7),
+                    Tap::HeadNorm,
+                ];
+                let num_batch = prompts.len();
+                let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+                let runtime = with_runtime!(builder, version, num_batch, |runtime| {
+                    JobRuntime::new(Tapped(runtime(), taps)).await
+                });
+
+                // the last batch only outputs its last token
+                let batches = prompts
+                    .iter()
+                    .enumerate()
+                    .map(|(batch, tokens)| InferInputBatch {
+                        tokens: tokens.clone().into(),
+                        option: match batch {
+                            0 => InferOption::Full,
+                            _ => InferOption::Last,
+                        },
+                        ..Default::default()
+                    })
+                    .collect();
+                let mut input = InferInput::new(batches, 32);
+                let mut outputs = vec![vec![]; num_batch];
+                while input.num_token() > 0 {
+                    let (next, output) = runtime.infer(input).await;
+                    input = next;
+                    for (batch, outputs) in outputs.iter_mut().enumerate() {
+                        let logits = &output.logits[batch];
+                        let taps = output.taps.values().map(|x| x[batch].clone()).collect_vec();
+                        if logits.shape()[1] > 0 {
+                            outputs.push((logits.clone(), taps));
+                        }
+                    }
+                }
+
+                let check = |a: &[f32], b: &[f32], name: &str| {
+                    for (a, b) in a.iter().zip_eq(b.iter()) {
+                        assert!(
+                            (a - b).abs() < 1.0e-2 * a.abs().max(1.0),
+                            "{version:?}, {name}: {a} vs {b}"
+                        );
+                    }
+                };
+                // batch 0 has outputs of all tokens
+                let (_, taps) = &outputs[0][0];
+                let tokens = taps[0].shape()[1];
+                for (tap, tensor) in [Tap::PostFfn(3), Tap::PostFfn(7)].iter().zip(&taps[1..3]) {
+                    let Tap::PostFfn(layer) = tap else {
+                        unreachable!()
+                    };
+                    for (token, expected) in expected[0].iter().take(tokens).enumerate() {
+                        let x = tensor.slice(.., token, .., ..)?;
+                        check(&expected[*layer], &x, &format!("{tap:?}"));
+                    }
+                }
+                // batch 1 has only the last token
+                let (logits, taps) = outputs[1].last().unwrap();
+                assert_eq!(logits.shape()[1], 1);
+                check(&expected[1].last().unwrap()[7], &taps[2], "last PostFfn(7)");
+                let head = layer_norm(
+                    &taps[2],
+                    &reference.vector("ln_out.weight"),
+                    &reference.vector("ln_out.bias"),
+                    LN_EPS,
+                );
+                check(&head, &taps[3], "HeadNorm");
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,813 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use half::f16;
+use safetensors::{tensor::TensorView, Dtype, SafeTensorError};
+
+use super::{
+    loader::{ReaderSend, ReaderTensor},
+    model::{ModelInfo, ModelVersion},
+};
+
+/// Minimal xorshift generator so that the weights only depend on the seed.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // splitmix the seed so that small seeds still give well mixed states
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Self((z ^ (z >> 31)).max(1))
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform in `[low, high)`.
+    fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.f32()
+    }
+}
+
+/// A model of any version with random weights, built directly in memory.
+/// It implements [`Reader`](super::loader::Reader), so it can be fed to [`ModelBuilder`](super::model::ModelBuilder)
+/// like a checkpoint, which lets tests and examples run the full pipeline without downloading anything.
+#[derive(Debug, Clone)]
+pub struct TinyModel {
+    info: ModelInfo,
+    pub(crate) tensors: HashMap<String, (Vec<usize>, Vec<f16>)>,
+}
+
+impl TinyModel {
+    /// Small dimensions that satisfy the constraints of all kernels: 2 layers, 128 embed, 2 heads.
+    pub fn info(version: ModelVersion) -> ModelInfo {
+        let (time_mix_adapter_size, time_decay_adapter_size) = match version {
+            ModelVersion::V6 => (32, 64),
+            _ => (0, 0),
+        };
+        ModelInfo {
+            version,
+            num_layer: 2,
+            num_emb: 128,
+            num_hidden: 256,
+            num_vocab: 256,
+            num_head: 2,
+            time_mix_adapter_size,
+            time_decay_adapter_size,
+        }
+    }
+
+    /// Generate the weights of a model with given dimensions.
+    /// The same `info` and `seed` always give the same weights.
+    ///
+    /// Note that the head size (`num_emb / num_head`) must divide 128 for V5 and V6 models.
+    pub fn new(info: ModelInfo, seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut tensors = HashMap::new();
+
+        let ModelInfo {
+            version,
+            num_layer,
+            num_emb,
+            num_hidden,
+            num_vocab,
+            num_head,
+            time_mix_adapter_size,
+            time_decay_adapter_size,
+        } = info;
+        let head_size = num_emb / num_head;
+
+        let mut insert = |name: String, shape: Vec<usize>, low: f32, high: f32| {
+            let len = shape.iter().product();
+            let data = (0..len)
+                .map(|_| f16::from_f32(rng.range(low, high)))
+                .collect();
+            tensors.insert(name, (shape, data));
+        };
+        let scale = |fan_in: usize| 1.0 / (fan_in as f32).sqrt();
+
+        insert("emb.weight".into(), vec![num_vocab, num_emb], -1.0, 1.0);
+        insert("blocks.0.ln0.weight".into(), vec![num_emb], 0.9, 1.1);
+        insert("blocks.0.ln0.bias".into(), vec![num_emb], -0.1, 0.1);
+
+        for layer in 0..num_layer {
+            let block = format!("blocks.{layer}");
+            for ln in ["ln1", "ln2"] {
+                insert(format!("{block}.{ln}.weight"), vec![num_emb], 0.9, 1.1);
+                insert(format!("{block}.{ln}.bias"), vec![num_emb], -0.1, 0.1);
+            }
+
+            let att = format!("{block}.att");
+            let s = scale(num_emb);
+            for name in ["key", "value", "receptance", "output"] {
+                insert(
+                    format!("{att}.{name}.weight"),
+                    vec![num_emb, num_emb],
+                    -s,
+                    s,
+                );
+            }
+
+            match version {
+                ModelVersion::V4 => {
+                    insert(format!("{att}.time_decay"), vec![num_emb], -1.0, 1.0);
+                    insert(format!("{att}.time_first"), vec![num_emb], -1.0, 1.0);
+                    for name in ["k", "v", "r"] {
+                        insert(format!("{att}.time_mix_{name}"), vec![num_emb], 0.0, 1.0);
+                    }
+                }
+                ModelVersion::V5 | ModelVersion::V6 => {
+                    insert(format!("{att}.gate.weight"), vec![num_emb, num_emb], -s, s);
+                    insert(format!("{att}.ln_x.weight"), vec![num_emb], 0.9, 1.1);
+                    insert(format!("{att}.ln_x.bias"), vec![num_emb], -0.1, 0.1);
+                    insert(
+                        format!("{att}.time_first"),
+                        vec![num_head, head_size],
+                        -1.0,
+                        1.0,
+                    );
+                }
+            }
+
+            match version {
+                ModelVersion::V4 => {}
+                ModelVersion::V5 => {
+                    insert(
+                        format!("{att}.time_decay"),
+                        vec![num_head, head_size],
+                        -2.0,
+                        1.0,
+                    );
+                    for name in ["k", "v", "r", "g"] {
+                        insert(format!("{att}.time_mix_{name}"), vec![num_emb], 0.0, 1.0);
+                    }
+                }
+                ModelVersion::V6 => {
+                    insert(format!("{att}.time_decay"), vec![num_emb], -2.0, 1.0);
+                    for name in ["x", "w", "k", "v", "r", "g"] {
+                        insert(format!("{att}.time_mix_{name}"), vec![num_emb], 0.0, 1.0);
+                    }
+
+                    let (d, s) = (time_mix_adapter_size, scale(time_mix_adapter_size));
+                    insert(
+                        format!("{att}.time_mix_w1"),
+                        vec![5 * d, num_emb],
+                        -0.1,
+                        0.1,
+                    );
+                    insert(format!("{att}.time_mix_w2"), vec![5, num_emb, d], -s, s);
+
+                    let (d, s) = (time_decay_adapter_size, scale(time_decay_adapter_size));
+                    insert(format!("{att}.time_decay_w1"), vec![d, num_emb], -0.1, 0.1);
+                    insert(format!("{att}.time_decay_w2"), vec![num_emb, d], -s, s);
+                }
+            }
+
+            let ffn = format!("{block}.ffn");
+            insert(format!("{ffn}.time_mix_k"), vec![num_emb], 0.0, 1.0);
+            insert(format!("{ffn}.time_mix_r"), vec![num_emb], 0.0, 1.0);
+
+            let s = scale(num_emb);
+            insert(
+                format!("{ffn}.key.weight"),
+                vec![num_hidden, num_emb],
+                -s,
+                s,
+            );
+            insert(
+                format!("{ffn}.receptance.weight"),
+                vec![num_emb, num_emb],
+                -s,
+                s,
+            );
+            let s = scale(num_hidden);
+            insert(
+                format!("{ffn}.value.weight"),
+                vec![num_emb, num_hidden],
+                -s,
+                s,
+            );
+        }
+
+        insert("ln_out.weight".into(), vec![num_emb], 0.9, 1.1);
+        insert("ln_out.bias".into(), vec![num_emb], -0.1, 0.1);
+
+        let s = scale(num_emb);
+        insert("head.weight".into(), vec![num_vocab, num_emb], -s, s);
+
+        let num_head = match version {
+            ModelVersion::V4 => num_emb,
+            _ => num_head,
+        };
+        let info = ModelInfo { num_head, ..info };
+        Self { info, tensors }
+    }
+
+    /// Dimensions of the model, as [`Loader::info`](super::loader::Loader::info) would report.
+    #[inline]
+    pub fn model_info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    /// Raw data of a tensor in `f16` together with its shape, in the same layout as the checkpoint.
+    pub fn data(&self, name: &str) -> Option<(&[usize], &[f16])> {
+        self.tensors
+            .get(name)
+            .map(|(shape, data)| (&shape[..], &data[..]))
+    }
+
+    /// Serialize the model into the safetensors format.
+    pub fn serialize(&self) -> Result<Vec<u8>, SafeTensorError> {
+        let views = self
+            .tensors
+            .iter()
+            .map(|(name, (shape, data))| {
+                let data = bytemuck::cast_slice(data);
+                Ok((name, TensorView::new(Dtype::F16, shape.clone(), data)?))
+            })
+            .collect::<Result<Vec<_>, SafeTensorError>>()?;
+        safetensors::serialize(views, &None)
+    }
+}
+
+impl ReaderSend for TinyModel {
+    #[inline]
+    fn names(&self) -> Vec<&str> {
+        self.tensors.keys().map(AsRef::as_ref).collect()
+    }
+
+    #[inline]
+    fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    #[inline]
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        match self.tensors.get(name) {
+            Some((shape, _)) => Ok(shape.clone()),
+            None => Err(SafeTensorError::TensorNotFound(name.into())),
+        }
+    }
+
+    #[inline]
+    async fn tensor(&self, name: &str) -> Result<ReaderTensor<'_>, SafeTensorError> {
+        match self.tensors.get(name) {
+            Some((shape, data)) => {
+                let data = Cow::Borrowed(bytemuck::cast_slice(data));
+                Ok((Dtype::F16, shape.clone(), data))
+            }
+            None => Err(SafeTensorError::TensorNotFound(name.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::ops::Deref;
+
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::TinyModel;
+    use crate::{
+        context::{Context, ContextBuilder, InstanceExt},
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+            loader::{Loader, Reader},
+            model::{ContextAutoLimits, ModelBuilder, ModelInfo, ModelVersion},
+            JobRuntime,
+        },
+    };
+
+    pub(crate) const LN_EPS: f32 = 1.0e-5;
+    const GN_EPS: f32 = 64.0e-5;
+
+    pub(crate) async fn create_context(info: &ModelInfo) -> Result<Context> {
+        let instance = Instance::default();
+        let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+        let context = ContextBuilder::new(adapter)
+            .auto_limits(info)
+            .build()
+            .await?;
+        Ok(context)
+    }
+
+    /// Build the model of `$builder` as `$version`, and evaluate `$body` with `$runtime` bound to a closure
+    /// that makes a new `ModelRuntime<f32>` of `$num_batch` batches on it.
+    /// Must be used inside of an async block returning [`Result`].
+    macro_rules! with_runtime {
+        ($builder:expr, $version:expr, $num_batch:expr, |$runtime:ident| $body:expr) => {{
+            use $crate::runtime::{
+                model::{Build, ModelVersion},
+                v4, v5, v6,
+            };
+            let builder = $builder;
+            let num_batch: usize = $num_batch;
+            match $version {
+                ModelVersion::V4 => {
+                    let model = Build::<v4::Model>::build(builder).await?;
+                    let $runtime = || v4::ModelRuntime::<f32>::new(model.clone(), num_batch);
+                    $body
+                }
+                ModelVersion::V5 => {
+                    let model = Build::<v5::Model>::build(builder).await?;
+                    let $runtime = || v5::ModelRuntime::<f32>::new(model.clone(), num_batch);
+                    $body
+                }
+                ModelVersion::V6 => {
+                    let model = Build::<v6::Model>::build(builder).await?;
+                    let $runtime = || v6::ModelRuntime::<f32>::new(model.clone(), num_batch);
+                    $body
+                }
+            }
+        }};
+    }
+    pub(crate) use with_runtime;
+
+    pub(crate) fn layer_norm(x: &[f32], w: &[f32], b: &[f32], eps: f32) -> Vec<f32> {
+        let n = x.len() as f32;
+        let mean = x.iter().sum::<f32>() / n;
+        let var = x.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / n;
+        let std = (var + eps).sqrt();
+        itertools::multizip((x, w, b))
+            .map(|(x, w, b)| (x - mean) / std * w + b)
+            .collect()
+    }
+
+    fn sigmoid(x: f32) -> f32 {
+        1.0 / (1.0 + (-x).exp())
+    }
+
+    /// Linear interpolation `x + (y - x) * f`.
+    fn mix(x: &[f32], y: &[f32], f: &[f32]) -> Vec<f32> {
+        itertools::multizip((x, y, f))
+            .map(|(x, y, f)| x + (y - x) * f)
+            .collect()
+    }
+
+    /// Recurrent state of one layer.
+    #[derive(Debug, Clone)]
+    pub(crate) struct LayerState {
+        att_x: Vec<f32>,
+        /// `aa`, `bb` and `pp` for V4; the `[H, S, S]` matrix for V5 and V6.
+        att: Vec<f32>,
+        ffn_x: Vec<f32>,
+    }
+
+    /// A straightforward single-token CPU implementation of the model in `f32`.
+    pub(crate) struct Reference<'a> {
+        model: &'a TinyModel,
+        info: ModelInfo,
+        head_size: usize,
+    }
+
+    impl<'a> Reference<'a> {
+        pub(crate) fn new(model: &'a TinyModel) -> Self {
+            let info = model.model_info().clone();
+            let head_size = match info.version {
+                ModelVersion::V4 => 1,
+                _ => info.num_emb / info.num_head,
+            };
+            Self {
+                model,
+                info,
+                head_size,
+            }
+        }
+
+        pub(crate) fn init(&self) -> Vec<LayerState> {
+            let num_emb = self.info.num_emb;
+            let att = match self.info.version {
+                ModelVersion::V4 => [vec![0.0; 2 * num_emb], vec![f32::MIN; num_emb]].concat(),
+                _ => vec![0.0; num_emb * self.head_size],
+            };
+            let state = LayerState {
+                att_x: vec![0.0; num_emb],
+                att,
+                ffn_x: vec![0.0; num_emb],
+            };
+            vec![state; self.info.num_layer]
+        }
+
+        pub(crate) fn vector(&self, name: &str) -> Vec<f32> {
+            let (_, data) = self.model.data(name).expect(name);
+            data.iter().map(|x| x.to_f32()).collect()
+        }
+
+        /// Multiply with a checkpoint matrix of shape `[M, K]`, optionally the `batch`-th one in a stack.
+        fn matmul(&self, name: &str, batch: usize, x: &[f32]) -> Vec<f32> {
+            let (shape, data) = self.model.data(name).expect(name);
+            let [m, k] = shape[shape.len() - 2..] else {
+                unreachable!()
+            };
+            assert_eq!(k, x.len());
+            let data = &data[batch * m * k..(batch + 1) * m * k];
+            data.chunks_exact(k)
+                .map(|row| row.iter().zip(x).map(|(w, x)| w.to_f32() * x).sum())
+                .collect()
+        }
+
+        fn forward(&self, state: &mut [LayerState], token: u16) -> Vec<f32> {
+            self.forward_layers(state, token).1
+        }
+
+        /// Run a token, returning the outputs of each layer along with the logits.
+        pub(crate) fn forward_layers(
+            &self,
+            state: &mut [LayerState],
+            token: u16,
+        ) -> (Vec<Vec<f32>>, Vec<f32>) {
+            let ModelInfo {
+                version, num_emb, ..
+            } = self.info;
+            let (num_head, head_size) = (num_emb / self.head_size, self.head_size);
+
+            let (_, emb) = self.model.data("emb.weight").unwrap();
+            let token = token as usize;
+            let x = emb[token * num_emb..(token + 1) * num_emb]
+                .iter()
+                .map(|x| x.to_f32())
+                .collect_vec();
+            let mut x = layer_norm(
+                &x,
+                &self.vector("blocks.0.ln0.weight"),
+                &self.vector("blocks.0.ln0.bias"),
+                LN_EPS,
+            );
+
+            let mut layers = vec![];
+            for (layer, state) in state.iter_mut().enumerate() {
+                let att = format!("blocks.{layer}.att");
+                let xa = layer_norm(
+                    &x,
+                    &self.vector(&format!("blocks.{layer}.ln1.weight")),
+                    &self.vector(&format!("blocks.{layer}.ln1.bias")),
+                    LN_EPS,
+                );
+                let mix_a = |name: &str| {
+                    let f = self.vector(&format!("{att}.time_mix_{name}"));
+                    match version {
+                        ModelVersion::V6 => mix(&xa, &state.att_x, &f),
+                        _ => mix(&state.att_x, &xa, &f),
+                    }
+                };
+
+                let y = match version {
+                    ModelVersion::V4 => {
+                        let k = self.matmul(&format!("{att}.key.weight"), 0, &mix_a("k"));
+                        let v = self.matmul(&format!("{att}.value.weight"), 0, &mix_a("v"));
+                        let r = self.matmul(&format!("{att}.receptance.weight"), 0, &mix_a("r"));
+                        let w = self
+                            .vector(&format!("{att}.time_decay"))
+                            .into_iter()
+                            .map(|x| -x.exp())
+                            .collect_vec();
+                        let u = self.vector(&format!("{att}.time_first"));
+
+                        let (aa, rest) = state.att.split_at_mut(num_emb);
+                        let (bb, pp) = rest.split_at_mut(num_emb);
+                        (0..num_emb)
+                            .map(|i| {
+                                let ww = u[i] + k[i];
+                                let q = pp[i].max(ww);
+                                let e1 = (pp[i] - q).exp();
+                                let e2 = (ww - q).exp();
+                                let y =
+                                    sigmoid(r[i]) * (e1 * aa[i] + e2 * v[i]) / (e1 * bb[i] + e2);
+
+                                let ww = w[i] + pp[i];
+                                let q = ww.max(k[i]);
+                                let e1 = (ww - q).exp();
+                                let e2 = (k[i] - q).exp();
+                                aa[i] = e1 * aa[i] + e2 * v[i];
+                                bb[i] = e1 * bb[i] + e2;
+                                pp[i] = q;
+                                y
+                            })
+                            .collect_vec()
+                    }
+                    ModelVersion::V5 | ModelVersion::V6 => {
+                        let (xk, xv, xr, xg, w) = match version {
+                            ModelVersion::V5 => {
+                                let w = self
+                                    .vector(&format!("{att}.time_decay"))
+                                    .into_iter()
+                                    .map(|x| (-x.exp()).exp())
+                                    .collect_vec();
+                                (mix_a("k"), mix_a("v"), mix_a("r"), mix_a("g"), w)
+                            }
+                            _ => {
+                                let d = self.info.time_mix_adapter_size;
+                                let xx = mix_a("x");
+                                let t = self
+                                    .matmul(&format!("{att}.time_mix_w1"), 0, &xx)
+                                    .into_iter()
+                                    .map(f32::tanh)
+                                    .collect_vec();
+                                let [xw, xk, xv, xr, xg] = ["w", "k", "v", "r", "g"]
+                                    .into_iter()
+                                    .enumerate()
+                                    .map(|(i, name)| {
+                                        let w2 = format!("{att}.time_mix_w2");
+                                        let m = self.matmul(&w2, i, &t[i * d..(i + 1) * d]);
+                                        let f = self.vector(&format!("{att}.time_mix_{name}"));
+                                        let f = m.iter().zip(f).map(|(m, f)| m + f).collect_vec();
+                                        mix(&xa, &state.att_x, &f)
+                                    })
+                                    .collect_vec()
+                                    .try_into()
+                                    .unwrap();
+                                let t = self
+                                    .matmul(&format!("{att}.time_decay_w1"), 0, &xw)
+                                    .into_iter()
+                                    .map(f32::tanh)
+                                    .collect_vec();
+                                let w = self
+                                    .matmul(&format!("{att}.time_decay_w2"), 0, &t)
+                                    .into_iter()
+                                    .zip(self.vector(&format!("{att}.time_decay")))
+                                    .map(|(x, y)| (-(x + y).exp()).exp())
+                                    .collect_vec();
+                                (xk, xv, xr, xg, w)
+                            }
+                        };
+                        let k = self.matmul(&format!("{att}.key.weight"), 0, &xk);
+                        let v = self.matmul(&format!("{att}.value.weight"), 0, &xv);
+                        let r = self.matmul(&format!("{att}.receptance.weight"), 0, &xr);
+                        let g = self.matmul(&format!("{att}.gate.weight"), 0, &xg);
+                        let u = self.vector(&format!("{att}.time_first"));
+
+                        let ln_w = self.vector(&format!("{att}.ln_x.weight"));
+                        let ln_b = self.vector(&format!("{att}.ln_x.bias"));
+
+                        let mut y = vec![];
+                        for h in 0..num_head {
+                            let offset = h * head_size;
+                            let s = &mut state.att
+                                [offset * head_size..(offset + head_size) * head_size];
+                            let mut yh = vec![0.0; head_size];
+                            for j in 0..head_size {
+                                let (kj, rj) = (k[offset + j], r[offset + j]);
+                                let (uj, wj) = (u[offset + j], w[offset + j]);
+                                for i in 0..head_size {
+                                    let kv = kj * v[offset + i];
+                                    let s = &mut s[j * head_size + i];
+                                    yh[i] += rj * (uj * kv + *s);
+                                    *s = wj * *s + kv;
+                                }
+                            }
+                            let range = offset..offset + head_size;
+                            y.append(&mut layer_norm(
+                                &yh,
+                                &ln_w[range.clone()],
+                                &ln_b[range],
+                                GN_EPS,
+                            ));
+                        }
+                        y.iter()
+                            .zip(g)
+                            .map(|(y, g)| y * g * sigmoid(g))
+                            .collect_vec()
+                    }
+                };
+                state.att_x = xa;
+
+                let o = self.matmul(&format!("{att}.output.weight"), 0, &y);
+                x.iter_mut().zip(o).for_each(|(x, o)| *x += o);
+
+                let ffn = format!("blocks.{layer}.ffn");
+                let xf = layer_norm(
+                    &x,
+                    &self.vector(&format!("blocks.{layer}.ln2.weight")),
+                    &self.vector(&format!("blocks.{layer}.ln2.bias")),
+                    LN_EPS,
+                );
+                let mix_f = |name: &str| {
+                    let f = self.vector(&format!("{ffn}.time_mix_{name}"));
+                    match version {
+                        ModelVersion::V6 => mix(&xf, &state.ffn_x, &f),
+                        _ => mix(&state.ffn_x, &xf, &f),
+                    }
+                };
+                let k = self
+                    .matmul(&format!("{ffn}.key.weight"), 0, &mix_f("k"))
+                    .into_iter()
+                    .map(|x| x.max(0.0).powi(2))
+                    .collect_vec();
+                let v = self.matmul(&format!("{ffn}.value.weight"), 0, &k);
+                let r = self.matmul(&format!("{ffn}.receptance.weight"), 0, &mix_f("r"));
+                state.ffn_x = xf;
+
+                itertools::multizip((x.iter_mut(), v, r))
+                    .for_each(|(x, v, r)| *x += sigmoid(r) * v);
+                layers.push(x.clone());
+            }
+
+            let x = layer_norm(
+                &x,
+                &self.vector("ln_out.weight"),
+                &self.vector("ln_out.bias"),
+                LN_EPS,
+            );
+            (layers, self.matmul("head.weight", 0, &x))
+        }
+    }
+
+    pub(crate) fn prompts(info: &ModelInfo) -> Vec<Vec<u16>> {
+        let mut rng = super::Rng::new(7);
+        [45, 20]
+            .into_iter()
+            .map(|len| {
+                (0..len)
+                    .map(|_| (rng.f32() * info.num_vocab as f32) as u16)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Run all prompts in one go on GPU, returning the logits of all tokens in each batch.
+    pub(crate) async fn infer_gpu<R: Reader>(
+        model: R,
+        prompts: &[Vec<u16>],
+        num_vocab: Option<usize>,
+    ) -> Result<Option<Vec<Vec<f32>>>> {
+        let info = Loader::info(&model)?;
+        let Ok(context) = create_context(&info).await else {
+            return Ok(None);
+        };
+
+        let num_batch = prompts.len();
+        let builder = ModelBuilder::new(&context, model);
+        let builder = match num_vocab {
+            Some(num_vocab) => builder.trim_vocab(num_vocab),
+            None => builder,
+        };
+        let runtime = with_runtime!(builder, info.version, num_batch, |runtime| {
+            JobRuntime::new(runtime()).await
+        });
+
+        let batches = prompts
+            .iter()
+            .map(|tokens| InferInputBatch {
+                tokens: tokens.clone().into(),
+                option: InferOption::Full,
+                ..Default::default()
+            })
+            .collect();
+        let mut input = InferInput::new(batches, 32);
+        let mut logits = vec![vec![]; num_batch];
+        while input.num_token() > 0 {
+            let (next, InferOutput(output)) = runtime.infer(input).await;
+            input = next;
+            for (logits, output) in logits.iter_mut().zip_eq(output) {
+                logits.extend_from_slice(&output.0);
+            }
+        }
+        Ok(Some(logits))
+    }
+
+    fn check_golden(version: ModelVersion) -> Result<()> {
+        let prompts = prompts(&TinyModel::info(version));
+        check_prompts(version, &prompts)
+    }
+
+    /// Compare the logits of all prompts run in one go against the reference.
+    pub(crate) fn check_prompts(version: ModelVersion, prompts: &[Vec<u16>]) -> Result<()> {
+        let model = TinyModel::new(TinyModel::info(version), 42);
+        let info = model.model_info().clone();
+
+        let reference = Reference::new(&model);
+        let expected = prompts
+            .iter()
+            .map(|tokens| {
+                let mut state = reference.init();
+                tokens
+                    .iter()
+                    .flat_map(|&token| reference.forward(&mut state, token))
+                    .collect_vec()
+            })
+            .collect_vec();
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let Some(output) = runtime.block_on(infer_gpu(model, prompts, None))? else {
+            return Ok(());
+        };
+
+        for (expected, output) in expected.iter().zip_eq(output.iter()) {
+            assert_eq!(expected.len(), output.len());
+            for (index, (a, b)) in expected.iter().zip_eq(output.iter()).enumerate() {
+                let token = index / info.num_vocab;
+                assert!(
+                    (a - b).abs() < 1.0e-2 * a.abs().max(1.0),
+                    "{version:?}: token {token}, logit {}: {a} vs {b}",
+                    index % info.num_vocab,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tiny_model_info() -> Result<()> {
+        for version in [ModelVersion::V4, ModelVersion::V5, ModelVersion::V6] {
+            let model = TinyModel::new(TinyModel::info(version), 42);
+            assert_eq!(&Loader::info(&model)?, model.model_info());
+
+            let data = model.serialize()?;
+            let tensors = safetensors::SafeTensors::deserialize(&data)?;
+            assert_eq!(&Loader::info(&tensors)?, model.model_info());
+
+            let other = TinyModel::new(TinyModel::info(version), 42);
+            let (_, x) = model.data("blocks.1.att.key.weight").unwrap();
+            let (_, y) = other.data("blocks.1.att.key.weight").unwrap();
+            assert_eq!(x, y);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_golden_v4() -> Result<()> {
+        check_golden(ModelVersion::V4)
+    }
+
+    #[test]
+    fn test_golden_v5() -> Result<()> {
+        check_golden(ModelVersion::V5)
+    }
+
+    #[test]
+    fn test_golden_v6() -> Result<()> {
+        check_golden(ModelVersion::V6)
+    }
+
+    /// Feed a prompt and then `steps` single tokens into both batches, returning the logits of each step.
+    pub(crate) async fn infer_steps(
+        runtime: JobRuntime<InferInput, InferOutput>,
+        prompt: &[u16],
+        steps: &[u16],
+    ) -> Vec<Vec<f32>> {
+        let mut logits = vec![];
+        for tokens in [prompt.to_vec()]
+            .into_iter()
+            .chain(steps.iter().map(|&token| vec![token]))
+        {
+            let batch = InferInputBatch {
+                tokens: tokens.into(),
+                option: InferOption::Last,
+                ..Default::default()
+            };
+            let mut input = InferInput::new(vec![batch; 2], 32);
+            let mut output = vec![];
+            while input.num_token() > 0 {
+                let (next, InferOutput(batches)) = runtime.infer(input).await;
+                input = next;
+                output = batches.into_iter().flat_map(|x| x.0.to_vec()).collect();
+            }
+            logits.push(output);
+        }
+        logits
+    }
+
+    /// Generate `len` tokens after each prompt by feeding back the tokens picked on GPU.
+    pub(crate) async fn generate<O>(
+        runtime: &JobRuntime<InferInput, O>,
+        prompts: &[Vec<u16>],
+        len: usize,
+    ) -> Vec<Vec<u16>>
+    where
+        O: Deref<Target = Vec<Vec<u16>>> + Send + 'static,
+    {
+        let batches = prompts
+            .iter()
+            .map(|tokens| InferInputBatch {
+                tokens: tokens.clone().into(),
+                option: InferOption::Last,
+                ..Default::default()
+            })
+            .collect_vec();
+        let mut input = InferInput::new(batches, 32);
+        let mut generated = vec![vec![]; prompts.len()];
+        while input.num_token() > 0 {
+            let (next, output) = runtime.infer(input).await;
+            input = next;
+            for (batch, tokens) in output.iter().enumerate() {
+                let Some(&token) = tokens.last() else {
+                    continue;
+                };
+                generated[batch].push(token);
+                if generated[batch].len() < len {
+                    input.batches[batch].tokens = vec![token].into();
+                }
+            }
+        }
+        generated
+    }
+}
@@ -0,0 +1,365 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::model::{ModelInfo, State};
+use crate::tensor::{TensorCpu, TensorInit, TensorShape};
+
+/// Leading bytes of an encoded state.
+pub const TRANSFER_MAGIC: [u8; 8] = *b"RWKVSTAT";
+/// Version of the layout of encoded states, bumped whenever it changes.
+pub const TRANSFER_FORMAT: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TransferError {
+    #[error("not an encoded state")]
+    Magic,
+    #[error("encoded state format {0} is not supported")]
+    Format(u32),
+    #[error("state encoded by crate version {0} is not compatible with {1}")]
+    CrateVersion(String, String),
+    #[error("state encoded for a different model")]
+    ModelInfo,
+    #[error("codec {0} is not registered")]
+    Codec(String),
+    #[error("state data is truncated or has trailing bytes")]
+    Size,
+    #[error("state checksum mismatch")]
+    Checksum,
+}
+
+/// Compresses the payload of an encoded state. The codec that encodes a state must be registered when decoding it.
+pub trait Codec: Send + Sync {
+    /// Name recorded in the header to find the codec when decoding.
+    fn name(&self) -> &str;
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>>;
+    /// Decode the payload back into `len` bytes.
+    fn decode(&self, data: &[u8], len: usize) -> Result<Vec<u8>>;
+}
+
+/// Stores the payload as is.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Uncompressed;
+
+impl Codec for Uncompressed {
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn decode(&self, data: &[u8], _len: usize) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// Compresses the payload with zstd at the given level.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct Zstd(pub i32);
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Self(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(zstd::bulk::compress(data, self.0)?)
+    }
+
+    fn decode(&self, data: &[u8], len: usize) -> Result<Vec<u8>> {
+        Ok(zstd::bulk::decompress(data, len)?)
+    }
+}
+
+/// Describes an encoded state, so that the receiver can check it before loading.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferHeader {
+    pub format: u32,
+    /// Version of the crate that encoded the state.
+    pub crate_version: String,
+    pub info: ModelInfo,
+    pub shape: [usize; 4],
+    pub codec: String,
+    /// Size of the payload before compression.
+    pub len: usize,
+    /// FNV-1a hash of the payload before compression.
+    pub checksum: u64,
+}
+
+impl TransferHeader {
+    /// Read the header of an encoded state without decoding the payload.
+    pub fn read(data: &[u8]) -> Result<(Self, &[u8])> {
+        let Some(data) = data.strip_prefix(&TRANSFER_MAGIC) else {
+            bail!(TransferError::Magic);
+        };
+        if data.len() < 4 {
+            bail!(TransferError::Size);
+        }
+        let (len, data) = data.split_at(4);
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if data.len() < len {
+            bail!(TransferError::Size);
+        }
+        let (header, payload) = data.split_at(len);
+
+        let reader = cbor4ii::core::utils::SliceReader::new(header);
+        let mut deserializer = cbor4ii::serde::Deserializer::new(reader);
+        let header = Self::deserialize(&mut deserializer)?;
+        Ok((header, payload))
+    }
+
+    /// Check that a state with this header can be loaded into a model of `info` by this build of the crate.
+    pub fn check(&self, info: &ModelInfo) -> Result<(), TransferError> {
+        if self.format != TRANSFER_FORMAT {
+            return Err(TransferError::Format(self.format));
+        }
+        let version = env!("CARGO_PKG_VERSION");
+        if !compatible(&self.crate_version, version) {
+            return Err(TransferError::CrateVersion(
+                self.crate_version.clone(),
+                version.into(),
+            ));
+        }
+        if &self.info != info {
+            return Err(TransferError::ModelInfo);
+        }
+        Ok(())
+    }
+}
+
+/// Versions are compatible if they agree up to the first non-zero component, as in Cargo's semver rules.
+fn compatible(x: &str, y: &str) -> bool {
+    let x = x.split('.');
+    let y = y.split('.');
+    for (x, y) in x.zip(y).take(3) {
+        if x != y {
+            return false;
+        }
+        if x != "0" {
+            return true;
+        }
+    }
+    true
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    data.iter()
+        .fold(OFFSET, |hash, &x| (hash ^ x as u64).wrapping_mul(PRIME))
+}
+
+/// Encodes backed states into self-describing bytes for sending sessions between server instances, and decodes them back.
+///
+/// The header ties the state to the [`ModelInfo`] and the crate version, so that a state is never loaded into an incompatible model;
+/// the payload is checked against a checksum after decompression. The checksum guards against corruption, not tampering.
+#[derive(Clone)]
+pub struct StateTransfer {
+    info: ModelInfo,
+    codec: Arc<dyn Codec>,
+    codecs: Vec<Arc<dyn Codec>>,
+}
+
+impl std::fmt::Debug for StateTransfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateTransfer")
+            .field("info", &self.info)
+            .field("codec", &self.codec.name())
+            .field(
+                "codecs",
+                &self.codecs.iter().map(|x| x.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl StateTransfer {
+    /// Encode without compression. Decoding accepts all codecs built into the crate.
+    pub fn new(info: ModelInfo) -> Self {
+        let codecs: Vec<Arc<dyn Codec>> = vec![
+            Arc::new(Uncompressed),
+            #[cfg(feature = "zstd")]
+            Arc::new(Zstd::default()),
+        ];
+        Self {
+            info,
+            codec: codecs[0].clone(),
+            codecs,
+        }
+    }
+
+    /// Encode with `codec`, and also accept it when decoding.
+    pub fn codec(mut self, codec: impl Codec + 'static) -> Self {
+        let codec: Arc<dyn Codec> = Arc::new(codec);
+        self.codecs.retain(|x| x.name() != codec.name());
+        self.codecs.push(codec.clone());
+        self.codec = codec;
+        self
+    }
+
+    pub fn encode(&self, tensor: &TensorCpu<f32>) -> Result<Vec<u8>> {
+        let shape = tensor.shape();
+        let data: Vec<u8> = tensor.data().iter().flat_map(|x| x.to_le_bytes()).collect();
+        let header = TransferHeader {
+            format: TRANSFER_FORMAT,
+            crate_version: env!("CARGO_PKG_VERSION").into(),
+            info: self.info.clone(),
+            shape: [shape[0], shape[1], shape[2], shape[3]],
+            codec: self.codec.name().into(),
+            len: data.len(),
+            checksum: checksum(&data),
+        };
+        let header = cbor4ii::serde::to_vec(vec![], &header)?;
+        let payload = self.codec.encode(&data)?;
+
+        let mut output =
+            Vec::with_capacity(TRANSFER_MAGIC.len() + 4 + header.len() + payload.len());
+        output.extend_from_slice(&TRANSFER_MAGIC);
+        output.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        output.extend_from_slice(&header);
+        output.extend_from_slice(&payload);
+        Ok(output)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<TensorCpu<f32>> {
+        let (header, payload) = TransferHeader::read(data)?;
+        header.check(&self.info)?;
+
+        let Some(codec) = self.codecs.iter().find(|x| x.name() == header.codec) else {
+            bail!(TransferError::Codec(header.codec));
+        };
+        let data = codec.decode(payload, header.len)?;
+        if data.len() != header.len || data.len() != 4 * header.shape.iter().product::<usize>() {
+            bail!(TransferError::Size);
+        }
+        if checksum(&data) != header.checksum {
+            bail!(TransferError::Checksum);
+        }
+
+        let data = data
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect::<Vec<_>>();
+        Ok(TensorCpu::from_data(header.shape, data)?)
+    }
+
+    /// Read back and encode one batch of a state.
+    pub async fn export(&self, state: &impl State, batch: usize) -> Result<Vec<u8>> {
+        let tensor = state.back(batch).await?;
+        self.encode(&tensor)
+    }
+
+    /// Decode a state and load it into one batch.
+    pub fn import(&self, state: &impl State, data: &[u8], batch: usize) -> Result<()> {
+        let tensor = self.decode(data)?;
+        state.load(tensor, batch)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{StateTransfer, TransferError, TransferHeader, TRANSFER_MAGIC};
+    use crate::{
+        runtime::model::{ModelInfo, ModelVersion},
+        tensor::{TensorCpu, TensorInit, TensorShape},
+    };
+
+    fn info(num_layer: usize) -> ModelInfo {
+        ModelInfo {
+            version: ModelVersion::V6,
+            num_layer,
+            num_emb: 8,
+            num_hidden: 16,
+            num_vocab: 32,
+            num_head: 2,
+            time_mix_adapter_size: 4,
+            time_decay_adapter_size: 4,
+        }
+    }
+
+    fn error(result: Result<TensorCpu<f32>>) -> TransferError {
+        result.unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn test_transfer() -> Result<()> {
+        let data: Vec<_> = (0..8 * 6 * 2).map(|x| x as f32 * 0.5 - 7.0).collect();
+        let tensor: TensorCpu<f32> = TensorCpu::from_data([8, 6, 2, 1], data)?;
+
+        let transfer = StateTransfer::new(info(2));
+        let encoded = transfer.encode(&tensor)?;
+        let decoded = transfer.decode(&encoded)?;
+        assert_eq!(decoded.shape(), tensor.shape());
+        assert_eq!(decoded.to_vec(), tensor.to_vec());
+
+        let (header, _) = TransferHeader::read(&encoded)?;
+        assert_eq!(header.codec, "none");
+        assert_eq!(header.crate_version, env!("CARGO_PKG_VERSION"));
+
+        // a flipped bit in the payload is caught by the checksum
+        let mut corrupted = encoded.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(error(transfer.decode(&corrupted)), TransferError::Checksum);
+        let truncated = &encoded[..encoded.len() - 4];
+        assert_eq!(error(transfer.decode(truncated)), TransferError::Size);
+        assert_eq!(error(transfer.decode(&encoded[1..])), TransferError::Magic);
+
+        let other = StateTransfer::new(info(3));
+        assert_eq!(error(other.decode(&encoded)), TransferError::ModelInfo);
+
+        // re-encode the header as if by an incompatible version
+        let mut header = header;
+        header.crate_version = "0.1.0".into();
+        let header = cbor4ii::serde::to_vec(vec![], &header)?;
+        let (_, payload) = TransferHeader::read(&encoded)?;
+        let mut encoded = TRANSFER_MAGIC.to_vec();
+        encoded.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(&header);
+        encoded.extend_from_slice(payload);
+        assert!(matches!(
+            error(transfer.decode(&encoded)),
+            TransferError::CrateVersion(..)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compatible() {
+        assert!(super::compatible("0.8.16", "0.8.3"));
+        assert!(!super::compatible("0.8.16", "0.9.0"));
+        assert!(super::compatible("1.2.0", "1.5.1"));
+        assert!(!super::compatible("1.2.0", "2.0.0"));
+        assert!(!super::compatible("0.0.1", "0.0.2"));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_transfer_zstd() -> Result<()> {
+        let tensor: TensorCpu<f32> = TensorCpu::from_data([64, 4, 1, 1], vec![0.25; 256])?;
+        let encoded = StateTransfer::new(info(2))
+            .codec(super::Zstd(3))
+            .encode(&tensor)?;
+        assert!(encoded.len() < 4 * 256);
+
+        // the default transfer accepts the built-in codecs
+        let decoded = StateTransfer::new(info(2)).decode(&encoded)?;
+        assert_eq!(decoded.to_vec(), tensor.to_vec());
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, marker::PhantomData, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use futures::future::BoxFuture;
 use half::f16;
 use itertools::Itertools;
@@ -9,21 +9,32 @@ use web_rwkv_derive::DeserializeSeed;
 use wgpu::CommandBuffer;
 
 use super::{
-    infer::{InferChunk, InferInfo, InferOutput, InferOutputBatch, InferRedirect},
-    loader::{Loader, Reader},
-    model::{AsAny, Build, EmbedDevice, ModelBuilder, ModelInfo, Quant, State as _},
+    bias::LogitBias,
+    infer::{
+        ExitJob, HeadJob, HeadJobBuilder, InferChunk, InferInfo, InferOutput, InferOutputBatch,
+        InferRedirect,
+    },
+    loader::{load_lora_factor, Loader, Lora, Reader},
+    lora::{LoraAdapter, LoraAdapters, LoraAlpha, LoraFactor, LoraTarget},
+    model::{
+        AsAny, Build, EarlyExit, EmbedDevice, ModelBuilder, ModelInfo, Quant, State as _,
+        StateBuilder, StateInit, StateQuant,
+    },
+    patch::PatchTarget,
+    stream::{HeadSlice, HeadStream},
+    tap::Tap,
     Job, JobBuilder,
 };
 use crate::{
-    context::Context,
+    context::{Arena, Context},
     num::Float,
     tensor::{
         kind::ReadWrite,
         matrix::Matrix,
-        ops::{Activation, TensorCommand, TensorOp},
+        ops::{Activation, BatchCopy, LogitTransform, TensorCommand, TensorOp},
         shape::Shape,
-        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView, TensorInit,
-        TensorShape, TensorStack,
+        Cursor, DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        TensorInit, TensorInto, TensorShape, TensorStack,
     },
 };
 
@@ -34,11 +45,96 @@ pub struct Model {
     pub tensor: ModelTensor,
 }
 
+/// Matrices of each layer that runtime LoRAs apply to, and if they are discounted like their weights.
+const LORA_MATRICES: [(&str, bool); 7] = [
+    ("att.key", false),
+    ("att.value", false),
+    ("att.receptance", false),
+    ("ffn.key", false),
+    ("ffn.receptance", false),
+    ("att.output", true),
+    ("ffn.value", true),
+];
+
 impl Model {
     pub const RESCALE_LAYER: usize = 6;
 
     pub const LN_EPS: f32 = 1.0e-5;
     pub const GN_EPS: f32 = 64.0e-5;
+
+    /// Load the matrices of a LoRA as an adapter to attach to runtimes of the model, see [`LoraAdapters`].
+    /// The LoRA is blended as a runtime LoRA; its vectors and placement are ignored.
+    pub async fn load_lora<R: Reader>(&self, lora: &Lora<R>) -> Result<LoraAdapter> {
+        let context = &self.context.in_arena(Arena::Model);
+        let mut layers = vec![];
+        for layer in 0..self.info.num_layer {
+            let discount = 2.0_f32.powi(-((layer / Self::RESCALE_LAYER) as i32));
+            let mut factors = vec![];
+            for (name, discounted) in LORA_MATRICES {
+                let name = format!("blocks.{layer}.{name}.weight");
+                let Some(target) = LoraTarget::from_name(&name) else {
+                    continue;
+                };
+                let discount = if discounted { discount } else { 1.0 };
+                if let Some(factor) =
+                    load_lora_factor(context, lora, &name, target, discount).await?
+                {
+                    factors.push(factor);
+                }
+            }
+            layers.push(factors);
+        }
+        Ok(LoraAdapter { layers })
+    }
+}
+
+impl super::patch::Patchable for Model {
+    #[inline]
+    fn context(&self) -> &Context {
+        &self.context
+    }
+
+    fn patch_targets(&self) -> Vec<Vec<(String, PatchTarget)>> {
+        let tensor = &self.tensor;
+        let vector = |name: &str, x: &TensorGpu<f16, ReadWrite>| {
+            (name.to_string(), PatchTarget::Vector(x.clone()))
+        };
+        let matrix = |name: &str, x: &Matrix, discount: f32| {
+            (name.to_string(), PatchTarget::Matrix(x.clone(), discount))
+        };
+
+        let mut groups = vec![vec![
+            vector("blocks.0.ln0.weight", &tensor.embed.layer_norm.w),
+            vector("blocks.0.ln0.bias", &tensor.embed.layer_norm.b),
+            vector("ln_out.weight", &tensor.head.layer_norm.w),
+            vector("ln_out.bias", &tensor.head.layer_norm.b),
+            matrix("head.weight", &tensor.head.w, 1.0),
+        ]];
+        for (index, layer) in tensor.layers.iter().enumerate() {
+            let discount = 2.0_f32.powi(-((index / Self::RESCALE_LAYER) as i32));
+            let (att, ffn) = (&layer.att, &layer.ffn);
+            let name = |x: &str| format!("blocks.{index}.{x}");
+            groups.push(vec![
+                vector(&name("ln1.weight"), &layer.att_layer_norm.w),
+                vector(&name("ln1.bias"), &layer.att_layer_norm.b),
+                vector(&name("ln2.weight"), &layer.ffn_layer_norm.w),
+                vector(&name("ln2.bias"), &layer.ffn_layer_norm.b),
+                vector(&name("att.time_mix_k"), &att.time_mix_k),
+                vector(&name("att.time_mix_v"), &att.time_mix_v),
+                vector(&name("att.time_mix_r"), &att.time_mix_r),
+                matrix(&name("att.key.weight"), &att.w_k, 1.0),
+                matrix(&name("att.value.weight"), &att.w_v, 1.0),
+                matrix(&name("att.receptance.weight"), &att.w_r, 1.0),
+                matrix(&name("att.output.weight"), &att.w_o, discount),
+                vector(&name("ffn.time_mix_k"), &ffn.time_mix_k),
+                vector(&name("ffn.time_mix_r"), &ffn.time_mix_r),
+                matrix(&name("ffn.key.weight"), &ffn.w_k, 1.0),
+                matrix(&name("ffn.value.weight"), &ffn.w_v, discount),
+                matrix(&name("ffn.receptance.weight"), &ffn.w_r, 1.0),
+            ]);
+        }
+        groups
+    }
 }
 
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
@@ -85,6 +181,7 @@ pub struct Layer {
     pub ffn_layer_norm: LayerNorm,
     pub att: Att,
     pub ffn: Ffn,
+    pub lora: Vec<LoraFactor>,
 }
 
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
@@ -105,19 +202,24 @@ pub struct State {
     pub context: Context,
     pub info: ModelInfo,
     pub data: TensorGpu<f32, ReadWrite>,
+    #[serde(default)]
+    pub init: StateInit,
 }
 
 impl State {
-    async fn back(&self, batch: usize) -> Result<TensorCpu<f32>, TensorError> {
-        let context = &self.context;
-
-        let shape = self.data.shape();
-        let tensor: TensorGpu<f32, ReadWrite> = context.tensor_init([shape[0], shape[1], 1, 1]);
-        let mut encoder = context.device.create_command_encoder(&Default::default());
-        encoder.copy_tensor_batch(&self.data, &tensor, batch, 0)?;
-        context.queue.submit(Some(encoder.finish()));
-
-        Ok(tensor.back().await)
+    /// Fail if any of the given layers does not exist.
+    fn check_layers(&self, layers: &[usize]) -> Result<(), TensorError> {
+        if layers.is_empty() {
+            return Err(TensorError::Empty);
+        }
+        match layers.iter().find(|&&layer| layer >= self.info.num_layer) {
+            Some(&layer) => Err(TensorError::SliceOutOfRange {
+                dim: self.info.num_layer,
+                start: layer,
+                end: layer + 1,
+            }),
+            None => Ok(()),
+        }
     }
 }
 
@@ -149,7 +251,7 @@ impl super::model::State for State {
             .collect_vec()
             .concat();
         let shape = Shape::new(info.num_emb, 5 * info.num_layer, 1, 1);
-        TensorCpu::from_data(shape, data).unwrap()
+        self.init.apply(TensorCpu::from_data(shape, data).unwrap())
     }
 
     fn att(&self, layer: usize) -> Result<TensorGpuView<f32>, TensorError> {
@@ -170,32 +272,159 @@ impl super::model::State for State {
     }
 
     fn back(&self, batch: usize) -> BoxFuture<Result<TensorCpu<f32>, TensorError>> {
-        Box::pin(self.back(batch))
+        let snapshot = self.snapshot(batch);
+        Box::pin(async move { Ok(snapshot?.back().await) })
     }
 
     fn write(&self, tensor: TensorGpu<f32, ReadWrite>, batch: usize) -> Result<(), TensorError> {
         tensor.check_shape([self.info.num_emb, self.info.num_layer * 5, 1, 1])?;
-        let op = TensorOp::blit(
-            tensor.view(.., .., .., ..)?,
-            self.data.view(.., .., batch, ..)?,
-        )?;
-        self.context.queue.submit(self.context.encode(&op));
+        let context = &self.context;
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        encoder.copy_tensor_batches([BatchCopy::new(&tensor, 0, &self.data, batch)])?;
+        context.submit(Some(encoder.finish()));
         Ok(())
     }
 
     fn read(&self, batch: usize) -> Result<TensorGpu<f32, ReadWrite>, TensorError> {
+        let context = &self.context;
         let shape = [self.info.num_emb, self.info.num_layer * 5, 1, 1];
-        let tensor: TensorGpu<_, _> = self.context.tensor_init(shape);
-        let op = TensorOp::blit(
-            self.data.view(.., .., batch, ..)?,
-            tensor.view(.., .., .., ..)?,
-        )?;
-        self.context.queue.submit(self.context.encode(&op));
+        let tensor: TensorGpu<_, _> = context.tensor_init(shape);
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        encoder.copy_tensor_batches([BatchCopy::new(&self.data, batch, &tensor, 0)])?;
+        context.submit(Some(encoder.finish()));
         Ok(tensor)
     }
 
+    fn fork(&self, batch: usize, targets: &[usize]) -> Result<(), TensorError> {
+        let context = &self.context;
+        let shape = [self.info.num_emb, self.info.num_layer * 5, 1, 1];
+        let tensor: TensorGpu<_, _> = context.tensor_init(shape);
+
+        // a buffer cannot be copied into itself, so the batch is copied out once, and then into all targets
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        encoder.copy_tensor_batches([BatchCopy::new(&self.data, batch, &tensor, 0)])?;
+        let copies = targets
+            .iter()
+            .filter(|&&target| target != batch)
+            .map(|&target| BatchCopy::new(&tensor, 0, &self.data, target));
+        encoder.copy_tensor_batches(copies)?;
+        context.submit(Some(encoder.finish()));
+        Ok(())
+    }
+
+    fn read_layers(
+        &self,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<TensorGpu<f32, ReadWrite>, TensorError> {
+        self.check_layers(layers)?;
+        let context = &self.context;
+        let shape = [self.info.num_emb, layers.len() * 5, 1, 1];
+        let tensor: TensorGpu<_, _> = context.tensor_init(shape);
+
+        let ops = layers
+            .iter()
+            .enumerate()
+            .map(|(index, &layer)| {
+                TensorOp::blit(
+                    self.data.view(.., 5 * layer..5 * layer + 5, batch, ..)?,
+                    tensor.view(.., 5 * index..5 * index + 5, .., ..)?,
+                )
+            })
+            .try_collect()?;
+        context.submit(context.encode(&TensorOp::List(ops)));
+
+        Ok(tensor)
+    }
+
+    fn write_layers(
+        &self,
+        tensor: TensorGpu<f32, ReadWrite>,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<(), TensorError> {
+        self.check_layers(layers)?;
+        tensor.check_shape([self.info.num_emb, layers.len() * 5, 1, 1])?;
+        let context = &self.context;
+
+        let ops = layers
+            .iter()
+            .enumerate()
+            .map(|(index, &layer)| {
+                TensorOp::blit(
+                    tensor.view(.., 5 * index..5 * index + 5, .., ..)?,
+                    self.data.view(.., 5 * layer..5 * layer + 5, batch, ..)?,
+                )
+            })
+            .try_collect()?;
+        context.submit(context.encode(&TensorOp::List(ops)));
+
+        Ok(())
+    }
+
+    fn load_layers(
+        &self,
+        tensor: TensorCpu<f32>,
+        batch: usize,
+        layers: &[usize],
+    ) -> Result<(), TensorError> {
+        self.write_layers(tensor.transfer_into(&self.context), batch, layers)
+    }
+
     fn embed(&self, layer: usize, backed: TensorCpu<f32>) -> Result<TensorCpu<f32>, TensorError> {
-        backed.slice(.., layer, .., ..)
+        backed.slice(.., 5 * layer, .., ..)
+    }
+
+    fn resize(&self, num_batch: usize) -> Result<Self, TensorError> {
+        if num_batch == 0 {
+            return Err(TensorError::Empty);
+        }
+        let context = &self.context;
+        let num_copy = self.num_batch().min(num_batch);
+
+        let init = self.init().repeat(2, num_batch);
+        let data: TensorGpu<f32, _> = context.tensor_from_data(init.shape(), init.to_vec())?;
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        let copies = (0..num_copy).map(|batch| BatchCopy::new(&self.data, batch, &data, batch));
+        encoder.copy_tensor_batches(copies)?;
+        context.submit(Some(encoder.finish()));
+
+        Ok(Self {
+            data,
+            ..self.clone()
+        })
+    }
+}
+
+impl Build<State> for StateBuilder {
+    async fn build(self) -> Result<State> {
+        self.validate()?;
+        let StateBuilder {
+            context,
+            info,
+            num_batch,
+            init,
+            quant,
+        } = self;
+        let context = context.in_arena(Arena::Session);
+        // the state of v4 is only a few vectors per layer, too small to be worth quantizing
+        if quant != StateQuant::None {
+            bail!("state quantization is not supported by v4 models");
+        }
+        let shape = Shape::new(info.num_emb, 5 * info.num_layer, num_batch, 1);
+        let data = context.zeros(shape);
+        let state = State {
+            context,
+            info,
+            data,
+            init,
+        };
+        state.context.check_budget(Arena::Session)?;
+        let tensor = state.init();
+        for batch in 0..num_batch {
+            state.load(tensor.clone(), batch)?;
+        }
+        Ok(state)
     }
 }
 
@@ -238,7 +467,7 @@ pub struct Runtime<F: Float> {
 impl<F: Float> Runtime<F> {
     pub fn new(context: &Context, info: &ModelInfo, num_token: usize) -> Self {
         let shape = Shape::new(info.num_emb, num_token, 1, 1);
-        let cursors_shape = Shape::new(num_token, 1, 1, 1);
+        let cursors_shape = Shape::new(Cursor::PACKED_SIZE, num_token, 1, 1);
         let tokens_shape = Shape::new(num_token, 1, 1, 1);
         let hidden_shape = Shape::new(info.num_hidden, num_token, 1, 1);
 
@@ -317,6 +546,16 @@ pub enum Hook {
 pub struct InferJob {
     commands: Vec<CommandBuffer>,
     redirect: InferRedirect,
+    /// If the job reads exit heads, which decide on the logits, so that they are read back anyway.
+    has_exit: bool,
+    /// Exit heads not read yet, in the order of their layers.
+    exits: Vec<ExitJob>,
+    /// The logits of the exit head that is confident, if any.
+    exited: Option<TensorGpu<f32, ReadWrite>>,
+
+    /// Batches that read back hidden states instead of logits.
+    embeds: Vec<bool>,
+    hidden: Option<TensorGpu<f32, ReadWrite>>,
 
     embed_device: EmbedDevice,
     embed: TensorCpu<f16>,
@@ -325,6 +564,10 @@ pub struct InferJob {
     tokens: TensorGpu<u32, ReadWrite>,
     input: TensorGpu<f16, ReadWrite>,
     output: TensorGpu<f32, ReadWrite>,
+    /// The logits converted to `f16` for readback, if all batches with logits ask for it.
+    half: Option<TensorGpu<f16, ReadWrite>>,
+    /// The head in slices of output rows that are read back on their own, if the runtime has a head chunk.
+    slices: Vec<HeadSlice>,
 }
 
 impl Job for InferJob {
@@ -333,6 +576,7 @@ impl Job for InferJob {
     type Output = InferOutput;
 
     fn load(self, input: &Self::Input) -> Result<Self> {
+        input.check(self.embed.shape()[1])?;
         if input.num_token() == 0 {
             return Ok(self);
         }
@@ -362,19 +606,18 @@ impl Job for InferJob {
         let cursors = TensorCpu::from_data(self.cursors.shape(), cursors)?;
         self.cursors.load(&cursors)?;
 
-        match self.embed_device {
-            EmbedDevice::Cpu => self.input.load(&stack.tensor)?,
-            EmbedDevice::Gpu => {
-                let tokens = input
-                    .iter()
-                    .map(|chunk| chunk.0.clone())
-                    .concat()
-                    .into_iter()
-                    .map(|token| token as u32)
-                    .collect_vec();
-                let tokens = TensorCpu::from_data(self.tokens.shape(), tokens)?;
-                self.tokens.load(&tokens)?;
-            }
+        // token ids are loaded even if embedded on CPU, for ops that look them up (e.g., `TensorOp::inject`)
+        let tokens = input
+            .iter()
+            .map(|chunk| chunk.0.clone())
+            .concat()
+            .into_iter()
+            .map(|token| token as u32)
+            .collect_vec();
+        let tokens = TensorCpu::from_data(self.tokens.shape(), tokens)?;
+        self.tokens.load(&tokens)?;
+        if let EmbedDevice::Cpu = self.embed_device {
+            self.input.load(&stack.tensor)?;
         }
 
         Ok(self)
@@ -382,22 +625,86 @@ impl Job for InferJob {
 
     fn submit(&mut self) {
         let commands = std::mem::take(&mut self.commands);
-        self.output.context.queue.submit(commands);
+        super::stream::submit(&self.output.context, commands, &mut self.slices);
     }
 
-    async fn back(self) -> Result<Self::Output> {
-        let output = self.output.back().await;
+    async fn settle(&mut self) {
+        let exits = std::mem::take(&mut self.exits);
+        if let Some(output) = ExitJob::settle(exits).await {
+            self.exited = Some(output);
+        }
+    }
+
+    async fn back(mut self) -> Result<Self::Output> {
+        let logits = self
+            .redirect
+            .outputs
+            .iter()
+            .zip_eq(&self.embeds)
+            .any(|(&(start, end), &embed)| end > start && !embed);
+        self.settle().await;
+        let output = match self.exited.take() {
+            Some(output) => output.back().await,
+            None if logits => match (&self.half, self.slices.is_empty()) {
+                (Some(half), _) => half.back().await.map(|x| x.to_f32()),
+                (None, false) => {
+                    let slices = std::mem::take(&mut self.slices);
+                    let stream = HeadStream::new(&self.output, &self.redirect, slices);
+                    stream.concat().await?
+                }
+                (None, true) => self.output.back().await,
+            },
+            // the head is skipped, so there are no logits to read back
+            None => TensorCpu::init(self.output.shape()),
+        };
+        let hidden = match self.hidden {
+            Some(hidden) => Some(hidden.back().await),
+            None => None,
+        };
         let batches: Vec<_> = self
             .redirect
             .outputs
             .into_iter()
-            .map(|(start, end)| output.slice(.., start..end, .., ..))
+            .zip_eq(self.embeds)
+            .map(|((start, end), embed)| match (embed, &hidden) {
+                (true, Some(hidden)) => hidden.slice(.., start..end, .., ..),
+                _ => output.slice(.., start..end, .., ..),
+            })
             .try_collect()?;
         let batches = batches.into_iter().map(InferOutputBatch).collect();
         Ok(InferOutput(batches))
     }
 }
 
+impl HeadJob for InferJob {
+    fn redirect(&self) -> &InferRedirect {
+        &self.redirect
+    }
+
+    fn has_exit(&self) -> bool {
+        self.has_exit
+    }
+
+    fn logits(&self) -> &TensorGpu<f32, ReadWrite> {
+        &self.output
+    }
+
+    fn append(&mut self, mut commands: Vec<CommandBuffer>) {
+        self.commands.append(&mut commands);
+    }
+
+    async fn stream(mut self) -> Result<HeadStream> {
+        let redirect = self.redirect.clone();
+        let num_vocab = self.output.shape()[0];
+        if self.slices.is_empty() {
+            let output = self.back().await?;
+            return Ok(HeadStream::from_output(output, &redirect, num_vocab));
+        }
+        let slices = std::mem::take(&mut self.slices);
+        Ok(HeadStream::new(&self.output, &redirect, slices))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Frame<F: Float> {
     pub state: State,
@@ -412,7 +719,12 @@ pub type HookMap<F> = HashMap<Hook, HookFn<F>>;
 pub struct ModelRuntime<F: Float> {
     model: Model,
     state: State,
+    lora: LoraAlpha,
+    adapters: Option<LoraAdapters>,
     hooks: Arc<HookMap<F>>,
+    early_exit: Vec<EarlyExit>,
+    bias: Option<LogitBias>,
+    head_chunk: Option<usize>,
     phantom: PhantomData<F>,
 }
 
@@ -431,11 +743,16 @@ impl<F: Float> super::model::ModelRuntime for ModelRuntime<F> {
     fn model(&self) -> impl Serialize + 'static {
         self.model.clone()
     }
+
+    #[inline]
+    fn lora(&self) -> Option<LoraAlpha> {
+        Some(self.lora.clone())
+    }
 }
 
 impl<F: Float> ModelRuntime<F> {
     pub fn new(model: Model, num_batch: usize) -> Self {
-        let context = model.context.clone();
+        let context = model.context.in_arena(Arena::Session);
         let info = model.info.clone();
         let state = {
             let shape = Shape::new(info.num_emb, 5 * info.num_layer, num_batch, 1);
@@ -457,12 +774,35 @@ impl<F: Float> ModelRuntime<F> {
                 context,
                 info,
                 data,
+                init: StateInit::Zero,
             }
         };
+        Self::new_with_state(model, state)
+    }
+
+    /// Create a runtime over an existing state, e.g., one [resized](super::model::State::resize) from another runtime's.
+    pub fn new_with_state(model: Model, state: State) -> Self {
+        let num_batch = super::model::State::num_batch(&state);
+        let lora = {
+            let num_adapter = model
+                .tensor
+                .layers
+                .iter()
+                .flat_map(|layer| layer.lora.iter())
+                .map(|factor| factor.adapter + 1)
+                .max()
+                .unwrap_or_default();
+            LoraAlpha::new(&model.context, num_adapter, num_batch)
+        };
         Self {
             model,
             state,
+            lora,
+            adapters: None,
             hooks: Default::default(),
+            early_exit: vec![],
+            bias: None,
+            head_chunk: None,
             phantom: PhantomData,
         }
     }
@@ -473,6 +813,90 @@ impl<F: Float> ModelRuntime<F> {
             ..Self::new(model, num_batch)
         }
     }
+
+    /// Set the [early exits](EarlyExit) of the runtime, or clear them with an empty list.
+    /// Exit heads are read in the order of their layers, and the first confident one ends the step.
+    pub fn early_exit(self, value: Vec<EarlyExit>) -> Self {
+        let mut early_exit = value;
+        early_exit.sort_by_key(|exit| exit.layer);
+        early_exit.dedup_by_key(|exit| exit.layer);
+        Self { early_exit, ..self }
+    }
+
+    /// Set or clear the [LoRA adapters](LoraAdapters) attached to the runtime, applied after the runtime LoRAs of the model.
+    pub fn lora_adapters(self, value: Option<LoraAdapters>) -> Self {
+        Self {
+            adapters: value,
+            ..self
+        }
+    }
+
+    /// The [LoRA adapters](LoraAdapters) attached to the runtime, e.g., to select them on a [migrated](Self::migrate) runtime.
+    pub fn adapters(&self) -> Option<&LoraAdapters> {
+        self.adapters.as_ref()
+    }
+
+    /// Set or clear the [logit biases](LogitBias) added to the outputs of the runtime.
+    pub fn logit_bias(self, value: Option<LogitBias>) -> Self {
+        Self {
+            bias: value,
+            ..self
+        }
+    }
+
+    /// Set or clear the number of output rows of each slice of the head, see [`Streamed`](super::stream::Streamed).
+    ///
+    /// Each slice is read back as soon as it is done, overlapping the readback with the rest of the head.
+    /// The head is not sliced if anything runs on the logits after it, e.g., logit biases or hooks after the head.
+    pub fn head_chunk(self, value: Option<usize>) -> Self {
+        Self {
+            head_chunk: value,
+            ..self
+        }
+    }
+
+    /// Move the runtime onto another context, e.g., when switching to a different adapter.
+    /// The model is rebuilt on the new context, and the states and LoRA alphas are copied over.
+    ///
+    /// Hooks are kept as is, so they must not hold tensors of the old context.
+    /// Attached [adapters](LoraAdapters) are copied over into a set of their own, see [`Self::adapters`].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn migrate(&self, context: &Context) -> Result<Self> {
+        let model = super::model::migrate::<Model>(&self.model, context).await?;
+        let state = {
+            // the state is rebuilt as configured, e.g., with noise as its initial state
+            let num_batch = self.state.num_batch();
+            let builder = StateBuilder::new(context, &self.state.info)
+                .num_batch(num_batch)
+                .init(self.state.init);
+            let state = Build::<State>::build(builder).await?;
+            for batch in 0..num_batch {
+                let backed = self.state.back(batch).await?;
+                state.load(backed, batch)?;
+            }
+            state
+        };
+        let lora = self.lora.migrate(context).await?;
+        let adapters = match &self.adapters {
+            Some(adapters) => Some(adapters.migrate(context).await?),
+            None => None,
+        };
+        let bias = match &self.bias {
+            Some(bias) => Some(bias.migrate(context).await?),
+            None => None,
+        };
+        Ok(Self {
+            model,
+            state,
+            lora,
+            adapters,
+            hooks: self.hooks.clone(),
+            early_exit: self.early_exit.clone(),
+            bias,
+            head_chunk: self.head_chunk,
+            phantom: PhantomData,
+        })
+    }
 }
 
 fn turbo(num_token: usize) -> bool {
@@ -493,7 +917,57 @@ fn hook_op<F: Float>(
 impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
     type Info = InferInfo;
 
+    fn maintain(&self) {
+        self.model.context.compact();
+    }
+
+    fn context(&self) -> Option<Context> {
+        Some(self.model.context.clone())
+    }
+
     fn build(&self, seed: Self::Info) -> Result<InferJob> {
+        let context = &self.model.context;
+        let (job, _) = context.catch_oom(|| {
+            self.build_job(seed, &[], self.model.info.num_layer, Default::default())
+        })??;
+        Ok(job)
+    }
+}
+
+impl<F: Float> HeadJobBuilder<InferJob> for ModelRuntime<F> {
+    fn num_layer(&self) -> usize {
+        self.model.info.num_layer
+    }
+
+    fn fuses_logits(&self, info: &InferInfo) -> bool {
+        matches!(self.model.tensor.head.w, Matrix::Int8Row { .. })
+            && self.early_exit.is_empty()
+            && !info.half_logits()
+            && !self.hooks.contains_key(&Hook::PostHead)
+    }
+
+    fn build_head_job(
+        &self,
+        info: InferInfo,
+        taps: &[Tap],
+        depth: usize,
+        transform: LogitTransform,
+    ) -> Result<(InferJob, Vec<(Tap, TensorGpu<f32, ReadWrite>)>)> {
+        let context = &self.model.context;
+        context.catch_oom(|| self.build_job(info, taps, depth, transform))?
+    }
+}
+
+impl<F: Float> ModelRuntime<F> {
+    /// See [`HeadJobBuilder::build_head_job`].
+    #[allow(clippy::type_complexity)]
+    fn build_job(
+        &self,
+        seed: InferInfo,
+        taps: &[Tap],
+        depth: usize,
+        transform: LogitTransform,
+    ) -> Result<(InferJob, Vec<(Tap, TensorGpu<f32, ReadWrite>)>)> {
         let model = &self.model;
         let state = &self.state;
         let context = &model.context;
@@ -505,6 +979,12 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
         let redirect = seed.redirect();
         let num_header = redirect.headers.len();
 
+        let num_layer_chunk = context.tuning().num_layer_chunk.max(1);
+        let embeds = seed.iter().map(|batch| batch.embed).collect_vec();
+        let logits = !seed.embed_only();
+        let hidden: Option<TensorGpu<f32, ReadWrite>> = (seed.embed() && num_header > 0)
+            .then(|| context.tensor_init([info.num_emb, num_header, 1, 1]));
+
         let buffer = Runtime::<F>::new(context, info, num_token);
         let header = Header::<F>::new(context, info, num_header);
         let frame = Frame {
@@ -513,6 +993,33 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             header: header.clone(),
         };
 
+        if depth == 0 || depth > info.num_layer {
+            bail!("depth {depth} out of {} layers", info.num_layer);
+        }
+        for &tap in taps {
+            match tap {
+                Tap::PostAtt(layer) | Tap::PostFfn(layer) if layer >= depth => {
+                    bail!("tap at layer {layer} out of {depth} layers")
+                }
+                Tap::HeadNorm if depth < info.num_layer => {
+                    bail!("tap at the head, which is skipped")
+                }
+                _ => {}
+            }
+        }
+        let taps: Vec<(Tap, TensorGpu<f32, ReadWrite>)> = match num_header {
+            0 => vec![],
+            _ => taps
+                .iter()
+                .unique()
+                .map(|&tap| (tap, context.tensor_init([info.num_emb, num_header, 1, 1])))
+                .collect(),
+        };
+        let hooks = match taps.is_empty() {
+            true => self.hooks.clone(),
+            false => Arc::new(tap_hooks(&self.hooks, &taps, &redirect.headers, num_token)),
+        };
+
         context.maintain();
 
         if num_token == 0 {
@@ -520,16 +1027,24 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
                 Some(_) => EmbedDevice::Gpu,
                 None => EmbedDevice::Cpu,
             };
-            return Ok(InferJob {
+            let job = InferJob {
                 commands: vec![],
                 redirect,
+                has_exit: false,
+                exits: vec![],
+                exited: None,
+                embeds,
+                hidden: None,
                 embed_device,
                 embed: model.tensor.embed.w.clone(),
                 tokens: buffer.tokens,
                 cursors: buffer.cursors,
                 input: buffer.input,
                 output: header.head_o,
-            });
+                half: None,
+                slices: vec![],
+            };
+            return Ok((job, vec![]));
         }
 
         #[cfg(feature = "trace")]
@@ -538,28 +1053,48 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
         let (head_ops, head_x) = if num_token == 1 || num_token == num_header {
             (vec![], buffer.x.clone())
         } else {
-            let headers = &redirect.headers;
-            let mut start = 0;
-            let mut end = 1;
-            let mut ops = vec![];
-            while end <= headers.len() {
-                if end == headers.len() || headers[end - 1] + 1 != headers[end] {
-                    let first = headers[start];
-                    let last = headers[end - 1];
-                    assert_eq!(last - first + 1, end - start);
-
-                    let input = buffer.x.view(.., first..=last, .., ..)?;
-                    let output = header.head_x.view(.., start..end, .., ..)?;
-                    ops.push(TensorOp::blit(input, output)?);
-
-                    start = end;
-                }
-                end += 1;
-            }
+            let ops = build_head_ops(&redirect.headers, &buffer.x, &header.head_x)?;
             (ops, header.head_x.clone())
         };
 
-        let hook_op = |hook: Hook| hook_op(&self.hooks, &hook, &frame);
+        // only exit if no token is left without passing through all layers
+        let early_exit = self
+            .early_exit
+            .iter()
+            .filter(|exit| num_token == num_header && exit.layer + 1 < info.num_layer)
+            .filter(|_| hidden.is_none() && taps.is_empty() && depth == info.num_layer)
+            .copied()
+            .collect_vec();
+        let mut exits = vec![];
+        // the exit head decides on the logits in `f32`, so they are read back as they are
+        // biases are added in the head matmul along with the rest of the transform, if it fuses them
+        let slots = match &self.bias {
+            Some(bias) if logits && num_header > 0 && self.fuses_logits(&seed) => {
+                Some(bias.slots(num_header, &redirect)?)
+            }
+            _ => None,
+        };
+        let transform = match (&self.bias, &slots) {
+            (Some(bias), Some(slots)) => transform.bias(slots, &bias.data),
+            _ => transform,
+        };
+        let half: Option<TensorGpu<f16, ReadWrite>> =
+            (seed.half_logits() && num_header > 0 && early_exit.is_empty())
+                .then(|| context.tensor_init(header.head_o.shape()));
+        // the head is sliced only if its output is read back as it is
+        let head_chunk = self.head_chunk.filter(|_| {
+            depth == info.num_layer
+                && logits
+                && num_header > 0
+                && early_exit.is_empty()
+                && hidden.is_none()
+                && half.is_none()
+                && self.bias.is_none()
+                && transform.is_empty()
+                && !hooks.contains_key(&Hook::PostHead)
+        });
+
+        let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
         let mut ops = vec![];
 
         let embed_device = {
@@ -590,50 +1125,152 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             embed_device
         };
 
-        for (index, layer) in tensor.layers.iter().enumerate() {
+        // adapters attached at runtime are numbered after the runtime LoRAs of the model
+        let (lora, attached) = match &self.adapters {
+            Some(adapters) => adapters.merge(&self.lora),
+            None => (self.lora.clone(), vec![]),
+        };
+
+        for (index, layer) in tensor.layers.iter().enumerate().take(depth) {
             #[cfg(feature = "trace")]
             let _span = tracing::trace_span!("layer", index).entered();
 
-            let hooks = self.hooks.clone();
+            let hooks = hooks.clone();
             let frame = frame.clone();
-            let layer = layer.clone();
+            let lora = lora.clone();
+            let mut layer = layer.clone();
+            if let Some(factors) = attached.get(index) {
+                layer.lora.extend(factors.iter().cloned());
+            }
 
-            let op = build_layer(hooks, frame, layer, index, num_token)?;
+            let op = build_layer(hooks, frame, lora, layer, index, num_token)?;
             ops.push(op);
 
-            if (index + 1) % (info.num_layer / super::infer::NUM_LAYER_CHUNK) == 0 {
+            if (index + 1) % (info.num_layer / num_layer_chunk).max(1) == 0 {
                 ops.push(TensorOp::Sep);
             }
+
+            if let Some(&option) = early_exit.iter().find(|exit| exit.layer == index) {
+                // the head normalizes its input in place, so the hidden state is copied out first
+                let header = Header::<F>::new(context, info, num_header);
+                let head_ops = build_head_ops(&redirect.headers, &buffer.x, &header.head_x)?;
+                let frame = Frame {
+                    state: state.clone(),
+                    buffer: buffer.clone(),
+                    header: header.clone(),
+                };
+                let head = model.tensor.head.clone();
+                let head_x = header.head_x.clone();
+                ops.push(build_header(
+                    Default::default(),
+                    frame,
+                    head,
+                    head_x,
+                    num_header,
+                    true,
+                    Default::default(),
+                    head_ops,
+                )?);
+
+                if let Some(bias) = &self.bias {
+                    ops.push(bias.op(&header.head_o, &redirect)?);
+                }
+
+                // only the top-1 probabilities are read back to decide on exiting
+                let confidence = context.tensor_init([1, num_header, 1, 1]);
+                ops.push(TensorOp::max_prob(&header.head_o, &confidence)?);
+
+                let commands = context.encode(&TensorOp::List(std::mem::take(&mut ops)));
+                exits.push((option, commands, header.head_o, confidence));
+            }
         }
 
-        {
+        if depth == info.num_layer {
             #[cfg(feature = "trace")]
             let _span = tracing::trace_span!("header").entered();
 
-            let hooks = self.hooks.clone();
+            let hooks = hooks.clone();
             let frame = frame.clone();
             let head = model.tensor.head.clone();
 
-            let op = build_header(hooks, frame, head, head_x, num_header, head_ops)?;
+            let op = build_header(
+                hooks,
+                frame,
+                head,
+                head_x.clone(),
+                num_header,
+                logits && head_chunk.is_none(),
+                transform,
+                head_ops,
+            )?;
             ops.push(op);
+
+            if let Some(bias) = self.bias.as_ref().filter(|_| logits && slots.is_none()) {
+                ops.push(bias.op(&header.head_o, &redirect)?);
+            }
+
+            // the head normalizes its input in place
+            if let Some(hidden) = &hidden {
+                let op =
+                    TensorOp::blit(head_x.view(.., .., .., ..)?, hidden.view(.., .., .., ..)?)?;
+                ops.push(op);
+            }
+
+            if let Some(half) = &half {
+                let output = header.head_o.view(.., .., .., ..)?;
+                ops.push(TensorOp::blit(output, half.view(.., .., .., ..)?)?);
+            }
         }
 
-        let commands = {
+        let (commands, slices) = {
             #[cfg(feature = "trace")]
             let _span = tracing::trace_span!("encode").entered();
-            context.encode(&TensorOp::List(ops))
+            let mut commands = context.encode(&TensorOp::List(ops));
+            let slices = match head_chunk {
+                Some(chunk) => super::stream::encode_head(
+                    &model.tensor.head.w,
+                    &head_x,
+                    &header.head_o,
+                    chunk,
+                    &mut commands,
+                )?,
+                None => vec![],
+            };
+            (commands, slices)
         };
+        // the commands up to the first exit head are submitted first, and those after each head only if it is not confident
+        let has_exit = !exits.is_empty();
+        let mut commands = commands;
+        let mut exits = exits
+            .into_iter()
+            .rev()
+            .map(|(option, early, output, confidence)| ExitJob {
+                option,
+                commands: std::mem::replace(&mut commands, early),
+                output,
+                confidence,
+            })
+            .collect_vec();
+        exits.reverse();
 
-        Ok(InferJob {
+        let job = InferJob {
             commands,
             redirect,
+            has_exit,
+            exits,
+            exited: None,
+            embeds,
+            hidden,
             embed_device,
             embed: model.tensor.embed.w.clone(),
             tokens: buffer.tokens,
             cursors: buffer.cursors,
             input: buffer.input,
             output: header.head_o,
-        })
+            half,
+            slices,
+        };
+        Ok((job, taps))
     }
 }
 
@@ -641,6 +1278,7 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
 fn build_layer<F: Float>(
     hooks: Arc<HookMap<F>>,
     frame: Frame<F>,
+    lora: LoraAlpha,
     layer: Layer,
     index: usize,
     num_token: usize,
@@ -690,21 +1328,33 @@ fn build_layer<F: Float>(
         )?,
         hook_op(Hook::PostAttTokenShift(index))?,
         hook_op(Hook::PreAttLinear(index))?,
-        layer.att.w_k.matmul_op(
+        lora.matmul_op(
+            &layer.att.w_k,
+            &layer.lora,
+            LoraTarget::AttKey,
+            &buffer.cursors,
             buffer.att_kx.view(.., .., .., ..)?,
-            buffer.att_k.view(.., .., .., ..)?,
+            &buffer.att_k,
             Activation::None,
             turbo(num_token),
         )?,
-        layer.att.w_v.matmul_op(
+        lora.matmul_op(
+            &layer.att.w_v,
+            &layer.lora,
+            LoraTarget::AttValue,
+            &buffer.cursors,
             buffer.att_vx.view(.., .., .., ..)?,
-            buffer.att_v.view(.., .., .., ..)?,
+            &buffer.att_v,
             Activation::None,
             turbo(num_token),
         )?,
-        layer.att.w_r.matmul_op(
+        lora.matmul_op(
+            &layer.att.w_r,
+            &layer.lora,
+            LoraTarget::AttReceptance,
+            &buffer.cursors,
             buffer.att_rx.view(.., .., .., ..)?,
-            buffer.att_r.view(.., .., .., ..)?,
+            &buffer.att_r,
             Activation::None,
             turbo(num_token),
         )?,
@@ -730,9 +1380,13 @@ fn build_layer<F: Float>(
         )?,
         hook_op(Hook::PostAttTimeMix(index))?,
         hook_op(Hook::PreAttOut(index))?,
-        layer.att.w_o.matmul_op(
+        lora.matmul_op(
+            &layer.att.w_o,
+            &layer.lora,
+            LoraTarget::AttOutput,
+            &buffer.cursors,
             buffer.att_x.view(.., .., .., ..)?,
-            buffer.att_o.view(.., .., .., ..)?,
+            &buffer.att_o,
             Activation::None,
             turbo(num_token),
         )?,
@@ -776,22 +1430,34 @@ fn build_layer<F: Float>(
         )?,
         hook_op(Hook::PostFfnTokenShift(index))?,
         hook_op(Hook::PreFfnLinear(index))?,
-        layer.ffn.w_k.matmul_op(
+        lora.matmul_op(
+            &layer.ffn.w_k,
+            &layer.lora,
+            LoraTarget::FfnKey,
+            &buffer.cursors,
             buffer.ffn_kx.view(.., .., .., ..)?,
-            buffer.ffn_k.view(.., .., .., ..)?,
+            &buffer.ffn_k,
             Activation::SquaredRelu,
             turbo(num_token),
         )?,
         hook_op(Hook::PostFfnActivate(index))?,
-        layer.ffn.w_v.matmul_op(
+        lora.matmul_op(
+            &layer.ffn.w_v,
+            &layer.lora,
+            LoraTarget::FfnValue,
+            &buffer.cursors,
             buffer.ffn_k.view(.., .., .., ..)?,
-            buffer.ffn_v.view(.., .., .., ..)?,
+            &buffer.ffn_v,
             Activation::None,
             turbo(num_token),
         )?,
-        layer.ffn.w_r.matmul_op(
+        lora.matmul_op(
+            &layer.ffn.w_r,
+            &layer.lora,
+            LoraTarget::FfnReceptance,
+            &buffer.cursors,
             buffer.ffn_rx.view(.., .., .., ..)?,
-            buffer.ffn_r.view(.., .., .., ..)?,
+            &buffer.ffn_r,
             Activation::None,
             turbo(num_token),
         )?,
@@ -819,12 +1485,88 @@ fn build_layer<F: Float>(
     Ok(TensorOp::List(ops))
 }
 
+/// Copy the hidden states of the output tokens from `x` into `head_x`.
+fn build_head_ops(
+    headers: &[usize],
+    x: &TensorGpu<impl Float, ReadWrite>,
+    head_x: &TensorGpu<impl Float, ReadWrite>,
+) -> Result<Vec<TensorOp>, TensorError> {
+    let mut start = 0;
+    let mut end = 1;
+    let mut ops = vec![];
+    while end <= headers.len() {
+        if end == headers.len() || headers[end - 1] + 1 != headers[end] {
+            let first = headers[start];
+            let last = headers[end - 1];
+            assert_eq!(last - first + 1, end - start);
+
+            let input = x.view(.., first..=last, .., ..)?;
+            let output = head_x.view(.., start..end, .., ..)?;
+            ops.push(TensorOp::blit(input, output)?);
+
+            start = end;
+        }
+        end += 1;
+    }
+    Ok(ops)
+}
+
+/// Wrap `hooks` with ops that copy the hidden states of the output tokens into the tensor of each tap.
+fn tap_hooks<F: Float>(
+    hooks: &Arc<HookMap<F>>,
+    taps: &[(Tap, TensorGpu<f32, ReadWrite>)],
+    headers: &[usize],
+    num_token: usize,
+) -> HookMap<F> {
+    let mut map: HookMap<F> = hooks
+        .keys()
+        .map(|&hook| {
+            let hooks = hooks.clone();
+            let f: HookFn<F> = Box::new(move |frame| hook_op(&hooks, &hook, &frame));
+            (hook, f)
+        })
+        .collect();
+    for (tap, tensor) in taps.iter().cloned() {
+        // undo the halving of the residual stream every few layers
+        let (hook, factor) = match tap {
+            Tap::PostAtt(layer) => (Hook::PostAtt(layer), layer / Model::RESCALE_LAYER),
+            Tap::PostFfn(layer) => (Hook::PostFfn(layer), layer / Model::RESCALE_LAYER),
+            Tap::HeadNorm => (Hook::PostHeadLayerNorm, 0),
+        };
+        let factor = 2.0_f32.powi(factor as i32);
+        // the head runs on `x` in place if all tokens have outputs
+        let in_place = num_token == 1 || num_token == headers.len();
+
+        let hooks = hooks.clone();
+        let headers = headers.to_vec();
+        let f: HookFn<F> = Box::new(move |frame: Frame<F>| {
+            let mut ops = vec![hook_op(&hooks, &hook, &frame)?];
+            match tap {
+                Tap::HeadNorm if !in_place => ops.push(TensorOp::blit(
+                    frame.header.head_x.view(.., .., .., ..)?,
+                    tensor.view(.., .., .., ..)?,
+                )?),
+                _ => ops.append(&mut build_head_ops(&headers, &frame.buffer.x, &tensor)?),
+            }
+            if factor != 1.0 {
+                ops.push(TensorOp::discount(&tensor, factor, 0.0)?);
+            }
+            Ok(TensorOp::List(ops))
+        });
+        map.insert(hook, f);
+    }
+    map
+}
+
+#[allow(clippy::too_many_arguments)]
 fn build_header<F: Float>(
     hooks: Arc<HookMap<F>>,
     frame: Frame<F>,
     head: Head,
     head_x: TensorGpu<F, ReadWrite>,
     num_header: usize,
+    logits: bool,
+    transform: LogitTransform,
     mut ops: Vec<TensorOp>,
 ) -> Result<TensorOp> {
     let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
@@ -840,11 +1582,16 @@ fn build_header<F: Float>(
                 Model::LN_EPS,
             )?,
             hook_op(Hook::PostHeadLayerNorm)?,
-            head.w.matmul_op(
+        ]);
+    }
+    // the head matmul is the largest of all, and is skipped if only the hidden states are read back
+    if num_header > 0 && logits {
+        ops.append(&mut vec![
+            head.w.matmul_logits_op(
                 head_x.view(.., .., .., ..)?,
-                header.head_o.view(.., .., .., ..)?,
-                Activation::None,
+                &header.head_o,
                 turbo(num_header),
+                transform,
             )?,
             hook_op(Hook::PostHead)?,
         ]);
@@ -854,19 +1601,31 @@ fn build_header<F: Float>(
 
 impl<R: Reader> Build<Model> for ModelBuilder<R> {
     async fn build(self) -> Result<Model> {
+        self.validate()?;
         let ModelBuilder {
             context,
             model,
             lora,
+            runtime_lora,
             quant,
+            quant_head,
             embed_device,
+            num_vocab,
+            registry,
+            token_chunk_size: _,
         } = self;
 
         let info = Loader::info(&model)?;
+        let info = match num_vocab {
+            Some(num_vocab) => info.trim_vocab(num_vocab),
+            None => info,
+        };
         let loader = Loader {
-            context: context.clone(),
+            context: context.in_arena(Arena::Model),
             model,
             lora,
+            runtime_lora,
+            registry,
         };
 
         let embed = Embed {
@@ -874,10 +1633,14 @@ impl<R: Reader> Build<Model> for ModelBuilder<R> {
                 w: loader.load_vector_f16("blocks.0.ln0.weight").await?,
                 b: loader.load_vector_f16("blocks.0.ln0.bias").await?,
             },
-            w: loader.load_embed().await?,
+            w: loader.load_embed_trimmed(info.num_vocab).await?,
             u: match embed_device {
                 EmbedDevice::Cpu => None,
-                EmbedDevice::Gpu => Some(loader.load_matrix_f16("emb.weight").await?),
+                EmbedDevice::Gpu => Some(
+                    loader
+                        .load_matrix_f16_trimmed("emb.weight", info.num_vocab)
+                        .await?,
+                ),
             },
         };
 
@@ -886,11 +1649,19 @@ impl<R: Reader> Build<Model> for ModelBuilder<R> {
                 w: loader.load_vector_f16("ln_out.weight").await?,
                 b: loader.load_vector_f16("ln_out.bias").await?,
             },
-            w: Matrix::Fp16(loader.load_matrix_f16("head.weight").await?),
+            w: {
+                let w = loader
+                    .load_matrix_f16_trimmed("head.weight", info.num_vocab)
+                    .await?;
+                match quant_head {
+                    true => Matrix::quant_int8_row(&w)?,
+                    false => Matrix::Fp16(w),
+                }
+            },
         };
 
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
+        context.submit(None);
+        context.wait();
 
         let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
         let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
@@ -953,19 +1724,27 @@ impl<R: Reader> Build<Model> for ModelBuilder<R> {
                 w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount).await?,
             };
 
-            context.queue.submit(None);
-            context.device.poll(wgpu::MaintainBase::Wait);
+            let mut lora = vec![];
+            for (name, discounted) in LORA_MATRICES {
+                let name = format!("blocks.{layer}.{name}.weight");
+                let discount = if discounted { discount } else { 1.0 };
+                lora.append(&mut loader.load_lora_factors(name, discount).await?);
+            }
+
+            context.submit(None);
+            context.wait();
 
             layers.push(Layer {
                 att_layer_norm,
                 ffn_layer_norm,
                 att,
                 ffn,
+                lora,
             })
         }
 
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
+        context.submit(None);
+        context.wait();
 
         let tensor = ModelTensor {
             embed,
@@ -981,6 +1760,7 @@ impl<R: Reader> Build<Model> for ModelBuilder<R> {
                 tensor,
             }
         };
+        context.check_budget(Arena::Model)?;
         Ok(model)
     }
 }
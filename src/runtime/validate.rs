@@ -0,0 +1,347 @@
+use std::fmt;
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use super::{
+    loader::{Loader, Reader},
+    model::{ModelBuilder, ModelInfo, ModelVersion, Quant, StateBuilder},
+};
+use crate::{num::Scalar, tensor::ops::TensorOp};
+
+/// A problem in the configuration of a [`ModelBuilder`] or a [`StateBuilder`], found before anything is loaded.
+/// See [`ConfigIssue::suggestion`] for how to fix each.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConfigIssue {
+    #[error("failed to read the model info: {0}")]
+    Info(String),
+    #[error("quantization is set for layer {layer}, but the model has {num_layer} layers")]
+    QuantLayer { layer: usize, num_layer: usize },
+    #[error("{quant:?} of layer {layer} needs rows of multiples of {block_size}, but the matrices have rows of {num_emb} and {num_hidden}")]
+    QuantBlock {
+        layer: usize,
+        quant: Quant,
+        block_size: usize,
+        num_emb: usize,
+        num_hidden: usize,
+    },
+    #[error(
+        "LoRA {lora} sets the placement of layer {layer}, but the model has {num_layer} layers"
+    )]
+    LoraLayer {
+        lora: usize,
+        layer: usize,
+        num_layer: usize,
+    },
+    #[error("the vocabulary is trimmed to 0 tokens")]
+    TrimVocab,
+    #[error("{name} takes {size} bytes, beyond the device's limit of {limit} bytes per buffer")]
+    BufferSize {
+        name: &'static str,
+        size: usize,
+        limit: usize,
+    },
+    #[error("chunks of {token_chunk_size} tokens exceed the device's limits, which allow up to {max} tokens")]
+    TokenChunkSize { token_chunk_size: usize, max: usize },
+    #[error("a state of {num_batch} batches exceeds the device's limits, which allow up to {max} batches")]
+    NumBatch { num_batch: usize, max: usize },
+}
+
+impl ConfigIssue {
+    /// A suggested change of the configuration that fixes the issue.
+    pub fn suggestion(&self) -> String {
+        match self {
+            ConfigIssue::Info(_) => "check that the file is a RWKV model of a supported version".into(),
+            ConfigIssue::QuantLayer { num_layer, .. } => {
+                format!("only set the quantization of layers in 0..{num_layer}")
+            }
+            ConfigIssue::QuantBlock { layer, .. } => {
+                format!("use another quantization for layer {layer}, e.g., `Quant::Int8`, or leave it unquantized")
+            }
+            ConfigIssue::LoraLayer { num_layer, .. } => {
+                format!("only set the placement of layers in 0..{num_layer}")
+            }
+            ConfigIssue::TrimVocab => "trim to the size of the tokenizer's vocabulary, or do not trim".into(),
+            ConfigIssue::BufferSize { size, .. } => format!(
+                "raise `max_buffer_size` and `max_storage_buffer_binding_size` to at least {size}, e.g., with `ContextBuilder::auto_limits`"
+            ),
+            ConfigIssue::TokenChunkSize { max, .. } => {
+                format!("use a token chunk size of at most {max}")
+            }
+            ConfigIssue::NumBatch { max, .. } => format!(
+                "use at most {max} batches, or raise `max_storage_buffer_binding_size` of the context"
+            ),
+        }
+    }
+}
+
+/// All issues found in a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(pub Vec<ConfigIssue>);
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration")?;
+        for issue in &self.0 {
+            write!(f, "\n- {issue}; {}", issue.suggestion())?;
+        }
+        Ok(())
+    }
+}
+
+impl ConfigError {
+    fn check(issues: Vec<ConfigIssue>) -> Result<(), Self> {
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(Self(issues)),
+        }
+    }
+}
+
+impl Quant {
+    /// The number of elements of each row of a matrix that are quantized together.
+    pub fn block_size(&self) -> usize {
+        let block_size = match self {
+            Quant::None => 1,
+            Quant::Int8 => TensorOp::INT8_BLOCK_SIZE,
+            Quant::NF4 => TensorOp::NF4_BLOCK_SIZE,
+            Quant::Fp8E4M3 | Quant::Fp8E5M2 => TensorOp::FP8_BLOCK_SIZE,
+            Quant::Q4K | Quant::Q5K => TensorOp::KQUANT_SUPER_BLOCK_SIZE,
+        };
+        block_size as usize
+    }
+}
+
+impl ModelInfo {
+    /// The most tokens that can be run in one go within the limits of the device.
+    pub fn max_token_chunk_size(&self, limits: &wgpu::Limits) -> usize {
+        let limit = limits.max_storage_buffer_binding_size as usize;
+        let num_token = limit / (self.num_hidden.max(self.num_emb) * f32::size());
+        num_token.min(limits.max_compute_workgroups_per_dimension as usize)
+    }
+
+    /// The most batches that a state can hold within the limits of the device.
+    pub fn max_state_batch(&self, limits: &wgpu::Limits) -> usize {
+        let num_row = match self.version {
+            ModelVersion::V4 => 5,
+            ModelVersion::V5 | ModelVersion::V6 => self.num_emb / self.num_head + 2,
+        };
+        let limit = limits.max_storage_buffer_binding_size as usize;
+        let num_batch = limit / (self.num_emb * num_row * f32::size());
+        num_batch.min(limits.max_compute_workgroups_per_dimension as usize)
+    }
+}
+
+impl<R: Reader> ModelBuilder<R> {
+    /// Check the configuration against the model and the device without loading anything,
+    /// and list all issues found, each with a suggested fix. [`Build`](super::model::Build) does this first.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let info = match Loader::info(&self.model) {
+            Ok(info) => info,
+            Err(err) => return Err(ConfigError(vec![ConfigIssue::Info(err.to_string())])),
+        };
+        let num_layer = info.num_layer;
+        let mut issues = vec![];
+
+        for (&layer, &quant) in self.quant.iter().sorted_by_key(|(&layer, _)| layer) {
+            if layer >= num_layer {
+                issues.push(ConfigIssue::QuantLayer { layer, num_layer });
+                continue;
+            }
+            let block_size = quant.block_size();
+            if info.num_emb % block_size != 0 || info.num_hidden % block_size != 0 {
+                issues.push(ConfigIssue::QuantBlock {
+                    layer,
+                    quant,
+                    block_size,
+                    num_emb: info.num_emb,
+                    num_hidden: info.num_hidden,
+                });
+            }
+        }
+
+        for (lora, value) in self.lora.iter().enumerate() {
+            let layers = value.placement.layers.keys().copied().sorted();
+            for layer in layers.filter(|&layer| layer >= num_layer) {
+                issues.push(ConfigIssue::LoraLayer {
+                    lora,
+                    layer,
+                    num_layer,
+                });
+            }
+        }
+
+        let info = match self.num_vocab {
+            Some(0) => {
+                issues.push(ConfigIssue::TrimVocab);
+                info
+            }
+            Some(num_vocab) => info.trim_vocab(num_vocab),
+            None => info,
+        };
+
+        let limits = self.context.device.limits();
+        let limit =
+            (limits.max_buffer_size as usize).min(limits.max_storage_buffer_binding_size as usize);
+        let buffers = [
+            ("the head", info.head_buffer_size()),
+            ("the largest matrix", info.max_non_head_buffer_size()),
+        ];
+        for (name, size) in buffers {
+            if size > limit {
+                issues.push(ConfigIssue::BufferSize { name, size, limit });
+            }
+        }
+
+        if let Some(token_chunk_size) = self.token_chunk_size {
+            let max = info.max_token_chunk_size(&limits);
+            if token_chunk_size == 0 || token_chunk_size > max {
+                issues.push(ConfigIssue::TokenChunkSize {
+                    token_chunk_size,
+                    max,
+                });
+            }
+        }
+
+        ConfigError::check(issues)
+    }
+}
+
+impl StateBuilder {
+    /// Check the configuration against the device without allocating anything,
+    /// and list all issues found, each with a suggested fix. [`Build`](super::model::Build) does this first.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut issues = vec![];
+
+        let limits = self.context.device.limits();
+        let max = self.info.max_state_batch(&limits);
+        if self.num_batch > max {
+            issues.push(ConfigIssue::NumBatch {
+                num_batch: self.num_batch,
+                max,
+            });
+        }
+
+        ConfigError::check(issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use safetensors::SafeTensors;
+    use wgpu::PowerPreference;
+
+    use super::{ConfigError, ConfigIssue};
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            loader::Lora,
+            lora::{LoraMode, LoraPlacement},
+            model::{ModelBuilder, ModelInfo, ModelVersion, Quant, StateBuilder},
+            tiny::TinyModel,
+        },
+    };
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let context = match pollster::block_on(async {
+            let instance = wgpu::Instance::default();
+            let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+            anyhow::Ok(ContextBuilder::new(adapter).build().await?)
+        }) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let data = TinyModel::new(info.clone(), 42).serialize()?;
+        let model = SafeTensors::deserialize(&data)?;
+        let builder = ModelBuilder::new(&context, model);
+        builder.validate()?;
+
+        let num_layer = info.num_layer;
+        let quant = HashMap::from([(0, Quant::Int8), (num_layer, Quant::NF4)]);
+        let ModelInfo {
+            num_emb,
+            num_hidden,
+            ..
+        } = info;
+        let block_size = Quant::Q4K.block_size();
+        let quant_block = (num_emb % block_size != 0 || num_hidden % block_size != 0).then_some(
+            ConfigIssue::QuantBlock {
+                layer: 1,
+                quant: Quant::Q4K,
+                block_size,
+                num_emb,
+                num_hidden,
+            },
+        );
+        let quant = quant.into_iter().chain([(1, Quant::Q4K)]).collect();
+        let max = info.max_token_chunk_size(&context.device.limits());
+
+        let builder = builder.quant(quant).trim_vocab(0).token_chunk_size(max + 1);
+        let ConfigError(issues) = builder.validate().expect_err("invalid configuration");
+        let expected: Vec<_> = quant_block
+            .into_iter()
+            .chain([
+                ConfigIssue::QuantLayer {
+                    layer: num_layer,
+                    num_layer,
+                },
+                ConfigIssue::TrimVocab,
+                ConfigIssue::TokenChunkSize {
+                    token_chunk_size: max + 1,
+                    max,
+                },
+            ])
+            .collect();
+        assert_eq!(issues.len(), expected.len(), "{issues:?}");
+        for issue in &expected {
+            assert!(issues.contains(issue), "{issue:?} not in {issues:?}");
+        }
+
+        let model = SafeTensors::deserialize(&data)?;
+        let placement = LoraPlacement::new(LoraMode::Merge).layer(num_layer + 1, LoraMode::Runtime);
+        // the data of LoRAs is not read in validation
+        let lora = Lora {
+            data: SafeTensors::deserialize(&data)?,
+            blend: Default::default(),
+            placement,
+        };
+        let err = ModelBuilder::new(&context, model)
+            .lora(lora)
+            .validate()
+            .expect_err("invalid placement");
+        assert_eq!(
+            err.0,
+            vec![ConfigIssue::LoraLayer {
+                lora: 0,
+                layer: num_layer + 1,
+                num_layer
+            }]
+        );
+        assert!(err.to_string().contains("only set the placement of layers"));
+
+        let max = info.max_state_batch(&context.device.limits());
+        StateBuilder::new(&context, &info)
+            .num_batch(max)
+            .validate()?;
+        let ConfigError(issues) = StateBuilder::new(&context, &info)
+            .num_batch(max + 1)
+            .validate()
+            .expect_err("too many batches");
+        assert_eq!(
+            issues,
+            vec![ConfigIssue::NumBatch {
+                num_batch: max + 1,
+                max
+            }]
+        );
+        Ok(())
+    }
+}
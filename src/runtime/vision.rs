@@ -0,0 +1,320 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use half::f16;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    loader::{Loader, Reader},
+    model::ModelInfo,
+};
+use crate::{
+    context::Context,
+    num::Float,
+    tensor::{
+        kind::ReadWrite,
+        ops::{Activation, TensorOp},
+        TensorCpu, TensorError, TensorGpu, TensorInit, TensorInto, TensorShape,
+    },
+};
+
+/// How image features are normalized before the projection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VisionNorm {
+    /// Use the features as they are, e.g., the last hidden states of the vision tower as in VisualRWKV.
+    #[default]
+    None,
+    /// Scale each feature to unit length, e.g., for pooled CLIP embeddings.
+    L2,
+}
+
+/// Projects CLIP/SigLIP image features into pseudo-token embeddings of a model.
+///
+/// The checkpoint holds the projection weight `proj.weight` of shape `[num_emb, vision_dim]` (as in `torch`),
+/// i.e., the linear layer without bias of VisualRWKV.
+#[derive(Debug, Clone)]
+pub struct VisionProjector {
+    context: Context,
+    matrix: TensorGpu<f16, ReadWrite>,
+    norm: VisionNorm,
+}
+
+impl VisionProjector {
+    pub const WEIGHT: &'static str = "proj.weight";
+
+    pub async fn load<R: Reader>(context: &Context, reader: R, norm: VisionNorm) -> Result<Self> {
+        let loader = Loader {
+            context: context.clone(),
+            model: reader,
+            lora: vec![],
+            runtime_lora: vec![],
+            registry: None,
+        };
+        let matrix = loader.load_matrix_f16(Self::WEIGHT).await?;
+        Ok(Self {
+            context: context.clone(),
+            matrix,
+            norm,
+        })
+    }
+
+    /// Size of the image features.
+    pub fn vision_dim(&self) -> usize {
+        self.matrix.shape()[0]
+    }
+
+    /// Size of the model's embeddings.
+    pub fn num_emb(&self) -> usize {
+        self.matrix.shape()[1]
+    }
+
+    /// Project the features of `N` patches of shape `[vision_dim, N]` into embeddings of shape `[num_emb, N]`.
+    pub fn project(&self, features: TensorCpu<f32>) -> Result<TensorGpu<f16, ReadWrite>> {
+        let shape = features.shape();
+        let num_patch = shape[1];
+        features.check_shape([self.vision_dim(), num_patch, 1, 1])?;
+
+        let features = match self.norm {
+            VisionNorm::None => features,
+            VisionNorm::L2 => {
+                let data = features
+                    .data()
+                    .chunks_exact(shape[0])
+                    .flat_map(|x| {
+                        let norm = x
+                            .iter()
+                            .map(|x| x * x)
+                            .sum::<f32>()
+                            .sqrt()
+                            .max(f32::EPSILON);
+                        x.iter().map(move |x| x / norm)
+                    })
+                    .collect::<Vec<_>>();
+                TensorCpu::from_data(shape, data)?
+            }
+        };
+
+        let context = &self.context;
+        let input: TensorGpu<f32, ReadWrite> = features.transfer_into(context);
+        let output: TensorGpu<f16, ReadWrite> =
+            context.tensor_init([self.num_emb(), num_patch, 1, 1]);
+        let op = TensorOp::matmul_vec_fp16(
+            &self.matrix,
+            input.view(.., .., .., ..)?,
+            output.view(.., .., .., ..)?,
+            Activation::None,
+        )?;
+        context.submit(context.encode(&op));
+        Ok(output)
+    }
+}
+
+/// Image embeddings for each batch of a runtime, injected into its input by a hook (see [`ImageSlots::op`]).
+///
+/// An image of `n` patches is fed as the placeholder tokens `0..n` (see [`ImageSlots::load`]):
+/// while a batch's slot is loaded, each of its tokens with id `i < n` takes the embedding of patch `i`.
+/// The hook must run right after the embeddings are loaded (e.g., `Hook::PostEmbedLoaded`),
+/// so that the embeddings go through `ln0` like those of real tokens, as in VisualRWKV.
+///
+/// Since text tokens of small ids would be replaced as well, the placeholders of an image should be fed on their own,
+/// and the slot [cleared](ImageSlots::clear) once they are consumed.
+#[derive(Debug, Clone)]
+pub struct ImageSlots {
+    embeds: TensorGpu<f16, ReadWrite>,
+    active: TensorGpu<u32, ReadWrite>,
+    mask: Arc<Mutex<Vec<u32>>>,
+}
+
+impl ImageSlots {
+    /// Create slots for images of at most `num_patch` patches, for each of `num_batch` batches.
+    pub fn new(context: &Context, info: &ModelInfo, num_patch: usize, num_batch: usize) -> Self {
+        Self {
+            embeds: context.tensor_init([info.num_emb, num_patch, num_batch, 1]),
+            active: context.zeros([num_batch, 1, 1, 1]),
+            mask: Arc::new(Mutex::new(vec![0; num_batch])),
+        }
+    }
+
+    pub fn num_patch(&self) -> usize {
+        self.embeds.shape()[1]
+    }
+
+    pub fn num_batch(&self) -> usize {
+        self.embeds.shape()[2]
+    }
+
+    /// Load the embeddings `[num_emb, n]` of an image into the slot of a batch, and return the placeholder tokens to feed.
+    pub fn load(&self, batch: usize, embeds: &TensorGpu<f16, ReadWrite>) -> Result<Vec<u16>> {
+        let num_patch = embeds.shape()[1];
+        if num_patch > self.num_patch() {
+            bail!(
+                "image of {num_patch} patches exceeds the limit of {}",
+                self.num_patch()
+            );
+        }
+        self.check_batch(batch)?;
+
+        let context = embeds.context();
+        let op = TensorOp::blit(
+            embeds.view(.., .., .., ..)?,
+            self.embeds.view(.., 0..num_patch, batch, ..)?,
+        )?;
+        context.submit(context.encode(&op));
+
+        self.mark(batch, 1)?;
+        Ok((0..num_patch as u16).collect())
+    }
+
+    /// Stop replacing the tokens of a batch.
+    pub fn clear(&self, batch: usize) -> Result<()> {
+        self.check_batch(batch)?;
+        self.mark(batch, 0)
+    }
+
+    /// The op to return from the hook.
+    /// - `cursors`, `tokens` and `input` are those of the runtime's buffer.
+    pub fn op(
+        &self,
+        cursors: &TensorGpu<u32, ReadWrite>,
+        tokens: &TensorGpu<u32, ReadWrite>,
+        input: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<TensorOp, TensorError> {
+        TensorOp::inject(cursors, tokens, &self.active, &self.embeds, input)
+    }
+
+    fn check_batch(&self, batch: usize) -> Result<(), TensorError> {
+        match batch < self.num_batch() {
+            true => Ok(()),
+            false => Err(TensorError::BatchOutOfRange {
+                batch,
+                max: self.num_batch(),
+            }),
+        }
+    }
+
+    fn mark(&self, batch: usize, value: u32) -> Result<()> {
+        let Ok(mut mask) = self.mask.lock() else {
+            bail!("image slots poisoned");
+        };
+        mask[batch] = value;
+        let mask = TensorCpu::from_data(self.active.shape(), mask.clone())?;
+        self.active.load(&mask)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use half::f16;
+    use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+    use wgpu::{Instance, PowerPreference};
+
+    use super::{ImageSlots, VisionNorm, VisionProjector};
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion},
+            tiny::TinyModel,
+            v6, JobRuntime,
+        },
+        tensor::{TensorCpu, TensorInit},
+    };
+
+    async fn infer(runtime: JobRuntime<InferInput, InferOutput>, tokens: Vec<u16>) -> Vec<f32> {
+        let batch = InferInputBatch {
+            tokens: tokens.into(),
+            option: InferOption::Full,
+            ..Default::default()
+        };
+        let (_, InferOutput(output)) = runtime.infer(InferInput::new(vec![batch], 32)).await;
+        output[0].0.to_vec()
+    }
+
+    #[test]
+    fn test_vision_inject() -> Result<()> {
+        let info = TinyModel::info(ModelVersion::V6);
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            // an identity projection, and the embeddings of some tokens as the image features
+            let num_emb = info.num_emb;
+            let identity = (0..num_emb * num_emb)
+                .map(|index| match index / num_emb == index % num_emb {
+                    true => f16::ONE,
+                    false => f16::ZERO,
+                })
+                .collect::<Vec<_>>();
+            let view = TensorView::new(
+                Dtype::F16,
+                vec![num_emb, num_emb],
+                bytemuck::cast_slice(&identity),
+            )?;
+            let data = safetensors::serialize([(VisionProjector::WEIGHT, view)], &None)?;
+            let reader = SafeTensors::deserialize(&data)?;
+            let projector = VisionProjector::load(&context, reader, VisionNorm::None).await?;
+
+            let tokens = [5u16, 9, 2];
+            let model = TinyModel::new(info.clone(), 42);
+            let (_, embed) = model.data("emb.weight").unwrap();
+            let features = tokens
+                .iter()
+                .flat_map(|&token| &embed[token as usize * num_emb..][..num_emb])
+                .map(|x| x.to_f32())
+                .collect::<Vec<_>>();
+            let features = TensorCpu::from_data([num_emb, tokens.len(), 1, 1], features)?;
+            let embeds = projector.project(features)?;
+
+            let slots = ImageSlots::new(&context, &info, 4, 1);
+            let placeholders = slots.load(0, &embeds)?;
+            assert_eq!(placeholders, vec![0, 1, 2]);
+
+            let hooks = || {
+                let slots = slots.clone();
+                let mut hooks: v6::HookMap<f32> = HashMap::new();
+                hooks.insert(
+                    v6::Hook::PostEmbedLoaded,
+                    Box::new(move |frame: v6::Frame<f32>| {
+                        let buffer = &frame.buffer;
+                        slots.op(&buffer.cursors, &buffer.tokens, &buffer.input)
+                    }),
+                );
+                hooks
+            };
+            let build = || Build::<v6::Model>::build(ModelBuilder::new(&context, model.clone()));
+            let runtime = v6::ModelRuntime::<f32>::new_with_hooks(build().await?, 1, hooks());
+            let image = infer(JobRuntime::new(runtime).await, placeholders.clone()).await;
+            let runtime = v6::ModelRuntime::<f32>::new(build().await?, 1);
+            let text = infer(JobRuntime::new(runtime).await, tokens.to_vec()).await;
+
+            assert_eq!(image.len(), text.len());
+            for (a, b) in image.iter().zip(text.iter()) {
+                assert!((a - b).abs() < 1.0e-3 * a.abs().max(1.0), "{a} vs {b}");
+            }
+
+            // once cleared, the placeholders are plain tokens again
+            slots.clear(0)?;
+            let runtime = v6::ModelRuntime::<f32>::new_with_hooks(build().await?, 1, hooks());
+            let cleared = infer(JobRuntime::new(runtime).await, placeholders.clone()).await;
+            let runtime = v6::ModelRuntime::<f32>::new(build().await?, 1);
+            let plain = infer(JobRuntime::new(runtime).await, placeholders).await;
+            assert_eq!(cleared, plain);
+            assert_ne!(cleared, image);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,335 @@
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::broadcast::error::{RecvError, TryRecvError},
+};
+
+use super::{
+    event::Event,
+    infer::{InferInput, InferKind, InferOutput, InferRequest, InferResponse, StopReason},
+    JobRuntime, SessionId,
+};
+use crate::tensor::{shape::Shape, TensorShape};
+
+/// Frames longer than this are rejected, so that a corrupt length prefix does not allocate without bound.
+pub const MAX_FRAME_SIZE: usize = 256 << 20;
+
+/// One batch of a [`WorkerRequest::Serve`], see [`InferRequest`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkerJob {
+    /// Prompt tokens. This must not be empty.
+    pub tokens: Vec<u16>,
+    pub kind: InferKind,
+    pub session: Option<SessionId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WorkerRequest {
+    /// Run jobs to completion, one for each batch of the runtime's state, as in [`JobRuntime::serve`].
+    Serve { id: u64, jobs: Vec<WorkerJob> },
+    /// Stop reading requests and exit.
+    Shutdown,
+}
+
+/// Output of one job, matching its [`InferKind`]. Tensors are sent flat, along with their shapes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WorkerOutput {
+    Logits {
+        shape: Shape,
+        data: Vec<f32>,
+    },
+    Embed {
+        shape: Shape,
+        data: Vec<f32>,
+    },
+    Token {
+        tokens: Vec<u16>,
+        reason: StopReason,
+    },
+}
+
+impl From<InferResponse> for WorkerOutput {
+    fn from(value: InferResponse) -> Self {
+        match value {
+            InferResponse::Logits(x) => Self::Logits {
+                shape: x.shape(),
+                data: x.to_vec(),
+            },
+            InferResponse::Embed(x) => Self::Embed {
+                shape: x.shape(),
+                data: x.to_vec(),
+            },
+            InferResponse::Token { tokens, reason } => Self::Token { tokens, reason },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WorkerResponse {
+    /// A token is generated for a batch of request `id`. These are streamed before the request is done.
+    Token {
+        id: u64,
+        session: Option<SessionId>,
+        batch: usize,
+        token: u16,
+    },
+    /// Request `id` is done, with one output for each job.
+    Done { id: u64, outputs: Vec<WorkerOutput> },
+    /// Request `id` failed. The worker goes on with the next request.
+    Error { id: u64, message: String },
+}
+
+/// Read a frame: the length of the payload as a little-endian `u32`, followed by the bincode payload.
+/// Returns `None` if the reader is closed before the frame starts.
+pub async fn read_frame<T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        bail!("frame of {len} bytes exceeds the limit of {MAX_FRAME_SIZE}");
+    }
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(bincode::deserialize(&data)?))
+}
+
+/// Write a frame, see [`read_frame`].
+pub async fn write_frame<T: Serialize>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    value: &T,
+) -> Result<()> {
+    let data = bincode::serialize(value)?;
+    if data.len() > MAX_FRAME_SIZE {
+        bail!(
+            "frame of {} bytes exceeds the limit of {MAX_FRAME_SIZE}",
+            data.len()
+        );
+    }
+    writer.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Serves a runtime to another process, so that servers not written in Rust can run inference in an isolated subprocess.
+///
+/// The worker reads [`WorkerRequest`] frames (see [`read_frame`]) and writes [`WorkerResponse`] frames.
+/// Requests are served one at a time and in order. For each request, generated tokens are streamed first,
+/// and then exactly one [`WorkerResponse::Done`] or [`WorkerResponse::Error`] with the same `id` follows.
+///
+/// There is backpressure both ways: no request is read while one is being served, and inference pauses
+/// while a response cannot be written, so a client that falls behind blocks the worker instead of growing its buffers.
+#[derive(Debug, Clone)]
+pub struct Worker {
+    runtime: JobRuntime<InferInput, InferOutput>,
+    token_chunk_size: usize,
+}
+
+impl Worker {
+    pub fn new(runtime: JobRuntime<InferInput, InferOutput>, token_chunk_size: usize) -> Self {
+        Self {
+            runtime,
+            token_chunk_size,
+        }
+    }
+
+    /// Serve requests until `reader` is closed or a [`WorkerRequest::Shutdown`] arrives.
+    /// Returns an error only if the frames cannot be read or written.
+    pub async fn run(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        let mut events = self.runtime.subscribe();
+        while let Some(request) = read_frame(&mut reader).await? {
+            let (id, jobs) = match request {
+                WorkerRequest::Serve { id, jobs } => (id, jobs),
+                WorkerRequest::Shutdown => break,
+            };
+            let requests = jobs
+                .into_iter()
+                .map(|job| InferRequest {
+                    tokens: job.tokens.into(),
+                    kind: job.kind,
+                    session: job.session,
+                })
+                .collect();
+
+            // skip events of earlier work
+            events = events.resubscribe();
+            let serve = self.runtime.serve(requests, self.token_chunk_size);
+            tokio::pin!(serve);
+            let result = loop {
+                tokio::select! {
+                    result = &mut serve => break result,
+                    event = events.recv() => match event {
+                        Ok(event) => forward(id, event, &mut writer).await?,
+                        Err(RecvError::Lagged(count)) => log::warn!("{count} tokens not streamed"),
+                        // the sender lives in the runtime held by the worker
+                        Err(RecvError::Closed) => unreachable!(),
+                    },
+                }
+            };
+            loop {
+                match events.try_recv() {
+                    Ok(event) => forward(id, event, &mut writer).await?,
+                    Err(TryRecvError::Lagged(count)) => log::warn!("{count} tokens not streamed"),
+                    Err(_) => break,
+                }
+            }
+
+            let response = match result {
+                Ok(responses) => WorkerResponse::Done {
+                    id,
+                    outputs: responses.into_iter().map(Into::into).collect(),
+                },
+                Err(err) => WorkerResponse::Error {
+                    id,
+                    message: err.to_string(),
+                },
+            };
+            write_frame(&mut writer, &response).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn forward(id: u64, event: Event, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+    if let Event::TokenGenerated {
+        session,
+        batch,
+        token,
+    } = event
+    {
+        let response = WorkerResponse::Token {
+            id,
+            session,
+            batch,
+            token,
+        };
+        write_frame(writer, &response).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::{
+        read_frame, write_frame, Worker, WorkerJob, WorkerOutput, WorkerRequest, WorkerResponse,
+    };
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            infer::{InferKind, InferOption, SampleOption, StopOption},
+            model::{Build, ContextAutoLimits, ModelBuilder, ModelVersion},
+            tiny::TinyModel,
+            v5, JobRuntime,
+        },
+    };
+
+    #[test]
+    fn test_worker() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            // a corrupt length is rejected before allocating
+            let data = [u32::MAX.to_le_bytes().as_slice(), &[0; 8]].concat();
+            assert!(read_frame::<WorkerRequest>(&mut data.as_slice())
+                .await
+                .is_err());
+
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = Instance::default();
+            let Ok(adapter) = instance.adapter(PowerPreference::HighPerformance).await else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+            let builder = ModelBuilder::new(&context, TinyModel::new(info.clone(), 42));
+            let model = Build::<v5::Model>::build(builder).await?;
+            let runtime = JobRuntime::new(v5::ModelRuntime::<f32>::new(model, 2)).await;
+            let worker = Worker::new(runtime, 32);
+
+            let (client, server) = tokio::io::duplex(64);
+            let (server_reader, server_writer) = tokio::io::split(server);
+            let (mut reader, mut writer) = tokio::io::split(client);
+            let handle =
+                tokio::spawn(async move { worker.run(server_reader, server_writer).await });
+
+            let jobs = vec![
+                WorkerJob {
+                    tokens: vec![1, 2, 3],
+                    kind: InferKind::Token {
+                        sample: SampleOption {
+                            temperature: 0.0,
+                            ..Default::default()
+                        },
+                        stop: StopOption {
+                            max_tokens: 4,
+                            tokens: vec![],
+                        },
+                        phrases: vec![],
+                    },
+                    session: Some(7),
+                },
+                WorkerJob {
+                    tokens: vec![4, 5],
+                    kind: InferKind::Logits(InferOption::Full),
+                    session: None,
+                },
+            ];
+            write_frame(&mut writer, &WorkerRequest::Serve { id: 1, jobs }).await?;
+            let empty = vec![WorkerJob {
+                tokens: vec![],
+                kind: Default::default(),
+                session: None,
+            }];
+            let request = WorkerRequest::Serve { id: 2, jobs: empty };
+            write_frame(&mut writer, &request).await?;
+            write_frame(&mut writer, &WorkerRequest::Shutdown).await?;
+
+            let mut streamed = vec![];
+            let outputs = loop {
+                match read_frame(&mut reader).await?.unwrap() {
+                    WorkerResponse::Token {
+                        id: 1,
+                        session: Some(7),
+                        batch: 0,
+                        token,
+                    } => streamed.push(token),
+                    WorkerResponse::Done { id: 1, outputs } => break outputs,
+                    response => panic!("unexpected response {response:?}"),
+                }
+            };
+            let [WorkerOutput::Token { tokens, .. }, WorkerOutput::Logits { shape, data }] =
+                &outputs[..]
+            else {
+                panic!("unexpected outputs {outputs:?}")
+            };
+            assert_eq!(&streamed, tokens);
+            assert_eq!(tokens.len(), 4);
+            assert_eq!(shape[1], 2);
+            assert_eq!(data.len(), shape.len());
+
+            let response: WorkerResponse = read_frame(&mut reader).await?.unwrap();
+            assert!(matches!(response, WorkerResponse::Error { id: 2, .. }));
+            handle.await??;
+            assert!(read_frame::<WorkerResponse>(&mut reader).await?.is_none());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,76 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+
+#ifdef IN_FP16
+@group(0) @binding(1) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(1) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+@group(0) @binding(2) var<storage, read_write> output: array<u32>;          // (B, T)
+#ifdef GATHER
+@group(0) @binding(3) var<storage, read> batches: array<u32>;               // (N)
+#endif
+
+var<workgroup> sketch: array<f32, BLOCK_SIZE>;
+var<workgroup> indices: array<u32, BLOCK_SIZE>;
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn reduce_argmax(index: u32, stride: u32) {
+    if index < stride {
+        let x = sketch[index];
+        let y = sketch[index + stride];
+        // ties resolve to the smaller index
+        if y > x || (y == x && indices[index + stride] < indices[index]) {
+            sketch[index] = y;
+            indices[index] = indices[index + stride];
+        }
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn argmax(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+#ifdef GATHER
+    let bb = (batches[batch] * shape[1] + token) * stride;
+#else
+    let bb = (batch * shape[1] + token) * stride;
+#endif
+
+    var _max = -3.40282347e38;
+    var _index = 0u;
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+#ifdef IN_FP16
+        let value = unpack4x16float(input[bb + i]);
+#else
+        let value = input[bb + i];
+#endif
+        for (var k = 0u; k < 4u; k += 1u) {
+            if value[k] > _max {
+                _max = value[k];
+                _index = (i << 2u) + k;
+            }
+        }
+    }
+    sketch[index] = _max;
+    indices[index] = _index;
+    workgroupBarrier();
+
+    reduce_argmax(index, 64u);
+    reduce_argmax(index, 32u);
+    reduce_argmax(index, 16u);
+    reduce_argmax(index, 8u);
+    reduce_argmax(index, 4u);
+    reduce_argmax(index, 2u);
+    reduce_argmax(index, 1u);
+
+    if index == 0u {
+        output[batch * shape[1] + token] = indices[0];
+    }
+}
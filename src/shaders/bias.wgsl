@@ -0,0 +1,18 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, 1, S]
+@group(0) @binding(1) var<storage, read> slots: array<u32>;                 // (R)
+@group(0) @binding(2) var<storage, read> bias: array<vec4<f32>>;            // (S, C)
+@group(0) @binding(3) var<storage, read_write> x: array<vec4<f32>>;         // (R, C)
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn apply_bias(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let row = invocation_id.y;
+
+    let slot = slots[row];
+    if index >= stride || slot >= shape[2] {
+        return;
+    }
+
+    x[row * stride + index] += bias[slot * stride + index];
+}
@@ -20,8 +20,10 @@ struct Input {
 @group(0) @binding(5) var<storage, read> xb: array<vec2<u32>>;              // (B, N, K)
 @group(0) @binding(6) var<storage, read_write> output: array<vec2<u32>>;    // (B, N, M)
 
-var<workgroup> sa: array<array<vec2<u32>, 32u>, 32u>;
-var<workgroup> sb: array<array<vec2<u32>, 32u>, 32u>;
+const TILE_SIZE: u32 = BLOCK_SIZE * 4u;
+
+var<workgroup> sa: array<array<vec2<u32>, TILE_SIZE>, TILE_SIZE>;
+var<workgroup> sb: array<array<vec2<u32>, TILE_SIZE>, TILE_SIZE>;
 
 fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
     let stride = view.stride.x >> 2u;
@@ -44,41 +46,41 @@ fn blend(v: vec4<f32>, z: u32, y: u32, x: u32) {
 
 @compute @workgroup_size(BLOCK_SIZE, BLOCK_SIZE, 1)
 fn blend_lora(in: Input) {
-    let b = in.bid.xy * 32u;
+    let b = in.bid.xy * TILE_SIZE;
     let u = in.uid.xy * 4u;
     let t = in.tid.xy * 4u;
     let ra = vec2<u32>(va.shape.x / 4u, va.shape.y);
     let rb = vec2<u32>(vb.shape.x / 4u, vb.shape.y);
     let stride = min(ra.x, rb.x);
-    let i = in.index & 31u;
 
     var local_sum: mat4x4<f32>;
-    for (var k = 0u; k < stride; k += 32u) {
-        // load 8x4 rows from each of the matrix, each with 32x4 columns
-        var x = k + i;
-        for (var j = 0u; j < 32u; j += 1u) {
-            if in.index < 32u {
-                let y = b.x + j;
-                if all(vec2<u32>(x, y) < ra) {
-                    sa[j][i] = xa[compute_index(va, in.uid.z, y, x)];
-                } else {
-                    sa[j][i] = vec2<u32>(0u);
-                }
+    for (var k = 0u; k < stride; k += TILE_SIZE) {
+        // load 4 x BLOCK_SIZE rows from each of the matrix, each with 4 x TILE_SIZE columns
+        for (var n = in.index; n < TILE_SIZE * TILE_SIZE; n += BLOCK_SIZE * BLOCK_SIZE) {
+            let i = n % TILE_SIZE;
+            let j = n / TILE_SIZE;
+            let x = k + i;
+
+            var y = b.x + j;
+            if all(vec2<u32>(x, y) < ra) {
+                sa[j][i] = xa[compute_index(va, in.uid.z, y, x)];
+            } else {
+                sa[j][i] = vec2<u32>(0u);
+            }
+
+            y = b.y + j;
+            if all(vec2<u32>(x, y) < rb) {
+                sb[j][i] = xb[compute_index(vb, in.uid.z, y, x)];
             } else {
-                let y = b.y + j;
-                if all(vec2<u32>(x, y) < rb) {
-                    sb[j][i] = xb[compute_index(vb, in.uid.z, y, x)];
-                } else {
-                    sb[j][i] = vec2<u32>(0u);
-                }
+                sb[j][i] = vec2<u32>(0u);
             }
         }
         workgroupBarrier();
 
         // each thread multiplies and sums up 4x4 blocks along the reduced dimension
         if all(u < vec2<u32>(ra.y, rb.y)) {
-            let reduce = min(32u, stride - k);
-            for (x = 0u; x < reduce; x += 1u) {
+            let reduce = min(TILE_SIZE, stride - k);
+            for (var x = 0u; x < reduce; x += 1u) {
                 let aa = mat4x4<f32>(
                     unpack4x16float(sa[t.x][x]),
                     unpack4x16float(sa[t.x + 1u][x]),
@@ -0,0 +1,89 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R]
+@group(0) @binding(1) var<uniform> history_shape: vec4<u32>;                // [H, 1, S]
+@group(0) @binding(2) var<storage, read> params: array<vec4<f32>>;          // (R, 8)
+@group(0) @binding(3) var<storage, read_write> history: array<u32>;         // (S, H)
+@group(0) @binding(4) var<storage, read_write> lengths: array<u32>;         // (R, H)
+@group(0) @binding(5) var<storage, read_write> x: array<f32>;               // (R, C)
+@group(0) @binding(6) var<storage, read> tokens: array<u32>;                // (R)
+
+// each row of params is `(multiplier, base, allowed_length, _)`, then `(slot, total, position, push)`,
+// where `total` tokens have been pushed into the ring of `slot` before the step, and the sampled token is pushed at `position`
+
+struct Ring {
+    slot: u32,
+    start: u32,
+    len: u32,
+};
+
+fn load_ring(row: u32) -> Ring {
+    let param = params[2u * row + 1u];
+    let total = u32(param[1]);
+    let len = min(total, history_shape[0]);
+    return Ring(u32(param[0]), total - len, len);
+}
+
+// the `index`-th token of the ring, oldest first
+fn token(ring: Ring, index: u32) -> u32 {
+    return history[ring.slot * history_shape[0] + (ring.start + index) % history_shape[0]];
+}
+
+// how many tokens up to `index` match the end of the history
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn dry_match(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let row = invocation_id.y;
+    if index >= history_shape[0] {
+        return;
+    }
+
+    let ring = load_ring(row);
+    var len = 0u;
+    if params[2u * row][0] != 0.0 && index + 1u < ring.len {
+        while len <= index && token(ring, index - len) == token(ring, ring.len - 1u - len) {
+            len += 1u;
+        }
+    }
+    lengths[row * history_shape[0] + index] = len;
+}
+
+// the token following a match is penalized by the longest match it follows, applied once by the last position of that match
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn apply_dry(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let row = invocation_id.y;
+    let param = params[2u * row];
+    let allowed = u32(param[2]);
+
+    let ring = load_ring(row);
+    if param[0] == 0.0 || index + 1u >= ring.len {
+        return;
+    }
+
+    let offset = row * history_shape[0];
+    let len = lengths[offset + index];
+    let next = token(ring, index + 1u);
+    if len < allowed || next >= shape[0] {
+        return;
+    }
+    for (var other = 0u; other + 1u < ring.len; other += 1u) {
+        let other_len = lengths[offset + other];
+        let longer = other_len > len || (other_len == len && other > index);
+        if other != index && token(ring, other + 1u) == next && longer {
+            return;
+        }
+    }
+    x[row * shape[0] + next] -= param[0] * pow(param[1], f32(len - allowed));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn push_history(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let row = invocation_id.x;
+    if row >= arrayLength(&tokens) {
+        return;
+    }
+
+    let param = params[2u * row + 1u];
+    if param[3] > 0.0 {
+        history[u32(param[0]) * history_shape[0] + u32(param[2]) % history_shape[0]] = tokens[row];
+    }
+}
@@ -0,0 +1,141 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> source: View;
+@group(0) @binding(1) var<uniform> destination: View;
+
+#ifdef IN_FP16
+@group(0) @binding(2) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(2) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(3) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, C)
+#else
+@group(0) @binding(3) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, C)
+#endif
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
+fn load_output(bti: u32) -> vec4<f32> {
+#ifdef OUT_FP16
+    return unpack4x16float(output[bti]);
+#else
+    return output[bti];
+#endif
+}
+
+fn store_output(bti: u32, x: vec4<f32>) {
+#ifdef OUT_FP16
+    output[bti] = pack4x16float(x);
+#else
+    output[bti] = x;
+#endif
+}
+
+// `y` is the output and `x` is the input
+fn apply_binary(y: vec4<f32>, x: vec4<f32>) -> vec4<f32> {
+    var z = y;
+#ifdef OP_ADD
+    z = y + x;
+#endif
+#ifdef OP_SUB
+    z = y - x;
+#endif
+#ifdef OP_MUL
+    z = y * x;
+#endif
+#ifdef OP_DIV
+    z = y / x;
+#endif
+#ifdef OP_MAX
+    z = max(y, x);
+#endif
+#ifdef OP_MIN
+    z = min(y, x);
+#endif
+    return z;
+}
+
+fn apply_unary(x: vec4<f32>) -> vec4<f32> {
+    var z = x;
+#ifdef OP_NEG
+    z = -x;
+#endif
+#ifdef OP_ABS
+    z = abs(x);
+#endif
+#ifdef OP_EXP
+    z = exp(x);
+#endif
+#ifdef OP_LOG
+    z = log(x);
+#endif
+#ifdef OP_SIGMOID
+    z = sigmoid(x);
+#endif
+#ifdef OP_TANH
+    z = tanh(x);
+#endif
+#ifdef OP_RELU
+    z = max(x, vec4<f32>(0.0));
+#endif
+#ifdef OP_SQUARED_RELU
+    let p = max(x, vec4<f32>(0.0));
+    z = p * p;
+#endif
+#ifdef OP_SILU
+    z = x * sigmoid(x);
+#endif
+    return z;
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn binary(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+#ifdef IN_FP16
+        let x = unpack4x16float(input[compute_index(source, batch, select(token, 0u, source.shape.y == 1u), index)]);
+#else
+        let x = input[compute_index(source, batch, select(token, 0u, source.shape.y == 1u), index)];
+#endif
+        let bti = compute_index(destination, batch, token, index);
+        store_output(bti, apply_binary(load_output(bti), x));
+    }
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn unary(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let bti = compute_index(destination, batch, token, index);
+        store_output(bti, apply_unary(load_output(bti)));
+    }
+}
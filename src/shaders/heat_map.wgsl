@@ -0,0 +1,76 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> source: View;                            // [C, R, L]
+@group(0) @binding(1) var<uniform> shape: vec4<u32>;                        // [W, R', H, L]
+
+@group(0) @binding(2) var<storage, read> input: array<f32>;                 // (L, R, C)
+@group(0) @binding(3) var<storage, read_write> output: array<f32>;          // (L, H, R', W)
+
+var<workgroup> sketch: array<f32, BLOCK_SIZE>;
+var<workgroup> maximum: f32;
+
+fn compute_index(view: View, layer: u32, row: u32, index: u32) -> u32 {
+    let offset = view.offset.xyz;
+    return dot(vec3<u32>(index, row, layer) + offset, vec3<u32>(1u, view.stride.x, view.stride.x * view.stride.y));
+}
+
+fn reduce_max(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] = max(sketch[index], sketch[index + stride]);
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn heat_map(@builtin(local_invocation_id) invocation_id: vec3<u32>, @builtin(workgroup_id) workgroup_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let head = workgroup_id.y;
+    let layer = workgroup_id.z;
+
+    let head_size = source.shape.x / shape[2];
+    let factor = vec2<u32>(head_size / shape[0], source.shape.y / shape[1]);
+    let len = shape[0] * shape[1];
+    let bb = (layer * shape[2] + head) * len;
+
+    // average-pool each block of the head down to one pixel
+    var _max = 0.0;
+    for (var i = index; i < len; i += BLOCK_SIZE) {
+        let pixel = vec2<u32>(i % shape[0], i / shape[0]);
+        let start = vec2<u32>(head * head_size, 0u) + pixel * factor;
+
+        var sum = 0.0;
+        for (var y = 0u; y < factor.y; y += 1u) {
+            for (var x = 0u; x < factor.x; x += 1u) {
+                sum += input[compute_index(source, layer, start.y + y, start.x + x)];
+            }
+        }
+        let value = sum / f32(factor.x * factor.y);
+        output[bb + i] = value;
+        _max = max(_max, abs(value));
+    }
+    sketch[index] = _max;
+    workgroupBarrier();
+
+    reduce_max(index, 64u);
+    reduce_max(index, 32u);
+    reduce_max(index, 16u);
+    reduce_max(index, 8u);
+    reduce_max(index, 4u);
+    reduce_max(index, 2u);
+    reduce_max(index, 1u);
+
+    if index == 0u {
+        maximum = sketch[0];
+    }
+    workgroupBarrier();
+
+    // normalize the head into [-1, 1]
+    let scale = select(1.0 / maximum, 1.0, maximum == 0.0);
+    for (var i = index; i < len; i += BLOCK_SIZE) {
+        output[bb + i] *= scale;
+    }
+}
@@ -0,0 +1,38 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, N, B]
+
+@group(0) @binding(1) var<storage, read> cursors: array<vec2<u32>>;         // [2, A]
+@group(0) @binding(2) var<storage, read> tokens: array<u32>;                // (A)
+@group(0) @binding(3) var<storage, read> enabled: array<u32>;               // (B)
+@group(0) @binding(4) var<storage, read> source: array<vec2<u32>>;          // (B, N, C)
+#ifdef FP16
+@group(0) @binding(5) var<storage, read_write> output: array<vec2<u32>>;    // (1, A, C)
+#else
+@group(0) @binding(5) var<storage, read_write> output: array<vec4<f32>>;    // (1, A, C)
+#endif
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn inject(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let stack = invocation_id.y;
+
+    let batch = cursors[stack].x;
+    let fetch = tokens[stack];
+
+    if index >= stride || batch >= shape[2] || fetch >= shape[1] || enabled[batch] == 0u {
+        return;
+    }
+
+    let bti = stack * stride + index;
+    let bni = (batch * shape[1] + fetch) * stride + index;
+
+#ifdef FP16
+    output[bti] = source[bni];
+#else
+    output[bti] = unpack4x16float(source[bni]);
+#endif
+}
@@ -0,0 +1,72 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+
+@group(0) @binding(1) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+@group(0) @binding(2) var<storage, read> tokens: array<u32>;                // (B, T)
+@group(0) @binding(3) var<storage, read_write> output: array<f32>;          // (B, T)
+
+var<workgroup> sketch: array<f32, BLOCK_SIZE>;
+
+fn reduce_max(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] = max(sketch[index], sketch[index + stride]);
+    }
+    workgroupBarrier();
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn log_prob(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bb = (batch * shape[1] + token) * stride;
+
+    var _max = vec4<f32>(-3.40282347e38);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        _max = max(_max, input[bb + i]);
+    }
+    sketch[index] = max(max(_max.x, _max.y), max(_max.z, _max.w));
+    workgroupBarrier();
+
+    reduce_max(index, 64u);
+    reduce_max(index, 32u);
+    reduce_max(index, 16u);
+    reduce_max(index, 8u);
+    reduce_max(index, 4u);
+    reduce_max(index, 2u);
+    reduce_max(index, 1u);
+
+    let maximum = sketch[0];
+    workgroupBarrier();
+
+    var _sum = vec4<f32>(0.0);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        _sum += exp(input[bb + i] - maximum);
+    }
+    sketch[index] = dot(_sum, vec4<f32>(1.0));
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    // a target out of range gives no log-probability
+    let bt = batch * shape[1] + token;
+    let label = tokens[bt];
+    if index == 0u && label < shape[0] {
+        let x = input[bb + (label >> 2u)][label & 3u];
+        output[bt] = x - maximum - log(sketch[0]);
+    }
+}
@@ -26,6 +26,9 @@ struct Input {
 #else
 @group(0) @binding(5) var<storage, read_write> output: array<vec4<f32>>;    // (B, N, M)
 #endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (M)
+#endif
 
 const TILE_SIZE: u32 = BLOCK_SIZE * 4u;
 
@@ -55,6 +58,10 @@ fn squared_relu(x: vec4<f32>) -> vec4<f32> {
     return p * p;
 }
 
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
 @compute @workgroup_size(BLOCK_SIZE, BLOCK_SIZE, 1)
 fn matmul(in: Input) {
     let b = in.bid.xy * TILE_SIZE;
@@ -124,6 +131,26 @@ fn matmul(in: Input) {
     }
 
     if all(u < vec2<u32>(ra.y, rb.y)) {
+#ifdef BIAS
+        let x = unpack4x16float(bias[in.uid.x]);
+        local_sum[0] += x;
+        local_sum[1] += x;
+        local_sum[2] += x;
+        local_sum[3] += x;
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        local_sum[0] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 0u, in.uid.x)]);
+        local_sum[1] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x)]);
+        local_sum[2] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 2u, in.uid.x)]);
+        local_sum[3] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 3u, in.uid.x)]);
+#else
+        local_sum[0] += output[compute_index(destination, in.uid.z, u.y + 0u, in.uid.x)];
+        local_sum[1] += output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x)];
+        local_sum[2] += output[compute_index(destination, in.uid.z, u.y + 2u, in.uid.x)];
+        local_sum[3] += output[compute_index(destination, in.uid.z, u.y + 3u, in.uid.x)];
+#endif
+#endif
 #ifdef ACT_SQUARED_RELU
         local_sum[0] = squared_relu(local_sum[0]);
         local_sum[1] = squared_relu(local_sum[1]);
@@ -136,6 +163,18 @@ fn matmul(in: Input) {
         local_sum[2] = tanh(local_sum[2]);
         local_sum[3] = tanh(local_sum[3]);
 #endif
+#ifdef ACT_RELU
+        local_sum[0] = max(local_sum[0], vec4<f32>(0.0));
+        local_sum[1] = max(local_sum[1], vec4<f32>(0.0));
+        local_sum[2] = max(local_sum[2], vec4<f32>(0.0));
+        local_sum[3] = max(local_sum[3], vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        local_sum[0] = sigmoid(local_sum[0]);
+        local_sum[1] = sigmoid(local_sum[1]);
+        local_sum[2] = sigmoid(local_sum[2]);
+        local_sum[3] = sigmoid(local_sum[3]);
+#endif
 #ifdef OUT_FP16
         output[compute_index(destination, in.uid.z, u.y + 0u, in.uid.x)] = pack4x16float(local_sum[0]);
         output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x)] = pack4x16float(local_sum[1]);
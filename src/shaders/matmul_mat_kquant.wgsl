@@ -0,0 +1,274 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+struct Input {
+    @builtin(workgroup_id) bid: vec3<u32>,
+    @builtin(global_invocation_id) uid: vec3<u32>,
+    @builtin(local_invocation_id) tid: vec3<u32>,
+    @builtin(local_invocation_index) index: u32,
+};
+
+@group(0) @binding(0) var<uniform> va: View;                                // [K, M, B]
+@group(0) @binding(1) var<uniform> vb: View;                                // [K, N, B]
+@group(0) @binding(2) var<uniform> destination: View;                       // [M, N, B]
+
+@group(0) @binding(3) var<storage, read> minmax: array<u32>;
+@group(0) @binding(4) var<storage, read> scales: array<u32>;
+#ifdef KQUANT_Q5
+@group(0) @binding(5) var<storage, read> high: array<u32>;
+#endif
+@group(0) @binding(6) var<storage, read> xa: array<u32>;                    // (B, M, K)
+#ifdef IN_FP16
+@group(0) @binding(7) var<storage, read> xb: array<vec4<u32>>;              // (B, N, K)
+#else
+@group(0) @binding(7) var<storage, read> xb: array<mat2x4<f32>>;            // (B, N, K)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(8) var<storage, read_write> output: array<vec2<u32>>;    // (B, N, M)
+#else
+@group(0) @binding(8) var<storage, read_write> output: array<vec4<f32>>;    // (B, N, M)
+#endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (M)
+#endif
+
+const TILE_SIZE: u32 = BLOCK_SIZE * 4u;
+
+// number of 8-element packs in a block, and of blocks in a super-block
+const KQUANT_BLOCK_STEP: u32 = KQUANT_BLOCK_SIZE / 8u;
+const KQUANT_NUM_BLOCK: u32 = KQUANT_SUPER_BLOCK_SIZE / KQUANT_BLOCK_SIZE;
+
+var<workgroup> sa: array<array<u32, BLOCK_SIZE>, TILE_SIZE>;
+var<workgroup> sh: array<array<u32, BLOCK_SIZE>, TILE_SIZE>;
+var<workgroup> ss: array<array<vec2<f32>, BLOCK_SIZE>, TILE_SIZE>;
+#ifdef IN_FP16
+var<workgroup> sb: array<array<vec4<u32>, BLOCK_SIZE>, TILE_SIZE>;
+#else
+var<workgroup> sb: array<array<mat2x4<f32>, BLOCK_SIZE>, TILE_SIZE>;
+#endif
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32, step: u32) -> u32 {
+    let stride = view.stride.x / step;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x / step);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+// the scale and the offset of the block of a pack, so that an element is `scale * q - offset`
+fn unpack_scale(index: u32) -> vec2<f32> {
+    let block = index / KQUANT_BLOCK_STEP;
+    let dd = unpack2x16float(minmax[block / KQUANT_NUM_BLOCK]);
+    let sm = scales[block >> 1u] >> (16u * (block & 1u));
+    return dd * vec2<f32>(f32(sm & 0xffu), f32((sm >> 8u) & 0xffu));
+}
+
+// the high bits of the elements in a pack
+fn unpack_high(index: u32) -> u32 {
+#ifdef KQUANT_Q5
+    let block = index / KQUANT_BLOCK_STEP;
+    return (high[block] >> (8u * (index % KQUANT_BLOCK_STEP))) & 0xffu;
+#else
+    return 0u;
+#endif
+}
+
+// 4 dequantized elements from the low 4 nibbles of `v` and the low 4 bits of `h`
+fn unpack4xq(v: u32, h: u32, s: vec2<f32>) -> vec4<f32> {
+    let q = vec4<u32>(v, v >> 4u, v >> 8u, v >> 12u) & vec4<u32>(0xfu);
+    let p = (vec4<u32>(h, h >> 1u, h >> 2u, h >> 3u) & vec4<u32>(1u)) << vec4<u32>(4u);
+    return s[0] * vec4<f32>(q | p) - s[1];
+}
+
+fn squared_relu(x: vec4<f32>) -> vec4<f32> {
+    let p = max(x, vec4<f32>(0.0));
+    return p * p;
+}
+
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, BLOCK_SIZE, 1)
+fn matmul(in: Input) {
+    let b = in.bid.xy * TILE_SIZE;
+    let u = in.uid.xy * 4u;
+    let t = in.tid.xy * 4u;
+    let ra = vec2<u32>(va.shape.x / 4u, va.shape.y);
+    let rb = vec2<u32>(vb.shape.x / 8u, vb.shape.y);
+    let stride = min(ra.x, rb.x);
+
+    var local_sum: mat4x4<f32>;
+    for (var k = 0u; k < stride; k += BLOCK_SIZE) {
+        // load 8x4 rows from each of the matrix, each with 8x8 columns
+        // also, load the scales and the high bits of the packs of the quantized matrix
+        for (var j = in.tid.y; j < TILE_SIZE; j += BLOCK_SIZE) {
+            let i = in.tid.x;
+            let x = k + i;
+            var y = b.x + j;
+            if all(vec2<u32>(x, y) < ra) {
+                let index = compute_index(va, in.uid.z, y, x, 4u);
+                sa[j][i] = xa[index];
+                sh[j][i] = unpack_high(index);
+                ss[j][i] = unpack_scale(index);
+            } else {
+                sa[j][i] = 0u;
+                sh[j][i] = 0u;
+                ss[j][i] = vec2<f32>(0.0);
+            }
+
+            y = b.y + j;
+            if all(vec2<u32>(x, y) < rb) {
+                sb[j][i] = xb[compute_index(vb, in.uid.z, y, x, 8u)];
+            } else {
+#ifdef IN_FP16
+                sb[j][i] = vec4<u32>(0u);
+#else
+                sb[j][i] = mat2x4<f32>();
+#endif
+            }
+        }
+        workgroupBarrier();
+
+        // each thread multiplies and sums up 4x4 blocks along the reduced dimension
+        if all(u < vec2<u32>(ra.y, rb.y)) {
+            for (var x = 0u; x < BLOCK_SIZE; x += 1u) {
+                if k + x >= stride {
+                    break;
+                }
+                let la = vec4<u32>(
+                    sa[t.x][x],
+                    sa[t.x + 1u][x],
+                    sa[t.x + 2u][x],
+                    sa[t.x + 3u][x],
+                );
+                let lh = vec4<u32>(
+                    sh[t.x][x],
+                    sh[t.x + 1u][x],
+                    sh[t.x + 2u][x],
+                    sh[t.x + 3u][x],
+                );
+                let ls = mat4x2<f32>(
+                    ss[t.x][x],
+                    ss[t.x + 1u][x],
+                    ss[t.x + 2u][x],
+                    ss[t.x + 3u][x],
+                );
+
+                var aa = mat4x4<f32>(
+                    unpack4xq(la[0], lh[0], ls[0]),
+                    unpack4xq(la[1], lh[1], ls[1]),
+                    unpack4xq(la[2], lh[2], ls[2]),
+                    unpack4xq(la[3], lh[3], ls[3]),
+                );
+#ifdef IN_FP16
+                var bb = mat4x4<f32>(
+                    unpack4x16float(sb[t.y][x].xy),
+                    unpack4x16float(sb[t.y + 1u][x].xy),
+                    unpack4x16float(sb[t.y + 2u][x].xy),
+                    unpack4x16float(sb[t.y + 3u][x].xy),
+                );
+#else
+                var bb = mat4x4<f32>(
+                    sb[t.y][x][0],
+                    sb[t.y + 1u][x][0],
+                    sb[t.y + 2u][x][0],
+                    sb[t.y + 3u][x][0],
+                );
+#endif
+                local_sum += transpose(aa) * bb;
+
+                aa = mat4x4<f32>(
+                    unpack4xq(la[0] >> 16u, lh[0] >> 4u, ls[0]),
+                    unpack4xq(la[1] >> 16u, lh[1] >> 4u, ls[1]),
+                    unpack4xq(la[2] >> 16u, lh[2] >> 4u, ls[2]),
+                    unpack4xq(la[3] >> 16u, lh[3] >> 4u, ls[3]),
+                );
+#ifdef IN_FP16
+                bb = mat4x4<f32>(
+                    unpack4x16float(sb[t.y][x].zw),
+                    unpack4x16float(sb[t.y + 1u][x].zw),
+                    unpack4x16float(sb[t.y + 2u][x].zw),
+                    unpack4x16float(sb[t.y + 3u][x].zw),
+                );
+#else
+                bb = mat4x4<f32>(
+                    sb[t.y][x][1],
+                    sb[t.y + 1u][x][1],
+                    sb[t.y + 2u][x][1],
+                    sb[t.y + 3u][x][1],
+                );
+#endif
+                local_sum += transpose(aa) * bb;
+            }
+        }
+        workgroupBarrier();
+    }
+
+    if all(u < vec2<u32>(ra.y, rb.y)) {
+#ifdef BIAS
+        let x = unpack4x16float(bias[in.uid.x]);
+        local_sum[0] += x;
+        local_sum[1] += x;
+        local_sum[2] += x;
+        local_sum[3] += x;
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        local_sum[0] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 0u, in.uid.x, 4u)]);
+        local_sum[1] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x, 4u)]);
+        local_sum[2] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 2u, in.uid.x, 4u)]);
+        local_sum[3] += unpack4x16float(output[compute_index(destination, in.uid.z, u.y + 3u, in.uid.x, 4u)]);
+#else
+        local_sum[0] += output[compute_index(destination, in.uid.z, u.y + 0u, in.uid.x, 4u)];
+        local_sum[1] += output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x, 4u)];
+        local_sum[2] += output[compute_index(destination, in.uid.z, u.y + 2u, in.uid.x, 4u)];
+        local_sum[3] += output[compute_index(destination, in.uid.z, u.y + 3u, in.uid.x, 4u)];
+#endif
+#endif
+#ifdef ACT_SQUARED_RELU
+        local_sum[0] = squared_relu(local_sum[0]);
+        local_sum[1] = squared_relu(local_sum[1]);
+        local_sum[2] = squared_relu(local_sum[2]);
+        local_sum[3] = squared_relu(local_sum[3]);
+#endif
+#ifdef ACT_TANH
+        local_sum[0] = tanh(local_sum[0]);
+        local_sum[1] = tanh(local_sum[1]);
+        local_sum[2] = tanh(local_sum[2]);
+        local_sum[3] = tanh(local_sum[3]);
+#endif
+#ifdef ACT_RELU
+        local_sum[0] = max(local_sum[0], vec4<f32>(0.0));
+        local_sum[1] = max(local_sum[1], vec4<f32>(0.0));
+        local_sum[2] = max(local_sum[2], vec4<f32>(0.0));
+        local_sum[3] = max(local_sum[3], vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        local_sum[0] = sigmoid(local_sum[0]);
+        local_sum[1] = sigmoid(local_sum[1]);
+        local_sum[2] = sigmoid(local_sum[2]);
+        local_sum[3] = sigmoid(local_sum[3]);
+#endif
+#ifdef OUT_FP16
+        output[compute_index(destination, in.uid.z, u.y + 0u, in.uid.x, 4u)] = pack4x16float(local_sum[0]);
+        output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x, 4u)] = pack4x16float(local_sum[1]);
+        output[compute_index(destination, in.uid.z, u.y + 2u, in.uid.x, 4u)] = pack4x16float(local_sum[2]);
+        output[compute_index(destination, in.uid.z, u.y + 3u, in.uid.x, 4u)] = pack4x16float(local_sum[3]);
+#else
+        output[compute_index(destination, in.uid.z, u.y + 0u, in.uid.x, 4u)] = local_sum[0];
+        output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x, 4u)] = local_sum[1];
+        output[compute_index(destination, in.uid.z, u.y + 2u, in.uid.x, 4u)] = local_sum[2];
+        output[compute_index(destination, in.uid.z, u.y + 3u, in.uid.x, 4u)] = local_sum[3];
+#endif
+    }
+}
@@ -19,6 +19,13 @@ struct View {
 #else
 @group(0) @binding(5) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
 #endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (R)
+#endif
+#ifdef GATHER
+@group(0) @binding(6) var<storage, read> input_batches: array<u32>;         // (N)
+@group(0) @binding(7) var<storage, read> output_batches: array<u32>;        // (N)
+#endif
 
 var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
 
@@ -41,6 +48,10 @@ fn squared_relu(x: vec4<f32>) -> vec4<f32> {
     return p * p;
 }
 
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
 fn reduce_sum(index: u32, stride: u32) {
     if index < stride {
         sketch[index] += sketch[index + stride];
@@ -57,8 +68,14 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let batch = invocation_id.z;
 
     // let bb = (batch * destination.shape[1] + token) * stride.x;
+#ifdef GATHER
+    // the matrix is shared by all gathered batches
+    let bb = compute_index(source, input_batches[batch], token, 0u);
+    let cb = channel * 4u * stride;
+#else
     let bb = compute_index(source, batch, token, 0u);
     let cb = batch * shape.y * stride + channel * 4u * stride;
+#endif
 
     var local_sum = vec4<f32>(0.0);
     for (var i = index; i < stride; i += BLOCK_SIZE) {
@@ -93,14 +110,34 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     reduce_sum(index, 1u);
 
     if index == 0u {
+#ifdef GATHER
+        let btc = compute_index(destination, output_batches[batch], token, channel);
+#else
         let btc = compute_index(destination, batch, token, channel);
+#endif
         var out = sketch[0];
+#ifdef BIAS
+        out += unpack4x16float(bias[channel]);
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        out += unpack4x16float(output[btc]);
+#else
+        out += output[btc];
+#endif
+#endif
 #ifdef ACT_SQUARED_RELU
         out = squared_relu(out);
 #endif
 #ifdef ACT_TANH
         out = tanh(out);
 #endif
+#ifdef ACT_RELU
+        out = max(out, vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        out = sigmoid(out);
+#endif
 #ifdef OUT_FP16
         output[btc] = pack4x16float(out);
 #else
@@ -0,0 +1,155 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R, B]
+@group(0) @binding(1) var<uniform> source: View;                            // [R, T, B]
+@group(0) @binding(2) var<uniform> destination: View;                       // [R, T, B]
+
+@group(0) @binding(3) var<storage, read> matrix: array<u32>;                // (B, R, C)
+@group(0) @binding(4) var<storage, read> absmax: array<u32>;
+
+#ifdef IN_FP16
+@group(0) @binding(5) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(5) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(6) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
+@group(0) @binding(6) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (R)
+#endif
+
+const FP8_BLOCK_STEP: u32 = FP8_BLOCK_SIZE / 4u;
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn unpack_absmax(index: u32) -> f32 {
+    let i = index / FP8_BLOCK_STEP; // 1 block of absmax: FP8_BLOCK_SIZE / 4u entries in matrix
+    return unpack2x16float(absmax[i >> 1u])[i & 1u];
+}
+
+// place 4 fp8 numbers into the high bits of f16s, and normalize them by the max of the format
+fn unpack4xfp8(x: u32) -> vec4<f32> {
+#ifdef FP8_E5M2
+    let lo = ((x & 0x000000ffu) << 8u) | ((x & 0x0000ff00u) << 16u);
+    let hi = ((x & 0x00ff0000u) >> 8u) | (x & 0xff000000u);
+    return vec4<f32>(unpack2x16float(lo), unpack2x16float(hi)) * (1.0 / 57344.0);
+#else
+    let s = x & 0x80808080u;
+    let v = x & 0x7f7f7f7fu;
+    let lo = ((s & 0x00000080u) << 8u) | ((v & 0x0000007fu) << 7u) | ((s & 0x00008000u) << 16u) | ((v & 0x00007f00u) << 15u);
+    let hi = ((s & 0x00800000u) >> 8u) | ((v & 0x007f0000u) >> 9u) | (s & 0x80000000u) | ((v & 0x7f000000u) >> 1u);
+    return vec4<f32>(unpack2x16float(lo), unpack2x16float(hi)) * (256.0 / 448.0);
+#endif
+}
+
+fn squared_relu(x: vec4<f32>) -> vec4<f32> {
+    let p = max(x, vec4<f32>(0.0));
+    return p * p;
+}
+
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape.x / 4u;
+    let index = invocation_id.x % BLOCK_SIZE;
+    let channel = invocation_id.x / BLOCK_SIZE;     // 1 channel: 4 rows in matrix
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bb = compute_index(source, batch, token, 0u);
+    let cb = batch * shape.y * stride + channel * 4u * stride;
+
+    var local_sum = vec4<f32>(0.0);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let bti = bb + i;
+        var ci = cb + i;
+
+        // read 4 elements from the input
+#ifdef IN_FP16
+        let x = unpack4x16float(input[bti]);
+#else
+        let x = input[bti];
+#endif
+
+        // read 4 rows from the matrix, each with 4 unpacked floats, forming a 4x4 sub-block
+        var m: mat4x4<f32>;
+        m[0] = unpack_absmax(ci) * unpack4xfp8(matrix[ci]); ci += stride;
+        m[1] = unpack_absmax(ci) * unpack4xfp8(matrix[ci]); ci += stride;
+        m[2] = unpack_absmax(ci) * unpack4xfp8(matrix[ci]); ci += stride;
+        m[3] = unpack_absmax(ci) * unpack4xfp8(matrix[ci]);
+        local_sum += transpose(m) * x;
+    }
+    sketch[index] = local_sum;
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        let btc = compute_index(destination, batch, token, channel);
+        var out = sketch[0];
+#ifdef BIAS
+        out += unpack4x16float(bias[channel]);
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        out += unpack4x16float(output[btc]);
+#else
+        out += output[btc];
+#endif
+#endif
+#ifdef ACT_SQUARED_RELU
+        out = squared_relu(out);
+#endif
+#ifdef ACT_TANH
+        out = tanh(out);
+#endif
+#ifdef ACT_RELU
+        out = max(out, vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        out = sigmoid(out);
+#endif
+#ifdef OUT_FP16
+        output[btc] = pack4x16float(out);
+#else
+        output[btc] = out;
+#endif
+    }
+}
@@ -21,6 +21,13 @@ struct View {
 #else
 @group(0) @binding(6) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
 #endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (R)
+#endif
+#ifdef LORA
+@group(0) @binding(10) var<storage, read> lora: array<vec2<u32>>;           // (R, LORA_RANK)
+@group(0) @binding(11) var<storage, read> hidden: array<vec4<f32>>;         // (B, T, LORA_RANK)
+#endif
 
 const INT8_BLOCK_STEP: u32 = INT8_BLOCK_SIZE / 4u;
 
@@ -50,6 +57,10 @@ fn squared_relu(x: vec4<f32>) -> vec4<f32> {
     return p * p;
 }
 
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
 fn reduce_sum(index: u32, stride: u32) {
     if index < stride {
         sketch[index] += sketch[index + stride];
@@ -89,6 +100,20 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         b = unpack_minmax(ci); m[3] = fma(unpack4x8unorm(matrix[ci]), vec4<f32>(b[1] - b[0]), vec4<f32>(b[0]));
         local_sum += transpose(m) * x;
     }
+#ifdef LORA
+    // the low-rank delta of the 4 rows, `lora * hidden`, is summed along with the product
+    let lr = LORA_RANK / 4u;
+    let hb = (batch * destination.shape.y + token) * lr;
+    let lb = channel * 4u * lr;
+    for (var k = index; k < lr; k += BLOCK_SIZE) {
+        var m: mat4x4<f32>;
+        m[0] = unpack4x16float(lora[lb + k]);
+        m[1] = unpack4x16float(lora[lb + lr + k]);
+        m[2] = unpack4x16float(lora[lb + 2u * lr + k]);
+        m[3] = unpack4x16float(lora[lb + 3u * lr + k]);
+        local_sum += transpose(m) * hidden[hb + k];
+    }
+#endif
     sketch[index] = local_sum;
     workgroupBarrier();
 
@@ -103,12 +128,28 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     if index == 0u {
         let btc = compute_index(destination, batch, token, channel);
         var out = sketch[0];
+#ifdef BIAS
+        out += unpack4x16float(bias[channel]);
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        out += unpack4x16float(output[btc]);
+#else
+        out += output[btc];
+#endif
+#endif
 #ifdef ACT_SQUARED_RELU
         out = squared_relu(out);
 #endif
 #ifdef ACT_TANH
         out = tanh(out);
 #endif
+#ifdef ACT_RELU
+        out = max(out, vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        out = sigmoid(out);
+#endif
 #ifdef OUT_FP16
         output[btc] = pack4x16float(out);
 #else
@@ -0,0 +1,175 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R, B]
+@group(0) @binding(1) var<uniform> source: View;                            // [R, T, B]
+@group(0) @binding(2) var<uniform> destination: View;                       // [R, T, B]
+
+@group(0) @binding(3) var<storage, read> matrix: array<u32>;                // (B, R, C)
+@group(0) @binding(4) var<storage, read> scale: array<f32>;                 // (B, R)
+
+#ifdef IN_FP16
+@group(0) @binding(5) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(5) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(6) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
+@group(0) @binding(6) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (R)
+#endif
+
+// the logit transform, indexed by the rows of the whole output
+#ifdef LOGIT_BIAS
+@group(0) @binding(10) var<uniform> logit_shape: vec4<u32>;                 // [R, 1, S]
+@group(0) @binding(11) var<storage, read> slots: array<u32>;                // (T)
+@group(0) @binding(12) var<storage, read> logit_bias: array<vec4<f32>>;     // (S, R)
+#endif
+// the length adjustment and the penalties of each row share a buffer, to stay within 8 storage buffers
+#ifdef PARAMS
+@group(0) @binding(13) var<storage, read> params: array<vec4<f32>>;         // (T, 2, 4)
+#endif
+#ifdef PENALTY
+@group(0) @binding(14) var<storage, read> counts: array<f32>;               // (S, R)
+#endif
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn squared_relu(x: vec4<f32>) -> vec4<f32> {
+    let p = max(x, vec4<f32>(0.0));
+    return p * p;
+}
+
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape.x / 4u;
+    let index = invocation_id.x % BLOCK_SIZE;
+    let channel = invocation_id.x / BLOCK_SIZE;     // 1 channel: 4 rows in matrix
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bb = compute_index(source, batch, token, 0u);
+    let cb = batch * shape.y * stride + channel * 4u * stride;
+
+    // the scales of the rows are applied once to the sums
+    var local_sum = vec4<f32>(0.0);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let bti = bb + i;
+        var ci = cb + i;
+
+        // read 4 elements from the input
+#ifdef IN_FP16
+        let x = unpack4x16float(input[bti]);
+#else
+        let x = input[bti];
+#endif
+
+        // read 4 rows from the matrix, each with 4 unpacked codes, forming a 4x4 sub-block
+        var m: mat4x4<f32>;
+        m[0] = unpack4x8snorm(matrix[ci]); ci += stride;
+        m[1] = unpack4x8snorm(matrix[ci]); ci += stride;
+        m[2] = unpack4x8snorm(matrix[ci]); ci += stride;
+        m[3] = unpack4x8snorm(matrix[ci]);
+        local_sum += transpose(m) * x;
+    }
+    sketch[index] = local_sum;
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        let btc = compute_index(destination, batch, token, channel);
+        let sb = batch * shape.y + channel * 4u;
+        var out = sketch[0] * vec4<f32>(scale[sb], scale[sb + 1u], scale[sb + 2u], scale[sb + 3u]);
+#ifdef BIAS
+        out += unpack4x16float(bias[channel]);
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        out += unpack4x16float(output[btc]);
+#else
+        out += output[btc];
+#endif
+#endif
+#ifdef ACT_SQUARED_RELU
+        out = squared_relu(out);
+#endif
+#ifdef ACT_TANH
+        out = tanh(out);
+#endif
+#ifdef ACT_RELU
+        out = max(out, vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        out = sigmoid(out);
+#endif
+
+        // in the same order as applied apart: biases, the length adjustment, then penalties
+        let row = destination.offset.y + token;
+#ifdef LOGIT_BIAS
+        let slot = slots[row];
+        if slot < logit_shape[2] {
+            out += logit_bias[slot * (shape.y / 4u) + channel];
+        }
+#endif
+#ifdef LENGTH
+        let length = params[row * 2u];
+        if length[0] >= 0.0 && (u32(length[0]) >> 2u) == channel {
+            let k = u32(length[0]) & 3u;
+            out[k] = out[k] + length[1] + abs(out[k]) * length[2];
+        }
+#endif
+#ifdef PENALTY
+        let penalty = params[row * 2u + 1u];
+        if penalty[0] != 0.0 || penalty[1] != 0.0 {
+            let cc = u32(penalty[2]) * shape.y + (channel << 2u);
+            let count = vec4<f32>(counts[cc], counts[cc + 1u], counts[cc + 2u], counts[cc + 3u]);
+            let presence = select(vec4<f32>(0.0), vec4<f32>(1.0), count > vec4<f32>(0.0));
+            out -= penalty[0] * presence + penalty[1] * count;
+        }
+#endif
+
+#ifdef OUT_FP16
+        output[btc] = pack4x16float(out);
+#else
+        output[btc] = out;
+#endif
+    }
+}
@@ -0,0 +1,187 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R, B]
+@group(0) @binding(1) var<uniform> source: View;                            // [R, T, B]
+@group(0) @binding(2) var<uniform> destination: View;                       // [R, T, B]
+
+@group(0) @binding(3) var<storage, read> matrix: array<u32>;                // (B, R, C)
+@group(0) @binding(4) var<storage, read> minmax: array<u32>;
+@group(0) @binding(5) var<storage, read> scales: array<u32>;
+#ifdef KQUANT_Q5
+@group(0) @binding(6) var<storage, read> high: array<u32>;
+#endif
+
+#ifdef IN_FP16
+@group(0) @binding(7) var<storage, read> input: array<vec4<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(7) var<storage, read> input: array<mat2x4<f32>>;         // (B, T, C)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(8) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
+@group(0) @binding(8) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (R)
+#endif
+
+// number of 8-element packs in a block, and of blocks in a super-block
+const KQUANT_BLOCK_STEP: u32 = KQUANT_BLOCK_SIZE / 8u;
+const KQUANT_NUM_BLOCK: u32 = KQUANT_SUPER_BLOCK_SIZE / KQUANT_BLOCK_SIZE;
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32, step: u32) -> u32 {
+    let stride = view.stride.x >> step;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> step);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+// the scale and the offset of the block of a pack, so that an element is `scale * q - offset`
+fn unpack_scale(index: u32) -> vec2<f32> {
+    let block = index / KQUANT_BLOCK_STEP;
+    let dd = unpack2x16float(minmax[block / KQUANT_NUM_BLOCK]);
+    let sm = scales[block >> 1u] >> (16u * (block & 1u));
+    return dd * vec2<f32>(f32(sm & 0xffu), f32((sm >> 8u) & 0xffu));
+}
+
+// the high bits of the elements in a pack
+fn unpack_high(index: u32) -> u32 {
+#ifdef KQUANT_Q5
+    let block = index / KQUANT_BLOCK_STEP;
+    return (high[block] >> (8u * (index % KQUANT_BLOCK_STEP))) & 0xffu;
+#else
+    return 0u;
+#endif
+}
+
+// 4 quantized elements from the low 4 nibbles of `v` and the low 4 bits of `h`
+fn unpack4xq(v: u32, h: u32) -> vec4<f32> {
+    let q = vec4<u32>(v, v >> 4u, v >> 8u, v >> 12u) & vec4<u32>(0xfu);
+    let p = (vec4<u32>(h, h >> 1u, h >> 2u, h >> 3u) & vec4<u32>(1u)) << vec4<u32>(4u);
+    return vec4<f32>(q | p);
+}
+
+fn squared_relu(x: vec4<f32>) -> vec4<f32> {
+    let p = max(x, vec4<f32>(0.0));
+    return p * p;
+}
+
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = source.stride.x / 8u;
+    let index = invocation_id.x % BLOCK_SIZE;
+    let channel = invocation_id.x / BLOCK_SIZE;     // 1 channel: 4 rows in matrix
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bb = compute_index(source, batch, token, 0u, 3u);
+    let cb = batch * shape.y * stride + channel * 4u * stride;
+
+    var local_sum = vec4<f32>(0.0);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        // read 4 rows from the matrix, each with 4x2 unpacked quantized elements, forming 2 4x4 sub-blocks
+        var ci = cb + i;
+        var v: vec4<u32>;
+        var h: vec4<u32>;
+        var s: mat4x2<f32>;
+        v[0] = matrix[ci]; h[0] = unpack_high(ci); s[0] = unpack_scale(ci); ci += stride;
+        v[1] = matrix[ci]; h[1] = unpack_high(ci); s[1] = unpack_scale(ci); ci += stride;
+        v[2] = matrix[ci]; h[2] = unpack_high(ci); s[2] = unpack_scale(ci); ci += stride;
+        v[3] = matrix[ci]; h[3] = unpack_high(ci); s[3] = unpack_scale(ci);
+        let scale = vec4<f32>(s[0][0], s[1][0], s[2][0], s[3][0]);
+        let offset = vec4<f32>(s[0][1], s[1][1], s[2][1], s[3][1]);
+
+        // read 8 elements from the input
+#ifdef IN_FP16
+        let x = input[bb + i];
+        let x0 = unpack4x16float(x.xy);
+        let x1 = unpack4x16float(x.zw);
+#else
+        let x0 = input[bb + i][0];
+        let x1 = input[bb + i][1];
+#endif
+
+        var m: mat4x4<f32>;
+        m[0] = unpack4xq(v[0], h[0]);
+        m[1] = unpack4xq(v[1], h[1]);
+        m[2] = unpack4xq(v[2], h[2]);
+        m[3] = unpack4xq(v[3], h[3]);
+        var q = transpose(m) * x0;
+
+        m[0] = unpack4xq(v[0] >> 16u, h[0] >> 4u);
+        m[1] = unpack4xq(v[1] >> 16u, h[1] >> 4u);
+        m[2] = unpack4xq(v[2] >> 16u, h[2] >> 4u);
+        m[3] = unpack4xq(v[3] >> 16u, h[3] >> 4u);
+        q += transpose(m) * x1;
+
+        // each row is `scale * q - offset`, dotted with the input
+        let sum = dot(x0 + x1, vec4<f32>(1.0));
+        local_sum += scale * q - offset * sum;
+    }
+    sketch[index] = local_sum;
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        let btc = compute_index(destination, batch, token, channel, 2u);
+        var out = sketch[0];
+#ifdef BIAS
+        out += unpack4x16float(bias[channel]);
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        out += unpack4x16float(output[btc]);
+#else
+        out += output[btc];
+#endif
+#endif
+#ifdef ACT_SQUARED_RELU
+        out = squared_relu(out);
+#endif
+#ifdef ACT_TANH
+        out = tanh(out);
+#endif
+#ifdef ACT_RELU
+        out = max(out, vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        out = sigmoid(out);
+#endif
+#ifdef OUT_FP16
+        output[btc] = pack4x16float(out);
+#else
+        output[btc] = out;
+#endif
+    }
+}
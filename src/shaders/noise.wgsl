@@ -0,0 +1,69 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+@group(0) @binding(1) var<uniform> seed: vec4<u32>;
+#ifdef FP16
+@group(0) @binding(2) var<storage, read_write> x: array<vec2<u32>>;    // (B, T, C)
+#else
+@group(0) @binding(2) var<storage, read_write> x: array<vec4<f32>>;    // (B, T, C)
+#endif
+
+const PI: f32 = 3.14159265358979;
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+// PCG hash, see "Hash Functions for GPU Rendering" (Jarzynski and Olano, 2020)
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// uniform in [0, 1)
+fn uniform(v: u32) -> f32 {
+    return f32(v >> 8u) / 16777216.0;
+}
+
+fn noise(x: f32, index: u32) -> f32 {
+    let h = pcg(index ^ pcg(seed[0] ^ pcg(seed[1])));
+#ifdef DROPOUT
+    if uniform(h) < AMOUNT {
+        return 0.0;
+    }
+    return x / (1.0 - AMOUNT);
+#else
+    // Box-Muller transform
+    let u = 1.0 - uniform(h);
+    let v = uniform(pcg(h));
+    return x + AMOUNT * sqrt(-2.0 * log(u)) * cos(2.0 * PI * v);
+#endif
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn add_noise(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let bti = (batch * shape[1] + token) * stride + index;
+#ifdef FP16
+        var value = unpack4x16float(x[bti]);
+#else
+        var value = x[bti];
+#endif
+        for (var k = 0u; k < 4u; k += 1u) {
+            value[k] = noise(value[k], (bti << 2u) + k);
+        }
+#ifdef FP16
+        x[bti] = pack4x16float(value);
+#else
+        x[bti] = value;
+#endif
+    }
+}
@@ -0,0 +1,60 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, 1, S]
+@group(0) @binding(1) var<storage, read> params: array<vec4<f32>>;          // (R, 4)
+@group(0) @binding(2) var<storage, read_write> counts: array<f32>;          // (S, C)
+@group(0) @binding(3) var<storage, read_write> x: array<vec4<f32>>;         // (R, C)
+@group(0) @binding(4) var<storage, read> tokens: array<u32>;                // (R)
+
+// each row of params is `(presence, frequency, slot, count)`
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn apply_penalty(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let row = invocation_id.y;
+
+    if index >= stride {
+        return;
+    }
+
+    let param = params[row];
+    if param[0] == 0.0 && param[1] == 0.0 {
+        return;
+    }
+
+    let cc = u32(param[2]) * shape[0] + (index << 2u);
+    let count = vec4<f32>(counts[cc], counts[cc + 1u], counts[cc + 2u], counts[cc + 3u]);
+    let presence = select(vec4<f32>(0.0), vec4<f32>(1.0), count > vec4<f32>(0.0));
+    x[row * stride + index] -= param[0] * presence + param[1] * count;
+}
+
+// each row of params is `(token, add, scale, _)`, where a negative token leaves the row as is
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn apply_length(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let row = invocation_id.x;
+    if row >= arrayLength(&params) {
+        return;
+    }
+
+    let param = params[row];
+    if param[0] < 0.0 || u32(param[0]) >= shape[0] {
+        return;
+    }
+
+    let token = u32(param[0]);
+    let bti = row * (shape[0] / 4u) + (token >> 2u);
+    var p = x[bti];
+    let logit = p[token & 3u];
+    p[token & 3u] = logit + param[1] + abs(logit) * param[2];
+    x[bti] = p;
+}
+
+// a single invocation, so that the tokens of a slot are counted in order without races
+@compute @workgroup_size(1, 1, 1)
+fn count_tokens() {
+    for (var row = 0u; row < arrayLength(&tokens); row += 1u) {
+        let param = params[row];
+        if param[3] > 0.0 {
+            counts[u32(param[2]) * shape[0] + tokens[row]] += 1.0;
+        }
+    }
+}
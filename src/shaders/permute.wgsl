@@ -0,0 +1,68 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> source: View;
+@group(0) @binding(1) var<uniform> destination: View;
+
+#ifdef IN_FP16
+@group(0) @binding(2) var<storage, read> input: array<u32>;                 // (B, T, C)
+#else
+@group(0) @binding(2) var<storage, read> input: array<f32>;                 // (B, T, C)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(3) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, C)
+#else
+@group(0) @binding(3) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, C)
+#endif
+
+// index of a single element, as the channel axis of the source may be permuted
+fn compute_source_index(view: View, coord: vec3<u32>) -> u32 {
+    let offset = view.offset.xyz;
+    return dot(coord + offset, vec3<u32>(1u, view.stride.x, view.stride.y * view.stride.x));
+}
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn load(index: u32) -> f32 {
+#ifdef IN_FP16
+    return unpack2x16float(input[index >> 1u])[index & 1u];
+#else
+    return input[index];
+#endif
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn permute(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        var x: vec4<f32>;
+        for (var k = 0u; k < 4u; k += 1u) {
+            // axis `AXIS_i` of the source is axis `i` of the destination
+            var coord: vec3<u32>;
+            coord[AXIS_0] = (index << 2u) + k;
+            coord[AXIS_1] = token;
+            coord[AXIS_2] = batch;
+            x[k] = load(compute_source_index(source, coord));
+        }
+#ifdef OUT_FP16
+        output[compute_index(destination, batch, token, index)] = pack4x16float(x);
+#else
+        output[compute_index(destination, batch, token, index)] = x;
+#endif
+    }
+}
@@ -0,0 +1,83 @@
+struct Input {
+    @builtin(global_invocation_id) uid: vec3<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [K, M, B]
+
+@group(0) @binding(1) var<storage, read> input: array<vec2<u32>>;           // (B, M, K)
+
+@group(0) @binding(2) var<storage, read_write> absmax: array<f32>;          // (B, M, K / S)
+@group(0) @binding(3) var<storage, read_write> output: array<u32>;          // (B, M, K)
+
+const FP8_BLOCK_STEP: u32 = FP8_BLOCK_SIZE / 4u;
+
+#ifdef FP8_E5M2
+const FP8_BIAS: u32 = 15u;
+const FP8_MANTISSA: u32 = 2u;
+const FP8_MAX: f32 = 57344.0;
+const FP8_MAX_CODE: u32 = 0x7bu;
+const FP8_MIN_NORMAL: f32 = 6.103515625e-5;
+const FP8_MIN_SUBNORMAL: f32 = 1.52587890625e-5;
+#else
+const FP8_BIAS: u32 = 7u;
+const FP8_MANTISSA: u32 = 3u;
+const FP8_MAX: f32 = 448.0;
+const FP8_MAX_CODE: u32 = 0x7eu;
+const FP8_MIN_NORMAL: f32 = 0.015625;
+const FP8_MIN_SUBNORMAL: f32 = 0.001953125;
+#endif
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+// round a float in the range of fp8 to the nearest fp8 code
+fn pack_fp8(x: f32) -> u32 {
+    let a = min(abs(x), FP8_MAX);
+    var code: u32;
+    if a < FP8_MIN_NORMAL {
+        code = u32(round(a / FP8_MIN_SUBNORMAL));
+    } else {
+        // re-bias the exponent of f32 and round off the extra mantissa bits; carries go into the exponent
+        let bits = bitcast<u32>(a) - ((127u - FP8_BIAS) << 23u);
+        code = (bits + (1u << (22u - FP8_MANTISSA))) >> (23u - FP8_MANTISSA);
+    }
+    code = min(code, FP8_MAX_CODE);
+    return select(code, code | 0x80u, x < 0.0);
+}
+
+fn pack4xfp8(x: vec4<f32>) -> u32 {
+    return pack_fp8(x[0]) | (pack_fp8(x[1]) << 8u) | (pack_fp8(x[2]) << 16u) | (pack_fp8(x[3]) << 24u);
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn compute_absmax(in: Input) {
+    let stride = shape[0] / FP8_BLOCK_SIZE;
+    let index = in.uid.x;
+    if index >= stride {
+        return;
+    }
+    let bti = (in.uid.z * shape[1] + in.uid.y) * stride + index;
+
+    var _max = vec4<f32>(0.0);
+    for (var i = 0u; i < FP8_BLOCK_STEP; i += 1u) {
+        let v = unpack4x16float(input[bti * FP8_BLOCK_STEP + i]);
+        _max = max(abs(v), _max);
+    }
+    absmax[bti] = max(max(_max[0], _max[1]), max(_max[2], _max[3]));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn quantize(in: Input) {
+    let stride = shape[0] / 4u;
+    let index = in.uid.x;
+    if index >= stride {
+        return;
+    }
+    let bti = (in.uid.z * shape[1] + in.uid.y) * stride + index;
+
+    let a = absmax[bti / FP8_BLOCK_STEP];
+    let scale = select(FP8_MAX / a, 0.0, a == 0.0);
+    let v = unpack4x16float(input[bti]) * scale;
+    output[bti] = pack4xfp8(v);
+}
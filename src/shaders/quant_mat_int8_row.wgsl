@@ -0,0 +1,45 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R, B]
+
+@group(0) @binding(1) var<storage, read> input: array<vec2<u32>>;           // (B, R, C)
+
+@group(0) @binding(2) var<storage, read_write> scale: array<f32>;           // (B, R)
+@group(0) @binding(3) var<storage, read_write> output: array<u32>;          // (B, R, C / 4)
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+// one invocation per row, as rows are as many as the vocabulary
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn compute_scale(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let row = invocation_id.x;
+    let batch = invocation_id.z;
+    if row >= shape[1] {
+        return;
+    }
+
+    let bb = (batch * shape[1] + row) * stride;
+    var _max = vec4<f32>(0.0);
+    for (var i = 0u; i < stride; i += 1u) {
+        _max = max(_max, abs(unpack4x16float(input[bb + i])));
+    }
+    scale[batch * shape[1] + row] = max(max(_max.x, _max.y), max(_max.z, _max.w));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn quantize(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let row = invocation_id.x;
+    let batch = invocation_id.z;
+    if row >= shape[1] {
+        return;
+    }
+
+    let bb = (batch * shape[1] + row) * stride;
+    let s = scale[batch * shape[1] + row];
+    for (var i = 0u; i < stride; i += 1u) {
+        let v = unpack4x16float(input[bb + i]);
+        output[bb + i] = pack4x8snorm(select(vec4<f32>(0.0), v / s, s > 0.0));
+    }
+}
@@ -0,0 +1,110 @@
+struct Input {
+    @builtin(global_invocation_id) uid: vec3<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [K, M, B]
+
+@group(0) @binding(1) var<storage, read> input: array<vec4<u32>>;           // (B, M, K)
+
+@group(0) @binding(2) var<storage, read_write> minmax: array<u32>;          // (B, M, K / SS, 2)
+@group(0) @binding(3) var<storage, read_write> scales: array<u32>;          // (B, M, K / S, 2)
+@group(0) @binding(4) var<storage, read_write> output: array<u32>;          // (B, M, K / 2)
+#ifdef KQUANT_Q5
+@group(0) @binding(5) var<storage, read_write> high: array<u32>;            // (B, M, K / 8)
+const KQUANT_MAX: f32 = 31.0;
+#else
+const KQUANT_MAX: f32 = 15.0;
+#endif
+
+// number of 8-element packs in a block, and of blocks in a super-block
+const KQUANT_BLOCK_STEP: u32 = KQUANT_BLOCK_SIZE / 8u;
+const KQUANT_NUM_BLOCK: u32 = KQUANT_SUPER_BLOCK_SIZE / KQUANT_BLOCK_SIZE;
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+// the scale and the (negated) minimum that map the elements of a block to `[0, KQUANT_MAX]`
+fn block_range(block: u32) -> vec2<f32> {
+    var _min = vec4<f32>(0.0);
+    var _max = vec4<f32>(0.0);
+    for (var i = 0u; i < KQUANT_BLOCK_STEP; i += 1u) {
+        let v = input[block * KQUANT_BLOCK_STEP + i];
+        let x = unpack4x16float(v.xy);
+        let y = unpack4x16float(v.zw);
+        _min = min(_min, min(x, y));
+        _max = max(_max, max(x, y));
+    }
+    let lo = min(min(_min[0], _min[1]), min(_min[2], _min[3]));
+    let hi = max(max(_max[0], _max[1]), max(_max[2], _max[3]));
+    return vec2<f32>((hi - lo) / KQUANT_MAX, -lo);
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn compute_scale(in: Input) {
+    let stride = shape[0] / KQUANT_SUPER_BLOCK_SIZE;
+    let index = in.uid.x;
+    if index >= stride {
+        return;
+    }
+    let bti = (in.uid.z * shape[1] + in.uid.y) * stride + index;
+
+    // scales and minimums of the blocks are quantized to 6 bits against those of the super-block
+    var ranges: array<vec2<f32>, KQUANT_NUM_BLOCK>;
+    var _max = vec2<f32>(0.0);
+    for (var j = 0u; j < KQUANT_NUM_BLOCK; j += 1u) {
+        ranges[j] = block_range(bti * KQUANT_NUM_BLOCK + j);
+        _max = max(_max, ranges[j]);
+    }
+    let dd_packed = pack2x16float(_max / 63.0);
+    let dd = unpack2x16float(dd_packed);
+    minmax[bti] = dd_packed;
+
+    for (var j = 0u; j < KQUANT_NUM_BLOCK; j += 2u) {
+        var word = 0u;
+        for (var k = 0u; k < 2u; k += 1u) {
+            let r = ranges[j + k];
+            let s = select(round(r / dd), vec2<f32>(0.0), dd == vec2<f32>(0.0));
+            let q = vec2<u32>(clamp(s, vec2<f32>(0.0), vec2<f32>(63.0)));
+            word |= (q[0] | (q[1] << 8u)) << (16u * k);
+        }
+        scales[(bti * KQUANT_NUM_BLOCK + j) >> 1u] = word;
+    }
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn quantize(in: Input) {
+    let stride = shape[0] / KQUANT_BLOCK_SIZE;
+    let index = in.uid.x;
+    if index >= stride {
+        return;
+    }
+    let bti = (in.uid.z * shape[1] + in.uid.y) * stride + index;
+
+    let dd = unpack2x16float(minmax[bti / KQUANT_NUM_BLOCK]);
+    let sm = (scales[bti >> 1u] >> (16u * (bti & 1u))) & 0xffffu;
+    let scale = dd[0] * f32(sm & 0xffu);
+    let offset = dd[1] * f32(sm >> 8u);
+    let a = select(1.0 / scale, 0.0, scale == 0.0);
+
+    var bits = 0u;
+    for (var i = 0u; i < KQUANT_BLOCK_STEP; i += 1u) {
+        let v = input[bti * KQUANT_BLOCK_STEP + i];
+        let x = unpack4x16float(v.xy);
+        let y = unpack4x16float(v.zw);
+        let qx = vec4<u32>(clamp(round((x + offset) * a), vec4<f32>(0.0), vec4<f32>(KQUANT_MAX)));
+        let qy = vec4<u32>(clamp(round((y + offset) * a), vec4<f32>(0.0), vec4<f32>(KQUANT_MAX)));
+
+        var word = 0u;
+        for (var k = 0u; k < 4u; k += 1u) {
+            word |= (qx[k] & 0xfu) << (4u * k);
+            word |= (qy[k] & 0xfu) << (4u * k + 16u);
+            bits |= (qx[k] >> 4u) << (8u * i + k);
+            bits |= (qy[k] >> 4u) << (8u * i + k + 4u);
+        }
+        output[bti * KQUANT_BLOCK_STEP + i] = word;
+    }
+#ifdef KQUANT_Q5
+    high[bti] = bits;
+#endif
+}
@@ -0,0 +1,90 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> source: View;                            // [C, S, B]
+@group(0) @binding(1) var<uniform> destination: View;                       // [C, S, B]
+
+#ifdef QUANT
+@group(0) @binding(2) var<storage, read> input: array<vec4<f32>>;           // (B, S, C)
+@group(0) @binding(3) var<storage, read_write> scale: array<f32>;           // (B, S)
+@group(0) @binding(4) var<storage, read_write> output: array<u32>;          // (B, S, C)
+#else
+@group(0) @binding(2) var<storage, read> input: array<u32>;                 // (B, S, C)
+@group(0) @binding(3) var<storage, read> scale: array<f32>;                 // (B, S)
+@group(0) @binding(4) var<storage, read_write> output: array<vec4<f32>>;    // (B, S, C)
+#endif
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+// the scales are laid out as the rows of the codes
+fn compute_scale(view: View, batch: u32, token: u32) -> u32 {
+    return (batch + view.offset.z) * view.stride.y + token + view.offset.y;
+}
+
+#ifdef QUANT
+var<workgroup> sketch: array<f32, BLOCK_SIZE>;
+
+fn reduce_max(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] = max(sketch[index], sketch[index + stride]);
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn quantize(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    var _max = 0.0;
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let x = abs(input[compute_index(source, batch, token, i)]);
+        _max = max(_max, max(max(x.x, x.y), max(x.z, x.w)));
+    }
+    sketch[index] = _max;
+    workgroupBarrier();
+
+    reduce_max(index, 64u);
+    reduce_max(index, 32u);
+    reduce_max(index, 16u);
+    reduce_max(index, 8u);
+    reduce_max(index, 4u);
+    reduce_max(index, 2u);
+    reduce_max(index, 1u);
+
+    let absmax = sketch[0];
+    if index == 0u {
+        scale[compute_scale(destination, batch, token)] = absmax;
+    }
+
+    let factor = select(0.0, 1.0 / absmax, absmax > 0.0);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let x = input[compute_index(source, batch, token, i)];
+        output[compute_index(destination, batch, token, i)] = pack4x8snorm(x * factor);
+    }
+}
+
+#else
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn dequantize(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let absmax = scale[compute_scale(source, batch, token)];
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let x = unpack4x8snorm(input[compute_index(source, batch, token, i)]);
+        output[compute_index(destination, batch, token, i)] = x * absmax;
+    }
+}
+#endif
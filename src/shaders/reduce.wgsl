@@ -0,0 +1,86 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> source: View;
+
+#ifdef IN_FP16
+@group(0) @binding(1) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(1) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+@group(0) @binding(2) var<storage, read_write> output: array<f32>;          // (B, T)
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn combine(x: vec4<f32>, y: vec4<f32>) -> vec4<f32> {
+#ifdef OP_MAX
+    return max(x, y);
+#else
+    return x + y;
+#endif
+}
+
+fn reduce_step(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] = combine(sketch[index], sketch[index + stride]);
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn reduce(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = source.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+#ifdef OP_MAX
+    var value = vec4<f32>(-3.402823e+38);
+#else
+    var value = vec4<f32>(0.0);
+#endif
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+#ifdef IN_FP16
+        let x = unpack4x16float(input[compute_index(source, batch, token, i)]);
+#else
+        let x = input[compute_index(source, batch, token, i)];
+#endif
+        value = combine(value, x);
+    }
+    sketch[index] = value;
+    workgroupBarrier();
+
+    reduce_step(index, 64u);
+    reduce_step(index, 32u);
+    reduce_step(index, 16u);
+    reduce_step(index, 8u);
+    reduce_step(index, 4u);
+    reduce_step(index, 2u);
+    reduce_step(index, 1u);
+
+    if index == 0u {
+        let x = sketch[0];
+#ifdef OP_MAX
+        let y = max(max(x.x, x.y), max(x.z, x.w));
+#else
+        var y = dot(x, vec4<f32>(1.0));
+#endif
+#ifdef OP_MEAN
+        y /= f32(source.shape.x);
+#endif
+        output[batch * source.shape.y + token] = y;
+    }
+}
@@ -0,0 +1,268 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+
+#ifdef IN_FP16
+@group(0) @binding(1) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(1) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+@group(0) @binding(2) var<storage, read> params: array<vec4<f32>>;          // (B, T, 4)
+@group(0) @binding(3) var<storage, read_write> output: array<u32>;          // (B, T)
+#ifdef GATHER
+@group(0) @binding(4) var<storage, read> batches: array<u32>;               // (N)
+#endif
+
+var<workgroup> sketch: array<f32, BLOCK_SIZE>;
+var<workgroup> indices: array<u32, BLOCK_SIZE>;
+var<workgroup> sums: array<vec2<f32>, BLOCK_SIZE>;
+var<workgroup> option: vec4<f32>;
+var<workgroup> result: f32;
+var<workgroup> result_sum: vec2<f32>;
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load(index: u32) -> vec4<f32> {
+#ifdef IN_FP16
+    return unpack4x16float(input[index]);
+#else
+    return input[index];
+#endif
+}
+
+fn reduce_argmax(index: u32, stride: u32) {
+    if index < stride {
+        let x = sketch[index];
+        let y = sketch[index + stride];
+        // ties resolve to the smaller index
+        if y > x || (y == x && indices[index + stride] < indices[index]) {
+            sketch[index] = y;
+            indices[index] = indices[index + stride];
+        }
+    }
+    workgroupBarrier();
+}
+
+fn reduce_min(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] = min(sketch[index], sketch[index + stride]);
+    }
+    workgroupBarrier();
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sums[index] += sums[index + stride];
+    }
+    workgroupBarrier();
+}
+
+// total weight and number of the tokens whose scaled logit is at least `threshold`
+fn mass(index: u32, bb: u32, start: u32, end: u32, x_max: f32, temperature: f32, threshold: f32) -> vec2<f32> {
+    var sum = vec2<f32>(0.0);
+    for (var i = start; i < end; i += 1u) {
+        let s = (load(bb + i) - x_max) / temperature;
+        let keep = s >= vec4<f32>(threshold);
+        sum += vec2<f32>(
+            dot(select(vec4<f32>(0.0), exp(s), keep), vec4<f32>(1.0)),
+            dot(select(vec4<f32>(0.0), vec4<f32>(1.0), keep), vec4<f32>(1.0)),
+        );
+    }
+    sums[index] = sum;
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        result_sum = sums[0];
+    }
+    return workgroupUniformLoad(&result_sum);
+}
+
+#ifdef EXACT
+// a token is kept if the tokens ranked before it weigh less than the target, and are fewer than `top_k`
+fn kept(bb: u32, stride: u32, x_max: f32, temperature: f32, target_mass: f32, top_k: f32, s: f32, token: u32) -> bool {
+    var before = vec2<f32>(0.0);
+    for (var i = 0u; i < stride; i += 1u) {
+        let t = (load(bb + i) - x_max) / temperature;
+        for (var k = 0u; k < 4u; k += 1u) {
+            if t[k] > s || (t[k] == s && (i << 2u) + k < token) {
+                before += vec2<f32>(exp(t[k]), 1.0);
+            }
+        }
+    }
+    return before[1] < top_k && (before[0] == 0.0 || before[0] < target_mass);
+}
+#endif
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn sample(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let row = batch * shape[1] + token;
+#ifdef GATHER
+    let bb = (batches[batch] * shape[1] + token) * stride;
+#else
+    let bb = row * stride;
+#endif
+
+    // each thread owns a contiguous chunk, so that the chunks are in token order
+    let chunk = (stride + BLOCK_SIZE - 1u) / BLOCK_SIZE;
+    let start = min(index * chunk, stride);
+    let end = min(start + chunk, stride);
+
+    if index == 0u {
+        option = params[row];
+    }
+    let param = workgroupUniformLoad(&option);
+    let temperature = param[0];
+    let top_p = param[1];
+    let random = param[2];
+    // no limit on the number of tokens if zero
+    let top_k = select(param[3], 3.40282347e38, param[3] <= 0.0);
+
+    var _max = -3.40282347e38;
+    var _min = 3.40282347e38;
+    var _index = 0u;
+    for (var i = start; i < end; i += 1u) {
+        let value = load(bb + i);
+        for (var k = 0u; k < 4u; k += 1u) {
+            if value[k] > _max {
+                _max = value[k];
+                _index = (i << 2u) + k;
+            }
+        }
+        _min = min(_min, min(min(value[0], value[1]), min(value[2], value[3])));
+    }
+    sketch[index] = _max;
+    indices[index] = _index;
+    workgroupBarrier();
+
+    reduce_argmax(index, 64u);
+    reduce_argmax(index, 32u);
+    reduce_argmax(index, 16u);
+    reduce_argmax(index, 8u);
+    reduce_argmax(index, 4u);
+    reduce_argmax(index, 2u);
+    reduce_argmax(index, 1u);
+
+    if index == 0u {
+        result = sketch[0];
+    }
+    let x_max = workgroupUniformLoad(&result);
+
+    if temperature <= 0.0 || top_k <= 1.0 {
+        if index == 0u {
+            output[row] = indices[0];
+        }
+        return;
+    }
+
+    sketch[index] = _min;
+    workgroupBarrier();
+
+    reduce_min(index, 64u);
+    reduce_min(index, 32u);
+    reduce_min(index, 16u);
+    reduce_min(index, 8u);
+    reduce_min(index, 4u);
+    reduce_min(index, 2u);
+    reduce_min(index, 1u);
+
+    if index == 0u {
+        result = sketch[0];
+    }
+    let s_min = (workgroupUniformLoad(&result) - x_max) / temperature;
+
+    let total = mass(index, bb, start, end, x_max, temperature, s_min)[0];
+    let target_mass = top_p * total;
+
+#ifndef EXACT
+    // bisect the threshold on scaled logits, keeping the tokens at or above `lo`:
+    // the threshold rises as long as either the tokens above weigh at least the target, or there are at least `top_k` of them
+    var lo = s_min;
+    var hi = 0.0;
+    var mass_lo = vec2<f32>(total, f32(shape[0]));
+    let mass_hi = mass(index, bb, start, end, x_max, temperature, hi);
+    if mass_hi[0] >= target_mass || mass_hi[1] >= top_k {
+        lo = hi;
+        mass_lo = mass_hi;
+    }
+    for (var i = 0u; i < MAX_ITER; i += 1u) {
+        if (mass_lo[0] - target_mass <= ERROR * total && mass_lo[1] <= top_k) || hi - lo <= 0.0 {
+            break;
+        }
+        let mid = 0.5 * (lo + hi);
+        let mass_mid = mass(index, bb, start, end, x_max, temperature, mid);
+        if mass_mid[0] >= target_mass || mass_mid[1] >= top_k {
+            lo = mid;
+            mass_lo = mass_mid;
+        } else {
+            hi = mid;
+        }
+    }
+#endif
+
+    // inverse transform sampling over the kept tokens, in token order
+    var sum = 0.0;
+    for (var i = start; i < end; i += 1u) {
+        let s = (load(bb + i) - x_max) / temperature;
+        for (var k = 0u; k < 4u; k += 1u) {
+#ifdef EXACT
+            let keep = kept(bb, stride, x_max, temperature, target_mass, top_k, s[k], (i << 2u) + k);
+#else
+            let keep = s[k] >= lo;
+#endif
+            if keep {
+                sum += exp(s[k]);
+            }
+        }
+    }
+    workgroupBarrier();
+    sketch[index] = sum;
+    workgroupBarrier();
+
+    var prefix = 0.0;
+    var kept_total = 0.0;
+    for (var j = 0u; j < BLOCK_SIZE; j += 1u) {
+        if j == index {
+            prefix = kept_total;
+        }
+        kept_total += sketch[j];
+    }
+
+    let r = random * kept_total;
+    let last = prefix + sum >= kept_total;
+    if sum > 0.0 && r >= prefix && (r < prefix + sum || last) {
+        var acc = prefix;
+        var pick = 0u;
+        for (var i = start; i < end; i += 1u) {
+            let s = (load(bb + i) - x_max) / temperature;
+            for (var k = 0u; k < 4u; k += 1u) {
+#ifdef EXACT
+                let keep = kept(bb, stride, x_max, temperature, target_mass, top_k, s[k], (i << 2u) + k);
+#else
+                let keep = s[k] >= lo;
+#endif
+                if keep {
+                    acc += exp(s[k]);
+                    pick = (i << 2u) + k;
+                    if r < acc {
+                        output[row] = pick;
+                        return;
+                    }
+                }
+            }
+        }
+        output[row] = pick;
+    }
+}
@@ -0,0 +1,48 @@
+struct Cursor {
+    batch: u32,
+    token: u32,
+    len: u32,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, A, 1]
+@group(0) @binding(1) var<storage, read> cursors: array<vec2<u32>>;          // [2, A]
+@group(0) @binding(2) var<storage, read> factor: array<f32>;                // [B]
+
+#ifdef FP16
+@group(0) @binding(3) var<storage, read_write> x: array<vec2<u32>>;         // (1, A, C)
+#else
+@group(0) @binding(3) var<storage, read_write> x: array<vec4<f32>>;         // (1, A, C)
+#endif
+
+fn compute_cursor(x: vec2<u32>) -> Cursor {
+    var cursor: Cursor;
+    cursor.batch = x.x;
+    cursor.token = x.y & 0xffffu;
+    cursor.len = x.y >> 16u;
+    return cursor;
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn scale_batch(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let stack = invocation_id.y;
+
+    if index < stride {
+        let cursor = compute_cursor(cursors[stack]);
+        let bti = stack * stride + index;
+#ifdef FP16
+        x[bti] = pack4x16float(factor[cursor.batch] * unpack4x16float(x[bti]));
+#else
+        x[bti] = factor[cursor.batch] * x[bti];
+#endif
+    }
+}
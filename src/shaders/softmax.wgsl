@@ -5,6 +5,9 @@
 #else
 @group(0) @binding(1) var<storage, read_write> x: array<vec4<f32>>;         // (B, T, C)
 #endif
+#ifdef GATHER
+@group(0) @binding(2) var<storage, read> batches: array<u32>;               // (N)
+#endif
 
 var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
 var<workgroup> sum: f32;
@@ -37,7 +40,11 @@ fn softmax(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let stride = shape[0] / 4u;
     let index = invocation_id.x;
     let token = invocation_id.y;
+#ifdef GATHER
+    let batch = batches[invocation_id.z];
+#else
     let batch = invocation_id.z;
+#endif
 
     let bb = (batch * shape[1] + token) * stride;
 
@@ -21,6 +21,9 @@ struct View {
 #else
 @group(0) @binding(6) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
 #endif
+#ifdef BIAS
+@group(0) @binding(9) var<storage, read> bias: array<vec2<u32>>;            // (R)
+#endif
 
 const INT8_BLOCK_STEP: u32 = INT8_BLOCK_SIZE / 4u;
 const NUM_SUBGROUPS: u32 = BLOCK_SIZE / MIN_SUBGROUP_SIZE;
@@ -58,6 +61,10 @@ fn squared_relu(x: vec4<f32>) -> vec4<f32> {
     return p * p;
 }
 
+fn sigmoid(x: vec4<f32>) -> vec4<f32> {
+    return 1.0 / (1.0 + exp(-x));
+}
+
 @compute @workgroup_size(BLOCK_SIZE, 1, 1)
 fn matmul(
     @builtin(global_invocation_id) invocation_id: vec3<u32>,
@@ -128,12 +135,28 @@ fn matmul(
     if index == 0u {
         let btc = compute_index(destination, batch, token, channel);
         var out = sketch[0];
+#ifdef BIAS
+        out += unpack4x16float(bias[channel]);
+#endif
+#ifdef ACCUMULATE
+#ifdef OUT_FP16
+        out += unpack4x16float(output[btc]);
+#else
+        out += output[btc];
+#endif
+#endif
 #ifdef ACT_SQUARED_RELU
         out = squared_relu(out);
 #endif
 #ifdef ACT_TANH
         out = tanh(out);
 #endif
+#ifdef ACT_RELU
+        out = max(out, vec4<f32>(0.0));
+#endif
+#ifdef ACT_SIGMOID
+        out = sigmoid(out);
+#endif
 #ifdef OUT_FP16
         output[btc] = pack4x16float(out);
 #else
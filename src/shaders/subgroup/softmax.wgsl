@@ -5,6 +5,9 @@
 #else
 @group(0) @binding(1) var<storage, read_write> x: array<vec4<f32>>;         // (B, T, C)
 #endif
+#ifdef GATHER
+@group(0) @binding(2) var<storage, read> batches: array<u32>;               // (N)
+#endif
 
 const NUM_SUBGROUPS: u32 = BLOCK_SIZE / MIN_SUBGROUP_SIZE;
 
@@ -45,7 +48,11 @@ fn softmax(
     let stride = shape[0] / 4u;
     let index = invocation_id.x;
     let token = invocation_id.y;
+#ifdef GATHER
+    let batch = batches[invocation_id.z];
+#else
     let batch = invocation_id.z;
+#endif
 
     let bb = (batch * shape[1] + token) * stride;
 
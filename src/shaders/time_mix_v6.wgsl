@@ -17,7 +17,7 @@ struct Input {
 
 @group(0) @binding(0) var<uniform> shape: vec4<u32>;                    // [S, H, A]
 @group(0) @binding(1) var<uniform> view: View;                          // [C, S + 1, B]
-@group(0) @binding(2) var<storage, read> cursors: array<u32>;           // [A]
+@group(0) @binding(2) var<storage, read> cursors: array<vec2<u32>>;      // [2, A]
 
 @group(0) @binding(3) var<storage, read> time_decay: array<vec4<f32>>;  // (A, H, S)
 @group(0) @binding(4) var<storage, read> time_first: array<vec4<f32>>;  // (H, S)
@@ -46,12 +46,11 @@ fn compute_index(batch: u32, token: u32, index: u32) -> u32 {
     return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
 }
 
-fn compute_cursor(x: u32) -> Cursor {
-    // let unpacked = vec4<u32>(unpack4x8unorm(x) * 255.0 + 0.5);
+fn compute_cursor(x: vec2<u32>) -> Cursor {
     var cursor: Cursor;
-    cursor.batch = x & 0xffu;
-    cursor.token = (x >> 8u) & 0xffffu;
-    cursor.len = (x >> 24u) & 0xffu;
+    cursor.batch = x.x;
+    cursor.token = x.y & 0xffffu;
+    cursor.len = x.y >> 16u;
     return cursor;
 }
 
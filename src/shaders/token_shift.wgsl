@@ -13,7 +13,7 @@ struct Cursor {
 @group(0) @binding(0) var<uniform> vx: View;                                // [C, A, 1] | [C, A, I]
 @group(0) @binding(1) var<uniform> vt: View;                                // [C, 1, I] | [C, A, I]
 @group(0) @binding(2) var<uniform> vs: View;                                // [C, _, B] / [C, 5L, B]
-@group(0) @binding(3) var<storage, read> cursors: array<u32>;               // [A]
+@group(0) @binding(3) var<storage, read> cursors: array<vec2<u32>>;          // [2, A]
 
 #ifdef TIME_MIX_FP16
 @group(0) @binding(4) var<storage, read> time_mix: array<vec2<u32>>;        // (I, 1, C) | (I, A, C)
@@ -39,12 +39,11 @@ fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
     return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
 }
 
-fn compute_cursor(x: u32) -> Cursor {
-    // let unpacked = vec4<u32>(unpack4x8unorm(x) * 255.0 + 0.5);
+fn compute_cursor(x: vec2<u32>) -> Cursor {
     var cursor: Cursor;
-    cursor.batch = x & 0xffu;
-    cursor.token = (x >> 8u) & 0xffffu;
-    cursor.len = (x >> 24u) & 0xffu;
+    cursor.batch = x.x;
+    cursor.token = x.y & 0xffffu;
+    cursor.len = x.y >> 16u;
     return cursor;
 }
 
@@ -91,7 +90,7 @@ fn token_shift(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin
     let cursor = compute_cursor(cursors[stack]);
     let token = stack - cursor.token;
 
-    if any(vec3<u32>(index, stack, count) > stride) {
+    if any(vec3<u32>(index, stack, count) >= stride) {
         return;
     }
 
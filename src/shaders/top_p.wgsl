@@ -0,0 +1,72 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [K, T, B]
+@group(0) @binding(1) var<storage, read> param: array<f32>;                 // (B, T)
+@group(0) @binding(2) var<storage, read_write> x: array<f32>;               // (B, T, K)
+@group(0) @binding(3) var<storage, read_write> count: array<u32>;           // (B, T)
+
+var<workgroup> sketch: array<vec2<f32>, BLOCK_SIZE>;
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn top_p(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let row = batch * shape[1] + token;
+    let bb = row * shape[0];
+    let p = param[row];
+
+    // each thread owns a contiguous chunk, so that the chunks are in rank order
+    let chunk = (shape[0] + BLOCK_SIZE - 1u) / BLOCK_SIZE;
+    let start = min(index * chunk, shape[0]);
+    let end = min(start + chunk, shape[0]);
+
+    var sum = 0.0;
+    for (var i = start; i < end; i += 1u) {
+        sum += x[bb + i];
+    }
+    sketch[index] = vec2<f32>(sum, 0.0);
+    workgroupBarrier();
+
+    var prefix = 0.0;
+    for (var j = 0u; j < index; j += 1u) {
+        prefix += sketch[j][0];
+    }
+    workgroupBarrier();
+
+    // a token is kept if the tokens ranked before it weigh less than `top_p`; the first token is always kept
+    var kept = vec2<f32>(0.0);
+    var before = prefix;
+    for (var i = start; i < end; i += 1u) {
+        let value = x[bb + i];
+        if value > 0.0 && (i == 0u || before < p) {
+            kept += vec2<f32>(value, 1.0);
+        }
+        before += value;
+    }
+    sketch[index] = kept;
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    let total = sketch[0];
+    let num = u32(total[1]);
+    for (var i = start; i < end; i += 1u) {
+        x[bb + i] = select(0.0, x[bb + i] / total[0], i < num);
+    }
+    if index == 0u {
+        count[row] = num;
+    }
+}
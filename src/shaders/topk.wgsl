@@ -0,0 +1,104 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+@group(0) @binding(1) var<uniform> shape_o: vec4<u32>;                      // [K, T, B]
+
+#ifdef IN_FP16
+@group(0) @binding(2) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(2) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+@group(0) @binding(3) var<storage, read_write> values: array<f32>;          // (B, T, K)
+@group(0) @binding(4) var<storage, read_write> indices: array<u32>;         // (B, T, K)
+
+const NONE: u32 = 0xffffffffu;
+
+var<workgroup> sketch: array<f32, BLOCK_SIZE>;
+var<workgroup> candidates: array<u32, BLOCK_SIZE>;
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load(bb: u32, i: u32) -> vec4<f32> {
+#ifdef IN_FP16
+    return unpack4x16float(input[bb + i]);
+#else
+    return input[bb + i];
+#endif
+}
+
+// if token `i` of value `x` ranks before token `j` of value `y`: larger values first, and ties to the smaller index
+fn before(x: f32, i: u32, y: f32, j: u32) -> bool {
+    return j == NONE || (i != NONE && (x > y || (x == y && i < j)));
+}
+
+fn reduce_best(index: u32, stride: u32) {
+    if index < stride {
+        let x = sketch[index];
+        let y = sketch[index + stride];
+        let i = candidates[index];
+        let j = candidates[index + stride];
+        if before(y, j, x, i) {
+            sketch[index] = y;
+            candidates[index] = j;
+        }
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn topk(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bb = (batch * shape[1] + token) * stride;
+    let ob = (batch * shape_o[1] + token) * shape_o[0];
+
+    // each thread keeps its best token ranking after the last one picked;
+    // after a pick, only the thread holding it looks for its next
+    var last = -3.40282347e38;
+    var last_index = NONE;
+    var value = 0.0;
+    var candidate = NONE;
+    var scan = true;
+
+    for (var n = 0u; n < shape_o[0]; n += 1u) {
+        if scan {
+            value = 0.0;
+            candidate = NONE;
+            for (var i = index; i < stride; i += BLOCK_SIZE) {
+                let x = load(bb, i);
+                for (var k = 0u; k < 4u; k += 1u) {
+                    let t = (i << 2u) + k;
+                    let after = last_index == NONE || before(last, last_index, x[k], t);
+                    if after && before(x[k], t, value, candidate) {
+                        value = x[k];
+                        candidate = t;
+                    }
+                }
+            }
+        }
+        sketch[index] = value;
+        candidates[index] = candidate;
+        workgroupBarrier();
+
+        reduce_best(index, 64u);
+        reduce_best(index, 32u);
+        reduce_best(index, 16u);
+        reduce_best(index, 8u);
+        reduce_best(index, 4u);
+        reduce_best(index, 2u);
+        reduce_best(index, 1u);
+
+        last = sketch[0];
+        last_index = candidates[0];
+        workgroupBarrier();
+
+        if index == 0u {
+            values[ob + n] = last;
+            indices[ob + n] = last_index;
+        }
+        scan = last_index == candidate;
+    }
+}
@@ -60,6 +60,15 @@ where
         }
     }
 
+    /// Release values that are not checked out.
+    pub fn compact(&self) {
+        let mut map = self.map.write().unwrap();
+        for items in map.values_mut() {
+            items.retain(|item| item.ref_count() > 1);
+        }
+        map.retain(|_, items| !items.is_empty());
+    }
+
     /// Release all values.
     pub fn clear(&self) {
         let mut map = self.map.write().unwrap();
@@ -0,0 +1,241 @@
+//! Typed dimensions over [`Shape`], for code building custom ops on top of the dynamic tensor API.
+//!
+//! The axes of a [`TypedShape`] or a [`Typed`] tensor are marker types, so that passing a `[T, C, B]` tensor
+//! where a `[C, T, B]` one is expected fails to compile:
+//! ```compile_fail
+//! use web_rwkv::tensor::dims::{Batch, BatchShape, Channel, Dim, Token, TypedShape};
+//!
+//! fn op(shape: BatchShape) {}
+//!
+//! let (c, t, b) = (Dim::<Channel>::new(768), Dim::<Token>::new(32), Dim::<Batch>::new(4));
+//! op(TypedShape::new(t, c, b));
+//! ```
+
+use std::{fmt::Debug, hash::Hash, marker::PhantomData, ops::Deref};
+
+use super::{shape::Shape, TensorError, TensorInitContext, TensorShape};
+use crate::{context::Context, num::Scalar};
+
+/// Marker type of an axis.
+pub trait Axis: Debug + Clone + Copy + PartialEq + Eq + Hash + 'static {
+    const NAME: &'static str;
+    /// The size of the axis if it is fixed.
+    const SIZE: Option<usize> = None;
+}
+
+/// Channels of embeddings or hidden states, i.e., the `C` axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Channel;
+
+/// Tokens, i.e., the `T` axis, or the `A` axis of tokens of all batches stacked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Token;
+
+/// Batches, i.e., the `B` axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Batch;
+
+/// An axis of size 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Unit;
+
+impl Axis for Channel {
+    const NAME: &'static str = "channel";
+}
+
+impl Axis for Token {
+    const NAME: &'static str = "token";
+}
+
+impl Axis for Batch {
+    const NAME: &'static str = "batch";
+}
+
+impl Axis for Unit {
+    const NAME: &'static str = "unit";
+    const SIZE: Option<usize> = Some(1);
+}
+
+/// The size of an axis `A`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Dim<A: Axis>(usize, PhantomData<A>);
+
+impl<A: Axis> Dim<A> {
+    pub fn new(size: usize) -> Self {
+        Self(size, PhantomData)
+    }
+
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl<A: Axis> From<Dim<A>> for usize {
+    fn from(value: Dim<A>) -> Self {
+        value.0
+    }
+}
+
+/// A [`Shape`] whose axes are `X`, `Y`, `Z` and `W`, from the fastest-moving to the slowest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TypedShape<X: Axis, Y: Axis, Z: Axis, W: Axis = Unit> {
+    shape: Shape,
+    phantom: PhantomData<(X, Y, Z, W)>,
+}
+
+/// `[C, T, B]`: tokens of each batch, e.g., the input or output of a runtime.
+pub type BatchShape = TypedShape<Channel, Token, Batch>;
+/// `[C, A, 1]`: tokens of all batches stacked, e.g., the hidden states of a runtime's buffer.
+pub type StackShape = TypedShape<Channel, Token, Unit>;
+
+impl<X: Axis, Y: Axis, Z: Axis> TypedShape<X, Y, Z> {
+    pub fn new(x: Dim<X>, y: Dim<Y>, z: Dim<Z>) -> Self {
+        Self::from_dims(x, y, z, Dim::new(1))
+    }
+}
+
+impl<X: Axis, Y: Axis, Z: Axis, W: Axis> TypedShape<X, Y, Z, W> {
+    pub fn from_dims(x: Dim<X>, y: Dim<Y>, z: Dim<Z>, w: Dim<W>) -> Self {
+        Self {
+            shape: Shape::new(x.0, y.0, z.0, w.0),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Interpret a dynamic shape. Fails if an axis of fixed size (e.g., [`Unit`]) does not match.
+    pub fn from_shape(shape: Shape) -> Result<Self, TensorError> {
+        let mut expected = shape;
+        for (index, size) in [X::SIZE, Y::SIZE, Z::SIZE, W::SIZE].into_iter().enumerate() {
+            if let Some(size) = size {
+                expected[index] = size;
+            }
+        }
+        match expected == shape {
+            true => Ok(Self {
+                shape,
+                phantom: PhantomData,
+            }),
+            false => Err(TensorError::Shape(shape, expected)),
+        }
+    }
+
+    pub fn shape(&self) -> Shape {
+        self.shape
+    }
+
+    pub fn x(&self) -> Dim<X> {
+        Dim::new(self.shape[0])
+    }
+
+    pub fn y(&self) -> Dim<Y> {
+        Dim::new(self.shape[1])
+    }
+
+    pub fn z(&self) -> Dim<Z> {
+        Dim::new(self.shape[2])
+    }
+
+    pub fn w(&self) -> Dim<W> {
+        Dim::new(self.shape[3])
+    }
+
+    /// Swap the first two axes, e.g., to describe the output of a transposing op.
+    pub fn swap_xy(self) -> TypedShape<Y, X, Z, W> {
+        let [x, y, z, w] = *self.shape;
+        TypedShape {
+            shape: Shape::new(y, x, z, w),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<X: Axis, Y: Axis, Z: Axis, W: Axis> From<TypedShape<X, Y, Z, W>> for Shape {
+    fn from(value: TypedShape<X, Y, Z, W>) -> Self {
+        value.shape
+    }
+}
+
+/// A tensor whose axes are typed. It dereferences to the tensor, so the dynamic API is still available.
+#[derive(Debug, Clone)]
+pub struct Typed<T, X: Axis, Y: Axis, Z: Axis, W: Axis = Unit> {
+    tensor: T,
+    phantom: PhantomData<(X, Y, Z, W)>,
+}
+
+impl<T: TensorShape, X: Axis, Y: Axis, Z: Axis, W: Axis> Typed<T, X, Y, Z, W> {
+    /// Type the axes of a tensor. Fails if an axis of fixed size does not match.
+    pub fn new(tensor: T) -> Result<Self, TensorError> {
+        TypedShape::<X, Y, Z, W>::from_shape(tensor.shape())?;
+        Ok(Self {
+            tensor,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Create a tensor of the given shape.
+    pub fn init<S: Scalar>(context: &Context, shape: TypedShape<X, Y, Z, W>) -> Self
+    where
+        T: TensorInitContext<S>,
+    {
+        Self {
+            tensor: T::init(context, shape),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn shape(&self) -> TypedShape<X, Y, Z, W> {
+        TypedShape {
+            shape: self.tensor.shape(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn check_shape(&self, shape: TypedShape<X, Y, Z, W>) -> Result<(), TensorError> {
+        self.tensor.check_shape(shape)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.tensor
+    }
+}
+
+impl<T, X: Axis, Y: Axis, Z: Axis, W: Axis> Deref for Typed<T, X, Y, Z, W> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tensor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{Batch, BatchShape, Channel, Dim, StackShape, Token, Typed, TypedShape, Unit};
+    use crate::tensor::{shape::Shape, TensorCpu, TensorInit, TensorShape};
+
+    #[test]
+    fn test_typed_shape() -> Result<()> {
+        let c = Dim::<Channel>::new(8);
+        let t = Dim::<Token>::new(3);
+        let b = Dim::<Batch>::new(2);
+
+        let shape: BatchShape = TypedShape::new(c, t, b);
+        assert_eq!(Shape::from(shape), Shape::new(8, 3, 2, 1));
+        assert_eq!((shape.x(), shape.y(), shape.z()), (c, t, b));
+        assert_eq!(shape.swap_xy().shape(), Shape::new(3, 8, 2, 1));
+
+        assert!(StackShape::from_shape(Shape::new(8, 6, 1, 1)).is_ok());
+        assert!(StackShape::from_shape(Shape::new(8, 3, 2, 1)).is_err());
+
+        let tensor = TensorCpu::<f32>::from_data(shape, vec![0.0; 48])?;
+        let tensor = Typed::<_, Channel, Token, Batch>::new(tensor)?;
+        assert_eq!(tensor.shape(), shape);
+        tensor.check_shape(shape)?;
+        assert_eq!(tensor.into_inner().shape(), Shape::new(8, 3, 2, 1));
+
+        let tensor = TensorCpu::<f32>::from_data(shape, vec![0.0; 48])?;
+        assert!(Typed::<_, Channel, Token, Unit>::new(tensor).is_err());
+        Ok(())
+    }
+}
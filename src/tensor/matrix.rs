@@ -2,7 +2,10 @@ use half::f16;
 use serde::Serialize;
 use web_rwkv_derive::DeserializeSeed;
 
-use super::{ops::Activation, TensorCpu, TensorInit, TensorInto};
+use super::{
+    ops::{Activation, Epilogue, Fp8Format, KQuantFormat, LogitTransform, LoraDelta},
+    TensorCpu, TensorInit, TensorInto,
+};
 use crate::{
     num::Float,
     tensor::{
@@ -42,63 +45,288 @@ impl Default for Nf4Quant {
     }
 }
 
+/// A weight matrix of logical shape `[C, R, B]`, either in half precision or quantized.
+///
+/// The quantized variants are blockwise along `C`; the tensors are public (to be treated as read-only) for custom kernels,
+/// and their layouts are documented on each field. Within each block, `x` is the dequantized value of an element.
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
 pub enum Matrix {
+    /// Shape `[C, R, B]`.
     Fp16(TensorGpu<f16, ReadWrite>),
+    /// Blocks of [`TensorOp::INT8_BLOCK_SIZE`] elements, `x = min + (max - min) * w / 255`.
     Int8 {
+        /// Shape `[C, R, B]`, one unsigned code per element.
         w: TensorGpu<u8, ReadWrite>,
+        /// Shape `[2C / INT8_BLOCK_SIZE, R, B]`, the `(min, max)` pair of each block.
         m: TensorGpu<f16, ReadWrite>,
     },
+    /// Whole rows as blocks, `x = w * absmax / 127`, e.g., for the head, whose rows are as many as the vocabulary.
+    /// The head matmul of this format applies a [`LogitTransform`] in the same kernel, see [`Matrix::matmul_logits_op`].
+    Int8Row {
+        /// Shape `[C, R, B]`, one signed code per element.
+        w: TensorGpu<u8, ReadWrite>,
+        /// Shape `[R, 1, B]`, the absolute maximum of each row.
+        s: TensorGpu<f32, ReadWrite>,
+    },
+    /// Blocks of [`TensorOp::NF4_BLOCK_SIZE`] elements, `x = q[w] * absmax`.
     NF4 {
+        /// Shape `[16, 1, 1]`, the NormalFloat4 levels (see [`Nf4Quant`]).
         q: TensorGpu<f32, Uniform>,
+        /// Shape `[C / 2, R, B]`, two 4-bit codes per byte, the even element in the low nibble.
+        w: TensorGpu<u8, ReadWrite>,
+        /// Shape `[C / NF4_BLOCK_SIZE, R, B]`, the absolute maximum of each block.
+        m: TensorGpu<f16, ReadWrite>,
+    },
+    /// Blocks of [`TensorOp::FP8_BLOCK_SIZE`] elements, `x = fp8(w) * absmax / fp8_max`, where `fp8_max` is the largest value of `format`.
+    Fp8 {
+        format: Fp8Format,
+        /// Shape `[C, R, B]`, one 8-bit float per element in `format`.
+        w: TensorGpu<u8, ReadWrite>,
+        /// Shape `[C / FP8_BLOCK_SIZE, R, B]`, the absolute maximum of each block.
+        m: TensorGpu<f16, ReadWrite>,
+    },
+    /// Super-blocks of [`TensorOp::KQUANT_SUPER_BLOCK_SIZE`] elements, each of sub-blocks of [`TensorOp::KQUANT_BLOCK_SIZE`].
+    Q4K {
+        /// Shape `[C / 2, R, B]`, two 4-bit codes per byte.
+        w: TensorGpu<u8, ReadWrite>,
+        /// Shape `[2C / KQUANT_BLOCK_SIZE, R, B]`, the 8-bit `(scale, min)` pair of each sub-block.
+        s: TensorGpu<u8, ReadWrite>,
+        /// Shape `[2C / KQUANT_SUPER_BLOCK_SIZE, R, B]`, the `(scale, min)` pair of each super-block.
+        m: TensorGpu<f16, ReadWrite>,
+    },
+    /// Like [`Matrix::Q4K`], with a fifth bit for each code.
+    Q5K {
+        /// Shape `[C / 2, R, B]`, the low 4 bits of two codes per byte.
         w: TensorGpu<u8, ReadWrite>,
+        /// Shape `[C / 8, R, B]`, the high bit of eight codes per byte.
+        h: TensorGpu<u8, ReadWrite>,
+        /// Shape `[2C / KQUANT_BLOCK_SIZE, R, B]`, the 8-bit `(scale, min)` pair of each sub-block.
+        s: TensorGpu<u8, ReadWrite>,
+        /// Shape `[2C / KQUANT_SUPER_BLOCK_SIZE, R, B]`, the `(scale, min)` pair of each super-block.
         m: TensorGpu<f16, ReadWrite>,
     },
 }
 
 impl Matrix {
-    pub fn matmul_vec_op(
+    /// The logical shape `[C, R, B]` of the matrix, before quantization.
+    pub fn shape(&self) -> Shape {
+        match self {
+            Matrix::Fp16(matrix) => matrix.shape(),
+            Matrix::Int8 { w, .. } | Matrix::Int8Row { w, .. } | Matrix::Fp8 { w, .. } => w.shape(),
+            Matrix::NF4 { w, .. } | Matrix::Q4K { w, .. } | Matrix::Q5K { w, .. } => {
+                let shape = w.shape();
+                Shape::new(shape[0] * 2, shape[1], shape[2], shape[3])
+            }
+        }
+    }
+
+    /// Number of elements along `C` that share the scales of a block, or `None` if the matrix is not quantized.
+    /// For k-quants, this is the size of a super-block.
+    pub fn block_size(&self) -> Option<usize> {
+        let size = match self {
+            Matrix::Fp16(_) => return None,
+            Matrix::Int8 { .. } => TensorOp::INT8_BLOCK_SIZE,
+            Matrix::Int8Row { w, .. } => return Some(w.shape()[0]),
+            Matrix::NF4 { .. } => TensorOp::NF4_BLOCK_SIZE,
+            Matrix::Fp8 { .. } => TensorOp::FP8_BLOCK_SIZE,
+            Matrix::Q4K { .. } | Matrix::Q5K { .. } => TensorOp::KQUANT_SUPER_BLOCK_SIZE,
+        };
+        Some(size as usize)
+    }
+
+    /// The quantized codes of the matrix, or `None` if it is not quantized.
+    pub fn codes(&self) -> Option<&TensorGpu<u8, ReadWrite>> {
+        match self {
+            Matrix::Fp16(_) => None,
+            Matrix::Int8 { w, .. }
+            | Matrix::Int8Row { w, .. }
+            | Matrix::NF4 { w, .. }
+            | Matrix::Fp8 { w, .. }
+            | Matrix::Q4K { w, .. }
+            | Matrix::Q5K { w, .. } => Some(w),
+        }
+    }
+
+    /// The half-precision block statistics of the matrix (e.g., `(min, max)` or absmax), or `None` if it is not quantized.
+    /// The row scales of [`Matrix::Int8Row`] are in single precision, and not returned here.
+    pub fn scales(&self) -> Option<&TensorGpu<f16, ReadWrite>> {
+        match self {
+            Matrix::Fp16(_) | Matrix::Int8Row { .. } => None,
+            Matrix::Int8 { m, .. }
+            | Matrix::NF4 { m, .. }
+            | Matrix::Fp8 { m, .. }
+            | Matrix::Q4K { m, .. }
+            | Matrix::Q5K { m, .. } => Some(m),
+        }
+    }
+
+    pub fn matmul_vec_op<'a>(
+        &self,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+    ) -> Result<TensorOp, TensorError> {
+        match self {
+            Matrix::Fp16(matrix) => TensorOp::matmul_vec_fp16(matrix, input, output, epilogue),
+            Matrix::Int8 { w, m } => TensorOp::matmul_vec_int8(w, m, input, output, epilogue),
+            Matrix::Int8Row { w, s } => {
+                TensorOp::matmul_vec_int8_row(w, s, input, output, epilogue, Default::default())
+            }
+            Matrix::NF4 { w, q, m } => TensorOp::matmul_vec_nf4(w, q, m, input, output, epilogue),
+            Matrix::Fp8 { format, w, m } => {
+                TensorOp::matmul_vec_fp8(w, m, *format, input, output, epilogue)
+            }
+            Matrix::Q4K { w, s, m } => {
+                TensorOp::matmul_vec_kquant(w, m, s, None, input, output, epilogue)
+            }
+            Matrix::Q5K { w, h, s, m } => {
+                TensorOp::matmul_vec_kquant(w, m, s, Some(h), input, output, epilogue)
+            }
+        }
+    }
+
+    /// Matrix-vector multiplication with the low-rank `delta` of a LoRA added to the product, before the activation.
+    ///
+    /// [`Matrix::Int8`] and [`Matrix::NF4`] add the delta in their own kernels, so that the LoRA stays in fp16
+    /// next to the quantized weights; other formats accumulate it into the output with a matmul of its own.
+    pub fn matmul_vec_lora_op<'a>(
         &self,
         input: TensorGpuView<impl Float>,
         output: TensorGpuView<impl Float>,
-        active: Activation,
+        epilogue: impl Into<Epilogue<'a>>,
+        delta: LoraDelta,
     ) -> Result<TensorOp, TensorError> {
+        let epilogue = epilogue.into();
         match self {
-            Matrix::Fp16(matrix) => TensorOp::matmul_vec_fp16(matrix, input, output, active),
-            Matrix::Int8 { w, m } => TensorOp::matmul_vec_int8(w, m, input, output, active),
-            Matrix::NF4 { w, q, m } => TensorOp::matmul_vec_nf4(w, q, m, input, output, active),
+            Matrix::Int8 { w, m } => {
+                TensorOp::matmul_vec_int8_lora(w, m, input, output, epilogue, Some(delta))
+            }
+            Matrix::NF4 { w, q, m } => {
+                TensorOp::matmul_vec_nf4_lora(w, q, m, input, output, epilogue, Some(delta))
+            }
+            _ => Ok(TensorOp::List(vec![
+                self.matmul_vec_op(
+                    input,
+                    output.clone(),
+                    Epilogue {
+                        activation: Activation::None,
+                        ..epilogue
+                    },
+                )?,
+                TensorOp::matmul_vec_fp16(
+                    delta.factor,
+                    delta.hidden.view(.., .., .., ..)?,
+                    output,
+                    Epilogue::new(epilogue.activation).accumulate(true),
+                )?,
+            ])),
         }
     }
 
-    pub fn matmul_mat_op(
+    pub fn matmul_mat_op<'a>(
         &self,
         input: TensorGpuView<impl Float>,
         output: TensorGpuView<impl Float>,
-        active: Activation,
+        epilogue: impl Into<Epilogue<'a>>,
     ) -> Result<TensorOp, TensorError> {
         match self {
             Matrix::Fp16(matrix) => {
-                TensorOp::matmul_mat_fp16(matrix.view(.., .., .., ..)?, input, output, active)
+                TensorOp::matmul_mat_fp16(matrix.view(.., .., .., ..)?, input, output, epilogue)
             }
             Matrix::Int8 { w, m } => {
-                TensorOp::matmul_mat_int8(w.view(.., .., .., ..)?, m, input, output, active)
+                TensorOp::matmul_mat_int8(w.view(.., .., .., ..)?, m, input, output, epilogue)
             }
+            // there is no matrix-matrix kernel for row scales, the head being its only use
+            Matrix::Int8Row { .. } => self.matmul_vec_op(input, output, epilogue),
             Matrix::NF4 { w, q, m } => {
-                TensorOp::matmul_mat_nf4(w.view(.., .., .., ..)?, q, m, input, output, active)
+                TensorOp::matmul_mat_nf4(w.view(.., .., .., ..)?, q, m, input, output, epilogue)
             }
+            Matrix::Fp8 { format, w, m } => TensorOp::matmul_mat_fp8(
+                w.view(.., .., .., ..)?,
+                m,
+                *format,
+                input,
+                output,
+                epilogue,
+            ),
+            Matrix::Q4K { w, s, m } => TensorOp::matmul_mat_kquant(
+                w.view(.., .., .., ..)?,
+                m,
+                s,
+                None,
+                input,
+                output,
+                epilogue,
+            ),
+            Matrix::Q5K { w, h, s, m } => TensorOp::matmul_mat_kquant(
+                w.view(.., .., .., ..)?,
+                m,
+                s,
+                Some(h),
+                input,
+                output,
+                epilogue,
+            ),
         }
     }
 
-    pub fn matmul_op(
+    pub fn matmul_op<'a>(
         &self,
         input: TensorGpuView<impl Float>,
         output: TensorGpuView<impl Float>,
-        active: Activation,
+        epilogue: impl Into<Epilogue<'a>>,
         turbo: bool,
     ) -> Result<TensorOp, TensorError> {
         match turbo {
-            true => self.matmul_mat_op(input, output, active),
-            false => self.matmul_vec_op(input, output, active),
+            true => self.matmul_mat_op(input, output, epilogue),
+            false => self.matmul_vec_op(input, output, epilogue),
+        }
+    }
+
+    /// The head matmul from `input` into the logits `output` of shape `[R, T]`, followed by `transform`.
+    ///
+    /// A [`Matrix::Int8Row`] applies the transform in the same kernel as the matmul,
+    /// saving passes over the vocabulary; other formats apply it with ops of its own after the matmul.
+    pub fn matmul_logits_op(
+        &self,
+        input: TensorGpuView<impl Float>,
+        output: &TensorGpu<f32, ReadWrite>,
+        turbo: bool,
+        transform: LogitTransform,
+    ) -> Result<TensorOp, TensorError> {
+        if let Matrix::Int8Row { w, s } = self {
+            let output = output.view(.., .., .., ..)?;
+            return TensorOp::matmul_vec_int8_row(w, s, input, output, Activation::None, transform);
+        }
+
+        let mut ops =
+            vec![self.matmul_op(input, output.view(.., .., .., ..)?, Activation::None, turbo)?];
+        if let Some((slots, bias)) = transform.bias {
+            ops.push(TensorOp::logit_bias(output, slots, bias)?);
+        }
+        if let Some(params) = transform.length {
+            ops.push(TensorOp::length_penalty(output, params)?);
+        }
+        if let Some((params, counts)) = transform.penalty {
+            ops.push(TensorOp::penalty(output, params, counts)?);
+        }
+        Ok(TensorOp::List(ops))
+    }
+
+    /// Write `matrix` into the buffers of this matrix in place, quantized in its format, e.g., to reload its weights.
+    /// - `matrix` shape: the shape of this matrix, see [`Matrix::shape`].
+    pub fn requant_op(&self, matrix: &TensorGpu<f16, ReadWrite>) -> Result<TensorOp, TensorError> {
+        matrix.check_shape(self.shape())?;
+        match self {
+            Matrix::Fp16(w) => {
+                TensorOp::blit(matrix.view(.., .., .., ..)?, w.view(.., .., .., ..)?)
+            }
+            Matrix::Int8 { w, m } => TensorOp::quantize_mat_int8(matrix, m, w),
+            Matrix::Int8Row { w, s } => TensorOp::quantize_mat_int8_row(matrix, s, w),
+            Matrix::NF4 { q, w, m } => TensorOp::quantize_mat_nf4(matrix, q, m, w),
+            Matrix::Fp8 { format, w, m } => TensorOp::quantize_mat_fp8(matrix, m, w, *format),
+            Matrix::Q4K { w, s, m } => TensorOp::quantize_mat_kquant(matrix, m, s, w, None),
+            Matrix::Q5K { w, h, s, m } => TensorOp::quantize_mat_kquant(matrix, m, s, w, Some(h)),
         }
     }
 
@@ -115,11 +343,25 @@ impl Matrix {
         ));
 
         let op = TensorOp::quantize_mat_int8(matrix, &m, &w)?;
-        context.queue.submit(context.encode(&op));
+        context.submit(context.encode(&op));
 
         Ok(Matrix::Int8 { w, m })
     }
 
+    /// Quantize to [`Matrix::Int8Row`], whose matmul has no matrix-matrix kernel and is meant for the head.
+    pub fn quant_int8_row(matrix: &TensorGpu<f16, ReadWrite>) -> Result<Self, TensorError> {
+        let context = matrix.context();
+        let shape = matrix.shape();
+
+        let w = context.tensor_init(shape);
+        let s = context.tensor_init([shape[1], 1, shape[2], 1]);
+
+        let op = TensorOp::quantize_mat_int8_row(matrix, &s, &w)?;
+        context.submit(context.encode(&op));
+
+        Ok(Matrix::Int8Row { w, s })
+    }
+
     pub fn quant_nf4(matrix: &TensorGpu<f16, ReadWrite>) -> Result<Self, TensorError> {
         let context = matrix.context();
         let shape = matrix.shape();
@@ -137,8 +379,65 @@ impl Matrix {
         let m = context.tensor_init(absmax_shape);
 
         let op = TensorOp::quantize_mat_nf4(matrix, &q, &m, &w)?;
-        context.queue.submit(context.encode(&op));
+        context.submit(context.encode(&op));
 
         Ok(Matrix::NF4 { w, q, m })
     }
+
+    pub fn quant_fp8(
+        matrix: &TensorGpu<f16, ReadWrite>,
+        format: Fp8Format,
+    ) -> Result<Self, TensorError> {
+        let context = matrix.context();
+        let shape = matrix.shape();
+
+        let w = context.tensor_init(shape);
+        let m = context.tensor_init(Shape::new(
+            shape[0] / TensorOp::FP8_BLOCK_SIZE as usize,
+            shape[1],
+            shape[2],
+            shape[3],
+        ));
+
+        let op = TensorOp::quantize_mat_fp8(matrix, &m, &w, format)?;
+        context.submit(context.encode(&op));
+
+        Ok(Matrix::Fp8 { format, w, m })
+    }
+
+    pub fn quant_kquant(
+        matrix: &TensorGpu<f16, ReadWrite>,
+        format: KQuantFormat,
+    ) -> Result<Self, TensorError> {
+        let context = matrix.context();
+        let shape = matrix.shape();
+
+        let w = context.tensor_init([shape[0] / 2, shape[1], shape[2], shape[3]]);
+        let s = context.tensor_init([
+            (shape[0] << 1) / TensorOp::KQUANT_BLOCK_SIZE as usize,
+            shape[1],
+            shape[2],
+            shape[3],
+        ]);
+        let m = context.tensor_init([
+            (shape[0] << 1) / TensorOp::KQUANT_SUPER_BLOCK_SIZE as usize,
+            shape[1],
+            shape[2],
+            shape[3],
+        ]);
+
+        match format {
+            KQuantFormat::Q4K => {
+                let op = TensorOp::quantize_mat_kquant(matrix, &m, &s, &w, None)?;
+                context.submit(context.encode(&op));
+                Ok(Matrix::Q4K { w, s, m })
+            }
+            KQuantFormat::Q5K => {
+                let h = context.tensor_init([shape[0] / 8, shape[1], shape[2], shape[3]]);
+                let op = TensorOp::quantize_mat_kquant(matrix, &m, &s, &w, Some(&h))?;
+                context.submit(context.encode(&op));
+                Ok(Matrix::Q5K { w, h, s, m })
+            }
+        }
+    }
 }
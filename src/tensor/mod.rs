@@ -10,11 +10,12 @@ use self::{
     shape::{IntoBytes, Shape, TensorAxis, TensorDimension, TensorSlice},
 };
 use crate::{
-    context::Context,
+    context::{ArenaBuffer, Context},
     num::{Float, Scalar},
 };
 
 pub mod cache;
+pub mod dims;
 pub mod matrix;
 pub mod ops;
 pub mod serialization;
@@ -25,7 +26,7 @@ pub mod shape;
 pub struct TensorGpuData {
     pub context: Context,
     pub meta: Arc<Buffer>,
-    pub buffer: Arc<Buffer>,
+    pub buffer: ArenaBuffer,
 }
 
 impl TensorGpuData {
@@ -113,6 +114,10 @@ pub enum TensorError {
     SliceInvalid,
     #[error("cannot split along the axis {0}")]
     SplitInvalid(usize),
+    #[error("cannot copy between batches of the same buffer")]
+    SameBuffer,
+    #[error("{0:?} is not a permutation of the axes 0, 1 and 2")]
+    Permute([usize; 3]),
 }
 
 /// Data defining a tensor view in shader.
@@ -143,11 +148,17 @@ pub struct Cursor {
 }
 
 impl Cursor {
-    pub fn pack(self) -> u32 {
-        let batch = self.batch as u8;
-        let token = (self.token as u16).to_ne_bytes();
-        let len = self.len as u8;
-        bytemuck::cast([batch, token[0], token[1], len])
+    /// Number of `u32` words of a packed cursor.
+    pub const PACKED_SIZE: usize = 2;
+
+    /// Pack into the batch index, followed by the token offset and the length in 16 bits each.
+    /// The number of batches is not limited, but a chunk must not exceed 65535 tokens.
+    pub fn pack(self) -> [u32; Self::PACKED_SIZE] {
+        debug_assert!(self.token + self.len <= u16::MAX as usize);
+        let batch = self.batch as u32;
+        let token = self.token as u16 as u32;
+        let len = self.len as u16 as u32;
+        [batch, token | (len << 16)]
     }
 }
 
@@ -160,7 +171,7 @@ impl IntoPackedCursors for Vec<Cursor> {
     fn into_stack(self) -> Vec<u32> {
         self.into_iter()
             .filter(|cursor| cursor.len > 0)
-            .map(Cursor::pack)
+            .flat_map(Cursor::pack)
             .collect()
     }
 
@@ -169,7 +180,7 @@ impl IntoPackedCursors for Vec<Cursor> {
             .filter(|cursor| cursor.len > 0)
             .map(|cursor| {
                 let repeat = cursor.len;
-                vec![cursor.pack(); repeat]
+                cursor.pack().repeat(repeat)
             })
             .collect_vec()
             .concat()
@@ -486,11 +497,18 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
 
         let mut encoder = context.device.create_command_encoder(&Default::default());
         encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, size);
-        context.queue.submit(Some(encoder.finish()));
+        context.submit(Some(encoder.finish()));
 
-        let (sender, receiver) = tokio::sync::oneshot::channel();
-        let _ = context.event().send(ContextEvent { buffer, sender });
-        let data = receiver.blocking_recv().unwrap();
+        let (sender, receiver) = flume::bounded(1);
+        let _ = context.event().send(
+            ContextEvent {
+                buffer,
+                sender,
+                index: None,
+            }
+            .into(),
+        );
+        let data = receiver.recv().unwrap();
         let data = unsafe {
             let data = Box::leak(data);
             let slice = bytemuck::cast_slice_mut::<_, T>(data);
@@ -519,12 +537,19 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
 
         let mut encoder = context.device.create_command_encoder(&Default::default());
         encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, size);
-        context.queue.submit(Some(encoder.finish()));
+        context.submit(Some(encoder.finish()));
 
-        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let (sender, receiver) = flume::bounded(1);
 
-        let _ = context.event().send(ContextEvent { buffer, sender });
-        let data = receiver.await.unwrap();
+        let _ = context.event().send(
+            ContextEvent {
+                buffer,
+                sender,
+                index: None,
+            }
+            .into(),
+        );
+        let data = receiver.recv_async().await.unwrap();
         let data = unsafe {
             let data = Box::leak(data);
             let slice = bytemuck::cast_slice_mut::<_, T>(data);
@@ -550,7 +575,7 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
 
         let mut encoder = context.device.create_command_encoder(&Default::default());
         encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, size);
-        context.queue.submit(Some(encoder.finish()));
+        context.submit(Some(encoder.finish()));
 
         let (sender, receiver) = flume::unbounded();
 
@@ -823,6 +848,71 @@ impl<F: Float> TensorGpuView<'_, F> {
     }
 }
 
+/// Like a reference to a tensor, but refer to an arbitrary list of its batches, in the given order.
+///
+/// Unlike [`TensorGpuView`], the batches need not be contiguous, so ops can run on a sparse subset of active batches
+/// without repacking them first. Batch `n` of the gather is batch `batches[n]` of the tensor.
+#[derive(Debug, Clone)]
+pub struct TensorGpuGather<'a, T: Scalar> {
+    tensor: &'a TensorGpu<T, ReadWrite>,
+    batches: Vec<usize>,
+    indices: TensorGpu<u32, ReadWrite>,
+}
+
+impl<T: Scalar> TensorShape for TensorGpuGather<'_, T> {
+    #[inline]
+    fn shape(&self) -> Shape {
+        let shape = self.tensor.shape;
+        Shape::new(shape[0], shape[1], self.batches.len(), shape[3])
+    }
+}
+
+impl<T: Scalar> TensorGpuGather<'_, T> {
+    #[inline]
+    pub fn tensor(&self) -> &TensorGpu<T, ReadWrite> {
+        self.tensor
+    }
+
+    #[inline]
+    pub fn context(&self) -> &Context {
+        self.tensor.context()
+    }
+
+    /// Batches of the tensor that are gathered.
+    #[inline]
+    pub fn batches(&self) -> &[usize] {
+        &self.batches
+    }
+
+    /// Binding of the shape of the whole tensor, which shaders index into.
+    #[inline]
+    pub fn meta_binding(&self) -> BindingResource<'_> {
+        self.tensor.meta_binding()
+    }
+
+    #[inline]
+    pub fn binding(&self) -> BindingResource<'_> {
+        self.tensor.binding()
+    }
+
+    /// Binding of the gathered batch indices, as `u32`.
+    #[inline]
+    pub fn batches_binding(&self) -> BindingResource<'_> {
+        self.indices.binding()
+    }
+}
+
+impl<T: Scalar> TensorScalar for TensorGpuGather<'_, T> {
+    type T = T;
+}
+
+impl<F: Float> TensorGpuGather<'_, F> {
+    #[inline]
+    pub const fn def(&self) -> &'static str {
+        F::DEF
+    }
+}
+
 impl<T: Scalar> TensorGpu<T, ReadWrite> {
     /// Create a view for the tensor.
     pub fn view(
@@ -848,6 +938,26 @@ impl<T: Scalar> TensorGpu<T, ReadWrite> {
             id,
         })
     }
+
+    /// Create a gather view over the given batches of the tensor. The list must not be empty, but may repeat batches.
+    pub fn gather(&self, batches: &[usize]) -> Result<TensorGpuGather<'_, T>, TensorError> {
+        if batches.is_empty() {
+            return Err(TensorError::Empty);
+        }
+        let max = self.shape[2];
+        if let Some(&batch) = batches.iter().find(|&&batch| batch >= max) {
+            return Err(TensorError::BatchOutOfRange { batch, max });
+        }
+        let data = batches.iter().map(|&batch| batch as u32).collect_vec();
+        let indices = self
+            .context
+            .tensor_from_data([batches.len(), 1, 1, 1], data)?;
+        Ok(TensorGpuGather {
+            tensor: self,
+            batches: batches.to_vec(),
+            indices,
+        })
+    }
 }
 
 impl<T: Scalar> DeepClone for TensorGpu<T, ReadWrite> {
@@ -859,7 +969,7 @@ impl<T: Scalar> DeepClone for TensorGpu<T, ReadWrite> {
 
         let mut encoder = context.device.create_command_encoder(&Default::default());
         encoder.copy_buffer_to_buffer(&self.buffer, 0, &cloned.buffer, 0, size);
-        context.queue.submit(Some(encoder.finish()));
+        context.submit(Some(encoder.finish()));
 
         cloned
     }
@@ -1,16 +1,19 @@
 use std::{hash::Hash, sync::Arc};
 
 use half::f16;
+use serde::{Deserialize, Serialize};
 use wgpu::{
     BindGroup, BindGroupDescriptor, BindGroupEntry, CommandBuffer, CommandEncoder, ComputePass,
 };
 
 use super::{
     kind::{Kind, ReadWrite, Uniform},
-    Shape, TensorError, TensorGpu, TensorGpuView, TensorScalar, TensorShape,
+    Cursor, Shape, TensorError, TensorGpu, TensorGpuGather, TensorGpuView, TensorScalar,
+    TensorShape,
 };
 use crate::{
-    context::{CachedPipeline, Macros},
+    context::{CachedPipeline, Context, Macros},
+    impl_deserialize_seed,
     num::{Float, Scalar},
 };
 
@@ -28,6 +31,43 @@ pub trait TensorCommand<T: Scalar, K: Kind> {
         from: usize,
         to: usize,
     ) -> Result<(), TensorError>;
+
+    /// Record copies of whole batches with as few copy commands as possible:
+    /// copies of consecutive batches between the same pair of tensors are merged into one command.
+    /// The source and the destination of each copy must be different tensors.
+    /// Returns the number of copy commands recorded.
+    fn copy_tensor_batches<'a>(
+        &mut self,
+        copies: impl IntoIterator<Item = BatchCopy<'a, T, K>>,
+    ) -> Result<usize, TensorError>
+    where
+        T: 'a,
+        K: 'a;
+}
+
+/// A copy of batch `from` of `source` into batch `to` of `destination`. See [`TensorCommand::copy_tensor_batches`].
+#[derive(Debug)]
+pub struct BatchCopy<'a, T: Scalar, K: Kind> {
+    pub source: &'a TensorGpu<T, K>,
+    pub from: usize,
+    pub destination: &'a TensorGpu<T, K>,
+    pub to: usize,
+}
+
+impl<'a, T: Scalar, K: Kind> BatchCopy<'a, T, K> {
+    pub fn new(
+        source: &'a TensorGpu<T, K>,
+        from: usize,
+        destination: &'a TensorGpu<T, K>,
+        to: usize,
+    ) -> Self {
+        Self {
+            source,
+            from,
+            destination,
+            to,
+        }
+    }
 }
 
 impl<T: Scalar, K: Kind> TensorCommand<T, K> for CommandEncoder {
@@ -72,6 +112,86 @@ impl<T: Scalar, K: Kind> TensorCommand<T, K> for CommandEncoder {
         );
         Ok(())
     }
+
+    fn copy_tensor_batches<'a>(
+        &mut self,
+        copies: impl IntoIterator<Item = BatchCopy<'a, T, K>>,
+    ) -> Result<usize, TensorError>
+    where
+        T: 'a,
+        K: 'a,
+    {
+        // a run of copies of `count` consecutive batches
+        struct Run<'a, T: Scalar, K: Kind> {
+            copy: BatchCopy<'a, T, K>,
+            count: usize,
+        }
+
+        let mut count = 0;
+        let mut flush = |encoder: &mut CommandEncoder, run: Run<T, K>| {
+            let Run { copy, count: len } = run;
+            let size = T::size() * copy.source.shape[0] * copy.source.shape[1];
+            encoder.copy_buffer_to_buffer(
+                &copy.source.buffer,
+                (size * copy.from) as u64,
+                &copy.destination.buffer,
+                (size * copy.to) as u64,
+                (size * len) as u64,
+            );
+            count += 1;
+        };
+
+        let mut run: Option<Run<T, K>> = None;
+        for copy in copies {
+            let BatchCopy {
+                source,
+                from,
+                destination,
+                to,
+            } = copy;
+            let [c, h, _, _] = *source.shape;
+            source.check_shape([c, h, source.shape[2], 1])?;
+            destination.check_shape([c, h, destination.shape[2], 1])?;
+            if Arc::ptr_eq(&source.buffer, &destination.buffer) {
+                return Err(TensorError::SameBuffer);
+            }
+            if from >= source.shape[2] {
+                return Err(TensorError::BatchOutOfRange {
+                    batch: from,
+                    max: source.shape[2],
+                });
+            }
+            if to >= destination.shape[2] {
+                return Err(TensorError::BatchOutOfRange {
+                    batch: to,
+                    max: destination.shape[2],
+                });
+            }
+
+            run = match run {
+                Some(mut run)
+                    if Arc::ptr_eq(&run.copy.source.buffer, &source.buffer)
+                        && Arc::ptr_eq(&run.copy.destination.buffer, &destination.buffer)
+                        && run.copy.from + run.count == from
+                        && run.copy.to + run.count == to =>
+                {
+                    run.count += 1;
+                    Some(run)
+                }
+                run => {
+                    if let Some(run) = run {
+                        flush(self, run);
+                    }
+                    let copy = BatchCopy::new(source, from, destination, to);
+                    Some(Run { copy, count: 1 })
+                }
+            };
+        }
+        if let Some(run) = run {
+            flush(self, run);
+        }
+        Ok(count)
+    }
 }
 
 impl crate::context::Context {
@@ -148,6 +268,8 @@ pub enum Activation {
     None,
     SquaredRelu,
     Tanh,
+    Relu,
+    Sigmoid,
 }
 
 impl std::fmt::Display for Activation {
@@ -156,6 +278,307 @@ impl std::fmt::Display for Activation {
             Activation::None => write!(f, "NONE"),
             Activation::SquaredRelu => write!(f, "SQUARED_RELU"),
             Activation::Tanh => write!(f, "TANH"),
+            Activation::Relu => write!(f, "RELU"),
+            Activation::Sigmoid => write!(f, "SIGMOID"),
+        }
+    }
+}
+
+/// An elementwise operation of two tensors in [`TensorOp::binary`], where `output` is updated with `input`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    /// `output + input`.
+    Add,
+    /// `output - input`.
+    Sub,
+    /// `output * input`.
+    Mul,
+    /// `output / input`.
+    Div,
+    Max,
+    Min,
+}
+
+impl std::fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BinaryOp::Add => write!(f, "ADD"),
+            BinaryOp::Sub => write!(f, "SUB"),
+            BinaryOp::Mul => write!(f, "MUL"),
+            BinaryOp::Div => write!(f, "DIV"),
+            BinaryOp::Max => write!(f, "MAX"),
+            BinaryOp::Min => write!(f, "MIN"),
+        }
+    }
+}
+
+/// An elementwise function of a tensor in [`TensorOp::unary`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    Abs,
+    Exp,
+    /// Natural logarithm.
+    Log,
+    Sigmoid,
+    Tanh,
+    Relu,
+    SquaredRelu,
+    /// `x * sigmoid(x)`.
+    Silu,
+}
+
+impl std::fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "NEG"),
+            UnaryOp::Abs => write!(f, "ABS"),
+            UnaryOp::Exp => write!(f, "EXP"),
+            UnaryOp::Log => write!(f, "LOG"),
+            UnaryOp::Sigmoid => write!(f, "SIGMOID"),
+            UnaryOp::Tanh => write!(f, "TANH"),
+            UnaryOp::Relu => write!(f, "RELU"),
+            UnaryOp::SquaredRelu => write!(f, "SQUARED_RELU"),
+            UnaryOp::Silu => write!(f, "SILU"),
+        }
+    }
+}
+
+/// A reduction over the channel axis in [`TensorOp::reduce`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReduceOp {
+    Sum,
+    Mean,
+    Max,
+}
+
+impl std::fmt::Display for ReduceOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReduceOp::Sum => write!(f, "SUM"),
+            ReduceOp::Mean => write!(f, "MEAN"),
+            ReduceOp::Max => write!(f, "MAX"),
+        }
+    }
+}
+
+/// What a matrix multiplication does with its product in the same kernel before writing it out, i.e.,
+/// `output = activation(matrix * input + bias + output)`, where `output` is only added if accumulating.
+///
+/// Converts from an [`Activation`], so that a plain activation can be passed where an epilogue is expected.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Epilogue<'a> {
+    pub activation: Activation,
+    /// A vector of shape `[R, 1, 1]` added to every token of the product.
+    pub bias: Option<&'a TensorGpu<f16, ReadWrite>>,
+    /// Add the product to the output instead of overwriting it.
+    pub accumulate: bool,
+}
+
+impl<'a> Epilogue<'a> {
+    pub fn new(activation: Activation) -> Self {
+        Self {
+            activation,
+            ..Default::default()
+        }
+    }
+
+    pub fn bias(mut self, bias: &'a TensorGpu<f16, ReadWrite>) -> Self {
+        self.bias = Some(bias);
+        self
+    }
+
+    pub fn accumulate(mut self, accumulate: bool) -> Self {
+        self.accumulate = accumulate;
+        self
+    }
+
+    fn check_shape(&self, rows: usize) -> Result<(), TensorError> {
+        match self.bias {
+            Some(bias) => bias.check_shape([rows, 1, 1, 1]),
+            None => Ok(()),
+        }
+    }
+
+    fn binding(&self) -> Option<BindGroupEntry<'a>> {
+        self.bias.map(|bias| BindGroupEntry {
+            binding: 9,
+            resource: bias.binding(),
+        })
+    }
+}
+
+impl From<Activation> for Epilogue<'_> {
+    fn from(activation: Activation) -> Self {
+        Self::new(activation)
+    }
+}
+
+/// Adjustments of logits that a head matmul applies to its product in the same kernel, after the [`Epilogue`],
+/// instead of in passes of their own over the vocabulary, see [`TensorOp::matmul_vec_int8_row`].
+///
+/// They are applied in the order of the fields, like [`TensorOp::logit_bias`], [`TensorOp::length_penalty`]
+/// and [`TensorOp::penalty`] one after another, and are indexed by the rows `T` of the whole output, even of a view of it.
+#[allow(clippy::type_complexity)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogitTransform<'a> {
+    /// The slots `[1, T]` of the rows, and the biases `[R, 1, S]` of the slots.
+    pub bias: Option<(&'a TensorGpu<u32, ReadWrite>, &'a TensorGpu<f32, ReadWrite>)>,
+    /// The `(token, add, scale, _)` adjustment `[4, T]` of each row.
+    pub length: Option<&'a TensorGpu<f32, ReadWrite>>,
+    /// The `(presence, frequency, slot, _)` penalties `[4, T]` of the rows, and the token counts `[R, 1, S]` of the slots.
+    pub penalty: Option<(&'a TensorGpu<f32, ReadWrite>, &'a TensorGpu<f32, ReadWrite>)>,
+}
+
+impl<'a> LogitTransform<'a> {
+    pub fn bias(
+        mut self,
+        slots: &'a TensorGpu<u32, ReadWrite>,
+        bias: &'a TensorGpu<f32, ReadWrite>,
+    ) -> Self {
+        self.bias = Some((slots, bias));
+        self
+    }
+
+    pub fn length(mut self, params: &'a TensorGpu<f32, ReadWrite>) -> Self {
+        self.length = Some(params);
+        self
+    }
+
+    pub fn penalty(
+        mut self,
+        params: &'a TensorGpu<f32, ReadWrite>,
+        counts: &'a TensorGpu<f32, ReadWrite>,
+    ) -> Self {
+        self.penalty = Some((params, counts));
+        self
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.bias.is_none() && self.length.is_none() && self.penalty.is_none()
+    }
+
+    fn check_shape(&self, rows: usize, tokens: usize) -> Result<(), TensorError> {
+        if let Some((slots, bias)) = self.bias {
+            slots.check_shape([1, tokens, 1, 1])?;
+            bias.check_shape([rows, 1, bias.shape()[2], 1])?;
+        }
+        if let Some(params) = self.length {
+            params.check_shape([4, tokens, 1, 1])?;
+        }
+        if let Some((params, counts)) = self.penalty {
+            params.check_shape([4, tokens, 1, 1])?;
+            counts.check_shape([rows, 1, counts.shape()[2], 1])?;
+        }
+        Ok(())
+    }
+
+    fn macros(&self, macros: Macros) -> Macros {
+        macros
+            .bool("LOGIT_BIAS", self.bias.is_some())
+            .bool("LENGTH", self.length.is_some())
+            .bool("PENALTY", self.penalty.is_some())
+            .bool("PARAMS", self.length.is_some() || self.penalty.is_some())
+    }
+}
+
+/// The low-rank delta `factor * hidden` of a LoRA, which a quantized matrix-vector multiplication adds to its product
+/// in the same kernel, before the bias and activation of the [`Epilogue`], see [`TensorOp::matmul_vec_int8_lora`].
+#[derive(Debug, Clone, Copy)]
+pub struct LoraDelta<'a> {
+    /// The up projection of shape `[r, R, 1]`, with the rank `r` in multiples of 4.
+    pub factor: &'a TensorGpu<f16, ReadWrite>,
+    /// The input projected down (and scaled) by the other factor, of shape `[r, T, B]`.
+    pub hidden: &'a TensorGpu<f32, ReadWrite>,
+}
+
+impl<'a> LoraDelta<'a> {
+    fn check_shape(&self, rows: usize, tokens: usize, batches: usize) -> Result<(), TensorError> {
+        let rank = self.factor.shape()[0];
+        if !rank.is_multiple_of(4) {
+            return Err(TensorError::Shape(
+                self.factor.shape(),
+                [4, rows, 1, 1].into(),
+            ));
+        }
+        self.factor.check_shape([rank, rows, 1, 1])?;
+        self.hidden.check_shape([rank, tokens, batches, 1])
+    }
+
+    fn macros(delta: Option<&Self>, macros: Macros) -> Macros {
+        match delta {
+            Some(delta) => macros
+                .bool("LORA", true)
+                .u32("LORA_RANK", delta.factor.shape()[0] as u32),
+            None => macros,
+        }
+    }
+
+    fn bindings(&self) -> [BindGroupEntry<'a>; 2] {
+        [
+            BindGroupEntry {
+                binding: 10,
+                resource: self.factor.binding(),
+            },
+            BindGroupEntry {
+                binding: 11,
+                resource: self.hidden.binding(),
+            },
+        ]
+    }
+}
+
+/// Random noise to inject into activations.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Noise {
+    /// Add Gaussian noise with the given standard deviation.
+    Gaussian(f32),
+    /// Zero elements with the given probability, and scale the others to keep the expectation.
+    Dropout(f32),
+}
+
+impl_deserialize_seed!(Noise);
+
+/// Encoding of 8-bit floats.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Fp8Format {
+    /// 4 exponent bits and 3 mantissa bits. More precise, with a range of ±448.
+    #[default]
+    E4M3,
+    /// 5 exponent bits and 2 mantissa bits. Less precise, with a range of ±57344.
+    E5M2,
+}
+
+impl_deserialize_seed!(Fp8Format);
+
+impl std::fmt::Display for Fp8Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fp8Format::E4M3 => write!(f, "E4M3"),
+            Fp8Format::E5M2 => write!(f, "E5M2"),
+        }
+    }
+}
+
+/// Layout of k-quant matrices, which are quantized in blocks of 32 elements,
+/// each with a 6-bit scale and a 6-bit minimum relative to those of its super-block of 256 elements.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KQuantFormat {
+    /// 4 bits per element.
+    #[default]
+    Q4K,
+    /// 5 bits per element, with the high bits stored apart from the low nibbles.
+    Q5K,
+}
+
+impl_deserialize_seed!(KQuantFormat);
+
+impl std::fmt::Display for KQuantFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KQuantFormat::Q4K => write!(f, "Q4"),
+            KQuantFormat::Q5K => write!(f, "Q5"),
         }
     }
 }
@@ -173,6 +596,22 @@ impl Macros {
         self
     }
 
+    /// Define a `u32` macro `FP8_BLOCK_SIZE` and the format of 8-bit floats.
+    pub fn fp8(mut self, block_size: u32, format: Fp8Format) -> Self {
+        self.insert("FP8_BLOCK_SIZE".into(), format!("{}u", block_size));
+        self.custom(format, Some("FP8"))
+    }
+
+    /// Define `u32` macros `KQUANT_BLOCK_SIZE`, `KQUANT_SUPER_BLOCK_SIZE` and the k-quant format.
+    pub fn kquant(mut self, block_size: u32, super_block_size: u32, format: KQuantFormat) -> Self {
+        self.insert("KQUANT_BLOCK_SIZE".into(), format!("{}u", block_size));
+        self.insert(
+            "KQUANT_SUPER_BLOCK_SIZE".into(),
+            format!("{}u", super_block_size),
+        );
+        self.custom(format, Some("KQUANT"))
+    }
+
     /// Define a `f32` macro with a given name.
     pub fn f32(mut self, name: impl Into<String>, value: f32) -> Self {
         self.insert(name.into(), format!("{}", value));
@@ -218,6 +657,13 @@ impl Macros {
         self
     }
 
+    /// Define the macros of the [`Epilogue`] of a matrix multiplication.
+    fn epilogue(self, epilogue: &Epilogue) -> Self {
+        self.custom(epilogue.activation, Some("ACT"))
+            .bool("BIAS", epilogue.bias.is_some())
+            .bool("ACCUMULATE", epilogue.accumulate)
+    }
+
     /// Add a define when `condition` is true.
     pub fn define(mut self, name: impl Into<String>, condition: bool) -> Self {
         if condition {
@@ -248,12 +694,39 @@ pub enum TensorOp {
 impl TensorOp {
     pub const NF4_BLOCK_SIZE: u32 = 64;
     pub const INT8_BLOCK_SIZE: u32 = 128;
+    /// Largest vocabulary that [`TensorOp::sample`] filters exactly.
+    pub const SAMPLE_EXACT_SIZE: u32 = 1024;
+    pub const FP8_BLOCK_SIZE: u32 = 128;
+    pub const KQUANT_BLOCK_SIZE: u32 = 32;
+    pub const KQUANT_SUPER_BLOCK_SIZE: u32 = 256;
 
     #[inline]
     fn block_count(count: u32, block_size: u32) -> u32 {
         (count + block_size - 1) / block_size
     }
 
+    #[inline]
+    fn scalar_size<T: Scalar>(_tensor: &impl TensorScalar<T = T>) -> u32 {
+        T::size() as u32
+    }
+
+    /// Pick the largest block size, no more than `max`, of a tiled kernel of `BLOCK_SIZE * BLOCK_SIZE`
+    /// invocations, whose workgroup memory in bytes given by `size` fits in the limits of the device.
+    ///
+    /// Adapters differ in how much workgroup memory they offer, so that smaller tiles keep the kernels
+    /// running on, e.g., integrated and mobile GPUs, while larger ones are used wherever they fit.
+    fn tile_block_size(context: &Context, max: u32, size: impl Fn(u32) -> u32) -> u32 {
+        let limits = context.device.limits();
+        std::iter::successors(Some(max), |&block| (block > 1).then_some(block >> 1))
+            .find(|&block| {
+                size(block) <= limits.max_compute_workgroup_storage_size
+                    && block * block <= limits.max_compute_invocations_per_workgroup
+                    && block <= limits.max_compute_workgroup_size_x
+                    && block <= limits.max_compute_workgroup_size_y
+            })
+            .unwrap_or(1)
+    }
+
     #[inline]
     pub fn empty() -> Self {
         Self::List(vec![])
@@ -306,35 +779,35 @@ impl TensorOp {
         })
     }
 
-    /// Embedding on GPU.
-    /// - `tokens` shape: `[T, B]`.
-    /// - `input` shape: `[C, V]`.
-    /// - `output` shape: `[C, T, B]`.
-    pub fn embed(
-        tokens: &TensorGpu<u32, ReadWrite>,
-        input: &TensorGpu<f16, ReadWrite>,
-        output: &TensorGpu<impl Float, ReadWrite>,
-    ) -> Result<Self, TensorError> {
+    /// Softmax operator applied on the gathered batches of `x`, leaving other batches untouched.
+    /// - `x` shape: `[C, T, N]`, gathered from `[C, T, B]`.
+    pub fn softmax_gather(x: &TensorGpuGather<impl Float>) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [index, token, batch, _] = *output.shape();
-            let [_, vocab, _, _] = *input.shape();
-            tokens.check_shape([token, batch, 1, 1])?;
-            input.check_shape([index, vocab, 1, 1])?;
-            output.check_shape([index, token, batch, 1])?;
-            output.shape()
-        };
-
-        let context = output.context();
+        let shape = x.shape();
+        let context = x.context();
+        #[cfg(not(feature = "subgroup-ops"))]
         let pipeline = context.checkout_pipeline(
-            "embed",
-            include_str!("../shaders/embed.wgsl"),
-            "embed",
+            "softmax",
+            include_str!("../shaders/softmax.wgsl"),
+            "softmax",
             None,
             Macros::new()
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(output, None),
+                .bool("GATHER", true)
+                .tensor(x, None),
+        );
+        #[cfg(feature = "subgroup-ops")]
+        let pipeline = context.checkout_pipeline(
+            "softmax",
+            include_str!("../shaders/subgroup/softmax.wgsl"),
+            "softmax",
+            None,
+            Macros::new()
+                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .bool("GATHER", true)
+                .tensor(x, None),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -342,19 +815,15 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: tokens.binding(),
+                    resource: x.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: output.binding(),
+                    resource: x.batches_binding(),
                 },
             ],
         })];
@@ -362,47 +831,31 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Layer normalization applied on `x`, with weight `w` and bias `b`.
+    /// Softmax operator applied on `x`, over the tokens kept by the mask of each batch.
+    /// Masked tokens are set to zero, and so are rows with all tokens masked.
     /// - `x` shape: `[C, T, B]`.
-    /// - `w` shape: `[C, 1, 1]`.
-    /// - `b` shape: `[C, 1, 1]`.
-    /// - `s` shape: `[4, T, B]`, mean and inverse std of `x`.
-    pub fn layer_norm(
-        w: &TensorGpu<f16, ReadWrite>,
-        b: &TensorGpu<f16, ReadWrite>,
+    /// - `mask` shape: `[ceil(C / 32), 1, B]`, a bitset where bit `c % 32` of word `c / 32` is set if token `c` is kept.
+    pub fn softmax_masked(
         x: &TensorGpu<impl Float, ReadWrite>,
-        eps: f32,
+        mask: &TensorGpu<u32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [index, token, batch, _] = *x.shape();
-            x.check_shape([index, token, batch, 1])?;
-            w.check_shape([index, 1, 1, 1])?;
-            b.check_shape([index, 1, 1, 1])?;
-            x.shape()
-        };
+        let shape = x.shape();
+        mask.check_shape([shape[0].div_ceil(32), 1, shape[2], 1])?;
 
         let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "layer_norm",
-            include_str!("../shaders/layer_norm.wgsl"),
-            "layer_norm",
+            "softmax_masked",
+            include_str!("../shaders/softmax_masked.wgsl"),
+            "softmax_masked",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("EPS", eps),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
         );
-
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
@@ -413,14 +866,10 @@ impl TensorOp {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: w.binding(),
+                    resource: mask.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: b.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
                     resource: x.binding(),
                 },
             ],
@@ -433,36 +882,26 @@ impl TensorOp {
         })
     }
 
-    /// Group normalization applied on `x`, with weight `w` and bias `b`.
-    /// - `x` shape: `[S, H, A]`.
-    /// - `w` shape: `[S, H, 1]`.
-    /// - `b` shape: `[S, H, 1]`.
-    pub fn group_norm(
-        w: &TensorGpu<f16, ReadWrite>,
-        b: &TensorGpu<f16, ReadWrite>,
+    /// Softmax operator applied on `x`, with the logits of each row divided by its temperature.
+    /// A row with a non-positive temperature puts all probability on its maximum (split evenly among ties).
+    /// - `x` shape: `[C, T, B]`.
+    /// - `temperature` shape: `[1, T, B]`.
+    pub fn softmax_temperature(
         x: &TensorGpu<impl Float, ReadWrite>,
-        eps: f32,
+        temperature: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 32;
+        const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [index, head, token, _] = *x.shape();
-            x.check_shape([index, head, token, 1])?;
-            w.check_shape([index, head, 1, 1])?;
-            b.check_shape([index, head, 1, 1])?;
-            x.shape()
-        };
+        let shape = x.shape();
+        temperature.check_shape([1, shape[1], shape[2], 1])?;
 
         let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "group_norm",
-            include_str!("../shaders/layer_norm.wgsl"),
-            "group_norm",
+            "softmax_temperature",
+            include_str!("../shaders/softmax_temperature.wgsl"),
+            "softmax_temperature",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("EPS", eps),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -474,15 +913,11 @@ impl TensorOp {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: w.binding(),
+                    resource: x.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: b.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: x.binding(),
+                    resource: temperature.binding(),
                 },
             ],
         })];
@@ -494,37 +929,31 @@ impl TensorOp {
         })
     }
 
-    /// Recenter `x` to be zero-mean.
-    pub fn recenter(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
+    /// Top-p (nucleus) truncation of probabilities sorted in descending order, e.g., by [`TensorOp::topk`].
+    /// Each row keeps its leading probabilities while those before weigh less than its `top_p` (the first is always kept),
+    /// renormalizes them, and sets the rest to zero; zero probabilities are never kept.
+    /// - `x` shape: `[K, T, B]`.
+    /// - `top_p` shape: `[1, T, B]`.
+    /// - `count` shape: `[1, T, B]`, the number of probabilities kept in each row.
+    pub fn top_p(
+        x: &TensorGpu<f32, ReadWrite>,
+        top_p: &TensorGpu<f32, ReadWrite>,
+        count: &TensorGpu<u32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
         let shape = x.shape();
+        top_p.check_shape([1, shape[1], shape[2], 1])?;
+        count.check_shape([1, shape[1], shape[2], 1])?;
 
         let context = x.context();
-        #[cfg(not(feature = "subgroup-ops"))]
         let pipeline = context.checkout_pipeline(
-            "recenter",
-            include_str!("../shaders/rms_norm.wgsl"),
-            "recenter",
-            None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("EPS", 0.0),
-        );
-        #[cfg(feature = "subgroup-ops")]
-        let pipeline = context.checkout_pipeline(
-            "recenter",
-            include_str!("../shaders/subgroup/rms_norm.wgsl"),
-            "recenter",
+            "top_p",
+            include_str!("../shaders/top_p.wgsl"),
+            "top_p",
             None,
-            Macros::new()
-                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("EPS", 0.0),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
-
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
@@ -534,9 +963,17 @@ impl TensorOp {
                     resource: x.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 3,
+                    binding: 1,
+                    resource: top_p.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
                     resource: x.binding(),
                 },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: count.binding(),
+                },
             ],
         })];
 
@@ -547,70 +984,43 @@ impl TensorOp {
         })
     }
 
-    /// Root-mean-square normalization applied on `x`, with weight `w` and bias `b`.
-    /// - `x` shape: `[C, T, B]`.
-    /// - `w` shape: `[C, 1, 1]`.
-    /// - `b` shape: `[C, 1, 1]`.
-    pub fn rms_norm(
-        w: &TensorGpu<f16, ReadWrite>,
-        b: &TensorGpu<f16, ReadWrite>,
-        x: &TensorGpu<impl Float, ReadWrite>,
-        eps: f32,
+    /// Index of the maximum of each row of `input`. Ties resolve to the smallest index.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[1, T, B]`.
+    pub fn argmax(
+        input: &TensorGpu<impl Float, ReadWrite>,
+        output: &TensorGpu<u32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [index, token, batch, _] = *x.shape();
-            x.check_shape([index, token, batch, 1])?;
-            w.check_shape([index, 1, 1, 1])?;
-            b.check_shape([index, 1, 1, 1])?;
-            x.shape()
-        };
+        let shape = input.shape();
+        output.check_shape([1, shape[1], shape[2], 1])?;
 
-        let context = x.context();
-        #[cfg(not(feature = "subgroup-ops"))]
-        let pipeline = context.checkout_pipeline(
-            "rms_norm",
-            include_str!("../shaders/rms_norm.wgsl"),
-            "rms_norm",
-            None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("EPS", eps),
-        );
-        #[cfg(feature = "subgroup-ops")]
+        let context = input.context();
         let pipeline = context.checkout_pipeline(
-            "rms_norm",
-            include_str!("../shaders/subgroup/rms_norm.wgsl"),
-            "rms_norm",
+            "argmax",
+            include_str!("../shaders/argmax.wgsl"),
+            "argmax",
             None,
             Macros::new()
-                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("EPS", eps),
+                .tensor(input, Some("IN")),
         );
-
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: x.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: w.binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: b.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: x.binding(),
+                    resource: output.binding(),
                 },
             ],
         })];
@@ -622,52 +1032,29 @@ impl TensorOp {
         })
     }
 
-    /// Fp32 matrix-vector multiplication.
-    /// - `matrix` shape: `[C, R, B]`.
+    /// Log-softmax of each row of `input` at its token, e.g., the log-probability of the next token for scoring.
+    /// Rows whose token is out of range are left as they are in `output`.
     /// - `input` shape: `[C, T, B]`.
-    /// - `output` shape: `[R, T, B]`.
-    pub fn matmul_vec_fp16(
-        matrix: &TensorGpu<f16, ReadWrite>,
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
-        active: Activation,
+    /// - `tokens` shape: `[1, T, B]`.
+    /// - `output` shape: `[1, T, B]`.
+    pub fn log_prob(
+        input: &TensorGpu<f32, ReadWrite>,
+        tokens: &TensorGpu<u32, ReadWrite>,
+        output: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [m, n, b, _] = *output.shape();
-            let [k, _, _, _] = *input.shape();
-            matrix.check_shape([k, m, b, 1])?;
-            input.check_shape([k, n, b, 1])?;
-            output.check_shape([m, n, b, 1])?;
-            output.shape()
-        };
+        let shape = input.shape();
+        tokens.check_shape([1, shape[1], shape[2], 1])?;
+        output.check_shape([1, shape[1], shape[2], 1])?;
 
-        let context = output.context();
-        #[cfg(not(feature = "subgroup-ops"))]
-        let pipeline = context.checkout_pipeline(
-            "matmul_vec_fp16",
-            include_str!("../shaders/matmul_vec_fp16.wgsl"),
-            "matmul",
-            None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
-        );
-        #[cfg(feature = "subgroup-ops")]
+        let context = input.context();
         let pipeline = context.checkout_pipeline(
-            "matmul_vec_fp16",
-            include_str!("../shaders/subgroup/matmul_vec_fp16.wgsl"),
-            "matmul",
+            "log_prob",
+            include_str!("../shaders/log_prob.wgsl"),
+            "log_prob",
             None,
-            Macros::new()
-                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -675,26 +1062,18 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: tokens.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
                     resource: output.binding(),
                 },
             ],
@@ -703,61 +1082,29 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Int8 matrix-vector multiplication.
-    /// - `matrix` shape: `[C, R, B]`.
+    /// The largest probability of the softmax of each row of `input`, e.g., how confident a head is of its top token.
     /// - `input` shape: `[C, T, B]`.
-    /// - `output` shape: `[R, T, B]`.
-    #[allow(clippy::too_many_arguments)]
-    pub fn matmul_vec_int8(
-        matrix: &TensorGpu<u8, ReadWrite>,
-        minmax: &TensorGpu<f16, ReadWrite>,
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
-        active: Activation,
+    /// - `output` shape: `[1, T, B]`.
+    pub fn max_prob(
+        input: &TensorGpu<f32, ReadWrite>,
+        output: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [m, n, b, _] = *output.shape();
-            let [k, _, _, _] = *input.shape();
-            minmax.check_shape([(k << 1) / Self::INT8_BLOCK_SIZE as usize, m, b, 1])?;
-            matrix.check_shape([k, m, b, 1])?;
-            input.check_shape([k, n, b, 1])?;
-            output.check_shape([m, n, b, 1])?;
-            output.shape()
-        };
+        let shape = input.shape();
+        output.check_shape([1, shape[1], shape[2], 1])?;
 
-        let context = matrix.context();
-        #[cfg(not(feature = "subgroup-ops"))]
-        let pipeline = context.checkout_pipeline(
-            "matmul_vec_int8",
-            include_str!("../shaders/matmul_vec_int8.wgsl"),
-            "matmul",
-            None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .int8(Self::INT8_BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
-        );
-        #[cfg(feature = "subgroup-ops")]
+        let context = input.context();
         let pipeline = context.checkout_pipeline(
-            "matmul_vec_int8",
-            include_str!("../shaders/matmul_vec_int8.wgsl"),
-            "matmul",
+            "max_prob",
+            include_str!("../shaders/max_prob.wgsl"),
+            "max_prob",
             None,
-            Macros::new()
-                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .int8(Self::INT8_BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -765,30 +1112,14 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
                     resource: input.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 2,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: minmax.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
+                    binding: 1,
                     resource: input.binding(),
                 },
                 BindGroupEntry {
-                    binding: 6,
+                    binding: 2,
                     resource: output.binding(),
                 },
             ],
@@ -797,61 +1128,32 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// NFloat4 matrix-vector multiplication.
-    /// - `matrix` shape: `[C, R, B]`.
-    /// - `input` shape: `[C, T, B]`.
-    /// - `output` shape: `[R, T, B]`.
-    pub fn matmul_vec_nf4(
-        matrix: &TensorGpu<u8, ReadWrite>,
-        quant: &TensorGpu<f32, Uniform>,
-        absmax: &TensorGpu<f16, ReadWrite>,
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
-        active: Activation,
+    /// Index of the maximum of each row of the gathered batches of `input`. Ties resolve to the smaller index.
+    /// - `input` shape: `[C, T, N]`, gathered from `[C, T, B]`.
+    /// - `output` shape: `[1, T, N]`, in the order of the gathered batches.
+    pub fn argmax_gather(
+        input: &TensorGpuGather<impl Float>,
+        output: &TensorGpu<u32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [m, n, b, _] = *output.shape();
-            let [k, _, _, _] = *input.shape();
-            absmax.check_shape([k / Self::NF4_BLOCK_SIZE as usize, m, b, 1])?;
-            matrix.check_shape([k >> 1, m, b, 1])?;
-            input.check_shape([k, n, b, 1])?;
-            output.check_shape([m, n, b, 1])?;
-            output.shape()
-        };
+        let shape = input.shape();
+        output.check_shape([1, shape[1], shape[2], 1])?;
 
-        let context = matrix.context();
-        #[cfg(not(feature = "subgroup-ops"))]
-        let pipeline = context.checkout_pipeline(
-            "matmul_vec_nf4",
-            include_str!("../shaders/matmul_vec_nf4.wgsl"),
-            "matmul",
-            None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .nf4(Self::NF4_BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
-        );
-        #[cfg(feature = "subgroup-ops")]
+        let context = input.context();
         let pipeline = context.checkout_pipeline(
-            "matmul_vec_nf4",
-            include_str!("../shaders/matmul_vec_nf4.wgsl"),
-            "matmul",
+            "argmax",
+            include_str!("../shaders/argmax.wgsl"),
+            "argmax",
             None,
             Macros::new()
-                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .nf4(Self::NF4_BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
+                .bool("GATHER", true)
+                .tensor(input, Some("IN")),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -859,35 +1161,19 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: output.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: quant.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: absmax.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: output.binding(),
+                    resource: input.batches_binding(),
                 },
             ],
         })];
@@ -895,44 +1181,42 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Fp16 matrix-matrix multiplication.
-    /// - `matrix` shape: `[K, M, B]`.
-    /// - `input` shape: `[K, N, B]`.
-    /// - `output` shape: `[M, N, B]`.
-    ///
-    /// Note: `K` must be multiples of 128; `M` and `N` must be multiples of 4.
-    pub fn matmul_mat_fp16(
-        matrix: TensorGpuView<f16>,
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
-        active: Activation,
+    /// The `K` largest values of each row of `input` in descending order, and their indices. Ties resolve to the smaller index.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `values` shape: `[K, T, B]`, where `K` is at most `C`.
+    /// - `indices` shape: `[K, T, B]`.
+    pub fn topk(
+        input: &TensorGpu<impl Float, ReadWrite>,
+        values: &TensorGpu<f32, ReadWrite>,
+        indices: &TensorGpu<u32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 8;
+        const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [m, n, b, _] = *output.shape();
-            let [k, _, _, _] = *input.shape();
-            matrix.check_shape([k, m, b, 1])?;
-            input.check_shape([k, n, b, 1])?;
-            output.check_shape([m, n, b, 1])?;
-            output.shape()
-        };
+        let shape = input.shape();
+        let k = values.shape()[0];
+        values.check_shape([k, shape[1], shape[2], 1])?;
+        indices.check_shape([k, shape[1], shape[2], 1])?;
+        if k > shape[0] {
+            return Err(TensorError::SliceOutOfRange {
+                dim: shape[0],
+                start: 0,
+                end: k,
+            });
+        }
 
-        let context = output.context();
+        let context = input.context();
         let pipeline = context.checkout_pipeline(
-            "matmul_mat_fp16",
-            include_str!("../shaders/matmul_mat_fp16.wgsl"),
-            "matmul",
+            "topk",
+            include_str!("../shaders/topk.wgsl"),
+            "topk",
             None,
             Macros::new()
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
+                .tensor(input, Some("IN")),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -940,27 +1224,23 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: values.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: matrix.binding(),
+                    resource: values.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: output.binding(),
+                    resource: indices.binding(),
                 },
             ],
         })];
@@ -968,52 +1248,46 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(Self::block_count(shape[0] as u32, 4), BLOCK_SIZE),
-                Self::block_count(Self::block_count(shape[1] as u32, 4), BLOCK_SIZE),
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Int8 matrix-matrix multiplication.
-    /// - `matrix` shape: `[K, M, B]`.
-    /// - `input` shape: `[K, N, B]`.
-    /// - `output` shape: `[M, N, B]`.
+    /// Sample a token from each row of logits with temperature, top-k and top-p (nucleus) filtering, without sorting.
     ///
-    /// Note: `K` must be multiples of 128; `M` and `N` must be multiples of 4.
-    #[allow(clippy::too_many_arguments)]
-    pub fn matmul_mat_int8(
-        matrix: TensorGpuView<u8>,
-        minmax: &TensorGpu<f16, ReadWrite>,
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
-        active: Activation,
+    /// Instead of sorting the vocabulary, a logit threshold is bisected until the tokens above it weigh at least `top_p`
+    /// and at most `top_p + error` of the probability mass, or until exactly `top_k` tokens are above it if that is fewer;
+    /// the token is then drawn from those tokens.
+    /// Rows with at most [`Self::SAMPLE_EXACT_SIZE`] logits are filtered exactly, as if sorted.
+    /// A row with a non-positive temperature or a `top_k` of 1 picks its argmax.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `params` shape: `[4, T, B]`, each row being `(temperature, top_p, random, top_k)`,
+    ///   with `random` uniform in `[0, 1)` and no limit on the number of tokens if `top_k` is 0.
+    /// - `output` shape: `[1, T, B]`.
+    pub fn sample(
+        input: &TensorGpu<impl Float, ReadWrite>,
+        params: &TensorGpu<f32, ReadWrite>,
+        output: &TensorGpu<u32, ReadWrite>,
+        error: f32,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 8;
+        const BLOCK_SIZE: u32 = 128;
+        const MAX_ITER: u32 = 32;
 
-        let shape = {
-            let [m, n, b, _] = *output.shape();
-            let [k, _, _, _] = *input.shape();
-            minmax.check_shape([(k << 1) / Self::INT8_BLOCK_SIZE as usize, m, b, 1])?;
-            matrix.check_shape([k, m, b, 1])?;
-            input.check_shape([k, n, b, 1])?;
-            output.check_shape([m, n, b, 1])?;
-            output.shape()
-        };
+        let shape = input.shape();
+        params.check_shape([4, shape[1], shape[2], 1])?;
+        output.check_shape([1, shape[1], shape[2], 1])?;
 
-        let context = output.context();
+        let context = input.context();
         let pipeline = context.checkout_pipeline(
-            "matmul_mat_int8",
-            include_str!("../shaders/matmul_mat_int8.wgsl"),
-            "matmul",
+            "sample",
+            include_str!("../shaders/sample.wgsl"),
+            "sample",
             None,
             Macros::new()
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .int8(Self::INT8_BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
+                .u32("MAX_ITER", MAX_ITER)
+                .f32("ERROR", error.max(0.0))
+                .bool("EXACT", shape[0] <= Self::SAMPLE_EXACT_SIZE as usize)
+                .tensor(input, Some("IN")),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1021,30 +1295,18 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: minmax.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
                     resource: output.binding(),
                 },
             ],
@@ -1053,52 +1315,40 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(Self::block_count(shape[0] as u32, 4), BLOCK_SIZE),
-                Self::block_count(Self::block_count(shape[1] as u32, 4), BLOCK_SIZE),
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// NFloat4 matrix-matrix multiplication.
-    /// - `matrix` shape: `[K, M, B]`.
-    /// - `input` shape: `[K, N, B]`.
-    /// - `output` shape: `[M, N, B]`.
-    ///
-    /// Note: `K` must be multiples of 256; `M` and `N` must be multiples of 8.
-    pub fn matmul_mat_nf4(
-        matrix: TensorGpuView<u8>,
-        quant: &TensorGpu<f32, Uniform>,
-        absmax: &TensorGpu<f16, ReadWrite>,
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
-        active: Activation,
+    /// Sample a token from each row of the gathered batches of `input`. See [`TensorOp::sample`].
+    /// - `input` shape: `[C, T, N]`, gathered from `[C, T, B]`.
+    /// - `params` shape: `[4, T, N]`, in the order of the gathered batches.
+    /// - `output` shape: `[1, T, N]`, in the order of the gathered batches.
+    pub fn sample_gather(
+        input: &TensorGpuGather<impl Float>,
+        params: &TensorGpu<f32, ReadWrite>,
+        output: &TensorGpu<u32, ReadWrite>,
+        error: f32,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 8;
+        const BLOCK_SIZE: u32 = 128;
+        const MAX_ITER: u32 = 32;
 
-        let shape = {
-            let [m, n, b, _] = *output.shape();
-            let [k, _, _, _] = *input.shape();
-            absmax.check_shape([k / Self::NF4_BLOCK_SIZE as usize, m, b, 1])?;
-            matrix.check_shape([k >> 1, m, b, 1])?;
-            input.check_shape([k, n, b, 1])?;
-            output.check_shape([m, n, b, 1])?;
-            output.shape()
-        };
+        let shape = input.shape();
+        params.check_shape([4, shape[1], shape[2], 1])?;
+        output.check_shape([1, shape[1], shape[2], 1])?;
 
-        let context = output.context();
+        let context = input.context();
         let pipeline = context.checkout_pipeline(
-            "matmul_mat_nf4",
-            include_str!("../shaders/matmul_mat_nf4.wgsl"),
-            "matmul",
+            "sample",
+            include_str!("../shaders/sample.wgsl"),
+            "sample",
             None,
             Macros::new()
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .nf4(Self::NF4_BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT"))
-                .custom(active, Some("ACT")),
+                .u32("MAX_ITER", MAX_ITER)
+                .f32("ERROR", error.max(0.0))
+                .bool("EXACT", shape[0] <= Self::SAMPLE_EXACT_SIZE as usize)
+                .bool("GATHER", true)
+                .tensor(input, Some("IN")),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1106,35 +1356,23 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: quant.binding(),
+                    resource: output.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: absmax.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: output.binding(),
+                    resource: input.batches_binding(),
                 },
             ],
         })];
@@ -1142,42 +1380,33 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(Self::block_count(shape[0] as u32, 4), BLOCK_SIZE),
-                Self::block_count(Self::block_count(shape[1] as u32, 4), BLOCK_SIZE),
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Add `input` to `output`.
-    /// - `input` shape: `[C, 1, B]` or `[C, T, B]`.
-    /// - `output` shape: `[C, T, B]`.
-    pub fn add(
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
+    /// Subtract presence and frequency penalties from rows of logits in place, given the token counts of their slots.
+    /// - `x` shape: `[C, R]`.
+    /// - `params` shape: `[4, R]`, each row being `(presence, frequency, slot, _)`.
+    /// - `counts` shape: `[C, 1, S]`.
+    pub fn penalty(
+        x: &TensorGpu<f32, ReadWrite>,
+        params: &TensorGpu<f32, ReadWrite>,
+        counts: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [index, token, batch, _] = *output.shape();
-            input
-                .check_shape([index, 1, batch, 1])
-                .or(input.check_shape([index, token, batch, 1]))?;
-            output.check_shape([index, token, batch, 1])?;
-            output.shape()
-        };
+        let shape = x.shape();
+        x.check_shape([shape[0], shape[1], 1, 1])?;
+        params.check_shape([4, shape[1], 1, 1])?;
+        counts.check_shape([shape[0], 1, counts.shape()[2], 1])?;
 
-        let context = output.context();
+        let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "add",
-            include_str!("../shaders/binary.wgsl"),
-            "add",
+            "apply_penalty",
+            include_str!("../shaders/penalty.wgsl"),
+            "apply_penalty",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT")),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1185,19 +1414,19 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: input.meta_binding(),
+                    resource: counts.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: output.meta_binding(),
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: input.binding(),
+                    resource: counts.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: output.binding(),
+                    resource: x.binding(),
                 },
             ],
         })];
@@ -1208,39 +1437,32 @@ impl TensorOp {
             dispatch: [
                 Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
                 shape[1] as u32,
-                shape[2] as u32,
+                1,
             ],
         })
     }
 
-    /// Multiply `input` to `output`.
-    /// - `input` shape: `[C, 1, B]` or `[C, T, B]`.
-    /// - `output` shape: `[C, T, B]`.
-    pub fn mul(
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
+    /// Adjust the logit of one token of each row in place, e.g., of the end-of-sequence token by the length generated.
+    /// - `x` shape: `[C, R]`.
+    /// - `params` shape: `[4, R]`, each row being `(token, add, scale, _)`: the logit of `token` gets `add + |logit| * scale`,
+    ///   and a row is left as is if its token is negative or out of range.
+    pub fn length_penalty(
+        x: &TensorGpu<f32, ReadWrite>,
+        params: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [index, token, batch, _] = *output.shape();
-            input
-                .check_shape([index, 1, batch, 1])
-                .or(input.check_shape([index, token, batch, 1]))?;
-            output.check_shape([index, token, batch, 1])?;
-            output.shape()
-        };
+        let shape = x.shape();
+        x.check_shape([shape[0], shape[1], 1, 1])?;
+        params.check_shape([4, shape[1], 1, 1])?;
 
-        let context = output.context();
+        let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "mul",
-            include_str!("../shaders/binary.wgsl"),
-            "mul",
+            "apply_length",
+            include_str!("../shaders/penalty.wgsl"),
+            "apply_length",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT")),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1248,19 +1470,15 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: input.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: input.binding(),
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: output.binding(),
+                    resource: x.binding(),
                 },
             ],
         })];
@@ -1268,49 +1486,34 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [Self::block_count(shape[1] as u32, BLOCK_SIZE), 1, 1],
         })
     }
 
-    pub fn token_shift(
-        cursors: &TensorGpu<u32, ReadWrite>,
-        time_mix: TensorGpuView<impl Float>,
-        state: TensorGpuView<f32>,
-        input: &TensorGpu<impl Float, ReadWrite>,
-        output: &TensorGpu<impl Float, ReadWrite>,
-        reversed: bool,
+    /// Add the logit biases of their slots to rows of logits in place.
+    /// Banned tokens are biased by negative infinity, so that they are never picked after softmax or argmax.
+    /// - `x` shape: `[C, R]`.
+    /// - `slots` shape: `[1, R]`; a row is left as is if its slot is out of range.
+    /// - `bias` shape: `[C, 1, S]`.
+    pub fn logit_bias(
+        x: &TensorGpu<f32, ReadWrite>,
+        slots: &TensorGpu<u32, ReadWrite>,
+        bias: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = {
-            let [index, token, count, _] = *output.shape();
-            let [_, head, batch, _] = *state.shape();
-            input
-                .check_shape([index, token, 1, 1])
-                .or(input.check_shape([index, token, count, 1]))?;
-            time_mix
-                .check_shape([index, 1, 1, 1])
-                .or(time_mix.check_shape([index, token, count, 1]))?;
-            state.check_shape([index, head, batch, 1])?;
-            output.shape()
-        };
+        let shape = x.shape();
+        x.check_shape([shape[0], shape[1], 1, 1])?;
+        slots.check_shape([1, shape[1], 1, 1])?;
+        bias.check_shape([shape[0], 1, bias.shape()[2], 1])?;
 
-        let context = output.context();
+        let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "token_shift",
-            include_str!("../shaders/token_shift.wgsl"),
-            "token_shift",
+            "apply_bias",
+            include_str!("../shaders/bias.wgsl"),
+            "apply_bias",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&time_mix, Some("TIME_MIX"))
-                .tensor(input, Some("IN"))
-                .tensor(output, Some("OUT"))
-                .bool("REVERSED", reversed),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1318,35 +1521,19 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: bias.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: time_mix.meta_binding(),
+                    resource: slots.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: state.meta_binding(),
+                    resource: bias.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: cursors.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: time_mix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: state.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: output.binding(),
+                    resource: x.binding(),
                 },
             ],
         })];
@@ -1357,39 +1544,31 @@ impl TensorOp {
             dispatch: [
                 Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
                 shape[1] as u32,
-                shape[2] as u32,
+                1,
             ],
         })
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn time_mix_v4<T: Float>(
-        cursors: &TensorGpu<u32, ReadWrite>,
-        time_decay: &TensorGpu<f32, ReadWrite>,
-        time_first: &TensorGpu<f32, ReadWrite>,
-        state: TensorGpuView<f32>,
-        k: &TensorGpu<T, ReadWrite>,
-        v: &TensorGpu<T, ReadWrite>,
-        r: &TensorGpu<T, ReadWrite>,
-        x: &TensorGpu<T, ReadWrite>,
+    /// Count sampled tokens into the slots of their rows, in row order.
+    /// - `tokens` shape: `[1, R]`.
+    /// - `params` shape: `[4, R]`, each row being `(_, _, slot, count)`; a row is counted only if `count` is positive.
+    /// - `counts` shape: `[C, 1, S]`.
+    pub fn count_tokens(
+        tokens: &TensorGpu<u32, ReadWrite>,
+        params: &TensorGpu<f32, ReadWrite>,
+        counts: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 128;
-
-        let shape = x.shape();
-        k.check_shape(shape)?;
-        v.check_shape(shape)?;
-        r.check_shape(shape)?;
-        time_decay.check_shape([shape[0], 1, 1, 1])?;
-        time_first.check_shape([shape[0], 1, 1, 1])?;
-        state.check_shape([shape[0], 4, state.shape()[2], 1])?;
+        let num_row = tokens.shape()[1];
+        tokens.check_shape([1, num_row, 1, 1])?;
+        params.check_shape([4, num_row, 1, 1])?;
 
-        let context = x.context();
+        let context = tokens.context();
         let pipeline = context.checkout_pipeline(
-            "time_mix_v4",
-            include_str!("../shaders/time_mix_v4.wgsl"),
-            "time_mix",
+            "count_tokens",
+            include_str!("../shaders/penalty.wgsl"),
+            "count_tokens",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new().u32("BLOCK_SIZE", 1),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1397,43 +1576,19 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: x.meta_binding(),
+                    resource: counts.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: state.meta_binding(),
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: time_decay.binding(),
+                    resource: counts.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: time_first.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: state.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: k.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: v.binding(),
-                },
-                BindGroupEntry {
-                    binding: 8,
-                    resource: r.binding(),
-                },
-                BindGroupEntry {
-                    binding: 9,
-                    resource: x.binding(),
+                    resource: tokens.binding(),
                 },
             ],
         })];
@@ -1441,125 +1596,81 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE), 1, 1],
+            dispatch: [1, 1, 1],
         })
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn time_mix_v5<T: Float>(
-        cursors: &TensorGpu<u32, ReadWrite>,
-        time_decay: &TensorGpu<f32, ReadWrite>,
-        time_first: &TensorGpu<f32, ReadWrite>,
-        state: TensorGpuView<f32>,
-        k: &TensorGpu<T, ReadWrite>,
-        v: &TensorGpu<T, ReadWrite>,
-        r: &TensorGpu<T, ReadWrite>,
-        x: &TensorGpu<T, ReadWrite>,
+    /// Apply the DRY ("don't repeat yourself") penalty in place to the rows of `x`, from the token rings of their slots:
+    /// a token that would extend a repetition of at least `allowed_length` tokens of the ring is penalized by
+    /// `multiplier * base ^ (length - allowed_length)`, taking the longest repetition it extends.
+    /// - `x` shape: `[C, R]`.
+    /// - `params` shape: `[8, R]`, each row being `(multiplier, base, allowed_length, _, slot, total, _, _)`,
+    ///   where the ring of `slot` holds the last of `total` tokens; a row is left as is if `multiplier` is zero.
+    /// - `history` shape: `[H, 1, S]`, the token rings of the slots.
+    /// - `lengths` shape: `[H, R]`, scratch for the lengths of the repetitions.
+    pub fn dry_penalty(
+        x: &TensorGpu<f32, ReadWrite>,
+        params: &TensorGpu<f32, ReadWrite>,
+        history: &TensorGpu<u32, ReadWrite>,
+        lengths: &TensorGpu<u32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 32;
+        const BLOCK_SIZE: u32 = 128;
 
         let shape = x.shape();
-        let dim = shape[0] * shape[1];
-
-        k.check_shape(shape)?;
-        v.check_shape(shape)?;
-        r.check_shape(shape)?;
-        time_decay.check_shape([shape[0], shape[1], 1, 1])?;
-        time_first.check_shape([shape[0], shape[1], 1, 1])?;
-        state.check_shape([dim, shape[0] + 1, state.shape()[2], 1])?;
+        let num_history = history.shape()[0];
+        x.check_shape([shape[0], shape[1], 1, 1])?;
+        params.check_shape([8, shape[1], 1, 1])?;
+        history.check_shape([num_history, 1, history.shape()[2], 1])?;
+        lengths.check_shape([num_history, shape[1], 1, 1])?;
 
         let context = x.context();
+        let dispatch = [
+            Self::block_count(num_history as u32, BLOCK_SIZE),
+            shape[1] as u32,
+            1,
+        ];
+
         let pipeline = context.checkout_pipeline(
-            "time_mix_v5",
-            include_str!("../shaders/time_mix_v5.wgsl"),
-            "time_mix",
+            "dry_match",
+            include_str!("../shaders/dry.wgsl"),
+            "dry_match",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
             entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: x.meta_binding(),
-                },
                 BindGroupEntry {
                     binding: 1,
-                    resource: state.meta_binding(),
+                    resource: history.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: time_decay.binding(),
+                    resource: history.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: time_first.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: state.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: k.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: v.binding(),
-                },
-                BindGroupEntry {
-                    binding: 8,
-                    resource: r.binding(),
-                },
-                BindGroupEntry {
-                    binding: 9,
-                    resource: x.binding(),
+                    resource: lengths.binding(),
                 },
             ],
         })];
-
-        Ok(Self::Atom {
+        let matches = Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(dim as u32 / 4, BLOCK_SIZE), 1, 1],
-        })
-    }
-
-    #[allow(clippy::too_many_arguments)]
-    pub fn time_mix_v6<T: Float>(
-        cursors: &TensorGpu<u32, ReadWrite>,
-        time_decay: &TensorGpu<f32, ReadWrite>,
-        time_first: &TensorGpu<f32, ReadWrite>,
-        state: TensorGpuView<f32>,
-        k: &TensorGpu<T, ReadWrite>,
-        v: &TensorGpu<T, ReadWrite>,
-        r: &TensorGpu<T, ReadWrite>,
-        x: &TensorGpu<T, ReadWrite>,
-    ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 32;
-
-        let shape = x.shape();
-        let dim = shape[0] * shape[1];
-
-        k.check_shape(shape)?;
-        v.check_shape(shape)?;
-        r.check_shape(shape)?;
-        time_decay.check_shape(shape)?;
-        time_first.check_shape([shape[0], shape[1], 1, 1])?;
-        state.check_shape([dim, shape[0] + 1, state.shape()[2], 1])?;
+            dispatch,
+        };
 
-        let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "time_mix_v6",
-            include_str!("../shaders/time_mix_v6.wgsl"),
-            "time_mix",
+            "apply_dry",
+            include_str!("../shaders/dry.wgsl"),
+            "apply_dry",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1571,39 +1682,78 @@ impl TensorOp {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: state.meta_binding(),
+                    resource: history.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: time_decay.binding(),
+                    resource: history.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: time_first.binding(),
+                    resource: lengths.binding(),
                 },
                 BindGroupEntry {
                     binding: 5,
-                    resource: state.binding(),
+                    resource: x.binding(),
                 },
+            ],
+        })];
+        let apply = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch,
+        };
+
+        Ok(Self::List(vec![matches, apply]))
+    }
+
+    /// Push sampled tokens into the token rings of the slots of their rows.
+    /// - `tokens` shape: `[1, R]`.
+    /// - `params` shape: `[8, R]`, each row being `(_, _, _, _, slot, _, position, push)`:
+    ///   the token goes to `position` modulo `H` of the ring of `slot`, only if `push` is positive.
+    /// - `history` shape: `[H, 1, S]`.
+    pub fn push_history(
+        tokens: &TensorGpu<u32, ReadWrite>,
+        params: &TensorGpu<f32, ReadWrite>,
+        history: &TensorGpu<u32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let num_row = tokens.shape()[1];
+        tokens.check_shape([1, num_row, 1, 1])?;
+        params.check_shape([8, num_row, 1, 1])?;
+
+        let context = tokens.context();
+        let pipeline = context.checkout_pipeline(
+            "push_history",
+            include_str!("../shaders/dry.wgsl"),
+            "push_history",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
                 BindGroupEntry {
-                    binding: 6,
-                    resource: k.binding(),
+                    binding: 1,
+                    resource: history.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 7,
-                    resource: v.binding(),
+                    binding: 2,
+                    resource: params.binding(),
                 },
                 BindGroupEntry {
-                    binding: 8,
-                    resource: r.binding(),
+                    binding: 3,
+                    resource: history.binding(),
                 },
                 BindGroupEntry {
-                    binding: 9,
-                    resource: x.binding(),
+                    binding: 6,
+                    resource: tokens.binding(),
                 },
             ],
         })];
@@ -1611,29 +1761,38 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(dim as u32 / 4, BLOCK_SIZE), 1, 1],
+            dispatch: [Self::block_count(num_row as u32, BLOCK_SIZE), 1, 1],
         })
     }
 
-    pub fn silu(
-        input: &TensorGpu<impl Float, ReadWrite>,
-        output: &TensorGpu<impl Float, ReadWrite>,
+    /// Average-pool per-head matrices down to a small map, and normalize each head into `[-1, 1]`.
+    /// Each head spans `C / H` columns of the input; the pooling factors are deduced from the shapes.
+    /// - `input` shape: `[C, R, L]`.
+    /// - `output` shape: `[W, R', H, L]`.
+    pub fn heat_map(
+        input: TensorGpuView<f32>,
+        output: &TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = output.shape();
-        input.check_shape(shape)?;
+        let [width, rows, num_head, num_layer] = *output.shape();
+        let [num_emb, num_row, _, _] = *input.shape();
+        input.check_shape([num_emb, num_row, num_layer, 1])?;
+        if num_head == 0
+            || num_emb % num_head != 0
+            || (num_emb / num_head) % width != 0
+            || num_row % rows != 0
+        {
+            return Err(TensorError::Deduce);
+        }
 
         let context = output.context();
         let pipeline = context.checkout_pipeline(
-            "silu",
-            include_str!("../shaders/silu.wgsl"),
-            "silu",
+            "heat_map",
+            include_str!("../shaders/heat_map.wgsl"),
+            "heat_map",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(input, Some("IN"))
-                .tensor(output, Some("OUT")),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1641,14 +1800,18 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
                     resource: output.binding(),
                 },
             ],
@@ -1657,25 +1820,39 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [1, num_head as u32, num_layer as u32],
         })
     }
 
-    pub fn tanh(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
+    /// Embedding on GPU.
+    /// - `tokens` shape: `[T, B]`.
+    /// - `input` shape: `[C, V]`.
+    /// - `output` shape: `[C, T, B]`.
+    pub fn embed(
+        tokens: &TensorGpu<u32, ReadWrite>,
+        input: &TensorGpu<f16, ReadWrite>,
+        output: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = x.shape();
-        let context = x.context();
+        let shape = {
+            let [index, token, batch, _] = *output.shape();
+            let [_, vocab, _, _] = *input.shape();
+            tokens.check_shape([token, batch, 1, 1])?;
+            input.check_shape([index, vocab, 1, 1])?;
+            output.check_shape([index, token, batch, 1])?;
+            output.shape()
+        };
+
+        let context = output.context();
         let pipeline = context.checkout_pipeline(
-            "tanh",
-            include_str!("../shaders/activation.wgsl"),
-            "act_tanh",
+            "embed",
+            include_str!("../shaders/embed.wgsl"),
+            "embed",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(output, None),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1683,11 +1860,19 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: x.meta_binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: x.binding(),
+                    resource: tokens.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
                 },
             ],
         })];
@@ -1703,18 +1888,39 @@ impl TensorOp {
         })
     }
 
-    pub fn opposite_exp(x: &TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
+    /// Layer normalization applied on `x`, with weight `w` and bias `b`.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `w` shape: `[C, 1, 1]`.
+    /// - `b` shape: `[C, 1, 1]`.
+    /// - `s` shape: `[4, T, B]`, mean and inverse std of `x`.
+    pub fn layer_norm(
+        w: &TensorGpu<f16, ReadWrite>,
+        b: &TensorGpu<f16, ReadWrite>,
+        x: &TensorGpu<impl Float, ReadWrite>,
+        eps: f32,
+    ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = x.shape();
+        let shape = {
+            let [index, token, batch, _] = *x.shape();
+            x.check_shape([index, token, batch, 1])?;
+            w.check_shape([index, 1, 1, 1])?;
+            b.check_shape([index, 1, 1, 1])?;
+            x.shape()
+        };
+
         let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "opposite_exp",
-            include_str!("../shaders/activation.wgsl"),
-            "opposite_exp",
+            "layer_norm",
+            include_str!("../shaders/layer_norm.wgsl"),
+            "layer_norm",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("EPS", eps),
         );
+
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
@@ -1725,6 +1931,14 @@ impl TensorOp {
                 },
                 BindGroupEntry {
                     binding: 1,
+                    resource: w.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: b.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
                     resource: x.binding(),
                 },
             ],
@@ -1733,25 +1947,40 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn stable_exp(x: &TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 128;
+    /// Group normalization applied on `x`, with weight `w` and bias `b`.
+    /// - `x` shape: `[S, H, A]`.
+    /// - `w` shape: `[S, H, 1]`.
+    /// - `b` shape: `[S, H, 1]`.
+    pub fn group_norm(
+        w: &TensorGpu<f16, ReadWrite>,
+        b: &TensorGpu<f16, ReadWrite>,
+        x: &TensorGpu<impl Float, ReadWrite>,
+        eps: f32,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 32;
+
+        let shape = {
+            let [index, head, token, _] = *x.shape();
+            x.check_shape([index, head, token, 1])?;
+            w.check_shape([index, head, 1, 1])?;
+            b.check_shape([index, head, 1, 1])?;
+            x.shape()
+        };
 
-        let shape = x.shape();
         let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "stable_exp",
-            include_str!("../shaders/activation.wgsl"),
-            "stable_exp",
+            "group_norm",
+            include_str!("../shaders/layer_norm.wgsl"),
+            "group_norm",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("EPS", eps),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1763,6 +1992,14 @@ impl TensorOp {
                 },
                 BindGroupEntry {
                     binding: 1,
+                    resource: w.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: b.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
                     resource: x.binding(),
                 },
             ],
@@ -1771,26 +2008,41 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn squared_relu(x: &TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
+    /// Recenter `x` to be zero-mean.
+    pub fn recenter(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
         let shape = x.shape();
+
         let context = x.context();
+        #[cfg(not(feature = "subgroup-ops"))]
         let pipeline = context.checkout_pipeline(
-            "squared_relu",
-            include_str!("../shaders/activation.wgsl"),
-            "squared_relu",
+            "recenter",
+            include_str!("../shaders/rms_norm.wgsl"),
+            "recenter",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("EPS", 0.0),
+        );
+        #[cfg(feature = "subgroup-ops")]
+        let pipeline = context.checkout_pipeline(
+            "recenter",
+            include_str!("../shaders/subgroup/rms_norm.wgsl"),
+            "recenter",
+            None,
+            Macros::new()
+                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("EPS", 0.0),
         );
+
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
@@ -1800,7 +2052,7 @@ impl TensorOp {
                     resource: x.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 1,
+                    binding: 3,
                     resource: x.binding(),
                 },
             ],
@@ -1809,36 +2061,55 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn channel_mix<T: Float>(
-        cursors: &TensorGpu<u32, ReadWrite>,
-        state: TensorGpuView<f32>,
-        r: &TensorGpu<T, ReadWrite>,
-        v: &TensorGpu<T, ReadWrite>,
-        x: &TensorGpu<T, ReadWrite>,
+    /// Root-mean-square normalization applied on `x`, with weight `w` and bias `b`.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `w` shape: `[C, 1, 1]`.
+    /// - `b` shape: `[C, 1, 1]`.
+    pub fn rms_norm(
+        w: &TensorGpu<f16, ReadWrite>,
+        b: &TensorGpu<f16, ReadWrite>,
+        x: &TensorGpu<impl Float, ReadWrite>,
+        eps: f32,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = x.shape();
-        v.check_shape(shape)?;
-        r.check_shape(shape)?;
-        state.check_shape([shape[0], 1, state.shape()[2], 1])?;
+        let shape = {
+            let [index, token, batch, _] = *x.shape();
+            x.check_shape([index, token, batch, 1])?;
+            w.check_shape([index, 1, 1, 1])?;
+            b.check_shape([index, 1, 1, 1])?;
+            x.shape()
+        };
 
         let context = x.context();
+        #[cfg(not(feature = "subgroup-ops"))]
         let pipeline = context.checkout_pipeline(
-            "channel_mix",
-            include_str!("../shaders/channel_mix.wgsl"),
-            "channel_mix",
+            "rms_norm",
+            include_str!("../shaders/rms_norm.wgsl"),
+            "rms_norm",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("EPS", eps),
+        );
+        #[cfg(feature = "subgroup-ops")]
+        let pipeline = context.checkout_pipeline(
+            "rms_norm",
+            include_str!("../shaders/subgroup/rms_norm.wgsl"),
+            "rms_norm",
+            None,
+            Macros::new()
+                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("EPS", eps),
         );
+
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
@@ -1849,26 +2120,14 @@ impl TensorOp {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: state.meta_binding(),
+                    resource: w.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
+                    resource: b.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: state.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: r.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: v.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
                     resource: x.binding(),
                 },
             ],
@@ -1877,862 +2136,4933 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                1,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Copy the content of `input` into `output` of the same shape.
-    pub fn blit(
+    /// Fp32 matrix-vector multiplication.
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_fp16<'a>(
+        matrix: &TensorGpu<f16, ReadWrite>,
         input: TensorGpuView<impl Float>,
         output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape();
-        input.check_shape(shape)?;
-
-        let block_size = match shape[1] {
-            x if x < 8 => [128, 1],
-            _ => [16, 16],
-        };
+        const BLOCK_SIZE: u32 = 128;
 
-        let context = input.context();
-        let pipeline = context.checkout_pipeline(
-            "blit",
-            include_str!("../shaders/blit.wgsl"),
-            "blit",
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            matrix.check_shape([k, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
+        };
+
+        let context = output.context();
+        #[cfg(not(feature = "subgroup-ops"))]
+        let pipeline = context.checkout_pipeline(
+            "matmul_vec_fp16",
+            include_str!("../shaders/matmul_vec_fp16.wgsl"),
+            "matmul",
             None,
             Macros::new()
-                .u32("BLOCK_SIZE_X", block_size[0])
-                .u32("BLOCK_SIZE_Y", block_size[1])
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
                 .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT")),
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
+        );
+        #[cfg(feature = "subgroup-ops")]
+        let pipeline = context.checkout_pipeline(
+            "matmul_vec_fp16",
+            include_str!("../shaders/subgroup/matmul_vec_fp16.wgsl"),
+            "matmul",
+            None,
+            Macros::new()
+                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: input.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: output.binding(),
-                },
-            ],
+            entries: &entries,
         })];
 
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, block_size[0]),
-                Self::block_count(shape[1] as u32, block_size[1]),
-                shape[2] as u32,
-            ],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Repeat the content of `input` into `output` along the token and batch axes.
-    pub fn broadcast(
-        input: TensorGpuView<impl Float>,
-        output: TensorGpuView<impl Float>,
+    /// Fp16 matrix-vector multiplication of the gathered batches of `input` into those of `output`,
+    /// with the matrix shared by all of them, e.g., the head applied on active batches only.
+    /// - `matrix` shape: `[C, R, 1]`.
+    /// - `input` shape: `[C, T, N]`, gathered from `[C, T, B]`.
+    /// - `output` shape: `[R, T, N]`, gathered from `[R, T, B']`.
+    pub fn matmul_vec_fp16_gather<'a>(
+        matrix: &TensorGpu<f16, ReadWrite>,
+        input: &TensorGpuGather<impl Float>,
+        output: &TensorGpuGather<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = output.shape();
-        input.check_shape([shape[0], input.shape()[1], input.shape()[2], 1])?;
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            matrix.check_shape([k, m, 1, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
+        };
+        let source = input.tensor().view(.., .., .., ..)?;
+        let destination = output.tensor().view(.., .., .., ..)?;
 
-        let context = input.context();
+        let context = output.context();
+        #[cfg(not(feature = "subgroup-ops"))]
         let pipeline = context.checkout_pipeline(
-            "broadcast",
-            include_str!("../shaders/reshape.wgsl"),
-            "broadcast",
+            "matmul_vec_fp16",
+            include_str!("../shaders/matmul_vec_fp16.wgsl"),
+            "matmul",
             None,
             Macros::new()
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT")),
+                .bool("GATHER", true)
+                .tensor(input, Some("IN"))
+                .tensor(output, Some("OUT"))
+                .epilogue(&epilogue),
+        );
+        #[cfg(feature = "subgroup-ops")]
+        let pipeline = context.checkout_pipeline(
+            "matmul_vec_fp16",
+            include_str!("../shaders/subgroup/matmul_vec_fp16.wgsl"),
+            "matmul",
+            None,
+            Macros::new()
+                .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .bool("GATHER", true)
+                .tensor(input, Some("IN"))
+                .tensor(output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: source.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: destination.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: output.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: input.batches_binding(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: output.batches_binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: input.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: output.binding(),
-                },
-            ],
+            entries: &entries,
         })];
 
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Swap the `token` and `batch` axes.
-    pub fn transpose(
+    /// Int8 matrix-vector multiplication.
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn matmul_vec_int8<'a>(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        minmax: &TensorGpu<f16, ReadWrite>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+    ) -> Result<Self, TensorError> {
+        Self::matmul_vec_int8_lora(matrix, minmax, input, output, epilogue, None)
+    }
+
+    /// Int8 matrix-vector multiplication which also adds the fp16 low-rank delta of a LoRA to the product, see [`LoraDelta`].
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    /// - `lora` shapes: `[r, R, 1]` and `[r, T, B]`.
+    pub fn matmul_vec_int8_lora<'a>(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        minmax: &TensorGpu<f16, ReadWrite>,
         input: TensorGpuView<impl Float>,
         output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+        lora: Option<LoraDelta<'_>>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = input.shape();
-        output.check_shape([shape[0], shape[2], shape[1], 1])?;
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            minmax.check_shape([(k << 1) / Self::INT8_BLOCK_SIZE as usize, m, b, 1])?;
+            matrix.check_shape([k, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            if let Some(lora) = &lora {
+                lora.check_shape(m, n, b)?;
+            }
+            output.shape()
+        };
 
-        let context = input.context();
+        let context = matrix.context();
+        #[cfg(not(feature = "subgroup-ops"))]
         let pipeline = context.checkout_pipeline(
-            "transpose",
-            include_str!("../shaders/reshape.wgsl"),
-            "transpose",
+            "matmul_vec_int8",
+            include_str!("../shaders/matmul_vec_int8.wgsl"),
+            "matmul",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(&input, Some("IN"))
-                .tensor(&output, Some("OUT")),
+            LoraDelta::macros(
+                lora.as_ref(),
+                Macros::new()
+                    .u32("BLOCK_SIZE", BLOCK_SIZE)
+                    .int8(Self::INT8_BLOCK_SIZE)
+                    .tensor(&input, Some("IN"))
+                    .tensor(&output, Some("OUT"))
+                    .epilogue(&epilogue),
+            ),
+        );
+        #[cfg(feature = "subgroup-ops")]
+        let pipeline = context.checkout_pipeline(
+            "matmul_vec_int8",
+            include_str!("../shaders/matmul_vec_int8.wgsl"),
+            "matmul",
+            None,
+            LoraDelta::macros(
+                lora.as_ref(),
+                Macros::new()
+                    .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
+                    .u32("BLOCK_SIZE", BLOCK_SIZE)
+                    .int8(Self::INT8_BLOCK_SIZE)
+                    .tensor(&input, Some("IN"))
+                    .tensor(&output, Some("OUT"))
+                    .epilogue(&epilogue),
+            ),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: minmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
+        entries.extend(lora.iter().flat_map(LoraDelta::bindings));
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: input.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: output.binding(),
-                },
-            ],
+            entries: &entries,
         })];
 
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn blend(
-        factor: &TensorGpu<f32, Uniform>,
-        input: &TensorGpu<impl Float, ReadWrite>,
-        output: &TensorGpu<impl Float, ReadWrite>,
+    /// Int8 matrix-vector multiplication with a scale for each row, applying `transform` to the product in the same kernel.
+    /// With all of the transform, the kernel takes all 8 storage buffers of a default device, leaving none for a bias in `epilogue`.
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `scale` shape: `[R, 1, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_int8_row<'a>(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        scale: &TensorGpu<f32, ReadWrite>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+        transform: LogitTransform<'a>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape();
-        input.check_shape(shape)?;
-        factor.check_shape([4, 1, 1, 1])?;
+        const BLOCK_SIZE: u32 = 128;
 
-        let block_size = match shape[1] {
-            x if x < 8 => [128, 1],
-            _ => [16, 16],
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            scale.check_shape([m, 1, b, 1])?;
+            matrix.check_shape([k, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            // the transform is indexed by the rows of the whole output
+            transform.check_shape(m, output.tensor.shape()[1])?;
+            output.shape()
         };
 
-        let context = output.context();
+        let context = matrix.context();
         let pipeline = context.checkout_pipeline(
-            "blend",
-            include_str!("../shaders/blend.wgsl"),
-            "blend",
+            "matmul_vec_int8_row",
+            include_str!("../shaders/matmul_vec_int8_row.wgsl"),
+            "matmul",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE_X", block_size[0])
-                .u32("BLOCK_SIZE_Y", block_size[1])
-                .tensor(input, Some("IN"))
-                .tensor(output, Some("OUT")),
+            transform.macros(
+                Macros::new()
+                    .u32("BLOCK_SIZE", BLOCK_SIZE)
+                    .tensor(&input, Some("IN"))
+                    .tensor(&output, Some("OUT"))
+                    .epilogue(&epilogue),
+            ),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: scale.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
+
+        // the parameters of the length adjustment and penalties are packed into one buffer of `[8, T]`
+        let mut ops = vec![];
+        let params: Option<TensorGpu<f32, ReadWrite>> = (transform.length.is_some()
+            || transform.penalty.is_some())
+        .then(|| context.tensor_init([8, output.tensor.shape()[1], 1, 1]));
+        if let (Some(params), Some(length)) = (&params, transform.length) {
+            let op = Self::blit(length.view(.., .., .., ..)?, params.view(0..4, .., .., ..)?)?;
+            ops.push(op);
+        }
+        if let (Some(params), Some((penalty, _))) = (&params, transform.penalty) {
+            let op = Self::blit(
+                penalty.view(.., .., .., ..)?,
+                params.view(4..8, .., .., ..)?,
+            )?;
+            ops.push(op);
+        }
+
+        if let Some((slots, bias)) = transform.bias {
+            entries.push(BindGroupEntry {
+                binding: 10,
+                resource: bias.meta_binding(),
+            });
+            entries.push(BindGroupEntry {
+                binding: 11,
+                resource: slots.binding(),
+            });
+            entries.push(BindGroupEntry {
+                binding: 12,
+                resource: bias.binding(),
+            });
+        }
+        if let Some(params) = &params {
+            entries.push(BindGroupEntry {
+                binding: 13,
+                resource: params.binding(),
+            });
+        }
+        if let Some((_, counts)) = transform.penalty {
+            entries.push(BindGroupEntry {
+                binding: 14,
+                resource: counts.binding(),
+            });
+        }
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: input.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: factor.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: output.binding(),
-                },
-            ],
+            entries: &entries,
         })];
 
-        Ok(Self::Atom {
+        let op = Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, block_size[0]),
-                Self::block_count(shape[1] as u32, block_size[1]),
-                shape[2] as u32,
-            ],
-        })
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+        };
+        match ops.is_empty() {
+            true => Ok(op),
+            false => {
+                ops.push(op);
+                Ok(Self::List(ops))
+            }
+        }
     }
 
-    pub fn blend_lora(
-        factor: &TensorGpu<f32, Uniform>,
-        xa: TensorGpuView<f16>,
-        xb: TensorGpuView<f16>,
-        output: TensorGpuView<f16>,
+    /// NFloat4 matrix-vector multiplication.
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_nf4<'a>(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        quant: &TensorGpu<f32, Uniform>,
+        absmax: &TensorGpu<f16, ReadWrite>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 8;
+        Self::matmul_vec_nf4_lora(matrix, quant, absmax, input, output, epilogue, None)
+    }
 
-        let shape = output.shape();
-        factor.check_shape([4, 1, 1, 1])?;
-        xa.check_shape([xa.shape()[0], shape[0], shape[2], 1])?;
-        xb.check_shape([xb.shape()[0], shape[1], shape[2], 1])?;
+    /// NFloat4 matrix-vector multiplication which also adds the fp16 low-rank delta of a LoRA to the product, see [`LoraDelta`].
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    /// - `lora` shapes: `[r, R, 1]` and `[r, T, B]`.
+    pub fn matmul_vec_nf4_lora<'a>(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        quant: &TensorGpu<f32, Uniform>,
+        absmax: &TensorGpu<f16, ReadWrite>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+        lora: Option<LoraDelta<'_>>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
 
-        let context = output.context();
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            absmax.check_shape([k / Self::NF4_BLOCK_SIZE as usize, m, b, 1])?;
+            matrix.check_shape([k >> 1, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            if let Some(lora) = &lora {
+                lora.check_shape(m, n, b)?;
+            }
+            output.shape()
+        };
+
+        let context = matrix.context();
+        #[cfg(not(feature = "subgroup-ops"))]
         let pipeline = context.checkout_pipeline(
-            "blend_lora",
-            include_str!("../shaders/blend_lora.wgsl"),
-            "blend_lora",
+            "matmul_vec_nf4",
+            include_str!("../shaders/matmul_vec_nf4.wgsl"),
+            "matmul",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
+            LoraDelta::macros(
+                lora.as_ref(),
+                Macros::new()
+                    .u32("BLOCK_SIZE", BLOCK_SIZE)
+                    .nf4(Self::NF4_BLOCK_SIZE)
+                    .tensor(&input, Some("IN"))
+                    .tensor(&output, Some("OUT"))
+                    .epilogue(&epilogue),
+            ),
+        );
+        #[cfg(feature = "subgroup-ops")]
+        let pipeline = context.checkout_pipeline(
+            "matmul_vec_nf4",
+            include_str!("../shaders/matmul_vec_nf4.wgsl"),
+            "matmul",
+            None,
+            LoraDelta::macros(
+                lora.as_ref(),
+                Macros::new()
+                    .subgroup(context.min_subgroup_size(), context.max_subgroup_size())
+                    .u32("BLOCK_SIZE", BLOCK_SIZE)
+                    .nf4(Self::NF4_BLOCK_SIZE)
+                    .tensor(&input, Some("IN"))
+                    .tensor(&output, Some("OUT"))
+                    .epilogue(&epilogue),
+            ),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: quant.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: absmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
+        entries.extend(lora.iter().flat_map(LoraDelta::bindings));
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: xa.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: xb.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: factor.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: xa.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: xb.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: output.binding(),
-                },
-            ],
+            entries: &entries,
         })];
 
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(Self::block_count(shape[0] as u32, 4), BLOCK_SIZE),
-                Self::block_count(Self::block_count(shape[1] as u32, 4), BLOCK_SIZE),
-                shape[2] as u32,
-            ],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn discount(
-        x: &TensorGpu<impl Float, ReadWrite>,
-        factor: f32,
-        bias: f32,
+    /// Fp8 matrix-vector multiplication.
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_fp8<'a>(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        absmax: &TensorGpu<f16, ReadWrite>,
+        format: Fp8Format,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let shape = x.shape();
-        let context = x.context();
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            absmax.check_shape([k / Self::FP8_BLOCK_SIZE as usize, m, b, 1])?;
+            matrix.check_shape([k, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
+        };
+
+        let context = matrix.context();
         let pipeline = context.checkout_pipeline(
-            "discount",
-            include_str!("../shaders/discount.wgsl"),
-            "discount",
+            "matmul_vec_fp8",
+            include_str!("../shaders/matmul_vec_fp8.wgsl"),
+            "matmul",
             None,
             Macros::new()
                 .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("FACTOR", factor)
-                .f32("BIAS", bias),
+                .fp8(Self::FP8_BLOCK_SIZE, format)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: absmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: x.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: x.binding(),
-                },
-            ],
+            entries: &entries,
         })];
 
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn quantize_mat_int8(
-        input: &TensorGpu<f16, ReadWrite>,
-        minmax: &TensorGpu<f16, ReadWrite>,
-        output: &TensorGpu<u8, ReadWrite>,
+    /// Fp16 matrix-matrix multiplication.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    ///
+    /// Note: `K` must be multiples of 128; `M` and `N` must be multiples of 4.
+    pub fn matmul_mat_fp16<'a>(
+        matrix: TensorGpuView<f16>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 128;
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            matrix.check_shape([k, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
+        };
 
         let context = output.context();
-        let shape = output.shape();
-        let minmax_shape = Shape::new(
-            (shape[0] << 1) / Self::INT8_BLOCK_SIZE as usize,
-            shape[1],
-            shape[2],
-            shape[3],
-        );
-
-        input.check_shape(shape)?;
-        minmax.check_shape(minmax_shape)?;
-
+        // `sa` and `sb` of `4 * BLOCK_SIZE * BLOCK_SIZE` packs each
+        let block_size = Self::tile_block_size(context, 8, |block| {
+            4 * block * block * (8 + 4 * Self::scalar_size(&input))
+        });
         let pipeline = context.checkout_pipeline(
-            "quant_mat_int8_minmax",
-            include_str!("../shaders/quant_mat_int8.wgsl"),
-            "compute_minmax",
+            "matmul_mat_fp16",
+            include_str!("../shaders/matmul_mat_fp16.wgsl"),
+            "matmul",
             None,
             Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .int8(Self::INT8_BLOCK_SIZE),
+                .u32("BLOCK_SIZE", block_size)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 1,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: minmax.binding(),
-                },
-            ],
+            entries: &entries,
         })];
-        let compute_minmax = Self::Atom {
+
+        Ok(Self::Atom {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(minmax_shape[0] as u32, BLOCK_SIZE),
-                minmax_shape[1] as u32,
-                minmax_shape[2] as u32,
+                Self::block_count(Self::block_count(shape[0] as u32, 4), block_size),
+                Self::block_count(Self::block_count(shape[1] as u32, 4), block_size),
+                shape[2] as u32,
             ],
+        })
+    }
+
+    /// Int8 matrix-matrix multiplication.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    ///
+    /// Note: `K` must be multiples of 128; `M` and `N` must be multiples of 4.
+    #[allow(clippy::too_many_arguments)]
+    pub fn matmul_mat_int8<'a>(
+        matrix: TensorGpuView<u8>,
+        minmax: &TensorGpu<f16, ReadWrite>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+    ) -> Result<Self, TensorError> {
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            minmax.check_shape([(k << 1) / Self::INT8_BLOCK_SIZE as usize, m, b, 1])?;
+            matrix.check_shape([k, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
         };
 
+        let context = output.context();
+        // `sa` and `sb` of `4 * BLOCK_SIZE * BLOCK_SIZE` packs each
+        let block_size = Self::tile_block_size(context, 8, |block| {
+            4 * block * block * (4 + 4 * Self::scalar_size(&input))
+        });
         let pipeline = context.checkout_pipeline(
-            "quant_mat_int8",
-            include_str!("../shaders/quant_mat_int8.wgsl"),
-            "quantize",
+            "matmul_mat_int8",
+            include_str!("../shaders/matmul_mat_int8.wgsl"),
+            "matmul",
             None,
             Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .int8(Self::INT8_BLOCK_SIZE),
+                .u32("BLOCK_SIZE", block_size)
+                .int8(Self::INT8_BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: minmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 1,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: minmax.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: output.binding(),
-                },
-            ],
+            entries: &entries,
         })];
-        let quantize = Self::Atom {
+
+        Ok(Self::Atom {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(shape[0] as u32, BLOCK_SIZE),
-                shape[1] as u32,
+                Self::block_count(Self::block_count(shape[0] as u32, 4), block_size),
+                Self::block_count(Self::block_count(shape[1] as u32, 4), block_size),
                 shape[2] as u32,
             ],
-        };
-
-        Ok(Self::List(vec![compute_minmax, quantize]))
+        })
     }
 
-    pub fn quantize_mat_nf4(
-        input: &TensorGpu<f16, ReadWrite>,
-        quant: &TensorGpu<f32, Uniform>,
-        absmax: &TensorGpu<f16, ReadWrite>,
-        output: &TensorGpu<u8, ReadWrite>,
+    /// K-quant matrix-vector multiplication.
+    /// The format is [`KQuantFormat::Q5K`] if `high` is given, and [`KQuantFormat::Q4K`] otherwise.
+    /// - `matrix` shape: `[C, R, B]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_kquant<'a>(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        minmax: &TensorGpu<f16, ReadWrite>,
+        scales: &TensorGpu<u8, ReadWrite>,
+        high: Option<&TensorGpu<u8, ReadWrite>>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
-        let context = output.context();
-        let shape = output.shape();
-        let input_shape = Shape::new(shape[0] << 1, shape[1], shape[2], shape[3]);
-        let absmax_shape = Shape::new(
-            input_shape[0] / Self::NF4_BLOCK_SIZE as usize,
-            shape[1],
-            shape[2],
-            shape[3],
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            Self::check_kquant_shape([k, m, b, 1], minmax, scales, high)?;
+            matrix.check_shape([k >> 1, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
+        };
+        let format = match high {
+            Some(_) => KQuantFormat::Q5K,
+            None => KQuantFormat::Q4K,
+        };
+
+        let context = matrix.context();
+        let pipeline = context.checkout_pipeline(
+            "matmul_vec_kquant",
+            include_str!("../shaders/matmul_vec_kquant.wgsl"),
+            "matmul",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .kquant(
+                    Self::KQUANT_BLOCK_SIZE,
+                    Self::KQUANT_SUPER_BLOCK_SIZE,
+                    format,
+                )
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: minmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: scales.binding(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: output.binding(),
+            },
+        ];
+        if let Some(high) = high {
+            entries.push(BindGroupEntry {
+                binding: 6,
+                resource: high.binding(),
+            });
+        }
+        entries.extend(epilogue.binding());
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &entries,
+        })];
 
-        input.check_shape(input_shape)?;
-        absmax.check_shape(absmax_shape)?;
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+        })
+    }
 
-        let absmax_f32: TensorGpu<f32, ReadWrite> = context.tensor_init(absmax_shape);
+    /// Fp8 matrix-matrix multiplication.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    ///
+    /// Note: `K` must be multiples of 128; `M` and `N` must be multiples of 4.
+    pub fn matmul_mat_fp8<'a>(
+        matrix: TensorGpuView<u8>,
+        absmax: &TensorGpu<f16, ReadWrite>,
+        format: Fp8Format,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+    ) -> Result<Self, TensorError> {
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            absmax.check_shape([k / Self::FP8_BLOCK_SIZE as usize, m, b, 1])?;
+            matrix.check_shape([k, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
+        };
 
+        let context = output.context();
+        // `sa` and `sb` of `4 * BLOCK_SIZE * BLOCK_SIZE` packs each
+        let block_size = Self::tile_block_size(context, 8, |block| {
+            4 * block * block * (4 + 4 * Self::scalar_size(&input))
+        });
         let pipeline = context.checkout_pipeline(
-            "quant_mat_nf4_absmax",
-            include_str!("../shaders/quant_mat_nf4.wgsl"),
-            "compute_absmax",
+            "matmul_mat_fp8",
+            include_str!("../shaders/matmul_mat_fp8.wgsl"),
+            "matmul",
             None,
             Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .nf4(Self::NF4_BLOCK_SIZE),
+                .u32("BLOCK_SIZE", block_size)
+                .fp8(Self::FP8_BLOCK_SIZE, format)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: absmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                // BindGroupEntry {
-                //     binding: 0,
-                //     resource: absmax_f32.meta_binding(),
-                // },
-                // BindGroupEntry {
-                //     binding: 1,
-                //     resource: quant.binding(),
-                // },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: absmax_f32.binding(),
-                },
-                // BindGroupEntry {
-                //     binding: 4,
-                //     resource: output.binding(),
-                // },
-            ],
+            entries: &entries,
         })];
-        let compute_absmax = Self::Atom {
+
+        Ok(Self::Atom {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(absmax_shape[0] as u32, BLOCK_SIZE),
-                absmax_shape[1] as u32,
-                absmax_shape[2] as u32,
+                Self::block_count(Self::block_count(shape[0] as u32, 4), block_size),
+                Self::block_count(Self::block_count(shape[1] as u32, 4), block_size),
+                shape[2] as u32,
             ],
+        })
+    }
+
+    /// K-quant matrix-matrix multiplication.
+    /// The format is [`KQuantFormat::Q5K`] if `high` is given, and [`KQuantFormat::Q4K`] otherwise.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    ///
+    /// Note: `K` must be multiples of 256; `M` and `N` must be multiples of 4.
+    pub fn matmul_mat_kquant<'a>(
+        matrix: TensorGpuView<u8>,
+        minmax: &TensorGpu<f16, ReadWrite>,
+        scales: &TensorGpu<u8, ReadWrite>,
+        high: Option<&TensorGpu<u8, ReadWrite>>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+    ) -> Result<Self, TensorError> {
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            Self::check_kquant_shape([k, m, b, 1], minmax, scales, high)?;
+            matrix.check_shape([k >> 1, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
+        };
+        let format = match high {
+            Some(_) => KQuantFormat::Q5K,
+            None => KQuantFormat::Q4K,
         };
 
+        let context = output.context();
+        // `sa`, `sh`, `ss` and `sb` of `4 * BLOCK_SIZE * BLOCK_SIZE` packs each
+        let block_size = Self::tile_block_size(context, 8, |block| {
+            4 * block * block * (16 + 8 * Self::scalar_size(&input))
+        });
         let pipeline = context.checkout_pipeline(
-            "quant_mat_nf4",
-            include_str!("../shaders/quant_mat_nf4.wgsl"),
-            "quantize",
+            "matmul_mat_kquant",
+            include_str!("../shaders/matmul_mat_kquant.wgsl"),
+            "matmul",
             None,
             Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .nf4(Self::NF4_BLOCK_SIZE),
+                .u32("BLOCK_SIZE", block_size)
+                .kquant(
+                    Self::KQUANT_BLOCK_SIZE,
+                    Self::KQUANT_SUPER_BLOCK_SIZE,
+                    format,
+                )
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
         );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: minmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: scales.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: output.binding(),
+            },
+        ];
+        if let Some(high) = high {
+            entries.push(BindGroupEntry {
+                binding: 5,
+                resource: high.binding(),
+            });
+        }
+        entries.extend(epilogue.binding());
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.layout,
-            entries: &[
-                // BindGroupEntry {
-                //     binding: 0,
-                //     resource: output.meta_binding(),
-                // },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: quant.binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: absmax_f32.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: output.binding(),
-                },
-            ],
+            entries: &entries,
         })];
-        let quantize = Self::Atom {
+
+        Ok(Self::Atom {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(shape[0] as u32, BLOCK_SIZE),
-                shape[1] as u32,
+                Self::block_count(Self::block_count(shape[0] as u32, 4), block_size),
+                Self::block_count(Self::block_count(shape[1] as u32, 4), block_size),
                 shape[2] as u32,
             ],
+        })
+    }
+
+    /// NFloat4 matrix-matrix multiplication.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    ///
+    /// Note: `K` must be multiples of 256; `M` and `N` must be multiples of 8.
+    pub fn matmul_mat_nf4<'a>(
+        matrix: TensorGpuView<u8>,
+        quant: &TensorGpu<f32, Uniform>,
+        absmax: &TensorGpu<f16, ReadWrite>,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        epilogue: impl Into<Epilogue<'a>>,
+    ) -> Result<Self, TensorError> {
+        let epilogue = epilogue.into();
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            absmax.check_shape([k / Self::NF4_BLOCK_SIZE as usize, m, b, 1])?;
+            matrix.check_shape([k >> 1, m, b, 1])?;
+            input.check_shape([k, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            epilogue.check_shape(m)?;
+            output.shape()
         };
 
-        let quantize_absmax = Self::blit(
-            absmax_f32.view(.., .., .., ..)?,
-            absmax.view(.., .., .., ..)?,
-        )?;
+        let context = output.context();
+        // `sa` and `sb` of `4 * BLOCK_SIZE * BLOCK_SIZE` packs each, and the quant table
+        let block_size = Self::tile_block_size(context, 8, |block| {
+            4 * block * block * (4 + 8 * Self::scalar_size(&input)) + 64
+        });
+        let pipeline = context.checkout_pipeline(
+            "matmul_mat_nf4",
+            include_str!("../shaders/matmul_mat_nf4.wgsl"),
+            "matmul",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", block_size)
+                .nf4(Self::NF4_BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .epilogue(&epilogue),
+        );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: matrix.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: quant.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: absmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: matrix.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: output.binding(),
+            },
+        ];
+        entries.extend(epilogue.binding());
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &entries,
+        })];
 
-        Ok(Self::List(vec![compute_absmax, quantize, quantize_absmax]))
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(Self::block_count(shape[0] as u32, 4), block_size),
+                Self::block_count(Self::block_count(shape[1] as u32, 4), block_size),
+                shape[2] as u32,
+            ],
+        })
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::f32::consts::PI;
 
-    use anyhow::Result;
-    use half::f16;
+    /// Add `input` to `output`.
+    /// - `input` shape: `[C, 1, B]` or `[C, T, B]`.
+    /// - `output` shape: `[C, T, B]`.
+    pub fn add(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = {
+            let [index, token, batch, _] = *output.shape();
+            input
+                .check_shape([index, 1, batch, 1])
+                .or(input.check_shape([index, token, batch, 1]))?;
+            output.check_shape([index, token, batch, 1])?;
+            output.shape()
+        };
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "add",
+            include_str!("../shaders/binary.wgsl"),
+            "add",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Multiply `input` to `output`.
+    /// - `input` shape: `[C, 1, B]` or `[C, T, B]`.
+    /// - `output` shape: `[C, T, B]`.
+    pub fn mul(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = {
+            let [index, token, batch, _] = *output.shape();
+            input
+                .check_shape([index, 1, batch, 1])
+                .or(input.check_shape([index, token, batch, 1]))?;
+            output.check_shape([index, token, batch, 1])?;
+            output.shape()
+        };
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "mul",
+            include_str!("../shaders/binary.wgsl"),
+            "mul",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Apply `op` to `output` and `input` elementwise, storing into `output`.
+    /// - `input` shape: `[C, 1, B]` or `[C, T, B]`.
+    /// - `output` shape: `[C, T, B]`.
+    pub fn binary(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        op: BinaryOp,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = {
+            let [index, token, batch, _] = *output.shape();
+            input
+                .check_shape([index, 1, batch, 1])
+                .or(input.check_shape([index, token, batch, 1]))?;
+            output.check_shape([index, token, batch, 1])?;
+            output.shape()
+        };
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "binary",
+            include_str!("../shaders/elementwise.wgsl"),
+            "binary",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .custom(op, Some("OP"))
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Subtract `input` from `output`.
+    /// - `input` shape: `[C, 1, B]` or `[C, T, B]`.
+    /// - `output` shape: `[C, T, B]`.
+    pub fn sub(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        Self::binary(input, output, BinaryOp::Sub)
+    }
+
+    /// Apply `op` to each element of `x` in place.
+    pub fn unary(x: TensorGpuView<impl Float>, op: UnaryOp) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "unary",
+            include_str!("../shaders/elementwise.wgsl"),
+            "unary",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .custom(op, Some("OP"))
+                .tensor(&x, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Exponential of each element of `x` in place.
+    pub fn exp(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
+        Self::unary(x.view(.., .., .., ..)?, UnaryOp::Exp)
+    }
+
+    /// Sigmoid of each element of `x` in place.
+    pub fn sigmoid(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
+        Self::unary(x.view(.., .., .., ..)?, UnaryOp::Sigmoid)
+    }
+
+    /// Reduce each token of `input` over the channel axis by `op`, with a tree reduction within a workgroup.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[1, T, B]`.
+    pub fn reduce(
+        input: TensorGpuView<impl Float>,
+        output: &TensorGpu<f32, ReadWrite>,
+        op: ReduceOp,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = input.shape();
+        output.check_shape([1, shape[1], shape[2], 1])?;
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "reduce",
+            include_str!("../shaders/reduce.wgsl"),
+            "reduce",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .custom(op, Some("OP"))
+                .tensor(&input, Some("IN")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
+        })
+    }
+
+    /// Sum each token of `input` over the channel axis into `output` of shape `[1, T, B]`.
+    pub fn reduce_sum(
+        input: TensorGpuView<impl Float>,
+        output: &TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        Self::reduce(input, output, ReduceOp::Sum)
+    }
+
+    /// Average each token of `input` over the channel axis into `output` of shape `[1, T, B]`.
+    pub fn reduce_mean(
+        input: TensorGpuView<impl Float>,
+        output: &TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        Self::reduce(input, output, ReduceOp::Mean)
+    }
+
+    /// Take the maximum of each token of `input` over the channel axis into `output` of shape `[1, T, B]`.
+    pub fn reduce_max(
+        input: TensorGpuView<impl Float>,
+        output: &TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        Self::reduce(input, output, ReduceOp::Max)
+    }
+
+    pub fn token_shift(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        time_mix: TensorGpuView<impl Float>,
+        state: TensorGpuView<f32>,
+        input: &TensorGpu<impl Float, ReadWrite>,
+        output: &TensorGpu<impl Float, ReadWrite>,
+        reversed: bool,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = {
+            let [index, token, count, _] = *output.shape();
+            let [_, head, batch, _] = *state.shape();
+            input
+                .check_shape([index, token, 1, 1])
+                .or(input.check_shape([index, token, count, 1]))?;
+            time_mix
+                .check_shape([index, 1, 1, 1])
+                .or(time_mix.check_shape([index, token, count, 1]))?;
+            state.check_shape([index, head, batch, 1])?;
+            output.shape()
+        };
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "token_shift",
+            include_str!("../shaders/token_shift.wgsl"),
+            "token_shift",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&time_mix, Some("TIME_MIX"))
+                .tensor(input, Some("IN"))
+                .tensor(output, Some("OUT"))
+                .bool("REVERSED", reversed),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: time_mix.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: time_mix.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn time_mix_v4<T: Float>(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        time_decay: &TensorGpu<f32, ReadWrite>,
+        time_first: &TensorGpu<f32, ReadWrite>,
+        state: TensorGpuView<f32>,
+        k: &TensorGpu<T, ReadWrite>,
+        v: &TensorGpu<T, ReadWrite>,
+        r: &TensorGpu<T, ReadWrite>,
+        x: &TensorGpu<T, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        k.check_shape(shape)?;
+        v.check_shape(shape)?;
+        r.check_shape(shape)?;
+        time_decay.check_shape([shape[0], 1, 1, 1])?;
+        time_first.check_shape([shape[0], 1, 1, 1])?;
+        state.check_shape([shape[0], 4, state.shape()[2], 1])?;
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "time_mix_v4",
+            include_str!("../shaders/time_mix_v4.wgsl"),
+            "time_mix",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: time_decay.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: time_first.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: k.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE), 1, 1],
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn time_mix_v5<T: Float>(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        time_decay: &TensorGpu<f32, ReadWrite>,
+        time_first: &TensorGpu<f32, ReadWrite>,
+        state: TensorGpuView<f32>,
+        k: &TensorGpu<T, ReadWrite>,
+        v: &TensorGpu<T, ReadWrite>,
+        r: &TensorGpu<T, ReadWrite>,
+        x: &TensorGpu<T, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 32;
+
+        let shape = x.shape();
+        let dim = shape[0] * shape[1];
+
+        k.check_shape(shape)?;
+        v.check_shape(shape)?;
+        r.check_shape(shape)?;
+        time_decay.check_shape([shape[0], shape[1], 1, 1])?;
+        time_first.check_shape([shape[0], shape[1], 1, 1])?;
+        state.check_shape([dim, shape[0] + 1, state.shape()[2], 1])?;
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "time_mix_v5",
+            include_str!("../shaders/time_mix_v5.wgsl"),
+            "time_mix",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: time_decay.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: time_first.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: k.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::block_count(dim as u32 / 4, BLOCK_SIZE), 1, 1],
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn time_mix_v6<T: Float>(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        time_decay: &TensorGpu<f32, ReadWrite>,
+        time_first: &TensorGpu<f32, ReadWrite>,
+        state: TensorGpuView<f32>,
+        k: &TensorGpu<T, ReadWrite>,
+        v: &TensorGpu<T, ReadWrite>,
+        r: &TensorGpu<T, ReadWrite>,
+        x: &TensorGpu<T, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 32;
+
+        let shape = x.shape();
+        let dim = shape[0] * shape[1];
+
+        k.check_shape(shape)?;
+        v.check_shape(shape)?;
+        r.check_shape(shape)?;
+        time_decay.check_shape(shape)?;
+        time_first.check_shape([shape[0], shape[1], 1, 1])?;
+        state.check_shape([dim, shape[0] + 1, state.shape()[2], 1])?;
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "time_mix_v6",
+            include_str!("../shaders/time_mix_v6.wgsl"),
+            "time_mix",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: time_decay.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: time_first.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: k.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::block_count(dim as u32 / 4, BLOCK_SIZE), 1, 1],
+        })
+    }
+
+    pub fn silu(
+        input: &TensorGpu<impl Float, ReadWrite>,
+        output: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = output.shape();
+        input.check_shape(shape)?;
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "silu",
+            include_str!("../shaders/silu.wgsl"),
+            "silu",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(input, Some("IN"))
+                .tensor(output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn tanh(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "tanh",
+            include_str!("../shaders/activation.wgsl"),
+            "act_tanh",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn opposite_exp(x: &TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "opposite_exp",
+            include_str!("../shaders/activation.wgsl"),
+            "opposite_exp",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn stable_exp(x: &TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "stable_exp",
+            include_str!("../shaders/activation.wgsl"),
+            "stable_exp",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn squared_relu(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "squared_relu",
+            include_str!("../shaders/activation.wgsl"),
+            "squared_relu",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn channel_mix<T: Float>(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        state: TensorGpuView<f32>,
+        r: &TensorGpu<T, ReadWrite>,
+        v: &TensorGpu<T, ReadWrite>,
+        x: &TensorGpu<T, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        v.check_shape(shape)?;
+        r.check_shape(shape)?;
+        state.check_shape([shape[0], 1, state.shape()[2], 1])?;
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "channel_mix",
+            include_str!("../shaders/channel_mix.wgsl"),
+            "channel_mix",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                1,
+            ],
+        })
+    }
+
+    /// Scale each token of `x` by the factor of the batch it belongs to.
+    /// - `cursors` shape: `[2, A, 1]`, see [`Cursor::pack`].
+    /// - `factor` shape: `[1, 1, B]`.
+    /// - `x` shape: `[C, A, 1]`.
+    pub fn scale_batch(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        factor: &TensorGpu<f32, ReadWrite>,
+        x: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        x.check_shape([shape[0], shape[1], 1, 1])?;
+        cursors.check_shape([Cursor::PACKED_SIZE, shape[1], 1, 1])?;
+        factor.check_shape([1, 1, factor.shape()[2], 1])?;
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "scale_batch",
+            include_str!("../shaders/scale_batch.wgsl"),
+            "scale_batch",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                1,
+            ],
+        })
+    }
+
+    /// Replace the embeddings of tokens in marked batches by rows of `source`, taking the token ids as row indices.
+    /// Tokens of unmarked batches, or with ids beyond the rows of `source`, are left untouched.
+    /// - `cursors` shape: `[2, A, 1]`, see [`Cursor::pack`].
+    /// - `tokens` shape: `[A, 1, 1]`.
+    /// - `active` shape: `[B, 1, 1]`, non-zero for batches to replace.
+    /// - `source` shape: `[C, N, B]`.
+    /// - `output` shape: `[C, A, 1]`.
+    pub fn inject(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        tokens: &TensorGpu<u32, ReadWrite>,
+        active: &TensorGpu<u32, ReadWrite>,
+        source: &TensorGpu<f16, ReadWrite>,
+        output: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = output.shape();
+        let [index, _, batch, _] = *source.shape();
+        output.check_shape([index, shape[1], 1, 1])?;
+        cursors.check_shape([Cursor::PACKED_SIZE, shape[1], 1, 1])?;
+        tokens.check_shape([shape[1], 1, 1, 1])?;
+        active.check_shape([batch, 1, 1, 1])?;
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "inject",
+            include_str!("../shaders/inject.wgsl"),
+            "inject",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(output, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: source.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: tokens.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: active.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: source.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                1,
+            ],
+        })
+    }
+
+    /// Copy the content of `input` into `output` of the same shape.
+    pub fn blit(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        input.check_shape(shape)?;
+
+        let block_size = match shape[1] {
+            x if x < 8 => [128, 1],
+            _ => [16, 16],
+        };
+
+        let context = input.context();
+        let pipeline = context.checkout_pipeline(
+            "blit",
+            include_str!("../shaders/blit.wgsl"),
+            "blit",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE_X", block_size[0])
+                .u32("BLOCK_SIZE_Y", block_size[1])
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, block_size[0]),
+                Self::block_count(shape[1] as u32, block_size[1]),
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Quantize rows of a state into Int8 codes, each row scaled by its absolute maximum.
+    /// - `input` shape: `[C, S, B]`.
+    /// - `scale` shape: `[1, S, B]`, the rows of the tensor of `output`.
+    /// - `output` shape: `[C, S, B]`.
+    pub fn quantize_state(
+        input: TensorGpuView<f32>,
+        scale: &TensorGpu<f32, ReadWrite>,
+        output: TensorGpuView<u8>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = output.shape();
+        input.check_shape(shape)?;
+        let [_, num_row, num_batch, _] = *output.tensor().shape();
+        scale.check_shape([1, num_row, num_batch, 1])?;
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "quant_state",
+            include_str!("../shaders/quant_state.wgsl"),
+            "quantize",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .bool("QUANT", true),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: scale.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
+        })
+    }
+
+    /// Dequantize rows of a state quantized by [`TensorOp::quantize_state`].
+    /// - `input` shape: `[C, S, B]`.
+    /// - `scale` shape: `[1, S, B]`, the rows of the tensor of `input`.
+    /// - `output` shape: `[C, S, B]`.
+    pub fn dequantize_state(
+        input: TensorGpuView<u8>,
+        scale: &TensorGpu<f32, ReadWrite>,
+        output: TensorGpuView<f32>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = output.shape();
+        input.check_shape(shape)?;
+        let [_, num_row, num_batch, _] = *input.tensor().shape();
+        scale.check_shape([1, num_row, num_batch, 1])?;
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "dequant_state",
+            include_str!("../shaders/quant_state.wgsl"),
+            "dequantize",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: scale.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
+        })
+    }
+
+    /// Repeat the content of `input` into `output` along the token and batch axes.
+    pub fn broadcast(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = output.shape();
+        input.check_shape([shape[0], input.shape()[1], input.shape()[2], 1])?;
+
+        let context = input.context();
+        let pipeline = context.checkout_pipeline(
+            "broadcast",
+            include_str!("../shaders/reshape.wgsl"),
+            "broadcast",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Swap the `token` and `batch` axes. See [`TensorOp::permute`] for other permutations.
+    pub fn transpose(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = input.shape();
+        output.check_shape([shape[0], shape[2], shape[1], 1])?;
+
+        let context = input.context();
+        let pipeline = context.checkout_pipeline(
+            "transpose",
+            include_str!("../shaders/reshape.wgsl"),
+            "transpose",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Permute the `channel`, `token` and `batch` axes: axis `i` of `output` is axis `axes[i]` of `input`.
+    /// For example, `[1, 0, 2]` transposes each batch of matrices, and `[0, 2, 1]` does what [`TensorOp::transpose`] does.
+    ///
+    /// Elements are read with strides, so the channel axis may move; the channels of `output` must be a multiple of 4.
+    pub fn permute(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        axes: [usize; 3],
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let mut sorted = axes;
+        sorted.sort_unstable();
+        if sorted != [0, 1, 2] {
+            return Err(TensorError::Permute(axes));
+        }
+
+        let shape = input.shape();
+        output.check_shape([shape[axes[0]], shape[axes[1]], shape[axes[2]], 1])?;
+        let shape = output.shape();
+
+        let context = input.context();
+        let pipeline = context.checkout_pipeline(
+            "permute",
+            include_str!("../shaders/permute.wgsl"),
+            "permute",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .u32("AXIS_0", axes[0] as u32)
+                .u32("AXIS_1", axes[1] as u32)
+                .u32("AXIS_2", axes[2] as u32)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn blend(
+        factor: &TensorGpu<f32, Uniform>,
+        input: &TensorGpu<impl Float, ReadWrite>,
+        output: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        input.check_shape(shape)?;
+        factor.check_shape([4, 1, 1, 1])?;
+
+        let block_size = match shape[1] {
+            x if x < 8 => [128, 1],
+            _ => [16, 16],
+        };
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "blend",
+            include_str!("../shaders/blend.wgsl"),
+            "blend",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE_X", block_size[0])
+                .u32("BLOCK_SIZE_Y", block_size[1])
+                .tensor(input, Some("IN"))
+                .tensor(output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, block_size[0]),
+                Self::block_count(shape[1] as u32, block_size[1]),
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn blend_lora(
+        factor: &TensorGpu<f32, Uniform>,
+        xa: TensorGpuView<f16>,
+        xb: TensorGpuView<f16>,
+        output: TensorGpuView<f16>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        factor.check_shape([4, 1, 1, 1])?;
+        xa.check_shape([xa.shape()[0], shape[0], shape[2], 1])?;
+        xb.check_shape([xb.shape()[0], shape[1], shape[2], 1])?;
+
+        let context = output.context();
+        // `sa` and `sb` of `16 * BLOCK_SIZE * BLOCK_SIZE` packs each
+        let block_size = Self::tile_block_size(context, 8, |block| 2 * 16 * block * block * 8);
+        let pipeline = context.checkout_pipeline(
+            "blend_lora",
+            include_str!("../shaders/blend_lora.wgsl"),
+            "blend_lora",
+            None,
+            Macros::new().u32("BLOCK_SIZE", block_size),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: xa.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: xb.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: xa.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: xb.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(Self::block_count(shape[0] as u32, 4), block_size),
+                Self::block_count(Self::block_count(shape[1] as u32, 4), block_size),
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn discount(
+        x: &TensorGpu<impl Float, ReadWrite>,
+        factor: f32,
+        bias: f32,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "discount",
+            include_str!("../shaders/discount.wgsl"),
+            "discount",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("FACTOR", factor)
+                .f32("BIAS", bias),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Inject random noise into `x` in place.
+    /// The noise only depends on `seed` (the first two components) and the position of each element.
+    pub fn noise(
+        x: &TensorGpu<impl Float, ReadWrite>,
+        noise: Noise,
+        seed: &TensorGpu<u32, Uniform>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        seed.check_shape([4, 1, 1, 1])?;
+
+        let (dropout, amount) = match noise {
+            Noise::Gaussian(std) => (false, std),
+            Noise::Dropout(p) => (true, p.clamp(0.0, 0.999)),
+        };
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "noise",
+            include_str!("../shaders/noise.wgsl"),
+            "add_noise",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .bool("DROPOUT", dropout)
+                .f32("AMOUNT", amount),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: seed.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn quantize_mat_int8(
+        input: &TensorGpu<f16, ReadWrite>,
+        minmax: &TensorGpu<f16, ReadWrite>,
+        output: &TensorGpu<u8, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let context = output.context();
+        let shape = output.shape();
+        let minmax_shape = Shape::new(
+            (shape[0] << 1) / Self::INT8_BLOCK_SIZE as usize,
+            shape[1],
+            shape[2],
+            shape[3],
+        );
+
+        input.check_shape(shape)?;
+        minmax.check_shape(minmax_shape)?;
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_int8_minmax",
+            include_str!("../shaders/quant_mat_int8.wgsl"),
+            "compute_minmax",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .int8(Self::INT8_BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: minmax.binding(),
+                },
+            ],
+        })];
+        let compute_minmax = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(minmax_shape[0] as u32, BLOCK_SIZE),
+                minmax_shape[1] as u32,
+                minmax_shape[2] as u32,
+            ],
+        };
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_int8",
+            include_str!("../shaders/quant_mat_int8.wgsl"),
+            "quantize",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .int8(Self::INT8_BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: minmax.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+        let quantize = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        };
+
+        Ok(Self::List(vec![compute_minmax, quantize]))
+    }
+
+    /// Quantize a matrix to `Int8` with a scale for each row, `x = s * w / 127`.
+    /// - `input` shape: `[C, R, B]`.
+    /// - `scale` shape: `[R, 1, B]`, the absolute maximum of each row.
+    /// - `output` shape: `[C, R, B]`.
+    pub fn quantize_mat_int8_row(
+        input: &TensorGpu<f16, ReadWrite>,
+        scale: &TensorGpu<f32, ReadWrite>,
+        output: &TensorGpu<u8, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let context = output.context();
+        let shape = output.shape();
+        input.check_shape(shape)?;
+        scale.check_shape([shape[1], 1, shape[2], 1])?;
+
+        let dispatch = [
+            Self::block_count(shape[1] as u32, BLOCK_SIZE),
+            1,
+            shape[2] as u32,
+        ];
+        let ops = ["compute_scale", "quantize"].map(|entry| {
+            let pipeline = context.checkout_pipeline(
+                format!("quant_mat_int8_row_{entry}"),
+                include_str!("../shaders/quant_mat_int8_row.wgsl"),
+                entry,
+                None,
+                Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
+            );
+            let mut entries = vec![
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: scale.binding(),
+                },
+            ];
+            if entry == "quantize" {
+                entries.push(BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                });
+            }
+            let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.layout,
+                entries: &entries,
+            })];
+            Self::Atom {
+                pipeline,
+                bindings,
+                dispatch,
+            }
+        });
+        Ok(Self::List(ops.into()))
+    }
+
+    pub fn quantize_mat_nf4(
+        input: &TensorGpu<f16, ReadWrite>,
+        quant: &TensorGpu<f32, Uniform>,
+        absmax: &TensorGpu<f16, ReadWrite>,
+        output: &TensorGpu<u8, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let context = output.context();
+        let shape = output.shape();
+        let input_shape = Shape::new(shape[0] << 1, shape[1], shape[2], shape[3]);
+        let absmax_shape = Shape::new(
+            input_shape[0] / Self::NF4_BLOCK_SIZE as usize,
+            shape[1],
+            shape[2],
+            shape[3],
+        );
+
+        input.check_shape(input_shape)?;
+        absmax.check_shape(absmax_shape)?;
+
+        let absmax_f32: TensorGpu<f32, ReadWrite> = context.tensor_init(absmax_shape);
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_nf4_absmax",
+            include_str!("../shaders/quant_mat_nf4.wgsl"),
+            "compute_absmax",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .nf4(Self::NF4_BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                // BindGroupEntry {
+                //     binding: 0,
+                //     resource: absmax_f32.meta_binding(),
+                // },
+                // BindGroupEntry {
+                //     binding: 1,
+                //     resource: quant.binding(),
+                // },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: absmax_f32.binding(),
+                },
+                // BindGroupEntry {
+                //     binding: 4,
+                //     resource: output.binding(),
+                // },
+            ],
+        })];
+        let compute_absmax = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(absmax_shape[0] as u32, BLOCK_SIZE),
+                absmax_shape[1] as u32,
+                absmax_shape[2] as u32,
+            ],
+        };
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_nf4",
+            include_str!("../shaders/quant_mat_nf4.wgsl"),
+            "quantize",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .nf4(Self::NF4_BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                // BindGroupEntry {
+                //     binding: 0,
+                //     resource: output.meta_binding(),
+                // },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: quant.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: absmax_f32.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+        let quantize = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        };
+
+        let quantize_absmax = Self::blit(
+            absmax_f32.view(.., .., .., ..)?,
+            absmax.view(.., .., .., ..)?,
+        )?;
+
+        Ok(Self::List(vec![compute_absmax, quantize, quantize_absmax]))
+    }
+
+    pub fn quantize_mat_fp8(
+        input: &TensorGpu<f16, ReadWrite>,
+        absmax: &TensorGpu<f16, ReadWrite>,
+        output: &TensorGpu<u8, ReadWrite>,
+        format: Fp8Format,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let context = output.context();
+        let shape = output.shape();
+        let absmax_shape = Shape::new(
+            shape[0] / Self::FP8_BLOCK_SIZE as usize,
+            shape[1],
+            shape[2],
+            shape[3],
+        );
+
+        input.check_shape(shape)?;
+        absmax.check_shape(absmax_shape)?;
+
+        let absmax_f32: TensorGpu<f32, ReadWrite> = context.tensor_init(absmax_shape);
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_fp8_absmax",
+            include_str!("../shaders/quant_mat_fp8.wgsl"),
+            "compute_absmax",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .fp8(Self::FP8_BLOCK_SIZE, format),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: absmax_f32.binding(),
+                },
+            ],
+        })];
+        let compute_absmax = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(absmax_shape[0] as u32, BLOCK_SIZE),
+                absmax_shape[1] as u32,
+                absmax_shape[2] as u32,
+            ],
+        };
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_fp8",
+            include_str!("../shaders/quant_mat_fp8.wgsl"),
+            "quantize",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .fp8(Self::FP8_BLOCK_SIZE, format),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: absmax_f32.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+        let quantize = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        };
+
+        let quantize_absmax = Self::blit(
+            absmax_f32.view(.., .., .., ..)?,
+            absmax.view(.., .., .., ..)?,
+        )?;
+
+        Ok(Self::List(vec![compute_absmax, quantize, quantize_absmax]))
+    }
+
+    /// Check the shapes of the side tensors of a k-quant matrix of shape `[K, M, B]`.
+    fn check_kquant_shape(
+        shape: impl Into<Shape>,
+        minmax: &TensorGpu<f16, ReadWrite>,
+        scales: &TensorGpu<u8, ReadWrite>,
+        high: Option<&TensorGpu<u8, ReadWrite>>,
+    ) -> Result<(), TensorError> {
+        let [k, m, b, _] = *shape.into();
+        let super_blocks = k / Self::KQUANT_SUPER_BLOCK_SIZE as usize;
+        let blocks = k / Self::KQUANT_BLOCK_SIZE as usize;
+        minmax.check_shape([super_blocks << 1, m, b, 1])?;
+        scales.check_shape([blocks << 1, m, b, 1])?;
+        if let Some(high) = high {
+            high.check_shape([k >> 3, m, b, 1])?;
+        }
+        Ok(())
+    }
+
+    /// Quantize a matrix into k-quant blocks.
+    /// The format is [`KQuantFormat::Q5K`] if `high` is given, and [`KQuantFormat::Q4K`] otherwise.
+    /// - `input` shape: `[K, M, B]`.
+    /// - `minmax` shape: `[2K / 256, M, B]`, the super-block scales and minimums.
+    /// - `scales` shape: `[2K / 32, M, B]`, the 6-bit block scales and minimums.
+    /// - `output` shape: `[K / 2, M, B]`, the low nibbles.
+    /// - `high` shape: `[K / 8, M, B]`, the high bits.
+    pub fn quantize_mat_kquant(
+        input: &TensorGpu<f16, ReadWrite>,
+        minmax: &TensorGpu<f16, ReadWrite>,
+        scales: &TensorGpu<u8, ReadWrite>,
+        output: &TensorGpu<u8, ReadWrite>,
+        high: Option<&TensorGpu<u8, ReadWrite>>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let context = output.context();
+        let shape = input.shape();
+        let size = shape[0].next_multiple_of(Self::KQUANT_SUPER_BLOCK_SIZE as usize);
+        if size != shape[0] {
+            return Err(TensorError::Size(shape[0], size));
+        }
+        Self::check_kquant_shape(shape, minmax, scales, high)?;
+        output.check_shape([shape[0] >> 1, shape[1], shape[2], 1])?;
+
+        let format = match high {
+            Some(_) => KQuantFormat::Q5K,
+            None => KQuantFormat::Q4K,
+        };
+        let macros = Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).kquant(
+            Self::KQUANT_BLOCK_SIZE,
+            Self::KQUANT_SUPER_BLOCK_SIZE,
+            format,
+        );
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_kquant_scale",
+            include_str!("../shaders/quant_mat_kquant.wgsl"),
+            "compute_scale",
+            None,
+            macros.clone(),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: minmax.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: scales.binding(),
+                },
+            ],
+        })];
+        let compute_scale = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / Self::KQUANT_SUPER_BLOCK_SIZE, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        };
+
+        let pipeline = context.checkout_pipeline(
+            "quant_mat_kquant",
+            include_str!("../shaders/quant_mat_kquant.wgsl"),
+            "quantize",
+            None,
+            macros,
+        );
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: input.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: minmax.binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: scales.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: output.binding(),
+            },
+        ];
+        if let Some(high) = high {
+            entries.push(BindGroupEntry {
+                binding: 5,
+                resource: high.binding(),
+            });
+        }
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &entries,
+        })];
+        let quantize = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / Self::KQUANT_BLOCK_SIZE, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        };
+
+        Ok(Self::List(vec![compute_scale, quantize]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use anyhow::Result;
+    use half::f16;
     use itertools::Itertools;
     use wgpu::{Instance, PowerPreference};
     // use wgpu_profiler::GpuProfiler;
 
-    use super::TensorOp;
-    use crate::{
-        context::{Context, ContextBuilder, InstanceExt},
-        tensor::{ops::Activation, Shape, TensorGpu},
-    };
+    use super::{LogitTransform, LoraDelta, TensorOp};
+    use crate::{
+        context::{Context, ContextBuilder, InstanceExt},
+        tensor::{
+            kind::ReadWrite,
+            matrix::{Matrix, Nf4Quant},
+            ops::{
+                Activation, BatchCopy, BinaryOp, Epilogue, Fp8Format, KQuantFormat, Noise,
+                ReduceOp, TensorCommand, UnaryOp,
+            },
+            Cursor, IntoPackedCursors, Shape, TensorError, TensorGpu,
+        },
+    };
+
+    fn is_approx(a: f32, b: f32) -> bool {
+        (a - b).abs() <= f32::max(f32::EPSILON, f32::max(a.abs(), b.abs()) * f32::EPSILON)
+    }
+
+    fn is_approx_eps(a: f32, b: f32, eps: f32) -> bool {
+        (a - b).abs() <= f32::max(eps, f32::max(a.abs(), b.abs()) * eps)
+    }
+
+    async fn create_context() -> Result<Context> {
+        let instance = Instance::default();
+        let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+        let context = ContextBuilder::new(adapter)
+            // .features(Features::TIMESTAMP_QUERY | Features::TIMESTAMP_QUERY_INSIDE_PASSES)
+            .build()
+            .await?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_softmax_masked() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 3;
+        const W: usize = C.div_ceil(32);
+
+        let x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        // batch 0 keeps random tokens, batch 1 keeps all, and batch 2 keeps none
+        let keep = (0..B)
+            .map(|batch| {
+                (0..C)
+                    .map(|_| match batch {
+                        0 => fastrand::bool(),
+                        1 => true,
+                        _ => false,
+                    })
+                    .collect_vec()
+            })
+            .collect_vec();
+        let mask = keep
+            .iter()
+            .flat_map(|keep| {
+                let mut words = vec![0u32; W];
+                for (token, _) in keep.iter().enumerate().filter(|(_, &x)| x) {
+                    words[token / 32] |= 1 << (token % 32);
+                }
+                words
+            })
+            .collect_vec();
+
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let mask_dev: TensorGpu<u32, _> = context.tensor_from_data([W, 1, B, 1], mask)?;
+        let op = TensorOp::softmax_masked(&x_dev, &mask_dev)?;
+        context.submit(context.encode(&op));
+        let x_host = x_dev.back_in_place().to_vec();
+
+        for (row, (x, y)) in x.chunks(C).zip_eq(x_host.chunks(C)).enumerate() {
+            let keep = &keep[row / T];
+            let max = x
+                .iter()
+                .zip(keep)
+                .filter(|(_, &keep)| keep)
+                .map(|(&x, _)| x)
+                .reduce(f32::max);
+            let sum: f32 = x
+                .iter()
+                .zip(keep)
+                .filter(|(_, &keep)| keep)
+                .map(|(&x, _)| (x - max.unwrap()).exp())
+                .sum();
+            for ((&x, &y), &keep) in x.iter().zip(y).zip(keep) {
+                let expected = match keep {
+                    true => (x - max.unwrap()).exp() / sum,
+                    false => 0.0,
+                };
+                assert!(is_approx_eps(expected, y, 1.0e-3), "{expected} vs {y}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 256;
+        const R: usize = 64;
+        const T: usize = 2;
+        const B: usize = 5;
+
+        let x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        let matrix = (0..C * R)
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .collect_vec();
+        let row = |batch: usize, token: usize| &x[(batch * T + token) * C..][..C];
+
+        // a repeated batch can be read twice, but written only once
+        let read = [3, 0, 3];
+        let write = [4, 1, 2];
+
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let softmax_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let matrix_dev: TensorGpu<f16, _> =
+            context.tensor_from_data([C, R, 1, 1], matrix.clone())?;
+        let output_dev: TensorGpu<f32, _> = context.tensor_init([R, T, B, 1]);
+        let argmax_dev: TensorGpu<u32, _> = context.tensor_init([1, T, read.len(), 1]);
+        let sample_dev: TensorGpu<u32, _> = context.tensor_init([1, T, read.len(), 1]);
+        let params = [0.0, 1.0, 0.5, 0.0].repeat(T * read.len());
+        let params_dev: TensorGpu<f32, _> =
+            context.tensor_from_data([4, T, read.len(), 1], params)?;
+
+        let ops = TensorOp::List(vec![
+            TensorOp::softmax_gather(&softmax_dev.gather(&write)?)?,
+            TensorOp::argmax_gather(&x_dev.gather(&read)?, &argmax_dev)?,
+            TensorOp::sample_gather(&x_dev.gather(&read)?, &params_dev, &sample_dev, 0.0)?,
+            TensorOp::matmul_vec_fp16_gather(
+                &matrix_dev,
+                &x_dev.gather(&read)?,
+                &output_dev.gather(&write)?,
+                Activation::None,
+            )?,
+        ]);
+        context.submit(context.encode(&ops));
+
+        let softmax_host = softmax_dev.back_in_place().to_vec();
+        for batch in 0..B {
+            for token in 0..T {
+                let x = row(batch, token);
+                let y = &softmax_host[(batch * T + token) * C..][..C];
+                if !write.contains(&batch) {
+                    assert_eq!(x, y);
+                    continue;
+                }
+                let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let sum: f32 = x.iter().map(|x| (x - max).exp()).sum();
+                for (&x, &y) in x.iter().zip(y) {
+                    let expected = (x - max).exp() / sum;
+                    assert!(is_approx_eps(expected, y, 1.0e-3), "{expected} vs {y}");
+                }
+            }
+        }
+
+        let argmax_host = argmax_dev.back_in_place().to_vec();
+        let sample_host = sample_dev.back_in_place().to_vec();
+        let output_host = output_dev.back_in_place().to_vec();
+        for (index, &batch) in read.iter().enumerate() {
+            for token in 0..T {
+                let x = row(batch, token);
+                let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let argmax = x.iter().position(|&x| x == max).unwrap() as u32;
+                assert_eq!(argmax_host[index * T + token], argmax);
+                assert_eq!(sample_host[index * T + token], argmax);
+
+                let y = &output_host[(write[index] * T + token) * R..][..R];
+                for (line, &y) in y.iter().enumerate() {
+                    let expected = matrix[line * C..][..C]
+                        .iter()
+                        .zip(x)
+                        .fold(0.0f32, |acc, (m, x)| acc + m.to_f32() * x);
+                    assert!(is_approx_eps(expected, y, 0.01), "{expected} vs {y}");
+                }
+            }
+        }
+        // the batch not written to is left untouched
+        assert!(output_host[..R * T].iter().all(|&x| x == 0.0));
+
+        assert!(matches!(
+            x_dev.gather(&[B]),
+            Err(TensorError::BatchOutOfRange { batch: B, max: B })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_topk() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+        const K: usize = 40;
+
+        // coarse values, so that there are ties
+        let mut x = [(); C * T * B]
+            .map(|_| (fastrand::f32() * 100.0).round() - 50.0)
+            .to_vec();
+        // the first row is all banned but one
+        x[..C].fill(f32::NEG_INFINITY);
+        x[3] = 1.0;
+
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let values: TensorGpu<f32, _> = context.tensor_init([K, T, B, 1]);
+        let indices: TensorGpu<u32, _> = context.tensor_init([K, T, B, 1]);
+        let op = TensorOp::topk(&x_dev, &values, &indices)?;
+        context.submit(context.encode(&op));
+        let values = values.back_in_place().to_vec();
+        let indices = indices.back_in_place().to_vec();
+
+        for (row, x) in x.chunks(C).enumerate() {
+            let expected = x
+                .iter()
+                .enumerate()
+                .sorted_by(|(i, x), (j, y)| y.total_cmp(x).then(i.cmp(j)))
+                .take(K)
+                .collect_vec();
+            let values = &values[row * K..(row + 1) * K];
+            let indices = &indices[row * K..(row + 1) * K];
+            for ((&(i, &x), &value), &index) in expected.iter().zip(values).zip(indices) {
+                assert_eq!((i as u32, x), (index, value), "row {row}");
+            }
+        }
+
+        let values: TensorGpu<f32, _> = context.tensor_init([C + 4, T, B, 1]);
+        let indices: TensorGpu<u32, _> = context.tensor_init([C + 4, T, B, 1]);
+        assert!(TensorOp::topk(&x_dev, &values, &indices).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipeline_warmup() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        assert!(context.pipelines().is_empty());
+
+        let x: TensorGpu<f32, _> = context.tensor_init([64, 2, 1, 1]);
+        let _ = TensorOp::softmax(&x)?;
+        let _ = TensorOp::softmax(&x)?;
+        let pipelines = context.pipelines();
+        assert_eq!(pipelines.len(), 1);
+        assert_eq!(pipelines[0].name, "softmax");
+
+        // a context warmed up with the recorded pipelines compiles nothing more for the same ops
+        let context = pollster::block_on(async {
+            let instance = Instance::default();
+            let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+            let builder = ContextBuilder::new(adapter).warmup(pipelines.clone());
+            anyhow::Ok(builder.build().await?)
+        })?;
+        assert_eq!(context.pipelines(), pipelines);
+        let x: TensorGpu<f32, _> = context.tensor_init([64, 2, 1, 1]);
+        let _ = TensorOp::softmax(&x)?;
+        assert_eq!(context.pipelines(), pipelines);
+        Ok(())
+    }
+
+    #[test]
+    fn test_softmax() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        let shape = Shape::new(C, T, B, 1);
+
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
+        let softmax = TensorOp::softmax(&x_dev)?;
+
+        context.submit(context.encode(&softmax));
+
+        let x_host = x_dev.back_in_place().to_vec();
+
+        let mut ans = vec![];
+        for x in &x.into_iter().chunks(C) {
+            let x = x.collect_vec().into_iter();
+            let max = x.clone().reduce(f32::max).unwrap_or_default();
+            let x = x.map(|x| (x - max).exp());
+            let sum: f32 = x.clone().sum();
+            let mut x: Vec<_> = x.map(|x| x / sum).collect();
+            ans.append(&mut x);
+        }
+
+        itertools::zip_eq(x_host, ans)
+            .enumerate()
+            .for_each(|(index, (a, b))| {
+                assert!(
+                    is_approx(a, b),
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_argmax() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let mut x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        // a tie in the last row must resolve to the first index
+        x[C * (T * B - 1) + 7] = 100.0;
+        x[C * (T * B - 1) + 900] = 100.0;
+        let shape = Shape::new(C, T, B, 1);
+
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_f16: TensorGpu<f16, _> =
+            context.tensor_from_data(shape, x.iter().map(|&x| f16::from_f32(x)).collect_vec())?;
+        let output: TensorGpu<u32, _> = context.tensor_init([1, T, B, 1]);
+        let output_f16: TensorGpu<u32, _> = context.tensor_init([1, T, B, 1]);
+
+        let ops = TensorOp::List(vec![
+            TensorOp::argmax(&x_dev, &output)?,
+            TensorOp::argmax(&x_f16, &output_f16)?,
+        ]);
+        context.submit(context.encode(&ops));
+
+        let output = output.back_in_place().to_vec();
+        let output_f16 = output_f16.back_in_place().to_vec();
+
+        let argmax = |x: &[f32]| {
+            x.chunks_exact(C)
+                .map(|x| {
+                    let max = x.iter().copied().reduce(f32::max).unwrap_or_default();
+                    x.iter().position(|&x| x == max).unwrap() as u32
+                })
+                .collect_vec()
+        };
+        let ans = argmax(&x);
+        // values close to the maximum may become equal after rounding
+        let ans_f16 = argmax(&x.iter().map(|&x| f16::from_f32(x).to_f32()).collect_vec());
+        assert_eq!(ans[T * B - 1], 7);
+        assert_eq!(output, ans);
+        assert_eq!(output_f16, ans_f16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_logit_bias() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 256;
+        const S: usize = 3;
+
+        // the fourth row has no slot, and is left as is
+        let slots = vec![0, 2, 1, u32::MAX, 0];
+        let num_row = slots.len();
+
+        let x = [(); C * 5].map(|_| fastrand::f32() - 0.5).to_vec();
+        let mut bias = [(); C * S].map(|_| fastrand::f32() - 0.5).to_vec();
+        bias[C + 13] = f32::NEG_INFINITY;
+
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, num_row, 1, 1], x.clone())?;
+        let slots_dev: TensorGpu<u32, _> =
+            context.tensor_from_data([1, num_row, 1, 1], slots.clone())?;
+        let bias_dev: TensorGpu<f32, _> = context.tensor_from_data([C, 1, S, 1], bias.clone())?;
+
+        let op = TensorOp::logit_bias(&x_dev, &slots_dev, &bias_dev)?;
+        context.submit(context.encode(&op));
+        let output = x_dev.back_in_place().to_vec();
+
+        let bias = &bias;
+        let ans = x
+            .chunks_exact(C)
+            .zip_eq(slots.iter())
+            .flat_map(|(x, &slot)| {
+                x.iter().enumerate().map(move |(index, &x)| match slot {
+                    slot if (slot as usize) < S => x + bias[slot as usize * C + index],
+                    _ => x,
+                })
+            })
+            .collect_vec();
+        assert_eq!(output[2 * C + 13], f32::NEG_INFINITY);
+        for (index, (a, b)) in output.into_iter().zip_eq(ans).enumerate() {
+            assert!(
+                a == b || is_approx(a, b),
+                "Failed at index {index}, computed: {a} vs. answer: {b}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        // the same logits in every batch, sampled with evenly spread random numbers
+        const B: usize = 256;
+        const TEMPERATURE: f32 = 0.8;
+        const TOP_P: f32 = 0.6;
+        const ERROR: f32 = 0.02;
+
+        for c in [512, 4096] {
+            let x = (0..c).map(|_| 8.0 * fastrand::f32()).collect_vec();
+            let shape = Shape::new(c, 1, B, 1);
+            let params = (0..B)
+                .flat_map(|batch| {
+                    let temperature = if batch == 0 { 0.0 } else { TEMPERATURE };
+                    [temperature, TOP_P, (batch as f32 + 0.5) / B as f32, 0.0]
+                })
+                .collect_vec();
+
+            let input: TensorGpu<f32, _> = context
+                .tensor_from_data(shape, x.iter().cycle().take(c * B).copied().collect_vec())?;
+            let params: TensorGpu<f32, _> = context.tensor_from_data([4, 1, B, 1], params)?;
+            let output: TensorGpu<u32, _> = context.tensor_init([1, 1, B, 1]);
+
+            let op = TensorOp::sample(&input, &params, &output, ERROR)?;
+            context.submit(context.encode(&op));
+            let output = output.back_in_place().to_vec();
+
+            let max = x.iter().copied().reduce(f32::max).unwrap();
+            let argmax = x.iter().position(|&x| x == max).unwrap() as u32;
+            assert_eq!(output[0], argmax);
+
+            // probability mass of the tokens more probable than each token
+            let probs = x
+                .iter()
+                .map(|&x| ((x - max) / TEMPERATURE).exp())
+                .collect_vec();
+            let total: f32 = probs.iter().sum();
+            let probs = probs.into_iter().map(|p| p / total).collect_vec();
+            let mut before = vec![0.0; c];
+            let mut cum = 0.0;
+            for (token, p) in probs
+                .iter()
+                .enumerate()
+                .sorted_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+            {
+                before[token] = cum;
+                cum += p;
+            }
+
+            let bound = match c <= TensorOp::SAMPLE_EXACT_SIZE as usize {
+                true => TOP_P,
+                false => TOP_P + ERROR,
+            };
+            for &token in &output[1..] {
+                assert!(before[token as usize] < bound + 1.0e-4);
+            }
+            assert!(output.iter().unique().count() > 4);
+
+            if c <= TensorOp::SAMPLE_EXACT_SIZE as usize {
+                // each token is picked in proportion to its probability within the nucleus
+                let kept = (0..c).filter(|&token| before[token] < TOP_P).collect_vec();
+                let mass: f32 = kept.iter().map(|&token| probs[token]).sum();
+                let counts = output.iter().skip(1).counts();
+                for token in kept {
+                    let count = counts.get(&(token as u32)).copied().unwrap_or_default();
+                    let expected = probs[token] / mass * B as f32;
+                    assert!(
+                        (count as f32 - expected).abs() <= 2.0,
+                        "{count} vs. {expected}"
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_noise() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const C: usize = 4096;
+        const T: usize = 4;
+        let shape = Shape::new(C, T, 1, 1);
+        let len = C * T;
+
+        let run = |noise: Noise, seed: u64| -> Result<(Vec<f32>, Vec<f32>)> {
+            let seed = vec![seed as u32, (seed >> 32) as u32, 0, 0];
+            let seed: TensorGpu<u32, _> = context.tensor_from_data([4, 1, 1, 1], seed)?;
+            let x: TensorGpu<f32, _> = context.tensor_from_data(shape, vec![1.0; len])?;
+            let x_f16: TensorGpu<f16, _> = context.tensor_from_data(shape, vec![f16::ONE; len])?;
+            let ops = TensorOp::List(vec![
+                TensorOp::noise(&x, noise, &seed)?,
+                TensorOp::noise(&x_f16, noise, &seed)?,
+            ]);
+            context.submit(context.encode(&ops));
+            let x = x.back_in_place().to_vec();
+            let x_f16 = x_f16.back_in_place().map(|x| x.to_f32()).to_vec();
+            Ok((x, x_f16))
+        };
+
+        // dropout zeros a fraction of elements and scales up the rest
+        let (x, x_f16) = run(Noise::Dropout(0.25), 42)?;
+        for (x, y) in x.iter().zip(x_f16.iter()) {
+            assert_eq!(*x == 0.0, *y == 0.0);
+        }
+        let zeros = x.iter().filter(|&&x| x == 0.0).count() as f32 / len as f32;
+        assert!((zeros - 0.25).abs() < 0.02, "{zeros}");
+        assert!(x
+            .iter()
+            .all(|&x| x == 0.0 || (x - 1.0 / 0.75).abs() < 1.0e-3));
+
+        // the same seed reproduces the same mask while another one does not
+        assert_eq!(run(Noise::Dropout(0.25), 42)?.0, x);
+        assert_ne!(run(Noise::Dropout(0.25), 43)?.0, x);
+
+        let (x, x_f16) = run(Noise::Gaussian(0.5), 7)?;
+        let mean = x.iter().map(|x| x - 1.0).sum::<f32>() / len as f32;
+        let var = x.iter().map(|x| (x - 1.0 - mean).powi(2)).sum::<f32>() / len as f32;
+        assert!(mean.abs() < 0.02, "{mean}");
+        assert!((var.sqrt() - 0.5).abs() < 0.02, "{}", var.sqrt());
+        for (x, y) in x.into_iter().zip(x_f16) {
+            assert!((x - y).abs() < 2.0e-3, "{x} vs {y}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_heat_map() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const H: usize = 2;
+        const S: usize = 64;
+        const C: usize = H * S;
+        const R: usize = S + 2;
+        const L: usize = 3;
+        const W: usize = 16;
+
+        let x = [(); C * R * L]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        let shape = Shape::new(C, R, L, 1);
+
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
+        let output: TensorGpu<f32, _> = context.tensor_init([W, W, H, L]);
+        let op = TensorOp::heat_map(x_dev.view(.., 1..=S, .., ..)?, &output)?;
+        context.submit(context.encode(&op));
+
+        let output_host = output.back_in_place().to_vec();
+
+        let factor = S / W;
+        let mut ans = vec![];
+        for layer in 0..L {
+            for head in 0..H {
+                let mut map = vec![];
+                for y in 0..W {
+                    for x0 in 0..W {
+                        let mut sum = 0.0;
+                        for j in 0..factor {
+                            for k in 0..factor {
+                                let row = 1 + y * factor + j;
+                                let col = head * S + x0 * factor + k;
+                                sum += x[(layer * R + row) * C + col];
+                            }
+                        }
+                        map.push(sum / (factor * factor) as f32);
+                    }
+                }
+                let max = map.iter().fold(0.0f32, |acc, x| acc.max(x.abs()));
+                ans.extend(map.into_iter().map(|x| x / max));
+            }
+        }
+
+        itertools::zip_eq(output_host, ans)
+            .enumerate()
+            .for_each(|(index, (a, b))| {
+                assert!(
+                    is_approx(a, b),
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_norm() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+        const EPS: f32 = 1.0e-5;
+
+        let x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        let w = [(); C]
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .repeat(T * B)
+            .to_vec();
+        let b = [(); C]
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .repeat(T * B)
+            .to_vec();
+
+        let shape = Shape::new(C, T, B, 1);
+        let x_dev = context.tensor_from_data(shape, x.clone())?;
+
+        let shape = Shape::new(C, 1, 1, 1);
+        let w_dev = context.tensor_from_data(shape, &w[..1000])?;
+        let b_dev = context.tensor_from_data(shape, &b[..1000])?;
+
+        // let shape = Shape::new(4, T, B, 1);
+        // let s_dev = context.tensor_init(shape);
+
+        let layer_norm = TensorOp::layer_norm(&w_dev, &b_dev, &x_dev, EPS)?;
+        context.submit(context.encode(&layer_norm));
+
+        let x_host = x_dev.back_in_place().to_vec();
+        // let s_host = s_dev.back_in_place().to_vec();
+
+        // test recenter and rms norm
+        let shape = Shape::new(C, T, B, 1);
+        let x_dev = context.tensor_from_data(shape, x.clone())?;
+        let ops = TensorOp::List(vec![
+            TensorOp::recenter(&x_dev)?,
+            TensorOp::rms_norm(&w_dev, &b_dev, &x_dev, EPS)?,
+        ]);
+        context.submit(context.encode(&ops));
+
+        let x_rms_host = x_dev.back_in_place().to_vec();
+
+        let mut ans = vec![];
+        // let mut ans_stats = vec![];
+        for chunk in &x
+            .into_iter()
+            .zip(w.into_iter())
+            .zip(b.into_iter())
+            .chunks(C)
+        {
+            let chunk = chunk.collect_vec();
+            let x = chunk.iter().map(|((x, _), _)| x).copied();
+            // let sum: f32 = x.clone().sum();
+            // let squared_sum: f32 = x.clone().map(|x| x.powi(2)).sum();
+
+            // let mean = sum / C as f32;
+            // let deviation = ((squared_sum / C as f32) - mean.powi(2)).sqrt();
+            let (mean, m2, count) = x.fold((0.0f32, 0.0f32, 0u32), |(mean, m2, count), x| {
+                let count = count + 1;
+                let delta = x - mean;
+                let mean = mean + delta / count as f32;
+                let m2 = m2 + delta * (x - mean);
+                (mean, m2, count)
+            });
+            let variance = m2 / count as f32 + EPS;
+            let deviation = 1.0 / variance.sqrt();
+            // ans_stats.append(&mut vec![mean, deviation, variance, 0.0]);
+
+            let mut x: Vec<_> = chunk
+                .into_iter()
+                .map(|((x, w), b)| (x - mean) * deviation * w.to_f32() + b.to_f32())
+                .collect();
+            ans.append(&mut x);
+        }
+
+        // itertools::zip_eq(s_host.into_iter(), ans_stats.into_iter())
+        //     .enumerate()
+        //     .for_each(|(index, (a, b))| {
+        //         assert!(
+        //             is_approx_eps(a, b, 1.0e-3),
+        //             "Failed at index {index}, computed: {a} vs. answer: {b}"
+        //         );
+        //     });
+
+        itertools::zip_eq(x_host, ans.iter())
+            .enumerate()
+            .for_each(|(index, (a, &b))| {
+                assert!(
+                    is_approx_eps(a, b, 1.0e-3),
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        itertools::zip_eq(x_rms_host, ans.iter())
+            .enumerate()
+            .for_each(|(index, (a, &b))| {
+                assert!(
+                    is_approx_eps(a, b, 1.0e-3),
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+        // let mut profiler = GpuProfiler::new(&context.adapter, &context.device, &context.queue, 1);
+
+        const C: usize = 2560;
+        const R: usize = 2048;
+        const T: usize = 32;
+        const B: usize = 2;
+
+        let matrix = vec![(); C * R * B]
+            .into_iter()
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .map(f16::from_f32)
+            .collect_vec();
+        let input_f32 = vec![(); C * T * B]
+            .into_iter()
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .collect_vec();
+        let input_f16 = input_f32.iter().copied().map(f16::from_f32).collect_vec();
+
+        let matrix_shape = Shape::new(C, R, B, 1);
+        let input_shape = Shape::new(C, T, B, 1);
+        let output_shape = Shape::new(R, T, 2 * B, 1);
+
+        let matrix_dev = context.tensor_from_data(matrix_shape, matrix.clone())?;
+        let input_f32_dev: TensorGpu<_, _> =
+            context.tensor_from_data(input_shape, input_f32.clone())?;
+        let input_f16_dev: TensorGpu<f16, _> = context.tensor_init(input_shape);
+        let output_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
 
-    fn is_approx(a: f32, b: f32) -> bool {
-        (a - b).abs() <= f32::max(f32::EPSILON, f32::max(a.abs(), b.abs()) * f32::EPSILON)
+        let ops = TensorOp::List(vec![
+            TensorOp::blit(
+                input_f32_dev.view(.., .., .., ..)?,
+                input_f16_dev.view(.., .., .., ..)?,
+            )?,
+            TensorOp::matmul_vec_fp16(
+                &matrix_dev,
+                input_f32_dev.view(.., .., .., ..)?,
+                output_dev.view(.., .., 0..B, ..)?,
+                Activation::None,
+            )?,
+            TensorOp::matmul_mat_fp16(
+                matrix_dev.view(.., .., .., ..)?,
+                input_f16_dev.view(.., .., .., ..)?,
+                output_dev.view(.., .., B.., ..)?,
+                Activation::None,
+            )?,
+        ]);
+
+        // profiler.resolve_queries(&mut encoder);
+        context.submit(context.encode(&ops));
+
+        let output_host = output_dev.back_in_place();
+        let output_host = Vec::from(output_host);
+
+        // profiler.end_frame().unwrap();
+        context.device.poll(wgpu::MaintainBase::Wait);
+
+        // if let Some(results) = profiler.process_finished_frame() {
+        //     wgpu_profiler::chrometrace::write_chrometrace(
+        //         std::path::Path::new(&format!("./trace/matmul_{T}.json")),
+        //         &results,
+        //     )
+        //     .expect("failed to write trace");
+        // }
+
+        let mut ans = vec![0.0; output_host.len()];
+        for batch in 0..B {
+            for token in 0..T {
+                for line in 0..R {
+                    let matrix = &matrix[((batch * R + line) * C)..((batch * R + line) + 1) * C];
+                    let input = &input_f32[(batch * T + token) * C..((batch * T + token) + 1) * C];
+                    let product = matrix
+                        .iter()
+                        .zip(input.iter())
+                        .fold(0.0f32, |acc, x| acc + x.0.to_f32() * *x.1);
+                    ans[(batch * T + token) * R + line] = product;
+
+                    let input = &input_f16[(batch * T + token) * C..((batch * T + token) + 1) * C];
+                    let product = matrix
+                        .iter()
+                        .zip(input.iter())
+                        .fold(0.0f32, |acc, x| acc + x.0.to_f32() * x.1.to_f32());
+                    ans[((B + batch) * T + token) * R + line] = product;
+                }
+            }
+        }
+
+        itertools::zip_eq(output_host, ans)
+            .enumerate()
+            .for_each(|(index, (a, b))| {
+                assert!(
+                    is_approx_eps(a, b, 0.01),
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        Ok(())
     }
 
-    fn is_approx_eps(a: f32, b: f32, eps: f32) -> bool {
-        (a - b).abs() <= f32::max(eps, f32::max(a.abs(), b.abs()) * eps)
+    #[test]
+    fn test_matmul_epilogue() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 256;
+        const R: usize = 64;
+        const T: usize = 8;
+
+        let matrix = (0..C * R)
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .collect_vec();
+        let input = (0..C * T).map(|_| fastrand::f32() - 0.5).collect_vec();
+        let bias = (0..R)
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .collect_vec();
+        let init = (0..R * T).map(|_| fastrand::f32() - 0.5).collect_vec();
+
+        let matrix_dev: TensorGpu<f16, _> = context.tensor_from_data([C, R, 1, 1], matrix)?;
+        let input_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, 1, 1], input)?;
+        let bias_dev: TensorGpu<f16, _> = context.tensor_from_data([R, 1, 1, 1], bias.clone())?;
+
+        let matrices = [
+            Matrix::quant_u8(&matrix_dev)?,
+            Matrix::quant_nf4(&matrix_dev)?,
+            Matrix::Fp16(matrix_dev),
+        ];
+        let activations = [
+            Activation::None,
+            Activation::SquaredRelu,
+            Activation::Tanh,
+            Activation::Relu,
+            Activation::Sigmoid,
+        ];
+        for (matrix, turbo, activation) in
+            itertools::iproduct!(&matrices, [false, true], activations)
+        {
+            let plain: TensorGpu<f32, _> = context.tensor_init([R, T, 1, 1]);
+            let fused: TensorGpu<f32, _> = context.tensor_from_data([R, T, 1, 1], init.clone())?;
+            let ops = TensorOp::List(vec![
+                matrix.matmul_op(
+                    input_dev.view(.., .., .., ..)?,
+                    plain.view(.., .., .., ..)?,
+                    Activation::None,
+                    turbo,
+                )?,
+                matrix.matmul_op(
+                    input_dev.view(.., .., .., ..)?,
+                    fused.view(.., .., .., ..)?,
+                    Epilogue::new(activation).bias(&bias_dev).accumulate(true),
+                    turbo,
+                )?,
+            ]);
+            context.submit(context.encode(&ops));
+
+            let plain = plain.back_in_place().to_vec();
+            let fused = fused.back_in_place().to_vec();
+            for (index, (&x, &y)) in plain.iter().zip_eq(&fused).enumerate() {
+                let x = x + bias[index % R].to_f32() + init[index];
+                let x = match activation {
+                    Activation::None => x,
+                    Activation::SquaredRelu => x.max(0.0).powi(2),
+                    Activation::Tanh => x.tanh(),
+                    Activation::Relu => x.max(0.0),
+                    Activation::Sigmoid => 1.0 / (1.0 + (-x).exp()),
+                };
+                assert!(
+                    is_approx_eps(x, y, 1.0e-4),
+                    "{activation:?} (turbo: {turbo}) failed at index {index}, computed: {y} vs. answer: {x}"
+                );
+            }
+        }
+
+        Ok(())
     }
 
-    async fn create_context() -> Result<Context> {
-        let instance = Instance::default();
-        let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
-        let context = ContextBuilder::new(adapter)
-            // .features(Features::TIMESTAMP_QUERY | Features::TIMESTAMP_QUERY_INSIDE_PASSES)
-            .build()
-            .await?;
-        Ok(context)
+    #[test]
+    fn test_matrix_layout() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 512;
+        const R: usize = 8;
+
+        let matrix = (0..C * R)
+            .map(|_| f16::from_f32(10.0 * (fastrand::f32() - 0.5)))
+            .collect_vec();
+        let matrix_dev: TensorGpu<f16, _> =
+            context.tensor_from_data([C, R, 1, 1], matrix.clone())?;
+
+        // dequantize from the exposed tensors following their documented layouts
+        let int8 = Matrix::quant_u8(&matrix_dev)?;
+        assert_eq!(int8.shape(), Shape::new(C, R, 1, 1));
+        let block = int8.block_size().unwrap();
+        let w = int8.codes().unwrap().back_in_place().to_vec();
+        let m = int8.scales().unwrap().back_in_place().to_vec();
+        for (index, (&x, &w)) in matrix.iter().zip_eq(&w).enumerate() {
+            let (min, max) = (m[2 * (index / block)], m[2 * (index / block) + 1]);
+            let (min, max) = (min.to_f32(), max.to_f32());
+            let y = min + (max - min) * w as f32 / 255.0;
+            assert!((x.to_f32() - y).abs() <= (max - min) / 255.0, "{x} vs {y}");
+        }
+
+        let nf4 = Matrix::quant_nf4(&matrix_dev)?;
+        assert_eq!(nf4.shape(), Shape::new(C, R, 1, 1));
+        let block = nf4.block_size().unwrap();
+        let q = Nf4Quant::default().0.to_vec();
+        let w = nf4.codes().unwrap().back_in_place().to_vec();
+        let m = nf4.scales().unwrap().back_in_place().to_vec();
+        for (index, &x) in matrix.iter().enumerate() {
+            let code = (w[index / 2] >> (4 * (index % 2))) & 0xf;
+            let absmax = m[index / block].to_f32();
+            let y = q[code as usize] * absmax;
+            // half of the widest gap between the levels
+            assert!((x.to_f32() - y).abs() <= 0.16 * absmax, "{x} vs {y}");
+        }
+
+        let fp16 = Matrix::Fp16(matrix_dev);
+        assert_eq!(fp16.block_size(), None);
+        assert!(fp16.codes().is_none() && fp16.scales().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_int8() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 2560;
+        const R: usize = 2048;
+        const T: usize = 64;
+        const INT8_BLOCK_SIZE: usize = TensorOp::INT8_BLOCK_SIZE as usize;
+
+        let matrix = vec![(); C * R]
+            .into_iter()
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .map(f16::from_f32)
+            .collect_vec();
+        let input_f32 = vec![(); C * T]
+            .into_iter()
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .collect_vec();
+        let input_f16 = input_f32.iter().copied().map(f16::from_f32).collect_vec();
+
+        let (matrix_u8, min, max) = {
+            let mut matrix_u8: Vec<u8> = vec![0; matrix.len()];
+            let mut min = vec![f16::MAX; matrix.len() / INT8_BLOCK_SIZE];
+            let mut max = vec![f16::MIN; matrix.len() / INT8_BLOCK_SIZE];
+
+            for (i, (min, max)) in min.iter_mut().zip_eq(max.iter_mut()).enumerate() {
+                let start = i * INT8_BLOCK_SIZE;
+                let end = start + INT8_BLOCK_SIZE;
+                let chunk = &matrix[start..end];
+                for value in chunk.iter() {
+                    *min = min.min(*value);
+                    *max = max.max(*value);
+                }
+                for (j, value) in chunk.iter().enumerate() {
+                    let value = value.to_f32();
+                    let min = min.to_f32();
+                    let max = max.to_f32();
+                    let value = (value - min) / (max - min);
+                    matrix_u8[start + j] = f32::round(value * 255.0) as u8;
+                }
+            }
+
+            (matrix_u8, min, max)
+        };
+
+        let minmax_shape = Shape::new(C / INT8_BLOCK_SIZE * 2, R, 1, 1);
+        let matrix_shape = Shape::new(C, R, 1, 1);
+        let input_shape = Shape::new(C, T, 1, 1);
+        let output_shape = Shape::new(R, T, 1, 1);
+
+        let minmax_dev = context.tensor_init(minmax_shape);
+        let matrix_f16_dev = context.tensor_from_data(matrix_shape, matrix.clone())?;
+
+        let matrix_u8_dev = context.tensor_init(matrix_shape);
+        let input_dev: TensorGpu<_, _> =
+            context.tensor_from_data(input_shape, input_f16.clone())?;
+        let output_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
+
+        let ops = TensorOp::List(vec![
+            TensorOp::quantize_mat_int8(&matrix_f16_dev, &minmax_dev, &matrix_u8_dev)?,
+            TensorOp::matmul_mat_int8(
+                matrix_u8_dev.view(.., .., .., ..)?,
+                &minmax_dev,
+                input_dev.view(.., .., .., ..)?,
+                output_dev.view(.., .., .., ..)?,
+                Activation::None,
+            )?,
+        ]);
+        context.submit(context.encode(&ops));
+
+        let matrix_u8_host = matrix_u8_dev.back_in_place().to_vec();
+        let output_host = output_dev.back_in_place().to_vec();
+
+        // let mut truth = vec![0.0; output_host.len()];
+        // for token in 0..T {
+        //     for line in 0..R {
+        //         let matrix = &matrix[line * C..(line + 1) * C];
+        //         let input = &input_f16[token * C..(token + 1) * C];
+        //         let product = matrix
+        //             .iter()
+        //             .zip(input.iter())
+        //             .fold(0.0f32, |acc, x| acc + x.0.to_f32() * x.1.to_f32());
+        //         truth[token * R + line] = product;
+        //     }
+        // }
+
+        let mut ans = vec![0.0; output_host.len()];
+        for token in 0..T {
+            for line in 0..R {
+                let matrix = &matrix_u8_host[line * C..(line + 1) * C];
+                let input = &input_f16[token * C..(token + 1) * C];
+                let product =
+                    matrix
+                        .iter()
+                        .zip_eq(input.iter())
+                        .enumerate()
+                        .fold(0.0f32, |acc, (i, x)| {
+                            let min = min[(line * C + i) / INT8_BLOCK_SIZE].to_f32();
+                            let max = max[(line * C + i) / INT8_BLOCK_SIZE].to_f32();
+                            let value = (*x.0 as f32) / 255.0;
+                            acc + (value * (max - min) + min) * x.1.to_f32()
+                        });
+                ans[token * R + line] = product;
+            }
+        }
+
+        itertools::zip_eq(matrix_u8_host, matrix_u8)
+            .enumerate()
+            .for_each(|(index, (a, b))| {
+                assert!(
+                    a.abs_diff(b) < 2,
+                    // a == b,
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        itertools::zip_eq(output_host, ans)
+            .enumerate()
+            .for_each(|(index, (a, b))| {
+                assert!(
+                    is_approx_eps(a, b, 0.01),
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        Ok(())
     }
 
     #[test]
-    fn test_softmax() -> Result<()> {
+    fn test_matmul_vec_lora() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
             Ok(context) => context,
             Err(_) => return Ok(()),
         };
         fastrand::seed(42);
 
-        const C: usize = 1000;
+        const C: usize = 1024;
+        const R: usize = 768;
         const T: usize = 3;
-        const B: usize = 2;
-
-        let x = [(); C * T * B]
-            .map(|_| 10.0 * (fastrand::f32() - 0.5))
-            .to_vec();
-        let shape = Shape::new(C, T, B, 1);
+        const RANK: usize = 16;
 
-        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
-        let softmax = TensorOp::softmax(&x_dev)?;
-
-        context.queue.submit(context.encode(&softmax));
-
-        let x_host = x_dev.back_in_place().to_vec();
+        let random = |len: usize| {
+            vec![(); len]
+                .into_iter()
+                .map(|_| fastrand::f32() - 0.5)
+                .collect_vec()
+        };
+        let matrix = random(C * R).into_iter().map(f16::from_f32).collect_vec();
+        let input = random(C * T).into_iter().map(f16::from_f32).collect_vec();
+        let factor = random(RANK * R)
+            .into_iter()
+            .map(f16::from_f32)
+            .collect_vec();
+        let hidden = random(RANK * T);
+
+        let matrix_dev: TensorGpu<f16, ReadWrite> =
+            context.tensor_from_data([C, R, 1, 1], matrix)?;
+        let input_dev: TensorGpu<f16, ReadWrite> = context.tensor_from_data([C, T, 1, 1], input)?;
+        let factor_dev = context.tensor_from_data([RANK, R, 1, 1], factor.clone())?;
+        let hidden_dev = context.tensor_from_data([RANK, T, 1, 1], hidden.clone())?;
+        let delta = LoraDelta {
+            factor: &factor_dev,
+            hidden: &hidden_dev,
+        };
 
-        let mut ans = vec![];
-        for x in &x.into_iter().chunks(C) {
-            let x = x.collect_vec().into_iter();
-            let max = x.clone().reduce(f32::max).unwrap_or_default();
-            let x = x.map(|x| (x - max).exp());
-            let sum: f32 = x.clone().sum();
-            let mut x: Vec<_> = x.map(|x| x / sum).collect();
-            ans.append(&mut x);
+        let mut ans = vec![0.0; R * T];
+        for token in 0..T {
+            for line in 0..R {
+                let factor = &factor[line * RANK..(line + 1) * RANK];
+                let hidden = &hidden[token * RANK..(token + 1) * RANK];
+                ans[token * R + line] = factor
+                    .iter()
+                    .zip_eq(hidden.iter())
+                    .fold(0.0f32, |acc, (x, y)| acc + x.to_f32() * y);
+            }
         }
 
-        itertools::zip_eq(x_host, ans)
-            .enumerate()
-            .for_each(|(index, (a, b))| {
-                assert!(
-                    is_approx(a, b),
-                    "Failed at index {index}, computed: {a} vs. answer: {b}"
-                );
-            });
+        for matrix in [
+            Matrix::quant_u8(&matrix_dev)?,
+            Matrix::quant_nf4(&matrix_dev)?,
+            Matrix::Fp16(matrix_dev.clone()),
+        ] {
+            let base_dev: TensorGpu<f32, ReadWrite> = context.tensor_init([R, T, 1, 1]);
+            let output_dev: TensorGpu<f32, ReadWrite> = context.tensor_init([R, T, 1, 1]);
+            let ops = TensorOp::List(vec![
+                matrix.matmul_vec_op(
+                    input_dev.view(.., .., .., ..)?,
+                    base_dev.view(.., .., .., ..)?,
+                    Activation::None,
+                )?,
+                matrix.matmul_vec_lora_op(
+                    input_dev.view(.., .., .., ..)?,
+                    output_dev.view(.., .., .., ..)?,
+                    Activation::None,
+                    delta,
+                )?,
+            ]);
+            context.submit(context.encode(&ops));
+
+            let base_host = base_dev.back_in_place().to_vec();
+            let output_host = output_dev.back_in_place().to_vec();
+            itertools::multizip((output_host, base_host, ans.iter()))
+                .enumerate()
+                .for_each(|(index, (a, base, delta))| {
+                    let b = base + delta;
+                    assert!(
+                        is_approx_eps(a, b, 0.01),
+                        "Failed at index {index}, computed: {a} vs. answer: {b}"
+                    );
+                });
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_layer_norm() -> Result<()> {
+    fn test_matmul_int8_row() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
             Ok(context) => context,
             Err(_) => return Ok(()),
         };
         fastrand::seed(42);
 
-        const C: usize = 1000;
-        const T: usize = 3;
-        const B: usize = 2;
-        const EPS: f32 = 1.0e-5;
+        const C: usize = 512;
+        const R: usize = 1024;
+        const T: usize = 6;
+        const S: usize = 2;
 
-        let x = [(); C * T * B]
+        let matrix = vec![(); C * R]
+            .into_iter()
             .map(|_| 10.0 * (fastrand::f32() - 0.5))
-            .to_vec();
-        let w = [(); C]
-            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
-            .repeat(T * B)
-            .to_vec();
-        let b = [(); C]
+            .map(f16::from_f32)
+            .collect_vec();
+        let input = vec![(); C * T]
+            .into_iter()
             .map(|_| f16::from_f32(fastrand::f32() - 0.5))
-            .repeat(T * B)
-            .to_vec();
-
-        let shape = Shape::new(C, T, B, 1);
-        let x_dev = context.tensor_from_data(shape, x.clone())?;
-
-        let shape = Shape::new(C, 1, 1, 1);
-        let w_dev = context.tensor_from_data(shape, &w[..1000])?;
-        let b_dev = context.tensor_from_data(shape, &b[..1000])?;
-
-        // let shape = Shape::new(4, T, B, 1);
-        // let s_dev = context.tensor_init(shape);
-
-        let layer_norm = TensorOp::layer_norm(&w_dev, &b_dev, &x_dev, EPS)?;
-        context.queue.submit(context.encode(&layer_norm));
+            .collect_vec();
 
-        let x_host = x_dev.back_in_place().to_vec();
-        // let s_host = s_dev.back_in_place().to_vec();
+        // the last row has no slot; lengths adjust a token of some rows, penalties apply to others
+        let slots = vec![0, 1, 1, 0, 1, u32::MAX];
+        let bias = (0..R * S).map(|_| fastrand::f32() - 0.5).collect_vec();
+        let counts = (0..R * S).map(|_| fastrand::u8(0..3) as f32).collect_vec();
+        let lengths = (0..T)
+            .flat_map(|row| match row % 2 {
+                0 => [fastrand::usize(0..R) as f32, 2.0, 0.5, 0.0],
+                _ => [-1.0, 0.0, 0.0, 0.0],
+            })
+            .collect_vec();
+        let penalties = (0..T)
+            .flat_map(|row| match row % 3 {
+                0 => [0.0, 0.0, 0.0, 0.0],
+                _ => [0.5, 0.25, (row % S) as f32, 1.0],
+            })
+            .collect_vec();
 
-        // test recenter and rms norm
-        let shape = Shape::new(C, T, B, 1);
-        let x_dev = context.tensor_from_data(shape, x.clone())?;
+        let matrix_dev: TensorGpu<f16, _> = context.tensor_from_data([C, R, 1, 1], matrix)?;
+        let input_dev: TensorGpu<f16, _> = context.tensor_from_data([C, T, 1, 1], input.clone())?;
+        let codes_dev: TensorGpu<u8, _> = context.tensor_init([C, R, 1, 1]);
+        let scale_dev: TensorGpu<f32, _> = context.tensor_init([R, 1, 1, 1]);
+        let slots_dev = context.tensor_from_data([1, T, 1, 1], slots)?;
+        let bias_dev = context.tensor_from_data([R, 1, S, 1], bias)?;
+        let counts_dev = context.tensor_from_data([R, 1, S, 1], counts)?;
+        let lengths_dev = context.tensor_from_data([4, T, 1, 1], lengths)?;
+        let penalties_dev = context.tensor_from_data([4, T, 1, 1], penalties)?;
+        let fused_dev: TensorGpu<f32, _> = context.tensor_init([R, T, 1, 1]);
+        let output_dev: TensorGpu<f32, _> = context.tensor_init([R, T, 1, 1]);
+
+        // the fused kernel runs on a view, as the transform is indexed by rows of the whole output
+        let transform = LogitTransform::default()
+            .bias(&slots_dev, &bias_dev)
+            .length(&lengths_dev)
+            .penalty(&penalties_dev, &counts_dev);
         let ops = TensorOp::List(vec![
-            TensorOp::recenter(&x_dev)?,
-            TensorOp::rms_norm(&w_dev, &b_dev, &x_dev, EPS)?,
+            TensorOp::quantize_mat_int8_row(&matrix_dev, &scale_dev, &codes_dev)?,
+            TensorOp::matmul_vec_int8_row(
+                &codes_dev,
+                &scale_dev,
+                input_dev.view(.., 2.., .., ..)?,
+                fused_dev.view(.., 2.., .., ..)?,
+                Activation::None,
+                transform,
+            )?,
+            TensorOp::matmul_vec_int8_row(
+                &codes_dev,
+                &scale_dev,
+                input_dev.view(.., .., .., ..)?,
+                output_dev.view(.., .., .., ..)?,
+                Activation::None,
+                Default::default(),
+            )?,
         ]);
-        context.queue.submit(context.encode(&ops));
-
-        let x_rms_host = x_dev.back_in_place().to_vec();
-
-        let mut ans = vec![];
-        // let mut ans_stats = vec![];
-        for chunk in &x
-            .into_iter()
-            .zip(w.into_iter())
-            .zip(b.into_iter())
-            .chunks(C)
-        {
-            let chunk = chunk.collect_vec();
-            let x = chunk.iter().map(|((x, _), _)| x).copied();
-            // let sum: f32 = x.clone().sum();
-            // let squared_sum: f32 = x.clone().map(|x| x.powi(2)).sum();
+        context.submit(context.encode(&ops));
 
-            // let mean = sum / C as f32;
-            // let deviation = ((squared_sum / C as f32) - mean.powi(2)).sqrt();
-            let (mean, m2, count) = x.fold((0.0f32, 0.0f32, 0u32), |(mean, m2, count), x| {
-                let count = count + 1;
-                let delta = x - mean;
-                let mean = mean + delta / count as f32;
-                let m2 = m2 + delta * (x - mean);
-                (mean, m2, count)
-            });
-            let variance = m2 / count as f32 + EPS;
-            let deviation = 1.0 / variance.sqrt();
-            // ans_stats.append(&mut vec![mean, deviation, variance, 0.0]);
+        let codes = codes_dev.back_in_place().to_vec();
+        let scale = scale_dev.back_in_place().to_vec();
+        let output = output_dev.back_in_place().to_vec();
 
-            let mut x: Vec<_> = chunk
-                .into_iter()
-                .map(|((x, w), b)| (x - mean) * deviation * w.to_f32() + b.to_f32())
-                .collect();
-            ans.append(&mut x);
+        let mut ans = vec![0.0; R * T];
+        for token in 0..T {
+            for line in 0..R {
+                let codes = &codes[line * C..(line + 1) * C];
+                let input = &input[token * C..(token + 1) * C];
+                ans[token * R + line] =
+                    codes
+                        .iter()
+                        .zip_eq(input.iter())
+                        .fold(0.0f32, |acc, (&w, x)| {
+                            let w = (w as i8 as f32 / 127.0).max(-1.0) * scale[line];
+                            acc + w * x.to_f32()
+                        });
+            }
+        }
+        for (index, (a, b)) in output.into_iter().zip_eq(ans).enumerate() {
+            assert!(
+                is_approx_eps(a, b, 0.001),
+                "Failed at index {index}, computed: {a} vs. answer: {b}"
+            );
         }
 
-        // itertools::zip_eq(s_host.into_iter(), ans_stats.into_iter())
-        //     .enumerate()
-        //     .for_each(|(index, (a, b))| {
-        //         assert!(
-        //             is_approx_eps(a, b, 1.0e-3),
-        //             "Failed at index {index}, computed: {a} vs. answer: {b}"
-        //         );
-        //     });
-
-        itertools::zip_eq(x_host, ans.iter())
-            .enumerate()
-            .for_each(|(index, (a, &b))| {
-                assert!(
-                    is_approx_eps(a, b, 1.0e-3),
-                    "Failed at index {index}, computed: {a} vs. answer: {b}"
-                );
-            });
-
-        itertools::zip_eq(x_rms_host, ans.iter())
-            .enumerate()
-            .for_each(|(index, (a, &b))| {
-                assert!(
-                    is_approx_eps(a, b, 1.0e-3),
-                    "Failed at index {index}, computed: {a} vs. answer: {b}"
-                );
-            });
+        // the transform applied apart gives the same logits
+        let ops = TensorOp::List(vec![
+            TensorOp::logit_bias(&output_dev, &slots_dev, &bias_dev)?,
+            TensorOp::length_penalty(&output_dev, &lengths_dev)?,
+            TensorOp::penalty(&output_dev, &penalties_dev, &counts_dev)?,
+        ]);
+        context.submit(context.encode(&ops));
+
+        let fused = fused_dev.back_in_place().to_vec();
+        let output = output_dev.back_in_place().to_vec();
+        assert!(fused[..2 * R].iter().all(|&x| x == 0.0));
+        for (index, (a, b)) in fused.into_iter().zip_eq(output).enumerate().skip(2 * R) {
+            assert!(
+                is_approx_eps(a, b, 0.0001),
+                "Failed at index {index}, computed: {a} vs. answer: {b}"
+            );
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_matmul() -> Result<()> {
+    fn test_matmul_nf4() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
             Ok(context) => context,
             Err(_) => return Ok(()),
         };
         fastrand::seed(42);
-        // let mut profiler = GpuProfiler::new(&context.adapter, &context.device, &context.queue, 1);
 
         const C: usize = 2560;
         const R: usize = 2048;
-        const T: usize = 32;
-        const B: usize = 2;
+        const T: usize = 64;
+        const NF4_BLOCK_SIZE: usize = TensorOp::NF4_BLOCK_SIZE as usize;
 
-        let matrix = vec![(); C * R * B]
+        fn normal() -> f32 {
+            let u = fastrand::f32();
+            let v = fastrand::f32();
+            (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+        }
+
+        let matrix = vec![(); C * R]
             .into_iter()
-            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .map(|_| normal())
             .map(f16::from_f32)
             .collect_vec();
-        let input_f32 = vec![(); C * T * B]
+        let input_f32 = vec![(); C * T]
             .into_iter()
-            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .map(|_| 2.0 * fastrand::f32() - 1.0)
             .collect_vec();
         let input_f16 = input_f32.iter().copied().map(f16::from_f32).collect_vec();
 
-        let matrix_shape = Shape::new(C, R, B, 1);
-        let input_shape = Shape::new(C, T, B, 1);
-        let output_shape = Shape::new(R, T, 2 * B, 1);
+        #[allow(clippy::excessive_precision)]
+        let quant: [f32; 16] = [
+            -1.0,
+            -0.6961928009986877,
+            -0.5250730514526367,
+            -0.39491748809814453,
+            -0.28444138169288635,
+            -0.18477343022823334,
+            -0.09105003625154495,
+            0.0,
+            0.07958029955625534,
+            0.16093020141124725,
+            0.24611230194568634,
+            0.33791524171829224,
+            0.44070982933044434,
+            0.5626170039176941,
+            0.7229568362236023,
+            1.0,
+        ];
+        let (matrix_u8, matrix_u4, absmax) = {
+            let mut matrix_u8: Vec<u8> = vec![0; matrix.len()];
+            let mut matrix_u4: Vec<u8> = vec![0; matrix.len() / 2];
+            let mut absmax = vec![f16::ZERO; matrix.len() / NF4_BLOCK_SIZE];
 
-        let matrix_dev = context.tensor_from_data(matrix_shape, matrix.clone())?;
-        let input_f32_dev: TensorGpu<_, _> =
-            context.tensor_from_data(input_shape, input_f32.clone())?;
-        let input_f16_dev: TensorGpu<f16, _> = context.tensor_init(input_shape);
-        let output_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
+            for (i, absmax) in absmax.iter_mut().enumerate() {
+                let start = i * NF4_BLOCK_SIZE;
+                let end = start + NF4_BLOCK_SIZE;
+                let chunk = &matrix[start..end];
+                *absmax = chunk
+                    .iter()
+                    .map(|&x| if x >= f16::ZERO { x } else { -x })
+                    .reduce(f16::max)
+                    .unwrap();
+                for (j, value) in chunk.iter().enumerate() {
+                    let value = value.to_f32() / absmax.to_f32();
+                    matrix_u8[start + j] = quant
+                        .iter()
+                        .map(|quant| (value - quant).abs())
+                        .enumerate()
+                        .fold((0, f32::MAX), |acc, x| if x.1 < acc.1 { x } else { acc })
+                        .0 as u8;
+                }
+            }
 
-        let ops = TensorOp::List(vec![
-            TensorOp::blit(
-                input_f32_dev.view(.., .., .., ..)?,
-                input_f16_dev.view(.., .., .., ..)?,
-            )?,
-            TensorOp::matmul_vec_fp16(
-                &matrix_dev,
-                input_f32_dev.view(.., .., .., ..)?,
-                output_dev.view(.., .., 0..B, ..)?,
-                Activation::None,
-            )?,
-            TensorOp::matmul_mat_fp16(
-                matrix_dev.view(.., .., .., ..)?,
-                input_f16_dev.view(.., .., .., ..)?,
-                output_dev.view(.., .., B.., ..)?,
+            for (i, x) in matrix_u4.iter_mut().enumerate() {
+                *x = matrix_u8[2 * i] | matrix_u8[2 * i + 1] << 4;
+            }
+
+            (matrix_u8, matrix_u4, absmax)
+        };
+
+        let quant_shape = Shape::new(quant.len(), 1, 1, 1);
+        let absmax_shape = Shape::new(C / NF4_BLOCK_SIZE, R, 1, 1);
+        let matrix_f16_shape = Shape::new(C, R, 1, 1);
+        let matrix_u4_shape = Shape::new(C / 2, R, 1, 1);
+        let input_shape = Shape::new(C, T, 1, 1);
+        let output_shape = Shape::new(R, T, 1, 1);
+
+        let quant_dev = context.tensor_from_data(quant_shape, quant.to_vec())?;
+        let absmax_dev = context.tensor_init(absmax_shape);
+        let matrix_f16_dev = context.tensor_from_data(matrix_f16_shape, matrix.clone())?;
+
+        let matrix_u4_dev = context.tensor_init(matrix_u4_shape);
+        let input_dev: TensorGpu<_, _> =
+            context.tensor_from_data(input_shape, input_f16.clone())?;
+        let output_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
+
+        let ops = TensorOp::List(vec![
+            TensorOp::quantize_mat_nf4(&matrix_f16_dev, &quant_dev, &absmax_dev, &matrix_u4_dev)?,
+            TensorOp::matmul_mat_nf4(
+                matrix_u4_dev.view(.., .., .., ..)?,
+                &quant_dev,
+                &absmax_dev,
+                input_dev.view(.., .., .., ..)?,
+                output_dev.view(.., .., .., ..)?,
                 Activation::None,
             )?,
         ]);
+        context.submit(context.encode(&ops));
 
-        // profiler.resolve_queries(&mut encoder);
-        context.queue.submit(context.encode(&ops));
-
-        let output_host = output_dev.back_in_place();
-        let output_host = Vec::from(output_host);
-
-        // profiler.end_frame().unwrap();
-        context.device.poll(wgpu::MaintainBase::Wait);
+        let matrix_u4_host = matrix_u4_dev.back_in_place().to_vec();
+        let absmax_host = absmax_dev.back_in_place().to_vec();
+        let output_host = output_dev.back_in_place().to_vec();
 
-        // if let Some(results) = profiler.process_finished_frame() {
-        //     wgpu_profiler::chrometrace::write_chrometrace(
-        //         std::path::Path::new(&format!("./trace/matmul_{T}.json")),
-        //         &results,
-        //     )
-        //     .expect("failed to write trace");
-        // }
+        let mut truth = vec![0.0; output_host.len()];
+        for token in 0..T {
+            for line in 0..R {
+                let matrix = &matrix[line * C..(line + 1) * C];
+                let input = &input_f16[token * C..(token + 1) * C];
+                let product = matrix
+                    .iter()
+                    .zip(input.iter())
+                    .fold(0.0f32, |acc, x| acc + x.0.to_f32() * x.1.to_f32());
+                truth[token * R + line] = product;
+            }
+        }
 
         let mut ans = vec![0.0; output_host.len()];
-        for batch in 0..B {
-            for token in 0..T {
-                for line in 0..R {
-                    let matrix = &matrix[((batch * R + line) * C)..((batch * R + line) + 1) * C];
-                    let input = &input_f32[(batch * T + token) * C..((batch * T + token) + 1) * C];
-                    let product = matrix
-                        .iter()
-                        .zip(input.iter())
-                        .fold(0.0f32, |acc, x| acc + x.0.to_f32() * *x.1);
-                    ans[(batch * T + token) * R + line] = product;
-
-                    let input = &input_f16[(batch * T + token) * C..((batch * T + token) + 1) * C];
-                    let product = matrix
+        for token in 0..T {
+            for line in 0..R {
+                let matrix = &matrix_u8[line * C..(line + 1) * C];
+                let input = &input_f16[token * C..(token + 1) * C];
+                let product =
+                    matrix
                         .iter()
                         .zip(input.iter())
-                        .fold(0.0f32, |acc, x| acc + x.0.to_f32() * x.1.to_f32());
-                    ans[((B + batch) * T + token) * R + line] = product;
-                }
+                        .enumerate()
+                        .fold(0.0f32, |acc, (i, x)| {
+                            let amp = absmax[(line * C + i) / NF4_BLOCK_SIZE];
+                            acc + quant[*x.0 as usize] * amp.to_f32() * x.1.to_f32()
+                        });
+                ans[token * R + line] = product;
             }
         }
 
+        itertools::zip_eq(matrix_u4_host, matrix_u4)
+            .enumerate()
+            .for_each(|(index, (a, b))| {
+                assert!(
+                    a == b,
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
+        itertools::zip_eq(absmax_host, absmax)
+            .enumerate()
+            .for_each(|(index, (a, b))| {
+                assert!(
+                    is_approx_eps(a.to_f32(), b.to_f32(), 0.01),
+                    "Failed at index {index}, computed: {a} vs. answer: {b}"
+                );
+            });
+
         itertools::zip_eq(output_host, ans)
             .enumerate()
             .for_each(|(index, (a, b))| {
@@ -2746,7 +7076,7 @@ mod tests {
     }
 
     #[test]
-    fn test_matmul_int8() -> Result<()> {
+    fn test_matmul_fp8() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
             Ok(context) => context,
             Err(_) => return Ok(()),
@@ -2756,7 +7086,19 @@ mod tests {
         const C: usize = 2560;
         const R: usize = 2048;
         const T: usize = 64;
-        const INT8_BLOCK_SIZE: usize = TensorOp::INT8_BLOCK_SIZE as usize;
+        const FP8_BLOCK_SIZE: usize = TensorOp::FP8_BLOCK_SIZE as usize;
+
+        // reference decoding of e4m3, normalized by its max
+        fn decode(x: u8) -> f32 {
+            let sign = if x & 0x80 == 0 { 1.0 } else { -1.0 };
+            let exp = ((x >> 3) & 0xf) as i32;
+            let mantissa = (x & 0x7) as f32;
+            let value = match exp {
+                0 => mantissa / 8.0 * 2.0f32.powi(-6),
+                _ => (1.0 + mantissa / 8.0) * 2.0f32.powi(exp - 7),
+            };
+            sign * value / 448.0
+        }
 
         let matrix = vec![(); C * R]
             .into_iter()
@@ -2769,116 +7111,96 @@ mod tests {
             .collect_vec();
         let input_f16 = input_f32.iter().copied().map(f16::from_f32).collect_vec();
 
-        let (matrix_u8, min, max) = {
-            let mut matrix_u8: Vec<u8> = vec![0; matrix.len()];
-            let mut min = vec![f16::MAX; matrix.len() / INT8_BLOCK_SIZE];
-            let mut max = vec![f16::MIN; matrix.len() / INT8_BLOCK_SIZE];
-
-            for (i, (min, max)) in min.iter_mut().zip_eq(max.iter_mut()).enumerate() {
-                let start = i * INT8_BLOCK_SIZE;
-                let end = start + INT8_BLOCK_SIZE;
-                let chunk = &matrix[start..end];
-                for value in chunk.iter() {
-                    *min = min.min(*value);
-                    *max = max.max(*value);
-                }
-                for (j, value) in chunk.iter().enumerate() {
-                    let value = value.to_f32();
-                    let min = min.to_f32();
-                    let max = max.to_f32();
-                    let value = (value - min) / (max - min);
-                    matrix_u8[start + j] = f32::round(value * 255.0) as u8;
-                }
-            }
-
-            (matrix_u8, min, max)
-        };
-
-        let minmax_shape = Shape::new(C / INT8_BLOCK_SIZE * 2, R, 1, 1);
+        let absmax_shape = Shape::new(C / FP8_BLOCK_SIZE, R, 1, 1);
         let matrix_shape = Shape::new(C, R, 1, 1);
         let input_shape = Shape::new(C, T, 1, 1);
         let output_shape = Shape::new(R, T, 1, 1);
 
-        let minmax_dev = context.tensor_init(minmax_shape);
+        let absmax_dev = context.tensor_init(absmax_shape);
         let matrix_f16_dev = context.tensor_from_data(matrix_shape, matrix.clone())?;
 
         let matrix_u8_dev = context.tensor_init(matrix_shape);
         let input_dev: TensorGpu<_, _> =
             context.tensor_from_data(input_shape, input_f16.clone())?;
-        let output_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
+        let output_vec_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
+        let output_mat_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
 
         let ops = TensorOp::List(vec![
-            TensorOp::quantize_mat_int8(&matrix_f16_dev, &minmax_dev, &matrix_u8_dev)?,
-            TensorOp::matmul_mat_int8(
+            TensorOp::quantize_mat_fp8(
+                &matrix_f16_dev,
+                &absmax_dev,
+                &matrix_u8_dev,
+                Fp8Format::E4M3,
+            )?,
+            TensorOp::matmul_vec_fp8(
+                &matrix_u8_dev,
+                &absmax_dev,
+                Fp8Format::E4M3,
+                input_dev.view(.., .., .., ..)?,
+                output_vec_dev.view(.., .., .., ..)?,
+                Activation::None,
+            )?,
+            TensorOp::matmul_mat_fp8(
                 matrix_u8_dev.view(.., .., .., ..)?,
-                &minmax_dev,
+                &absmax_dev,
+                Fp8Format::E4M3,
                 input_dev.view(.., .., .., ..)?,
-                output_dev.view(.., .., .., ..)?,
+                output_mat_dev.view(.., .., .., ..)?,
                 Activation::None,
             )?,
         ]);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let matrix_u8_host = matrix_u8_dev.back_in_place().to_vec();
-        let output_host = output_dev.back_in_place().to_vec();
+        let absmax_host = absmax_dev.back_in_place().to_vec();
+        let output_vec_host = output_vec_dev.back_in_place().to_vec();
+        let output_mat_host = output_mat_dev.back_in_place().to_vec();
 
-        // let mut truth = vec![0.0; output_host.len()];
-        // for token in 0..T {
-        //     for line in 0..R {
-        //         let matrix = &matrix[line * C..(line + 1) * C];
-        //         let input = &input_f16[token * C..(token + 1) * C];
-        //         let product = matrix
-        //             .iter()
-        //             .zip(input.iter())
-        //             .fold(0.0f32, |acc, x| acc + x.0.to_f32() * x.1.to_f32());
-        //         truth[token * R + line] = product;
-        //     }
-        // }
+        let dequant = matrix_u8_host
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| decode(x) * absmax_host[i / FP8_BLOCK_SIZE].to_f32())
+            .collect_vec();
 
-        let mut ans = vec![0.0; output_host.len()];
+        itertools::zip_eq(&dequant, &matrix)
+            .enumerate()
+            .for_each(|(index, (&a, b))| {
+                let b = b.to_f32();
+                assert!(
+                    (a - b).abs() <= b.abs() / 15.0 + 1.0e-3,
+                    "Failed at index {index}, dequantized: {a} vs. original: {b}"
+                );
+            });
+
+        let mut ans = vec![0.0; output_vec_host.len()];
         for token in 0..T {
             for line in 0..R {
-                let matrix = &matrix_u8_host[line * C..(line + 1) * C];
+                let matrix = &dequant[line * C..(line + 1) * C];
                 let input = &input_f16[token * C..(token + 1) * C];
-                let product =
-                    matrix
-                        .iter()
-                        .zip_eq(input.iter())
-                        .enumerate()
-                        .fold(0.0f32, |acc, (i, x)| {
-                            let min = min[(line * C + i) / INT8_BLOCK_SIZE].to_f32();
-                            let max = max[(line * C + i) / INT8_BLOCK_SIZE].to_f32();
-                            let value = (*x.0 as f32) / 255.0;
-                            acc + (value * (max - min) + min) * x.1.to_f32()
-                        });
+                let product = matrix
+                    .iter()
+                    .zip_eq(input.iter())
+                    .fold(0.0f32, |acc, x| acc + x.0 * x.1.to_f32());
                 ans[token * R + line] = product;
             }
         }
 
-        itertools::zip_eq(matrix_u8_host, matrix_u8)
-            .enumerate()
-            .for_each(|(index, (a, b))| {
-                assert!(
-                    a.abs_diff(b) < 2,
-                    // a == b,
-                    "Failed at index {index}, computed: {a} vs. answer: {b}"
-                );
-            });
-
-        itertools::zip_eq(output_host, ans)
-            .enumerate()
-            .for_each(|(index, (a, b))| {
-                assert!(
-                    is_approx_eps(a, b, 0.01),
-                    "Failed at index {index}, computed: {a} vs. answer: {b}"
-                );
-            });
+        for output in [output_vec_host, output_mat_host] {
+            itertools::zip_eq(output, ans.iter())
+                .enumerate()
+                .for_each(|(index, (a, &b))| {
+                    assert!(
+                        is_approx_eps(a, b, 0.01),
+                        "Failed at index {index}, computed: {a} vs. answer: {b}"
+                    );
+                });
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_matmul_nf4() -> Result<()> {
+    fn test_matmul_kquant() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
             Ok(context) => context,
             Err(_) => return Ok(()),
@@ -2888,163 +7210,266 @@ mod tests {
         const C: usize = 2560;
         const R: usize = 2048;
         const T: usize = 64;
-        const NF4_BLOCK_SIZE: usize = TensorOp::NF4_BLOCK_SIZE as usize;
-
-        fn normal() -> f32 {
-            let u = fastrand::f32();
-            let v = fastrand::f32();
-            (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
-        }
+        const KQUANT_BLOCK_SIZE: usize = TensorOp::KQUANT_BLOCK_SIZE as usize;
+        const KQUANT_SUPER_BLOCK_SIZE: usize = TensorOp::KQUANT_SUPER_BLOCK_SIZE as usize;
 
         let matrix = vec![(); C * R]
             .into_iter()
-            .map(|_| normal())
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
             .map(f16::from_f32)
             .collect_vec();
         let input_f32 = vec![(); C * T]
             .into_iter()
-            .map(|_| 2.0 * fastrand::f32() - 1.0)
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
             .collect_vec();
         let input_f16 = input_f32.iter().copied().map(f16::from_f32).collect_vec();
 
-        #[allow(clippy::excessive_precision)]
-        let quant: [f32; 16] = [
-            -1.0,
-            -0.6961928009986877,
-            -0.5250730514526367,
-            -0.39491748809814453,
-            -0.28444138169288635,
-            -0.18477343022823334,
-            -0.09105003625154495,
-            0.0,
-            0.07958029955625534,
-            0.16093020141124725,
-            0.24611230194568634,
-            0.33791524171829224,
-            0.44070982933044434,
-            0.5626170039176941,
-            0.7229568362236023,
-            1.0,
-        ];
-        let (matrix_u8, matrix_u4, absmax) = {
-            let mut matrix_u8: Vec<u8> = vec![0; matrix.len()];
-            let mut matrix_u4: Vec<u8> = vec![0; matrix.len() / 2];
-            let mut absmax = vec![f16::ZERO; matrix.len() / NF4_BLOCK_SIZE];
+        let matrix_shape = Shape::new(C, R, 1, 1);
+        let input_shape = Shape::new(C, T, 1, 1);
+        let output_shape = Shape::new(R, T, 1, 1);
 
-            for (i, absmax) in absmax.iter_mut().enumerate() {
-                let start = i * NF4_BLOCK_SIZE;
-                let end = start + NF4_BLOCK_SIZE;
-                let chunk = &matrix[start..end];
-                *absmax = chunk
-                    .iter()
-                    .map(|&x| if x >= f16::ZERO { x } else { -x })
-                    .reduce(f16::max)
-                    .unwrap();
-                for (j, value) in chunk.iter().enumerate() {
-                    let value = value.to_f32() / absmax.to_f32();
-                    matrix_u8[start + j] = quant
+        let matrix_f16_dev = context.tensor_from_data(matrix_shape, matrix.clone())?;
+        let input_dev: TensorGpu<_, _> =
+            context.tensor_from_data(input_shape, input_f16.clone())?;
+
+        for format in [KQuantFormat::Q4K, KQuantFormat::Q5K] {
+            let minmax_dev = context.tensor_init([C / KQUANT_SUPER_BLOCK_SIZE * 2, R, 1, 1]);
+            let scales_dev = context.tensor_init([C / KQUANT_BLOCK_SIZE * 2, R, 1, 1]);
+            let matrix_u8_dev = context.tensor_init([C / 2, R, 1, 1]);
+            let high_dev = match format {
+                KQuantFormat::Q4K => None,
+                KQuantFormat::Q5K => Some(context.tensor_init([C / 8, R, 1, 1])),
+            };
+            let output_vec_dev: TensorGpu<f32, _> = context.tensor_init(output_shape);
+            let output_mat_dev: TensorGpu<f32, _> = context.tensor_init(output_shape);
+
+            let ops = TensorOp::List(vec![
+                TensorOp::quantize_mat_kquant(
+                    &matrix_f16_dev,
+                    &minmax_dev,
+                    &scales_dev,
+                    &matrix_u8_dev,
+                    high_dev.as_ref(),
+                )?,
+                TensorOp::matmul_vec_kquant(
+                    &matrix_u8_dev,
+                    &minmax_dev,
+                    &scales_dev,
+                    high_dev.as_ref(),
+                    input_dev.view(.., .., .., ..)?,
+                    output_vec_dev.view(.., .., .., ..)?,
+                    Activation::None,
+                )?,
+                TensorOp::matmul_mat_kquant(
+                    matrix_u8_dev.view(.., .., .., ..)?,
+                    &minmax_dev,
+                    &scales_dev,
+                    high_dev.as_ref(),
+                    input_dev.view(.., .., .., ..)?,
+                    output_mat_dev.view(.., .., .., ..)?,
+                    Activation::None,
+                )?,
+            ]);
+            context.submit(context.encode(&ops));
+
+            let matrix_u8_host = matrix_u8_dev.back_in_place().to_vec();
+            let minmax_host = minmax_dev.back_in_place().to_vec();
+            let scales_host = scales_dev.back_in_place().to_vec();
+            let high_host = high_dev.map(|high| high.back_in_place().to_vec());
+            let output_vec_host = output_vec_dev.back_in_place().to_vec();
+            let output_mat_host = output_mat_dev.back_in_place().to_vec();
+
+            // reference dequantization: `d * sc * q - dmin * m`
+            let dequant = (0..C * R)
+                .map(|i| {
+                    let low = (matrix_u8_host[i / 2] >> (4 * (i % 2))) & 0xf;
+                    let high = match &high_host {
+                        Some(high) => (high[i / 8] >> (i % 8)) & 1,
+                        None => 0,
+                    };
+                    let q = (low | (high << 4)) as f32;
+                    let block = i / KQUANT_BLOCK_SIZE;
+                    let sc = scales_host[2 * block] as f32;
+                    let m = scales_host[2 * block + 1] as f32;
+                    let block = i / KQUANT_SUPER_BLOCK_SIZE;
+                    let d = minmax_host[2 * block].to_f32();
+                    let dmin = minmax_host[2 * block + 1].to_f32();
+                    d * sc * q - dmin * m
+                })
+                .collect_vec();
+
+            // one quantization step over the range of the matrix
+            let step = match format {
+                KQuantFormat::Q4K => 10.0 / 15.0,
+                KQuantFormat::Q5K => 10.0 / 31.0,
+            };
+            itertools::zip_eq(&dequant, &matrix)
+                .enumerate()
+                .for_each(|(index, (&a, b))| {
+                    let b = b.to_f32();
+                    assert!(
+                        (a - b).abs() <= step,
+                        "{format}: failed at index {index}, dequantized: {a} vs. original: {b}"
+                    );
+                });
+
+            let mut ans = vec![0.0; output_vec_host.len()];
+            for token in 0..T {
+                for line in 0..R {
+                    let matrix = &dequant[line * C..(line + 1) * C];
+                    let input = &input_f16[token * C..(token + 1) * C];
+                    let product = matrix
                         .iter()
-                        .map(|quant| (value - quant).abs())
-                        .enumerate()
-                        .fold((0, f32::MAX), |acc, x| if x.1 < acc.1 { x } else { acc })
-                        .0 as u8;
+                        .zip_eq(input.iter())
+                        .fold(0.0f32, |acc, x| acc + x.0 * x.1.to_f32());
+                    ans[token * R + line] = product;
                 }
             }
-
-            for (i, x) in matrix_u4.iter_mut().enumerate() {
-                *x = matrix_u8[2 * i] | matrix_u8[2 * i + 1] << 4;
+
+            for output in [output_vec_host, output_mat_host] {
+                itertools::zip_eq(output, ans.iter())
+                    .enumerate()
+                    .for_each(|(index, (a, &b))| {
+                        assert!(
+                            is_approx_eps(a, b, 0.01),
+                            "{format}: failed at index {index}, computed: {a} vs. answer: {b}"
+                        );
+                    });
             }
+        }
 
-            (matrix_u8, matrix_u4, absmax)
+        Ok(())
+    }
+
+    #[test]
+    fn test_tile_block_size() -> Result<()> {
+        // a device that offers less workgroup memory than the default tiles take
+        let context = match pollster::block_on(async {
+            let instance = Instance::default();
+            let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+            ContextBuilder::new(adapter)
+                .update_limits(|limits| limits.max_compute_workgroup_storage_size = 2048)
+                .build()
+                .await
+        }) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
         };
+        fastrand::seed(42);
 
-        let quant_shape = Shape::new(quant.len(), 1, 1, 1);
-        let absmax_shape = Shape::new(C / NF4_BLOCK_SIZE, R, 1, 1);
-        let matrix_f16_shape = Shape::new(C, R, 1, 1);
-        let matrix_u4_shape = Shape::new(C / 2, R, 1, 1);
-        let input_shape = Shape::new(C, T, 1, 1);
-        let output_shape = Shape::new(R, T, 1, 1);
+        let block_size = TensorOp::tile_block_size(&context, 8, |block| 64 * block * block);
+        assert_eq!(block_size, 4);
 
-        let quant_dev = context.tensor_from_data(quant_shape, quant.to_vec())?;
-        let absmax_dev = context.tensor_init(absmax_shape);
-        let matrix_f16_dev = context.tensor_from_data(matrix_f16_shape, matrix.clone())?;
+        const K: usize = 256;
+        const M: usize = 96;
+        const N: usize = 40;
 
-        let matrix_u4_dev = context.tensor_init(matrix_u4_shape);
-        let input_dev: TensorGpu<_, _> =
-            context.tensor_from_data(input_shape, input_f16.clone())?;
-        let output_dev: TensorGpu<_, _> = context.tensor_init(output_shape);
+        let xa = vec![(); K * M]
+            .into_iter()
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .collect_vec();
+        let xb = vec![(); K * N]
+            .into_iter()
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .collect_vec();
+        let base = vec![(); M * N]
+            .into_iter()
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .collect_vec();
+
+        let xa_dev: TensorGpu<_, _> = context.tensor_from_data([K, M, 1, 1], xa.clone())?;
+        let xb_dev: TensorGpu<_, _> = context.tensor_from_data([K, N, 1, 1], xb.clone())?;
+        let matmul_dev: TensorGpu<f32, _> = context.tensor_init([M, N, 1, 1]);
+        let blend_dev: TensorGpu<_, _> = context.tensor_from_data([M, N, 1, 1], base.clone())?;
+        let factor = context.tensor_from_data([4, 1, 1, 1], vec![0.5f32, 1.0, 0.0, 0.0])?;
 
         let ops = TensorOp::List(vec![
-            TensorOp::quantize_mat_nf4(&matrix_f16_dev, &quant_dev, &absmax_dev, &matrix_u4_dev)?,
-            TensorOp::matmul_mat_nf4(
-                matrix_u4_dev.view(.., .., .., ..)?,
-                &quant_dev,
-                &absmax_dev,
-                input_dev.view(.., .., .., ..)?,
-                output_dev.view(.., .., .., ..)?,
+            TensorOp::matmul_mat_fp16(
+                xa_dev.view(.., .., .., ..)?,
+                xb_dev.view(.., .., .., ..)?,
+                matmul_dev.view(.., .., .., ..)?,
                 Activation::None,
             )?,
+            TensorOp::blend_lora(
+                &factor,
+                xa_dev.view(.., .., .., ..)?,
+                xb_dev.view(.., .., .., ..)?,
+                blend_dev.view(.., .., .., ..)?,
+            )?,
         ]);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
-        let matrix_u4_host = matrix_u4_dev.back_in_place().to_vec();
-        let absmax_host = absmax_dev.back_in_place().to_vec();
-        let output_host = output_dev.back_in_place().to_vec();
+        let matmul_host = matmul_dev.back_in_place().to_vec();
+        let blend_host = blend_dev.back_in_place().to_vec();
 
-        let mut truth = vec![0.0; output_host.len()];
-        for token in 0..T {
-            for line in 0..R {
-                let matrix = &matrix[line * C..(line + 1) * C];
-                let input = &input_f16[token * C..(token + 1) * C];
-                let product = matrix
-                    .iter()
-                    .zip(input.iter())
-                    .fold(0.0f32, |acc, x| acc + x.0.to_f32() * x.1.to_f32());
-                truth[token * R + line] = product;
+        for n in 0..N {
+            for m in 0..M {
+                let product = itertools::zip_eq(&xa[m * K..(m + 1) * K], &xb[n * K..(n + 1) * K])
+                    .fold(0.0f32, |acc, (a, b)| acc + a.to_f32() * b.to_f32());
+                let index = n * M + m;
+                let a = matmul_host[index];
+                assert!(
+                    is_approx_eps(a, product, 0.01),
+                    "matmul failed at {index}, computed: {a} vs. answer: {product}"
+                );
+                let a = blend_host[index].to_f32();
+                let b = 0.5 * product + base[index].to_f32();
+                assert!(
+                    is_approx_eps(a, b, 0.01),
+                    "blend failed at {index}, computed: {a} vs. answer: {b}"
+                );
             }
         }
 
-        let mut ans = vec![0.0; output_host.len()];
-        for token in 0..T {
-            for line in 0..R {
-                let matrix = &matrix_u8[line * C..(line + 1) * C];
-                let input = &input_f16[token * C..(token + 1) * C];
-                let product =
-                    matrix
-                        .iter()
-                        .zip(input.iter())
-                        .enumerate()
-                        .fold(0.0f32, |acc, (i, x)| {
-                            let amp = absmax[(line * C + i) / NF4_BLOCK_SIZE];
-                            acc + quant[*x.0 as usize] * amp.to_f32() * x.1.to_f32()
-                        });
-                ans[token * R + line] = product;
-            }
-        }
+        Ok(())
+    }
 
-        itertools::zip_eq(matrix_u4_host, matrix_u4)
-            .enumerate()
-            .for_each(|(index, (a, b))| {
-                assert!(
-                    a == b,
-                    "Failed at index {index}, computed: {a} vs. answer: {b}"
-                );
-            });
+    #[test]
+    fn test_scale_batch() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
 
-        itertools::zip_eq(absmax_host, absmax)
-            .enumerate()
-            .for_each(|(index, (a, b))| {
-                assert!(
-                    is_approx_eps(a.to_f32(), b.to_f32(), 0.01),
-                    "Failed at index {index}, computed: {a} vs. answer: {b}"
-                );
-            });
+        const C: usize = 16;
+        let cursors = vec![
+            Cursor {
+                batch: 0,
+                token: 0,
+                len: 2,
+            },
+            Cursor {
+                batch: 2,
+                token: 2,
+                len: 3,
+            },
+        ];
+        let batches = [0, 0, 2, 2, 2];
+        let factor = vec![0.5, 1.0, -2.0];
 
-        itertools::zip_eq(output_host, ans)
+        let x = [(); C * 5].map(|_| fastrand::f32() - 0.5).to_vec();
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, 5, 1, 1], x.clone())?;
+        let cursors: TensorGpu<u32, _> =
+            context.tensor_from_data([2, 5, 1, 1], cursors.into_cursors())?;
+        let factor_dev: TensorGpu<f32, _> =
+            context.tensor_from_data([1, 1, 3, 1], factor.clone())?;
+
+        let op = TensorOp::scale_batch(&cursors, &factor_dev, &x_dev)?;
+        context.submit(context.encode(&op));
+
+        let x_host = x_dev.back_in_place().to_vec();
+        let ans = x
+            .chunks(C)
+            .zip_eq(batches)
+            .flat_map(|(x, batch)| x.iter().map(|x| x * factor[batch]).collect_vec())
+            .collect_vec();
+
+        itertools::zip_eq(x_host, ans)
             .enumerate()
             .for_each(|(index, (a, b))| {
                 assert!(
-                    is_approx_eps(a, b, 0.01),
+                    is_approx(a, b),
                     "Failed at index {index}, computed: {a} vs. answer: {b}"
                 );
             });
@@ -3078,7 +7503,7 @@ mod tests {
         ops.push(TensorOp::blit(input, output.view(.., 2.., 1..2, ..)?)?);
 
         let ops = TensorOp::List(ops);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let output_host = output.back_in_place();
         let output_host = Vec::from(output_host);
@@ -3094,6 +7519,102 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_quant_state() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const S: usize = 3;
+        const B: usize = 4;
+
+        // the last row is zeros, which must not give NaNs
+        let x = (0..C * S * B)
+            .map(|index| match index / C {
+                x if x == S * B - 1 => 0.0,
+                x => (fastrand::f32() - 0.5) * (x + 1) as f32,
+            })
+            .collect_vec();
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data([C, S, B, 1], x.clone())?;
+        let codes: TensorGpu<u8, _> = context.zeros([C, S, B, 1]);
+        let scale: TensorGpu<f32, _> = context.zeros([1, S, B, 1]);
+        let output: TensorGpu<f32, _> = context.zeros([C, S, 2, 1]);
+
+        let ops = TensorOp::List(vec![
+            TensorOp::quantize_state(
+                x_dev.view(.., .., .., ..)?,
+                &scale,
+                codes.view(.., .., .., ..)?,
+            )?,
+            TensorOp::dequantize_state(
+                codes.view(.., .., 2.., ..)?,
+                &scale,
+                output.view(.., .., .., ..)?,
+            )?,
+        ]);
+        context.submit(context.encode(&ops));
+
+        let scale = scale.back_in_place().to_vec();
+        let output = output.back_in_place().to_vec();
+        for (row, (x, y)) in x.chunks(C).skip(2 * S).zip_eq(output.chunks(C)).enumerate() {
+            let absmax = x.iter().fold(0.0f32, |acc, x| acc.max(x.abs()));
+            assert_eq!(scale[2 * S + row], absmax);
+            for (x, y) in x.iter().zip_eq(y.iter()) {
+                assert!((x - y).abs() <= absmax / 127.0, "{x} vs {y}");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_tensor_batches() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let x = (0..16).map(|x| x as f32).collect_vec();
+        let x: TensorGpu<f32, ReadWrite> = context.tensor_from_data([2, 2, 4, 1], x)?;
+        let y = (16..20).map(|x| x as f32).collect_vec();
+        let y: TensorGpu<f32, ReadWrite> = context.tensor_from_data([2, 2, 1, 1], y)?;
+        let output: TensorGpu<f32, ReadWrite> = context.zeros([2, 2, 5, 1]);
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        let copies = [
+            BatchCopy::new(&x, 0, &output, 1),
+            BatchCopy::new(&x, 1, &output, 2),
+            BatchCopy::new(&x, 2, &output, 3),
+            BatchCopy::new(&x, 3, &output, 0),
+            BatchCopy::new(&y, 0, &output, 4),
+        ];
+        assert_eq!(encoder.copy_tensor_batches(copies)?, 3);
+        context.submit(Some(encoder.finish()));
+
+        let output = output.back_in_place().to_vec();
+        let expected = [12..16, 0..4, 4..8, 8..12, 16..20]
+            .into_iter()
+            .flatten()
+            .map(|x| x as f32)
+            .collect_vec();
+        assert_eq!(output, expected);
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        let output: TensorGpu<f32, ReadWrite> = context.zeros([2, 2, 1, 1]);
+        assert_eq!(
+            encoder.copy_tensor_batches([BatchCopy::new(&x, 0, &x, 1)]),
+            Err(TensorError::SameBuffer)
+        );
+        assert_eq!(
+            encoder.copy_tensor_batches([BatchCopy::new(&x, 4, &output, 0)]),
+            Err(TensorError::BatchOutOfRange { batch: 4, max: 4 })
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_transpose() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
@@ -3109,7 +7630,7 @@ mod tests {
         let input: TensorGpu<_, _> = context.tensor_from_data([4, 3, 2, 1], input)?;
 
         let ops = TensorOp::transpose(input.view(.., .., .., ..)?, output.view(.., ..2, .., ..)?)?;
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let output_host = output.back_in_place();
         let output_host: Vec<f32> = Vec::from(output_host);
@@ -3125,4 +7646,337 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_elementwise() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 64;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = (0..C * T * B)
+            .map(|_| 4.0 * fastrand::f32() - 2.0)
+            .collect_vec();
+        // broadcast along tokens, and kept away from zero for division
+        let y = (0..C * B)
+            .map(|_| (fastrand::f32() + 0.5) * if fastrand::bool() { 1.0 } else { -1.0 })
+            .collect_vec();
+        let y_dev: TensorGpu<f16, _> = context.tensor_from_data(
+            [C, 1, B, 1],
+            y.iter().map(|&y| f16::from_f32(y)).collect_vec(),
+        )?;
+        let y = y.iter().map(|&y| f16::from_f32(y).to_f32()).collect_vec();
+
+        let binary = |op, x: f32, y: f32| match op {
+            BinaryOp::Add => x + y,
+            BinaryOp::Sub => x - y,
+            BinaryOp::Mul => x * y,
+            BinaryOp::Div => x / y,
+            BinaryOp::Max => x.max(y),
+            BinaryOp::Min => x.min(y),
+        };
+        for op in [
+            BinaryOp::Add,
+            BinaryOp::Sub,
+            BinaryOp::Mul,
+            BinaryOp::Div,
+            BinaryOp::Max,
+            BinaryOp::Min,
+        ] {
+            let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+            let ops =
+                TensorOp::binary(y_dev.view(.., .., .., ..)?, x_dev.view(.., .., .., ..)?, op)?;
+            context.submit(context.encode(&ops));
+
+            let output = x_dev.back_in_place().to_vec();
+            for (index, (&z, &x)) in output.iter().zip_eq(&x).enumerate() {
+                let (c, b) = (index % C, index / (C * T));
+                let expected = binary(op, x, y[b * C + c]);
+                assert!(
+                    is_approx_eps(z, expected, 1.0e-5),
+                    "{op}: {z} vs. {expected}"
+                );
+            }
+        }
+
+        let unary = |op, x: f32| match op {
+            UnaryOp::Neg => -x,
+            UnaryOp::Abs => x.abs(),
+            UnaryOp::Exp => x.exp(),
+            UnaryOp::Log => x.ln(),
+            UnaryOp::Sigmoid => 1.0 / (1.0 + (-x).exp()),
+            UnaryOp::Tanh => x.tanh(),
+            UnaryOp::Relu => x.max(0.0),
+            UnaryOp::SquaredRelu => x.max(0.0).powi(2),
+            UnaryOp::Silu => x / (1.0 + (-x).exp()),
+        };
+        for op in [
+            UnaryOp::Neg,
+            UnaryOp::Abs,
+            UnaryOp::Exp,
+            UnaryOp::Log,
+            UnaryOp::Sigmoid,
+            UnaryOp::Tanh,
+            UnaryOp::Relu,
+            UnaryOp::SquaredRelu,
+            UnaryOp::Silu,
+        ] {
+            let x = match op {
+                UnaryOp::Log => x.iter().map(|x| x.abs()).collect_vec(),
+                _ => x.clone(),
+            };
+            let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+            let x_fp16: TensorGpu<f16, _> = context.tensor_from_data(
+                [C, T, B, 1],
+                x.iter().map(|&x| f16::from_f32(x)).collect_vec(),
+            )?;
+            let ops = TensorOp::List(vec![
+                TensorOp::unary(x_dev.view(.., .., .., ..)?, op)?,
+                // only the middle token
+                TensorOp::unary(x_fp16.view(.., 1, .., ..)?, op)?,
+            ]);
+            context.submit(context.encode(&ops));
+
+            let output = x_dev.back_in_place().to_vec();
+            let output_fp16 = x_fp16.back_in_place().to_vec();
+            for (index, ((&z, &z_fp16), &x)) in
+                output.iter().zip_eq(&output_fp16).zip_eq(&x).enumerate()
+            {
+                let expected = unary(op, x);
+                assert!(
+                    is_approx_eps(z, expected, 1.0e-5),
+                    "{op}: {z} vs. {expected}"
+                );
+
+                let x = f16::from_f32(x).to_f32();
+                let expected = match (index / C) % T {
+                    1 => unary(op, x),
+                    _ => x,
+                };
+                assert!(
+                    is_approx_eps(z_fp16.to_f32(), expected, 1.0e-2),
+                    "{op}: {z_fp16} vs. {expected}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reduce() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = (0..C * T * B)
+            .map(|_| 4.0 * fastrand::f32() - 3.0)
+            .collect_vec();
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let x_fp16: TensorGpu<f16, _> = context.tensor_from_data(
+            [C, T, B, 1],
+            x.iter().map(|&x| f16::from_f32(x)).collect_vec(),
+        )?;
+
+        for op in [ReduceOp::Sum, ReduceOp::Mean, ReduceOp::Max] {
+            let output: TensorGpu<f32, _> = context.tensor_init([1, T, B, 1]);
+            let output_fp16: TensorGpu<f32, _> = context.tensor_init([1, T, B, 1]);
+            // a view of the last 500 channels of the first token
+            let output_view: TensorGpu<f32, _> = context.tensor_init([1, 1, B, 1]);
+            let ops = TensorOp::List(vec![
+                TensorOp::reduce(x_dev.view(.., .., .., ..)?, &output, op)?,
+                TensorOp::reduce(x_fp16.view(.., .., .., ..)?, &output_fp16, op)?,
+                TensorOp::reduce(x_dev.view(500.., 0, .., ..)?, &output_view, op)?,
+            ]);
+            context.submit(context.encode(&ops));
+
+            let reduce = |x: &[f32]| match op {
+                ReduceOp::Sum => x.iter().sum(),
+                ReduceOp::Mean => x.iter().sum::<f32>() / x.len() as f32,
+                ReduceOp::Max => x.iter().copied().fold(f32::MIN, f32::max),
+            };
+            let output = output.back_in_place().to_vec();
+            let output_fp16 = output_fp16.back_in_place().to_vec();
+            for (index, x) in x.chunks_exact(C).enumerate() {
+                let expected = reduce(x);
+                assert!(is_approx_eps(output[index], expected, 1.0e-4), "{op}");
+                assert!(is_approx_eps(output_fp16[index], expected, 1.0e-2), "{op}");
+            }
+
+            let output_view = output_view.back_in_place().to_vec();
+            for (batch, &y) in output_view.iter().enumerate() {
+                let start = batch * T * C;
+                let expected = reduce(&x[start + 500..start + C]);
+                assert!(is_approx_eps(y, expected, 1.0e-4), "{op}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_prob() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = (0..C * T * B)
+            .map(|_| 10.0 * fastrand::f32() - 5.0)
+            .collect_vec();
+        // the last row has no target
+        let mut tokens = (0..T * B).map(|_| fastrand::u32(0..C as u32)).collect_vec();
+        tokens[T * B - 1] = u32::MAX;
+
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let tokens_dev: TensorGpu<u32, _> =
+            context.tensor_from_data([1, T, B, 1], tokens.clone())?;
+        let output: TensorGpu<f32, _> = context.tensor_from_data([1, T, B, 1], vec![1.0; T * B])?;
+        let op = TensorOp::log_prob(&x_dev, &tokens_dev, &output)?;
+        context.submit(context.encode(&op));
+
+        let output = output.back_in_place().to_vec();
+        for ((x, &token), &y) in x.chunks_exact(C).zip(&tokens).zip(&output) {
+            if token == u32::MAX {
+                assert_eq!(y, 1.0);
+                continue;
+            }
+            let max = x.iter().copied().fold(f32::MIN, f32::max);
+            let sum: f32 = x.iter().map(|&x| (x - max).exp()).sum();
+            let expected = x[token as usize] - max - sum.ln();
+            assert!(is_approx_eps(y, expected, 1.0e-4), "{y} vs {expected}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_prob() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = (0..C * T * B)
+            .map(|_| 10.0 * fastrand::f32() - 5.0)
+            .collect_vec();
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let output: TensorGpu<f32, _> = context.tensor_init([1, T, B, 1]);
+        let op = TensorOp::max_prob(&x_dev, &output)?;
+        context.submit(context.encode(&op));
+
+        let output = output.back_in_place().to_vec();
+        for (x, &y) in x.chunks_exact(C).zip(&output) {
+            let max = x.iter().copied().fold(f32::MIN, f32::max);
+            let sum: f32 = x.iter().map(|&x| (x - max).exp()).sum();
+            let expected = sum.recip();
+            assert!(is_approx_eps(y, expected, 1.0e-4), "{y} vs {expected}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_permute() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 12;
+        const T: usize = 8;
+        const B: usize = 4;
+
+        let x = (0..C * T * B).map(|_| fastrand::f32()).collect_vec();
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let x_fp16: TensorGpu<f16, _> = context.tensor_from_data(
+            [C, T, B, 1],
+            x.iter().map(|&x| f16::from_f32(x)).collect_vec(),
+        )?;
+        let shape = [C, T, B];
+
+        for axes in [0, 1, 2].into_iter().permutations(3) {
+            let axes = [axes[0], axes[1], axes[2]];
+            let [c, t, b] = axes.map(|axis| shape[axis]);
+            let output: TensorGpu<f32, _> = context.zeros([c, t, b, 1]);
+            let output_fp16: TensorGpu<f16, _> = context.zeros([c, t, b, 1]);
+
+            let ops = TensorOp::List(vec![
+                TensorOp::permute(
+                    x_dev.view(.., .., .., ..)?,
+                    output.view(.., .., .., ..)?,
+                    axes,
+                )?,
+                TensorOp::permute(
+                    x_fp16.view(.., .., .., ..)?,
+                    output_fp16.view(.., .., .., ..)?,
+                    axes,
+                )?,
+            ]);
+            context.submit(context.encode(&ops));
+
+            let output = output.back_in_place().to_vec();
+            let output_fp16 = output_fp16.back_in_place().to_vec();
+            for (index, (&y, &y_fp16)) in output.iter().zip_eq(&output_fp16).enumerate() {
+                let coord = [index % c, (index / c) % t, index / (c * t)];
+                let mut source = [0; 3];
+                for (i, &axis) in axes.iter().enumerate() {
+                    source[axis] = coord[i];
+                }
+                let [i, j, k] = source;
+                let expected = x[(k * T + j) * C + i];
+                assert_eq!(y, expected, "{axes:?} at {coord:?}");
+                assert!(
+                    (y_fp16.to_f32() - expected).abs() < 1.0e-3,
+                    "{axes:?} at {coord:?}"
+                );
+            }
+        }
+
+        // a permutation of a view, written into a view
+        let output: TensorGpu<f32, _> = context.zeros([T, C + 4, 1, 1]);
+        let ops = TensorOp::permute(
+            x_dev.view(.., .., 1, ..)?,
+            output.view(.., 4.., .., ..)?,
+            [1, 0, 2],
+        )?;
+        context.submit(context.encode(&ops));
+        let output = output.back_in_place().to_vec();
+        for (index, &y) in output.iter().enumerate() {
+            let (j, i) = (index % T, index / T);
+            let expected = match i {
+                i if i < 4 => 0.0,
+                i => x[(T + j) * C + i - 4],
+            };
+            assert_eq!(y, expected);
+        }
+
+        assert!(matches!(
+            TensorOp::permute(
+                x_dev.view(.., .., .., ..)?,
+                x_dev.view(.., .., .., ..)?,
+                [0, 0, 2]
+            ),
+            Err(TensorError::Permute(_))
+        ));
+        Ok(())
+    }
 }
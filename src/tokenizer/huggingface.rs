@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use super::{Tokenizer, TokenizerError};
+
+/// The parts of a Hugging Face `tokenizer.json` needed to rebuild its vocabulary.
+#[derive(Debug, Deserialize)]
+struct TokenizerJson {
+    model: ModelJson,
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    #[serde(default)]
+    pre_tokenizer: Value,
+    #[serde(default)]
+    decoder: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ModelJson {
+    #[serde(rename = "BPE")]
+    Bpe {
+        vocab: HashMap<String, u32>,
+        #[serde(default)]
+        byte_fallback: bool,
+    },
+    Unigram {
+        vocab: Vec<(String, f64)>,
+    },
+    WordLevel {
+        vocab: HashMap<String, u32>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddedToken {
+    id: u32,
+    content: String,
+}
+
+/// Check if a component of the pipeline, or one in a sequence of them, is of type `name`.
+fn has_type(value: &Value, name: &str) -> bool {
+    match value {
+        Value::Object(map) => {
+            map.get("type").and_then(Value::as_str) == Some(name)
+                || map.values().any(|value| has_type(value, name))
+        }
+        Value::Array(values) => values.iter().any(|value| has_type(value, name)),
+        _ => false,
+    }
+}
+
+/// The inverse of the GPT-2 mapping from bytes to printable characters used by byte-level BPE.
+fn byte_level_chars() -> HashMap<char, u8> {
+    let printable = |x: u8| matches!(x, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+    let mut next = 256;
+    (0..=255u8)
+        .map(|byte| match printable(byte) {
+            true => (char::from(byte), byte),
+            false => {
+                let char = char::from_u32(next).expect("valid char");
+                next += 1;
+                (char, byte)
+            }
+        })
+        .collect()
+}
+
+/// Parse the byte of a byte-fallback token like `<0x0A>`.
+fn fallback_byte(text: &str) -> Option<u8> {
+    text.strip_prefix("<0x")
+        .and_then(|x| x.strip_suffix('>'))
+        .filter(|x| x.len() == 2)
+        .and_then(|x| u8::from_str_radix(x, 16).ok())
+}
+
+#[wasm_bindgen]
+impl Tokenizer {
+    /// Build from a Hugging Face `tokenizer.json` with a BPE, Unigram or WordLevel model.
+    ///
+    /// Token texts are turned back into bytes: byte-level (GPT-2 style) characters are mapped back to their bytes,
+    /// `▁` becomes a space in SentencePiece-style vocabularies, and byte-fallback tokens like `<0x0A>` become their byte.
+    /// Decoding is exact, while encoding uses the greedy longest match of this tokenizer instead of BPE merges or Unigram scores,
+    /// so it may split text differently from the original (with the same text).
+    #[wasm_bindgen(js_name = fromHuggingFace)]
+    pub fn from_hugging_face(json: &str) -> Result<Tokenizer, TokenizerError> {
+        let json: TokenizerJson =
+            serde_json::from_str(json).map_err(TokenizerError::FailedToParseVocabulary)?;
+        let byte_level =
+            has_type(&json.pre_tokenizer, "ByteLevel") || has_type(&json.decoder, "ByteLevel");
+
+        let (vocab, byte_fallback): (Vec<(String, u32)>, bool) = match json.model {
+            ModelJson::Bpe {
+                vocab,
+                byte_fallback,
+            } => (vocab.into_iter().collect(), byte_fallback),
+            ModelJson::Unigram { vocab } => {
+                let vocab = vocab
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, (text, _))| (text, id as u32))
+                    .collect();
+                (vocab, true)
+            }
+            ModelJson::WordLevel { vocab } => (vocab.into_iter().collect(), false),
+            ModelJson::Other => return Err(TokenizerError::Unsupported("model type".into())),
+        };
+
+        let chars = byte_level_chars();
+        let to_bytes = |text: &str| -> Vec<u8> {
+            match byte_level {
+                true => text
+                    .chars()
+                    .flat_map(|char| match chars.get(&char) {
+                        Some(&byte) => vec![byte],
+                        None => char.to_string().into_bytes(),
+                    })
+                    .collect(),
+                false => text.replace('▁', " ").into_bytes(),
+            }
+        };
+        let to_id =
+            |id: u32| u16::try_from(id).map_err(|_| TokenizerError::TooManyTokens(id as usize + 1));
+
+        // byte-fallback tokens go first, so that regular tokens of the same bytes are used for encoding
+        let mut fallbacks = vec![];
+        let mut list = vec![];
+        for (text, id) in vocab {
+            match fallback_byte(&text).filter(|_| byte_fallback) {
+                Some(byte) => fallbacks.push((vec![byte], to_id(id)?)),
+                None => list.push((to_bytes(&text), to_id(id)?)),
+            }
+        }
+        // sort for a deterministic order among tokens of the same bytes
+        fallbacks.sort_by_key(|&(_, id)| id);
+        list.sort_by_key(|&(_, id)| id);
+        let added = json
+            .added_tokens
+            .into_iter()
+            .map(|token| Ok((token.content.into_bytes(), to_id(token.id)?)))
+            .collect::<Result<Vec<_>, TokenizerError>>()?;
+
+        let list = [fallbacks, list, added].concat();
+        Ok(Self::from_patterns(list))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::{Tokenize, Tokenizer, TokenizerError};
+
+    #[test]
+    fn test_byte_level_bpe() -> Result<(), TokenizerError> {
+        // "Ġ" is the space, and "Ċ" the newline, in the byte-level alphabet
+        let json = r#"{
+            "added_tokens": [{"id": 7, "content": "<|endoftext|>", "special": true}],
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false},
+            "decoder": {"type": "ByteLevel"},
+            "model": {
+                "type": "BPE",
+                "vocab": {"a": 0, "b": 1, "ab": 2, "Ġ": 3, "Ġab": 4, "Ċ": 5, "Ã©": 6, "<|endoftext|>": 7},
+                "merges": ["a b", "Ġ ab"]
+            }
+        }"#;
+        let tokenizer = Tokenizer::from_hugging_face(json)?;
+        assert_eq!(tokenizer.vocab_size(), 8);
+        assert_eq!(tokenizer.encode(b" ab\n")?, [4, 5]);
+        assert_eq!(tokenizer.encode("é<|endoftext|>".as_bytes())?, [6, 7]);
+        assert_eq!(tokenizer.decode(&[4, 0, 3, 6])?, " aba é".as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sentencepiece_style() -> Result<(), TokenizerError> {
+        let json = r#"{
+            "decoder": {"type": "Sequence", "decoders": [
+                {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                {"type": "ByteFallback"}
+            ]},
+            "model": {
+                "type": "Unigram",
+                "vocab": [["<unk>", 0.0], ["<0x20>", 0.0], ["<0x41>", 0.0], ["▁", -1.0], ["▁hi", -2.0], ["i", -3.0]]
+            }
+        }"#;
+        let tokenizer = Tokenizer::from_hugging_face(json)?;
+        // the space is encoded as "▁" rather than its byte-fallback token
+        assert_eq!(tokenizer.encode(b"A hi i")?, [2, 4, 3, 5]);
+        assert_eq!(tokenizer.decode(&[2, 1, 4])?, b"A  hi");
+
+        let json = r#"{"model": {"type": "WordPiece", "vocab": {}}}"#;
+        assert!(matches!(
+            Tokenizer::from_hugging_face(json),
+            Err(TokenizerError::Unsupported(_))
+        ));
+        Ok(())
+    }
+}
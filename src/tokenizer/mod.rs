@@ -0,0 +1,458 @@
+use ahash::{AHashMap as HashMap, AHashSet as HashSet};
+use derive_getters::Getters;
+use std::{collections::BTreeMap, ops::Range};
+use thiserror::Error;
+use wasm_bindgen::prelude::wasm_bindgen;
+use web_rwkv_derive::JsError;
+
+pub mod huggingface;
+pub mod sentencepiece;
+
+#[derive(Debug, Error, JsError)]
+pub enum TokenizerError {
+    #[error("failed to parse vocabulary: {0}")]
+    FailedToParseVocabulary(serde_json::Error),
+    #[error("invalid vocabulary entry at line {0}")]
+    InvalidVocabularyEntry(usize),
+    #[error("too many tokens in vocabulary: {0}")]
+    TooManyTokens(usize),
+    #[error("no matching token found")]
+    NoMatchingTokenFound,
+    #[error("out of range token: {0}")]
+    OutOfRangeToken(u16),
+    #[error("unsupported tokenizer: {0}")]
+    Unsupported(String),
+}
+
+/// Converts between bytes and tokens, so that other tokenizers (e.g., [`sentencepiece::SentencePiece`],
+/// or a wrapper around HuggingFace `tokenizers`) can be used in place of the world vocabulary.
+pub trait Tokenize {
+    fn encode(&self, input: &[u8]) -> Result<Vec<u16>, TokenizerError>;
+    /// Decode tokens into bytes and append them to `output`.
+    fn decode_into(&self, tokens: &[u16], output: &mut Vec<u8>) -> Result<(), TokenizerError>;
+    /// Number of token ids, i.e., one plus the largest id.
+    fn vocab_size(&self) -> usize;
+
+    fn decode(&self, tokens: &[u16]) -> Result<Vec<u8>, TokenizerError> {
+        let mut output = Vec::with_capacity(tokens.len());
+        self.decode_into(tokens, &mut output)?;
+        Ok(output)
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Getters)]
+pub struct Tokenizer {
+    first_bytes_to_lengths: Vec<Box<[u16]>>,
+    bytes_to_token_index: HashMap<Vec<u8>, u16>,
+    token_index_to_bytes: Vec<Vec<u8>>,
+    max_token_length: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum StrOrBytes {
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+#[wasm_bindgen]
+impl Tokenizer {
+    #[wasm_bindgen(constructor)]
+    pub fn new(vocab: &str) -> Result<Tokenizer, TokenizerError> {
+        let map: BTreeMap<u16, StrOrBytes> =
+            serde_json::from_str(vocab).map_err(TokenizerError::FailedToParseVocabulary)?;
+
+        let list: Vec<(Vec<u8>, u16)> = map
+            .into_iter()
+            .map(|(token, pattern)| {
+                let pattern = match pattern {
+                    StrOrBytes::Str(string) => string.into_bytes(),
+                    StrOrBytes::Bytes(bytes) => bytes,
+                };
+                (pattern, token)
+            })
+            .collect();
+
+        Ok(Self::from_patterns(list))
+    }
+
+    pub fn encode(&self, input: &[u8]) -> Result<Vec<u16>, TokenizerError> {
+        let mut output = Vec::new();
+        self.encode_into(input, &mut output)?;
+        Ok(output)
+    }
+
+    pub fn decode(&self, tokens: &[u16]) -> Result<Vec<u8>, TokenizerError> {
+        let mut output = Vec::with_capacity(tokens.len());
+        self.decode_into(tokens, &mut output)?;
+        Ok(output)
+    }
+}
+
+impl Tokenizer {
+    /// Build from the bytes of each token. If several tokens have the same bytes, the last one is used for encoding.
+    fn from_patterns(list: Vec<(Vec<u8>, u16)>) -> Self {
+        let mut first_bytes_to_len = Vec::new();
+        first_bytes_to_len.resize(u16::MAX as usize, 2);
+
+        let mut first_bytes_to_lengths = Vec::new();
+        first_bytes_to_lengths.resize(u16::MAX as usize, {
+            let mut set = HashSet::new();
+            set.insert(1);
+            set
+        });
+
+        let mut token_index_to_bytes = Vec::new();
+        token_index_to_bytes.resize_with(u16::MAX as usize, Vec::new);
+
+        let mut bytes_to_token_index = HashMap::new();
+        let mut max_token_length = 1;
+        for (token_bytes, token_index) in list {
+            max_token_length = max_token_length.max(token_bytes.len());
+            if token_bytes.len() >= 2 {
+                let key = u16::from_ne_bytes([token_bytes[0], token_bytes[1]]) as usize;
+                let max_length = &mut first_bytes_to_len[key];
+                if token_bytes.len() > *max_length {
+                    *max_length = token_bytes.len();
+                }
+
+                first_bytes_to_lengths[key].insert(token_bytes.len() as u16);
+            }
+
+            bytes_to_token_index.insert(token_bytes.clone(), token_index);
+            token_index_to_bytes[token_index as usize] = token_bytes;
+        }
+
+        let first_bytes_to_lengths: Vec<Box<[_]>> = first_bytes_to_lengths
+            .into_iter()
+            .map(|inner| {
+                let mut inner: Vec<_> = inner.into_iter().collect();
+                inner.sort_unstable_by_key(|l| !*l);
+                inner.into_boxed_slice()
+            })
+            .collect();
+
+        Self {
+            first_bytes_to_lengths,
+            bytes_to_token_index,
+            token_index_to_bytes,
+            max_token_length,
+        }
+    }
+
+    pub fn encode_into(
+        &self,
+        mut input: &[u8],
+        output: &mut Vec<u16>,
+    ) -> Result<(), TokenizerError> {
+        'next_token: while !input.is_empty() {
+            let lengths = if input.len() >= 2 {
+                let key = u16::from_ne_bytes([input[0], input[1]]) as usize;
+                &self.first_bytes_to_lengths[key][..]
+            } else {
+                &[1][..]
+            };
+
+            for &length in lengths {
+                let length = length as usize;
+                if length > input.len() {
+                    continue;
+                }
+
+                if let Some(&token_index) = self.bytes_to_token_index.get(&input[..length]) {
+                    output.push(token_index);
+                    input = &input[length..];
+                    continue 'next_token;
+                }
+            }
+
+            return Err(TokenizerError::NoMatchingTokenFound);
+        }
+
+        Ok(())
+    }
+
+    /// Encode `input` as if appended to the text of `tokens`, re-encoding only the last tokens that may merge with it,
+    /// and return the number of tokens kept; the tokens from there on are new.
+    ///
+    /// The result is the same as encoding the whole text if `tokens` is itself how the text is encoded.
+    /// On error, `tokens` is left untouched.
+    pub fn encode_incremental(
+        &self,
+        tokens: &mut Vec<u16>,
+        input: &[u8],
+    ) -> Result<usize, TokenizerError> {
+        // a token stays the same if the longest token starting there would still end before the new text
+        let mut keep = tokens.len();
+        let mut tail = 0;
+        for &token in tokens.iter().rev() {
+            let bytes = self
+                .token_index_to_bytes
+                .get(token as usize)
+                .ok_or(TokenizerError::OutOfRangeToken(token))?;
+            if tail + bytes.len() > self.max_token_length {
+                break;
+            }
+            tail += bytes.len();
+            keep -= 1;
+        }
+
+        let mut text = self.decode(&tokens[keep..])?;
+        text.extend_from_slice(input);
+        let mut output = vec![];
+        self.encode_into(&text, &mut output)?;
+
+        tokens.truncate(keep);
+        tokens.append(&mut output);
+        Ok(keep)
+    }
+
+    /// Decode tokens into text, together with the byte range of the text that each token contributes.
+    /// See [`TextAssembler`] for how characters split across tokens are attributed.
+    pub fn decode_aligned(
+        &self,
+        tokens: &[u16],
+    ) -> Result<(String, Vec<Range<usize>>), TokenizerError> {
+        let mut assembler = TextAssembler::default();
+        for &token in tokens {
+            assembler.push(self, token)?;
+        }
+        assembler.finish();
+        Ok((assembler.text, assembler.ranges))
+    }
+
+    pub fn decode_into(&self, tokens: &[u16], output: &mut Vec<u8>) -> Result<(), TokenizerError> {
+        for &token in tokens {
+            let bytes = self
+                .token_index_to_bytes
+                .get(token as usize)
+                .ok_or(TokenizerError::OutOfRangeToken(token))?;
+
+            output.extend_from_slice(bytes);
+        }
+
+        Ok(())
+    }
+}
+
+impl Tokenize for Tokenizer {
+    fn encode(&self, input: &[u8]) -> Result<Vec<u16>, TokenizerError> {
+        Tokenizer::encode(self, input)
+    }
+
+    fn decode_into(&self, tokens: &[u16], output: &mut Vec<u8>) -> Result<(), TokenizerError> {
+        Tokenizer::decode_into(self, tokens, output)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.token_index_to_bytes
+            .iter()
+            .rposition(|bytes| !bytes.is_empty())
+            .map_or(0, |index| index + 1)
+    }
+}
+
+/// Move the complete characters at the front of `pending` into `text`, replacing invalid bytes by `U+FFFD`,
+/// and leave the bytes of an incomplete character at the end.
+fn drain_utf8(pending: &mut Vec<u8>, text: &mut String) {
+    loop {
+        match std::str::from_utf8(pending) {
+            Ok(valid) => {
+                text.push_str(valid);
+                pending.clear();
+                return;
+            }
+            Err(err) => {
+                let valid = err.valid_up_to();
+                // SAFETY: the bytes are just checked to be valid
+                text.push_str(unsafe { std::str::from_utf8_unchecked(&pending[..valid]) });
+                match err.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        pending.drain(..valid + len);
+                    }
+                    None => {
+                        pending.drain(..valid);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Assembles generated tokens into text incrementally, recording the byte range of the text that each token contributes.
+///
+/// A token may end in the middle of a multi-byte UTF-8 character. Such a character is attributed to the token that completes it,
+/// so the ranges always fall on character boundaries, and a token may contribute an empty range.
+/// Invalid byte sequences are replaced by `U+FFFD`.
+#[derive(Debug, Default, Clone)]
+pub struct TextAssembler {
+    text: String,
+    ranges: Vec<Range<usize>>,
+    pending: Vec<u8>,
+}
+
+impl TextAssembler {
+    /// The text assembled so far, excluding bytes of incomplete characters.
+    #[inline]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The byte range of the text contributed by each token pushed so far.
+    #[inline]
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges
+    }
+
+    /// Append a token, and return the byte range of the text it contributes.
+    pub fn push(
+        &mut self,
+        tokenizer: &impl Tokenize,
+        token: u16,
+    ) -> Result<Range<usize>, TokenizerError> {
+        tokenizer.decode_into(&[token], &mut self.pending)?;
+
+        let start = self.text.len();
+        drain_utf8(&mut self.pending, &mut self.text);
+
+        let range = start..self.text.len();
+        self.ranges.push(range.clone());
+        Ok(range)
+    }
+
+    /// Flush the bytes of an incomplete character at the end as `U+FFFD`, attributed to the last token.
+    pub fn finish(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        self.pending.clear();
+        self.text.push(char::REPLACEMENT_CHARACTER);
+        if let Some(range) = self.ranges.last_mut() {
+            range.end = self.text.len();
+        }
+    }
+}
+
+/// Decodes generated tokens into text pieces as they come, holding back the bytes of a character split across tokens
+/// until it is complete, so that every piece is valid UTF-8. Invalid byte sequences are replaced by `U+FFFD`.
+#[derive(Debug, Default, Clone)]
+pub struct DecodeStream {
+    pending: Vec<u8>,
+}
+
+impl DecodeStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the next token, and return the text it completes, if any.
+    pub fn step(
+        &mut self,
+        tokenizer: &impl Tokenize,
+        token: u16,
+    ) -> Result<Option<String>, TokenizerError> {
+        tokenizer.decode_into(&[token], &mut self.pending)?;
+        let mut text = String::new();
+        drain_utf8(&mut self.pending, &mut text);
+        Ok((!text.is_empty()).then_some(text))
+    }
+
+    /// Check if bytes of an incomplete character are held back.
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Flush the bytes of an incomplete character as `U+FFFD`, e.g., when generation ends.
+    pub fn finish(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.pending.clear();
+        Some(char::REPLACEMENT_CHARACTER.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeStream, Tokenizer};
+
+    #[test]
+    fn test_decode_aligned() {
+        // "你" is `[228, 189, 160]` in UTF-8, split into two tokens here
+        let vocab = r#"{"1": "a", "2": [228], "3": [189, 160], "4": " b", "5": [255]}"#;
+        let tokenizer = Tokenizer::new(vocab).unwrap();
+
+        let (text, ranges) = tokenizer.decode_aligned(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(text, "a你 b");
+        assert_eq!(ranges, vec![0..1, 1..1, 1..4, 4..6]);
+
+        let (text, ranges) = tokenizer.decode_aligned(&[1, 5, 1]).unwrap();
+        assert_eq!(text, "a\u{fffd}a");
+        assert_eq!(ranges, vec![0..1, 1..4, 4..5]);
+
+        let (text, ranges) = tokenizer.decode_aligned(&[4, 2]).unwrap();
+        assert_eq!(text, " b\u{fffd}");
+        assert_eq!(ranges, vec![0..2, 2..5]);
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let vocab = r#"{"1": "a", "2": [228], "3": [189, 160], "4": " b", "5": [255]}"#;
+        let tokenizer = Tokenizer::new(vocab).unwrap();
+
+        let mut stream = DecodeStream::new();
+        let pieces: Vec<_> = [1, 2, 3, 4, 5, 1, 2]
+            .into_iter()
+            .map(|token| stream.step(&tokenizer, token).unwrap())
+            .collect();
+        assert_eq!(
+            pieces,
+            [
+                Some("a"),
+                None,
+                Some("你"),
+                Some(" b"),
+                Some("\u{fffd}"),
+                Some("a"),
+                None
+            ]
+            .map(|piece| piece.map(String::from))
+        );
+        assert!(stream.is_pending());
+        assert_eq!(stream.finish(), Some("\u{fffd}".into()));
+        assert_eq!(stream.finish(), None);
+        assert!(stream.step(&tokenizer, u16::MAX).is_err());
+    }
+
+    #[test]
+    fn test_encode_incremental() {
+        let vocab = r#"{"1": "a", "2": "b", "3": "c", "4": "ab", "5": "abc", "6": "ca", "7": " "}"#;
+        let tokenizer = Tokenizer::new(vocab).unwrap();
+
+        // "ab" + "c" merges into "abc"
+        let mut tokens = tokenizer.encode(b"a a a").unwrap();
+        assert_eq!(tokenizer.encode_incremental(&mut tokens, b"b").unwrap(), 2);
+        assert_eq!(tokens, [1, 7, 1, 7, 4]);
+        assert_eq!(tokenizer.encode_incremental(&mut tokens, b"c").unwrap(), 3);
+        assert_eq!(tokens, [1, 7, 1, 7, 5]);
+
+        // appending in any split gives the same tokens as encoding the whole text
+        let text = b"abcab cabca bcabc";
+        for split in 0..=text.len() {
+            let mut tokens = tokenizer.encode(&text[..split]).unwrap();
+            let keep = tokenizer
+                .encode_incremental(&mut tokens, &text[split..])
+                .unwrap();
+            assert_eq!(tokens, tokenizer.encode(text).unwrap());
+            assert!(keep <= tokens.len());
+        }
+
+        let mut tokens = vec![1];
+        assert!(tokenizer.encode_incremental(&mut tokens, b"d").is_err());
+        assert_eq!(tokens, [1]);
+    }
+}
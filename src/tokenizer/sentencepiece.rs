@@ -0,0 +1,239 @@
+use ahash::AHashMap as HashMap;
+
+use super::{Tokenize, TokenizerError};
+
+/// The meta symbol that SentencePiece replaces spaces with.
+const SPACE: char = '\u{2581}';
+/// How much worse than the worst piece an unknown character scores, as in SentencePiece.
+const UNKNOWN_PENALTY: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceKind {
+    Normal,
+    Byte(u8),
+    Unknown,
+    Control,
+}
+
+#[derive(Debug, Clone)]
+struct Piece {
+    text: String,
+    kind: PieceKind,
+}
+
+/// A SentencePiece unigram model, loaded from the `.vocab` file (one `piece<TAB>score` per line) that comes with the `.model` file.
+///
+/// Text is normalized by replacing spaces with `▁` (and adding one in front), then split into the pieces with the highest total score.
+/// Characters not covered by any piece fall back to byte pieces (`<0x..>`) if the vocabulary has them, and to `<unk>` otherwise.
+///
+/// Decoding keeps the leading space, so that tokens decode the same one at a time as all together.
+#[derive(Debug, Clone)]
+pub struct SentencePiece {
+    pieces: Vec<Piece>,
+    index: HashMap<String, (u16, f32)>,
+    bytes: [Option<u16>; 256],
+    unknown: Option<u16>,
+    /// Length of the longest piece, in chars.
+    max_len: usize,
+    min_score: f32,
+    dummy_prefix: bool,
+}
+
+impl SentencePiece {
+    pub fn new(vocab: &str) -> Result<Self, TokenizerError> {
+        let mut pieces = vec![];
+        let mut index = HashMap::new();
+        let mut bytes = [None; 256];
+        let mut unknown = None;
+        let mut max_len = 1;
+        let mut min_score = 0.0f32;
+
+        for (line, entry) in vocab.lines().enumerate() {
+            if entry.is_empty() {
+                continue;
+            }
+            let (text, score) = entry
+                .split_once('\t')
+                .ok_or(TokenizerError::InvalidVocabularyEntry(line + 1))?;
+            let score: f32 = score
+                .trim()
+                .parse()
+                .map_err(|_| TokenizerError::InvalidVocabularyEntry(line + 1))?;
+            let id = u16::try_from(pieces.len())
+                .map_err(|_| TokenizerError::TooManyTokens(pieces.len() + 1))?;
+
+            let byte = text
+                .strip_prefix("<0x")
+                .and_then(|x| x.strip_suffix('>'))
+                .filter(|x| x.len() == 2)
+                .and_then(|x| u8::from_str_radix(x, 16).ok());
+            let kind = match (text, byte) {
+                (_, Some(byte)) => PieceKind::Byte(byte),
+                ("<unk>", _) => PieceKind::Unknown,
+                ("<s>" | "</s>" | "<pad>", _) => PieceKind::Control,
+                _ => PieceKind::Normal,
+            };
+            match kind {
+                PieceKind::Normal => {
+                    index.insert(text.to_string(), (id, score));
+                    max_len = max_len.max(text.chars().count());
+                    min_score = min_score.min(score);
+                }
+                PieceKind::Byte(byte) => bytes[byte as usize] = Some(id),
+                PieceKind::Unknown => unknown = Some(id),
+                PieceKind::Control => {}
+            }
+
+            let text = text.to_string();
+            pieces.push(Piece { text, kind });
+        }
+
+        Ok(Self {
+            pieces,
+            index,
+            bytes,
+            unknown,
+            max_len,
+            min_score,
+            dummy_prefix: true,
+        })
+    }
+
+    /// Whether to add a space in front of the text before encoding. This is on by default, as in SentencePiece.
+    pub fn dummy_prefix(mut self, value: bool) -> Self {
+        self.dummy_prefix = value;
+        self
+    }
+
+    /// Tokens for a character that no piece covers.
+    fn fallback(&self, piece: &str, output: &mut Vec<u16>) -> Result<(), TokenizerError> {
+        let bytes: Option<Vec<_>> = piece.bytes().map(|x| self.bytes[x as usize]).collect();
+        match (bytes, self.unknown) {
+            (Some(bytes), _) => output.extend(bytes),
+            (None, Some(unknown)) => output.push(unknown),
+            (None, None) => return Err(TokenizerError::NoMatchingTokenFound),
+        }
+        Ok(())
+    }
+}
+
+impl Tokenize for SentencePiece {
+    fn encode(&self, input: &[u8]) -> Result<Vec<u16>, TokenizerError> {
+        let input = String::from_utf8_lossy(input);
+        if input.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut text = String::with_capacity(input.len() + SPACE.len_utf8());
+        if self.dummy_prefix {
+            text.push(SPACE);
+        }
+        text.extend(input.chars().map(|x| if x == ' ' { SPACE } else { x }));
+
+        let bounds: Vec<usize> = text
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain([text.len()])
+            .collect();
+        let len = bounds.len() - 1;
+
+        // best score of the text up to each char, the char where its last piece starts, and the piece (`None` for fallback)
+        let mut best = vec![(f32::NEG_INFINITY, 0, None); len + 1];
+        best[0].0 = 0.0;
+        for start in 0..len {
+            let (score, _, _) = best[start];
+            if score == f32::NEG_INFINITY {
+                continue;
+            }
+
+            let fallback = score + self.min_score - UNKNOWN_PENALTY;
+            if fallback > best[start + 1].0 {
+                best[start + 1] = (fallback, start, None);
+            }
+
+            for end in start + 1..=len.min(start + self.max_len) {
+                let piece = &text[bounds[start]..bounds[end]];
+                if let Some(&(id, x)) = self.index.get(piece) {
+                    if score + x > best[end].0 {
+                        best[end] = (score + x, start, Some(id));
+                    }
+                }
+            }
+        }
+
+        let mut segments = vec![];
+        let mut end = len;
+        while end > 0 {
+            let (_, start, id) = best[end];
+            segments.push((start, end, id));
+            end = start;
+        }
+
+        let mut output = Vec::with_capacity(segments.len());
+        for (start, end, id) in segments.into_iter().rev() {
+            match id {
+                Some(id) => output.push(id),
+                None => self.fallback(&text[bounds[start]..bounds[end]], &mut output)?,
+            }
+        }
+        Ok(output)
+    }
+
+    fn decode_into(&self, tokens: &[u16], output: &mut Vec<u8>) -> Result<(), TokenizerError> {
+        for &token in tokens {
+            let piece = self
+                .pieces
+                .get(token as usize)
+                .ok_or(TokenizerError::OutOfRangeToken(token))?;
+            match piece.kind {
+                PieceKind::Normal => {
+                    let text = piece.text.replace(SPACE, " ");
+                    output.extend_from_slice(text.as_bytes());
+                }
+                PieceKind::Byte(byte) => output.push(byte),
+                PieceKind::Unknown => output.extend_from_slice(" \u{2047} ".as_bytes()),
+                PieceKind::Control => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SentencePiece;
+    use crate::tokenizer::{TextAssembler, Tokenize};
+
+    const VOCAB: &str = "<unk>\t0\n<s>\t0\n</s>\t0\n<0xE4>\t0\n<0xBD>\t0\n<0xA0>\t0\n\
+        \u{2581}\t-2\n\u{2581}he\t-3\n\u{2581}hello\t-1\nllo\t-2\n\u{2581}world\t-1.5\n";
+
+    #[test]
+    fn test_sentencepiece() {
+        let tokenizer = SentencePiece::new(VOCAB).unwrap();
+        assert_eq!(tokenizer.vocab_size(), 11);
+
+        // "▁hello" scores higher than "▁he" + "llo"
+        let tokens = tokenizer.encode(b"hello world").unwrap();
+        assert_eq!(tokens, vec![8, 10]);
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), b" hello world");
+
+        // "你" falls back to bytes, and "x" to `<unk>`
+        let tokens = tokenizer.encode("hello 你".as_bytes()).unwrap();
+        assert_eq!(tokens, vec![8, 6, 3, 4, 5]);
+        assert_eq!(tokenizer.encode(b"hex").unwrap(), vec![7, 0]);
+
+        let mut assembler = TextAssembler::default();
+        for token in tokens {
+            assembler.push(&tokenizer, token).unwrap();
+        }
+        assert_eq!(assembler.text(), " hello 你");
+        assert_eq!(assembler.ranges(), &[0..6, 6..7, 7..7, 7..7, 7..10]);
+
+        let tokenizer = tokenizer.dummy_prefix(false);
+        assert_eq!(tokenizer.encode(b"hello").unwrap(), vec![0, 0, 9]);
+        assert!(SentencePiece::new("a\tb\n").is_err());
+    }
+}
@@ -0,0 +1,597 @@
+//! Bindings of the whole pipeline for JavaScript, through `wasm-bindgen`, to run models in browsers on WebGPU.
+//!
+//! ```js
+//! const info = modelInfo(data);
+//! const context = await new ContextBuilder().autoLimits(info).build();
+//! const runtime = await new ModelBuilder(context, data).build();
+//! const tokenizer = new Tokenizer(vocab);
+//! const text = await runtime.generateText(tokenizer, prompt, new GenerateOption(), (piece) => print(piece));
+//! ```
+//!
+//! The scheduler of [`JobRuntime`](crate::runtime::JobRuntime) needs tokio's threads, so the [`JsRuntime`] here runs
+//! its steps one after another on the browser's event loop instead. Calls on the same runtime must not overlap.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use half::f16;
+use js_sys::{Float32Array, Function, Promise, Uint16Array, Uint8Array};
+use safetensors::SafeTensors;
+use wasm_bindgen::{prelude::wasm_bindgen, JsError, JsValue};
+use wasm_bindgen_futures::future_to_promise;
+use wgpu::{Instance, PowerPreference};
+
+use crate::{
+    context::{Context, ContextBuilder, InstanceExt},
+    runtime::{
+        infer::{
+            Greedy, GreedyOutput, InferChunk, InferInfo, InferInput, InferInputBatch, InferOption,
+            InferOutput, SampleOption,
+        },
+        loader::Loader,
+        model::{
+            Build, ContextAutoLimits, EmbedDevice, ModelBuilder, ModelInfo, ModelVersion, Quant,
+            State,
+        },
+        sampler::SeededSampler,
+        snapshot::InferenceSessionSnapshot,
+        transfer::StateTransfer,
+        v4, v5, v6, Job, JobBuilder, JobInput,
+    },
+    tensor::{TensorCpu, TensorInit, TensorShape},
+    tokenizer::{DecodeStream, Tokenizer},
+};
+
+fn js_error(err: impl Display) -> JsError {
+    JsError::new(&err.to_string())
+}
+
+fn js_value(err: impl Display) -> JsValue {
+    js_error(err).into()
+}
+
+/// Call a JS callback with `arg`, which asks to stop by returning `false`.
+fn call(callback: &Function, arg: JsValue) -> Result<bool> {
+    let result = callback
+        .call1(&JsValue::NULL, &arg)
+        .map_err(|err| anyhow!("callback failed: {err:?}"))?;
+    Ok(result != JsValue::FALSE)
+}
+
+/// Read the info of a model from the bytes of its SafeTensors file.
+#[wasm_bindgen(js_name = modelInfo)]
+pub fn model_info(data: &[u8]) -> Result<ModelInfo, JsError> {
+    let model = SafeTensors::deserialize(data).map_err(js_error)?;
+    Loader::info(&model).map_err(js_error)
+}
+
+#[wasm_bindgen(js_name = ContextBuilder)]
+#[derive(Debug, Clone, Default)]
+pub struct JsContextBuilder {
+    low_power: bool,
+    info: Option<ModelInfo>,
+}
+
+#[wasm_bindgen(js_class = ContextBuilder)]
+impl JsContextBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Request the low-power adapter, e.g., the integrated GPU, rather than the high-performance one.
+    #[wasm_bindgen(js_name = lowPower)]
+    pub fn low_power(mut self, value: bool) -> Self {
+        self.low_power = value;
+        self
+    }
+
+    /// Request limits large enough for the buffers of models of `info`.
+    #[wasm_bindgen(js_name = autoLimits)]
+    pub fn auto_limits(mut self, info: &ModelInfo) -> Self {
+        self.info = Some(info.clone());
+        self
+    }
+
+    pub async fn build(self) -> Result<JsContext, JsError> {
+        let power_preference = match self.low_power {
+            true => PowerPreference::LowPower,
+            false => PowerPreference::HighPerformance,
+        };
+        let instance = Instance::default();
+        let adapter = instance.adapter(power_preference).await?;
+        let builder = ContextBuilder::new(adapter);
+        let builder = match &self.info {
+            Some(info) => builder.auto_limits(info),
+            None => builder,
+        };
+        let context = builder.build().await?;
+        Ok(JsContext(context))
+    }
+}
+
+#[wasm_bindgen(js_name = Context)]
+#[derive(Debug, Clone)]
+pub struct JsContext(Context);
+
+impl JsContext {
+    pub fn new(context: Context) -> Self {
+        Self(context)
+    }
+}
+
+#[wasm_bindgen(js_name = ModelBuilder)]
+#[derive(Debug, Clone)]
+pub struct JsModelBuilder {
+    context: Context,
+    data: Vec<u8>,
+    quant: HashMap<usize, Quant>,
+    embed_device: EmbedDevice,
+}
+
+#[wasm_bindgen(js_class = ModelBuilder)]
+impl JsModelBuilder {
+    /// Load a model from the bytes of its SafeTensors file, e.g., fetched into a `Uint8Array`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(context: &JsContext, data: Vec<u8>) -> Self {
+        Self {
+            context: context.0.clone(),
+            data,
+            quant: HashMap::new(),
+            embed_device: EmbedDevice::Cpu,
+        }
+    }
+
+    /// Quantize `layer` when loading.
+    pub fn quant(mut self, layer: usize, value: Quant) -> Self {
+        self.quant.insert(layer, value);
+        self
+    }
+
+    #[wasm_bindgen(js_name = embedDevice)]
+    pub fn embed_device(mut self, value: EmbedDevice) -> Self {
+        self.embed_device = value;
+        self
+    }
+
+    pub async fn build(self) -> Result<JsRuntime, JsError> {
+        self.load().await.map_err(js_error)
+    }
+}
+
+impl JsModelBuilder {
+    async fn load(self) -> Result<JsRuntime> {
+        let model = SafeTensors::deserialize(&self.data)?;
+        let info = Loader::info(&model)?;
+        let builder = ModelBuilder::new(&self.context, model)
+            .quant(self.quant)
+            .embed_device(self.embed_device);
+        let runner = match info.version {
+            ModelVersion::V4 => {
+                let model = Build::<v4::Model>::build(builder).await?;
+                Runner::V4(v4::ModelRuntime::new(model, 1))
+            }
+            ModelVersion::V5 => {
+                let model = Build::<v5::Model>::build(builder).await?;
+                Runner::V5(v5::ModelRuntime::new(model, 1))
+            }
+            ModelVersion::V6 => {
+                let model = Build::<v6::Model>::build(builder).await?;
+                Runner::V6(v6::ModelRuntime::new(model, 1))
+            }
+        };
+        let state = runner.state();
+        Ok(JsRuntime {
+            runner,
+            state,
+            info,
+            token_chunk_size: 128,
+            sampler: Default::default(),
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Runner {
+    V4(v4::ModelRuntime<f16>),
+    V5(v5::ModelRuntime<f16>),
+    V6(v6::ModelRuntime<f16>),
+}
+
+impl Runner {
+    fn state(&self) -> Arc<dyn State + Send + Sync> {
+        use crate::runtime::model::ModelRuntime;
+        match self {
+            Runner::V4(runtime) => Arc::new(runtime.state()),
+            Runner::V5(runtime) => Arc::new(runtime.state()),
+            Runner::V6(runtime) => Arc::new(runtime.state()),
+        }
+    }
+
+    async fn step(&self, input: &mut InferInput) -> Result<InferOutput> {
+        match self {
+            Runner::V4(runtime) => step(runtime, input).await,
+            Runner::V5(runtime) => step(runtime, input).await,
+            Runner::V6(runtime) => step(runtime, input).await,
+        }
+    }
+
+    async fn step_greedy(&self, input: &mut InferInput) -> Result<GreedyOutput> {
+        match self {
+            Runner::V4(runtime) => step(&Greedy(runtime.clone()), input).await,
+            Runner::V5(runtime) => step(&Greedy(runtime.clone()), input).await,
+            Runner::V6(runtime) => step(&Greedy(runtime.clone()), input).await,
+        }
+    }
+}
+
+/// Build, run and read back the job of one step of `input`, and advance it.
+async fn step<J>(
+    builder: &impl JobBuilder<J, Info = InferInfo>,
+    input: &mut InferInput,
+) -> Result<J::Output>
+where
+    J: Job<Input = InferChunk>,
+{
+    let Some(info) = (&*input).into_iter().next() else {
+        bail!("no input");
+    };
+    let mut job = builder.build(info)?.load(&input.chunk())?;
+    job.submit();
+    let output = job.back().await?;
+    input.step();
+    Ok(output)
+}
+
+/// Options of [`JsRuntime::generate`].
+#[wasm_bindgen(js_name = GenerateOption, getter_with_clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerateOption {
+    /// Maximum number of tokens to generate.
+    #[wasm_bindgen(js_name = maxTokens)]
+    pub max_tokens: usize,
+    /// Pick the most probable token if this is zero.
+    pub temperature: f32,
+    #[wasm_bindgen(js_name = topP)]
+    pub top_p: f32,
+    pub seed: u32,
+    /// Tokens that end the generation, not included in the output.
+    pub stop: Vec<u16>,
+}
+
+impl Default for GenerateOption {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            temperature: 1.0,
+            top_p: 0.5,
+            seed: 0,
+            stop: vec![0],
+        }
+    }
+}
+
+#[wasm_bindgen(js_class = GenerateOption)]
+impl GenerateOption {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+/// A model with the state of one sequence.
+#[wasm_bindgen(js_name = Runtime)]
+#[derive(Clone)]
+pub struct JsRuntime {
+    runner: Runner,
+    state: Arc<dyn State + Send + Sync>,
+    info: ModelInfo,
+    token_chunk_size: usize,
+    /// The sampler of an imported session, kept to be exported again.
+    sampler: Arc<Mutex<Option<SeededSampler>>>,
+}
+
+#[wasm_bindgen(js_class = Runtime)]
+impl JsRuntime {
+    pub fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+
+    /// Maximum number of tokens run in one step when reading a prompt.
+    #[wasm_bindgen(js_name = setTokenChunkSize)]
+    pub fn set_token_chunk_size(&mut self, value: usize) {
+        self.token_chunk_size = value.max(1);
+    }
+
+    /// Reset the state to the initial state, e.g., to start a new conversation.
+    pub fn reset(&self) -> Result<(), JsError> {
+        self.state.load(self.state.init(), 0).map_err(js_error)
+    }
+
+    /// Read the state back, e.g., to keep it in `IndexedDB`. Resolves to a `Float32Array`.
+    #[wasm_bindgen(js_name = backState)]
+    pub fn back_state(&self) -> Promise {
+        let state = self.state.clone();
+        future_to_promise(async move {
+            let tensor = state.back(0).await.map_err(js_value)?;
+            Ok(Float32Array::from(&tensor.data()[..]).into())
+        })
+    }
+
+    /// Load a state read by [`JsRuntime::back_state`] before.
+    #[wasm_bindgen(js_name = loadState)]
+    pub fn load_state(&self, data: Vec<f32>) -> Result<(), JsError> {
+        let tensor = TensorCpu::from_data(self.state.init().shape(), data).map_err(js_error)?;
+        self.state.load(tensor, 0).map_err(js_error)
+    }
+
+    /// Encode the state and the last `tail` tokens of `history` into a session snapshot, to keep it in `IndexedDB`,
+    /// or to continue the session in a native app. Resolves to a `Uint8Array`.
+    #[wasm_bindgen(js_name = exportSession)]
+    pub fn export_session(&self, history: Vec<u16>, tail: usize) -> Promise {
+        let runtime = self.clone();
+        future_to_promise(async move {
+            let mut snapshot = InferenceSessionSnapshot::capture(runtime.state.as_ref(), 0)
+                .await
+                .map_err(js_value)?
+                .history(&history, tail);
+            snapshot.sampler = runtime.lock_sampler().clone();
+            let transfer = StateTransfer::new(runtime.info.clone());
+            let data = snapshot.encode(&transfer).map_err(js_value)?;
+            Ok(Uint8Array::from(&data[..]).into())
+        })
+    }
+
+    /// Load a session snapshot encoded by [`JsRuntime::export_session`] or by a native app, and return its history.
+    /// Generation here samples with the seed of each call, so the sampler of the snapshot is only kept to be exported again.
+    #[wasm_bindgen(js_name = importSession)]
+    pub fn import_session(&self, data: &[u8]) -> Result<Uint16Array, JsError> {
+        let transfer = StateTransfer::new(self.info.clone());
+        let snapshot = InferenceSessionSnapshot::decode(&transfer, data).map_err(js_error)?;
+        snapshot.restore(self.state.as_ref(), 0).map_err(js_error)?;
+        *self.lock_sampler() = snapshot.sampler;
+        Ok(Uint16Array::from(&snapshot.history[..]))
+    }
+
+    /// Run `tokens` from the current state. Resolves to the logits of the last token as a `Float32Array`.
+    pub fn infer(&self, tokens: Vec<u16>) -> Promise {
+        let runtime = self.clone();
+        future_to_promise(async move {
+            let logits = runtime.run(&tokens).await.map_err(js_value)?;
+            Ok(Float32Array::from(&logits[..]).into())
+        })
+    }
+
+    /// Generate from the current state after `prompt`, calling `callback` with each token as it is sampled.
+    /// Generation stops early if `callback` returns `false`. Resolves to the generated tokens as a `Uint16Array`.
+    pub fn generate(
+        &self,
+        prompt: Vec<u16>,
+        option: &GenerateOption,
+        callback: Function,
+    ) -> Promise {
+        let runtime = self.clone();
+        let option = option.clone();
+        future_to_promise(async move {
+            let tokens = runtime
+                .generate_tokens(&prompt, &option, |token| {
+                    call(&callback, JsValue::from(token))
+                })
+                .await
+                .map_err(js_value)?;
+            Ok(Uint16Array::from(&tokens[..]).into())
+        })
+    }
+
+    /// Like [`JsRuntime::generate`], but with text in and out: `callback` is called with each piece of text
+    /// as soon as it is complete UTF-8. Resolves to the whole generated text.
+    #[wasm_bindgen(js_name = generateText)]
+    pub fn generate_text(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        option: &GenerateOption,
+        callback: Function,
+    ) -> Result<Promise, JsError> {
+        let prompt = tokenizer.encode(prompt.as_bytes()).map_err(js_error)?;
+        let runtime = self.clone();
+        let tokenizer = tokenizer.clone();
+        let option = option.clone();
+        Ok(future_to_promise(async move {
+            let mut stream = DecodeStream::new();
+            let mut text = String::new();
+            runtime
+                .generate_tokens(&prompt, &option, |token| {
+                    let Some(piece) = stream.step(&tokenizer, token)? else {
+                        return Ok(true);
+                    };
+                    text.push_str(&piece);
+                    call(&callback, JsValue::from(piece))
+                })
+                .await
+                .map_err(js_value)?;
+            Ok(JsValue::from(text))
+        }))
+    }
+}
+
+impl JsRuntime {
+    fn lock_sampler(&self) -> std::sync::MutexGuard<'_, Option<SeededSampler>> {
+        self.sampler.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Run `tokens` from the current state, and return the logits of the last one.
+    async fn run(&self, tokens: &[u16]) -> Result<Vec<f32>> {
+        if tokens.is_empty() {
+            bail!("no token");
+        }
+        let batch = InferInputBatch {
+            tokens: tokens.to_vec().into(),
+            option: InferOption::Last,
+            ..Default::default()
+        };
+        let mut input = InferInput::new(vec![batch], self.token_chunk_size);
+        let mut logits = vec![];
+        while input.num_token() > 0 {
+            let InferOutput(output) = self.runner.step(&mut input).await?;
+            match output.into_iter().next() {
+                Some(output) if output.size() > 0 => logits = output.to_vec(),
+                _ => {}
+            }
+        }
+        Ok(logits)
+    }
+
+    /// Run `tokens` from the current state, and return the argmax token after the last one, reading back only it.
+    async fn run_greedy(&self, tokens: &[u16]) -> Result<u16> {
+        if tokens.is_empty() {
+            bail!("no token");
+        }
+        let batch = InferInputBatch {
+            tokens: tokens.to_vec().into(),
+            option: InferOption::Last,
+            ..Default::default()
+        };
+        let mut input = InferInput::new(vec![batch], self.token_chunk_size);
+        let mut token = None;
+        while input.num_token() > 0 {
+            let GreedyOutput(output) = self.runner.step_greedy(&mut input).await?;
+            if let Some(&last) = output.first().and_then(|tokens| tokens.last()) {
+                token = Some(last);
+            }
+        }
+        token.ok_or_else(|| anyhow!("no output"))
+    }
+
+    /// Pick the next token after `tokens`; greedy decoding reads back only the token instead of the logits.
+    async fn next_token(
+        &self,
+        tokens: &[u16],
+        sample: &SampleOption,
+        random: &mut u64,
+    ) -> Result<u16> {
+        match sample.temperature <= 0.0 {
+            true => self.run_greedy(tokens).await,
+            false => Ok(sample.sample(&self.run(tokens).await?, random)),
+        }
+    }
+
+    /// Sample tokens after `prompt` until a stop token, the token limit, or `on_token` returns `false`.
+    async fn generate_tokens(
+        &self,
+        prompt: &[u16],
+        option: &GenerateOption,
+        mut on_token: impl FnMut(u16) -> Result<bool>,
+    ) -> Result<Vec<u16>> {
+        let sample = SampleOption {
+            temperature: option.temperature,
+            top_p: option.top_p,
+            seed: option.seed as u64,
+        };
+        let mut random = sample.seed;
+        let mut tokens = vec![];
+        let mut token = self.next_token(prompt, &sample, &mut random).await?;
+        while tokens.len() < option.max_tokens {
+            if option.stop.contains(&token) {
+                break;
+            }
+            tokens.push(token);
+            if !on_token(token)? {
+                break;
+            }
+            token = self.next_token(&[token], &sample, &mut random).await?;
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{GenerateOption, JsContext, JsModelBuilder};
+    use crate::{
+        context::{ContextBuilder, InstanceExt},
+        runtime::{
+            model::{ContextAutoLimits, ModelVersion},
+            tiny::TinyModel,
+        },
+    };
+
+    #[test]
+    fn test_js_runtime() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let info = TinyModel::info(ModelVersion::V5);
+            let instance = wgpu::Instance::default();
+            let Ok(adapter) = instance
+                .adapter(wgpu::PowerPreference::HighPerformance)
+                .await
+            else {
+                return Ok(());
+            };
+            let Ok(context) = ContextBuilder::new(adapter)
+                .auto_limits(&info)
+                .build()
+                .await
+            else {
+                return Ok(());
+            };
+
+            let data = TinyModel::new(info.clone(), 42).serialize()?;
+            let runtime = JsModelBuilder::new(&JsContext::new(context), data)
+                .load()
+                .await?;
+            assert_eq!(runtime.info(), info);
+
+            // generation stops at the limit, or when the callback asks to
+            let option = GenerateOption {
+                max_tokens: 8,
+                temperature: 0.0,
+                stop: vec![],
+                ..Default::default()
+            };
+            let prompt = [1u16, 2, 3, 4, 5, 6, 7];
+            let mut streamed = vec![];
+            let tokens = runtime
+                .generate_tokens(&prompt, &option, |token| {
+                    streamed.push(token);
+                    Ok(true)
+                })
+                .await?;
+            assert_eq!(tokens.len(), 8);
+            assert_eq!(tokens, streamed);
+
+            runtime.state.load(runtime.state.init(), 0)?;
+            let first = runtime
+                .generate_tokens(&prompt, &option, |_| Ok(false))
+                .await?;
+            assert_eq!(first, tokens[..1]);
+
+            // the same prompt from the initial state, fed in small chunks, gives the same tokens
+            let mut runtime = runtime;
+            runtime.set_token_chunk_size(2);
+            runtime.state.load(runtime.state.init(), 0)?;
+            let again = runtime
+                .generate_tokens(&prompt, &option, |_| Ok(true))
+                .await?;
+            assert_eq!(again, tokens);
+
+            let option = GenerateOption {
+                stop: vec![tokens[3]],
+                ..option
+            };
+            runtime.state.load(runtime.state.init(), 0)?;
+            let stopped = runtime
+                .generate_tokens(&prompt, &option, |_| Ok(true))
+                .await?;
+            let end = tokens.iter().position(|&x| x == tokens[3]).unwrap_or(3);
+            assert_eq!(stopped, tokens[..end]);
+            Ok(())
+        })
+    }
+}